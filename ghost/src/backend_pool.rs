@@ -6,11 +6,24 @@
 
 use std::collections::HashMap;
 use std::ffi::CString;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::config::{BackendTLS, ExternalProxy};
-use crate::external_backend::{warm_runtime, ExternalBackend, ExternalBody};
+use crate::config::{
+    BackendTLS, ExternalProxy, HealthCheck, NonceFilter, OutboundRateLimit, RateLimitFilter,
+};
+use crate::dns::DnsCache;
+use crate::external_backend::{
+    warm_runtime, ClientParams, ExternalBackend, ExternalBody, Reconfigure,
+};
+use crate::health::{HealthMap, HealthTarget};
+use crate::nonce::NonceStore;
+use crate::outlier::{OutcomeRecorder, OutlierDetector, OutlierSlot};
+use crate::rate_limit::{ClientRateLimiter, TokenBucket};
+use crate::signing::{RequestSigner, SignerSlot};
+use crate::stats::HistogramSnapshot;
 use varnish::vcl::{Backend, BackendRef, Ctx, NativeBackend, NativeBackendBuilder, VclError};
 
 /// Entry stored in the BackendPool. Native backends wrap real Varnish backend
@@ -40,15 +53,122 @@ impl BackendEntry {
     }
 }
 
+/// How long a reload keeps a backend it removed from the routing, so fetches
+/// already sent to it can finish.
+pub const DRAIN_GRACE: Duration = Duration::from_secs(30);
+
+/// A backend removed from the routing, waiting out its grace period
+#[derive(Clone, Debug)]
+struct Retired<E> {
+    key: String,
+    item: E,
+    in_flight: Arc<AtomicU64>,
+    until: Instant,
+}
+
+/// Backends removed by recent reloads, with their in-flight counters.
+#[derive(Clone, Debug)]
+struct GraceList<E>(Vec<Retired<E>>);
+
+impl<E> Default for GraceList<E> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<E> GraceList<E> {
+    /// Keep `item` until `until`, and after that for as long as requests are
+    /// in flight to it.
+    fn retire(&mut self, key: String, item: E, in_flight: Arc<AtomicU64>, until: Instant) {
+        self.0.push(Retired {
+            key,
+            item,
+            in_flight,
+            until,
+        });
+    }
+
+    /// Take back a retired item, e.g. when a reload re-adds its backend
+    fn revive(&mut self, key: &str) -> Option<(E, Arc<AtomicU64>)> {
+        let i = self.0.iter().position(|r| r.key == key)?;
+        let r = self.0.swap_remove(i);
+        Some((r.item, r.in_flight))
+    }
+
+    /// Drop the items past their grace period with nothing in flight
+    fn prune(&mut self, now: Instant) {
+        self.0
+            .retain(|r| r.until > now || r.in_flight.load(Ordering::Relaxed) > 0);
+    }
+}
+
+/// A change to a backend that the live pool may share with this one, held
+/// back until `BackendPool::commit()`
+#[derive(Clone, Debug)]
+enum PendingUpdate {
+    Signer(SignerSlot, Option<Arc<RequestSigner>>),
+    External(BackendEntry, Reconfigure),
+}
+
 /// Backend pool for a single director instance
 ///
 /// Each director owns its own backends. Backends are indexed by their key
 /// (e.g. `"address:port"` for native, `"external:scheme://host:port"` for
 /// external proxies) and reused across config reloads within the same director.
 /// Backends are wrapped in Arc for efficient cloning during hot-reload.
+///
+/// Each backend also gets an in-flight request counter, shared across pool
+/// clones so least-connections selection sees the same load after a reload.
+/// Active health check results are shared the same way, and so are the
+/// token buckets of rate limited backends.
+///
+/// Because of that sharing, a reload building a new pool from a clone of
+/// the live one stages changes to existing backends instead of making them,
+/// and only `commit()`s them once the whole config has been built.
+///
+/// Backends are owned through `Arc`s held by every pool that lists them:
+/// the live pool, and an older one for as long as a request still routes
+/// with the vhost directors built alongside it. Once the last `Arc` goes,
+/// the Varnish backend is deleted and, for external proxies, the
+/// `ExternalBackend` behind it freed. A fetch holds no `Arc`, so a reload
+/// that drops a backend from the routing keeps it in a grace list rather
+/// than letting it go with the old pool. It is dropped at a later reload,
+/// once `DRAIN_GRACE` has passed and no request to it is in flight (only
+/// external proxies count theirs; a native backend just gets the grace
+/// period, with Varnish holding its own reference during a fetch).
 #[derive(Clone, Debug)]
 pub struct BackendPool {
     backends: HashMap<String, BackendEntry>,
+    in_flight: HashMap<String, Arc<AtomicU64>>,
+    health_targets: HashMap<String, HealthTarget>,
+    health: HealthMap,
+    outliers: Arc<OutlierDetector>,
+    /// Outlier state of each backend, resolved when it joins the pool
+    outlier_slots: HashMap<String, Arc<OutlierSlot>>,
+    signers: HashMap<String, SignerSlot>,
+    /// Host header naming each backend, for routes with `forward_host: backend`.
+    host_names: HashMap<String, String>,
+    /// Outbound rate limits, keyed like `backends`
+    rate_limits: HashMap<String, Arc<TokenBucket>>,
+    /// Buckets from before `clear_rate_limits()`, reused for backends whose
+    /// limit a reload leaves unchanged
+    retired_rate_limits: HashMap<String, Arc<TokenBucket>>,
+    /// Per-client rate limits of routes, keyed by route
+    client_rate_limits: HashMap<String, Arc<ClientRateLimiter>>,
+    /// Limiters from before `clear_rate_limits()`, as for outbound ones
+    retired_client_rate_limits: HashMap<String, Arc<ClientRateLimiter>>,
+    /// Nonces seen by routes with a `nonce` filter, keyed by route
+    nonce_stores: HashMap<String, Arc<NonceStore>>,
+    /// Stores from before `clear_rate_limits()`, as for client limiters
+    retired_nonce_stores: HashMap<String, Arc<NonceStore>>,
+    /// Changes staged by this build, applied by `commit()`
+    pending: Vec<PendingUpdate>,
+    /// Backends removed by recent reloads, with their signer slots
+    retired: GraceList<(BackendEntry, Option<SignerSlot>)>,
+    /// HTTP client settings external proxies are built or reconfigured with
+    client_params: ClientParams,
+    /// Answers for backends addressed by DNS name, shared across pool clones
+    dns: Arc<DnsCache>,
 }
 
 // SAFETY: NativeBackend wraps VCL_BACKEND pointers which are thread-safe in Varnish.
@@ -64,9 +184,42 @@ impl BackendPool {
     pub fn new() -> Self {
         Self {
             backends: HashMap::new(),
+            in_flight: HashMap::new(),
+            health_targets: HashMap::new(),
+            health: HealthMap::default(),
+            outliers: Arc::new(OutlierDetector::new()),
+            outlier_slots: HashMap::new(),
+            signers: HashMap::new(),
+            host_names: HashMap::new(),
+            rate_limits: HashMap::new(),
+            retired_rate_limits: HashMap::new(),
+            client_rate_limits: HashMap::new(),
+            retired_client_rate_limits: HashMap::new(),
+            nonce_stores: HashMap::new(),
+            retired_nonce_stores: HashMap::new(),
+            pending: Vec::new(),
+            retired: GraceList::default(),
+            client_params: ClientParams::default(),
+            dns: Arc::new(DnsCache::default()),
         }
     }
 
+    /// Set the HTTP client settings of external proxies added or reused
+    /// from now on. Set before building a reload's backends.
+    pub fn set_client_params(&mut self, params: ClientParams) {
+        self.client_params = params;
+    }
+
+    /// HTTP client settings of the external proxies in this pool
+    pub fn client_params(&self) -> &ClientParams {
+        &self.client_params
+    }
+
+    /// Answers for the DNS names native backends are addressed by
+    pub fn dns(&self) -> &DnsCache {
+        &self.dns
+    }
+
     /// Get or create a backend in the pool
     ///
     /// Returns the backend key. If the backend already exists,
@@ -88,21 +241,52 @@ impl BackendPool {
         port: u16,
         tls: Option<&BackendTLS>,
     ) -> Result<String, VclError> {
-        let key = match tls {
-            Some(t) => format!("{}:{}:tls:{}", address, port, t.hostname),
-            None => format!("{}:{}", address, port),
+        // Parse IP address
+        let ip: IpAddr = address
+            .parse()
+            .map_err(|e| VclError::new(format!("Invalid IP address '{}': {}", address, e)))?;
+        self.get_or_create_native(ctx, address, ip, address, port, tls)
+    }
+
+    /// Get or create the native backend for one address `host` resolved to.
+    ///
+    /// Keyed by the IP like any native backend; `host` only names it in the
+    /// Host header of `forward_host: backend` routes. For an IP literal the
+    /// two are the same.
+    pub fn get_or_create_resolved(
+        &mut self,
+        ctx: &mut Ctx,
+        ip: IpAddr,
+        host: &str,
+        port: u16,
+        tls: Option<&BackendTLS>,
+    ) -> Result<String, VclError> {
+        self.get_or_create_native(ctx, &ip.to_string(), ip, host, port, tls)
+    }
+
+    /// Native backend keyed by `address`, connecting to `ip`
+    fn get_or_create_native(
+        &mut self,
+        ctx: &mut Ctx,
+        address: &str,
+        ip: IpAddr,
+        host: &str,
+        port: u16,
+        tls: Option<&BackendTLS>,
+    ) -> Result<String, VclError> {
+        let key = native_key(address, port, tls);
+        let host_name = match tls {
+            Some(t) => host_header(&t.hostname, port, 443),
+            None => host_header(host, port, 80),
         };
+        self.host_names.insert(key.clone(), host_name);
+        self.revive(&key);
 
         // Check if backend already exists
         if self.backends.contains_key(&key) {
             return Ok(key);
         }
 
-        // Parse IP address
-        let ip: std::net::IpAddr = address
-            .parse()
-            .map_err(|e| VclError::new(format!("Invalid IP address '{}': {}", address, e)))?;
-
         // Create socket address
         let addr = SocketAddr::new(ip, port);
 
@@ -143,6 +327,9 @@ impl BackendPool {
         #[allow(clippy::arc_with_non_send_sync)]
         self.backends
             .insert(key.clone(), BackendEntry::Native(Arc::new(backend)));
+        self.in_flight.entry(key.clone()).or_default();
+        self.outlier_slots
+            .insert(key.clone(), self.outliers.slot(&key));
 
         Ok(key)
     }
@@ -159,17 +346,32 @@ impl BackendPool {
         ctx: &mut Ctx,
         proxy: &ExternalProxy,
     ) -> Result<String, VclError> {
-        let scheme = if proxy.tls { "https" } else { "http" };
-        let key = format!("external:{}://{}:{}", scheme, proxy.hostname, proxy.port);
+        let key = external_key(proxy);
+        // Same value the backend sends by default
+        self.host_names.insert(key.clone(), proxy.hostname.clone());
+        self.revive(&key);
 
-        if self.backends.contains_key(&key) {
+        // Signing settings can change without the upstream tuple changing, so
+        // refresh them even when the backend is reused.
+        let signer = self.stage_signer(&key, proxy);
+
+        if let Some(entry @ BackendEntry::External(backend)) = self.backends.get(&key) {
+            let update = backend
+                .get_inner()
+                .prepare_reconfigure(proxy, &self.client_params)?;
+            self.pending
+                .push(PendingUpdate::External(entry.clone(), update));
             return Ok(key);
         }
 
         // Pay tokio startup at reload time, not on the first proxied request.
         warm_runtime();
 
-        let impl_ = ExternalBackend::new(proxy)?;
+        let in_flight = Arc::clone(self.in_flight.entry(key.clone()).or_default());
+        let slot = self.outliers.slot(&key);
+        self.outlier_slots.insert(key.clone(), Arc::clone(&slot));
+        let outcomes = OutcomeRecorder::new(Arc::clone(&self.outliers), slot);
+        let impl_ = ExternalBackend::new(proxy, &self.client_params, in_flight, outcomes, signer)?;
         let backend_name = format!("ghost_{}", sanitize_backend_name(&key));
         let backend = Backend::new(ctx, "ghost", &backend_name, impl_, false)?;
 
@@ -180,6 +382,48 @@ impl BackendPool {
         Ok(key)
     }
 
+    /// Move a backend a recent reload retired back into the pool, so adding
+    /// it again doesn't create a second Varnish backend of the same name.
+    fn revive(&mut self, key: &str) {
+        let Some(((entry, signer), in_flight)) = self.retired.revive(key) else {
+            return;
+        };
+        self.backends.insert(key.to_string(), entry);
+        self.in_flight.insert(key.to_string(), in_flight);
+        self.outlier_slots
+            .insert(key.to_string(), self.outliers.slot(key));
+        if let Some(signer) = signer {
+            self.signers.insert(key.to_string(), signer);
+        }
+    }
+
+    /// Stage the signing settings of an external backend, returning its
+    /// signer slot
+    fn stage_signer(&mut self, key: &str, proxy: &ExternalProxy) -> SignerSlot {
+        let signer = Arc::clone(self.signers.entry(key.to_string()).or_default());
+        let update = proxy
+            .signing
+            .as_ref()
+            .map(|s| Arc::new(RequestSigner::new(s)));
+        self.pending
+            .push(PendingUpdate::Signer(Arc::clone(&signer), update));
+        signer
+    }
+
+    /// Apply the changes staged while building this pool. Called once a
+    /// reload can no longer fail, right before the pool goes live.
+    pub fn commit(&mut self) {
+        for update in self.pending.drain(..) {
+            match update {
+                PendingUpdate::Signer(slot, signer) => slot.store(signer),
+                PendingUpdate::External(BackendEntry::External(backend), update) => {
+                    backend.get_inner().apply_reconfigure(update)
+                }
+                PendingUpdate::External(BackendEntry::Native(_), _) => {}
+            }
+        }
+    }
+
     /// Look up a backend in the pool by key
     ///
     /// Returns None if the backend doesn't exist (shouldn't happen in normal use).
@@ -187,6 +431,175 @@ impl BackendPool {
         self.backends.get(key).cloned()
     }
 
+    /// Host header value naming a backend
+    pub fn host_name(&self, key: &str) -> Option<&str> {
+        self.host_names.get(key).map(String::as_str)
+    }
+
+    /// Number of requests currently in flight to a backend.
+    ///
+    /// Only external proxy backends report real counts: native backends hand
+    /// the connection to Varnish, which gives the VMOD no completion signal,
+    /// so their counter stays at zero.
+    pub fn in_flight(&self, key: &str) -> u64 {
+        self.in_flight
+            .get(key)
+            .map(|c| c.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    /// First-byte and last-byte latency of each external proxy backend that
+    /// has served a request, sorted by key.
+    pub fn latencies(&self) -> Vec<(&str, HistogramSnapshot, HistogramSnapshot)> {
+        let mut latencies: Vec<_> = self
+            .backends
+            .iter()
+            .filter_map(|(key, entry)| match entry {
+                BackendEntry::External(b) => {
+                    let latency = b.get_inner().latency();
+                    let first_byte = latency.first_byte.snapshot();
+                    (first_byte.count() > 0)
+                        .then(|| (key.as_str(), first_byte, latency.last_byte.snapshot()))
+                }
+                BackendEntry::Native(_) => None,
+            })
+            .collect();
+        latencies.sort_by(|a, b| a.0.cmp(b.0));
+        latencies
+    }
+
+    /// Register an active health check for a native backend.
+    pub fn set_health_check(&mut self, key: &str, address: &str, port: u16, check: &HealthCheck) {
+        if let Ok(ip) = address.parse::<std::net::IpAddr>() {
+            let url = format!("http://{}{}", SocketAddr::new(ip, port), check.path);
+            self.health_targets.insert(
+                key.to_string(),
+                HealthTarget {
+                    url,
+                    check: check.clone(),
+                },
+            );
+        }
+    }
+
+    /// Forget all health checks. Called before a reload re-registers them
+    /// from the new config.
+    pub fn clear_health_checks(&mut self) {
+        self.health_targets.clear();
+    }
+
+    /// Limit the rate of requests sent to a backend. A backend in several
+    /// rate limited groups gets the limit registered last.
+    pub fn set_rate_limit(&mut self, key: &str, limit: &OutboundRateLimit) {
+        if self.rate_limits.get(key).is_some_and(|b| b.limit() == limit) {
+            return;
+        }
+        let bucket = self
+            .retired_rate_limits
+            .remove(key)
+            .filter(|b| b.limit() == limit)
+            .unwrap_or_else(|| Arc::new(TokenBucket::new(limit)));
+        self.rate_limits.insert(key.to_string(), bucket);
+    }
+
+    /// Forget all rate limits and nonce stores. Called before a reload
+    /// re-registers them from the new config; a bucket whose limit is
+    /// unchanged keeps its tokens, and a store its nonces.
+    pub fn clear_rate_limits(&mut self) {
+        self.retired_rate_limits = std::mem::take(&mut self.rate_limits);
+        self.retired_client_rate_limits = std::mem::take(&mut self.client_rate_limits);
+        self.retired_nonce_stores = std::mem::take(&mut self.nonce_stores);
+    }
+
+    /// Client buckets of the route `route_key` names. A route whose limit
+    /// a reload leaves unchanged keeps its clients' tokens.
+    pub fn client_rate_limiter(
+        &mut self,
+        route_key: &str,
+        limit: &RateLimitFilter,
+    ) -> Arc<ClientRateLimiter> {
+        let limiter = self
+            .client_rate_limits
+            .get(route_key)
+            .or_else(|| self.retired_client_rate_limits.get(route_key))
+            .filter(|l| l.limit() == limit)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(ClientRateLimiter::new(limit)));
+        self.client_rate_limits
+            .insert(route_key.to_string(), Arc::clone(&limiter));
+        limiter
+    }
+
+    /// Nonces seen by the route `route_key` names. A route whose filter a
+    /// reload leaves unchanged keeps them, so a reload opens no replay
+    /// window.
+    pub fn nonce_store(&mut self, route_key: &str, filter: &NonceFilter) -> Arc<NonceStore> {
+        let store = self
+            .nonce_stores
+            .get(route_key)
+            .or_else(|| self.retired_nonce_stores.get(route_key))
+            .filter(|s| s.filter() == filter)
+            .cloned()
+            .unwrap_or_else(|| Arc::new(NonceStore::new(filter)));
+        self.nonce_stores
+            .insert(route_key.to_string(), Arc::clone(&store));
+        store
+    }
+
+    /// Token bucket of a rate limited backend
+    pub fn rate_limit(&self, key: &str) -> Option<&Arc<TokenBucket>> {
+        self.rate_limits.get(key)
+    }
+
+    /// Fetches throttled per rate limited backend, sorted by key
+    pub fn throttled(&self) -> Vec<(&str, u64)> {
+        let mut throttled: Vec<_> = self
+            .rate_limits
+            .iter()
+            .map(|(key, bucket)| (key.as_str(), bucket.throttled()))
+            .collect();
+        throttled.sort();
+        throttled
+    }
+
+    /// Backends with an active health check, keyed like the pool.
+    pub fn health_targets(&self) -> &HashMap<String, HealthTarget> {
+        &self.health_targets
+    }
+
+    /// Shared probe results, updated by the health probe tasks.
+    pub fn health_map(&self) -> &HealthMap {
+        &self.health
+    }
+
+    /// Whether a backend may be selected: not failing its active health
+    /// check (unprobed counts as passing) and not ejected as an outlier.
+    pub fn is_healthy(&self, key: &str) -> bool {
+        self.health.get(key).unwrap_or(true)
+            && !self
+                .outlier_slots
+                .get(key)
+                .is_some_and(|slot| slot.is_ejected())
+    }
+
+    /// Passive outlier detector shared by all clones of this pool.
+    pub fn outliers(&self) -> &Arc<OutlierDetector> {
+        &self.outliers
+    }
+
+    /// Backends currently failing their health check (for diagnostics)
+    pub fn unhealthy_keys(&self) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .health
+            .snapshot()
+            .iter()
+            .filter(|(_, healthy)| !**healthy)
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
+
     /// Get the number of backends in the pool (for diagnostics)
     pub fn len(&self) -> usize {
         self.backends.len()
@@ -195,10 +608,68 @@ impl BackendPool {
     /// Remove all backends except those in the provided set of keys
     ///
     /// This is used during config reload to clean up backends that are
-    /// no longer referenced in the routing state.
+    /// no longer referenced in the routing state. Removed backends are
+    /// retired for `DRAIN_GRACE` rather than dropped, and the ones retired
+    /// by earlier reloads that are done draining are dropped.
     pub fn retain_only(&mut self, keys_to_keep: &std::collections::HashSet<String>) {
-        self.backends
-            .retain(|key, _backend| keys_to_keep.contains(key));
+        let now = Instant::now();
+        self.retired.prune(now);
+        let removed: Vec<String> = self
+            .backends
+            .keys()
+            .filter(|key| !keys_to_keep.contains(*key))
+            .cloned()
+            .collect();
+        for key in removed {
+            let Some(entry) = self.backends.remove(&key) else {
+                continue;
+            };
+            let in_flight = self.in_flight.remove(&key).unwrap_or_default();
+            let signer = self.signers.remove(&key);
+            self.retired
+                .retire(key, (entry, signer), in_flight, now + DRAIN_GRACE);
+        }
+        self.in_flight.retain(|key, _| keys_to_keep.contains(key));
+        self.health_targets
+            .retain(|key, _| keys_to_keep.contains(key));
+        self.outlier_slots
+            .retain(|key, _| keys_to_keep.contains(key));
+        self.outliers.retain(|key| keys_to_keep.contains(key));
+        self.signers.retain(|key, _| keys_to_keep.contains(key));
+        self.host_names.retain(|key, _| keys_to_keep.contains(key));
+        self.rate_limits.retain(|key, _| keys_to_keep.contains(key));
+        self.retired_rate_limits.clear();
+        self.retired_client_rate_limits.clear();
+        self.retired_nonce_stores.clear();
+    }
+}
+
+/// Pool key of a native backend
+pub fn native_key(address: &str, port: u16, tls: Option<&BackendTLS>) -> String {
+    match tls {
+        Some(t) => format!("{}:{}:tls:{}", address, port, t.hostname),
+        None => format!("{}:{}", address, port),
+    }
+}
+
+/// Pool key of an external proxy backend
+pub fn external_key(proxy: &ExternalProxy) -> String {
+    let scheme = if proxy.tls { "https" } else { "http" };
+    format!("external:{}://{}:{}", scheme, proxy.hostname, proxy.port)
+}
+
+/// Host header value for `host` on `port`, leaving out the scheme's default
+/// port and bracketing IPv6 addresses.
+fn host_header(host: &str, port: u16, default_port: u16) -> String {
+    let host = if host.contains(':') {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    };
+    if port == default_port {
+        host
+    } else {
+        format!("{}:{}", host, port)
     }
 }
 
@@ -227,6 +698,7 @@ mod tests {
     fn test_backend_key_format() {
         let key = format!("{}:{}", "10.0.0.1", 8080);
         assert_eq!(key, "10.0.0.1:8080");
+        assert_eq!(native_key("10.0.0.1", 8080, None), key);
     }
 
     #[test]
@@ -249,9 +721,230 @@ mod tests {
         assert_eq!(key, "::1:8080");
     }
 
+    #[test]
+    fn test_host_header() {
+        assert_eq!(host_header("10.0.0.1", 80, 80), "10.0.0.1");
+        assert_eq!(host_header("10.0.0.1", 8080, 80), "10.0.0.1:8080");
+        assert_eq!(host_header("::1", 8080, 80), "[::1]:8080");
+        assert_eq!(host_header("api.internal", 443, 443), "api.internal");
+        assert_eq!(host_header("api.internal", 80, 443), "api.internal:80");
+    }
+
     #[test]
     fn test_backend_pool_creation() {
         let pool = BackendPool::new();
         assert_eq!(pool.len(), 0);
     }
+
+    #[test]
+    fn test_in_flight_unknown_key() {
+        let pool = BackendPool::new();
+        assert_eq!(pool.in_flight("10.0.0.1:8080"), 0);
+    }
+
+    #[test]
+    fn test_health_check_targets() {
+        let check = HealthCheck {
+            path: "/healthz".to_string(),
+            interval_ms: 1000,
+            timeout_ms: 500,
+            expected_status: 200,
+            healthy_threshold: 1,
+            unhealthy_threshold: 1,
+        };
+        let mut pool = BackendPool::new();
+        pool.set_health_check("::1:8080", "::1", 8080, &check);
+        assert_eq!(
+            pool.health_targets()["::1:8080"].url,
+            "http://[::1]:8080/healthz"
+        );
+
+        // Unprobed backends are healthy; a failed probe flips that.
+        assert!(pool.is_healthy("::1:8080"));
+        pool.health_map().set("::1:8080", false);
+        assert!(!pool.is_healthy("::1:8080"));
+        assert_eq!(pool.unhealthy_keys(), vec!["::1:8080".to_string()]);
+
+        pool.clear_health_checks();
+        assert!(pool.health_targets().is_empty());
+    }
+
+    #[test]
+    fn test_client_rate_limits_survive_reload() {
+        let limit = RateLimitFilter {
+            rps: 10,
+            burst: None,
+            key: crate::config::HashSource::ClientIp,
+            max_clients: 100,
+        };
+        let mut pool = BackendPool::new();
+        let limiter = pool.client_rate_limiter("api.example.com 0 default/api", &limit);
+
+        // Unchanged by a reload: the same buckets
+        pool.clear_rate_limits();
+        let again = pool.client_rate_limiter("api.example.com 0 default/api", &limit);
+        assert!(Arc::ptr_eq(&limiter, &again));
+
+        // A new limit starts over
+        pool.clear_rate_limits();
+        let changed = pool.client_rate_limiter(
+            "api.example.com 0 default/api",
+            &RateLimitFilter { rps: 20, ..limit },
+        );
+        assert!(!Arc::ptr_eq(&limiter, &changed));
+    }
+
+    #[test]
+    fn test_nonce_stores_survive_reload() {
+        let filter = NonceFilter {
+            header: "X-Nonce".to_string(),
+            window_seconds: 300,
+            max_nonces: 100,
+            validated_header: "X-Nonce-Validated".to_string(),
+        };
+        let mut pool = BackendPool::new();
+        let store = pool.nonce_store("api.example.com 0 default/api", &filter);
+        store.check(Some("abc")).unwrap();
+
+        // Unchanged by a reload: nonces seen before are still replays
+        pool.clear_rate_limits();
+        let again = pool.nonce_store("api.example.com 0 default/api", &filter);
+        assert!(Arc::ptr_eq(&store, &again));
+        assert!(again.check(Some("abc")).is_err());
+
+        pool.clear_rate_limits();
+        let changed = pool.nonce_store(
+            "api.example.com 0 default/api",
+            &NonceFilter {
+                window_seconds: 60,
+                ..filter
+            },
+        );
+        assert!(!Arc::ptr_eq(&store, &changed));
+    }
+
+    #[test]
+    fn test_rate_limits_survive_reload() {
+        let limit = OutboundRateLimit {
+            rps: 10,
+            burst: None,
+            max_wait_ms: 0,
+        };
+        let mut pool = BackendPool::new();
+        pool.set_rate_limit("10.0.0.1:8080", &limit);
+        pool.set_rate_limit("10.0.0.2:8080", &limit);
+        let bucket = pool.rate_limit("10.0.0.1:8080").unwrap().clone();
+        assert!(pool.rate_limit("10.0.0.3:8080").is_none());
+
+        // An unchanged limit keeps its bucket, even when several groups
+        // register it; a changed one starts afresh, and one left out of the
+        // new config is gone.
+        pool.clear_rate_limits();
+        pool.set_rate_limit("10.0.0.1:8080", &limit);
+        pool.set_rate_limit("10.0.0.3:8080", &limit);
+        pool.set_rate_limit("10.0.0.1:8080", &limit);
+        assert!(Arc::ptr_eq(
+            pool.rate_limit("10.0.0.1:8080").unwrap(),
+            &bucket
+        ));
+        assert!(pool.rate_limit("10.0.0.2:8080").is_none());
+
+        pool.clear_rate_limits();
+        pool.set_rate_limit("10.0.0.1:8080", &OutboundRateLimit { rps: 20, ..limit });
+        assert!(!Arc::ptr_eq(
+            pool.rate_limit("10.0.0.1:8080").unwrap(),
+            &bucket
+        ));
+        assert_eq!(pool.throttled(), vec![("10.0.0.1:8080", 0)]);
+    }
+
+    #[test]
+    fn test_signer_changes_wait_for_commit() {
+        let mut proxy: ExternalProxy =
+            serde_json::from_str(r#"{"hostname": "up.example.com", "port": 443}"#).unwrap();
+        let mut live = BackendPool::new();
+        let slot = live.stage_signer("external:https://up.example.com:443", &proxy);
+        live.commit();
+        assert!(slot.load().is_none());
+
+        // A reload builds from a clone that shares the slot
+        let mut next = live.clone();
+        proxy.signing =
+            Some(serde_json::from_str(r#"{"key_id": "k1", "secret_ref": "/dev/null"}"#).unwrap());
+        let staged = next.stage_signer("external:https://up.example.com:443", &proxy);
+        assert!(Arc::ptr_eq(&slot, &staged));
+        assert!(slot.load().is_none(), "applied before commit");
+
+        // A failed reload drops the clone; the live signer is unchanged
+        drop(next.clone());
+        assert!(slot.load().is_none());
+
+        next.commit();
+        assert!(slot.load().is_some());
+    }
+
+    #[test]
+    fn test_retired_backend_outlives_in_flight_fetch() {
+        let backend = Arc::new(());
+        let in_flight = Arc::new(AtomicU64::new(0));
+        let now = Instant::now();
+        let mut retired = GraceList::default();
+
+        // A fetch starts, then a reload removes its backend
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        retired.retire(
+            "external:https://up.example.com:443".to_string(),
+            Arc::clone(&backend),
+            Arc::clone(&in_flight),
+            now + DRAIN_GRACE,
+        );
+        retired.prune(now);
+        assert_eq!(Arc::strong_count(&backend), 2);
+
+        // Past the grace period, the fetch still holds it
+        retired.prune(now + DRAIN_GRACE);
+        assert_eq!(Arc::strong_count(&backend), 2);
+
+        // The fetch completes: the next reload drops it
+        in_flight.fetch_sub(1, Ordering::Relaxed);
+        retired.prune(now + DRAIN_GRACE);
+        assert_eq!(Arc::strong_count(&backend), 1);
+    }
+
+    #[test]
+    fn test_retired_backend_idle_dropped_after_grace() {
+        let backend = Arc::new(());
+        let now = Instant::now();
+        let mut retired = GraceList::default();
+        retired.retire(
+            "10.0.0.1:8080".to_string(),
+            Arc::clone(&backend),
+            Arc::default(),
+            now + DRAIN_GRACE,
+        );
+
+        retired.prune(now + DRAIN_GRACE - Duration::from_secs(1));
+        assert_eq!(Arc::strong_count(&backend), 2);
+        retired.prune(now + DRAIN_GRACE);
+        assert_eq!(Arc::strong_count(&backend), 1);
+    }
+
+    #[test]
+    fn test_retired_backend_revived() {
+        let in_flight = Arc::new(AtomicU64::new(3));
+        let mut retired = GraceList::default();
+        retired.retire(
+            "10.0.0.1:8080".to_string(),
+            "backend",
+            Arc::clone(&in_flight),
+            Instant::now(),
+        );
+        assert!(retired.revive("10.0.0.2:8080").is_none());
+
+        // Re-added by a reload: same backend, same counter
+        let (item, counter) = retired.revive("10.0.0.1:8080").unwrap();
+        assert_eq!(item, "backend");
+        assert!(Arc::ptr_eq(&counter, &in_flight));
+        assert!(retired.revive("10.0.0.1:8080").is_none());
+    }
 }
@@ -0,0 +1,86 @@
+//! Synthetic 400 backend for malformed request targets
+//!
+//! This backend generates 400 responses when the request target contains control
+//! characters, before any vhost or route matching is attempted.
+
+use varnish::vcl::{Ctx, VclBackend, VclError, VclResponse};
+
+/// Backend that generates synthetic 400 responses
+pub struct BadRequestBackend;
+
+impl VclBackend<BadRequestBody> for BadRequestBackend {
+    fn get_response(&self, ctx: &mut Ctx) -> Result<Option<BadRequestBody>, VclError> {
+        let beresp = ctx
+            .http_beresp
+            .as_mut()
+            .ok_or_else(|| VclError::new("Missing beresp in bad_request backend".to_string()))?;
+        beresp.set_status(400);
+        beresp.set_header("Content-Type", "text/plain")?;
+        beresp.set_header("Cache-Control", "no-store")?;
+
+        Ok(Some(BadRequestBody::new()))
+    }
+}
+
+/// Response body for 400 error
+pub struct BadRequestBody {
+    data: &'static [u8],
+    cursor: usize,
+}
+
+impl BadRequestBody {
+    /// Create a new 400 response body
+    pub fn new() -> Self {
+        Self {
+            data: b"invalid request target",
+            cursor: 0,
+        }
+    }
+}
+
+impl VclResponse for BadRequestBody {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, VclError> {
+        let remaining = &self.data[self.cursor..];
+        let to_copy = remaining.len().min(buf.len());
+
+        buf[..to_copy].copy_from_slice(&remaining[..to_copy]);
+        self.cursor += to_copy;
+
+        Ok(to_copy)
+    }
+
+    fn len(&self) -> Option<usize> {
+        Some(self.data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bad_request_body_new() {
+        let body = BadRequestBody::new();
+        assert_eq!(body.cursor, 0);
+    }
+
+    #[test]
+    fn test_bad_request_body_len() {
+        let body = BadRequestBody::new();
+        assert_eq!(body.len(), Some(22));
+    }
+
+    #[test]
+    fn test_bad_request_body_read() {
+        let mut body = BadRequestBody::new();
+        let mut buf = vec![0u8; 100];
+
+        let n = body.read(&mut buf).unwrap();
+        assert_eq!(n, 22);
+        assert_eq!(&buf[..n], b"invalid request target");
+
+        // Second read should return 0 (EOF)
+        let n = body.read(&mut buf).unwrap();
+        assert_eq!(n, 0);
+    }
+}
@@ -6,21 +6,84 @@
 //! EndpointSlice discoveries.
 
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::path::Path;
 
 /// A single upstream pod endpoint discovered from Kubernetes EndpointSlices.
 #[derive(Debug, Clone, Deserialize)]
 pub struct Backend {
+    /// IP address, or a DNS name resolved to one backend per address
     pub address: String,
     pub port: u16,
+    /// Endpoint is being removed. A draining backend keeps serving requests
+    /// whose consistent-hash key maps to it, but attracts no new sessions.
+    #[serde(default)]
+    pub draining: bool,
+    /// Active HTTP health check. Without one, the backend is trusted to be
+    /// healthy (Kubernetes readiness already gates EndpointSlices).
+    #[serde(default)]
+    pub health: Option<HealthCheck>,
+    /// EndpointSlice topology hints: the zones that should prefer this
+    /// backend. Used with the gateway's `zone`.
+    #[serde(default)]
+    pub hints: Option<TopologyHints>,
+}
+
+/// Topology Aware Hints of an endpoint, from EndpointSlice `hints`
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct TopologyHints {
+    #[serde(default)]
+    pub for_zones: Vec<String>,
 }
 
 fn default_weight() -> u32 {
     100
 }
 
+fn default_health_path() -> String {
+    "/".to_string()
+}
+
+fn default_health_interval_ms() -> u64 {
+    5000
+}
+
+fn default_health_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_health_expected_status() -> u16 {
+    200
+}
+
+fn default_health_threshold() -> u32 {
+    1
+}
+
+/// Upper bound for probe thresholds.
+const MAX_HEALTH_THRESHOLD: u32 = 100;
+
+/// Periodic HTTP GET probe for a native backend, for setups where endpoint
+/// readiness isn't managed by Kubernetes (bare metal, cross-cluster).
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct HealthCheck {
+    #[serde(default = "default_health_path")]
+    pub path: String,
+    #[serde(default = "default_health_interval_ms")]
+    pub interval_ms: u64,
+    #[serde(default = "default_health_timeout_ms")]
+    pub timeout_ms: u64,
+    #[serde(default = "default_health_expected_status")]
+    pub expected_status: u16,
+    /// Consecutive passing probes that mark an unhealthy backend healthy.
+    #[serde(default = "default_health_threshold")]
+    pub healthy_threshold: u32,
+    /// Consecutive failing probes that mark a healthy backend unhealthy.
+    #[serde(default = "default_health_threshold")]
+    pub unhealthy_threshold: u32,
+}
+
 /// TLS configuration for backend connections, derived from BackendTLSPolicy.
 #[derive(Debug, Clone, Deserialize)]
 pub struct BackendTLS {
@@ -28,6 +91,48 @@ pub struct BackendTLS {
     pub hostname: String,
 }
 
+/// HMAC algorithms supported for upstream request signing.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+pub enum SigningAlgorithm {
+    #[default]
+    #[serde(rename = "hmac-sha256")]
+    HmacSha256,
+}
+
+fn default_signing_header() -> String {
+    "X-Signature".to_string()
+}
+
+/// Request signing for upstreams that authenticate callers with an HMAC
+/// over method, path and timestamp.
+#[derive(Clone, Deserialize, PartialEq)]
+pub struct RequestSigning {
+    /// Identifies the secret to the upstream; sent with the signature.
+    pub key_id: String,
+    /// Path of a file holding the shared secret, e.g. a mounted Kubernetes
+    /// Secret. Read on every config load.
+    pub secret_ref: String,
+    #[serde(default = "default_signing_header")]
+    pub header: String,
+    #[serde(default)]
+    pub algorithm: SigningAlgorithm,
+    /// Secret read from `secret_ref` by `load()`.
+    #[serde(skip)]
+    pub secret: Vec<u8>,
+}
+
+// Hand-written so the secret never ends up in logs.
+impl std::fmt::Debug for RequestSigning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RequestSigning")
+            .field("key_id", &self.key_id)
+            .field("secret_ref", &self.secret_ref)
+            .field("header", &self.header)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
 /// External HTTP(S) origin proxied via ghost's built-in HTTP client.
 /// Used for Kubernetes Services of type ExternalName. Ghost keeps one
 /// stable synthetic backend per (hostname, port, tls) tuple, hiding DNS
@@ -38,6 +143,94 @@ pub struct ExternalProxy {
     pub port: u16,
     #[serde(default)]
     pub tls: bool,
+    /// Protocol spoken to the upstream: HTTP/1.1, or HTTP/2 over cleartext
+    /// with prior knowledge (`h2c`), e.g. for gRPC services.
+    #[serde(default)]
+    pub protocol: UpstreamProtocol,
+    /// Sign each upstream request. All groups naming the same upstream must
+    /// agree on this, since they share one backend.
+    #[serde(default)]
+    pub signing: Option<RequestSigning>,
+    /// TCP/TLS connect timeout. None keeps the client default (10s).
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u64>,
+    /// Whole-request timeout, replaced per request by the route's
+    /// `timeouts` when set. None keeps the client default (60s).
+    #[serde(default)]
+    pub request_timeout_ms: Option<u64>,
+    /// Time the upstream has to send complete response headers, counted
+    /// from when the request is handed to the client. Exceeding it fails
+    /// the fetch with a 504, however much of `request_timeout_ms` is left.
+    /// None leaves headers bounded by the request timeout only.
+    #[serde(default)]
+    pub header_timeout_ms: Option<u64>,
+    /// Longest a Varnish worker waits on the upstream task for the
+    /// response headers or the next body chunk, a backstop should the task
+    /// never answer. Expiry before the headers gives a 504. None waits 5s
+    /// past the request's timeout.
+    #[serde(default)]
+    pub recv_timeout_ms: Option<u64>,
+    /// Halve the connect timeout after each consecutive connect timeout,
+    /// restoring it on the next successful connect.
+    #[serde(default)]
+    pub adaptive_connect_timeout: bool,
+    /// Server name sent in the TLS handshake and checked against the
+    /// certificate, in place of `hostname`. Connections still go to
+    /// `hostname`, and the Host header is unchanged. Requires `tls`.
+    #[serde(default)]
+    pub sni: Option<String>,
+    /// Accept any upstream certificate, e.g. a self-signed one. Requires
+    /// `tls`; never use it for upstreams reached over untrusted networks.
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    /// Inflate gzip request bodies (`Content-Encoding: gzip`) before
+    /// forwarding, for upstreams that can't handle compressed uploads.
+    #[serde(default)]
+    pub decompress_request_body: bool,
+    /// Inflate gzip responses before delivery, for clients that can't
+    /// handle them. Varnish then caches the inflated body.
+    #[serde(default)]
+    pub decompress_response: bool,
+    /// Gzip request bodies before forwarding, for upstreams known to accept
+    /// `Content-Encoding: gzip` uploads.
+    #[serde(default)]
+    pub compress_request: Option<CompressRequest>,
+    /// Request headers sent upstream as one comma-joined line when the
+    /// client split them over several, e.g. `accept-encoding`, for
+    /// upstreams that only read the first instance.
+    #[serde(default)]
+    pub coalesce_request_headers: Vec<String>,
+    /// Response headers delivered as one comma-joined line when the
+    /// upstream split them over several.
+    #[serde(default)]
+    pub coalesce_response_headers: Vec<String>,
+}
+
+/// Request body compression for an external proxy
+#[derive(Debug, Clone, Deserialize, serde::Serialize, PartialEq)]
+pub struct CompressRequest {
+    #[serde(default = "default_compress_enabled")]
+    pub enabled: bool,
+    /// Smaller bodies are sent as they are
+    #[serde(default = "default_compress_min_bytes")]
+    pub min_bytes: usize,
+}
+
+/// Wire protocol of an external proxy's upstream connections
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProtocol {
+    #[default]
+    Http1,
+    H2c,
+}
+
+fn default_compress_enabled() -> bool {
+    true
+}
+
+fn default_compress_min_bytes() -> usize {
+    1024
 }
 
 /// A group of backends sharing a weight for correct weighted traffic distribution.
@@ -63,6 +256,38 @@ pub struct BackendGroup {
     /// VMOD's HTTP client instead of via native Varnish backends.
     #[serde(default)]
     pub external_proxy: Option<ExternalProxy>,
+    /// Cap on the request rate sent to each backend of the group (or to
+    /// the external proxy), for upstreams with rate limits of their own.
+    #[serde(default)]
+    pub outbound_rate_limit: Option<OutboundRateLimit>,
+}
+
+fn default_rate_limit_wait_ms() -> u64 {
+    100
+}
+
+/// Upper bound for `OutboundRateLimit::max_wait_ms`. Waiting fetches hold a
+/// backend worker thread.
+const MAX_RATE_LIMIT_WAIT_MS: u64 = 10_000;
+
+/// Token bucket limit on requests sent to one backend. A fetch that finds
+/// no token waits up to `max_wait_ms` for one, then gets a 503.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+pub struct OutboundRateLimit {
+    /// Sustained requests per second
+    pub rps: u32,
+    /// Requests that may go out at once after an idle period. Defaults to
+    /// `rps`.
+    #[serde(default)]
+    pub burst: Option<u32>,
+    #[serde(default = "default_rate_limit_wait_ms")]
+    pub max_wait_ms: u64,
+}
+
+impl OutboundRateLimit {
+    pub fn burst(&self) -> u32 {
+        self.burst.unwrap_or(self.rps)
+    }
 }
 
 /// Mirrors Gateway API's HTTPPathMatch types for URL routing decisions.
@@ -88,22 +313,38 @@ pub struct PathMatch {
 pub enum MatchType {
     Exact,
     RegularExpression,
+    /// Present with any value. Takes no `value`.
+    Present,
+    /// Not present at all. Takes no `value`.
+    Absent,
+}
+
+impl MatchType {
+    /// Whether the match compares against a `value`
+    pub fn takes_value(&self) -> bool {
+        matches!(self, MatchType::Exact | MatchType::RegularExpression)
+    }
 }
 
 /// Header matching rule.
 #[derive(Debug, Clone, Deserialize)]
 pub struct HeaderMatch {
     pub name: String,
-    pub value: String,
+    #[serde(default)]
+    pub value: Option<String>,
     #[serde(rename = "type")]
     pub match_type: MatchType,
+    /// Compare values ignoring ASCII case (e.g. `Content-Type` values).
+    #[serde(default)]
+    pub case_insensitive: bool,
 }
 
 /// Query parameter matching rule.
 #[derive(Debug, Clone, Deserialize)]
 pub struct QueryParamMatch {
     pub name: String,
-    pub value: String,
+    #[serde(default)]
+    pub value: Option<String>,
     #[serde(rename = "type")]
     pub match_type: MatchType,
 }
@@ -127,7 +368,7 @@ pub struct RequestHeaderFilter {
 }
 
 /// Response header modification filter
-#[derive(Debug, Clone, Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, serde::Serialize)]
 pub struct ResponseHeaderFilter {
     #[serde(default)]
     pub set: Vec<HTTPHeaderAction>,
@@ -135,6 +376,14 @@ pub struct ResponseHeaderFilter {
     pub add: Vec<HTTPHeaderAction>,
     #[serde(default)]
     pub remove: Vec<String>,
+    /// Set only when the response doesn't already carry the header. Filled
+    /// from the vhost's `security_headers` with `preserve_upstream`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub set_if_absent: Vec<HTTPHeaderAction>,
+    /// Reason phrase to send per response status. Filled from the vhost's
+    /// `reason_phrases`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub reason_phrases: BTreeMap<u16, String>,
 }
 
 /// URL rewrite filter
@@ -144,6 +393,12 @@ pub struct URLRewriteFilter {
     pub path_type: Option<String>,
     pub replace_full_path: Option<String>,
     pub replace_prefix_match: Option<String>,
+    /// Regex whose match in the path `RegexSubstitution` replaces. The
+    /// route's `RegularExpression` path match when absent.
+    pub pattern: Option<String>,
+    /// `RegexSubstitution` replacement, with `$1` / `${name}` references to
+    /// the regex's capture groups
+    pub substitution: Option<String>,
 }
 
 /// Request redirect filter
@@ -192,22 +447,358 @@ pub struct CachePolicy {
     pub bypass_headers: Vec<BypassHeaderConfig>,
 }
 
+/// Longest per-route timeout accepted, in milliseconds (one hour).
+const MAX_ROUTE_TIMEOUT_MS: u64 = 3_600_000;
+
+/// Per-route timeouts, from HTTPRoute `timeouts.request` and
+/// `timeouts.backendRequest`.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+pub struct RouteTimeouts {
+    /// Total time for the request, from dispatch until the response completes.
+    #[serde(default)]
+    pub request_ms: Option<u64>,
+    /// Time for a single request to the backend.
+    #[serde(default)]
+    pub backend_request_ms: Option<u64>,
+    /// Report the `request_ms` budget, time elapsed and time remaining in an
+    /// `X-Ghost-Budget` header on responses that carry debug headers.
+    #[serde(default)]
+    pub expose_budget_header: bool,
+}
+
+impl RouteTimeouts {
+    /// Timeout to apply to the backend fetch: the tighter of the two.
+    /// Without retries a backend request can't outlive the overall request.
+    pub fn effective_ms(&self) -> Option<u64> {
+        match (self.request_ms, self.backend_request_ms) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// Backend selection strategy for a route's backend groups.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionPolicy {
+    /// Two-level weighted random: pick a group by weight, then a random pod.
+    #[default]
+    Weighted,
+    /// Pick the backend with the fewest in-flight requests, breaking ties by
+    /// group weight.
+    LeastConn,
+    /// Hash a request attribute (see `Route::hash_on`) onto a consistent
+    /// hash ring so the same client keeps hitting the same backend.
+    ConsistentHash,
+}
+
+impl SelectionPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SelectionPolicy::Weighted => "weighted",
+            SelectionPolicy::LeastConn => "least_conn",
+            SelectionPolicy::ConsistentHash => "consistent_hash",
+        }
+    }
+}
+
+/// Selection audit mode: what a route would pick under another strategy
+/// and/or group weights. The route's own selection still serves the request.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ShadowSelection {
+    /// Strategy to compare with. None means the route's own.
+    #[serde(default)]
+    pub selection: Option<SelectionPolicy>,
+    /// Group weights to compare with, one per backend group in order.
+    /// None means the configured weights.
+    #[serde(default)]
+    pub weights: Option<Vec<u32>>,
+}
+
+impl ShadowSelection {
+    pub fn uses_hash(&self) -> bool {
+        self.selection == Some(SelectionPolicy::ConsistentHash)
+    }
+}
+
+fn default_shadow_diff_fields() -> Vec<DiffField> {
+    vec![DiffField::Status, DiffField::BodyHash, DiffField::Headers]
+}
+
+fn default_shadow_diff_max_sink_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+/// Shadow response diffing: a sampled share of the route's GET and HEAD
+/// requests is replayed to the selected backend and to `backend`, and the
+/// responses' differences in `fields` are appended to `sink` as JSON lines.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct ShadowDiff {
+    pub backend: RequestMirrorFilter,
+    /// Share of eligible requests replayed, 0-100
+    pub percent: f64,
+    /// File the diff records are appended to
+    pub sink: String,
+    #[serde(default = "default_shadow_diff_fields")]
+    pub fields: Vec<DiffField>,
+    /// Size past which the sink is rotated to `<sink>.1`, replacing it
+    #[serde(default = "default_shadow_diff_max_sink_bytes")]
+    pub max_sink_bytes: u64,
+}
+
+/// Response attribute compared by `shadow_diff`
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiffField {
+    Status,
+    /// SHA-256 of the response body
+    BodyHash,
+    /// End-to-end response headers, less the ones that differ between any
+    /// two responses (Date, Age, ...)
+    Headers,
+}
+
+impl DiffField {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiffField::Status => "status",
+            DiffField::BodyHash => "body_hash",
+            DiffField::Headers => "headers",
+        }
+    }
+}
+
+/// Request attribute hashed for consistent-hash selection.
+#[derive(Debug, Clone, Deserialize, serde::Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "PascalCase")]
+pub enum HashSource {
+    /// Client IP: the first address in `X-Forwarded-For`.
+    ClientIp,
+    /// Value of the named request header.
+    Header { name: String },
+    /// Value of the named cookie.
+    Cookie { name: String },
+}
+
+fn default_retry_attempts() -> u32 {
+    2
+}
+
+fn default_retry_on() -> Vec<u16> {
+    vec![502, 503, 504]
+}
+
+/// Most attempts a retry policy may ask for. Matches varnishd's default
+/// `max_retries` of 4 plus the first attempt.
+const MAX_RETRY_ATTEMPTS: u32 = 5;
+
+fn default_max_inspect_bytes() -> usize {
+    512
+}
+
+/// Largest body prefix a `retry_on_body` condition may buffer.
+const MAX_INSPECT_BYTES: usize = 4096;
+
+/// Retry responses whose body starts with an error marker despite their
+/// status, for upstreams answering `200 {"error":"try again"}`. Only GET,
+/// HEAD and OPTIONS requests to external proxies are inspected.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct RetryOnBody {
+    /// Literal bytes to look for within the inspected prefix.
+    pub pattern: String,
+    /// Bytes of the body buffered before streaming starts.
+    #[serde(default = "default_max_inspect_bytes")]
+    pub max_inspect_bytes: usize,
+}
+
+/// Per-route retry policy, from HTTPRoute `retry`. Failed responses are
+/// retried on a backend of the same route that hasn't been tried yet.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first.
+    #[serde(default = "default_retry_attempts")]
+    pub max_attempts: u32,
+    /// Backend response statuses that trigger another attempt.
+    #[serde(default = "default_retry_on")]
+    pub retry_on: Vec<u16>,
+    /// Timeout for each attempt. The route's `timeouts.request_ms`, if set,
+    /// bounds the attempts as a whole.
+    #[serde(default)]
+    pub per_try_timeout_ms: Option<u64>,
+    /// Also retry methods other than GET, HEAD and OPTIONS.
+    #[serde(default)]
+    pub unsafe_methods: bool,
+    /// Also retry responses whose body prefix contains a pattern.
+    #[serde(default)]
+    pub retry_on_body: Option<RetryOnBody>,
+}
+
+fn default_affinity_cookie() -> String {
+    "GHOST_AFFINITY".to_string()
+}
+
+/// Cookie-based session persistence, from HTTPRoute `sessionPersistence`.
+/// The first response carries a cookie naming the chosen backend (opaquely);
+/// later requests presenting it are pinned to that backend while it exists.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct SessionPersistence {
+    #[serde(default = "default_affinity_cookie")]
+    pub cookie_name: String,
+    /// Cookie lifetime. None issues a session cookie.
+    #[serde(default)]
+    pub max_age_seconds: Option<u32>,
+}
+
+/// Request mirror filter: a copy of each matched request is sent to this
+/// upstream and its response discarded.
+#[derive(Debug, Clone, Deserialize, serde::Serialize, PartialEq)]
+pub struct RequestMirrorFilter {
+    pub address: String,
+    pub port: u16,
+    #[serde(default)]
+    pub tls: bool,
+    /// Share of requests mirrored, 0-100. Every request when absent.
+    #[serde(default)]
+    pub percent: Option<f64>,
+}
+
+/// CORS filter, from HTTPRoute `CORS`. Preflights are answered by the
+/// gateway without reaching the upstream; responses to requests from an
+/// allowed origin get `Access-Control-Allow-*` headers.
+#[derive(Debug, Clone, Deserialize, serde::Serialize, PartialEq)]
+pub struct CorsFilter {
+    /// `scheme://host[:port]` origins. `*` alone allows every origin, and a
+    /// host starting with `*` matches any labels in its place, e.g.
+    /// `https://*.example.com`.
+    pub allow_origins: Vec<String>,
+    /// Methods for `Access-Control-Allow-Methods`, or `*`
+    #[serde(default)]
+    pub allow_methods: Vec<String>,
+    /// Request headers for `Access-Control-Allow-Headers`, or `*`
+    #[serde(default)]
+    pub allow_headers: Vec<String>,
+    /// Response headers for `Access-Control-Expose-Headers`
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    /// Seconds a browser may cache a preflight's answer. 5 when absent, as in
+    /// Gateway API.
+    #[serde(default)]
+    pub max_age: Option<u32>,
+}
+
+fn default_rate_limit_key() -> HashSource {
+    HashSource::ClientIp
+}
+
+fn default_rate_limit_max_clients() -> usize {
+    100_000
+}
+
+/// Per-client request rate limit on a route: a token bucket per client,
+/// refilled at `rps` tokens a second up to `burst`. A request finding its
+/// bucket empty is answered with a 429 and `Retry-After`.
+#[derive(Debug, Clone, Deserialize, serde::Serialize, PartialEq)]
+pub struct RateLimitFilter {
+    /// Sustained requests per second per client
+    pub rps: u32,
+    /// Requests a client may send at once after an idle period. Defaults
+    /// to `rps`.
+    #[serde(default)]
+    pub burst: Option<u32>,
+    /// What tells clients apart. Defaults to the client IP. Requests
+    /// without the key share one bucket.
+    #[serde(default = "default_rate_limit_key")]
+    pub key: HashSource,
+    /// Most clients tracked at once. Idle ones are evicted first.
+    #[serde(default = "default_rate_limit_max_clients")]
+    pub max_clients: usize,
+}
+
+impl RateLimitFilter {
+    pub fn burst(&self) -> u32 {
+        self.burst.unwrap_or(self.rps)
+    }
+}
+
+fn default_nonce_header() -> String {
+    "X-Nonce".to_string()
+}
+
+fn default_nonce_window_seconds() -> u64 {
+    300
+}
+
+fn default_nonce_max_nonces() -> usize {
+    100_000
+}
+
+fn default_nonce_validated_header() -> String {
+    "X-Nonce-Validated".to_string()
+}
+
+/// Upper bound for `NonceFilter::window_seconds`
+const MAX_NONCE_WINDOW_SECONDS: u64 = 86_400;
+
+/// Replay protection on a route: every request carries a nonce the route
+/// hasn't seen within `window_seconds`. Requests without one get a 400,
+/// replays a 409.
+#[derive(Debug, Clone, Deserialize, serde::Serialize, PartialEq)]
+pub struct NonceFilter {
+    /// Request header carrying the nonce
+    #[serde(default = "default_nonce_header")]
+    pub header: String,
+    /// How long a nonce is remembered after its first use
+    #[serde(default = "default_nonce_window_seconds")]
+    pub window_seconds: u64,
+    /// Most nonces remembered at once. New nonces are refused with a 503
+    /// while all of them are still in their window.
+    #[serde(default = "default_nonce_max_nonces")]
+    pub max_nonces: usize,
+    /// Header set to `true` on requests sent upstream with a fresh nonce
+    #[serde(default = "default_nonce_validated_header")]
+    pub validated_header: String,
+}
+
 /// Route filters container
-#[derive(Debug, Clone, Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, serde::Serialize)]
 pub struct RouteFilters {
     pub request_header_modifier: Option<RequestHeaderFilter>,
     pub response_header_modifier: Option<ResponseHeaderFilter>,
     pub url_rewrite: Option<URLRewriteFilter>,
     pub request_redirect: Option<RequestRedirectFilter>,
+    pub request_mirror: Option<RequestMirrorFilter>,
+    pub cors: Option<CorsFilter>,
+    pub rate_limit: Option<RateLimitFilter>,
+    pub nonce: Option<NonceFilter>,
+}
+
+/// A route's `method` match: one method, or any of a list, e.g.
+/// `["GET", "HEAD"]`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum RouteMethod {
+    One(String),
+    Any(Vec<String>),
+}
+
+impl RouteMethod {
+    pub fn methods(&self) -> &[String] {
+        match self {
+            RouteMethod::One(method) => std::slice::from_ref(method),
+            RouteMethod::Any(methods) => methods,
+        }
+    }
 }
 
 /// Maps a URL path pattern to a set of backend pods.
 /// Multiple routes per vhost enable path-based traffic splitting.
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Route {
     pub path_match: Option<PathMatch>,
     #[serde(default)]
-    pub method: Option<String>,
+    pub method: Option<RouteMethod>,
     #[serde(default)]
     pub headers: Vec<HeaderMatch>,
     #[serde(default)]
@@ -222,12 +813,110 @@ pub struct Route {
     /// HTTPRoute namespace/name for X-Gateway-Route header.
     #[serde(default)]
     pub route_name: Option<String>,
-    pub priority: i32,
+    /// Explicit precedence override, higher first. Routes without one count
+    /// as 0 and are otherwise ordered by Gateway API match precedence.
+    #[serde(default)]
+    pub priority: Option<i32>,
     #[serde(default)]
     pub rule_index: i32,
     /// Cache policy from VarnishCachePolicy. None means pass-through (no caching).
     #[serde(default)]
     pub cache_policy: Option<CachePolicy>,
+    /// Backend selection strategy. Defaults to weighted random.
+    #[serde(default)]
+    pub selection: SelectionPolicy,
+    /// Hash key source; required when `selection` is `consistent_hash`.
+    #[serde(default)]
+    pub hash_on: Option<HashSource>,
+    /// Alternative selection computed alongside `selection` and only
+    /// logged, to compare a strategy or weight set before enforcing it.
+    #[serde(default)]
+    pub shadow_selection: Option<ShadowSelection>,
+    /// Replay a sample of requests to a shadow backend and record how its
+    /// responses differ.
+    #[serde(default)]
+    pub shadow_diff: Option<ShadowDiff>,
+    /// Request timeouts. None means the backend's defaults apply.
+    #[serde(default)]
+    pub timeouts: Option<RouteTimeouts>,
+    /// Cookie-based session affinity. Takes precedence over `selection`
+    /// while the pinned backend is still part of the route.
+    #[serde(default)]
+    pub session_persistence: Option<SessionPersistence>,
+    /// Retry policy for failed backend responses. None never retries.
+    #[serde(default)]
+    pub retry: Option<RetryPolicy>,
+    /// Load-shedding class. None inherits the vhost's.
+    #[serde(default)]
+    pub qos: Option<QosClass>,
+    /// Host header sent upstream. None keeps each backend kind's default:
+    /// native backends get the request's Host, external proxies their
+    /// hostname.
+    #[serde(default)]
+    pub forward_host: Option<ForwardHost>,
+    /// Largest request body an external proxy group forwards; larger ones
+    /// are answered 413. None leaves streamed bodies unbounded and buffered
+    /// ones at the built-in 16 MiB cap, which this can only lower.
+    #[serde(default)]
+    pub max_request_body_bytes: Option<u64>,
+}
+
+/// Host header a route sends to its upstream, given in config as
+/// `"preserve"`, `"backend"` or any other string for a literal value.
+///
+/// A URLRewrite hostname filter rewrites the request's Host first, so it is
+/// what `Preserve` sends; `Backend` and `Literal` replace it on the way
+/// upstream, leaving the rewritten Host for VCL and the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ForwardHost {
+    /// The request's Host, as rewritten by the route's filters.
+    Preserve,
+    /// The selected backend's own name: the external proxy hostname, or a
+    /// native backend's BackendTLS hostname or address.
+    Backend,
+    Literal(String),
+}
+
+impl<'de> Deserialize<'de> for ForwardHost {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Ok(match value.as_str() {
+            "preserve" => ForwardHost::Preserve,
+            "backend" => ForwardHost::Backend,
+            _ => ForwardHost::Literal(value),
+        })
+    }
+}
+
+/// Load-shedding class of a route's requests. When the external proxy
+/// concurrency limits fill up, low-priority requests are refused first and
+/// high-priority ones last.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum QosClass {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+impl QosClass {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            QosClass::High => "high",
+            QosClass::Normal => "normal",
+            QosClass::Low => "low",
+        }
+    }
+
+    pub fn from_name(s: &str) -> Option<Self> {
+        match s {
+            "high" => Some(QosClass::High),
+            "normal" => Some(QosClass::Normal),
+            "low" => Some(QosClass::Low),
+            _ => None,
+        }
+    }
 }
 
 /// All routing rules for a single hostname (e.g., "api.example.com").
@@ -237,59 +926,601 @@ pub struct VHost {
     pub routes: Vec<Route>,
     #[serde(default)]
     pub default_backends: Vec<BackendGroup>,
+    /// Load-shedding class of routes without their own, and of
+    /// `default_backends`.
+    #[serde(default)]
+    pub qos: QosClass,
+    /// Static security headers added to every response of this vhost.
+    #[serde(default)]
+    pub security_headers: Option<SecurityHeaders>,
+    /// Reason phrase sent instead of Varnish's default, per response
+    /// status, e.g. `"503": "Down For Maintenance"`.
+    #[serde(default)]
+    pub reason_phrases: BTreeMap<u16, String>,
+    /// Answer 421 Misdirected Request when the TLS SNI of the client
+    /// connection (`sni_header`) selects another vhost, e.g. after HTTP/2
+    /// connection coalescing.
+    #[serde(default)]
+    pub reject_misdirected: bool,
 }
 
-/// Root configuration loaded from ghost.json.
-/// Generated by chaperone, consumed by the ghost VMOD at runtime.
-#[derive(Debug, Clone, Deserialize)]
-pub struct Config {
-    pub version: u32,
+/// Security response headers (CSP, X-Frame-Options, HSTS, ...) managed at
+/// the gateway. Applied through the response header filter of each route,
+/// after the route's own ResponseHeaderModifier, which wins for any header
+/// it names.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SecurityHeaders {
+    /// Header name to value, e.g. `"x-frame-options": "DENY"`.
     #[serde(default)]
-    pub vhosts: HashMap<String, VHost>,
+    pub headers: BTreeMap<String, String>,
+    /// Keep a header the backend already sent instead of replacing it.
+    #[serde(default)]
+    pub preserve_upstream: bool,
 }
 
-/// Load and validate ghost.json from disk.
-/// Returns an empty config if the file doesn't exist yet, allowing Varnish
-/// to start before chaperone has generated the initial configuration.
-pub fn load(path: &Path) -> Result<Config, String> {
-    // If file doesn't exist, return empty config
-    if !path.exists() {
-        return Ok(Config::empty());
-    }
-
-    let content = fs::read_to_string(path)
-        .map_err(|e| format!("failed to read config file {}: {}", path.display(), e))?;
+fn default_max_pending_requests() -> usize {
+    1024
+}
 
-    let config: Config = serde_json::from_str(&content)
-        .map_err(|e| format!("failed to parse config file {}: {}", path.display(), e))?;
+fn default_max_active_streams() -> usize {
+    1024
+}
 
-    validate(&config)?;
+fn default_max_queued_requests() -> usize {
+    1024
+}
 
-    Ok(config)
+/// Settings for the HTTP client behind external proxy backends.
+/// Global rather than per-backend: every external proxy shares one runtime.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExternalClientConfig {
+    /// Requests that may be waiting on upstream response headers at once.
+    /// Beyond this, new requests get a synthetic 503 with Retry-After.
+    #[serde(default = "default_max_pending_requests")]
+    pub max_pending_requests: usize,
+    /// Responses that may be streaming to clients at once. Beyond this, new
+    /// requests get a synthetic 503 with Retry-After.
+    #[serde(default = "default_max_active_streams")]
+    pub max_active_streams: usize,
+    /// How long a request may wait for one of `max_pending_requests`,
+    /// queued fairly across vhosts, before the 503. None: no waiting.
+    #[serde(default)]
+    pub queue_timeout_ms: Option<u64>,
+    /// Requests that may be waiting at once. Beyond this, new requests get
+    /// the 503 right away.
+    #[serde(default = "default_max_queued_requests")]
+    pub max_queued_requests: usize,
+    /// Idle connections kept open per upstream host. 0 disables reuse;
+    /// None keeps no limit.
+    #[serde(default)]
+    pub pool_max_idle_per_host: Option<usize>,
+    /// How long an idle upstream connection is kept. None: 90s.
+    #[serde(default)]
+    pub pool_idle_timeout_ms: Option<u64>,
+    /// Connect timeout of external proxies without their own
+    /// `connect_timeout_ms`. None: 10s.
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u64>,
+    /// Whole-request timeout of external proxies without their own
+    /// `request_timeout_ms`. None: 60s.
+    #[serde(default)]
+    pub request_timeout_ms: Option<u64>,
+    /// Interval of TCP keepalive probes on upstream connections. None
+    /// leaves keepalive off.
+    #[serde(default)]
+    pub tcp_keepalive_ms: Option<u64>,
+    /// Set `TCP_NODELAY` on upstream connections.
+    #[serde(default = "default_tcp_nodelay")]
+    pub tcp_nodelay: bool,
 }
 
-impl Config {
-    /// Bootstrap config for startup when ghost.json hasn't been written yet.
-    /// All requests will 404 until chaperone discovers endpoints and reloads.
-    pub fn empty() -> Self {
-        Config {
-            version: 2,
-            vhosts: HashMap::new(),
+impl Default for ExternalClientConfig {
+    fn default() -> Self {
+        Self {
+            max_pending_requests: default_max_pending_requests(),
+            max_active_streams: default_max_active_streams(),
+            queue_timeout_ms: None,
+            max_queued_requests: default_max_queued_requests(),
+            pool_max_idle_per_host: None,
+            pool_idle_timeout_ms: None,
+            connect_timeout_ms: None,
+            request_timeout_ms: None,
+            tcp_keepalive_ms: None,
+            tcp_nodelay: default_tcp_nodelay(),
         }
     }
 }
 
-/// Catch config errors early rather than failing at request time.
-fn validate(config: &Config) -> Result<(), String> {
-    if config.version != 2 {
-        return Err(format!(
-            "unsupported config version: {} (expected 2)",
-            config.version
-        ));
-    }
+fn default_tcp_nodelay() -> bool {
+    true
+}
 
-    for (hostname, vhost) in &config.vhosts {
-        validate_hostname(hostname)?;
+fn default_outlier_consecutive_failures() -> u32 {
+    5
+}
+
+fn default_outlier_ejection_ms() -> u64 {
+    30_000
+}
+
+/// Upper bound for an outlier ejection, so a typo can't bench a backend for days.
+const MAX_EJECTION_MS: u64 = 3_600_000;
+
+/// Passive outlier detection: backends failing `consecutive_failures`
+/// requests in a row are skipped for `ejection_ms`, doubled for each repeat
+/// ejection up to `max_ejection_ms`.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct OutlierDetectionConfig {
+    #[serde(default = "default_outlier_consecutive_failures")]
+    pub consecutive_failures: u32,
+    #[serde(default = "default_outlier_ejection_ms")]
+    pub ejection_ms: u64,
+    /// Cap on the doubled ejection. None means 5 minutes, or `ejection_ms`
+    /// if that is longer.
+    #[serde(default)]
+    pub max_ejection_ms: Option<u64>,
+}
+
+/// Kinds of hostname match, tried in the order given by `host_match_order`.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HostMatchKind {
+    /// Literal hostname (`api.example.com`).
+    Exact,
+    /// Leading-wildcard hostname (`*.example.com`), most specific first.
+    Wildcard,
+    /// Catch-all vhost (`*`) for routes without hostnames.
+    Default,
+}
+
+fn default_host_match_order() -> Vec<HostMatchKind> {
+    vec![
+        HostMatchKind::Exact,
+        HostMatchKind::Wildcard,
+        HostMatchKind::Default,
+    ]
+}
+
+/// Where the vhost lookup key comes from, for traffic whose Host header
+/// doesn't name the service (e.g. mesh calls routed by SNI).
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum RouteKey {
+    /// Value of the named request header, e.g. `x-service`.
+    Header { name: String },
+    /// First path segment, e.g. `billing` for `/billing/invoices`.
+    PathPrefix,
+}
+
+/// Client TLS fingerprint (e.g. JA3) that VCL computes and ghost forwards.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct TlsFingerprintConfig {
+    /// Request header VCL sets the fingerprint in before `router.recv()`.
+    pub header: String,
+    /// Header the fingerprint is forwarded in. Any instance the client sent
+    /// is replaced, so upstreams can trust it. Defaults to `header`.
+    #[serde(default)]
+    pub forward_as: Option<String>,
+}
+
+impl TlsFingerprintConfig {
+    pub fn forward_header(&self) -> &str {
+        self.forward_as.as_deref().unwrap_or(&self.header)
+    }
+}
+
+/// When routing debug headers (`X-Ghost-Vhost`, `X-Ghost-Route`,
+/// `X-Ghost-Backend`) are added to responses. `true` and `false` are
+/// accepted for `always` and `off`.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(from = "DebugHeadersRepr")]
+pub enum DebugHeaders {
+    #[default]
+    Off,
+    Always,
+    /// Only for requests carrying `X-Ghost-Debug: 1`.
+    OnRequest,
+}
+
+impl DebugHeaders {
+    /// Whether a request gets the headers, given whether it asked for them.
+    pub fn applies(self, requested: bool) -> bool {
+        match self {
+            DebugHeaders::Off => false,
+            DebugHeaders::Always => true,
+            DebugHeaders::OnRequest => requested,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DebugHeadersRepr {
+    Flag(bool),
+    Mode(DebugHeadersMode),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum DebugHeadersMode {
+    Off,
+    Always,
+    OnRequest,
+}
+
+impl From<DebugHeadersRepr> for DebugHeaders {
+    fn from(repr: DebugHeadersRepr) -> Self {
+        match repr {
+            DebugHeadersRepr::Flag(false) | DebugHeadersRepr::Mode(DebugHeadersMode::Off) => {
+                DebugHeaders::Off
+            }
+            DebugHeadersRepr::Flag(true) | DebugHeadersRepr::Mode(DebugHeadersMode::Always) => {
+                DebugHeaders::Always
+            }
+            DebugHeadersRepr::Mode(DebugHeadersMode::OnRequest) => DebugHeaders::OnRequest,
+        }
+    }
+}
+
+/// How much of each routing decision is logged to VSL.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingLog {
+    #[default]
+    Off,
+    /// One `VCL_Log` line per routed request: vhost, rule, path match,
+    /// backend and selection policy.
+    Decisions,
+    /// Decision lines that also name the filters the route applied.
+    Full,
+}
+
+/// Marking responses to authorized requests private.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthorizedPrivate {
+    /// Request headers that make a request authorized. Any one of them
+    /// being present is enough.
+    #[serde(default = "default_authorized_private_headers")]
+    pub headers: Vec<String>,
+}
+
+fn default_authorized_private_headers() -> Vec<String> {
+    vec!["Authorization".to_string()]
+}
+
+/// Trace context propagation and request IDs.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TracingConfig {
+    /// Generate a W3C `traceparent` and an `X-Request-Id` for requests
+    /// without a valid one, echo the request ID on responses and log both
+    /// IDs to VSL.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Header set to an ID of the client connection each request came in
+    /// on, sent to backends for log correlation. Not set when absent.
+    #[serde(default)]
+    pub connection_id_header: Option<String>,
+    /// Share of requests without a valid incoming `traceparent` whose
+    /// generated one is sampled, 0-100. Incoming ones keep their own
+    /// sampled flag. Only sampled requests have their IDs logged.
+    #[serde(default = "default_trace_sample_percent")]
+    pub sample_percent: f64,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            enabled: false,
+            connection_id_header: None,
+            sample_percent: default_trace_sample_percent(),
+        }
+    }
+}
+
+fn default_trace_sample_percent() -> f64 {
+    100.0
+}
+
+/// Root configuration loaded from ghost.json.
+/// Generated by chaperone, consumed by the ghost VMOD at runtime.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    pub version: u32,
+    #[serde(default)]
+    pub vhosts: HashMap<String, VHost>,
+    #[serde(default)]
+    pub external_client: ExternalClientConfig,
+    /// Order in which hostname match kinds are tried. Defaults to
+    /// exact, wildcard, then the catch-all vhost.
+    #[serde(default = "default_host_match_order")]
+    pub host_match_order: Vec<HostMatchKind>,
+    /// Passive outlier detection. Disabled when absent.
+    #[serde(default)]
+    pub outlier_detection: Option<OutlierDetectionConfig>,
+    /// Client TLS fingerprint forwarding. Disabled when absent.
+    #[serde(default)]
+    pub tls_fingerprint: Option<TlsFingerprintConfig>,
+    /// Vhost lookup key used instead of Host. Requests without it are
+    /// matched on Host as usual.
+    #[serde(default)]
+    pub route_key: Option<RouteKey>,
+    /// Request header VCL sets to the TLS SNI of the client connection
+    /// before `recv()`. Required by `reject_misdirected`.
+    #[serde(default)]
+    pub sni_header: Option<String>,
+    /// Routing debug response headers. Off when absent.
+    #[serde(default)]
+    pub debug_headers: DebugHeaders,
+    /// VSL logging of routing decisions. Off when absent.
+    #[serde(default)]
+    pub routing_log: RoutingLog,
+    /// Trace context and request IDs. Disabled when absent.
+    #[serde(default)]
+    pub tracing: TracingConfig,
+    /// `Cache-Control: private` for responses to authorized requests that
+    /// carry no caching directives. Off when absent.
+    #[serde(default)]
+    pub authorized_private: Option<AuthorizedPrivate>,
+    /// How often backend DNS names are looked up again, in milliseconds.
+    /// 30 seconds when absent.
+    #[serde(default)]
+    pub dns_refresh_ms: Option<u64>,
+    /// Topology zone the gateway runs in. Backend groups whose backends all
+    /// carry topology hints are narrowed to the ones hinted for it.
+    #[serde(default)]
+    pub zone: Option<String>,
+    /// Routes whose `method` accepts GET accept HEAD as well. Gateway API
+    /// leaves this to implementations; off when absent.
+    #[serde(default)]
+    pub head_with_get: bool,
+}
+
+/// Load and validate ghost.json from disk.
+/// Returns an empty config if the file doesn't exist yet, allowing Varnish
+/// to start before chaperone has generated the initial configuration.
+pub fn load(path: &Path) -> Result<Config, String> {
+    // If file doesn't exist, return empty config
+    if !path.exists() {
+        return Ok(Config::empty());
+    }
+
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("failed to read config file {}: {}", path.display(), e))?;
+
+    let mut config = parse(&content)
+        .map_err(|e| format!("failed to parse config file {}: {}", path.display(), e))?;
+
+    validate(&config)?;
+    load_signing_secrets(&mut config)?;
+
+    Ok(config)
+}
+
+/// Versions of the ghost.json schema `load()` reads. All of them produce the
+/// same `Config`:
+/// - 1: simple vhosts only, `{"backends": [<group>, ...]}` per hostname,
+///   with optional per-method groups in `"methods"`.
+/// - 2: routed vhosts only, with `routes` and `default_backends`.
+/// - 3: either kind of vhost, mixed in one file.
+const CONFIG_VERSIONS: [u32; 3] = [1, 2, 3];
+
+/// Just the `version` field, read first to pick the schema
+#[derive(Deserialize)]
+struct ConfigVersion {
+    version: u32,
+}
+
+/// Host-only vhost of config versions 1 and 3: every request for the
+/// hostname goes to these backend groups, unless `methods` names other
+/// groups for its method.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SimpleVHost {
+    backends: Vec<BackendGroup>,
+    /// Backend groups by request method, e.g. `{"POST": [...]}`. Each
+    /// becomes a route matching only that method.
+    #[serde(default)]
+    methods: BTreeMap<String, Vec<BackendGroup>>,
+}
+
+impl From<SimpleVHost> for VHost {
+    fn from(simple: SimpleVHost) -> Self {
+        let routes = simple
+            .methods
+            .into_iter()
+            .enumerate()
+            .map(|(i, (method, backend_groups))| Route {
+                method: Some(RouteMethod::One(method)),
+                backend_groups,
+                rule_index: i as i32,
+                ..Route::default()
+            })
+            .collect();
+        VHost {
+            routes,
+            default_backends: simple.backends,
+            qos: QosClass::default(),
+            security_headers: None,
+            reason_phrases: BTreeMap::new(),
+            reject_misdirected: false,
+        }
+    }
+}
+
+/// Parse ghost.json content of any supported version into a `Config`.
+/// Unsupported versions parse, and are refused by `validate()`.
+fn parse(content: &str) -> Result<Config, String> {
+    let ConfigVersion { version } = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    if version == 2 || !CONFIG_VERSIONS.contains(&version) {
+        return serde_json::from_str(content).map_err(|e| e.to_string());
+    }
+
+    // Versions 1 and 3: vhosts are deserialized one by one, by kind
+    let mut root: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(content).map_err(|e| e.to_string())?;
+    let vhosts = match root.remove("vhosts") {
+        Some(serde_json::Value::Object(vhosts)) => vhosts,
+        Some(_) => return Err("vhosts: expected an object".to_string()),
+        None => serde_json::Map::new(),
+    };
+    let mut config: Config =
+        serde_json::from_value(serde_json::Value::Object(root)).map_err(|e| e.to_string())?;
+    for (hostname, value) in vhosts {
+        let vhost = parse_vhost(version, value).map_err(|e| format!("{}: {}", hostname, e))?;
+        config.vhosts.insert(hostname, vhost);
+    }
+    Ok(config)
+}
+
+/// Parse one vhost of a version 1 or 3 config. A vhost with `backends` is
+/// simple; in version 3 any other is routed as in version 2, `routes`
+/// defaulting to none.
+fn parse_vhost(version: u32, value: serde_json::Value) -> Result<VHost, String> {
+    let mut value = match value {
+        serde_json::Value::Object(map) => map,
+        _ => return Err("expected an object".to_string()),
+    };
+    if value.contains_key("backends") || version == 1 {
+        let simple: SimpleVHost = serde_json::from_value(serde_json::Value::Object(value))
+            .map_err(|e| format!("simple vhost: {}", e))?;
+        return Ok(simple.into());
+    }
+    value
+        .entry("routes")
+        .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+    serde_json::from_value(serde_json::Value::Object(value)).map_err(|e| e.to_string())
+}
+
+impl Config {
+    /// Bootstrap config for startup when ghost.json hasn't been written yet.
+    /// All requests will 404 until chaperone discovers endpoints and reloads.
+    pub fn empty() -> Self {
+        Config {
+            version: 2,
+            vhosts: HashMap::new(),
+            external_client: ExternalClientConfig::default(),
+            host_match_order: default_host_match_order(),
+            outlier_detection: None,
+            tls_fingerprint: None,
+            route_key: None,
+            sni_header: None,
+            debug_headers: DebugHeaders::Off,
+            routing_log: RoutingLog::Off,
+            tracing: TracingConfig::default(),
+            authorized_private: None,
+            dns_refresh_ms: None,
+            zone: None,
+            head_with_get: false,
+        }
+    }
+}
+
+/// Every external proxy target in the config, route groups and defaults alike.
+fn external_proxies(config: &Config) -> impl Iterator<Item = &ExternalProxy> {
+    config.vhosts.values().flat_map(|vhost| {
+        vhost
+            .routes
+            .iter()
+            .flat_map(|r| r.backend_groups.iter())
+            .chain(vhost.default_backends.iter())
+            .filter_map(|g| g.external_proxy.as_ref())
+    })
+}
+
+/// Read each signing secret from its `secret_ref` file. Done at load time so a
+/// missing secret fails the reload instead of the requests.
+fn load_signing_secrets(config: &mut Config) -> Result<(), String> {
+    let groups = config.vhosts.values_mut().flat_map(|vhost| {
+        vhost
+            .routes
+            .iter_mut()
+            .flat_map(|r| r.backend_groups.iter_mut())
+            .chain(vhost.default_backends.iter_mut())
+    });
+    for group in groups {
+        let Some(ep) = group.external_proxy.as_mut() else {
+            continue;
+        };
+        let Some(signing) = ep.signing.as_mut() else {
+            continue;
+        };
+        let mut secret = fs::read(&signing.secret_ref).map_err(|e| {
+            format!(
+                "external_proxy {}: failed to read signing secret {}: {}",
+                ep.hostname, signing.secret_ref, e
+            )
+        })?;
+        // Secret files are usually written with a trailing newline.
+        while secret.last().is_some_and(|b| *b == b'\n' || *b == b'\r') {
+            secret.pop();
+        }
+        if secret.is_empty() {
+            return Err(format!(
+                "external_proxy {}: signing secret {} is empty",
+                ep.hostname, signing.secret_ref
+            ));
+        }
+        signing.secret = secret;
+    }
+    Ok(())
+}
+
+/// Catch config errors early rather than failing at request time.
+fn validate(config: &Config) -> Result<(), String> {
+    if !CONFIG_VERSIONS.contains(&config.version) {
+        return Err(format!(
+            "unsupported config version: {} (expected 1, 2 or 3)",
+            config.version
+        ));
+    }
+
+    validate_host_match_order(&config.host_match_order)?;
+
+    if let Some(ref fp) = config.tls_fingerprint {
+        validate_tls_fingerprint(fp)?;
+    }
+    if let Some(RouteKey::Header { ref name }) = config.route_key {
+        validate_route_key_header(name)?;
+    }
+    if let Some(ref name) = config.tracing.connection_id_header {
+        validate_connection_id_header(name)?;
+    }
+    if !(0.0..=100.0).contains(&config.tracing.sample_percent) {
+        return Err("tracing.sample_percent must be between 0 and 100".to_string());
+    }
+    if let Some(ref private) = config.authorized_private {
+        validate_authorized_private(private)?;
+    }
+    if config.zone.as_deref() == Some("") {
+        return Err("zone cannot be empty".to_string());
+    }
+    match config.dns_refresh_ms {
+        Some(0) => return Err("dns_refresh_ms must be greater than 0".to_string()),
+        Some(ms) if ms > MAX_ROUTE_TIMEOUT_MS => {
+            return Err(format!(
+                "dns_refresh_ms too large ({} ms, max {})",
+                ms, MAX_ROUTE_TIMEOUT_MS
+            ))
+        }
+        _ => {}
+    }
+    if let Some(ref name) = config.sni_header {
+        validate_sni_header(name)?;
+    }
+
+    if config.external_client.max_pending_requests == 0 {
+        return Err("external_client.max_pending_requests must be greater than 0".to_string());
+    }
+    if config.external_client.max_active_streams == 0 {
+        return Err("external_client.max_active_streams must be greater than 0".to_string());
+    }
+    validate_external_client(&config.external_client)?;
+    if let Some(ref od) = config.outlier_detection {
+        validate_outlier_detection(od)?;
+    }
+
+    for (hostname, vhost) in &config.vhosts {
+        validate_hostname(hostname)?;
 
         for (i, route) in vhost.routes.iter().enumerate() {
             let route_ctx = format!("{} route {}", hostname, i);
@@ -304,7 +1535,12 @@ fn validate(config: &Config) -> Result<(), String> {
             }
 
             if let Some(ref method) = route.method {
-                validate_method(method, &route_ctx)?;
+                if method.methods().is_empty() {
+                    return Err(format!("{}: method list cannot be empty", route_ctx));
+                }
+                for method in method.methods() {
+                    validate_method(method, &route_ctx)?;
+                }
             }
 
             for (j, header) in route.headers.iter().enumerate() {
@@ -316,6 +1552,63 @@ fn validate(config: &Config) -> Result<(), String> {
                 let qp_ctx = format!("{} query_param {}", route_ctx, j);
                 validate_query_param_match(qp, &qp_ctx)?;
             }
+
+            validate_selection(route, &route_ctx)?;
+
+            if let Some(ref timeouts) = route.timeouts {
+                validate_timeouts(timeouts, &route_ctx)?;
+            }
+
+            if let Some(ref sp) = route.session_persistence {
+                validate_session_persistence(sp, &route_ctx)?;
+            }
+
+            if let Some(ref retry) = route.retry {
+                validate_retry(retry, &route_ctx)?;
+            }
+
+            if let Some(cache_key) = route
+                .cache_policy
+                .as_ref()
+                .and_then(|cp| cp.cache_key.as_ref())
+            {
+                validate_cache_key(cache_key, &route_ctx)?;
+            }
+
+            if let Some(ForwardHost::Literal(ref host)) = route.forward_host {
+                validate_forward_host(host, &route_ctx)?;
+            }
+
+            if route.max_request_body_bytes == Some(0) {
+                return Err(format!(
+                    "{}: max_request_body_bytes must be greater than 0",
+                    route_ctx
+                ));
+            }
+
+            if let Some(mirror) = route
+                .filters
+                .as_ref()
+                .and_then(|f| f.request_mirror.as_ref())
+            {
+                validate_request_mirror(mirror, &route_ctx)?;
+            }
+
+            if let Some(cors) = route.filters.as_ref().and_then(|f| f.cors.as_ref()) {
+                validate_cors(cors, &route_ctx)?;
+            }
+
+            if let Some(limit) = route.filters.as_ref().and_then(|f| f.rate_limit.as_ref()) {
+                validate_rate_limit(limit, &route_ctx)?;
+            }
+
+            if let Some(nonce) = route.filters.as_ref().and_then(|f| f.nonce.as_ref()) {
+                validate_nonce(nonce, &route_ctx)?;
+            }
+
+            if let Some(diff) = &route.shadow_diff {
+                validate_shadow_diff(diff, &route_ctx)?;
+            }
         }
 
         for (g, group) in vhost.default_backends.iter().enumerate() {
@@ -324,9 +1617,20 @@ fn validate(config: &Config) -> Result<(), String> {
                 group,
             )?;
         }
+
+        if vhost.reject_misdirected && config.sni_header.is_none() {
+            return Err(format!(
+                "{}: reject_misdirected requires sni_header",
+                hostname
+            ));
+        }
+        if let Some(ref sh) = vhost.security_headers {
+            validate_security_headers(sh, hostname)?;
+        }
+        validate_reason_phrases(&vhost.reason_phrases, hostname)?;
     }
 
-    Ok(())
+    validate_external_proxy_consistency(config)
 }
 
 /// Ensure vhost keys conform to Gateway API hostname rules.
@@ -366,6 +1670,10 @@ fn validate_hostname(hostname: &str) -> Result<(), String> {
 /// Validate a backend group: either a native group with backends, or an
 /// external proxy group. The two modes are mutually exclusive.
 fn validate_backend_group(context: &str, group: &BackendGroup) -> Result<(), String> {
+    if let Some(ref limit) = group.outbound_rate_limit {
+        validate_outbound_rate_limit(limit)
+            .map_err(|e| format!("{}: outbound_rate_limit: {}", context, e))?;
+    }
     if let Some(ref ep) = group.external_proxy {
         if !group.backends.is_empty() {
             return Err(format!(
@@ -379,43 +1687,352 @@ fn validate_backend_group(context: &str, group: &BackendGroup) -> Result<(), Str
         if ep.port == 0 {
             return Err(format!("{}: external_proxy.port cannot be 0", context));
         }
+        if let Some(ref signing) = ep.signing {
+            validate_signing(signing)
+                .map_err(|e| format!("{}: external_proxy.signing: {}", context, e))?;
+        }
+        if ep.decompress_request_body && ep.compress_request.as_ref().is_some_and(|c| c.enabled) {
+            return Err(format!(
+                "{}: external_proxy.compress_request and decompress_request_body are mutually exclusive",
+                context
+            ));
+        }
+        for (name, value) in [
+            ("connect_timeout_ms", ep.connect_timeout_ms),
+            ("request_timeout_ms", ep.request_timeout_ms),
+            ("header_timeout_ms", ep.header_timeout_ms),
+            ("recv_timeout_ms", ep.recv_timeout_ms),
+        ] {
+            match value {
+                Some(0) => {
+                    return Err(format!(
+                        "{}: external_proxy.{} must be greater than 0",
+                        context, name
+                    ))
+                }
+                Some(ms) if ms > MAX_ROUTE_TIMEOUT_MS => {
+                    return Err(format!(
+                        "{}: external_proxy.{} too large ({} ms, max {})",
+                        context, name, ms, MAX_ROUTE_TIMEOUT_MS
+                    ))
+                }
+                _ => {}
+            }
+        }
+        if ep.tls && ep.protocol == UpstreamProtocol::H2c {
+            return Err(format!(
+                "{}: external_proxy.protocol h2c is cleartext and can't be used with tls",
+                context
+            ));
+        }
+        if !ep.tls && (ep.sni.is_some() || ep.insecure_skip_verify) {
+            return Err(format!(
+                "{}: external_proxy.sni and insecure_skip_verify require tls",
+                context
+            ));
+        }
+        if let Some(ref sni) = ep.sni {
+            if !is_dns_name(sni) {
+                return Err(format!(
+                    "{}: external_proxy.sni must be a DNS name, got '{}'",
+                    context, sni
+                ));
+            }
+        }
+        for (field, names) in [
+            ("coalesce_request_headers", &ep.coalesce_request_headers),
+            ("coalesce_response_headers", &ep.coalesce_response_headers),
+        ] {
+            validate_coalesced_headers(names)
+                .map_err(|e| format!("{}: external_proxy.{}: {}", context, field, e))?;
+        }
         return Ok(());
     }
+    if group.backend_tls.is_some() && group.backends.iter().any(|b| b.health.is_some()) {
+        return Err(format!(
+            "{}: health checks are not supported with backend_tls",
+            context
+        ));
+    }
     validate_backends(context, &group.backends)
 }
 
-/// Guard against misconfigured endpoints that would cause request failures.
-/// Empty addresses or zero ports indicate EndpointSlice corruption or
-/// chaperone bugs that should be surfaced at config load, not at request time.
-fn validate_backends(context: &str, backends: &[Backend]) -> Result<(), String> {
-    for (i, backend) in backends.iter().enumerate() {
-        if backend.address.is_empty() {
-            return Err(format!(
-                "backend {} in '{}': address cannot be empty",
-                i, context
-            ));
-        }
-        if backend.port == 0 {
-            return Err(format!("backend {} in '{}': port cannot be 0", i, context));
-        }
-        // weight=0 is valid per Gateway API spec (means "no traffic")
+fn validate_outbound_rate_limit(limit: &OutboundRateLimit) -> Result<(), String> {
+    if limit.rps == 0 {
+        return Err("rps must be greater than 0".to_string());
+    }
+    if limit.burst == Some(0) {
+        return Err("burst must be greater than 0".to_string());
+    }
+    if limit.max_wait_ms > MAX_RATE_LIMIT_WAIT_MS {
+        return Err(format!(
+            "max_wait_ms too large ({} ms, max {})",
+            limit.max_wait_ms, MAX_RATE_LIMIT_WAIT_MS
+        ));
     }
     Ok(())
 }
 
-/// Enforce Gateway API path constraints and catch regex errors early.
-/// Invalid regexes would panic at match time; malformed paths indicate
-/// operator bugs that should fail loudly during config reload.
-fn validate_path_match(path_match: &PathMatch, context: &str) -> Result<(), String> {
-    match path_match.match_type {
-        PathMatchType::Exact | PathMatchType::PathPrefix => {
-            // Paths must start with /
-            if !path_match.value.starts_with('/') {
-                return Err(format!(
-                    "{}: path '{}' must start with /",
-                    context, path_match.value
-                ));
-            }
+/// Validate the signing key id and header name
+fn validate_signing(signing: &RequestSigning) -> Result<(), String> {
+    if signing.key_id.is_empty()
+        || !signing
+            .key_id
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && b != b',' && b != b'=')
+    {
+        return Err(format!("invalid key_id '{}'", signing.key_id));
+    }
+    if signing.secret_ref.is_empty() {
+        return Err("secret_ref cannot be empty".to_string());
+    }
+    let is_token = !signing.header.is_empty()
+        && signing
+            .header
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    let lower = signing.header.to_ascii_lowercase();
+    if !is_token || lower == "host" || lower.starts_with("x-ghost-") {
+        return Err(format!("invalid header '{}'", signing.header));
+    }
+    Ok(())
+}
+
+/// Whether `name` can be sent as a TLS server name: a DNS name, not an IP
+/// address literal.
+fn is_dns_name(name: &str) -> bool {
+    name.parse::<std::net::IpAddr>().is_err()
+        && !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// A literal `forward_host` must be a valid Host header value: a DNS name or
+/// IP address, optionally followed by a port.
+fn validate_forward_host(host: &str, context: &str) -> Result<(), String> {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if !name.contains(':') || name.ends_with(']') => {
+            if port.parse::<u16>().is_err() {
+                return Err(format!(
+                    "{}: invalid port in forward_host '{}'",
+                    context, host
+                ));
+            }
+            name
+        }
+        _ => host,
+    };
+    let ip_literal = name
+        .strip_prefix('[')
+        .and_then(|n| n.strip_suffix(']'))
+        .is_some_and(|n| n.parse::<std::net::Ipv6Addr>().is_ok());
+    if !(ip_literal || name.parse::<std::net::Ipv4Addr>().is_ok() || is_dns_name(name)) {
+        return Err(format!("{}: invalid forward_host '{}'", context, host));
+    }
+    Ok(())
+}
+
+/// All groups naming the same upstream share one backend, so they must
+/// agree on how it signs requests, on its timeouts, on its TLS settings and
+/// on request body handling.
+fn validate_external_proxy_consistency(config: &Config) -> Result<(), String> {
+    let mut seen: HashMap<(&str, u16, bool), &ExternalProxy> = HashMap::new();
+    for ep in external_proxies(config) {
+        let key = (ep.hostname.as_str(), ep.port, ep.tls);
+        let Some(prev) = seen.get(&key) else {
+            seen.insert(key, ep);
+            continue;
+        };
+        if prev.signing != ep.signing {
+            return Err(format!(
+                "external_proxy {}:{}: conflicting signing settings",
+                ep.hostname, ep.port
+            ));
+        }
+        if prev.connect_timeout_ms != ep.connect_timeout_ms
+            || prev.request_timeout_ms != ep.request_timeout_ms
+            || prev.header_timeout_ms != ep.header_timeout_ms
+            || prev.recv_timeout_ms != ep.recv_timeout_ms
+            || prev.adaptive_connect_timeout != ep.adaptive_connect_timeout
+        {
+            return Err(format!(
+                "external_proxy {}:{}: conflicting timeouts",
+                ep.hostname, ep.port
+            ));
+        }
+        if prev.protocol != ep.protocol {
+            return Err(format!(
+                "external_proxy {}:{}: conflicting protocol",
+                ep.hostname, ep.port
+            ));
+        }
+        if prev.sni != ep.sni || prev.insecure_skip_verify != ep.insecure_skip_verify {
+            return Err(format!(
+                "external_proxy {}:{}: conflicting TLS settings",
+                ep.hostname, ep.port
+            ));
+        }
+        if prev.decompress_request_body != ep.decompress_request_body {
+            return Err(format!(
+                "external_proxy {}:{}: conflicting decompress_request_body",
+                ep.hostname, ep.port
+            ));
+        }
+        if prev.decompress_response != ep.decompress_response {
+            return Err(format!(
+                "external_proxy {}:{}: conflicting decompress_response",
+                ep.hostname, ep.port
+            ));
+        }
+        if prev.compress_request != ep.compress_request {
+            return Err(format!(
+                "external_proxy {}:{}: conflicting compress_request",
+                ep.hostname, ep.port
+            ));
+        }
+        if prev.coalesce_request_headers != ep.coalesce_request_headers
+            || prev.coalesce_response_headers != ep.coalesce_response_headers
+        {
+            return Err(format!(
+                "external_proxy {}:{}: conflicting header coalescing",
+                ep.hostname, ep.port
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Validate the client timeouts of `external_client`.
+fn validate_external_client(client: &ExternalClientConfig) -> Result<(), String> {
+    for (name, value) in [
+        ("pool_idle_timeout_ms", client.pool_idle_timeout_ms),
+        ("connect_timeout_ms", client.connect_timeout_ms),
+        ("request_timeout_ms", client.request_timeout_ms),
+        ("tcp_keepalive_ms", client.tcp_keepalive_ms),
+        ("queue_timeout_ms", client.queue_timeout_ms),
+    ] {
+        match value {
+            Some(0) => return Err(format!("external_client.{} must be greater than 0", name)),
+            Some(ms) if ms > MAX_ROUTE_TIMEOUT_MS => {
+                return Err(format!(
+                    "external_client.{} too large ({} ms, max {})",
+                    name, ms, MAX_ROUTE_TIMEOUT_MS
+                ))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Validate the header names an external proxy coalesces. `Set-Cookie`
+/// values may contain commas, so its instances can't be joined.
+fn validate_coalesced_headers(names: &[String]) -> Result<(), String> {
+    for name in names {
+        if !is_header_name(name) {
+            return Err(format!("invalid header name '{}'", name));
+        }
+        if name.eq_ignore_ascii_case("set-cookie") {
+            return Err("set-cookie can't be coalesced".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Validate health check timing and expected status
+fn validate_health_check(health: &HealthCheck) -> Result<(), String> {
+    if !health.path.starts_with('/') {
+        return Err(format!("path must start with '/', got '{}'", health.path));
+    }
+    if health.interval_ms == 0 {
+        return Err("interval_ms must be greater than 0".to_string());
+    }
+    if health.timeout_ms == 0 || health.timeout_ms > health.interval_ms {
+        return Err(format!(
+            "timeout_ms must be between 1 and interval_ms ({}), got {}",
+            health.interval_ms, health.timeout_ms
+        ));
+    }
+    if !(100..=599).contains(&health.expected_status) {
+        return Err(format!(
+            "expected_status must be a valid HTTP status, got {}",
+            health.expected_status
+        ));
+    }
+    for (name, value) in [
+        ("healthy_threshold", health.healthy_threshold),
+        ("unhealthy_threshold", health.unhealthy_threshold),
+    ] {
+        if value == 0 || value > MAX_HEALTH_THRESHOLD {
+            return Err(format!(
+                "{} must be between 1 and {}, got {}",
+                name, MAX_HEALTH_THRESHOLD, value
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Guard against misconfigured endpoints that would cause request failures.
+/// Empty addresses or zero ports indicate EndpointSlice corruption or
+/// chaperone bugs that should be surfaced at config load, not at request time.
+fn validate_backends(context: &str, backends: &[Backend]) -> Result<(), String> {
+    for (i, backend) in backends.iter().enumerate() {
+        if backend.address.is_empty() {
+            return Err(format!(
+                "backend {} in '{}': address cannot be empty",
+                i, context
+            ));
+        }
+        if backend.address.parse::<std::net::IpAddr>().is_err() && !is_dns_name(&backend.address) {
+            return Err(format!(
+                "backend {} in '{}': invalid address '{}'",
+                i, context, backend.address
+            ));
+        }
+        if backend.port == 0 {
+            return Err(format!("backend {} in '{}': port cannot be 0", i, context));
+        }
+        if let Some(ref health) = backend.health {
+            validate_health_check(health)
+                .map_err(|e| format!("backend {} in '{}': health.{}", i, context, e))?;
+        }
+        if backend
+            .hints
+            .as_ref()
+            .is_some_and(|h| h.for_zones.iter().any(String::is_empty))
+        {
+            return Err(format!(
+                "backend {} in '{}': hints.for_zones cannot contain an empty zone",
+                i, context
+            ));
+        }
+        // weight=0 is valid per Gateway API spec (means "no traffic")
+    }
+    Ok(())
+}
+
+/// Enforce Gateway API path constraints and catch regex errors early.
+/// Invalid regexes would panic at match time; malformed paths indicate
+/// operator bugs that should fail loudly during config reload.
+fn validate_path_match(path_match: &PathMatch, context: &str) -> Result<(), String> {
+    match path_match.match_type {
+        PathMatchType::Exact | PathMatchType::PathPrefix => {
+            // Paths must start with /
+            if !path_match.value.starts_with('/') {
+                return Err(format!(
+                    "{}: path '{}' must start with /",
+                    context, path_match.value
+                ));
+            }
             // No consecutive slashes
             if path_match.value.contains("//") {
                 return Err(format!(
@@ -452,95 +2069,750 @@ fn validate_path_match(path_match: &PathMatch, context: &str) -> Result<(), Stri
     Ok(())
 }
 
-/// Validate HTTP method
-fn validate_method(method: &str, context: &str) -> Result<(), String> {
-    const VALID_METHODS: &[&str] = &[
-        "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
-    ];
-    if !VALID_METHODS.contains(&method) {
-        return Err(format!("{}: invalid method '{}'", context, method));
+/// Validate that host_match_order lists every match kind exactly once
+fn validate_host_match_order(order: &[HostMatchKind]) -> Result<(), String> {
+    for kind in default_host_match_order() {
+        let count = order.iter().filter(|k| **k == kind).count();
+        if count != 1 {
+            return Err(format!(
+                "host_match_order: {:?} must appear exactly once (found {})",
+                kind, count
+            ));
+        }
     }
     Ok(())
 }
 
-/// Validate header match configuration
-fn validate_header_match(header: &HeaderMatch, context: &str) -> Result<(), String> {
-    if header.name.is_empty() {
-        return Err(format!("{}: header name cannot be empty", context));
+/// Validate that the selection policy has the inputs it needs
+fn validate_selection(route: &Route, context: &str) -> Result<(), String> {
+    let shadow_hash = route
+        .shadow_selection
+        .as_ref()
+        .is_some_and(ShadowSelection::uses_hash);
+    match (&route.selection, &route.hash_on) {
+        (SelectionPolicy::ConsistentHash, None) => {
+            return Err(format!(
+                "{}: consistent_hash selection requires hash_on",
+                context
+            ));
+        }
+        (_, None) if shadow_hash => {
+            return Err(format!(
+                "{}: consistent_hash shadow_selection requires hash_on",
+                context
+            ));
+        }
+        (SelectionPolicy::ConsistentHash, Some(_)) => {}
+        (_, Some(_)) if shadow_hash => {}
+        (_, Some(_)) => {
+            return Err(format!(
+                "{}: hash_on is only valid with consistent_hash selection",
+                context
+            ));
+        }
+        (_, None) => {}
     }
-    if header.value.is_empty() {
-        return Err(format!("{}: header value cannot be empty", context));
+    if let Some(shadow) = &route.shadow_selection {
+        if shadow.selection.is_none() && shadow.weights.is_none() {
+            return Err(format!(
+                "{}: shadow_selection needs a selection or weights to compare",
+                context
+            ));
+        }
+        if let Some(weights) = &shadow.weights {
+            if weights.len() != route.backend_groups.len() {
+                return Err(format!(
+                    "{}: shadow_selection has {} weights for {} backend groups",
+                    context,
+                    weights.len(),
+                    route.backend_groups.len()
+                ));
+            }
+        }
     }
-    if header.value.len() > 4096 {
+    match &route.hash_on {
+        Some(HashSource::Header { name }) | Some(HashSource::Cookie { name })
+            if name.is_empty() =>
+        {
+            Err(format!("{}: hash_on name cannot be empty", context))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Validate outlier detection thresholds are non-zero and within a sane bound
+fn validate_outlier_detection(od: &OutlierDetectionConfig) -> Result<(), String> {
+    if od.consecutive_failures == 0 {
+        return Err("outlier_detection.consecutive_failures must be greater than 0".to_string());
+    }
+    if od.ejection_ms == 0 || od.ejection_ms > MAX_EJECTION_MS {
         return Err(format!(
-            "{}: header value too long ({} chars, max 4096)",
-            context,
-            header.value.len()
+            "outlier_detection.ejection_ms must be between 1 and {}, got {}",
+            MAX_EJECTION_MS, od.ejection_ms
         ));
     }
-    if header.match_type == MatchType::RegularExpression {
-        // Regex compilation deferred - see detailed comment in validate_path_match()
+    if let Some(max) = od.max_ejection_ms {
+        if max < od.ejection_ms || max > MAX_EJECTION_MS {
+            return Err(format!(
+                "outlier_detection.max_ejection_ms must be between ejection_ms ({}) and {}, got {}",
+                od.ejection_ms, MAX_EJECTION_MS, max
+            ));
+        }
     }
     Ok(())
 }
 
-/// Validate query parameter match configuration
-fn validate_query_param_match(qp: &QueryParamMatch, context: &str) -> Result<(), String> {
-    if qp.name.is_empty() {
-        return Err(format!("{}: query param name cannot be empty", context));
+/// Validate cache key headers. The cache key is also what Varnish coalesces
+/// concurrent misses on, so a header that can never vary the key would
+/// merge requests that need different responses.
+fn validate_cache_key(cache_key: &CacheKeyConfig, context: &str) -> Result<(), String> {
+    for name in &cache_key.headers {
+        if !is_header_name(name) {
+            return Err(format!(
+                "{}: cache_key: invalid header name '{}'",
+                context, name
+            ));
+        }
+        // Stripped before routing, so it would never vary the key
+        if crate::vhost_director::is_internal_header(name) {
+            return Err(format!(
+                "{}: cache_key: header '{}' is internal",
+                context, name
+            ));
+        }
     }
-    if qp.value.is_empty() {
-        return Err(format!("{}: query param value cannot be empty", context));
+    Ok(())
+}
+
+/// Validate route timeouts are non-zero and within a sane bound
+fn validate_timeouts(timeouts: &RouteTimeouts, context: &str) -> Result<(), String> {
+    for (name, value) in [
+        ("request_ms", timeouts.request_ms),
+        ("backend_request_ms", timeouts.backend_request_ms),
+    ] {
+        match value {
+            Some(0) => {
+                return Err(format!(
+                    "{}: timeouts.{} must be greater than 0",
+                    context, name
+                ))
+            }
+            Some(ms) if ms > MAX_ROUTE_TIMEOUT_MS => {
+                return Err(format!(
+                    "{}: timeouts.{} too large ({} ms, max {})",
+                    context, name, ms, MAX_ROUTE_TIMEOUT_MS
+                ))
+            }
+            _ => {}
+        }
     }
-    if qp.value.len() > 1024 {
+    if timeouts.expose_budget_header && timeouts.request_ms.is_none() {
         return Err(format!(
-            "{}: query param value too long ({} chars, max 1024)",
-            context,
-            qp.value.len()
+            "{}: timeouts.expose_budget_header requires timeouts.request_ms",
+            context
         ));
     }
-    if qp.match_type == MatchType::RegularExpression {
-        // Regex compilation deferred - see detailed comment in validate_path_match()
+    Ok(())
+}
+
+/// Validate the affinity cookie name is a valid cookie token (RFC 6265)
+fn validate_session_persistence(sp: &SessionPersistence, context: &str) -> Result<(), String> {
+    let valid = !sp.cookie_name.is_empty()
+        && sp
+            .cookie_name
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b));
+    if !valid {
+        return Err(format!(
+            "{}: invalid session_persistence cookie_name '{}'",
+            context, sp.cookie_name
+        ));
     }
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::io::Write;
-    use tempfile::NamedTempFile;
+/// Validate a request mirror target
+fn validate_request_mirror(mirror: &RequestMirrorFilter, context: &str) -> Result<(), String> {
+    if mirror.address.is_empty() {
+        return Err(format!(
+            "{}: request_mirror.address cannot be empty",
+            context
+        ));
+    }
+    if mirror.port == 0 {
+        return Err(format!("{}: request_mirror.port cannot be 0", context));
+    }
+    if mirror
+        .percent
+        .is_some_and(|percent| !(0.0..=100.0).contains(&percent))
+    {
+        return Err(format!(
+            "{}: request_mirror.percent must be between 0 and 100",
+            context
+        ));
+    }
+    Ok(())
+}
 
-    fn write_config(content: &str) -> NamedTempFile {
-        let mut file = NamedTempFile::new().unwrap();
-        write!(file, "{}", content).unwrap();
-        file
+/// Validate a CORS filter's origins and header lists
+fn validate_cors(cors: &CorsFilter, context: &str) -> Result<(), String> {
+    if cors.allow_origins.is_empty() {
+        return Err(format!("{}: cors.allow_origins cannot be empty", context));
+    }
+    for origin in &cors.allow_origins {
+        if origin != "*" && !is_cors_origin(origin) {
+            return Err(format!(
+                "{}: cors.allow_origins: invalid origin '{}'",
+                context, origin
+            ));
+        }
+    }
+    for (field, names) in [
+        ("allow_methods", &cors.allow_methods),
+        ("allow_headers", &cors.allow_headers),
+        ("expose_headers", &cors.expose_headers),
+    ] {
+        if let Some(name) = names.iter().find(|n| *n != "*" && !is_header_name(n)) {
+            return Err(format!(
+                "{}: cors.{}: invalid name '{}'",
+                context, field, name
+            ));
+        }
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_load_minimal_config() {
-        let file = write_config(r#"{"version": 2}"#);
-        let config = load(file.path()).unwrap();
-        assert_eq!(config.version, 2);
-        assert!(config.vhosts.is_empty());
+fn validate_rate_limit(limit: &RateLimitFilter, context: &str) -> Result<(), String> {
+    if limit.rps == 0 {
+        return Err(format!(
+            "{}: rate_limit.rps must be greater than 0",
+            context
+        ));
+    }
+    if limit.burst == Some(0) {
+        return Err(format!(
+            "{}: rate_limit.burst must be greater than 0",
+            context
+        ));
+    }
+    if limit.max_clients == 0 {
+        return Err(format!(
+            "{}: rate_limit.max_clients must be greater than 0",
+            context
+        ));
     }
+    match &limit.key {
+        HashSource::Header { name } if !is_header_name(name) => Err(format!(
+            "{}: rate_limit.key: invalid header name '{}'",
+            context, name
+        )),
+        HashSource::Cookie { name } if name.is_empty() => Err(format!(
+            "{}: rate_limit.key: cookie name cannot be empty",
+            context
+        )),
+        _ => Ok(()),
+    }
+}
 
-    #[test]
-    fn test_load_nonexistent_file() {
-        // Loading a non-existent file should return an empty config
-        let path = Path::new("/nonexistent/ghost.json");
-        let config = load(path).unwrap();
-        assert_eq!(config.version, 2);
-        assert!(config.vhosts.is_empty());
+fn validate_nonce(nonce: &NonceFilter, context: &str) -> Result<(), String> {
+    for (field, name) in [
+        ("header", &nonce.header),
+        ("validated_header", &nonce.validated_header),
+    ] {
+        if !is_header_name(name) || name.to_ascii_lowercase().starts_with("x-ghost-") {
+            return Err(format!(
+                "{}: nonce.{}: invalid header name '{}'",
+                context, field, name
+            ));
+        }
+    }
+    if nonce.header.eq_ignore_ascii_case(&nonce.validated_header) {
+        return Err(format!(
+            "{}: nonce.header and nonce.validated_header must differ",
+            context
+        ));
+    }
+    if nonce.window_seconds == 0 || nonce.window_seconds > MAX_NONCE_WINDOW_SECONDS {
+        return Err(format!(
+            "{}: nonce.window_seconds must be between 1 and {}",
+            context, MAX_NONCE_WINDOW_SECONDS
+        ));
     }
+    if nonce.max_nonces == 0 {
+        return Err(format!(
+            "{}: nonce.max_nonces must be greater than 0",
+            context
+        ));
+    }
+    Ok(())
+}
 
-    #[test]
-    fn test_load_full_config() {
-        let file = write_config(
-            r#"{
-            "version": 2,
-            "vhosts": {
-                "api.example.com": {
+/// `http(s)://host[:port]`, where the host may start with a `*` wildcard
+fn is_cors_origin(origin: &str) -> bool {
+    let Some(authority) = origin
+        .strip_prefix("https://")
+        .or_else(|| origin.strip_prefix("http://"))
+    else {
+        return false;
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, Some(port)),
+        None => (authority, None),
+    };
+    let valid_host = match host.strip_prefix('*') {
+        Some(rest) => rest.is_empty() || rest.strip_prefix('.').is_some_and(is_dns_name),
+        None => is_dns_name(host) || host.parse::<std::net::Ipv4Addr>().is_ok(),
+    };
+    valid_host && port.is_none_or(|p| p.parse::<u16>().is_ok_and(|p| p > 0))
+}
+
+/// Validate a shadow diff's target, sampling and sink
+fn validate_shadow_diff(diff: &ShadowDiff, context: &str) -> Result<(), String> {
+    if diff.backend.address.is_empty() {
+        return Err(format!(
+            "{}: shadow_diff.backend.address cannot be empty",
+            context
+        ));
+    }
+    if diff.backend.port == 0 {
+        return Err(format!("{}: shadow_diff.backend.port cannot be 0", context));
+    }
+    if !(0.0..=100.0).contains(&diff.percent) {
+        return Err(format!(
+            "{}: shadow_diff.percent must be between 0 and 100",
+            context
+        ));
+    }
+    if !diff.sink.starts_with('/') {
+        return Err(format!(
+            "{}: shadow_diff.sink must be an absolute path",
+            context
+        ));
+    }
+    if diff.fields.is_empty() {
+        return Err(format!("{}: shadow_diff.fields cannot be empty", context));
+    }
+    if diff.max_sink_bytes == 0 {
+        return Err(format!(
+            "{}: shadow_diff.max_sink_bytes cannot be 0",
+            context
+        ));
+    }
+    Ok(())
+}
+
+/// Validate a retry policy's attempt count, statuses and per-try timeout
+fn validate_retry(retry: &RetryPolicy, context: &str) -> Result<(), String> {
+    if retry.max_attempts == 0 || retry.max_attempts > MAX_RETRY_ATTEMPTS {
+        return Err(format!(
+            "{}: retry.max_attempts must be between 1 and {}, got {}",
+            context, MAX_RETRY_ATTEMPTS, retry.max_attempts
+        ));
+    }
+    if let Some(status) = retry.retry_on.iter().find(|s| !(500..=599).contains(*s)) {
+        return Err(format!(
+            "{}: retry.retry_on status {} is not a 5xx status",
+            context, status
+        ));
+    }
+    if let Some(ref body) = retry.retry_on_body {
+        if body.max_inspect_bytes == 0 || body.max_inspect_bytes > MAX_INSPECT_BYTES {
+            return Err(format!(
+                "{}: retry.retry_on_body.max_inspect_bytes must be between 1 and {}, got {}",
+                context, MAX_INSPECT_BYTES, body.max_inspect_bytes
+            ));
+        }
+        if body.pattern.is_empty() || body.pattern.len() > body.max_inspect_bytes {
+            return Err(format!(
+                "{}: retry.retry_on_body.pattern must be 1 to max_inspect_bytes ({}) bytes long",
+                context, body.max_inspect_bytes
+            ));
+        }
+    }
+    match retry.per_try_timeout_ms {
+        Some(0) => Err(format!(
+            "{}: retry.per_try_timeout_ms must be greater than 0",
+            context
+        )),
+        Some(ms) if ms > MAX_ROUTE_TIMEOUT_MS => Err(format!(
+            "{}: retry.per_try_timeout_ms too large ({} ms, max {})",
+            context, ms, MAX_ROUTE_TIMEOUT_MS
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Validate the TLS fingerprint header names. Internal and hop-by-hop
+/// headers would be stripped before reaching the upstream.
+fn validate_tls_fingerprint(fp: &TlsFingerprintConfig) -> Result<(), String> {
+    for name in std::iter::once(&fp.header).chain(&fp.forward_as) {
+        let valid = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b));
+        if !valid {
+            return Err(format!("tls_fingerprint: invalid header name '{}'", name));
+        }
+        if crate::vhost_director::is_internal_header(name)
+            || crate::external_backend::is_hop_by_hop(name)
+            || name.eq_ignore_ascii_case("host")
+        {
+            return Err(format!(
+                "tls_fingerprint: header '{}' is not forwarded upstream",
+                name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Validate the route key header. Internal headers are stripped before
+/// routing, so they could never match.
+fn validate_route_key_header(name: &str) -> Result<(), String> {
+    if !is_header_name(name) {
+        return Err(format!("route_key: invalid header name '{}'", name));
+    }
+    if crate::vhost_director::is_internal_header(name) {
+        return Err(format!("route_key: header '{}' is internal", name));
+    }
+    Ok(())
+}
+
+/// Validate the SNI header. Internal headers are stripped before routing,
+/// so VCL could not hand the SNI over in one.
+fn validate_sni_header(name: &str) -> Result<(), String> {
+    if !is_header_name(name) {
+        return Err(format!("sni_header: invalid header name '{}'", name));
+    }
+    if crate::vhost_director::is_internal_header(name) {
+        return Err(format!("sni_header: header '{}' is internal", name));
+    }
+    Ok(())
+}
+
+/// Validate the connection ID header. It has to reach the backend, so
+/// internal and hop-by-hop headers won't do.
+fn validate_connection_id_header(name: &str) -> Result<(), String> {
+    if !is_header_name(name) {
+        return Err(format!(
+            "tracing.connection_id_header: invalid header name '{}'",
+            name
+        ));
+    }
+    if crate::vhost_director::is_internal_header(name)
+        || crate::external_backend::is_hop_by_hop(name)
+        || name.eq_ignore_ascii_case("host")
+    {
+        return Err(format!(
+            "tracing.connection_id_header: header '{}' is not forwarded upstream",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Validate the headers that mark a request authorized. Internal headers
+/// are stripped before routing looks, so they would never match.
+fn validate_authorized_private(private: &AuthorizedPrivate) -> Result<(), String> {
+    if private.headers.is_empty() {
+        return Err("authorized_private: headers must not be empty".to_string());
+    }
+    for name in &private.headers {
+        if !is_header_name(name) || crate::vhost_director::is_internal_header(name) {
+            return Err(format!(
+                "authorized_private: invalid header name '{}'",
+                name
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// RFC 9110 token, as header names must be
+fn is_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}".contains(&b))
+}
+
+const REFERRER_POLICIES: &[&str] = &[
+    "no-referrer",
+    "no-referrer-when-downgrade",
+    "origin",
+    "origin-when-cross-origin",
+    "same-origin",
+    "strict-origin",
+    "strict-origin-when-cross-origin",
+    "unsafe-url",
+];
+
+/// `max-age=<seconds>` among the directives, as HSTS and Expect-CT require
+/// (separated by `;` and `,` respectively)
+fn has_max_age(value: &str) -> bool {
+    value.split([';', ',']).any(|d| {
+        d.trim().split_once('=').is_some_and(|(k, v)| {
+            k.trim().eq_ignore_ascii_case("max-age")
+                && !v.trim().is_empty()
+                && v.trim().bytes().all(|b| b.is_ascii_digit())
+        })
+    })
+}
+
+/// Check the syntax of well-known security headers. Other headers only
+/// need a valid name and value.
+fn validate_security_header(name: &str, value: &str) -> Result<(), String> {
+    let directive_name =
+        |d: &str| !d.is_empty() && d.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-');
+    let valid = match name.to_ascii_lowercase().as_str() {
+        "strict-transport-security" | "expect-ct" => has_max_age(value),
+        "x-frame-options" => {
+            value.eq_ignore_ascii_case("DENY") || value.eq_ignore_ascii_case("SAMEORIGIN")
+        }
+        "x-content-type-options" => value.eq_ignore_ascii_case("nosniff"),
+        "referrer-policy" => value.split(',').all(|p| {
+            REFERRER_POLICIES
+                .iter()
+                .any(|known| p.trim().eq_ignore_ascii_case(known))
+        }),
+        // `directive source...` pairs separated by `;`
+        "content-security-policy" | "content-security-policy-report-only" => {
+            value
+                .split(';')
+                .map(str::trim)
+                .filter(|d| !d.is_empty())
+                .all(|d| directive_name(d.split_whitespace().next().unwrap_or_default()))
+                && value.split(';').any(|d| !d.trim().is_empty())
+        }
+        // `feature=allowlist` pairs separated by `,`, the allowlist `*` or
+        // a parenthesized (possibly empty) list
+        "permissions-policy" => value.split(',').all(|item| {
+            item.trim().split_once('=').is_some_and(|(feature, allow)| {
+                let allow = allow.trim();
+                directive_name(feature.trim())
+                    && (allow == "*" || (allow.starts_with('(') && allow.ends_with(')')))
+            })
+        }),
+        "report-to" => serde_json::from_str::<serde_json::Value>(&format!("[{}]", value)).is_ok(),
+        _ => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!("invalid {} value '{}'", name, value))
+    }
+}
+
+fn validate_security_headers(sh: &SecurityHeaders, hostname: &str) -> Result<(), String> {
+    let mut seen = std::collections::HashSet::new();
+    for (name, value) in &sh.headers {
+        if !seen.insert(name.to_ascii_lowercase()) {
+            return Err(format!(
+                "{} security_headers: duplicate header '{}'",
+                hostname, name
+            ));
+        }
+        if !is_header_name(name) {
+            return Err(format!(
+                "{} security_headers: invalid header name '{}'",
+                hostname, name
+            ));
+        }
+        if crate::vhost_director::is_internal_header(name) {
+            return Err(format!(
+                "{} security_headers: header '{}' is internal",
+                hostname, name
+            ));
+        }
+        if value.trim().is_empty() || value.bytes().any(|b| b.is_ascii_control() && b != b'\t') {
+            return Err(format!(
+                "{} security_headers: invalid value for '{}'",
+                hostname, name
+            ));
+        }
+        validate_security_header(name, value)
+            .map_err(|e| format!("{} security_headers: {}", hostname, e))?;
+    }
+    Ok(())
+}
+
+/// Longest reason phrase accepted in `reason_phrases`
+const MAX_REASON_PHRASE_LEN: usize = 128;
+
+fn validate_reason_phrases(phrases: &BTreeMap<u16, String>, hostname: &str) -> Result<(), String> {
+    for (status, phrase) in phrases {
+        if !(100..=599).contains(status) {
+            return Err(format!(
+                "{} reason_phrases: invalid status {}",
+                hostname, status
+            ));
+        }
+        // reason-phrase = 1*( HTAB / SP / VCHAR ), RFC 9112 section 4
+        if phrase.trim().is_empty()
+            || phrase.len() > MAX_REASON_PHRASE_LEN
+            || phrase
+                .bytes()
+                .any(|b| !(b == b'\t' || b == b' ' || b.is_ascii_graphic()))
+        {
+            return Err(format!(
+                "{} reason_phrases: invalid reason phrase for {}",
+                hostname, status
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Methods a route's `method` may name
+pub const ROUTE_METHODS: &[&str] = &[
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+/// Validate HTTP method
+fn validate_method(method: &str, context: &str) -> Result<(), String> {
+    if !ROUTE_METHODS.contains(&method) {
+        return Err(format!("{}: invalid method '{}'", context, method));
+    }
+    Ok(())
+}
+
+/// Validate header match configuration
+fn validate_header_match(header: &HeaderMatch, context: &str) -> Result<(), String> {
+    if header.name.is_empty() {
+        return Err(format!("{}: header name cannot be empty", context));
+    }
+    let value = match (&header.value, header.match_type.takes_value()) {
+        (Some(value), true) => value,
+        (None, false) => return Ok(()),
+        (Some(_), false) => {
+            return Err(format!(
+                "{}: {:?} header match takes no value",
+                context, header.match_type
+            ))
+        }
+        (None, true) => return Err(format!("{}: header value cannot be empty", context)),
+    };
+    if value.is_empty() {
+        return Err(format!("{}: header value cannot be empty", context));
+    }
+    if value.len() > 4096 {
+        return Err(format!(
+            "{}: header value too long ({} chars, max 4096)",
+            context,
+            value.len()
+        ));
+    }
+    if header.match_type == MatchType::RegularExpression {
+        // Regex compilation deferred - see detailed comment in validate_path_match()
+    }
+    Ok(())
+}
+
+/// Validate query parameter match configuration
+fn validate_query_param_match(qp: &QueryParamMatch, context: &str) -> Result<(), String> {
+    if qp.name.is_empty() {
+        return Err(format!("{}: query param name cannot be empty", context));
+    }
+    let value = match (&qp.value, qp.match_type.takes_value()) {
+        (Some(value), true) => value,
+        (None, false) => return Ok(()),
+        (Some(_), false) => {
+            return Err(format!(
+                "{}: {:?} query param match takes no value",
+                context, qp.match_type
+            ))
+        }
+        (None, true) => return Err(format!("{}: query param value cannot be empty", context)),
+    };
+    if value.is_empty() {
+        return Err(format!("{}: query param value cannot be empty", context));
+    }
+    if value.len() > 1024 {
+        return Err(format!(
+            "{}: query param value too long ({} chars, max 1024)",
+            context,
+            value.len()
+        ));
+    }
+    if qp.match_type == MatchType::RegularExpression {
+        // Regex compilation deferred - see detailed comment in validate_path_match()
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    fn write_config(content: &str) -> NamedTempFile {
+        let mut file = NamedTempFile::new().unwrap();
+        write!(file, "{}", content).unwrap();
+        file
+    }
+
+    /// The validation error loading `content` gives
+    fn load_err(content: &str) -> String {
+        let file = write_config(content);
+        load(file.path()).expect_err("expected validation error")
+    }
+
+    /// ghost.json with the single vhost api.example.com, made of `fields`
+    fn vhost_config(fields: &str) -> String {
+        format!(
+            r#"{{"version": 2, "vhosts": {{"api.example.com": {{{}}}}}}}"#,
+            fields
+        )
+    }
+
+    /// One route on api.example.com to 10.0.0.1:8080, with the route
+    /// fields in `extra` (comma first) added
+    fn route_config(extra: &str) -> String {
+        vhost_config(&format!(
+            r#""routes": [{{
+                "backend_groups": [{{"backends": [{{"address": "10.0.0.1", "port": 8080}}]}}],
+                "priority": 100{}
+            }}]"#,
+            extra
+        ))
+    }
+
+    /// Two routes on api.example.com to the external proxy `proxy`, the
+    /// first adding the proxy fields in `a` (comma first), the second `b`
+    fn proxy_routes_config(proxy: &str, a: &str, b: &str) -> String {
+        vhost_config(&format!(
+            r#""routes": [
+                {{"backend_groups": [{{"external_proxy": {{{proxy}{a}}}}}], "priority": 100}},
+                {{"backend_groups": [{{"external_proxy": {{{proxy}{b}}}}}], "priority": 50}}
+            ]"#
+        ))
+    }
+
+    #[test]
+    fn test_load_minimal_config() {
+        let file = write_config(r#"{"version": 2}"#);
+        let config = load(file.path()).unwrap();
+        assert_eq!(config.version, 2);
+        assert!(config.vhosts.is_empty());
+    }
+
+    #[test]
+    fn test_load_nonexistent_file() {
+        // Loading a non-existent file should return an empty config
+        let path = Path::new("/nonexistent/ghost.json");
+        let config = load(path).unwrap();
+        assert_eq!(config.version, 2);
+        assert!(config.vhosts.is_empty());
+    }
+
+    #[test]
+    fn test_load_full_config() {
+        let file = write_config(
+            r#"{
+            "version": 2,
+            "vhosts": {
+                "api.example.com": {
                     "routes": [
                         {
                             "path_match": {"type": "PathPrefix", "value": "/api"},
@@ -600,49 +2872,239 @@ mod tests {
     }
 
     #[test]
-    fn test_invalid_wildcard_middle() {
-        let file =
-            write_config(r#"{"version": 2, "vhosts": {"foo.*.example.com": {"routes": []}}}"#);
-        let result = load(file.path());
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("wildcard must be at start"));
-    }
-
-    #[test]
-    fn test_invalid_wildcard_double() {
-        let file = write_config(r#"{"version": 2, "vhosts": {"*.*.example.com": {"routes": []}}}"#);
-        let result = load(file.path());
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("only single leading wildcard"));
-    }
-
-    #[test]
-    fn test_invalid_backend_empty_address() {
+    fn test_load_v1_config() {
         let file = write_config(
-            r#"{"version": 2, "vhosts": {"foo.com": {"routes": [{"backend_groups": [{"weight": 100, "backends": [{"address": "", "port": 80}]}], "priority": 100}]}}}"#,
+            r#"{
+            "version": 1,
+            "vhosts": {
+                "app.example.com": {"backends": [
+                    {"weight": 90, "backends": [{"address": "10.0.0.1", "port": 8080}]},
+                    {"weight": 10, "backends": [{"address": "10.0.0.2", "port": 8080}]}
+                ]}
+            }
+        }"#,
         );
-        let result = load(file.path());
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("address cannot be empty"));
-    }
+        let config = load(file.path()).unwrap();
+        assert_eq!(config.version, 1);
+        let app = &config.vhosts["app.example.com"];
+        assert!(app.routes.is_empty());
+        assert_eq!(app.default_backends.len(), 2);
+        assert_eq!(app.default_backends[0].weight, 90);
+        assert_eq!(app.default_backends[1].backends[0].address, "10.0.0.2");
 
-    #[test]
-    fn test_invalid_backend_zero_port() {
+        // Version 1 has no routed vhosts, but can split by method
         let file = write_config(
-            r#"{"version": 2, "vhosts": {"foo.com": {"routes": [{"backend_groups": [{"weight": 100, "backends": [{"address": "1.2.3.4", "port": 0}]}], "priority": 100}]}}}"#,
+            r#"{
+            "version": 1,
+            "vhosts": {
+                "app.example.com": {
+                    "backends": [{"backends": [{"address": "10.0.0.1", "port": 8080}]}],
+                    "methods": {
+                        "POST": [{"backends": [{"address": "10.0.0.2", "port": 8080}]}],
+                        "DELETE": [{"backends": [{"address": "10.0.0.3", "port": 8080}]}]
+                    }
+                }
+            }
+        }"#,
         );
-        let result = load(file.path());
-        assert!(result.is_err());
-        assert!(result.unwrap_err().contains("port cannot be 0"));
+        let config = load(file.path()).unwrap();
+        let app = &config.vhosts["app.example.com"];
+        assert_eq!(app.routes.len(), 2);
+        assert_eq!(
+            app.routes[0].method,
+            Some(RouteMethod::One("DELETE".to_string()))
+        );
+        assert_eq!(
+            app.routes[0].backend_groups[0].backends[0].address,
+            "10.0.0.3"
+        );
+        assert_eq!(
+            app.routes[1].method,
+            Some(RouteMethod::One("POST".to_string()))
+        );
+        assert_eq!(
+            app.routes[1].backend_groups[0].backends[0].address,
+            "10.0.0.2"
+        );
+        assert!(app.routes.iter().all(|r| r.path_match.is_none()));
+        assert_eq!(app.default_backends[0].backends[0].address, "10.0.0.1");
+
+        let err = load_err(
+            r#"{"version": 1, "vhosts": {"app.example.com": {"backends": [], "methods": {"G E T": []}}}}"#,
+        );
+        assert!(err.contains("method"), "{}", err);
+
+        let err = load_err(r#"{"version": 1, "vhosts": {"app.example.com": {"routes": []}}}"#);
+        assert!(err.contains("app.example.com: simple vhost"), "{}", err);
     }
 
     #[test]
-    fn test_valid_backend_group_zero_weight() {
-        // weight=0 is valid per Gateway API spec (means "no traffic")
+    fn test_load_v3_mixed_config() {
         let file = write_config(
-            r#"{"version": 2, "vhosts": {"foo.com": {"routes": [{"backend_groups": [{"weight": 0, "backends": [{"address": "1.2.3.4", "port": 80}]}], "priority": 100}]}}}"#,
-        );
-        let result = load(file.path());
+            r#"{
+            "version": 3,
+            "host_match_order": ["wildcard", "exact", "default"],
+            "vhosts": {
+                "simple.example.com": {
+                    "backends": [{"backends": [{"address": "10.0.0.1", "port": 8080}]}]
+                },
+                "api.example.com": {
+                    "routes": [{
+                        "path_match": {"type": "PathPrefix", "value": "/v1"},
+                        "backend_groups": [{"backends": [{"address": "10.0.0.2", "port": 8080}]}]
+                    }],
+                    "default_backends": [{"backends": [{"address": "10.0.0.3", "port": 8080}]}],
+                    "qos": "high"
+                },
+                "fallback.example.com": {
+                    "default_backends": [{"backends": [{"address": "10.0.0.4", "port": 8080}]}]
+                }
+            }
+        }"#,
+        );
+        let config = load(file.path()).unwrap();
+        assert_eq!(config.version, 3);
+        assert_eq!(config.host_match_order[0], HostMatchKind::Wildcard);
+        assert_eq!(config.vhosts.len(), 3);
+
+        let simple = &config.vhosts["simple.example.com"];
+        assert!(simple.routes.is_empty());
+        assert_eq!(simple.default_backends[0].weight, 100);
+        assert_eq!(simple.default_backends[0].backends[0].address, "10.0.0.1");
+
+        let api = &config.vhosts["api.example.com"];
+        assert_eq!(api.routes.len(), 1);
+        assert_eq!(api.default_backends[0].backends[0].address, "10.0.0.3");
+        assert_eq!(api.qos, QosClass::High);
+
+        // `routes` is optional in version 3
+        let fallback = &config.vhosts["fallback.example.com"];
+        assert!(fallback.routes.is_empty());
+        assert_eq!(fallback.default_backends.len(), 1);
+    }
+
+    #[test]
+    fn test_load_v3_config_errors() {
+        for (vhost, expected) in [
+            (
+                r#"{"backends": [], "routes": []}"#,
+                "api.example.com: simple vhost: unknown field `routes`",
+            ),
+            (r#"[]"#, "api.example.com: expected an object"),
+            (
+                r#"{"routes": [{"backend_groups": "none"}]}"#,
+                "api.example.com: invalid type",
+            ),
+        ] {
+            let err = load_err(&format!(
+                r#"{{"version": 3, "vhosts": {{"api.example.com": {}}}}}"#,
+                vhost
+            ));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+        }
+
+        // Simple vhosts are validated like default_backends
+        let file = write_config(
+            r#"{"version": 3, "vhosts": {"api.example.com": {"backends": [
+                {"backends": [{"address": "10.0.0.1", "port": 0}]}
+            ]}}}"#,
+        );
+        assert!(load(file.path()).unwrap_err().contains("port"));
+    }
+
+    #[test]
+    fn test_invalid_wildcard_middle() {
+        let file =
+            write_config(r#"{"version": 2, "vhosts": {"foo.*.example.com": {"routes": []}}}"#);
+        let result = load(file.path());
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("wildcard must be at start"));
+    }
+
+    #[test]
+    fn test_invalid_wildcard_double() {
+        let file = write_config(r#"{"version": 2, "vhosts": {"*.*.example.com": {"routes": []}}}"#);
+        let result = load(file.path());
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("only single leading wildcard"));
+    }
+
+    #[test]
+    fn test_invalid_backend_empty_address() {
+        let file = write_config(
+            r#"{"version": 2, "vhosts": {"foo.com": {"routes": [{"backend_groups": [{"weight": 100, "backends": [{"address": "", "port": 80}]}], "priority": 100}]}}}"#,
+        );
+        let result = load(file.path());
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("address cannot be empty"));
+    }
+
+    #[test]
+    fn test_backend_dns_address() {
+        let file = write_config(
+            r#"{"version": 2, "vhosts": {"foo.com": {"routes": [{"backend_groups": [{"backends": [{"address": "api.default.svc", "port": 80}]}]}]}}, "dns_refresh_ms": 5000}"#,
+        );
+        let config = load(file.path()).unwrap();
+        assert_eq!(config.dns_refresh_ms, Some(5000));
+
+        let file = write_config(
+            r#"{"version": 2, "vhosts": {"foo.com": {"routes": [{"backend_groups": [{"backends": [{"address": "api_svc:80", "port": 80}]}]}]}}}"#,
+        );
+        assert!(load(file.path())
+            .unwrap_err()
+            .contains("invalid address 'api_svc:80'"));
+
+        let file = write_config(r#"{"version": 2, "vhosts": {}, "dns_refresh_ms": 0}"#);
+        assert!(load(file.path())
+            .unwrap_err()
+            .contains("dns_refresh_ms must be greater than 0"));
+    }
+
+    #[test]
+    fn test_topology_hints() {
+        let file = write_config(
+            r#"{"version": 2, "zone": "us-east-1a", "vhosts": {"foo.com": {"routes": [{"backend_groups": [{"backends": [{"address": "10.0.0.1", "port": 80, "hints": {"for_zones": ["us-east-1a"]}}]}]}]}}}"#,
+        );
+        let config = load(file.path()).unwrap();
+        assert_eq!(config.zone.as_deref(), Some("us-east-1a"));
+        let backend = &config.vhosts["foo.com"].routes[0].backend_groups[0].backends[0];
+        assert_eq!(
+            backend.hints,
+            Some(TopologyHints {
+                for_zones: vec!["us-east-1a".to_string()]
+            })
+        );
+
+        let file = write_config(
+            r#"{"version": 2, "vhosts": {"foo.com": {"routes": [{"backend_groups": [{"backends": [{"address": "10.0.0.1", "port": 80, "hints": {"for_zones": [""]}}]}]}]}}}"#,
+        );
+        assert!(load(file.path())
+            .unwrap_err()
+            .contains("hints.for_zones cannot contain an empty zone"));
+
+        let file = write_config(r#"{"version": 2, "zone": "", "vhosts": {}}"#);
+        assert!(load(file.path())
+            .unwrap_err()
+            .contains("zone cannot be empty"));
+    }
+
+    #[test]
+    fn test_invalid_backend_zero_port() {
+        let file = write_config(
+            r#"{"version": 2, "vhosts": {"foo.com": {"routes": [{"backend_groups": [{"weight": 100, "backends": [{"address": "1.2.3.4", "port": 0}]}], "priority": 100}]}}}"#,
+        );
+        let result = load(file.path());
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("port cannot be 0"));
+    }
+
+    #[test]
+    fn test_valid_backend_group_zero_weight() {
+        // weight=0 is valid per Gateway API spec (means "no traffic")
+        let file = write_config(
+            r#"{"version": 2, "vhosts": {"foo.com": {"routes": [{"backend_groups": [{"weight": 0, "backends": [{"address": "1.2.3.4", "port": 80}]}], "priority": 100}]}}}"#,
+        );
+        let result = load(file.path());
         assert!(result.is_ok());
     }
 
@@ -813,7 +3275,7 @@ mod tests {
     #[test]
     fn test_external_proxy_mutex_with_backends() {
         // A group cannot carry both backends and external_proxy.
-        let file = write_config(
+        let err = load_err(
             r#"{
                 "version": 2,
                 "vhosts": {
@@ -832,7 +3294,6 @@ mod tests {
                 }
             }"#,
         );
-        let err = load(file.path()).expect_err("expected validation error");
         assert!(
             err.contains("external_proxy and backends are mutually exclusive"),
             "unexpected error: {}",
@@ -842,7 +3303,7 @@ mod tests {
 
     #[test]
     fn test_external_proxy_rejects_empty_hostname() {
-        let file = write_config(
+        let err = load_err(
             r#"{
                 "version": 2,
                 "vhosts": {
@@ -859,10 +3320,350 @@ mod tests {
                 }
             }"#,
         );
-        let err = load(file.path()).expect_err("expected validation error");
         assert!(err.contains("hostname cannot be empty"), "unexpected error: {}", err);
     }
 
+    #[test]
+    fn test_external_proxy_signing() {
+        let secret = write_config("s3cret\n");
+        let config = |signing: &str, other: &str| {
+            proxy_routes_config(
+                r#""hostname": "up.example.com", "port": 443, "tls": true"#,
+                &format!(r#", "signing": {}"#, signing),
+                &format!(r#", "signing": {}"#, other),
+            )
+        };
+        let signing = format!(
+            r#"{{"key_id": "gw-1", "secret_ref": "{}"}}"#,
+            secret.path().display()
+        );
+
+        let file = write_config(&config(&signing, &signing));
+        let loaded = load(file.path()).unwrap();
+        let ep = loaded.vhosts["api.example.com"].routes[0].backend_groups[0]
+            .external_proxy
+            .clone()
+            .unwrap();
+        let signing_cfg = ep.signing.unwrap();
+        assert_eq!(signing_cfg.key_id, "gw-1");
+        assert_eq!(signing_cfg.header, "X-Signature");
+        assert_eq!(signing_cfg.algorithm, SigningAlgorithm::HmacSha256);
+        // Trailing newline trimmed, and never printed
+        assert_eq!(signing_cfg.secret, b"s3cret");
+        assert!(!format!("{:?}", signing_cfg).contains("s3cret"));
+
+        // Groups sharing an upstream must agree
+        let err = load_err(&config(&signing, "null"));
+        assert!(
+            err.contains("conflicting signing"),
+            "unexpected error: {}",
+            err
+        );
+
+        let missing = r#"{"key_id": "gw-1", "secret_ref": "/nonexistent/ghost-secret"}"#;
+        let file = write_config(&config(missing, missing));
+        let err = load(file.path()).expect_err("expected load error");
+        assert!(
+            err.contains("failed to read signing secret"),
+            "unexpected error: {}",
+            err
+        );
+
+        for bad in [
+            r#"{"key_id": "", "secret_ref": "/s"}"#,
+            r#"{"key_id": "a,b", "secret_ref": "/s"}"#,
+            r#"{"key_id": "k", "secret_ref": ""}"#,
+            r#"{"key_id": "k", "secret_ref": "/s", "header": "X-Ghost-Sig"}"#,
+            r#"{"key_id": "k", "secret_ref": "/s", "header": "bad header"}"#,
+        ] {
+            let err = load_err(&config(bad, bad));
+            assert!(
+                err.contains("external_proxy.signing"),
+                "unexpected error: {}",
+                err
+            );
+        }
+
+        let file = write_config(&config(
+            r#"{"key_id": "k", "secret_ref": "/s", "algorithm": "md5"}"#,
+            "null",
+        ));
+        assert!(load(file.path()).is_err());
+    }
+
+    #[test]
+    fn test_external_proxy_timeouts() {
+        let config = |a: &str, b: &str| {
+            proxy_routes_config(
+                r#""hostname": "up.example.com", "port": 443, "tls": true"#,
+                a,
+                b,
+            )
+        };
+        let timeouts = r#", "connect_timeout_ms": 250, "request_timeout_ms": 2000,
+            "header_timeout_ms": 500, "recv_timeout_ms": 2500"#;
+
+        let file = write_config(&config(timeouts, timeouts));
+        let loaded = load(file.path()).unwrap();
+        let ep = loaded.vhosts["api.example.com"].routes[0].backend_groups[0]
+            .external_proxy
+            .clone()
+            .unwrap();
+        assert_eq!(ep.connect_timeout_ms, Some(250));
+        assert_eq!(ep.request_timeout_ms, Some(2000));
+        assert_eq!(ep.header_timeout_ms, Some(500));
+        assert_eq!(ep.recv_timeout_ms, Some(2500));
+
+        let file = write_config(&config("", ""));
+        let ep = load(file.path()).unwrap().vhosts["api.example.com"].routes[0].backend_groups[0]
+            .external_proxy
+            .clone()
+            .unwrap();
+        assert_eq!(ep.connect_timeout_ms, None);
+        assert_eq!(ep.request_timeout_ms, None);
+        assert_eq!(ep.header_timeout_ms, None);
+        assert_eq!(ep.recv_timeout_ms, None);
+        assert!(!ep.adaptive_connect_timeout);
+
+        let adaptive = r#", "adaptive_connect_timeout": true"#;
+        let file = write_config(&config(adaptive, adaptive));
+        let ep = load(file.path()).unwrap().vhosts["api.example.com"].routes[0].backend_groups[0]
+            .external_proxy
+            .clone()
+            .unwrap();
+        assert!(ep.adaptive_connect_timeout);
+
+        for (a, b, expected) in [
+            (timeouts, "", "conflicting timeouts"),
+            (adaptive, "", "conflicting timeouts"),
+            (
+                r#", "connect_timeout_ms": 0"#,
+                r#", "connect_timeout_ms": 0"#,
+                "connect_timeout_ms must be greater than 0",
+            ),
+            (
+                r#", "request_timeout_ms": 3600001"#,
+                r#", "request_timeout_ms": 3600001"#,
+                "request_timeout_ms too large",
+            ),
+            (
+                r#", "header_timeout_ms": 0"#,
+                r#", "header_timeout_ms": 0"#,
+                "header_timeout_ms must be greater than 0",
+            ),
+            (
+                r#", "header_timeout_ms": 500"#,
+                r#", "header_timeout_ms": 1000"#,
+                "conflicting timeouts",
+            ),
+            (
+                r#", "recv_timeout_ms": 0"#,
+                r#", "recv_timeout_ms": 0"#,
+                "recv_timeout_ms must be greater than 0",
+            ),
+            (r#", "recv_timeout_ms": 500"#, "", "conflicting timeouts"),
+        ] {
+            let err = load_err(&config(a, b));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_external_proxy_compress_request() {
+        let config = |a: &str, b: &str| {
+            proxy_routes_config(r#""hostname": "up.example.com", "port": 80"#, a, b)
+        };
+        let on = r#", "compress_request": {"min_bytes": 4096}"#;
+        let file = write_config(&config(on, on));
+        let ep = load(file.path()).unwrap().vhosts["api.example.com"].routes[0].backend_groups[0]
+            .external_proxy
+            .clone()
+            .unwrap();
+        assert_eq!(
+            ep.compress_request,
+            Some(CompressRequest {
+                enabled: true,
+                min_bytes: 4096
+            })
+        );
+
+        let err = load_err(&config(r#", "compress_request": {}"#, ""));
+        assert!(
+            err.contains("conflicting compress_request"),
+            "unexpected error: {}",
+            err
+        );
+
+        let both = r#", "compress_request": {}, "decompress_request_body": true"#;
+        let err = load_err(&config(both, both));
+        assert!(
+            err.contains("mutually exclusive"),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_external_proxy_protocol() {
+        let config = |a: &str, b: &str| {
+            proxy_routes_config(r#""hostname": "grpc.example.com", "port": 50051"#, a, b)
+        };
+        let h2c = r#", "protocol": "h2c""#;
+        let file = write_config(&config(h2c, h2c));
+        let ep = load(file.path()).unwrap().vhosts["api.example.com"].routes[0].backend_groups[0]
+            .external_proxy
+            .clone()
+            .unwrap();
+        assert_eq!(ep.protocol, UpstreamProtocol::H2c);
+
+        let file = write_config(&config("", r#", "protocol": "http1""#));
+        assert!(load(file.path()).is_ok());
+
+        let err = load_err(&config(h2c, ""));
+        assert!(
+            err.contains("conflicting protocol"),
+            "unexpected error: {}",
+            err
+        );
+
+        let tls = r#", "protocol": "h2c", "tls": true"#;
+        let err = load_err(&config(tls, tls));
+        assert!(
+            err.contains("can't be used with tls"),
+            "unexpected error: {}",
+            err
+        );
+
+        let file = write_config(&config(r#", "protocol": "h3""#, ""));
+        assert!(load(file.path()).is_err());
+    }
+
+    #[test]
+    fn test_external_proxy_decompress_request_body() {
+        let config = |a: &str, b: &str| {
+            proxy_routes_config(r#""hostname": "up.example.com", "port": 80"#, a, b)
+        };
+        let on = r#", "decompress_request_body": true"#;
+        let file = write_config(&config(on, on));
+        let ep = load(file.path()).unwrap().vhosts["api.example.com"].routes[0].backend_groups[0]
+            .external_proxy
+            .clone()
+            .unwrap();
+        assert!(ep.decompress_request_body);
+        assert!(!ep.decompress_response);
+
+        let err = load_err(&config(on, ""));
+        assert!(
+            err.contains("conflicting decompress_request_body"),
+            "unexpected error: {}",
+            err
+        );
+
+        let on = r#", "decompress_response": true"#;
+        let file = write_config(&config(on, on));
+        let ep = load(file.path()).unwrap().vhosts["api.example.com"].routes[0].backend_groups[0]
+            .external_proxy
+            .clone()
+            .unwrap();
+        assert!(ep.decompress_response);
+
+        let err = load_err(&config("", on));
+        assert!(
+            err.contains("conflicting decompress_response"),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_external_proxy_coalesced_headers() {
+        let config = |a: &str, b: &str| {
+            proxy_routes_config(r#""hostname": "up.example.com", "port": 80"#, a, b)
+        };
+        let on = r#", "coalesce_request_headers": ["Accept-Encoding", "forwarded"],
+                    "coalesce_response_headers": ["vary"]"#;
+        let file = write_config(&config(on, on));
+        let ep = load(file.path()).unwrap().vhosts["api.example.com"].routes[0].backend_groups[0]
+            .external_proxy
+            .clone()
+            .unwrap();
+        assert_eq!(
+            ep.coalesce_request_headers,
+            vec!["Accept-Encoding", "forwarded"]
+        );
+        assert_eq!(ep.coalesce_response_headers, vec!["vary"]);
+
+        let err = load_err(&config(on, ""));
+        assert!(
+            err.contains("conflicting header coalescing"),
+            "unexpected error: {}",
+            err
+        );
+
+        for (bad, expected) in [
+            (
+                r#", "coalesce_request_headers": ["bad header"]"#,
+                "coalesce_request_headers: invalid header name 'bad header'",
+            ),
+            (
+                r#", "coalesce_response_headers": ["Set-Cookie"]"#,
+                "coalesce_response_headers: set-cookie can't be coalesced",
+            ),
+        ] {
+            let err = load_err(&config(bad, bad));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_external_proxy_tls_options() {
+        let config =
+            |a: &str, b: &str| proxy_routes_config(r#""hostname": "10.0.0.7", "port": 8443"#, a, b);
+        let tls = r#", "tls": true, "sni": "api.internal", "insecure_skip_verify": true"#;
+        let file = write_config(&config(tls, tls));
+        let ep = load(file.path()).unwrap().vhosts["api.example.com"].routes[0].backend_groups[0]
+            .external_proxy
+            .clone()
+            .unwrap();
+        assert_eq!(ep.sni.as_deref(), Some("api.internal"));
+        assert!(ep.insecure_skip_verify);
+
+        let file = write_config(&config(r#", "tls": true"#, r#", "tls": true"#));
+        let ep = load(file.path()).unwrap().vhosts["api.example.com"].routes[0].backend_groups[0]
+            .external_proxy
+            .clone()
+            .unwrap();
+        assert_eq!(ep.sni, None);
+        assert!(!ep.insecure_skip_verify);
+
+        for (a, b, expected) in [
+            (tls, r#", "tls": true"#, "conflicting TLS settings"),
+            (
+                r#", "sni": "api.internal""#,
+                r#", "sni": "api.internal""#,
+                "require tls",
+            ),
+            (
+                r#", "insecure_skip_verify": true"#,
+                r#", "insecure_skip_verify": true"#,
+                "require tls",
+            ),
+            (
+                r#", "tls": true, "sni": "10.0.0.8""#,
+                r#", "tls": true, "sni": "10.0.0.8""#,
+                "sni must be a DNS name",
+            ),
+            (
+                r#", "tls": true, "sni": "api.internal:443""#,
+                r#", "tls": true, "sni": "api.internal:443""#,
+                "sni must be a DNS name",
+            ),
+        ] {
+            let err = load_err(&config(a, b));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+        }
+    }
+
     #[test]
     fn test_backend_tls_absent() {
         let file = write_config(
@@ -893,4 +3694,1423 @@ mod tests {
         let group = &config.vhosts["api.example.com"].routes[0].backend_groups[0];
         assert!(group.backend_tls.is_none());
     }
+
+    #[test]
+    fn test_route_selection_policy() {
+        let file = write_config(
+            r#"{
+                "version": 2,
+                "vhosts": {
+                    "api.example.com": {
+                        "routes": [
+                            {
+                                "backend_groups": [
+                                    {"backends": [{"address": "10.0.0.1", "port": 8080}]}
+                                ],
+                                "priority": 100,
+                                "selection": "least_conn"
+                            },
+                            {
+                                "backend_groups": [
+                                    {"backends": [{"address": "10.0.0.2", "port": 8080}]}
+                                ],
+                                "priority": 50
+                            }
+                        ]
+                    }
+                }
+            }"#,
+        );
+
+        let config = load(file.path()).unwrap();
+        let routes = &config.vhosts["api.example.com"].routes;
+        assert_eq!(routes[0].selection, SelectionPolicy::LeastConn);
+        assert_eq!(routes[1].selection, SelectionPolicy::Weighted);
+    }
+
+    #[test]
+    fn test_external_client_config() {
+        let file = write_config(r#"{"version": 2}"#);
+        let config = load(file.path()).unwrap();
+        assert_eq!(config.external_client.max_pending_requests, 1024);
+
+        let file =
+            write_config(r#"{"version": 2, "external_client": {"max_pending_requests": 16}}"#);
+        let config = load(file.path()).unwrap();
+        assert_eq!(config.external_client.max_pending_requests, 16);
+
+        let err = load_err(r#"{"version": 2, "external_client": {"max_pending_requests": 0}}"#);
+        assert!(
+            err.contains("max_pending_requests"),
+            "unexpected error: {}",
+            err
+        );
+
+        assert_eq!(config.external_client.max_active_streams, 1024);
+        let err = load_err(r#"{"version": 2, "external_client": {"max_active_streams": 0}}"#);
+        assert!(
+            err.contains("max_active_streams"),
+            "unexpected error: {}",
+            err
+        );
+
+        // Requests aren't queued unless asked for
+        assert_eq!(config.external_client.queue_timeout_ms, None);
+        assert_eq!(config.external_client.max_queued_requests, 1024);
+        let file = write_config(
+            r#"{"version": 2, "external_client": {"queue_timeout_ms": 250, "max_queued_requests": 64}}"#,
+        );
+        let config = load(file.path()).unwrap();
+        assert_eq!(config.external_client.queue_timeout_ms, Some(250));
+        assert_eq!(config.external_client.max_queued_requests, 64);
+        let err = load_err(r#"{"version": 2, "external_client": {"queue_timeout_ms": 0}}"#);
+        assert!(
+            err.contains("queue_timeout_ms must be greater than 0"),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_external_client_http_params() {
+        let file = write_config(r#"{"version": 2}"#);
+        let client = load(file.path()).unwrap().external_client;
+        assert_eq!(client.pool_max_idle_per_host, None);
+        assert_eq!(client.pool_idle_timeout_ms, None);
+        assert_eq!(client.connect_timeout_ms, None);
+        assert_eq!(client.request_timeout_ms, None);
+        assert_eq!(client.tcp_keepalive_ms, None);
+        assert!(client.tcp_nodelay);
+
+        let file = write_config(
+            r#"{"version": 2, "external_client": {
+                "pool_max_idle_per_host": 0, "pool_idle_timeout_ms": 30000,
+                "connect_timeout_ms": 2000, "request_timeout_ms": 300000,
+                "tcp_keepalive_ms": 15000, "tcp_nodelay": false}}"#,
+        );
+        let client = load(file.path()).unwrap().external_client;
+        assert_eq!(client.pool_max_idle_per_host, Some(0));
+        assert_eq!(client.pool_idle_timeout_ms, Some(30000));
+        assert_eq!(client.connect_timeout_ms, Some(2000));
+        assert_eq!(client.request_timeout_ms, Some(300000));
+        assert_eq!(client.tcp_keepalive_ms, Some(15000));
+        assert!(!client.tcp_nodelay);
+
+        for (field, value, expected) in [
+            ("pool_idle_timeout_ms", 0, "must be greater than 0"),
+            ("connect_timeout_ms", 0, "must be greater than 0"),
+            ("request_timeout_ms", 0, "must be greater than 0"),
+            ("tcp_keepalive_ms", 0, "must be greater than 0"),
+            ("request_timeout_ms", 3_600_001, "too large"),
+        ] {
+            let err = load_err(&format!(
+                r#"{{"version": 2, "external_client": {{"{}": {}}}}}"#,
+                field, value
+            ));
+            assert!(
+                err.contains(&format!("external_client.{} {}", field, expected)),
+                "unexpected error: {}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_route_consistent_hash_selection() {
+        let file = write_config(&route_config(
+            r#", "selection": "consistent_hash", "hash_on": {"type": "Cookie", "name": "session"}"#,
+        ));
+        let config = load(file.path()).unwrap();
+        let r = &config.vhosts["api.example.com"].routes[0];
+        assert_eq!(r.selection, SelectionPolicy::ConsistentHash);
+        assert_eq!(
+            r.hash_on,
+            Some(HashSource::Cookie {
+                name: "session".to_string()
+            })
+        );
+
+        let file = write_config(&route_config(
+            r#", "selection": "consistent_hash", "hash_on": {"type": "ClientIp"}"#,
+        ));
+        assert!(load(file.path()).is_ok());
+
+        let err = load_err(&route_config(r#", "selection": "consistent_hash""#));
+        assert!(
+            err.contains("requires hash_on"),
+            "unexpected error: {}",
+            err
+        );
+
+        let err = load_err(&route_config(r#", "hash_on": {"type": "ClientIp"}"#));
+        assert!(
+            err.contains("only valid with consistent_hash"),
+            "unexpected error: {}",
+            err
+        );
+
+        let err = load_err(&route_config(
+            r#", "selection": "consistent_hash", "hash_on": {"type": "Header", "name": ""}"#,
+        ));
+        assert!(
+            err.contains("name cannot be empty"),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_backend_draining() {
+        let file = write_config(
+            r#"{"version": 2, "vhosts": {"api.example.com": {"routes": [{
+                "backend_groups": [{"backends": [
+                    {"address": "10.0.0.1", "port": 8080},
+                    {"address": "10.0.0.2", "port": 8080, "draining": true}
+                ]}],
+                "priority": 100
+            }]}}}"#,
+        );
+        let config = load(file.path()).unwrap();
+        let backends = &config.vhosts["api.example.com"].routes[0].backend_groups[0].backends;
+        assert!(!backends[0].draining);
+        assert!(backends[1].draining);
+    }
+
+    #[test]
+    fn test_host_match_order() {
+        let file = write_config(r#"{"version": 2}"#);
+        let config = load(file.path()).unwrap();
+        assert_eq!(
+            config.host_match_order,
+            vec![
+                HostMatchKind::Exact,
+                HostMatchKind::Wildcard,
+                HostMatchKind::Default
+            ]
+        );
+
+        let file =
+            write_config(r#"{"version": 2, "host_match_order": ["wildcard", "exact", "default"]}"#);
+        let config = load(file.path()).unwrap();
+        assert_eq!(config.host_match_order[0], HostMatchKind::Wildcard);
+
+        let err = load_err(r#"{"version": 2, "host_match_order": ["exact", "exact", "default"]}"#);
+        assert!(err.contains("exactly once"), "unexpected error: {}", err);
+
+        let err = load_err(r#"{"version": 2, "host_match_order": ["exact", "wildcard"]}"#);
+        assert!(err.contains("Default"), "unexpected error: {}", err);
+
+        let file = write_config(r#"{"version": 2, "host_match_order": ["regex"]}"#);
+        assert!(load(file.path()).is_err());
+    }
+
+    #[test]
+    fn test_qos_classes() {
+        let file = write_config(
+            r#"{"version": 2, "vhosts": {"api.example.com": {
+                "qos": "low",
+                "routes": [
+                    {"backend_groups": [], "priority": 100, "qos": "high"},
+                    {"backend_groups": [], "priority": 50}
+                ]
+            }, "web.example.com": {"routes": []}}}"#,
+        );
+        let config = load(file.path()).unwrap();
+        let vhost = &config.vhosts["api.example.com"];
+        assert_eq!(vhost.qos, QosClass::Low);
+        assert_eq!(vhost.routes[0].qos, Some(QosClass::High));
+        assert_eq!(vhost.routes[1].qos, None);
+        assert_eq!(config.vhosts["web.example.com"].qos, QosClass::Normal);
+
+        for class in [QosClass::High, QosClass::Normal, QosClass::Low] {
+            assert_eq!(QosClass::from_name(class.as_str()), Some(class));
+        }
+        assert_eq!(QosClass::from_name("urgent"), None);
+
+        let file = write_config(
+            r#"{"version": 2, "vhosts": {"api.example.com": {"routes": [], "qos": "urgent"}}}"#,
+        );
+        assert!(load(file.path()).is_err());
+    }
+
+    #[test]
+    fn test_shadow_selection_config() {
+        let route = |extra: &str| {
+            vhost_config(&format!(
+                r#""routes": [{{
+                    "backend_groups": [
+                        {{"weight": 90, "backends": []}},
+                        {{"weight": 10, "backends": []}}
+                    ],
+                    "priority": 100{}
+                }}]"#,
+                extra
+            ))
+        };
+
+        let file = write_config(&route(
+            r#", "shadow_selection": {"selection": "least_conn", "weights": [50, 50]}"#,
+        ));
+        let config = load(file.path()).unwrap();
+        assert_eq!(
+            config.vhosts["api.example.com"].routes[0].shadow_selection,
+            Some(ShadowSelection {
+                selection: Some(SelectionPolicy::LeastConn),
+                weights: Some(vec![50, 50]),
+            })
+        );
+
+        // A consistent-hash shadow brings its own hash_on
+        let file = write_config(&route(
+            r#", "shadow_selection": {"selection": "consistent_hash"},
+                "hash_on": {"type": "ClientIp"}"#,
+        ));
+        assert!(load(file.path()).is_ok());
+
+        for (bad, expected) in [
+            (
+                r#", "shadow_selection": {}"#,
+                "needs a selection or weights",
+            ),
+            (
+                r#", "shadow_selection": {"weights": [100]}"#,
+                "1 weights for 2 backend groups",
+            ),
+            (
+                r#", "shadow_selection": {"selection": "consistent_hash"}"#,
+                "consistent_hash shadow_selection requires hash_on",
+            ),
+            (
+                r#", "shadow_selection": {"selection": "least_conn"}, "hash_on": {"type": "ClientIp"}"#,
+                "hash_on is only valid",
+            ),
+            (
+                r#", "shadow_selection": {"selection": "round_robin"}"#,
+                "round_robin",
+            ),
+        ] {
+            let err = load_err(&route(bad));
+            assert!(
+                err.contains(expected),
+                "unexpected error for {}: {}",
+                bad,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_security_headers_config() {
+        let config = |headers: &str| {
+            vhost_config(&format!(
+                r#""routes": [], "security_headers": {{"headers": {}}}"#,
+                headers
+            ))
+        };
+
+        let file = write_config(&config(
+            r#"{
+                "strict-transport-security": "max-age=31536000; includeSubDomains; preload",
+                "Content-Security-Policy": "default-src 'self'; img-src * data:; upgrade-insecure-requests",
+                "x-frame-options": "SAMEORIGIN",
+                "x-content-type-options": "nosniff",
+                "referrer-policy": "no-referrer, strict-origin-when-cross-origin",
+                "permissions-policy": "geolocation=(), camera=(self \"https://a.example\"), fullscreen=*",
+                "expect-ct": "max-age=86400, enforce",
+                "report-to": "{\"group\": \"csp\", \"max_age\": 600, \"endpoints\": [{\"url\": \"https://r.example\"}]}",
+                "x-custom": "anything goes"
+            }"#,
+        ));
+        let sh = load(file.path())
+            .unwrap()
+            .vhosts
+            .remove("api.example.com")
+            .unwrap()
+            .security_headers
+            .unwrap();
+        assert_eq!(sh.headers.len(), 9);
+        assert!(!sh.preserve_upstream);
+
+        let file = write_config(r#"{"version": 2, "vhosts": {"api.example.com": {"routes": []}}}"#);
+        assert!(load(file.path()).unwrap().vhosts["api.example.com"]
+            .security_headers
+            .is_none());
+
+        for (bad, expected) in [
+            (
+                r#"{"strict-transport-security": "includeSubDomains"}"#,
+                "invalid strict-transport-security",
+            ),
+            (
+                r#"{"strict-transport-security": "max-age=soon"}"#,
+                "invalid strict-transport-security",
+            ),
+            (r#"{"expect-ct": "enforce"}"#, "invalid expect-ct"),
+            (
+                r#"{"x-frame-options": "ALLOW-FROM https://a.example"}"#,
+                "invalid x-frame-options",
+            ),
+            (
+                r#"{"x-content-type-options": "sniff"}"#,
+                "invalid x-content-type-options",
+            ),
+            (
+                r#"{"referrer-policy": "origin, everywhere"}"#,
+                "invalid referrer-policy",
+            ),
+            (
+                r#"{"content-security-policy": "default_src 'self'"}"#,
+                "invalid content-security-policy",
+            ),
+            (
+                r#"{"content-security-policy": " ; "}"#,
+                "invalid content-security-policy",
+            ),
+            (
+                r#"{"permissions-policy": "geolocation self"}"#,
+                "invalid permissions-policy",
+            ),
+            (r#"{"report-to": "{group: csp}"}"#, "invalid report-to"),
+            (r#"{"x frame": "DENY"}"#, "invalid header name"),
+            (r#"{"X-Ghost-Route": "x"}"#, "internal"),
+            (r#"{"x-custom": ""}"#, "invalid value"),
+            (r#"{"x-custom": "a\r\nSet-Cookie: b"}"#, "invalid value"),
+            (
+                r#"{"X-Frame-Options": "DENY", "x-frame-options": "DENY"}"#,
+                "duplicate header",
+            ),
+        ] {
+            let err = load_err(&config(bad));
+            assert!(
+                err.contains(expected),
+                "unexpected error for {}: {}",
+                bad,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_presence_matches() {
+        let config = |headers: &str, query_params: &str| {
+            route_config(&format!(
+                r#", "path_match": {{"type": "PathPrefix", "value": "/"}},
+                    "headers": {}, "query_params": {}"#,
+                headers, query_params
+            ))
+        };
+
+        let file = write_config(&config(
+            r#"[{"name": "Authorization", "type": "Absent"},
+                {"name": "Cookie", "type": "Present"},
+                {"name": "Accept", "value": "text/html", "type": "Exact"}]"#,
+            r#"[{"name": "debug", "type": "Present"}]"#,
+        ));
+        let config_loaded = load(file.path()).unwrap();
+        let route = &config_loaded.vhosts["api.example.com"].routes[0];
+        assert_eq!(route.headers[0].match_type, MatchType::Absent);
+        assert!(route.headers[0].value.is_none());
+        assert_eq!(route.headers[2].value.as_deref(), Some("text/html"));
+        assert_eq!(route.query_params[0].match_type, MatchType::Present);
+
+        for (headers, query_params, expected) in [
+            (
+                r#"[{"name": "Authorization", "value": "x", "type": "Absent"}]"#,
+                "[]",
+                "Absent header match takes no value",
+            ),
+            (
+                "[]",
+                r#"[{"name": "debug", "value": "1", "type": "Present"}]"#,
+                "Present query param match takes no value",
+            ),
+            (
+                r#"[{"name": "Accept", "type": "Exact"}]"#,
+                "[]",
+                "header value cannot be empty",
+            ),
+            (
+                "[]",
+                r#"[{"name": "q", "type": "RegularExpression"}]"#,
+                "query param value cannot be empty",
+            ),
+            (
+                r#"[{"name": "", "type": "Present"}]"#,
+                "[]",
+                "header name cannot be empty",
+            ),
+        ] {
+            let err = load_err(&config(headers, query_params));
+            assert!(
+                err.contains(expected),
+                "unexpected error for {} {}: {}",
+                headers,
+                query_params,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_route_method_list() {
+        let file = write_config(
+            r#"{"version": 2, "head_with_get": true, "vhosts": {"api.example.com": {"routes": [
+                {"path_match": {"type": "PathPrefix", "value": "/a"}, "method": "GET",
+                 "backend_groups": []},
+                {"path_match": {"type": "PathPrefix", "value": "/b"}, "method": ["GET", "HEAD"],
+                 "backend_groups": []},
+                {"path_match": {"type": "PathPrefix", "value": "/c"}, "backend_groups": []}
+            ]}}}"#,
+        );
+        let config = load(file.path()).unwrap();
+        assert!(config.head_with_get);
+        let routes = &config.vhosts["api.example.com"].routes;
+        assert_eq!(routes[0].method, Some(RouteMethod::One("GET".to_string())));
+        assert_eq!(
+            routes[1].method.as_ref().unwrap().methods(),
+            ["GET", "HEAD"]
+        );
+        assert!(routes[2].method.is_none());
+
+        for (bad, expected) in [
+            (r#"[]"#, "method list cannot be empty"),
+            (r#"["GET", "FETCH"]"#, "invalid method 'FETCH'"),
+            (r#""get""#, "invalid method 'get'"),
+        ] {
+            let err = load_err(&route_config(&format!(r#", "method": {}"#, bad)));
+            assert!(
+                err.contains(expected),
+                "unexpected error for {}: {}",
+                bad,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_reason_phrases_config() {
+        let config = |phrases: &str| {
+            vhost_config(&format!(r#""routes": [], "reason_phrases": {}"#, phrases))
+        };
+
+        let file = write_config(&config(r#"{"503": "Down For Maintenance", "200": "Fine"}"#));
+        let phrases = load(file.path()).unwrap().vhosts["api.example.com"]
+            .reason_phrases
+            .clone();
+        assert_eq!(phrases[&503], "Down For Maintenance");
+        assert_eq!(phrases[&200], "Fine");
+
+        let file = write_config(r#"{"version": 2, "vhosts": {"api.example.com": {"routes": []}}}"#);
+        assert!(load(file.path()).unwrap().vhosts["api.example.com"]
+            .reason_phrases
+            .is_empty());
+
+        for (bad, expected) in [
+            (r#"{"99": "Too Low"}"#, "invalid status 99"),
+            (r#"{"600": "Too High"}"#, "invalid status 600"),
+            (r#"{"503": " "}"#, "invalid reason phrase for 503"),
+            (
+                r#"{"503": "Down\r\nX-Injected: 1"}"#,
+                "invalid reason phrase",
+            ),
+            (r#"{"503": "Caf\u00e9"}"#, "invalid reason phrase"),
+        ] {
+            let err = load_err(&config(bad));
+            assert!(
+                err.contains(expected),
+                "unexpected error for {}: {}",
+                bad,
+                err
+            );
+        }
+        let long = format!(r#"{{"503": "{}"}}"#, "x".repeat(MAX_REASON_PHRASE_LEN + 1));
+        let file = write_config(&config(&long));
+        assert!(load(file.path()).is_err());
+    }
+
+    #[test]
+    fn test_forward_host() {
+        let route =
+            |forward_host: &str| route_config(&format!(r#", "forward_host": {}"#, forward_host));
+        let parse = |forward_host: &str| {
+            let file = write_config(&route(forward_host));
+            load(file.path()).map(|c| c.vhosts["api.example.com"].routes[0].forward_host.clone())
+        };
+
+        assert_eq!(parse("null").unwrap(), None);
+        assert_eq!(parse(r#""preserve""#).unwrap(), Some(ForwardHost::Preserve));
+        assert_eq!(parse(r#""backend""#).unwrap(), Some(ForwardHost::Backend));
+        for literal in [
+            "origin.example.com",
+            "origin.example.com:8443",
+            "10.0.0.1",
+            "10.0.0.1:8080",
+            "[2001:db8::1]",
+            "[2001:db8::1]:8080",
+        ] {
+            assert_eq!(
+                parse(&format!(r#""{}""#, literal)).unwrap(),
+                Some(ForwardHost::Literal(literal.to_string())),
+                "{}",
+                literal
+            );
+        }
+
+        for bad in [
+            "",
+            "origin.example.com:http",
+            "origin.example.com:99999",
+            "2001:db8::1",
+            "bad host",
+            "*.example.com",
+        ] {
+            let err = parse(&format!(r#""{}""#, bad)).expect_err(bad);
+            assert!(err.contains("forward_host"), "unexpected error: {}", err);
+        }
+        assert!(parse("true").is_err());
+    }
+
+    #[test]
+    fn test_max_request_body_bytes() {
+        let route =
+            |limit: &str| route_config(&format!(r#", "max_request_body_bytes": {}"#, limit));
+
+        let file = write_config(&route("1048576"));
+        let config = load(file.path()).unwrap();
+        assert_eq!(
+            config.vhosts["api.example.com"].routes[0].max_request_body_bytes,
+            Some(1048576)
+        );
+
+        let err = load_err(&route("0"));
+        assert!(
+            err.contains("max_request_body_bytes must be greater than 0"),
+            "unexpected error: {}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_route_timeouts() {
+        let route = |timeouts: &str| route_config(&format!(r#", "timeouts": {}"#, timeouts));
+
+        let file = write_config(&route(r#"{"request_ms": 5000, "backend_request_ms": 100}"#));
+        let config = load(file.path()).unwrap();
+        let timeouts = config.vhosts["api.example.com"].routes[0].timeouts.unwrap();
+        assert_eq!(timeouts.request_ms, Some(5000));
+        assert_eq!(timeouts.backend_request_ms, Some(100));
+        assert_eq!(timeouts.effective_ms(), Some(100));
+
+        let file = write_config(&route(r#"{"request_ms": 250}"#));
+        let config = load(file.path()).unwrap();
+        let timeouts = config.vhosts["api.example.com"].routes[0].timeouts.unwrap();
+        assert_eq!(timeouts.effective_ms(), Some(250));
+        assert!(!timeouts.expose_budget_header);
+
+        let file = write_config(&route(
+            r#"{"request_ms": 250, "expose_budget_header": true}"#,
+        ));
+        let config = load(file.path()).unwrap();
+        let timeouts = config.vhosts["api.example.com"].routes[0].timeouts.unwrap();
+        assert!(timeouts.expose_budget_header);
+
+        let err = load_err(&route(
+            r#"{"backend_request_ms": 250, "expose_budget_header": true}"#,
+        ));
+        assert!(
+            err.contains("expose_budget_header requires timeouts.request_ms"),
+            "unexpected error: {}",
+            err
+        );
+
+        let err = load_err(&route(r#"{"request_ms": 0}"#));
+        assert!(
+            err.contains("must be greater than 0"),
+            "unexpected error: {}",
+            err
+        );
+
+        let err = load_err(&route(r#"{"backend_request_ms": 86400000}"#));
+        assert!(err.contains("too large"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_request_mirror_filter() {
+        let route = |mirror: &str| {
+            route_config(&format!(r#", "filters": {{"request_mirror": {}}}"#, mirror))
+        };
+
+        let file = write_config(&route(r#"{"address": "10.0.0.9", "port": 8081}"#));
+        let config = load(file.path()).unwrap();
+        let mirror = config.vhosts["api.example.com"].routes[0]
+            .filters
+            .as_ref()
+            .and_then(|f| f.request_mirror.clone())
+            .unwrap();
+        assert_eq!(mirror.address, "10.0.0.9");
+        assert_eq!(mirror.port, 8081);
+        assert!(!mirror.tls);
+        assert_eq!(mirror.percent, None);
+
+        let file = write_config(&route(
+            r#"{"address": "10.0.0.9", "port": 8081, "percent": 12.5}"#,
+        ));
+        let config = load(file.path()).unwrap();
+        let mirror = config.vhosts["api.example.com"].routes[0]
+            .filters
+            .as_ref()
+            .and_then(|f| f.request_mirror.clone())
+            .unwrap();
+        assert_eq!(mirror.percent, Some(12.5));
+
+        for (bad, expected) in [
+            (
+                r#"{"address": "", "port": 8081}"#,
+                "address cannot be empty",
+            ),
+            (r#"{"address": "10.0.0.9", "port": 0}"#, "port cannot be 0"),
+            (
+                r#"{"address": "10.0.0.9", "port": 8081, "percent": 101}"#,
+                "percent must be between 0 and 100",
+            ),
+            (
+                r#"{"address": "10.0.0.9", "port": 8081, "percent": -1}"#,
+                "percent must be between 0 and 100",
+            ),
+        ] {
+            let err = load_err(&route(bad));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_filter() {
+        let route =
+            |limit: &str| route_config(&format!(r#", "filters": {{"rate_limit": {}}}"#, limit));
+        let limit = |json: &str| {
+            let file = write_config(&route(json));
+            load(file.path()).map(|config| {
+                config.vhosts["api.example.com"].routes[0]
+                    .filters
+                    .as_ref()
+                    .and_then(|f| f.rate_limit.clone())
+                    .unwrap()
+            })
+        };
+
+        // Per client IP, with a burst of rps, unless told otherwise
+        let l = limit(r#"{"rps": 10}"#).unwrap();
+        assert_eq!(l.burst(), 10);
+        assert_eq!(l.key, HashSource::ClientIp);
+        assert_eq!(l.max_clients, 100_000);
+
+        let l = limit(
+            r#"{"rps": 5, "burst": 20, "key": {"type": "Header", "name": "X-Api-Key"}, "max_clients": 1000}"#,
+        )
+        .unwrap();
+        assert_eq!(l.burst(), 20);
+        assert_eq!(
+            l.key,
+            HashSource::Header {
+                name: "X-Api-Key".to_string()
+            }
+        );
+
+        for (bad, expected) in [
+            (r#"{"rps": 0}"#, "rate_limit.rps must be greater than 0"),
+            (
+                r#"{"rps": 1, "burst": 0}"#,
+                "rate_limit.burst must be greater than 0",
+            ),
+            (
+                r#"{"rps": 1, "max_clients": 0}"#,
+                "rate_limit.max_clients must be greater than 0",
+            ),
+            (
+                r#"{"rps": 1, "key": {"type": "Header", "name": "X Key"}}"#,
+                "invalid header name 'X Key'",
+            ),
+            (
+                r#"{"rps": 1, "key": {"type": "Cookie", "name": ""}}"#,
+                "cookie name cannot be empty",
+            ),
+        ] {
+            let err = limit(bad).unwrap_err();
+            assert!(err.contains(expected), "{}: {}", bad, err);
+        }
+    }
+
+    #[test]
+    fn test_nonce_filter() {
+        let route = |nonce: &str| route_config(&format!(r#", "filters": {{"nonce": {}}}"#, nonce));
+        let nonce = |json: &str| {
+            let file = write_config(&route(json));
+            load(file.path()).map(|config| {
+                config.vhosts["api.example.com"].routes[0]
+                    .filters
+                    .as_ref()
+                    .and_then(|f| f.nonce.clone())
+                    .unwrap()
+            })
+        };
+
+        let n = nonce("{}").unwrap();
+        assert_eq!(n.header, "X-Nonce");
+        assert_eq!(n.window_seconds, 300);
+        assert_eq!(n.max_nonces, 100_000);
+        assert_eq!(n.validated_header, "X-Nonce-Validated");
+
+        let n = nonce(r#"{"header": "X-Request-Nonce", "window_seconds": 60}"#).unwrap();
+        assert_eq!(n.header, "X-Request-Nonce");
+        assert_eq!(n.window_seconds, 60);
+
+        for (bad, expected) in [
+            (
+                r#"{"window_seconds": 0}"#,
+                "nonce.window_seconds must be between 1 and 86400",
+            ),
+            (
+                r#"{"window_seconds": 86401}"#,
+                "nonce.window_seconds must be between 1 and 86400",
+            ),
+            (
+                r#"{"max_nonces": 0}"#,
+                "nonce.max_nonces must be greater than 0",
+            ),
+            (
+                r#"{"header": "X Nonce"}"#,
+                "nonce.header: invalid header name 'X Nonce'",
+            ),
+            (
+                r#"{"validated_header": "X-Ghost-Nonce"}"#,
+                "nonce.validated_header: invalid header name",
+            ),
+            (r#"{"validated_header": "x-nonce"}"#, "must differ"),
+        ] {
+            let err = nonce(bad).unwrap_err();
+            assert!(err.contains(expected), "{}: {}", bad, err);
+        }
+    }
+
+    #[test]
+    fn test_cors_filter() {
+        let route = |cors: &str| route_config(&format!(r#", "filters": {{"cors": {}}}"#, cors));
+
+        let file = write_config(&route(
+            r#"{"allow_origins": ["https://app.example.com", "https://*.example.net:8443", "http://10.0.0.5", "*"],
+                "allow_methods": ["GET", "*"], "allow_headers": ["Content-Type"], "max_age": 600}"#,
+        ));
+        let config = load(file.path()).unwrap();
+        let cors = config.vhosts["api.example.com"].routes[0]
+            .filters
+            .as_ref()
+            .and_then(|f| f.cors.clone())
+            .unwrap();
+        assert_eq!(cors.allow_origins.len(), 4);
+        assert!(!cors.allow_credentials);
+        assert_eq!(cors.max_age, Some(600));
+
+        for (bad, expected) in [
+            (r#"{"allow_origins": []}"#, "allow_origins cannot be empty"),
+            (
+                r#"{"allow_origins": ["app.example.com"]}"#,
+                "invalid origin 'app.example.com'",
+            ),
+            (
+                r#"{"allow_origins": ["https://app.example.com/path"]}"#,
+                "invalid origin",
+            ),
+            (
+                r#"{"allow_origins": ["https://a*.example.com"]}"#,
+                "invalid origin",
+            ),
+            (
+                r#"{"allow_origins": ["ftp://app.example.com"]}"#,
+                "invalid origin",
+            ),
+            (
+                r#"{"allow_origins": ["https://app.example.com:0"]}"#,
+                "invalid origin",
+            ),
+            (
+                r#"{"allow_origins": ["*"], "allow_headers": ["Bad Header"]}"#,
+                "cors.allow_headers: invalid name 'Bad Header'",
+            ),
+        ] {
+            let err = load_err(&route(bad));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_shadow_diff_config() {
+        let route = |diff: &str| route_config(&format!(r#", "shadow_diff": {}"#, diff));
+
+        let file = write_config(&route(
+            r#"{"backend": {"address": "10.0.0.9", "port": 8081}, "percent": 5,
+                "sink": "/var/log/ghost-diff.jsonl", "fields": ["status", "body_hash"]}"#,
+        ));
+        let config = load(file.path()).unwrap();
+        let diff = config.vhosts["api.example.com"].routes[0]
+            .shadow_diff
+            .clone()
+            .unwrap();
+        assert_eq!(diff.backend.address, "10.0.0.9");
+        assert_eq!(diff.percent, 5.0);
+        assert_eq!(diff.fields, vec![DiffField::Status, DiffField::BodyHash]);
+        assert_eq!(diff.max_sink_bytes, 64 * 1024 * 1024);
+
+        let file = write_config(&route(
+            r#"{"backend": {"address": "10.0.0.9", "port": 8081}, "percent": 0.5, "sink": "/tmp/d"}"#,
+        ));
+        let diff = load(file.path()).unwrap().vhosts["api.example.com"].routes[0]
+            .shadow_diff
+            .clone()
+            .unwrap();
+        assert_eq!(diff.fields.len(), 3);
+
+        let valid = r#""backend": {"address": "10.0.0.9", "port": 8081}, "sink": "/tmp/d""#;
+        for (bad, expected) in [
+            (
+                r#"{"backend": {"address": "", "port": 8081}, "percent": 5, "sink": "/tmp/d"}"#
+                    .to_string(),
+                "backend.address cannot be empty",
+            ),
+            (
+                r#"{"backend": {"address": "10.0.0.9", "port": 0}, "percent": 5, "sink": "/tmp/d"}"#
+                    .to_string(),
+                "backend.port cannot be 0",
+            ),
+            (
+                format!(r#"{{{}, "percent": 101}}"#, valid),
+                "percent must be between 0 and 100",
+            ),
+            (
+                format!(r#"{{{}, "percent": -1}}"#, valid),
+                "percent must be between 0 and 100",
+            ),
+            (
+                r#"{"backend": {"address": "10.0.0.9", "port": 8081}, "percent": 5, "sink": "diff.jsonl"}"#
+                    .to_string(),
+                "sink must be an absolute path",
+            ),
+            (
+                format!(r#"{{{}, "percent": 5, "fields": []}}"#, valid),
+                "fields cannot be empty",
+            ),
+            (
+                format!(r#"{{{}, "percent": 5, "max_sink_bytes": 0}}"#, valid),
+                "max_sink_bytes cannot be 0",
+            ),
+        ] {
+            let err = load_err(&route(&bad));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_route_retry() {
+        let route = |retry: &str| route_config(&format!(r#", "retry": {}"#, retry));
+
+        let file = write_config(&route("{}"));
+        let config = load(file.path()).unwrap();
+        let retry = config.vhosts["api.example.com"].routes[0]
+            .retry
+            .clone()
+            .unwrap();
+        assert_eq!(retry.max_attempts, 2);
+        assert_eq!(retry.retry_on, vec![502, 503, 504]);
+        assert_eq!(retry.per_try_timeout_ms, None);
+        assert!(!retry.unsafe_methods);
+
+        let file = write_config(&route(
+            r#"{"max_attempts": 3, "retry_on": [500], "per_try_timeout_ms": 200, "unsafe_methods": true}"#,
+        ));
+        let config = load(file.path()).unwrap();
+        let retry = config.vhosts["api.example.com"].routes[0]
+            .retry
+            .clone()
+            .unwrap();
+        assert_eq!(retry.max_attempts, 3);
+        assert_eq!(retry.retry_on, vec![500]);
+        assert_eq!(retry.per_try_timeout_ms, Some(200));
+        assert!(retry.unsafe_methods);
+        assert_eq!(retry.retry_on_body, None);
+
+        let file = write_config(&route(r#"{"retry_on_body": {"pattern": "try again"}}"#));
+        let config = load(file.path()).unwrap();
+        let body = config.vhosts["api.example.com"].routes[0]
+            .retry
+            .clone()
+            .unwrap()
+            .retry_on_body
+            .unwrap();
+        assert_eq!(body.pattern, "try again");
+        assert_eq!(body.max_inspect_bytes, 512);
+
+        for (bad, expected) in [
+            (r#"{"max_attempts": 0}"#, "max_attempts must be between"),
+            (r#"{"max_attempts": 6}"#, "max_attempts must be between"),
+            (r#"{"retry_on": [404]}"#, "not a 5xx status"),
+            (r#"{"per_try_timeout_ms": 0}"#, "must be greater than 0"),
+            (
+                r#"{"retry_on_body": {"pattern": "x", "max_inspect_bytes": 0}}"#,
+                "max_inspect_bytes must be between",
+            ),
+            (
+                r#"{"retry_on_body": {"pattern": "x", "max_inspect_bytes": 4097}}"#,
+                "max_inspect_bytes must be between",
+            ),
+            (r#"{"retry_on_body": {"pattern": ""}}"#, "pattern must be"),
+            (
+                r#"{"retry_on_body": {"pattern": "too long", "max_inspect_bytes": 4}}"#,
+                "pattern must be",
+            ),
+        ] {
+            let err = load_err(&route(bad));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_route_session_persistence() {
+        let route = |sp: &str| route_config(&format!(r#", "session_persistence": {}"#, sp));
+
+        let file = write_config(&route("{}"));
+        let config = load(file.path()).unwrap();
+        let sp = config.vhosts["api.example.com"].routes[0]
+            .session_persistence
+            .clone()
+            .unwrap();
+        assert_eq!(sp.cookie_name, "GHOST_AFFINITY");
+        assert_eq!(sp.max_age_seconds, None);
+
+        let file = write_config(&route(
+            r#"{"cookie_name": "sticky", "max_age_seconds": 3600}"#,
+        ));
+        let config = load(file.path()).unwrap();
+        let sp = config.vhosts["api.example.com"].routes[0]
+            .session_persistence
+            .clone()
+            .unwrap();
+        assert_eq!(sp.cookie_name, "sticky");
+        assert_eq!(sp.max_age_seconds, Some(3600));
+
+        for bad in [r#""""#, r#""bad name""#, r#""a;b""#, r#""a=b""#] {
+            let err = load_err(&route(&format!(r#"{{"cookie_name": {}}}"#, bad)));
+            assert!(err.contains("cookie_name"), "unexpected error: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_backend_health_check() {
+        let config_with = |health: &str| {
+            vhost_config(&format!(
+                r#""routes": [{{
+                    "backend_groups": [{{"backends": [
+                        {{"address": "10.0.0.1", "port": 8080, "health": {}}}
+                    ]}}],
+                    "priority": 100
+                }}]"#,
+                health
+            ))
+        };
+
+        let file = write_config(&config_with("{}"));
+        let config = load(file.path()).unwrap();
+        let health = config.vhosts["api.example.com"].routes[0].backend_groups[0].backends[0]
+            .health
+            .clone()
+            .unwrap();
+        assert_eq!(health.path, "/");
+        assert_eq!(health.interval_ms, 5000);
+        assert_eq!(health.timeout_ms, 2000);
+        assert_eq!(health.expected_status, 200);
+        assert_eq!(health.healthy_threshold, 1);
+        assert_eq!(health.unhealthy_threshold, 1);
+
+        let file = write_config(&config_with(
+            r#"{"path": "/healthz", "interval_ms": 1000, "timeout_ms": 500, "expected_status": 204,
+                "healthy_threshold": 2, "unhealthy_threshold": 3}"#,
+        ));
+        let config = load(file.path()).unwrap();
+        let health = config.vhosts["api.example.com"].routes[0].backend_groups[0].backends[0]
+            .health
+            .clone()
+            .unwrap();
+        assert_eq!(health.path, "/healthz");
+        assert_eq!(health.expected_status, 204);
+        assert_eq!(health.healthy_threshold, 2);
+        assert_eq!(health.unhealthy_threshold, 3);
+
+        for (bad, expected) in [
+            (r#"{"path": "healthz"}"#, "path must start"),
+            (r#"{"interval_ms": 0}"#, "interval_ms"),
+            (r#"{"interval_ms": 1000, "timeout_ms": 2000}"#, "timeout_ms"),
+            (r#"{"expected_status": 42}"#, "expected_status"),
+            (r#"{"healthy_threshold": 0}"#, "healthy_threshold"),
+            (r#"{"unhealthy_threshold": 101}"#, "unhealthy_threshold"),
+        ] {
+            let err = load_err(&config_with(bad));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+        }
+
+        let err = load_err(
+            r#"{"version": 2, "vhosts": {"api.example.com": {"routes": [{
+                "backend_groups": [{
+                    "backends": [{"address": "10.0.0.1", "port": 8443, "health": {}}],
+                    "backend_tls": {"hostname": "api.internal"}
+                }],
+                "priority": 100
+            }]}}}"#,
+        );
+        assert!(err.contains("backend_tls"), "unexpected error: {}", err);
+    }
+
+    #[test]
+    fn test_outbound_rate_limit() {
+        let config_with = |limit: &str| {
+            vhost_config(&format!(
+                r#""routes": [{{
+                    "backend_groups": [{{
+                        "backends": [{{"address": "10.0.0.1", "port": 8080}}],
+                        "outbound_rate_limit": {}
+                    }}],
+                    "priority": 100
+                }}]"#,
+                limit
+            ))
+        };
+
+        let file = write_config(&config_with(r#"{"rps": 50}"#));
+        let config = load(file.path()).unwrap();
+        let limit = config.vhosts["api.example.com"].routes[0].backend_groups[0]
+            .outbound_rate_limit
+            .unwrap();
+        assert_eq!(limit.rps, 50);
+        assert_eq!(limit.burst(), 50);
+        assert_eq!(limit.max_wait_ms, 100);
+
+        let file = write_config(&config_with(
+            r#"{"rps": 50, "burst": 100, "max_wait_ms": 0}"#,
+        ));
+        let config = load(file.path()).unwrap();
+        let limit = config.vhosts["api.example.com"].routes[0].backend_groups[0]
+            .outbound_rate_limit
+            .unwrap();
+        assert_eq!(limit.burst(), 100);
+        assert_eq!(limit.max_wait_ms, 0);
+
+        for (bad, expected) in [
+            (r#"{"rps": 0}"#, "rps"),
+            (r#"{"rps": 10, "burst": 0}"#, "burst"),
+            (r#"{"rps": 10, "max_wait_ms": 60000}"#, "max_wait_ms"),
+        ] {
+            let err = load_err(&config_with(bad));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+            assert!(
+                err.contains("outbound_rate_limit"),
+                "unexpected error: {}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_debug_headers_config() {
+        let file = write_config(r#"{"version": 2}"#);
+        assert_eq!(load(file.path()).unwrap().debug_headers, DebugHeaders::Off);
+
+        for (value, expected) in [
+            ("false", DebugHeaders::Off),
+            ("true", DebugHeaders::Always),
+            (r#""off""#, DebugHeaders::Off),
+            (r#""always""#, DebugHeaders::Always),
+            (r#""on_request""#, DebugHeaders::OnRequest),
+        ] {
+            let file = write_config(&format!(r#"{{"version": 2, "debug_headers": {}}}"#, value));
+            assert_eq!(
+                load(file.path()).unwrap().debug_headers,
+                expected,
+                "{}",
+                value
+            );
+        }
+
+        let file = write_config(r#"{"version": 2, "debug_headers": "sometimes"}"#);
+        assert!(load(file.path()).is_err());
+
+        assert!(!DebugHeaders::Off.applies(true));
+        assert!(DebugHeaders::Always.applies(false));
+        assert!(DebugHeaders::OnRequest.applies(true));
+        assert!(!DebugHeaders::OnRequest.applies(false));
+    }
+
+    #[test]
+    fn test_cache_key_validation() {
+        let route = |cache_key: &str| {
+            route_config(&format!(
+                r#", "cache_policy": {{"cache_key": {}}}"#,
+                cache_key
+            ))
+        };
+        let file = write_config(&route(
+            r#"{"headers": ["Accept-Encoding", "X-Region"], "query_params_exclude": ["utm_source"]}"#,
+        ));
+        assert!(load(file.path()).is_ok());
+
+        for (bad, expected) in [
+            (r#"{"headers": ["Accept Encoding"]}"#, "invalid header name"),
+            (r#"{"headers": [""]}"#, "invalid header name"),
+            (r#"{"headers": ["X-Ghost-Pass"]}"#, "is internal"),
+        ] {
+            let err = load_err(&route(bad));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_routing_log_config() {
+        let file = write_config(r#"{"version": 2}"#);
+        assert_eq!(load(file.path()).unwrap().routing_log, RoutingLog::Off);
+
+        let file = write_config(r#"{"version": 2, "routing_log": "decisions"}"#);
+        assert_eq!(
+            load(file.path()).unwrap().routing_log,
+            RoutingLog::Decisions
+        );
+        let file = write_config(r#"{"version": 2, "routing_log": "full"}"#);
+        assert_eq!(load(file.path()).unwrap().routing_log, RoutingLog::Full);
+
+        let file = write_config(r#"{"version": 2, "routing_log": "verbose"}"#);
+        assert!(load(file.path()).is_err());
+    }
+
+    #[test]
+    fn test_tracing_config() {
+        let file = write_config(r#"{"version": 2}"#);
+        assert!(!load(file.path()).unwrap().tracing.enabled);
+
+        let file = write_config(r#"{"version": 2, "tracing": {"enabled": true}}"#);
+        assert!(load(file.path()).unwrap().tracing.enabled);
+        let file = write_config(r#"{"version": 2, "tracing": {}}"#);
+        assert!(!load(file.path()).unwrap().tracing.enabled);
+    }
+
+    #[test]
+    fn test_trace_sample_percent() {
+        // Everything is sampled unless told otherwise
+        let file = write_config(r#"{"version": 2}"#);
+        assert_eq!(load(file.path()).unwrap().tracing.sample_percent, 100.0);
+
+        let file =
+            write_config(r#"{"version": 2, "tracing": {"enabled": true, "sample_percent": 12.5}}"#);
+        assert_eq!(load(file.path()).unwrap().tracing.sample_percent, 12.5);
+
+        for percent in ["-1", "100.1"] {
+            let file = write_config(&format!(
+                r#"{{"version": 2, "tracing": {{"sample_percent": {}}}}}"#,
+                percent
+            ));
+            assert!(load(file.path())
+                .unwrap_err()
+                .contains("sample_percent must be between 0 and 100"));
+        }
+    }
+
+    #[test]
+    fn test_connection_id_header_config() {
+        let file = write_config(r#"{"version": 2}"#);
+        assert!(load(file.path())
+            .unwrap()
+            .tracing
+            .connection_id_header
+            .is_none());
+
+        let file = write_config(
+            r#"{"version": 2, "tracing": {"connection_id_header": "X-Gateway-Conn-Id"}}"#,
+        );
+        let config = load(file.path()).unwrap();
+        assert_eq!(
+            config.tracing.connection_id_header.as_deref(),
+            Some("X-Gateway-Conn-Id")
+        );
+        // Independent of request ID generation
+        assert!(!config.tracing.enabled);
+
+        for (name, error) in [
+            ("X Conn", "invalid header name"),
+            ("", "invalid header name"),
+            ("X-Ghost-Conn-Id", "not forwarded upstream"),
+            ("Connection", "not forwarded upstream"),
+        ] {
+            let err = load_err(&format!(
+                r#"{{"version": 2, "tracing": {{"connection_id_header": "{}"}}}}"#,
+                name
+            ));
+            assert!(err.contains(error), "{}: {}", name, err);
+        }
+    }
+
+    #[test]
+    fn test_authorized_private_config() {
+        let file = write_config(r#"{"version": 2}"#);
+        assert!(load(file.path()).unwrap().authorized_private.is_none());
+
+        // Authorization unless told otherwise
+        let file = write_config(r#"{"version": 2, "authorized_private": {}}"#);
+        let private = load(file.path()).unwrap().authorized_private.unwrap();
+        assert_eq!(private.headers, vec!["Authorization"]);
+
+        let file = write_config(
+            r#"{"version": 2, "authorized_private": {"headers": ["Authorization", "X-Api-Key"]}}"#,
+        );
+        let private = load(file.path()).unwrap().authorized_private.unwrap();
+        assert_eq!(private.headers, vec!["Authorization", "X-Api-Key"]);
+
+        for (headers, error) in [
+            ("[]", "must not be empty"),
+            (r#"["X Api Key"]"#, "invalid header name"),
+            (r#"["X-Ghost-Auth"]"#, "invalid header name"),
+        ] {
+            let err = load_err(&format!(
+                r#"{{"version": 2, "authorized_private": {{"headers": {}}}}}"#,
+                headers
+            ));
+            assert!(err.contains(error), "{}: {}", headers, err);
+        }
+    }
+
+    #[test]
+    fn test_tls_fingerprint_config() {
+        let file = write_config(r#"{"version": 2}"#);
+        assert!(load(file.path()).unwrap().tls_fingerprint.is_none());
+
+        let file = write_config(r#"{"version": 2, "tls_fingerprint": {"header": "X-JA3"}}"#);
+        let fp = load(file.path()).unwrap().tls_fingerprint.unwrap();
+        assert_eq!(fp.forward_header(), "X-JA3");
+
+        let file = write_config(
+            r#"{"version": 2, "tls_fingerprint": {"header": "X-JA3", "forward_as": "X-Client-JA3"}}"#,
+        );
+        let fp = load(file.path()).unwrap().tls_fingerprint.unwrap();
+        assert_eq!(fp.header, "X-JA3");
+        assert_eq!(fp.forward_header(), "X-Client-JA3");
+
+        for (bad, expected) in [
+            (r#"{"header": ""}"#, "invalid header name"),
+            (r#"{"header": "X JA3"}"#, "invalid header name"),
+            (r#"{"header": "X-Ghost-JA3"}"#, "not forwarded"),
+            (
+                r#"{"header": "X-JA3", "forward_as": "Connection"}"#,
+                "not forwarded",
+            ),
+        ] {
+            let err = load_err(&format!(r#"{{"version": 2, "tls_fingerprint": {}}}"#, bad));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_route_key_config() {
+        let file = write_config(r#"{"version": 2}"#);
+        assert!(load(file.path()).unwrap().route_key.is_none());
+
+        let file = write_config(
+            r#"{"version": 2, "route_key": {"source": "header", "name": "x-service"}}"#,
+        );
+        assert_eq!(
+            load(file.path()).unwrap().route_key,
+            Some(RouteKey::Header {
+                name: "x-service".to_string()
+            })
+        );
+
+        let file = write_config(r#"{"version": 2, "route_key": {"source": "path_prefix"}}"#);
+        assert_eq!(
+            load(file.path()).unwrap().route_key,
+            Some(RouteKey::PathPrefix)
+        );
+
+        for (bad, expected) in [
+            (r#"{"source": "header", "name": ""}"#, "invalid header name"),
+            (
+                r#"{"source": "header", "name": "x service"}"#,
+                "invalid header name",
+            ),
+            (
+                r#"{"source": "header", "name": "X-Ghost-Service"}"#,
+                "internal",
+            ),
+            (r#"{"source": "header"}"#, "name"),
+            (r#"{"source": "sni"}"#, "sni"),
+        ] {
+            let err = load_err(&format!(r#"{{"version": 2, "route_key": {}}}"#, bad));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_sni_header_config() {
+        let file = write_config(r#"{"version": 2}"#);
+        assert!(load(file.path()).unwrap().sni_header.is_none());
+
+        let file = write_config(
+            r#"{"version": 2, "sni_header": "X-TLS-SNI", "vhosts": {"a.example.com": {"routes": [], "reject_misdirected": true}}}"#,
+        );
+        let config = load(file.path()).unwrap();
+        assert_eq!(config.sni_header.as_deref(), Some("X-TLS-SNI"));
+        assert!(config.vhosts["a.example.com"].reject_misdirected);
+
+        for (bad, expected) in [
+            (r#""sni_header": """#, "invalid header name"),
+            (r#""sni_header": "X TLS""#, "invalid header name"),
+            (r#""sni_header": "X-Ghost-SNI""#, "internal"),
+            (
+                r#""vhosts": {"a.example.com": {"routes": [], "reject_misdirected": true}}"#,
+                "requires sni_header",
+            ),
+        ] {
+            let err = load_err(&format!(r#"{{"version": 2, {}}}"#, bad));
+            assert!(err.contains(expected), "unexpected error: {}", err);
+        }
+    }
+
+    #[test]
+    fn test_outlier_detection_config() {
+        let file = write_config(r#"{"version": 2}"#);
+        assert!(load(file.path()).unwrap().outlier_detection.is_none());
+
+        let file = write_config(r#"{"version": 2, "outlier_detection": {}}"#);
+        let od = load(file.path()).unwrap().outlier_detection.unwrap();
+        assert_eq!(od.consecutive_failures, 5);
+        assert_eq!(od.ejection_ms, 30_000);
+        assert_eq!(od.max_ejection_ms, None);
+
+        let file = write_config(
+            r#"{"version": 2, "outlier_detection": {"consecutive_failures": 3, "ejection_ms": 5000, "max_ejection_ms": 60000}}"#,
+        );
+        let od = load(file.path()).unwrap().outlier_detection.unwrap();
+        assert_eq!(od.consecutive_failures, 3);
+        assert_eq!(od.ejection_ms, 5000);
+        assert_eq!(od.max_ejection_ms, Some(60_000));
+
+        for bad in [
+            r#"{"consecutive_failures": 0}"#,
+            r#"{"ejection_ms": 0}"#,
+            r#"{"ejection_ms": 86400000}"#,
+            r#"{"ejection_ms": 5000, "max_ejection_ms": 1000}"#,
+            r#"{"max_ejection_ms": 86400000}"#,
+        ] {
+            let err = load_err(&format!(
+                r#"{{"version": 2, "outlier_detection": {}}}"#,
+                bad
+            ));
+            assert!(
+                err.contains("outlier_detection"),
+                "unexpected error: {}",
+                err
+            );
+        }
+    }
 }
@@ -0,0 +1,208 @@
+//! Adaptive connect timeout for external proxy upstreams
+//!
+//! An upstream that keeps timing out on connect would otherwise cost every
+//! request the full connect timeout before a retry can fail over. With
+//! `adaptive_connect_timeout` enabled, each consecutive connect timeout
+//! halves the deadline for the next attempt (down to a floor), and the first
+//! successful connect restores the configured value.
+//!
+//! The deadline is applied by a reqwest connector layer, so it only covers
+//! establishing new connections; pooled connections are unaffected.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tower::timeout::error::Elapsed;
+use tower::{BoxError, Layer, Service};
+
+/// Never shorten below `base / 2^MAX_HALVINGS`.
+const MAX_HALVINGS: u32 = 3;
+
+/// Absolute lower bound on the shortened deadline.
+const MIN_CONNECT_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Consecutive connect timeouts of one upstream and the deadline they imply.
+#[derive(Debug)]
+pub struct AdaptiveConnectTimeout {
+    base: Duration,
+    failures: AtomicU32,
+}
+
+impl AdaptiveConnectTimeout {
+    pub fn new(base: Duration) -> Self {
+        Self {
+            base,
+            failures: AtomicU32::new(0),
+        }
+    }
+
+    /// Deadline for the next connect attempt.
+    pub fn current(&self) -> Duration {
+        let halvings = self.failures.load(Ordering::Relaxed).min(MAX_HALVINGS);
+        (self.base / 2u32.pow(halvings)).max(MIN_CONNECT_TIMEOUT.min(self.base))
+    }
+
+    pub fn record_timeout(&self) {
+        let _ = self
+            .failures
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                Some(n.saturating_add(1))
+            });
+    }
+
+    pub fn record_success(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Connector layer applying an `AdaptiveConnectTimeout`.
+#[derive(Clone)]
+pub struct AdaptiveConnectLayer {
+    timeout: Arc<AdaptiveConnectTimeout>,
+}
+
+impl AdaptiveConnectLayer {
+    pub fn new(timeout: Arc<AdaptiveConnectTimeout>) -> Self {
+        Self { timeout }
+    }
+}
+
+impl<S> Layer<S> for AdaptiveConnectLayer {
+    type Service = AdaptiveConnect<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        AdaptiveConnect {
+            inner,
+            timeout: self.timeout.clone(),
+        }
+    }
+}
+
+/// Connector wrapped by `AdaptiveConnectLayer`.
+#[derive(Clone)]
+pub struct AdaptiveConnect<S> {
+    inner: S,
+    timeout: Arc<AdaptiveConnectTimeout>,
+}
+
+impl<S, R> Service<R> for AdaptiveConnect<S>
+where
+    S: Service<R>,
+    S::Error: Into<BoxError>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        let connecting = self.inner.call(req);
+        let timeout = self.timeout.clone();
+        Box::pin(async move {
+            match tokio::time::timeout(timeout.current(), connecting).await {
+                Ok(Ok(conn)) => {
+                    timeout.record_success();
+                    Ok(conn)
+                }
+                // Refused or reset connections fail fast on their own
+                Ok(Err(e)) => Err(e.into()),
+                Err(_) => {
+                    timeout.record_timeout();
+                    // reqwest reports `Elapsed` as a timeout, like its own
+                    // connect_timeout
+                    Err(Elapsed::new().into())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_current_halves_down_to_floor() {
+        let t = AdaptiveConnectTimeout::new(Duration::from_millis(800));
+        assert_eq!(t.current(), Duration::from_millis(800));
+        t.record_timeout();
+        assert_eq!(t.current(), Duration::from_millis(400));
+        t.record_timeout();
+        t.record_timeout();
+        assert_eq!(t.current(), Duration::from_millis(100));
+        t.record_timeout();
+        assert_eq!(t.current(), Duration::from_millis(100));
+        t.record_success();
+        assert_eq!(t.current(), Duration::from_millis(800));
+    }
+
+    #[test]
+    fn test_current_respects_min() {
+        let t = AdaptiveConnectTimeout::new(Duration::from_millis(200));
+        for _ in 0..5 {
+            t.record_timeout();
+        }
+        assert_eq!(t.current(), MIN_CONNECT_TIMEOUT);
+
+        // A base below the floor is never raised
+        let t = AdaptiveConnectTimeout::new(Duration::from_millis(20));
+        t.record_timeout();
+        assert_eq!(t.current(), Duration::from_millis(20));
+    }
+
+    /// Connector that hangs when asked for "hang" and succeeds otherwise.
+    #[derive(Clone)]
+    struct FakeConnector;
+
+    impl Service<&'static str> for FakeConnector {
+        type Response = ();
+        type Error = BoxError;
+        type Future = Pin<Box<dyn Future<Output = Result<(), BoxError>> + Send>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), BoxError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: &'static str) -> Self::Future {
+            Box::pin(async move {
+                if req == "hang" {
+                    std::future::pending::<()>().await;
+                }
+                Ok(())
+            })
+        }
+    }
+
+    #[test]
+    fn test_repeated_connect_timeouts_shorten_until_success() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        let timeout = Arc::new(AdaptiveConnectTimeout::new(Duration::from_secs(8)));
+        let mut connector = AdaptiveConnectLayer::new(timeout.clone()).layer(FakeConnector);
+
+        rt.block_on(async {
+            let mut waited = Vec::new();
+            for _ in 0..4 {
+                let start = tokio::time::Instant::now();
+                let err = connector.call("hang").await.unwrap_err();
+                assert!(err.is::<Elapsed>());
+                waited.push(start.elapsed().as_secs());
+            }
+            assert_eq!(waited, vec![8, 4, 2, 1]);
+
+            connector.call("ok").await.unwrap();
+            assert_eq!(timeout.current(), Duration::from_secs(8));
+        });
+    }
+}
@@ -0,0 +1,287 @@
+//! CORS for routes with a `cors` filter.
+//!
+//! A preflight (`OPTIONS` with `Origin` and `Access-Control-Request-Method`)
+//! is answered with a 204 by the synthetic preflight backend and never
+//! reaches the upstream. `route_request()` computes the answer's headers and
+//! hands them over in [`PREFLIGHT_HEADER`], the way redirects get their
+//! config. Other requests from an allowed origin get their
+//! `Access-Control-*` headers added to the route's response filters, which
+//! `ghost.deliver()` applies, so a cache hit gets the headers for its own
+//! origin.
+//!
+//! An origin that isn't allowed gets no CORS headers at all, which the
+//! browser takes as a refusal.
+
+use crate::config::{CorsFilter, HTTPHeaderAction, ResponseHeaderFilter};
+
+/// Request header carrying a preflight's answer to the preflight backend
+pub const PREFLIGHT_HEADER: &str = "X-Ghost-Cors-Preflight";
+
+/// `Access-Control-Max-Age` when the filter doesn't set `max_age`
+const DEFAULT_MAX_AGE: u32 = 5;
+
+/// Whether a request is a CORS preflight
+pub fn is_preflight(method: &str, origin: Option<&str>, request_method: Option<&str>) -> bool {
+    method == "OPTIONS" && origin.is_some() && request_method.is_some()
+}
+
+/// Whether `origin` is one of the filter's allowed origins
+pub fn origin_allowed(cors: &CorsFilter, origin: &str) -> bool {
+    let origin = normalize_origin(origin);
+    cors.allow_origins
+        .iter()
+        .any(|allowed| allowed == "*" || origin_matches(&normalize_origin(allowed), &origin))
+}
+
+/// Lowercased, and without the scheme's default port, which an origin may
+/// or may not spell out
+fn normalize_origin(origin: &str) -> String {
+    let origin = origin.to_ascii_lowercase();
+    for (scheme, port) in [("http://", ":80"), ("https://", ":443")] {
+        if origin.starts_with(scheme) {
+            if let Some(origin) = origin.strip_suffix(port) {
+                return origin.to_string();
+            }
+        }
+    }
+    origin
+}
+
+/// Match a normalized origin against a normalized allowed one. A `*` at the
+/// start of the allowed host stands for one or more labels.
+fn origin_matches(allowed: &str, origin: &str) -> bool {
+    match allowed.split_once("://*") {
+        Some((scheme, suffix)) => origin
+            .strip_prefix(scheme)
+            .and_then(|o| o.strip_prefix("://"))
+            .is_some_and(|host| host.len() > suffix.len() && host.ends_with(suffix)),
+        None => allowed == origin,
+    }
+}
+
+/// `Access-Control-Allow-Origin` for an allowed `origin`: `*` when every
+/// origin is allowed and credentials aren't, which must name the origin
+fn allow_origin<'a>(cors: &CorsFilter, origin: &'a str) -> &'a str {
+    if !cors.allow_credentials && cors.allow_origins.iter().any(|o| o == "*") {
+        "*"
+    } else {
+        origin
+    }
+}
+
+/// `names` comma-joined, or what the request asked for in place of a `*`
+/// when credentials are allowed and a literal `*` wouldn't be honored
+fn allow_list(cors: &CorsFilter, names: &[String], requested: Option<&str>) -> Option<String> {
+    if names.iter().any(|n| n == "*") {
+        if cors.allow_credentials {
+            return requested.map(str::to_string);
+        }
+        return Some("*".to_string());
+    }
+    (!names.is_empty()).then(|| names.join(", "))
+}
+
+fn action(name: &str, value: impl Into<String>) -> HTTPHeaderAction {
+    HTTPHeaderAction {
+        name: name.to_string(),
+        value: value.into(),
+    }
+}
+
+/// Headers answering a preflight from `origin`. Empty when the origin isn't
+/// allowed, so the preflight fails.
+pub fn preflight_headers(
+    cors: &CorsFilter,
+    origin: &str,
+    request_method: Option<&str>,
+    request_headers: Option<&str>,
+) -> Vec<HTTPHeaderAction> {
+    if !origin_allowed(cors, origin) {
+        return Vec::new();
+    }
+    let allow_origin = allow_origin(cors, origin);
+    let mut headers = vec![action("Access-Control-Allow-Origin", allow_origin)];
+    if cors.allow_credentials {
+        headers.push(action("Access-Control-Allow-Credentials", "true"));
+    }
+    if let Some(methods) = allow_list(cors, &cors.allow_methods, request_method) {
+        headers.push(action("Access-Control-Allow-Methods", methods));
+    }
+    if let Some(names) = allow_list(cors, &cors.allow_headers, request_headers) {
+        headers.push(action("Access-Control-Allow-Headers", names));
+    }
+    headers.push(action(
+        "Access-Control-Max-Age",
+        cors.max_age.unwrap_or(DEFAULT_MAX_AGE).to_string(),
+    ));
+    if allow_origin != "*" {
+        headers.push(action("Vary", "Origin"));
+    }
+    headers
+}
+
+/// Add the CORS headers of a response to a request from `origin` to the
+/// route's response filter. Nothing is added when the origin isn't allowed.
+pub fn add_response_headers(filter: &mut ResponseHeaderFilter, cors: &CorsFilter, origin: &str) {
+    if !origin_allowed(cors, origin) {
+        return;
+    }
+    let allow_origin = allow_origin(cors, origin);
+    filter
+        .set
+        .push(action("Access-Control-Allow-Origin", allow_origin));
+    if cors.allow_credentials {
+        filter
+            .set
+            .push(action("Access-Control-Allow-Credentials", "true"));
+    }
+    if !cors.expose_headers.is_empty() {
+        filter.set.push(action(
+            "Access-Control-Expose-Headers",
+            cors.expose_headers.join(", "),
+        ));
+    }
+    if allow_origin != "*" {
+        filter.add.push(action("Vary", "Origin"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &[&str]) -> CorsFilter {
+        CorsFilter {
+            allow_origins: origins.iter().map(|o| o.to_string()).collect(),
+            allow_methods: vec!["GET".to_string(), "PUT".to_string()],
+            allow_headers: vec!["Content-Type".to_string()],
+            expose_headers: vec!["X-Request-Id".to_string()],
+            allow_credentials: false,
+            max_age: None,
+        }
+    }
+
+    fn names(headers: &[HTTPHeaderAction]) -> Vec<(&str, &str)> {
+        headers
+            .iter()
+            .map(|h| (h.name.as_str(), h.value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_origin_allowed() {
+        let filter = cors(&["https://app.example.com", "https://*.example.net"]);
+        assert!(origin_allowed(&filter, "https://app.example.com"));
+        assert!(origin_allowed(&filter, "HTTPS://App.Example.com"));
+        // The scheme's default port is implied
+        assert!(origin_allowed(&filter, "https://app.example.com:443"));
+        assert!(!origin_allowed(&filter, "https://app.example.com:8443"));
+        assert!(!origin_allowed(&filter, "http://app.example.com"));
+        assert!(!origin_allowed(&filter, "https://evil.example.com"));
+
+        // A wildcard stands for any number of labels, but at least one
+        assert!(origin_allowed(&filter, "https://a.example.net"));
+        assert!(origin_allowed(&filter, "https://a.b.example.net"));
+        assert!(!origin_allowed(&filter, "https://example.net"));
+        assert!(!origin_allowed(&filter, "https://evilexample.net"));
+        assert!(!origin_allowed(&filter, "https://a.example.net.evil.com"));
+        assert!(!origin_allowed(&filter, "null"));
+
+        assert!(origin_allowed(&cors(&["*"]), "http://localhost:3000"));
+        assert!(origin_allowed(
+            &cors(&["http://*"]),
+            "http://localhost:3000"
+        ));
+        assert!(!origin_allowed(&cors(&["http://*"]), "https://localhost"));
+    }
+
+    #[test]
+    fn test_preflight_headers() {
+        let filter = cors(&["https://app.example.com"]);
+        assert_eq!(
+            names(&preflight_headers(
+                &filter,
+                "https://app.example.com",
+                Some("PUT"),
+                Some("content-type")
+            )),
+            [
+                ("Access-Control-Allow-Origin", "https://app.example.com"),
+                ("Access-Control-Allow-Methods", "GET, PUT"),
+                ("Access-Control-Allow-Headers", "Content-Type"),
+                ("Access-Control-Max-Age", "5"),
+                ("Vary", "Origin"),
+            ]
+        );
+        assert!(
+            preflight_headers(&filter, "https://evil.example.com", Some("PUT"), None).is_empty()
+        );
+
+        // Wildcards stay wildcards without credentials
+        let mut filter = CorsFilter {
+            allow_methods: vec!["*".to_string()],
+            allow_headers: vec!["*".to_string()],
+            max_age: Some(600),
+            ..cors(&["*"])
+        };
+        assert_eq!(
+            names(&preflight_headers(
+                &filter,
+                "https://a.test",
+                Some("PUT"),
+                None
+            )),
+            [
+                ("Access-Control-Allow-Origin", "*"),
+                ("Access-Control-Allow-Methods", "*"),
+                ("Access-Control-Allow-Headers", "*"),
+                ("Access-Control-Max-Age", "600"),
+            ]
+        );
+
+        // ... and echo the request with them
+        filter.allow_credentials = true;
+        assert_eq!(
+            names(&preflight_headers(
+                &filter,
+                "https://a.test",
+                Some("PUT"),
+                None
+            )),
+            [
+                ("Access-Control-Allow-Origin", "https://a.test"),
+                ("Access-Control-Allow-Credentials", "true"),
+                ("Access-Control-Allow-Methods", "PUT"),
+                ("Access-Control-Max-Age", "600"),
+                ("Vary", "Origin"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_add_response_headers() {
+        let filter = cors(&["https://app.example.com"]);
+        let mut resp = ResponseHeaderFilter::default();
+        add_response_headers(&mut resp, &filter, "https://app.example.com");
+        assert_eq!(
+            names(&resp.set),
+            [
+                ("Access-Control-Allow-Origin", "https://app.example.com"),
+                ("Access-Control-Expose-Headers", "X-Request-Id"),
+            ]
+        );
+        assert_eq!(names(&resp.add), [("Vary", "Origin")]);
+
+        let mut resp = ResponseHeaderFilter::default();
+        add_response_headers(&mut resp, &filter, "https://evil.example.com");
+        assert!(resp.set.is_empty() && resp.add.is_empty());
+    }
+
+    #[test]
+    fn test_is_preflight() {
+        assert!(is_preflight("OPTIONS", Some("https://a.test"), Some("PUT")));
+        assert!(!is_preflight("OPTIONS", Some("https://a.test"), None));
+        assert!(!is_preflight("OPTIONS", None, Some("PUT")));
+        assert!(!is_preflight("GET", Some("https://a.test"), Some("PUT")));
+    }
+}
@@ -0,0 +1,64 @@
+//! Synthetic 204 backend for CORS preflights
+//!
+//! Answers the preflights of routes with a `cors` filter, so they never reach
+//! the upstream. The headers come from `route_request()` in
+//! [`PREFLIGHT_HEADER`]; an origin that isn't allowed gets none.
+
+use varnish::vcl::{Ctx, StrOrBytes, VclBackend, VclError};
+
+use crate::config::HTTPHeaderAction;
+use crate::cors::PREFLIGHT_HEADER;
+use crate::internal_headers;
+
+/// Synthetic backend answering preflights with a 204 and the headers
+/// `route_request()` left in [`PREFLIGHT_HEADER`]
+pub struct PreflightBackend;
+
+impl VclBackend<()> for PreflightBackend {
+    fn get_response(&self, ctx: &mut Ctx) -> Result<Option<()>, VclError> {
+        internal_headers::restore(ctx);
+        let bereq = ctx
+            .http_bereq
+            .as_mut()
+            .ok_or_else(|| VclError::new("Missing bereq in preflight backend".to_string()))?;
+        let json = match bereq.header(PREFLIGHT_HEADER) {
+            Some(StrOrBytes::Utf8(s)) => s.to_string(),
+            _ => return Err(VclError::new("Missing preflight header".to_string())),
+        };
+        bereq.unset_header(PREFLIGHT_HEADER);
+        let headers: Vec<HTTPHeaderAction> = serde_json::from_str(&json)
+            .map_err(|e| VclError::new(format!("Invalid preflight header: {}", e)))?;
+
+        let beresp = ctx
+            .http_beresp
+            .as_mut()
+            .ok_or_else(|| VclError::new("Missing beresp in preflight backend".to_string()))?;
+        beresp.set_status(204);
+        // Only CORS headers, whatever the internal header says
+        for header in headers.iter().filter(|h| is_cors_header(&h.name)) {
+            beresp.set_header(&header.name, &header.value)?;
+        }
+        Ok(None)
+    }
+}
+
+fn is_cors_header(name: &str) -> bool {
+    name.eq_ignore_ascii_case("Vary")
+        || name
+            .get(..15)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("Access-Control-"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_cors_header() {
+        assert!(is_cors_header("Access-Control-Allow-Origin"));
+        assert!(is_cors_header("access-control-max-age"));
+        assert!(is_cors_header("Vary"));
+        assert!(!is_cors_header("Location"));
+        assert!(!is_cors_header("Access"));
+    }
+}
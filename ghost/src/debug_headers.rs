@@ -0,0 +1,204 @@
+//! Opt-in routing debug headers.
+//!
+//! With `debug_headers` enabled in ghost.json, routing in vcl_recv records
+//! where a request went in a [`DebugInfo`] on req, and `ghost.deliver()`
+//! turns it into `X-Ghost-Vhost`, `X-Ghost-Route` and `X-Ghost-Backend`
+//! response headers. Under `on_request`, only requests sending
+//! `X-Ghost-Debug: 1` get them. Routes with `timeouts.expose_budget_header`
+//! add `X-Ghost-Budget`, the request budget as it stands at delivery.
+//! Copies of those headers sent by an upstream are always removed, so a
+//! response never carries debug headers ghost didn't set.
+
+use serde::{Deserialize, Serialize};
+use varnish::vcl::{HttpHeaders, StrOrBytes};
+
+/// Request header asking for debug headers under `debug_headers: on_request`.
+pub(crate) const DEBUG_REQUEST_HEADER: &str = "X-Ghost-Debug";
+
+/// Header carrying a [`DebugInfo`] from routing to `ghost.deliver()`.
+pub(crate) const DEBUG_INFO_HEADER: &str = "X-Ghost-Debug-Info";
+
+pub(crate) const VHOST_HEADER: &str = "X-Ghost-Vhost";
+pub(crate) const ROUTE_HEADER: &str = "X-Ghost-Route";
+pub(crate) const BACKEND_HEADER: &str = "X-Ghost-Backend";
+pub(crate) const BUDGET_HEADER: &str = "X-Ghost-Budget";
+
+/// Where routing sent a request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct DebugInfo {
+    /// Matched vhost, as written in ghost.json (e.g. `*.example.com`)
+    pub vhost: String,
+    /// Name of the matched route, when the operator gave it one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    /// Rule index of the matched route; `i32::MAX` for `default_backends`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rule: Option<i32>,
+    /// Selected backend key. None when a synthetic response (redirect,
+    /// 404, 503) answers the request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    /// The route's request budget, for routes exposing it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget: Option<Budget>,
+}
+
+/// A route's `request_ms` budget and the deadline it set at routing time.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Budget {
+    pub budget_ms: u64,
+    /// Epoch milliseconds by which the request should complete
+    pub deadline_ms: u64,
+}
+
+impl Budget {
+    /// `X-Ghost-Budget` value at `now_ms`, e.g.
+    /// `budget=5000ms; elapsed=120ms; remaining=4880ms`. Both elapsed and
+    /// remaining saturate, so an overrun reads `remaining=0ms`.
+    pub fn header_value(&self, now_ms: u64) -> String {
+        let remaining = self.deadline_ms.saturating_sub(now_ms);
+        format!(
+            "budget={}ms; elapsed={}ms; remaining={}ms",
+            self.budget_ms,
+            self.budget_ms.saturating_sub(remaining),
+            remaining
+        )
+    }
+}
+
+impl DebugInfo {
+    pub fn to_header(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn from_header(value: &str) -> Option<Self> {
+        serde_json::from_str(value).ok()
+    }
+
+    /// `X-Ghost-Route` value: the route name and rule index, e.g.
+    /// `default/api; rule=2`. None when no route matched.
+    fn route_value(&self) -> Option<String> {
+        match (&self.route, self.rule) {
+            (_, Some(i32::MAX)) => Some("default_backends".to_string()),
+            (Some(name), Some(rule)) => Some(format!("{}; rule={}", name, rule)),
+            (Some(name), None) => Some(name.clone()),
+            (None, Some(rule)) => Some(format!("rule={}", rule)),
+            (None, None) => None,
+        }
+    }
+
+    /// Response headers to emit, in order.
+    pub fn response_headers(&self) -> Vec<(&'static str, String)> {
+        let mut headers = vec![(VHOST_HEADER, self.vhost.clone())];
+        if let Some(route) = self.route_value() {
+            headers.push((ROUTE_HEADER, route));
+        }
+        if let Some(backend) = &self.backend {
+            headers.push((BACKEND_HEADER, backend.clone()));
+        }
+        headers
+    }
+}
+
+/// Whether the request asked for debug headers with `X-Ghost-Debug: 1`.
+pub(crate) fn requested(http: &HttpHeaders) -> bool {
+    match http.header(DEBUG_REQUEST_HEADER) {
+        Some(StrOrBytes::Utf8(s)) => s.trim() == "1",
+        _ => false,
+    }
+}
+
+/// Replace any debug headers on the response with the ones for `info`.
+pub(crate) fn apply(resp: &mut HttpHeaders, info: Option<&DebugInfo>) {
+    for name in [VHOST_HEADER, ROUTE_HEADER, BACKEND_HEADER, BUDGET_HEADER] {
+        resp.unset_header(name);
+    }
+    for (name, value) in info.map(DebugInfo::response_headers).unwrap_or_default() {
+        let _ = resp.set_header(name, &value);
+    }
+    if let Some(budget) = info.and_then(|i| i.budget) {
+        let value = budget.header_value(crate::retry::now_ms());
+        let _ = resp.set_header(BUDGET_HEADER, &value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(route: Option<&str>, rule: Option<i32>, backend: Option<&str>) -> DebugInfo {
+        DebugInfo {
+            vhost: "api.example.com".to_string(),
+            route: route.map(str::to_string),
+            rule,
+            backend: backend.map(str::to_string),
+            budget: None,
+        }
+    }
+
+    #[test]
+    fn test_debug_info_header_round_trip() {
+        let i = info(Some("default/api"), Some(2), Some("10.0.0.1:8080"));
+        assert_eq!(DebugInfo::from_header(&i.to_header()).unwrap(), i);
+
+        let bare = info(None, None, None);
+        assert_eq!(bare.to_header(), r#"{"vhost":"api.example.com"}"#);
+        assert_eq!(DebugInfo::from_header(&bare.to_header()).unwrap(), bare);
+        assert!(DebugInfo::from_header("{not json").is_none());
+    }
+
+    #[test]
+    fn test_debug_response_headers() {
+        let headers = info(Some("default/api"), Some(2), Some("10.0.0.1:8080")).response_headers();
+        assert_eq!(
+            headers,
+            vec![
+                (VHOST_HEADER, "api.example.com".to_string()),
+                (ROUTE_HEADER, "default/api; rule=2".to_string()),
+                (BACKEND_HEADER, "10.0.0.1:8080".to_string()),
+            ]
+        );
+
+        let headers = info(None, Some(i32::MAX), Some("10.0.0.1:8080")).response_headers();
+        assert_eq!(headers[1], (ROUTE_HEADER, "default_backends".to_string()));
+
+        let headers = info(None, Some(0), None).response_headers();
+        assert_eq!(
+            headers,
+            vec![
+                (VHOST_HEADER, "api.example.com".to_string()),
+                (ROUTE_HEADER, "rule=0".to_string()),
+            ]
+        );
+
+        // A vhost that matched no route
+        assert_eq!(info(None, None, None).response_headers().len(), 1);
+    }
+
+    #[test]
+    fn test_budget_header_value() {
+        let budget = Budget {
+            budget_ms: 5000,
+            deadline_ms: 1_000_005_000,
+        };
+        assert_eq!(
+            budget.header_value(1_000_000_120),
+            "budget=5000ms; elapsed=120ms; remaining=4880ms"
+        );
+        assert_eq!(
+            budget.header_value(1_000_000_000),
+            "budget=5000ms; elapsed=0ms; remaining=5000ms"
+        );
+        assert_eq!(
+            budget.header_value(1_000_007_000),
+            "budget=5000ms; elapsed=5000ms; remaining=0ms"
+        );
+
+        let mut i = info(Some("default/api"), Some(0), Some("10.0.0.1:8080"));
+        i.budget = Some(budget);
+        assert_eq!(DebugInfo::from_header(&i.to_header()).unwrap(), i);
+        assert!(i
+            .to_header()
+            .contains(r#""budget":{"budget_ms":5000,"deadline_ms":1000005000}"#));
+    }
+}
@@ -4,25 +4,52 @@
 //! It implements the VclDirector trait to integrate with Varnish's director system.
 
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::PathBuf;
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
 
 use arc_swap::ArcSwap;
-use parking_lot::RwLock;
-use regex::Regex;
+use parking_lot::{Mutex, RwLock};
+use regex::{Regex, RegexBuilder};
+use varnish::ffi::VCL_MET_BACKEND_ERROR;
 use varnish::vcl::{
     Backend, BackendRef, Buffer, Ctx, HttpHeaders, LogTag, ProbeResult, StrOrBytes, VclDirector,
     VclError,
 };
 
 use crate::backend_pool::BackendPool;
-use crate::config::{BackendGroup, Config, HeaderMatch, MatchType, PathMatch, PathMatchType, QueryParamMatch};
+use crate::bad_request_backend::{BadRequestBackend, BadRequestBody};
+use crate::config::{
+    self, BackendGroup, Config, DebugHeaders, ForwardHost, HTTPHeaderAction, HashSource,
+    HeaderMatch, HostMatchKind, MatchType, PathMatch, PathMatchType, QosClass, QueryParamMatch,
+    RetryPolicy, Route, RouteFilters, RouteKey, RouteTimeouts, RoutingLog, SecurityHeaders,
+    SelectionPolicy, SessionPersistence, ShadowSelection, TlsFingerprintConfig, VHost,
+};
+use crate::cors_backend::PreflightBackend;
+use crate::debug_headers::{self, Budget, DebugInfo};
+use crate::external_backend::ClientParams;
+use crate::hash_ring::HashRing;
+use crate::health::HealthProbes;
 use crate::internal_error_backend::{InternalErrorBackend, InternalErrorBody};
+use crate::internal_headers;
+use crate::method_not_allowed_backend::{MethodNotAllowedBackend, MethodNotAllowedBody};
+use crate::misdirected_backend::{MisdirectedBackend, MisdirectedBody};
+use crate::nonce::NonceStore;
+use crate::nonce_rejected_backend::{NonceRejectedBackend, NonceRejectedBody};
 use crate::not_found_backend::{NotFoundBackend, NotFoundBody};
+use crate::rate_limit::{ClientRateLimiter, RATE_LIMIT_HEADER};
+use crate::rate_limited_backend::{RateLimitedBackend, RateLimitedBody};
 use crate::redirect_backend::{RedirectBackend, RedirectBody};
+use crate::reload_diff::{ReloadDiff, Snapshot};
+use crate::retry::{RetryState, Trigger, BODY_MATCH_HEADER, RETRY_STATE_HEADER};
+use crate::routing_log::Decision;
+use crate::shadow_diff::ShadowDiffer;
 use crate::sync_wrapper::SendSyncBackendRef;
+use crate::trace_context;
+use crate::unavailable_backend::{UnavailableBackend, UnavailableBody};
+use crate::validate::Issue;
 use crate::vhost_director;
 use crate::vhost_director::VhostDirector;
 
@@ -34,6 +61,33 @@ pub struct WeightedBackendGroup {
     pub weight: u32,
     /// Backend pool keys ("address:port") within this group
     pub backends: Vec<String>,
+    /// Draining backend keys. Kept out of `backends` so they attract no new
+    /// sessions; only the session persistence cookie still routes to them.
+    pub draining: Vec<String>,
+    /// Backends addressed by a DNS name that didn't resolve, as `name:port`.
+    /// They make the group count as configured, so it answers 503, not 500.
+    pub unresolved: Vec<String>,
+}
+
+/// Whether all of `groups` have the same non-zero weight, making a weighted
+/// pick among them a uniform one
+pub fn equal_weights(groups: &[WeightedBackendGroup]) -> bool {
+    groups
+        .first()
+        .is_some_and(|first| first.weight > 0 && groups.iter().all(|g| g.weight == first.weight))
+}
+
+impl WeightedBackendGroup {
+    /// Backends eligible for new sessions. Falls back to the draining set
+    /// when every backend in the group is draining, which selection only
+    /// sees once every group of the route is (see `new_session_groups`).
+    pub fn selectable(&self) -> &[String] {
+        if self.backends.is_empty() {
+            &self.draining
+        } else {
+            &self.backends
+        }
+    }
 }
 
 /// Compiled path match for efficient matching
@@ -72,6 +126,171 @@ impl PathMatchCompiled {
     }
 }
 
+/// Piece of a rewrite path template: literal text or a `$1` / `${name}`
+/// capture group reference.
+#[derive(Debug, PartialEq)]
+enum TemplatePart<'a> {
+    Literal(&'a str),
+    Group(&'a str),
+}
+
+/// Split a rewrite path template into literal text and capture references.
+/// `$$` is a literal `$`, as is a `$` not followed by a digit or `{`.
+fn parse_template(template: &str) -> Result<Vec<TemplatePart<'_>>, String> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(pos) = rest.find('$') {
+        if pos > 0 {
+            parts.push(TemplatePart::Literal(&rest[..pos]));
+        }
+        let after = &rest[pos + 1..];
+        if let Some(braced) = after.strip_prefix('{') {
+            let end = braced
+                .find('}')
+                .ok_or_else(|| format!("unterminated capture reference in '{}'", template))?;
+            if end == 0 {
+                return Err(format!("empty capture reference in '{}'", template));
+            }
+            parts.push(TemplatePart::Group(&braced[..end]));
+            rest = &braced[end + 1..];
+        } else if let Some(escaped) = after.strip_prefix('$') {
+            parts.push(TemplatePart::Literal("$"));
+            rest = escaped;
+        } else {
+            let digits = after.len() - after.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+            if digits == 0 {
+                parts.push(TemplatePart::Literal("$"));
+            } else {
+                parts.push(TemplatePart::Group(&after[..digits]));
+            }
+            rest = &after[digits..];
+        }
+    }
+    if !rest.is_empty() {
+        parts.push(TemplatePart::Literal(rest));
+    }
+    Ok(parts)
+}
+
+/// Check that every capture reference in a rewrite path template names a
+/// group of the route's path match. References need a `RegularExpression`
+/// match to be resolved against.
+pub fn validate_capture_refs(
+    template: &str,
+    path_match: Option<&PathMatchCompiled>,
+) -> Result<(), String> {
+    for part in parse_template(template)? {
+        let TemplatePart::Group(group) = part else {
+            continue;
+        };
+        let Some(PathMatchCompiled::Regex(re)) = path_match else {
+            return Err(format!(
+                "'{}' references capture group '{}' but the route has no RegularExpression path match",
+                template, group
+            ));
+        };
+        let defined = match group.parse::<usize>() {
+            Ok(index) => index < re.captures_len(),
+            Err(_) => re.capture_names().flatten().any(|name| name == group),
+        };
+        if !defined {
+            return Err(format!(
+                "'{}' references capture group '{}', which '{}' does not define",
+                template,
+                group,
+                re.as_str()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Substitute capture references in a rewrite path template with the groups
+/// captured from `path` by the route's regex path match. Groups that did not
+/// participate in the match expand to nothing. The template is returned
+/// unchanged when there is no regex match to take captures from.
+pub fn expand_captures(
+    template: &str,
+    path_match: Option<&PathMatchCompiled>,
+    path: &str,
+) -> String {
+    let Some(PathMatchCompiled::Regex(re)) = path_match else {
+        return template.to_string();
+    };
+    let (Some(caps), Ok(parts)) = (re.captures(path), parse_template(template)) else {
+        return template.to_string();
+    };
+    let mut out = String::with_capacity(template.len());
+    expand_parts(&parts, &caps, &mut out);
+    out
+}
+
+fn expand_parts(parts: &[TemplatePart<'_>], caps: &regex::Captures<'_>, out: &mut String) {
+    for part in parts {
+        match part {
+            TemplatePart::Literal(text) => out.push_str(text),
+            TemplatePart::Group(group) => {
+                let m = match group.parse::<usize>() {
+                    Ok(index) => caps.get(index),
+                    Err(_) => caps.name(group),
+                };
+                if let Some(m) = m {
+                    out.push_str(m.as_str());
+                }
+            }
+        }
+    }
+}
+
+/// Replace the first match of `re` in `path` with `substitution`, whose
+/// capture references take the groups of that match. A path `re` doesn't
+/// match is returned unchanged.
+pub fn substitute_path(re: &Regex, substitution: &str, path: &str) -> String {
+    let Ok(parts) = parse_template(substitution) else {
+        return path.to_string();
+    };
+    re.replace(path, |caps: &regex::Captures<'_>| {
+        let mut out = String::new();
+        expand_parts(&parts, caps, &mut out);
+        out
+    })
+    .into_owned()
+}
+
+/// Regex a `RegexSubstitution` URL rewrite applies: its own `pattern`, or
+/// the route's `RegularExpression` path match. None for other rewrites.
+fn compile_url_rewrite(
+    rewrite: &crate::config::URLRewriteFilter,
+    path_match: Option<&PathMatchCompiled>,
+) -> Result<Option<Arc<Regex>>, String> {
+    if rewrite.path_type.as_deref() != Some("RegexSubstitution") {
+        return Ok(None);
+    }
+    let substitution = rewrite
+        .substitution
+        .as_ref()
+        .ok_or("RegexSubstitution requires a substitution")?;
+    let re = match (&rewrite.pattern, path_match) {
+        (Some(pattern), _) => Arc::new(
+            Regex::new(pattern)
+                .map_err(|e| format!("Invalid regex pattern '{}': {}", pattern, e))?,
+        ),
+        (None, Some(PathMatchCompiled::Regex(re))) => Arc::clone(re),
+        (None, _) => {
+            return Err(
+                "RegexSubstitution without a pattern requires a RegularExpression path match"
+                    .to_string(),
+            )
+        }
+    };
+    validate_capture_refs(
+        substitution,
+        Some(&PathMatchCompiled::Regex(Arc::clone(&re))),
+    )
+    .map_err(|e| format!("Invalid substitution: {}", e))?;
+    Ok(Some(re))
+}
+
 /// Compiled bypass header rule for efficient per-request matching.
 /// Pre-compiled at config load time to avoid regex compilation on every request.
 #[derive(Debug, Clone)]
@@ -85,46 +304,94 @@ pub enum BypassHeaderCompiled {
 /// Compiled header match for efficient matching
 #[derive(Debug, Clone)]
 pub enum HeaderMatchCompiled {
-    Exact { name: String, value: String },
-    Regex { name: String, regex: Arc<Regex> },
+    Exact {
+        name: String,
+        value: String,
+        case_insensitive: bool,
+    },
+    Regex {
+        name: String,
+        regex: Arc<Regex>,
+    },
+    Present {
+        name: String,
+    },
+    Absent {
+        name: String,
+    },
 }
 
 impl HeaderMatchCompiled {
     /// Create from config HeaderMatch
     fn from_config(hm: &HeaderMatch) -> Result<Self, String> {
         let name = hm.name.to_lowercase(); // Case-insensitive per HTTP spec
+        let value = hm.value.clone().unwrap_or_default();
         match hm.match_type {
             MatchType::Exact => Ok(HeaderMatchCompiled::Exact {
                 name,
-                value: hm.value.clone(),
+                value,
+                case_insensitive: hm.case_insensitive,
             }),
             MatchType::RegularExpression => {
-                let re = Regex::new(&hm.value)
-                    .map_err(|e| format!("Invalid regex '{}': {}", hm.value, e))?;
+                let re = RegexBuilder::new(&value)
+                    .case_insensitive(hm.case_insensitive)
+                    .build()
+                    .map_err(|e| format!("Invalid regex '{}': {}", value, e))?;
                 Ok(HeaderMatchCompiled::Regex {
                     name,
                     regex: Arc::new(re),
                 })
             }
+            MatchType::Present => Ok(HeaderMatchCompiled::Present { name }),
+            MatchType::Absent => Ok(HeaderMatchCompiled::Absent { name }),
         }
     }
 
-    /// Check if this header match matches the given request
+    /// Check if this header match matches the given request.
+    /// A repeated header matches if any of its instances does.
     /// Works with borrowed data - no allocations
     pub fn matches(&self, bereq: &HttpHeaders) -> bool {
-        let header_value = match bereq.header(match self {
-            HeaderMatchCompiled::Exact { name, .. } => name,
-            HeaderMatchCompiled::Regex { name, .. } => name,
-        }) {
-            Some(v) => v,
-            None => return false,
+        self.matches_headers(bereq)
+    }
+
+    fn matches_headers<'a>(
+        &self,
+        headers: impl IntoIterator<Item = (&'a str, StrOrBytes<'a>)>,
+    ) -> bool {
+        let name = match self {
+            HeaderMatchCompiled::Exact { name, .. }
+            | HeaderMatchCompiled::Regex { name, .. }
+            | HeaderMatchCompiled::Present { name }
+            | HeaderMatchCompiled::Absent { name } => name,
         };
+        let mut named = headers
+            .into_iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case(name));
+        match self {
+            HeaderMatchCompiled::Present { .. } => named.next().is_some(),
+            HeaderMatchCompiled::Absent { .. } => named.next().is_none(),
+            _ => named.any(|(_, v)| self.matches_value(&v)),
+        }
+    }
 
+    /// Check a single header value
+    fn matches_value(&self, header_value: &StrOrBytes) -> bool {
         match self {
-            HeaderMatchCompiled::Exact { value, .. } => match header_value {
-                StrOrBytes::Utf8(s) => s == value,
-                StrOrBytes::Bytes(b) => b == value.as_bytes(),
-            },
+            HeaderMatchCompiled::Exact {
+                value,
+                case_insensitive,
+                ..
+            } => {
+                let bytes = match header_value {
+                    StrOrBytes::Utf8(s) => s.as_bytes(),
+                    StrOrBytes::Bytes(b) => b,
+                };
+                if *case_insensitive {
+                    bytes.eq_ignore_ascii_case(value.as_bytes())
+                } else {
+                    bytes == value.as_bytes()
+                }
+            }
             HeaderMatchCompiled::Regex { regex, .. } => match header_value {
                 StrOrBytes::Utf8(s) => regex.is_match(s),
                 StrOrBytes::Bytes(b) => {
@@ -134,6 +401,8 @@ impl HeaderMatchCompiled {
                         .unwrap_or(false)
                 }
             },
+            HeaderMatchCompiled::Present { .. } => true,
+            HeaderMatchCompiled::Absent { .. } => false,
         }
     }
 }
@@ -143,50 +412,98 @@ impl HeaderMatchCompiled {
 pub enum QueryParamMatchCompiled {
     Exact { name: String, value: String },
     Regex { name: String, regex: Arc<Regex> },
+    Present { name: String },
+    Absent { name: String },
 }
 
 impl QueryParamMatchCompiled {
     /// Create from config QueryParamMatch
     fn from_config(qpm: &QueryParamMatch) -> Result<Self, String> {
+        let name = qpm.name.clone();
+        let value = qpm.value.clone().unwrap_or_default();
         match qpm.match_type {
-            MatchType::Exact => Ok(QueryParamMatchCompiled::Exact {
-                name: qpm.name.clone(),
-                value: qpm.value.clone(),
-            }),
+            MatchType::Exact => Ok(QueryParamMatchCompiled::Exact { name, value }),
             MatchType::RegularExpression => {
-                let re = Regex::new(&qpm.value)
-                    .map_err(|e| format!("Invalid regex '{}': {}", qpm.value, e))?;
+                let re =
+                    Regex::new(&value).map_err(|e| format!("Invalid regex '{}': {}", value, e))?;
                 Ok(QueryParamMatchCompiled::Regex {
-                    name: qpm.name.clone(),
+                    name,
                     regex: Arc::new(re),
                 })
             }
+            MatchType::Present => Ok(QueryParamMatchCompiled::Present { name }),
+            MatchType::Absent => Ok(QueryParamMatchCompiled::Absent { name }),
         }
     }
 
-    /// Check if this query param match matches the given query string
+    /// Check if this query param match matches the given query string.
+    /// For `Present` and `Absent`, a parameter without `=` counts.
     pub fn matches(&self, query_string: &str) -> bool {
-        let params = parse_query_string(query_string);
-        let param_value = match self {
-            QueryParamMatchCompiled::Exact { name, .. }
-            | QueryParamMatchCompiled::Regex { name, .. } => match params.get(name.as_str()) {
-                Some(v) => v,
-                None => return false,
-            },
-        };
-
         match self {
-            QueryParamMatchCompiled::Exact { value, .. } => param_value == value,
-            QueryParamMatchCompiled::Regex { regex, .. } => regex.is_match(param_value),
+            QueryParamMatchCompiled::Exact { name, value } => {
+                parse_query_string(query_string).get(name.as_str()) == Some(&value.as_str())
+            }
+            QueryParamMatchCompiled::Regex { name, regex } => parse_query_string(query_string)
+                .get(name.as_str())
+                .is_some_and(|v| regex.is_match(v)),
+            QueryParamMatchCompiled::Present { name } => has_query_param(query_string, name),
+            QueryParamMatchCompiled::Absent { name } => !has_query_param(query_string, name),
+        }
+    }
+}
+
+/// Methods a route accepts, one bit per entry of
+/// [`config::ROUTE_METHODS`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MethodSet(u16);
+
+impl MethodSet {
+    /// The set of a route's `method`, with HEAD added to GET when
+    /// `head_with_get` is on
+    pub fn from_config(method: &config::RouteMethod, head_with_get: bool) -> Self {
+        let mut set = MethodSet::default();
+        for name in method.methods() {
+            set.insert(name);
+        }
+        if head_with_get && set.contains("GET") {
+            set.insert("HEAD");
+        }
+        set
+    }
+
+    fn bit(method: &str) -> Option<u16> {
+        config::ROUTE_METHODS
+            .iter()
+            .position(|m| *m == method)
+            .map(|i| 1 << i)
+    }
+
+    fn insert(&mut self, method: &str) {
+        if let Some(bit) = Self::bit(method) {
+            self.0 |= bit;
         }
     }
+
+    pub fn contains(&self, method: &str) -> bool {
+        Self::bit(method).is_some_and(|bit| self.0 & bit != 0)
+    }
+
+    /// Method names in the set
+    pub fn iter(self) -> impl Iterator<Item = &'static str> {
+        config::ROUTE_METHODS
+            .iter()
+            .enumerate()
+            .filter(move |(i, _)| self.0 & (1 << i) != 0)
+            .map(|(_, m)| *m)
+    }
 }
 
 /// Route entry with optional path matching (v2)
 #[derive(Debug, Clone)]
 pub struct RouteEntry {
     pub path_match: Option<PathMatchCompiled>,
-    pub method: Option<String>,
+    /// Methods the route accepts, any when None
+    pub method: Option<MethodSet>,
     pub headers: Vec<HeaderMatchCompiled>,
     pub query_params: Vec<QueryParamMatchCompiled>,
     pub filters: Option<Arc<crate::config::RouteFilters>>,
@@ -196,12 +513,122 @@ pub struct RouteEntry {
     pub listeners: Vec<String>,
     /// HTTPRoute namespace/name for X-Gateway-Route header.
     pub route_name: Option<String>,
-    pub priority: i32,
+    /// Explicit precedence override from config.
+    pub priority: Option<i32>,
     pub rule_index: i32,
     /// Cache policy from VarnishCachePolicy. None means pass-through (no caching).
     pub cache_policy: Option<crate::config::CachePolicy>,
     /// Pre-compiled bypass header rules (extracted from cache_policy at config load time).
     pub bypass_headers: Vec<BypassHeaderCompiled>,
+    /// Backend selection strategy for this route's backend groups.
+    pub selection: SelectionPolicy,
+    /// Every backend group has the same non-zero weight, so weighted
+    /// selection picks a group uniformly.
+    pub equal_weights: bool,
+    /// Request attribute hashed for consistent-hash selection.
+    pub hash_on: Option<HashSource>,
+    /// Consistent hash ring over `backend_groups`, built when `selection` is
+    /// `ConsistentHash`.
+    pub hash_ring: Option<Arc<HashRing>>,
+    /// Selection computed alongside the enforced one for audit logging.
+    pub shadow_selection: Option<ShadowSelectionCompiled>,
+    /// Sampled replays to a shadow backend, diffed against the selected one.
+    pub shadow_diff: Option<Arc<ShadowDiffer>>,
+    /// Per-route request timeouts. None means backend defaults apply.
+    pub timeouts: Option<RouteTimeouts>,
+    /// Cookie-based session affinity settings.
+    pub session_persistence: Option<SessionPersistence>,
+    /// Retry policy for failed backend responses.
+    pub retry: Option<RetryPolicy>,
+    /// Load-shedding class, with the vhost's default already applied.
+    pub qos: QosClass,
+    /// Host header sent upstream. None keeps the backend's default.
+    pub forward_host: Option<ForwardHost>,
+    /// Request body limit for external proxy groups
+    pub max_request_body_bytes: Option<u64>,
+    /// Client buckets of the route's `rate_limit` filter
+    pub rate_limiter: Option<Arc<ClientRateLimiter>>,
+    /// Nonces seen by the route's `nonce` filter
+    pub nonce_store: Option<Arc<NonceStore>>,
+    /// Regex of a `RegexSubstitution` URL rewrite
+    pub url_rewrite_regex: Option<Arc<Regex>>,
+}
+
+/// Shadow selection with its weights applied to the route's backend groups.
+#[derive(Debug, Clone)]
+pub struct ShadowSelectionCompiled {
+    pub selection: SelectionPolicy,
+    /// The route's backend groups, reweighted if the shadow has weights
+    pub backend_groups: Vec<WeightedBackendGroup>,
+    /// Ring over the reweighted groups, for a `ConsistentHash` shadow
+    pub hash_ring: Option<Arc<HashRing>>,
+}
+
+impl ShadowSelectionCompiled {
+    fn new(
+        shadow: &ShadowSelection,
+        route_selection: SelectionPolicy,
+        groups: &[WeightedBackendGroup],
+    ) -> Self {
+        let selection = shadow.selection.unwrap_or(route_selection);
+        let mut backend_groups = groups.to_vec();
+        if let Some(weights) = &shadow.weights {
+            for (group, &weight) in backend_groups.iter_mut().zip(weights) {
+                group.weight = weight;
+            }
+        }
+        let hash_ring = (selection == SelectionPolicy::ConsistentHash)
+            .then(|| Arc::new(HashRing::new(&backend_groups)));
+        Self {
+            selection,
+            backend_groups,
+            hash_ring,
+        }
+    }
+}
+
+/// How specific a route's matches are, per Gateway API HTTPRoute
+/// precedence. Compared field by field, so earlier fields dominate:
+/// Exact paths beat prefixes, which beat regexes, which beat no path
+/// match; longer prefixes beat shorter ones; then a method match, more
+/// header matches and more query param matches each win.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RoutePrecedence {
+    path_kind: u8,
+    path_len: usize,
+    method: bool,
+    headers: usize,
+    query_params: usize,
+}
+
+impl RouteEntry {
+    pub fn precedence(&self) -> RoutePrecedence {
+        let (path_kind, path_len) = match &self.path_match {
+            Some(PathMatchCompiled::Exact(p)) => (3, p.len()),
+            Some(PathMatchCompiled::PathPrefix(p)) => (2, p.len()),
+            Some(PathMatchCompiled::Regex(_)) => (1, 0),
+            None => (0, 0),
+        };
+        RoutePrecedence {
+            path_kind,
+            path_len,
+            method: self.method.is_some(),
+            headers: self.headers.len(),
+            query_params: self.query_params.len(),
+        }
+    }
+}
+
+/// First-match evaluation order: explicit priority (descending, absent
+/// counts as 0), then match precedence (descending), then `rule_index`
+/// (ascending), which carries route age and rule order from the control
+/// plane.
+fn route_order(a: &RouteEntry, b: &RouteEntry) -> std::cmp::Ordering {
+    b.priority
+        .unwrap_or(0)
+        .cmp(&a.priority.unwrap_or(0))
+        .then_with(|| b.precedence().cmp(&a.precedence()))
+        .then_with(|| a.rule_index.cmp(&b.rule_index))
 }
 
 /// Map of vhost directors for two-tier routing
@@ -211,6 +638,29 @@ pub struct VhostDirectorMap {
     pub exact: HashMap<String, Arc<VhostDirector>>,
     /// Wildcard hostname patterns to vhost directors (in order)
     pub wildcards: Vec<(String, Arc<VhostDirector>)>,
+    /// Order in which exact, wildcard and catch-all matches are tried
+    pub match_order: Vec<HostMatchKind>,
+    /// Client TLS fingerprint forwarding, applied before route matching
+    pub tls_fingerprint: Option<TlsFingerprintConfig>,
+    /// Vhost lookup key used instead of Host, when the request has it
+    pub route_key: Option<RouteKey>,
+    /// When routing debug headers are added to responses
+    pub debug_headers: DebugHeaders,
+    /// VSL logging of routing decisions
+    pub routing_log: RoutingLog,
+    /// Trace context and request ID generation (`tracing.enabled`)
+    pub tracing: bool,
+    /// Share of generated trace contexts sampled (`tracing.sample_percent`)
+    pub trace_sample_percent: f64,
+    /// Header naming the client connection (`tracing.connection_id_header`)
+    pub connection_id_header: Option<String>,
+    /// Request headers that make a response private (`authorized_private`).
+    /// Empty when off.
+    pub authorized_private: Vec<String>,
+    /// Header holding the TLS SNI of the client connection
+    pub sni_header: Option<String>,
+    /// Vhosts answering 421 when the SNI selects another vhost
+    pub reject_misdirected: HashSet<String>,
 }
 
 impl VhostDirectorMap {
@@ -224,47 +674,236 @@ impl VhostDirectorMap {
 
 /// Resolve a config `BackendGroup` into a `WeightedBackendGroup` by registering
 /// its backends with the pool. External-proxy groups yield one synthetic
-/// backend per upstream; native groups yield one entry per resolved pod IP.
+/// backend per upstream; native groups yield one entry per resolved pod IP,
+/// and one per address a backend's DNS name resolves to.
 fn resolve_backend_group(
     ctx: &mut Ctx,
     backend_pool: &mut BackendPool,
     group: &BackendGroup,
+    zone: Option<&str>,
 ) -> Result<WeightedBackendGroup, VclError> {
     let mut backend_keys = Vec::new();
+    let mut draining_keys = Vec::new();
+    let mut unresolved = Vec::new();
     if let Some(ref ep) = group.external_proxy {
         backend_keys.push(backend_pool.get_or_create_external(ctx, ep)?);
     } else {
-        for backend in &group.backends {
-            backend_keys.push(backend_pool.get_or_create(
-                ctx,
-                &backend.address,
-                backend.port,
-                group.backend_tls.as_ref(),
-            )?);
+        for backend in topology_aligned(&group.backends, zone) {
+            let keys = if crate::dns::is_dns_name(&backend.address) {
+                let ips = match backend_pool.dns().resolve(&backend.address, backend.port) {
+                    Ok(ips) => ips,
+                    Err(e) => {
+                        ctx.log(
+                            LogTag::Error,
+                            format!(
+                                "ghost: backend {}:{} does not resolve ({}); requests for it get a 503",
+                                backend.address, backend.port, e
+                            ),
+                        );
+                        unresolved.push(format!("{}:{}", backend.address, backend.port));
+                        continue;
+                    }
+                };
+                let mut keys = Vec::with_capacity(ips.len());
+                for ip in ips {
+                    let key = backend_pool.get_or_create_resolved(
+                        ctx,
+                        ip,
+                        &backend.address,
+                        backend.port,
+                        group.backend_tls.as_ref(),
+                    )?;
+                    keys.push((key, ip.to_string()));
+                }
+                keys
+            } else {
+                let key = backend_pool.get_or_create(
+                    ctx,
+                    &backend.address,
+                    backend.port,
+                    group.backend_tls.as_ref(),
+                )?;
+                vec![(key, backend.address.clone())]
+            };
+            for (key, address) in keys {
+                if let Some(ref check) = backend.health {
+                    backend_pool.set_health_check(&key, &address, backend.port, check);
+                }
+                if backend.draining {
+                    draining_keys.push(key);
+                } else {
+                    backend_keys.push(key);
+                }
+            }
+        }
+    }
+    if let Some(ref limit) = group.outbound_rate_limit {
+        for key in backend_keys.iter().chain(&draining_keys) {
+            backend_pool.set_rate_limit(key, limit);
         }
     }
     Ok(WeightedBackendGroup {
         weight: group.weight,
         backends: backend_keys,
+        draining: draining_keys,
+        unresolved,
     })
 }
 
+/// The backends of a group the gateway's `zone` should use, following
+/// Kubernetes Topology Aware Hints: when every backend carries hints and at
+/// least one is hinted for the zone, only those. Otherwise, including when
+/// no zone is set, all of them.
+fn topology_aligned<'a>(
+    backends: &'a [config::Backend],
+    zone: Option<&str>,
+) -> Vec<&'a config::Backend> {
+    let hinted_for_zone = |b: &config::Backend| {
+        b.hints
+            .as_ref()
+            .is_some_and(|h| zone.is_some_and(|zone| h.for_zones.iter().any(|z| z == zone)))
+    };
+    if zone.is_some()
+        && backends.iter().all(|b| b.hints.is_some())
+        && backends.iter().any(hinted_for_zone)
+    {
+        backends.iter().filter(|b| hinted_for_zone(b)).collect()
+    } else {
+        backends.iter().collect()
+    }
+}
+
+/// A route's matchers and filters, compiled from config
+pub(crate) struct CompiledRoute {
+    pub path_match: Option<PathMatchCompiled>,
+    pub headers: Vec<HeaderMatchCompiled>,
+    pub query_params: Vec<QueryParamMatchCompiled>,
+    pub filters: Option<Arc<RouteFilters>>,
+    pub bypass_headers: Vec<BypassHeaderCompiled>,
+    pub url_rewrite_regex: Option<Arc<Regex>>,
+}
+
+/// Compile a route's regexes and filters: the checks a reload makes beyond
+/// `config::load()`. Shared with the dry-run validation so the two can't
+/// disagree.
+pub(crate) fn compile_route(route: &Route, vhost: &VHost) -> Result<CompiledRoute, String> {
+    let path_match = match route.path_match.as_ref() {
+        Some(pm) => Some(
+            PathMatchCompiled::from_config(pm).map_err(|e| format!("Invalid path match: {}", e))?,
+        ),
+        None => None,
+    };
+
+    // Compile header matches
+    let headers: Result<Vec<_>, _> = route
+        .headers
+        .iter()
+        .map(HeaderMatchCompiled::from_config)
+        .collect();
+    let headers = headers.map_err(|e| format!("Invalid header match: {}", e))?;
+
+    // Compile query param matches
+    let query_params: Result<Vec<_>, _> = route
+        .query_params
+        .iter()
+        .map(QueryParamMatchCompiled::from_config)
+        .collect();
+    let query_params = query_params.map_err(|e| format!("Invalid query param match: {}", e))?;
+
+    // Capture references in rewrite paths must resolve against the
+    // path regex; catch typos at reload rather than per request
+    if let Some(f) = &route.filters {
+        let full_paths = [
+            f.url_rewrite
+                .as_ref()
+                .and_then(|r| r.replace_full_path.as_ref()),
+            f.request_redirect
+                .as_ref()
+                .and_then(|r| r.replace_full_path.as_ref()),
+        ];
+        for template in full_paths.into_iter().flatten() {
+            validate_capture_refs(template, path_match.as_ref())
+                .map_err(|e| format!("Invalid replace_full_path: {}", e))?;
+        }
+    }
+    let url_rewrite_regex = match route.filters.as_ref().and_then(|f| f.url_rewrite.as_ref()) {
+        Some(rewrite) => compile_url_rewrite(rewrite, path_match.as_ref())
+            .map_err(|e| format!("Invalid url_rewrite: {}", e))?,
+        None => None,
+    };
+
+    let filters = with_vhost_response(route.filters.as_ref(), vhost).map(Arc::new);
+
+    // Pre-compile bypass header regexes (avoids per-request compilation)
+    let bypass_headers = match &route.cache_policy {
+        Some(cp) => {
+            let compiled: Result<Vec<_>, String> = cp
+                .bypass_headers
+                .iter()
+                .map(|bh| match &bh.value_regex {
+                    None => Ok(BypassHeaderCompiled::Present {
+                        name: bh.name.clone(),
+                    }),
+                    Some(pattern) => {
+                        let re = Regex::new(pattern).map_err(|e| {
+                            format!("Invalid bypass header regex '{}': {}", pattern, e)
+                        })?;
+                        Ok(BypassHeaderCompiled::Regex {
+                            name: bh.name.clone(),
+                            regex: Arc::new(re),
+                        })
+                    }
+                })
+                .collect();
+            compiled?
+        }
+        None => Vec::new(),
+    };
+
+    Ok(CompiledRoute {
+        path_match,
+        headers,
+        query_params,
+        filters,
+        bypass_headers,
+        url_rewrite_regex,
+    })
+}
+
+/// Synthetic backends vhost directors answer with in place of a route's
+/// own backends
+pub struct SyntheticBackends {
+    pub redirect: BackendRef,
+    pub internal_error: BackendRef,
+    pub unavailable: BackendRef,
+    pub preflight: BackendRef,
+    pub rate_limited: BackendRef,
+    pub method_not_allowed: BackendRef,
+    pub nonce_rejected: BackendRef,
+}
+
 /// Build vhost directors from configuration
 ///
 /// Creates a VhostDirector for each vhost in the config. Each director handles
-/// route matching and backend selection for its hostname.
+/// route matching and backend selection for its hostname. Errors name the
+/// vhost and route that failed; changes to backends shared with live routing
+/// stay staged in `backend_pool` until the caller commits it.
 pub fn build_vhost_directors(
     config: &Config,
     backend_pool: &mut BackendPool,
     ctx: &mut Ctx,
-    redirect_backend: BackendRef,
-    internal_error_backend: BackendRef,
-) -> Result<VhostDirectorMap, VclError> {
+    synthetic: &SyntheticBackends,
+) -> Result<VhostDirectorMap, Issue> {
     let mut exact = HashMap::new();
     let mut wildcards = Vec::new();
 
+    // Health checks and rate limits are re-registered from this config below
+    backend_pool.clear_health_checks();
+    backend_pool.clear_rate_limits();
+
     // First pass: build routes and populate backend pool
-    let mut vhost_routes: HashMap<String, Vec<RouteEntry>> = HashMap::new();
+    let mut vhost_routes: HashMap<String, (Vec<RouteEntry>, Option<Arc<RouteFilters>>)> =
+        HashMap::new();
 
     // Process vhosts
     for (hostname, vhost) in &config.vhosts {
@@ -275,69 +914,52 @@ pub fn build_vhost_directors(
             let mut groups = Vec::new();
 
             for group in &route.backend_groups {
-                groups.push(resolve_backend_group(ctx, backend_pool, group)?);
+                groups.push(
+                    resolve_backend_group(ctx, backend_pool, group, config.zone.as_deref())
+                        .map_err(|e| Issue::in_route(hostname, route, e.to_string()))?,
+                );
             }
 
-            let path_match = match route.path_match.as_ref() {
-                Some(pm) => Some(
-                    PathMatchCompiled::from_config(pm)
-                        .map_err(|e| VclError::new(format!("Invalid path match: {}", e)))?,
-                ),
-                None => None,
-            };
-
-            // Compile header matches
-            let headers: Result<Vec<_>, _> = route
-                .headers
-                .iter()
-                .map(HeaderMatchCompiled::from_config)
-                .collect();
-            let headers =
-                headers.map_err(|e| VclError::new(format!("Invalid header match: {}", e)))?;
-
-            // Compile query param matches
-            let query_params: Result<Vec<_>, _> = route
-                .query_params
-                .iter()
-                .map(QueryParamMatchCompiled::from_config)
-                .collect();
-            let query_params = query_params
-                .map_err(|e| VclError::new(format!("Invalid query param match: {}", e)))?;
-
-            let filters = route.filters.as_ref().map(|f| Arc::new(f.clone()));
-
-            // Pre-compile bypass header regexes (avoids per-request compilation)
-            let bypass_headers = match &route.cache_policy {
-                Some(cp) => {
-                    let compiled: Result<Vec<_>, String> = cp
-                        .bypass_headers
-                        .iter()
-                        .map(|bh| match &bh.value_regex {
-                            None => Ok(BypassHeaderCompiled::Present {
-                                name: bh.name.clone(),
-                            }),
-                            Some(pattern) => {
-                                let re = Regex::new(pattern).map_err(|e| {
-                                    format!(
-                                        "Invalid bypass header regex '{}': {}",
-                                        pattern, e
-                                    )
-                                })?;
-                                Ok(BypassHeaderCompiled::Regex {
-                                    name: bh.name.clone(),
-                                    regex: Arc::new(re),
-                                })
-                            }
-                        })
-                        .collect();
-                    compiled.map_err(VclError::new)?
-                }
-                None => Vec::new(),
-            };
+            let CompiledRoute {
+                path_match,
+                headers,
+                query_params,
+                filters,
+                bypass_headers,
+                url_rewrite_regex,
+            } = compile_route(route, vhost).map_err(|e| Issue::in_route(hostname, route, e))?;
+
+            let route_key = format!(
+                "{} {} {}",
+                hostname,
+                route.rule_index,
+                route.route_name.as_deref().unwrap_or("-")
+            );
+            let rate_limiter = route
+                .filters
+                .as_ref()
+                .and_then(|f| f.rate_limit.as_ref())
+                .map(|limit| backend_pool.client_rate_limiter(&route_key, limit));
+            let nonce_store = route
+                .filters
+                .as_ref()
+                .and_then(|f| f.nonce.as_ref())
+                .map(|nonce| backend_pool.nonce_store(&route_key, nonce));
+
+            let uniform = equal_weights(&groups);
+            let hash_ring = (route.selection == SelectionPolicy::ConsistentHash)
+                .then(|| Arc::new(HashRing::new(&groups)));
+            let shadow_selection = route
+                .shadow_selection
+                .as_ref()
+                .map(|shadow| ShadowSelectionCompiled::new(shadow, route.selection, &groups));
 
             route_entries.push(RouteEntry {
                 path_match,
-                method: route.method.clone(),
+                method: route
+                    .method
+                    .as_ref()
+                    .map(|m| MethodSet::from_config(m, config.head_with_get)),
                 headers,
                 query_params,
                 filters,
@@ -348,55 +970,100 @@ pub fn build_vhost_directors(
                 rule_index: route.rule_index,
                 cache_policy: route.cache_policy.clone(),
                 bypass_headers,
+                selection: route.selection,
+                equal_weights: uniform,
+                hash_on: route.hash_on.clone(),
+                hash_ring,
+                shadow_selection,
+                shadow_diff: route
+                    .shadow_diff
+                    .as_ref()
+                    .map(|diff| Arc::new(ShadowDiffer::new(diff))),
+                timeouts: route.timeouts,
+                session_persistence: route.session_persistence.clone(),
+                retry: route.retry.clone(),
+                qos: route.qos.unwrap_or(vhost.qos),
+                forward_host: route.forward_host.clone(),
+                max_request_body_bytes: route.max_request_body_bytes,
+                rate_limiter,
+                nonce_store,
+                url_rewrite_regex,
             });
         }
 
-        // Sort routes by priority (descending), then by rule_index (ascending) for tiebreaking
-        route_entries.sort_by(|a, b| {
-            b.priority
-                .cmp(&a.priority)
-                .then_with(|| a.rule_index.cmp(&b.rule_index))
-        });
+        route_entries.sort_by(route_order);
 
-        // Add default_backends as lowest priority route if present
+        // Add default_backends as the last route if present
         if !vhost.default_backends.is_empty() {
             let mut default_groups = Vec::new();
             for group in &vhost.default_backends {
-                default_groups.push(resolve_backend_group(ctx, backend_pool, group)?);
+                default_groups.push(
+                    resolve_backend_group(ctx, backend_pool, group, config.zone.as_deref())
+                        .map_err(|e| {
+                            Issue::in_vhost(hostname, format!("default_backends: {}", e))
+                        })?,
+                );
             }
             route_entries.push(RouteEntry {
                 path_match: None,
                 method: None,
                 headers: Vec::new(),
                 query_params: Vec::new(),
-                filters: None,
+                filters: with_vhost_response(None, vhost).map(Arc::new),
+                equal_weights: equal_weights(&default_groups),
                 backend_groups: default_groups,
                 listeners: Vec::new(),
                 route_name: None,
-                priority: 0,
+                priority: None,
                 rule_index: i32::MAX,
                 cache_policy: None,
                 bypass_headers: Vec::new(),
+                selection: SelectionPolicy::default(),
+                hash_on: None,
+                hash_ring: None,
+                shadow_selection: None,
+                shadow_diff: None,
+                timeouts: None,
+                session_persistence: None,
+                retry: None,
+                qos: vhost.qos,
+                forward_host: None,
+                max_request_body_bytes: None,
+                rate_limiter: None,
+                nonce_store: None,
+                url_rewrite_regex: None,
             });
         }
 
         // Store routes for second pass
-        vhost_routes.insert(hostname.clone(), route_entries);
+        // Requests no route matches get the vhost's 404, which still
+        // carries its security headers and reason phrases
+        let unmatched_filters = with_vhost_response(None, vhost).map(Arc::new);
+        vhost_routes.insert(hostname.clone(), (route_entries, unmatched_filters));
     }
 
     // Second pass: create VhostDirectors with shared backend pool
     // Now that all backends are created, wrap the pool in Arc
     let backend_pool_arc = Arc::new(backend_pool.clone());
 
-    for (hostname, route_entries) in vhost_routes {
+    for (hostname, (route_entries, unmatched_filters)) in vhost_routes {
         // Create VhostDirector for this vhost
-        let vhost_director = Arc::new(VhostDirector::new(
-            hostname.clone(),
-            route_entries,
-            Arc::clone(&backend_pool_arc),
-            Some(redirect_backend.clone()),
-            Some(internal_error_backend.clone()),
-        ));
+        let vhost_director = Arc::new(
+            VhostDirector::new(
+                hostname.clone(),
+                route_entries,
+                Arc::clone(&backend_pool_arc),
+                Some(synthetic.redirect.clone()),
+                Some(synthetic.internal_error.clone()),
+                Some(synthetic.unavailable.clone()),
+                config.routing_log,
+            )
+            .with_unmatched_filters(unmatched_filters)
+            .with_preflight_backend(Some(synthetic.preflight.clone()))
+            .with_rate_limited_backend(Some(synthetic.rate_limited.clone()))
+            .with_method_not_allowed_backend(Some(synthetic.method_not_allowed.clone()))
+            .with_nonce_rejected_backend(Some(synthetic.nonce_rejected.clone())),
+        );
 
         // Categorize into exact or wildcard
         if hostname.starts_with("*.") {
@@ -410,7 +1077,85 @@ pub fn build_vhost_directors(
     // (e.g., *.bar.example.com before *.example.com)
     wildcards.sort_by_key(|w| std::cmp::Reverse(w.0.len()));
 
-    Ok(VhostDirectorMap { exact, wildcards })
+    Ok(VhostDirectorMap {
+        exact,
+        wildcards,
+        match_order: config.host_match_order.clone(),
+        tls_fingerprint: config.tls_fingerprint.clone(),
+        route_key: config.route_key.clone(),
+        debug_headers: config.debug_headers,
+        routing_log: config.routing_log,
+        tracing: config.tracing.enabled,
+        trace_sample_percent: config.tracing.sample_percent,
+        connection_id_header: config.tracing.connection_id_header.clone(),
+        authorized_private: config
+            .authorized_private
+            .as_ref()
+            .map(|p| p.headers.clone())
+            .unwrap_or_default(),
+        sni_header: config.sni_header.clone(),
+        reject_misdirected: config
+            .vhosts
+            .iter()
+            .filter(|(_, vhost)| vhost.reject_misdirected)
+            .map(|(hostname, _)| hostname.clone())
+            .collect(),
+    })
+}
+
+/// A route's filters with what the vhost adds to every response: its
+/// security headers and reason phrases.
+fn with_vhost_response(filters: Option<&RouteFilters>, vhost: &VHost) -> Option<RouteFilters> {
+    let filters = with_security_headers(filters, vhost.security_headers.as_ref());
+    if vhost.reason_phrases.is_empty() {
+        return filters;
+    }
+    let mut filters = filters.unwrap_or_default();
+    filters
+        .response_header_modifier
+        .get_or_insert_with(Default::default)
+        .reason_phrases
+        .clone_from(&vhost.reason_phrases);
+    Some(filters)
+}
+
+/// A route's filters with the vhost's security headers folded into its
+/// response header modifier. Headers the route's own modifier names are
+/// left to the route.
+fn with_security_headers(
+    filters: Option<&RouteFilters>,
+    security: Option<&SecurityHeaders>,
+) -> Option<RouteFilters> {
+    let Some(security) = security.filter(|sh| !sh.headers.is_empty()) else {
+        return filters.cloned();
+    };
+    let mut filters = filters.cloned().unwrap_or_default();
+    let resp = filters
+        .response_header_modifier
+        .get_or_insert_with(Default::default);
+    let named_by_route: HashSet<String> = resp
+        .set
+        .iter()
+        .chain(&resp.add)
+        .map(|action| &action.name)
+        .chain(&resp.remove)
+        .map(|name| name.to_ascii_lowercase())
+        .collect();
+    for (name, value) in &security.headers {
+        if named_by_route.contains(&name.to_ascii_lowercase()) {
+            continue;
+        }
+        let action = HTTPHeaderAction {
+            name: name.clone(),
+            value: value.clone(),
+        };
+        if security.preserve_upstream {
+            resp.set_if_absent.push(action);
+        } else {
+            resp.set.push(action);
+        }
+    }
+    Some(filters)
 }
 
 /// Collect all backend keys referenced in vhost directors
@@ -426,6 +1171,48 @@ fn collect_referenced_backends_from_directors(
     keys
 }
 
+/// Every `ghost_backend` director still alive, for module-level functions
+/// that aren't called on a particular object. Entries go stale when their
+/// VCL is discarded and are pruned on the next registration.
+static LIVE_DIRECTORS: Mutex<Vec<Weak<GhostDirector>>> = Mutex::new(Vec::new());
+
+/// Make a director visible to `stats_json()` and `stats_prometheus()`
+pub fn register(director: &Arc<GhostDirector>) {
+    let mut live = LIVE_DIRECTORS.lock();
+    live.retain(|d| d.strong_count() > 0);
+    live.push(Arc::downgrade(director));
+}
+
+/// Vhost maps of every live director
+fn live_vhost_maps() -> Vec<Arc<VhostDirectorMap>> {
+    LIVE_DIRECTORS
+        .lock()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .map(|d| d.vhost_directors.load_full())
+        .collect()
+}
+
+/// Per-vhost routing stats of every live director as JSON
+pub fn stats_json() -> String {
+    let maps = live_vhost_maps();
+    crate::metrics::render_json(
+        maps.iter()
+            .flat_map(|m| m.all_directors())
+            .map(|d| d.as_ref()),
+    )
+}
+
+/// Per-vhost routing stats of every live director in Prometheus text format
+pub fn stats_prometheus() -> String {
+    let maps = live_vhost_maps();
+    crate::metrics::render_vhosts(
+        maps.iter()
+            .flat_map(|m| m.all_directors())
+            .map(|d| d.as_ref()),
+    )
+}
+
 /// Ghost director implementation
 pub struct GhostDirector {
     /// Vhost directors (atomic swap for lock-free reads)
@@ -440,13 +1227,71 @@ pub struct GhostDirector {
     redirect_backend: SendSyncBackendRef,
     /// Synthetic 500 backend for matched routes with no backends
     internal_error_backend: SendSyncBackendRef,
-    /// Last reload error message (for debugging)
-    last_error: RwLock<Option<String>>,
+    /// Synthetic 503 backend for matched routes with no selectable backend
+    unavailable_backend: SendSyncBackendRef,
+    /// Synthetic 400 backend for malformed request targets
+    bad_request_backend: SendSyncBackendRef,
+    /// Synthetic 421 backend for requests whose SNI names another vhost
+    misdirected_backend: SendSyncBackendRef,
+    /// Synthetic 204 backend for CORS preflights
+    preflight_backend: SendSyncBackendRef,
+    /// Synthetic 429 backend for clients over a route's `rate_limit`
+    rate_limited_backend: SendSyncBackendRef,
+    /// Synthetic 405 backend for requests only a route's `method` refused
+    method_not_allowed_backend: SendSyncBackendRef,
+    /// Synthetic 400/409 backend for requests a route's `nonce` refused
+    nonce_rejected_backend: SendSyncBackendRef,
+    /// Last reload error message (for debugging), and where in the config
+    /// it was found
+    last_error: RwLock<Option<(String, Issue)>>,
+    /// What the last successful reload or rollback changed in routing
+    last_changes: RwLock<Option<ReloadDiff>>,
+    /// Active health probe tasks for backends with a `health` block
+    health_probes: HealthProbes,
+    /// Recently loaded configs for `rollback()`. Also serializes reloads.
+    history: Mutex<ConfigHistory>,
+    /// Successful loads and rollbacks, for metrics
+    generation: AtomicU64,
+    /// When the live config was loaded
+    last_reload: RwLock<Option<SystemTime>>,
+    /// How often the live config's DNS names are looked up again
+    dns_refresh: RwLock<Duration>,
+}
+
+/// Configs kept for rollback, the live one included.
+const CONFIG_HISTORY_DEPTH: usize = 4;
+
+/// The last few successfully loaded configs, newest (live) last.
+#[derive(Debug, Default)]
+struct ConfigHistory(VecDeque<Config>);
+
+impl ConfigHistory {
+    fn push(&mut self, config: Config) {
+        if self.0.len() == CONFIG_HISTORY_DEPTH {
+            self.0.pop_front();
+        }
+        self.0.push_back(config);
+    }
+
+    /// The live config
+    fn live(&self) -> Option<&Config> {
+        self.0.back()
+    }
+
+    /// The config loaded before the live one
+    fn previous(&self) -> Option<&Config> {
+        self.0.len().checked_sub(2).map(|i| &self.0[i])
+    }
+
+    /// Forget the live config once its predecessor is live again
+    fn pop(&mut self) {
+        self.0.pop_back();
+    }
 }
 
 /// Bundle returned by [`GhostDirectorBundle::new`].
 ///
-/// The three synthetic `Backend` values must outlive the director — clones of
+/// The synthetic `Backend` values must outlive the director — clones of
 /// their `BackendRef`s are stored inside it. The caller is expected to keep
 /// the whole bundle alive (typically as fields on the owning VMOD object).
 pub struct GhostDirectorBundle {
@@ -454,6 +1299,13 @@ pub struct GhostDirectorBundle {
     pub not_found: Backend<NotFoundBackend, NotFoundBody>,
     pub redirect: Backend<RedirectBackend, RedirectBody>,
     pub internal_error: Backend<InternalErrorBackend, InternalErrorBody>,
+    pub unavailable: Backend<UnavailableBackend, UnavailableBody>,
+    pub bad_request: Backend<BadRequestBackend, BadRequestBody>,
+    pub misdirected: Backend<MisdirectedBackend, MisdirectedBody>,
+    pub preflight: Backend<PreflightBackend, ()>,
+    pub rate_limited: Backend<RateLimitedBackend, RateLimitedBody>,
+    pub method_not_allowed: Backend<MethodNotAllowedBackend, MethodNotAllowedBody>,
+    pub nonce_rejected: Backend<NonceRejectedBackend, NonceRejectedBody>,
 }
 
 impl GhostDirectorBundle {
@@ -482,6 +1334,42 @@ impl GhostDirectorBundle {
             Backend::new(ctx, "ghost", "ghost_500", InternalErrorBackend, false)?;
         let internal_error_ref = SendSyncBackendRef(internal_error_backend.as_ref().clone());
 
+        // Create synthetic 503 backend for matched routes with no selectable backend
+        let unavailable_backend =
+            Backend::new(ctx, "ghost", "ghost_503", UnavailableBackend, false)?;
+        let unavailable_ref = SendSyncBackendRef(unavailable_backend.as_ref().clone());
+
+        // Create synthetic 400 backend for malformed request targets
+        let bad_request_backend =
+            Backend::new(ctx, "ghost", "ghost_400", BadRequestBackend, false)?;
+        let bad_request_ref = SendSyncBackendRef(bad_request_backend.as_ref().clone());
+
+        // Create synthetic 421 backend for misdirected requests
+        let misdirected_backend =
+            Backend::new(ctx, "ghost", "ghost_421", MisdirectedBackend, false)?;
+        let misdirected_ref = SendSyncBackendRef(misdirected_backend.as_ref().clone());
+
+        // Create synthetic 204 backend for CORS preflights
+        let preflight_backend =
+            Backend::new(ctx, "ghost", "ghost_preflight", PreflightBackend, false)?;
+        let preflight_ref = SendSyncBackendRef(preflight_backend.as_ref().clone());
+
+        // Create synthetic 429 backend for rate limited clients
+        let rate_limited_backend =
+            Backend::new(ctx, "ghost", "ghost_429", RateLimitedBackend, false)?;
+        let rate_limited_ref = SendSyncBackendRef(rate_limited_backend.as_ref().clone());
+
+        // Create synthetic 405 backend for methods no route accepts
+        let method_not_allowed_backend =
+            Backend::new(ctx, "ghost", "ghost_405", MethodNotAllowedBackend, false)?;
+        let method_not_allowed_ref =
+            SendSyncBackendRef(method_not_allowed_backend.as_ref().clone());
+
+        // Create synthetic 400/409 backend for missing and replayed nonces
+        let nonce_rejected_backend =
+            Backend::new(ctx, "ghost", "ghost_nonce", NonceRejectedBackend, false)?;
+        let nonce_rejected_ref = SendSyncBackendRef(nonce_rejected_backend.as_ref().clone());
+
         let director = GhostDirector {
             vhost_directors: ArcSwap::new(Arc::clone(&vhost_directors)),
             backends: ArcSwap::new(Arc::new(backends)),
@@ -489,7 +1377,20 @@ impl GhostDirectorBundle {
             not_found_backend: not_found_ref,
             redirect_backend: redirect_ref,
             internal_error_backend: internal_error_ref,
+            unavailable_backend: unavailable_ref,
+            bad_request_backend: bad_request_ref,
+            misdirected_backend: misdirected_ref,
+            preflight_backend: preflight_ref,
+            rate_limited_backend: rate_limited_ref,
+            method_not_allowed_backend: method_not_allowed_ref,
+            nonce_rejected_backend: nonce_rejected_ref,
             last_error: RwLock::new(None),
+            last_changes: RwLock::new(None),
+            health_probes: HealthProbes::new(),
+            history: Mutex::new(ConfigHistory::default()),
+            generation: AtomicU64::new(0),
+            last_reload: RwLock::new(None),
+            dns_refresh: RwLock::new(crate::dns::DEFAULT_REFRESH),
         };
 
         Ok(GhostDirectorBundle {
@@ -497,6 +1398,13 @@ impl GhostDirectorBundle {
             not_found: not_found_backend,
             redirect: redirect_backend,
             internal_error: internal_error_backend,
+            unavailable: unavailable_backend,
+            bad_request: bad_request_backend,
+            misdirected: misdirected_backend,
+            preflight: preflight_backend,
+            rate_limited: rate_limited_backend,
+            method_not_allowed: method_not_allowed_backend,
+            nonce_rejected: nonce_rejected_backend,
         })
     }
 }
@@ -505,42 +1413,131 @@ impl GhostDirector {
 
     /// Reload configuration from disk
     pub fn reload(&self, ctx: &mut Ctx) -> Result<(), String> {
-        // Clone current backend pool for modification
-        let current_backends = self.backends.load();
-        let mut backend_pool = (**current_backends).clone();
+        let mut history = self.history.lock();
 
         // Load config
-        let config = crate::config::load(&self.config_path).map_err(|e| {
-            let error_msg = format!("Ghost reload failed: {}", e);
-            // Log to VSL for visibility in varnishlog
-            ctx.log(LogTag::Error, &error_msg);
-            // Store for VCL access
-            *self.last_error.write() = Some(error_msg.clone());
-            error_msg
+        let config = crate::config::load(&self.config_path)
+            .map_err(Issue::new)
+            .and_then(|config| self.apply(ctx, &config).map(|()| config))
+            .map_err(|issue| {
+                crate::vsc::incr(|c| &c.reload_failures);
+                self.fail(ctx, "reload", issue)
+            })?;
+        history.push(config);
+        crate::vsc::incr(|c| &c.reloads);
+
+        Ok(())
+    }
+
+    /// Dry run of `reload()`: the JSON validation report for the config file
+    pub fn validate(&self) -> String {
+        crate::validate::validate_file(&self.config_path).to_json()
+    }
+
+    /// Revert to the config loaded before the current one, without reading
+    /// the file. Up to `CONFIG_HISTORY_DEPTH - 1` reloads can be undone; the
+    /// next `reload()` loads the file again.
+    pub fn rollback(&self, ctx: &mut Ctx) -> Result<(), String> {
+        let mut history = self.history.lock();
+        let previous = history.previous().ok_or_else(|| {
+            self.fail(
+                ctx,
+                "rollback",
+                Issue::new("no previous config to roll back to"),
+            )
         })?;
 
+        self.apply(ctx, previous)
+            .map_err(|issue| self.fail(ctx, "rollback", issue))?;
+        history.pop();
+        ctx.log(LogTag::Debug, "ghost: rolled back to the previous config");
+
+        Ok(())
+    }
+
+    /// How long the DNS refresher waits between lookups
+    pub fn dns_refresh_interval(&self) -> Duration {
+        *self.dns_refresh.read()
+    }
+
+    /// Apply the live config again if a DNS name a native backend is
+    /// addressed by now resolves differently, so backends follow the new
+    /// addresses. Run periodically by the DNS refresher.
+    pub fn refresh_dns(&self, ctx: &mut Ctx) {
+        let history = self.history.lock();
+        let Some(config) = history.live() else {
+            return;
+        };
+        let names = crate::dns::names(config);
+        if names.is_empty() || !self.backends.load().dns().changed(&names) {
+            return;
+        }
+        ctx.log(
+            LogTag::Debug,
+            "ghost: DNS answers changed, rebuilding backends",
+        );
+        if let Err(issue) = self.apply(ctx, config) {
+            self.fail(ctx, "DNS refresh", issue);
+        }
+    }
+
+    /// Log a reload or rollback error to VSL and keep it for `last_error()`.
+    fn fail(&self, ctx: &mut Ctx, what: &str, issue: Issue) -> String {
+        let error_msg = format!("Ghost {} failed: {}", what, issue);
+        ctx.log(LogTag::Error, &error_msg);
+        *self.last_error.write() = Some((error_msg.clone(), issue));
+        error_msg
+    }
+
+    /// Build routing state for `config` and swap it in.
+    ///
+    /// Everything is built into temporaries first: on any error the live
+    /// vhost directors and backend pool, and the backends they share with
+    /// the new pool, are left exactly as they were.
+    fn apply(&self, ctx: &mut Ctx, config: &Config) -> Result<(), Issue> {
+        // Clone current backend pool for modification
+        let current_backends = self.backends.load();
+        let mut backend_pool = (**current_backends).clone();
+        backend_pool.set_client_params(ClientParams::from_config(&config.external_client));
+
         // Build new vhost directors
         let new_directors = build_vhost_directors(
-            &config,
+            config,
             &mut backend_pool,
             ctx,
-            self.redirect_backend.0.clone(),
-            self.internal_error_backend.0.clone(),
-        )
-        .map_err(|e| {
-            let error_msg = format!("Ghost reload failed: {}", e);
-            // Log to VSL for visibility in varnishlog
-            ctx.log(LogTag::Error, &error_msg);
-            // Store for VCL access
-            *self.last_error.write() = Some(error_msg.clone());
-            error_msg
-        })?;
+            &SyntheticBackends {
+                redirect: self.redirect_backend.0.clone(),
+                internal_error: self.internal_error_backend.0.clone(),
+                unavailable: self.unavailable_backend.0.clone(),
+                preflight: self.preflight_backend.0.clone(),
+                rate_limited: self.rate_limited_backend.0.clone(),
+                method_not_allowed: self.method_not_allowed_backend.0.clone(),
+                nonce_rejected: self.nonce_rejected_backend.0.clone(),
+            },
+        )?;
 
         // Collect all backend keys referenced in the new directors
         let referenced_keys = collect_referenced_backends_from_directors(&new_directors);
 
         // Clean up unreferenced backends from the pool
         backend_pool.retain_only(&referenced_keys);
+        backend_pool.dns().retain(&crate::dns::names(config));
+
+        // Nothing can fail from here on: apply the staged backend changes
+        backend_pool.commit();
+
+        crate::external_backend::configure(&config.external_client);
+        backend_pool
+            .outliers()
+            .configure(config.outlier_detection.as_ref());
+        self.health_probes
+            .sync(backend_pool.health_targets(), backend_pool.health_map());
+
+        let changes = ReloadDiff::between(
+            &Snapshot::of(&self.vhost_directors.load()),
+            &Snapshot::of(&new_directors),
+        );
+        ctx.log(LogTag::Debug, changes.summary());
 
         // Atomic swap of vhost_directors and backends
         self.vhost_directors.store(Arc::new(new_directors));
@@ -548,45 +1545,377 @@ impl GhostDirector {
 
         // Clear error on success
         *self.last_error.write() = None;
+        *self.last_changes.write() = Some(changes);
+        self.generation.fetch_add(1, Ordering::Relaxed);
+        *self.last_reload.write() = Some(SystemTime::now());
+        *self.dns_refresh.write() = config
+            .dns_refresh_ms
+            .map_or(crate::dns::DEFAULT_REFRESH, Duration::from_millis);
 
         Ok(())
     }
 
     /// Get the last reload error message (if any)
     pub fn last_error(&self) -> Option<String> {
-        self.last_error.read().clone()
+        self.last_error.read().as_ref().map(|(msg, _)| msg.clone())
+    }
+
+    /// The last reload error as JSON, naming the vhost and route that
+    /// failed when it was one of them; `{"status":"ok"}` if there is none
+    pub fn last_error_json(&self) -> String {
+        #[derive(serde::Serialize)]
+        struct Failure<'a> {
+            status: &'static str,
+            error: &'a str,
+            #[serde(flatten)]
+            issue: &'a Issue,
+        }
+
+        match &*self.last_error.read() {
+            Some((error, issue)) => serde_json::to_string(&Failure {
+                status: "error",
+                error,
+                issue,
+            })
+            .unwrap_or_default(),
+            None => r#"{"status":"ok"}"#.to_string(),
+        }
+    }
+
+    /// What the last reload or rollback changed, as
+    /// `{"status":"ok","changes":{...}}`. `changes` is null before the
+    /// first reload.
+    pub fn last_reload_json(&self) -> String {
+        serde_json::json!({
+            "status": "ok",
+            "changes": &*self.last_changes.read(),
+        })
+        .to_string()
+    }
+
+    /// Routing statistics in Prometheus text format
+    pub fn metrics(&self) -> String {
+        crate::metrics::render(
+            &self.vhost_directors.load(),
+            &self.backends.load(),
+            &crate::metrics::ReloadInfo {
+                generation: self.generation.load(Ordering::Relaxed),
+                last_reload: *self.last_reload.read(),
+            },
+        )
     }
 
     /// Full routing in client context: hostname match → vhost → route → backend.
     ///
     /// Used by the recv() VMOD method to route requests in vcl_recv using
     /// req headers and local_socket() for listener-aware routing.
+    /// `sess_xid` identifies the client connection, when there is one.
     pub fn route_request(
         &self,
         http: &mut HttpHeaders,
         listener: Option<&str>,
+        sess_xid: Option<u64>,
+    ) -> vhost_director::RouteRequestResult {
+        // X-Ghost-Debug is the one X-Ghost-* header a client may send
+        let debug_requested = debug_headers::requested(http);
+        // Internal headers are only trusted when ghost set them itself.
+        strip_internal_headers(http);
+
+        let directors = self.vhost_directors.load();
+        let trace = directors
+            .tracing
+            .then(|| trace_context::apply(http, directors.trace_sample_percent));
+        if let (Some(name), Some(xid)) = (&directors.connection_id_header, sess_xid) {
+            trace_context::set_connection_id(http, name, xid);
+        }
+        if directors
+            .authorized_private
+            .iter()
+            .any(|name| http.header(name).is_some())
+        {
+            let _ = http.set_header(vhost_director::PRIVATE_HEADER, "1");
+        }
+        let mut result = self.route_with(&directors, http, listener, debug_requested);
+        if let Some(ids) = trace.filter(|ids| ids.sampled) {
+            result.log_msgs.insert(0, (LogTag::VclLog, ids.log_line()));
+        }
+        result
+    }
+
+    /// Routing proper, on a request stripped of internal headers
+    fn route_with(
+        &self,
+        directors: &VhostDirectorMap,
+        http: &mut HttpHeaders,
+        listener: Option<&str>,
+        debug_requested: bool,
     ) -> vhost_director::RouteRequestResult {
-        let host = match get_host_header(http) {
+        // Reject control characters before anything matches on the URL, so
+        // a smuggled CR/LF or NUL can't steer routing or reach a backend.
+        if http
+            .url()
+            .is_some_and(|u| !is_valid_request_target(u.as_ref()))
+        {
+            let mut log_msgs = vec![(
+                LogTag::Error,
+                "ghost: rejecting request target with control characters".to_string(),
+            )];
+            log_fallback(
+                &mut log_msgs,
+                directors.routing_log,
+                None,
+                None,
+                "bad_request",
+            );
+            return vhost_director::RouteRequestResult {
+                backend: Some(self.bad_request_backend.0.clone()),
+                log_msgs,
+                ..Default::default()
+            };
+        }
+
+        let host = match directors
+            .route_key
+            .as_ref()
+            .and_then(|key| get_route_key(http, key))
+            .or_else(|| get_host_header(http))
+        {
             Some(h) => h,
             None => return vhost_director::RouteRequestResult::default(),
         };
 
-        let directors = self.vhost_directors.load();
-        let vhost = match match_hostname(&directors, &host) {
+        if let Some(ref fp) = directors.tls_fingerprint {
+            forward_tls_fingerprint(http, fp);
+        }
+        let vhost = match match_hostname(directors, &host) {
             Some(dir) => dir,
-            None => return vhost_director::RouteRequestResult {
-                backend: Some(self.not_found_backend.0.clone()),
-                ..Default::default()
-            },
+            None => {
+                crate::stats::record_no_vhost_match();
+                let mut log_msgs = Vec::new();
+                log_fallback(
+                    &mut log_msgs,
+                    directors.routing_log,
+                    None,
+                    None,
+                    "not_found",
+                );
+                return vhost_director::RouteRequestResult {
+                    backend: Some(self.not_found_backend.0.clone()),
+                    log_msgs,
+                    ..Default::default()
+                };
+            }
         };
 
+        let sni = directors
+            .sni_header
+            .as_ref()
+            .and_then(|name| get_sni(http, name));
+        if is_misdirected(directors, vhost, sni.as_deref()) {
+            let mut log_msgs = Vec::new();
+            log_fallback(
+                &mut log_msgs,
+                directors.routing_log,
+                Some(vhost.hostname()),
+                None,
+                "misdirected",
+            );
+            return vhost_director::RouteRequestResult {
+                backend: Some(self.misdirected_backend.0.clone()),
+                log_msgs,
+                ..Default::default()
+            };
+        }
+
         let mut result = vhost.route_request(http, listener);
         if result.backend.is_none() {
             result.backend = Some(self.not_found_backend.0.clone());
+            log_fallback(
+                &mut result.log_msgs,
+                directors.routing_log,
+                Some(vhost.hostname()),
+                result.rule_index,
+                "not_found",
+            );
+        }
+        if directors.debug_headers.applies(debug_requested) {
+            let info = DebugInfo {
+                vhost: vhost.hostname().to_string(),
+                route: result.route_name.clone(),
+                rule: result.rule_index,
+                backend: result.backend_key.clone(),
+                budget: result.budget_ms.map(|budget_ms| Budget {
+                    budget_ms,
+                    deadline_ms: crate::retry::now_ms() + budget_ms,
+                }),
+            };
+            let _ = http.set_header(debug_headers::DEBUG_INFO_HEADER, &info.to_header());
         }
         result
     }
 
+    /// Decide in `vcl_backend_response` or `vcl_backend_error` whether to
+    /// retry the fetch.
+    ///
+    /// Retries when the route that served the request has a retry policy
+    /// covering the response status (or, with `retry_on_body`, a body the
+    /// backend flagged), attempts and deadline remain, and the route has a
+    /// healthy backend that hasn't been tried. In `vcl_backend_error` no
+    /// response headers arrived (connection refused or reset, DNS failure),
+    /// so any status qualifies. The chosen backend is recorded in the retry
+    /// state for `retry_backend()`.
+    ///
+    /// Since it sees every fetch, it also counts failed and 5xx fetches in
+    /// the vhost's upstream errors.
+    pub fn retry(&self, ctx: &mut Ctx) -> bool {
+        let fetch_failed = ctx.raw.method == VCL_MET_BACKEND_ERROR;
+        // Consume the body-match flag first so it never reaches the cache
+        // or the client, whatever the decision.
+        let body_matched = match ctx.http_beresp.as_mut() {
+            Some(beresp) => {
+                let matched = beresp.header(BODY_MATCH_HEADER).is_some();
+                beresp.unset_header(BODY_MATCH_HEADER);
+                matched
+            }
+            None => false,
+        };
+        let status = match ctx.http_beresp.as_ref().and_then(|r| r.status()) {
+            Some(s) => match str_or_bytes_to_cow(&s).and_then(|s| s.parse::<u16>().ok()) {
+                Some(status) => status,
+                None => return false,
+            },
+            None => return false,
+        };
+        let state = self.retry_state(ctx);
+        let directors = self.vhost_directors.load();
+        if fetch_failed || status >= 500 {
+            if let Some(vhost) = fetch_vhost(&directors, ctx, state.as_ref()) {
+                vhost.stats().record_upstream_error();
+            }
+        }
+
+        let Some(mut state) = state else {
+            return false;
+        };
+        let Some(vhost) = directors.all_directors().find(|d| d.id() == state.vhost) else {
+            // Config reloaded since routing; the route index is meaningless now.
+            return false;
+        };
+        let Some(policy) = vhost.retry_policy(state.route) else {
+            return false;
+        };
+        let trigger = if fetch_failed {
+            Trigger::FetchFailed
+        } else if body_matched {
+            Trigger::BodyMatched
+        } else {
+            Trigger::Status(status)
+        };
+        if !crate::retry::should_retry(policy, &state, trigger, crate::retry::now_ms()) {
+            return false;
+        }
+        let Some(next) = vhost.select_retry_backend(state.route, &state.tried) else {
+            ctx.log(
+                LogTag::Debug,
+                format!("ghost: not retrying {}: no untried backend left", status),
+            );
+            return false;
+        };
+
+        ctx.log(
+            LogTag::Debug,
+            format!(
+                "ghost: retrying {}{} from {} on {} (attempt {}/{})",
+                status,
+                match trigger {
+                    Trigger::FetchFailed => " (fetch failed)",
+                    Trigger::BodyMatched => " (body matched)",
+                    Trigger::Status(_) => "",
+                },
+                state.current().unwrap_or("-"),
+                next,
+                state.attempts() + 1,
+                policy.max_attempts
+            ),
+        );
+        let forward_host = vhost
+            .retry_forward_host(state.route, &next)
+            .map(str::to_string);
+        let rate_limit_key = self
+            .backends
+            .load()
+            .rate_limit(&next)
+            .map(|_| next.clone());
+        state.tried.push(next);
+        let Some(bereq) = ctx.http_bereq.as_mut() else {
+            return false;
+        };
+        // The retried fetch takes a token from its own backend's bucket
+        bereq.unset_header(RATE_LIMIT_HEADER);
+        if let Some(key) = rate_limit_key {
+            let _ = bereq.set_header(RATE_LIMIT_HEADER, &key);
+        }
+        // vcl_backend_fetch applies it again on the retried fetch
+        if let Some(host) = forward_host {
+            bereq.unset_header(vhost_director::FORWARD_HOST_HEADER);
+            let _ = bereq.set_header(vhost_director::FORWARD_HOST_HEADER, &host);
+        }
+        // Must unset first since set_header() appends a header slot.
+        bereq.unset_header(RETRY_STATE_HEADER);
+        bereq
+            .set_header(RETRY_STATE_HEADER, &state.to_header())
+            .is_ok()
+    }
+
+    /// Backend chosen by the last successful `retry()` call, for
+    /// `vcl_backend_fetch` on a retried fetch. Falls back to the internal
+    /// error backend if that backend left the pool in the meantime.
+    pub fn retry_backend(&self, ctx: &mut Ctx) -> Option<BackendRef> {
+        let state = self.retry_state(ctx)?;
+        let backend = state
+            .current()
+            .and_then(|key| self.backends.load().get(key))
+            .map(|entry| entry.backend_ref());
+        Some(backend.unwrap_or_else(|| self.internal_error_backend.0.clone()))
+    }
+
+    /// Take a token for the fetch from its backend's outbound rate limit.
+    ///
+    /// The backend is the one routing (or `retry()`) named on bereq; fetches
+    /// to backends without a limit always pass. False when the fetch should
+    /// get a 503 instead.
+    pub fn throttle(&self, ctx: &mut Ctx) -> bool {
+        let Some(bereq) = ctx.http_bereq.as_mut() else {
+            return true;
+        };
+        let Some(key) = bereq
+            .header(RATE_LIMIT_HEADER)
+            .and_then(|h| str_or_bytes_to_cow(&h).map(|s| s.into_owned()))
+        else {
+            return true;
+        };
+        bereq.unset_header(RATE_LIMIT_HEADER);
+        let backends = self.backends.load();
+        let Some(bucket) = backends.rate_limit(&key) else {
+            // Limit removed by a reload since routing
+            return true;
+        };
+        if bucket.acquire() {
+            return true;
+        }
+        ctx.log(
+            LogTag::Debug,
+            format!("ghost: backend {} rate limited, not fetching", key),
+        );
+        false
+    }
+
+    /// Retry state routing left on bereq, if the request is retryable
+    fn retry_state(&self, ctx: &Ctx) -> Option<RetryState> {
+        let value = ctx.http_bereq.as_ref()?.header(RETRY_STATE_HEADER)?;
+        RetryState::from_header(&str_or_bytes_to_cow(&value)?)
+    }
+
     /// JSON output format for backend.list -j
     fn list_json(&self, ctx: &mut Ctx, vsb: &mut Buffer) {
         let directors = self.vhost_directors.load();
@@ -607,11 +1936,16 @@ impl GhostDirector {
                     "health": if director.probe(ctx).healthy { "healthy" } else { "sick" },
                     "routes": director.route_count(),
                     "total_requests": total,
+                    "retries": director.stats().retries(),
+                    "no_backends": director.stats().no_backends(),
+                    "route_no_backends": director.stats().route_no_backends(),
+                    "missing_backends": director.stats().missing_backends(),
                     "last_request": director.stats().last_request().map(|t| {
                         use crate::format::format_timestamp;
                         format_timestamp(Some(t))
                     }),
-                    "backends": backend_objs
+                    "backends": backend_objs,
+                    "selection_audit": crate::format::format_shadow_json(director.stats())
                 })
             })
             .collect();
@@ -619,7 +1953,31 @@ impl GhostDirector {
         let output = serde_json::json!({
             "backends": all_backends,
             "total_vhosts": directors.exact.len() + directors.wildcards.len(),
-            "total_backends": backends.len()
+            "total_backends": backends.len(),
+            "no_vhost_match": crate::stats::no_vhost_match(),
+            "external_pending_requests": crate::external_backend::pending_requests(),
+            "external_queued_requests": crate::external_backend::queued_requests(),
+            "external_active_streams": crate::external_backend::active_streams(),
+            "external_client": backends.client_params().to_json(),
+            "upstream_errors": crate::stats::upstream_errors()
+                .into_iter()
+                .map(|(class, n)| (class.as_str().to_string(), serde_json::json!(n)))
+                .collect::<serde_json::Map<_, _>>(),
+            "backend_latency": backends
+                .latencies()
+                .into_iter()
+                .map(|(key, first_byte, last_byte)| {
+                    serde_json::json!({
+                        "backend": key,
+                        "requests": first_byte.count(),
+                        "first_byte_ms": crate::format::format_percentiles_json(&first_byte),
+                        "last_byte_ms": crate::format::format_percentiles_json(&last_byte),
+                    })
+                })
+                .collect::<Vec<_>>(),
+            "health_probes": self.health_probes.len(),
+            "unhealthy_backends": backends.unhealthy_keys(),
+            "ejected_backends": backends.outliers().ejected_keys()
         });
 
         let json_str = serde_json::to_string(&output).unwrap_or_else(|_| "{}".to_string());
@@ -629,12 +1987,43 @@ impl GhostDirector {
 
 impl VclDirector for GhostDirector {
     fn resolve(&self, ctx: &mut Ctx) -> Option<BackendRef> {
+        // vcl_backend_fetch hid them; routing reads and replaces them
+        internal_headers::restore(ctx);
         let bereq = ctx.http_bereq.as_mut()?;
-        let result = self.route_request(bereq, None);
+        // A retry routes again; it must see the Host the client sent, not
+        // the one a previous attempt forwarded.
+        if let Some(host) = bereq
+            .header(vhost_director::CLIENT_HOST_HEADER)
+            .and_then(|h| str_or_bytes_to_cow(&h).map(|s| s.into_owned()))
+        {
+            bereq.unset_header("host");
+            let _ = bereq.set_header("host", &host);
+        }
+        let result = self.route_request(bereq, None, None);
+        // Session affinity cookies and debug headers are only emitted when
+        // routing in vcl_recv; here they would just leak to the backend.
+        bereq.unset_header(vhost_director::AFFINITY_COOKIE_HEADER);
+        bereq.unset_header(debug_headers::DEBUG_INFO_HEADER);
+        bereq.unset_header(trace_context::ECHO_REQUEST_ID_HEADER);
+        // vcl_backend_fetch already ran, so forward_host is applied here.
+        apply_forward_host(bereq);
         for (tag, msg) in result.log_msgs {
             ctx.log(tag, &msg);
         }
-        result.backend
+        if let Some(mirror) = result.mirror {
+            if let Err(reason) = mirror.send(ctx) {
+                ctx.log(LogTag::Debug, format!("Request mirror skipped: {}", reason));
+            }
+        }
+        // Likewise for the outbound rate limit
+        let backend = if self.throttle(ctx) {
+            result.backend
+        } else {
+            Some(self.unavailable_backend.0.clone())
+        };
+        // ... and for keeping internal headers from a native backend
+        internal_headers::hide(ctx);
+        backend
     }
 
     fn probe(&self, ctx: &mut Ctx) -> ProbeResult {
@@ -662,6 +2051,74 @@ impl VclDirector for GhostDirector {
         for director in directors.all_directors() {
             director.report_details(ctx, vsb);
         }
+
+        let probes = self.health_probes.statuses();
+        if !probes.is_empty() {
+            let _ = vsb.write(&"Health probes:\n");
+            for (key, status) in probes {
+                let health = match status.healthy {
+                    Some(true) => "healthy",
+                    Some(false) => "sick",
+                    None => "pending",
+                };
+                let msg = format!(
+                    "  {} - {}, last probe {} at {}\n",
+                    key,
+                    health,
+                    status.last_result.as_deref().unwrap_or("-"),
+                    crate::format::format_timestamp(status.last_probe)
+                );
+                let _ = vsb.write(&msg);
+            }
+        }
+
+        let errors: Vec<String> = crate::stats::upstream_errors()
+            .into_iter()
+            .filter(|&(_, n)| n > 0)
+            .map(|(class, n)| format!("{} {}", class.as_str(), n))
+            .collect();
+        if !errors.is_empty() {
+            let _ = vsb.write(&format!("Upstream errors: {}\n", errors.join(", ")));
+        }
+
+        let unmatched = crate::stats::no_vhost_match();
+        if unmatched > 0 {
+            let _ = vsb.write(&format!("No vhost match (404): {}\n", unmatched));
+        }
+
+        let backends = self.backends.load();
+        let _ = vsb.write(&format!(
+            "External client: {}\n",
+            backends.client_params().describe()
+        ));
+        let latencies = backends.latencies();
+        if !latencies.is_empty() {
+            let _ = vsb.write(&"Latency (p50/p90/p95/p99):\n");
+            for (key, first_byte, last_byte) in latencies {
+                let msg = format!(
+                    "  {} - first byte {}, last byte {} ({} requests)\n",
+                    key,
+                    crate::format::format_percentiles(&first_byte),
+                    crate::format::format_percentiles(&last_byte),
+                    first_byte.count()
+                );
+                let _ = vsb.write(&msg);
+            }
+        }
+
+        let ejected = backends.outliers().ejections();
+        if !ejected.is_empty() {
+            let _ = vsb.write(&"Ejected backends:\n");
+            for e in ejected {
+                let msg = format!(
+                    "  {} - {:.1}s remaining (ejection {})\n",
+                    e.key,
+                    e.remaining.as_secs_f64(),
+                    e.ejections
+                );
+                let _ = vsb.write(&msg);
+            }
+        }
     }
 
     fn report_json(&self, ctx: &mut Ctx, vsb: &mut Buffer) {
@@ -676,30 +2133,30 @@ impl VclDirector for GhostDirector {
 /// Match hostname to vhost director
 ///
 /// Returns the vhost director for the matched hostname.
-/// Matching priority: exact hostname > wildcard hostname > catch-all ("*")
+/// Match kinds are tried in `directors.match_order`; by default
+/// exact hostname > wildcard hostname > catch-all ("*").
 fn match_hostname<'a>(
     directors: &'a VhostDirectorMap,
     host: &str,
 ) -> Option<&'a Arc<VhostDirector>> {
     let host = host.to_lowercase();
 
-    // 1. Try exact hostname match
-    if let Some(director) = directors.exact.get(&host) {
-        return Some(director);
-    }
-
-    // 2. Try wildcard hostname match
-    for (pattern, director) in &directors.wildcards {
-        if matches_wildcard(pattern, &host) {
-            return Some(director);
+    for kind in &directors.match_order {
+        let matched = match kind {
+            HostMatchKind::Exact => directors.exact.get(&host),
+            HostMatchKind::Wildcard => directors
+                .wildcards
+                .iter()
+                .find(|(pattern, _)| matches_wildcard(pattern, &host))
+                .map(|(_, director)| director),
+            // Catch-all ("*") — routes with no explicit hostnames
+            HostMatchKind::Default => directors.exact.get("*"),
+        };
+        if matched.is_some() {
+            return matched;
         }
     }
 
-    // 3. Try catch-all ("*") — routes with no explicit hostnames
-    if let Some(director) = directors.exact.get("*") {
-        return Some(director);
-    }
-
     None
 }
 
@@ -759,15 +2216,167 @@ fn str_or_bytes_to_cow<'a>(sob: &'a StrOrBytes<'a>) -> Option<Cow<'a, str>> {
     }
 }
 
+/// A request target must not contain ASCII control characters (including
+/// DEL). A target starting with `//` is not special: it is an origin-form
+/// path, never a protocol-relative authority.
+fn is_valid_request_target(url: &[u8]) -> bool {
+    !url.iter().any(|b| b.is_ascii_control())
+}
+
 /// Get Host header value (without port)
 ///
 /// Handles regular hostnames, IPv4 addresses, and IPv6 bracketed addresses.
+/// Routing log line for a request ghost answers itself, before or instead
+/// of a vhost's route selection.
+fn log_fallback(
+    log_msgs: &mut Vec<(LogTag, String)>,
+    level: RoutingLog,
+    vhost: Option<&str>,
+    rule: Option<i32>,
+    backend: &str,
+) {
+    let decision = Decision {
+        vhost,
+        rule,
+        path_match: None,
+        backend,
+        policy: None,
+        filters: None,
+    };
+    if let Some(line) = decision.line(level) {
+        log_msgs.push((LogTag::VclLog, line));
+    }
+}
+
+/// Vhost that routed the fetch on bereq: the one named by its retry state,
+/// or else the one its route key or client Host matches.
+fn fetch_vhost<'a>(
+    directors: &'a VhostDirectorMap,
+    ctx: &Ctx,
+    state: Option<&RetryState>,
+) -> Option<&'a Arc<VhostDirector>> {
+    if let Some(state) = state {
+        return directors.all_directors().find(|d| d.id() == state.vhost);
+    }
+    let bereq = ctx.http_bereq.as_ref()?;
+    let host = directors
+        .route_key
+        .as_ref()
+        .and_then(|key| get_route_key(bereq, key))
+        .or_else(|| {
+            bereq
+                .header(vhost_director::CLIENT_HOST_HEADER)
+                .and_then(|h| str_or_bytes_to_cow(&h).map(|s| strip_port(&s).to_lowercase()))
+        })
+        .or_else(|| get_host_header(bereq))?;
+    match_hostname(directors, &host)
+}
+
+/// Remove every `X-Ghost-*` header, e.g. forged ones sent by a client.
+fn strip_internal_headers(http: &mut HttpHeaders) {
+    let names: Vec<String> = http
+        .iter()
+        .map(|(name, _)| name)
+        .filter(|name| vhost_director::is_internal_header(name))
+        .map(str::to_string)
+        .collect();
+    for name in names {
+        http.unset_header(&name);
+    }
+}
+
+/// Replace the bereq Host with the routed `forward_host`, keeping the
+/// client's Host for `x-cache-host`. The Rust side of what vcl_backend_fetch
+/// does when routing ran in vcl_recv.
+fn apply_forward_host(bereq: &mut HttpHeaders) {
+    let Some(forward) = bereq
+        .header(vhost_director::FORWARD_HOST_HEADER)
+        .and_then(|h| str_or_bytes_to_cow(&h).map(|s| s.into_owned()))
+    else {
+        return;
+    };
+    if let Some(host) = bereq
+        .header("host")
+        .and_then(|h| str_or_bytes_to_cow(&h).map(|s| s.into_owned()))
+    {
+        // Must unset first since set_header() appends a header slot.
+        bereq.unset_header(vhost_director::CLIENT_HOST_HEADER);
+        let _ = bereq.set_header(vhost_director::CLIENT_HOST_HEADER, &host);
+    }
+    bereq.unset_header("host");
+    let _ = bereq.set_header("host", &forward);
+}
+
+/// Copy the VCL-provided TLS fingerprint into its forwarded header, replacing
+/// anything the client sent under that name. A no-op when both names match.
+fn forward_tls_fingerprint(http: &mut HttpHeaders, fp: &TlsFingerprintConfig) {
+    let forward = fp.forward_header();
+    if forward.eq_ignore_ascii_case(&fp.header) {
+        return;
+    }
+    let value = http
+        .header(&fp.header)
+        .and_then(|v| str_or_bytes_to_cow(&v).map(|s| s.into_owned()));
+    // Must unset first since set_header() appends a header slot.
+    http.unset_header(forward);
+    if let Some(value) = value {
+        let _ = http.set_header(forward, &value);
+    }
+}
+
+/// Whether `vhost` rejects misdirected requests and the TLS SNI selects
+/// another vhost (or none). Requests without an SNI pass.
+fn is_misdirected(
+    directors: &VhostDirectorMap,
+    vhost: &Arc<VhostDirector>,
+    sni: Option<&str>,
+) -> bool {
+    match sni {
+        Some(sni) if directors.reject_misdirected.contains(vhost.hostname()) => {
+            !match_hostname(directors, sni).is_some_and(|d| Arc::ptr_eq(d, vhost))
+        }
+        _ => false,
+    }
+}
+
+/// TLS SNI that VCL put in `name`, lowercased. Empty means no TLS.
+fn get_sni(http: &HttpHeaders, name: &str) -> Option<String> {
+    let value = http.header(name)?;
+    let value = str_or_bytes_to_cow(&value)?;
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_lowercase())
+}
+
 fn get_host_header(http: &HttpHeaders) -> Option<String> {
     let host_value = http.header("host")?;
     let host_str = str_or_bytes_to_cow(&host_value)?;
     Some(strip_port(&host_str).to_lowercase())
 }
 
+/// Vhost lookup key from the configured source, lowercased like Host.
+fn get_route_key(http: &HttpHeaders, key: &RouteKey) -> Option<String> {
+    match key {
+        RouteKey::Header { name } => {
+            let value = http.header(name)?;
+            let value = str_or_bytes_to_cow(&value)?;
+            let value = value.trim();
+            (!value.is_empty()).then(|| value.to_lowercase())
+        }
+        RouteKey::PathPrefix => {
+            let url = http.url()?;
+            first_path_segment(std::str::from_utf8(url.as_ref()).ok()?)
+        }
+    }
+}
+
+/// First segment of a request target's path: `billing` for
+/// `/billing/invoices?page=2`.
+fn first_path_segment(url: &str) -> Option<String> {
+    let path = url.split(['?', '#']).next()?;
+    let segment = path.strip_prefix('/')?.split('/').next()?;
+    (!segment.is_empty()).then(|| segment.to_lowercase())
+}
+
 /// Strip port from a host string, handling IPv6 bracketed addresses.
 fn strip_port(host: &str) -> &str {
     if host.starts_with('[') {
@@ -800,6 +2409,13 @@ fn parse_query_string(query: &str) -> HashMap<&str, &str> {
     params
 }
 
+/// Whether the query string has a parameter `name`, with a value or not
+fn has_query_param(query: &str, name: &str) -> bool {
+    query
+        .split('&')
+        .any(|pair| pair.split('=').next() == Some(name))
+}
+
 /// Match path prefix according to Gateway API semantics
 ///
 /// Gateway API PathPrefix matching is element-wise, not simple string prefix:
@@ -846,11 +2462,182 @@ mod tests {
         assert!(!matches_wildcard("*.example.com", "example.com"));
     }
 
+    fn route(path_match: Option<PathMatchCompiled>) -> RouteEntry {
+        RouteEntry {
+            path_match,
+            method: None,
+            headers: Vec::new(),
+            query_params: Vec::new(),
+            filters: None,
+            backend_groups: Vec::new(),
+            listeners: Vec::new(),
+            route_name: None,
+            priority: None,
+            rule_index: 0,
+            cache_policy: None,
+            bypass_headers: Vec::new(),
+            selection: SelectionPolicy::default(),
+            hash_on: None,
+            hash_ring: None,
+            shadow_selection: None,
+            shadow_diff: None,
+            timeouts: None,
+            session_persistence: None,
+            retry: None,
+            qos: QosClass::Normal,
+            forward_host: None,
+            max_request_body_bytes: None,
+            equal_weights: false,
+            rate_limiter: None,
+            nonce_store: None,
+            url_rewrite_regex: None,
+        }
+    }
+
+    fn exact(p: &str) -> Option<PathMatchCompiled> {
+        Some(PathMatchCompiled::Exact(p.to_string()))
+    }
+
+    fn prefix(p: &str) -> Option<PathMatchCompiled> {
+        Some(PathMatchCompiled::PathPrefix(p.to_string()))
+    }
+
+    fn header(name: &str) -> HeaderMatchCompiled {
+        HeaderMatchCompiled::Exact {
+            name: name.to_string(),
+            value: "1".to_string(),
+            case_insensitive: false,
+        }
+    }
+
+    fn query(name: &str) -> QueryParamMatchCompiled {
+        QueryParamMatchCompiled::Exact {
+            name: name.to_string(),
+            value: "1".to_string(),
+        }
+    }
+
+    fn methods(names: &[&str]) -> MethodSet {
+        let names = names.iter().map(|m| m.to_string()).collect();
+        MethodSet::from_config(&config::RouteMethod::Any(names), false)
+    }
+
+    #[test]
+    fn test_method_set() {
+        let one = MethodSet::from_config(&config::RouteMethod::One("GET".to_string()), false);
+        assert!(one.contains("GET"));
+        assert!(!one.contains("HEAD"));
+        assert!(!one.contains("get"));
+        assert!(!one.contains("PURGE"));
+
+        // HEAD only comes with GET when asked for
+        let one = MethodSet::from_config(&config::RouteMethod::One("GET".to_string()), true);
+        assert_eq!(one.iter().collect::<Vec<_>>(), ["GET", "HEAD"]);
+        let post = MethodSet::from_config(&config::RouteMethod::One("POST".to_string()), true);
+        assert_eq!(post.iter().collect::<Vec<_>>(), ["POST"]);
+
+        let set = methods(&["PATCH", "GET", "PATCH", "DELETE"]);
+        assert!(set.contains("DELETE"));
+        assert!(!set.contains("PUT"));
+        assert_eq!(set.iter().collect::<Vec<_>>(), ["GET", "DELETE", "PATCH"]);
+    }
+
+    /// Asserts `a` is evaluated before `b`, whatever the input order.
+    fn assert_before(a: RouteEntry, b: RouteEntry) {
+        assert_eq!(route_order(&a, &b), std::cmp::Ordering::Less);
+        assert_eq!(route_order(&b, &a), std::cmp::Ordering::Greater);
+    }
+
+    #[test]
+    fn test_route_order_path_kind() {
+        // Exact beats prefix, even a longer one
+        assert_before(route(exact("/api/users")), route(prefix("/api")));
+        assert_before(route(exact("/a")), route(prefix("/api/users/long")));
+        // Prefix beats regex, regex beats no path match
+        let regex = Some(PathMatchCompiled::Regex(Arc::new(
+            Regex::new("^/api/.*").unwrap(),
+        )));
+        assert_before(route(prefix("/")), route(regex.clone()));
+        assert_before(route(regex), route(None));
+    }
+
+    #[test]
+    fn test_route_order_longer_prefix_wins() {
+        assert_before(route(prefix("/api/users")), route(prefix("/api")));
+        assert_before(route(prefix("/api")), route(prefix("/")));
+    }
+
+    #[test]
+    fn test_route_order_method_then_headers_then_query() {
+        let with_method = || RouteEntry {
+            method: Some(methods(&["GET"])),
+            ..route(prefix("/api"))
+        };
+        let with_headers = |n: usize| RouteEntry {
+            headers: (0..n).map(|i| header(&format!("x-{}", i))).collect(),
+            ..route(prefix("/api"))
+        };
+        let with_query = |n: usize| RouteEntry {
+            query_params: (0..n).map(|i| query(&format!("q{}", i))).collect(),
+            ..route(prefix("/api"))
+        };
+
+        // Path still dominates everything else
+        assert_before(route(prefix("/api/v1")), with_method());
+        // A method match beats any number of header matches
+        assert_before(with_method(), with_headers(5));
+        // More methods don't make a route more or less specific
+        let with_methods = RouteEntry {
+            method: Some(methods(&["GET", "HEAD", "POST"])),
+            ..route(prefix("/api"))
+        };
+        assert_eq!(with_methods.precedence(), with_method().precedence());
+        // More header matches win, and beat any number of query matches
+        assert_before(with_headers(2), with_headers(1));
+        assert_before(with_headers(1), with_query(5));
+        // More query param matches win over none
+        assert_before(with_query(2), with_query(1));
+        assert_before(with_query(1), route(prefix("/api")));
+    }
+
+    #[test]
+    fn test_route_order_ties_fall_back_to_rule_index() {
+        let at = |rule_index: i32| RouteEntry {
+            rule_index,
+            ..route(prefix("/api"))
+        };
+        assert_before(at(0), at(1));
+        assert_eq!(route_order(&at(3), &at(3)), std::cmp::Ordering::Equal);
+    }
+
+    #[test]
+    fn test_route_order_explicit_priority_overrides() {
+        let with_priority = |priority: i32, path| RouteEntry {
+            priority: Some(priority),
+            ..route(path)
+        };
+        // A higher explicit priority beats a more specific match
+        assert_before(with_priority(10, prefix("/")), route(exact("/api")));
+        assert_before(
+            with_priority(20, prefix("/")),
+            with_priority(10, exact("/api")),
+        );
+        // Absent counts as 0
+        assert_before(route(prefix("/")), with_priority(-1, exact("/api")));
+        // Equal priorities fall back to match precedence
+        assert_before(
+            with_priority(5, exact("/api")),
+            with_priority(5, prefix("/api")),
+        );
+    }
+
     #[test]
     fn test_backend_group_creation() {
         let group = WeightedBackendGroup {
             weight: 100,
             backends: vec!["10.0.0.1:8080".to_string(), "10.0.0.2:8080".to_string()],
+            draining: Vec::new(),
+            unresolved: Vec::new(),
         };
         assert_eq!(group.weight, 100);
         assert_eq!(group.backends.len(), 2);
@@ -948,6 +2735,256 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_with_security_headers() {
+        let security = SecurityHeaders {
+            headers: [
+                ("x-frame-options", "DENY"),
+                ("X-Content-Type-Options", "nosniff"),
+                ("referrer-policy", "no-referrer"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+            preserve_upstream: false,
+        };
+        let names = |actions: &[HTTPHeaderAction]| {
+            actions.iter().map(|a| a.name.clone()).collect::<Vec<_>>()
+        };
+
+        // No security headers: filters untouched
+        assert!(with_security_headers(None, None).is_none());
+        assert!(with_security_headers(None, Some(&SecurityHeaders::default())).is_none());
+
+        // Route without filters gets a response modifier of its own
+        let filters = with_security_headers(None, Some(&security)).unwrap();
+        let resp = filters.response_header_modifier.unwrap();
+        assert_eq!(
+            names(&resp.set),
+            [
+                "X-Content-Type-Options",
+                "referrer-policy",
+                "x-frame-options"
+            ]
+        );
+        assert!(resp.set_if_absent.is_empty());
+
+        // The route's modifier wins for headers it names, other filters stay
+        let route_filters = RouteFilters {
+            response_header_modifier: Some(crate::config::ResponseHeaderFilter {
+                set: vec![HTTPHeaderAction {
+                    name: "X-Frame-Options".to_string(),
+                    value: "SAMEORIGIN".to_string(),
+                }],
+                remove: vec!["Referrer-Policy".to_string()],
+                ..Default::default()
+            }),
+            url_rewrite: Some(crate::config::URLRewriteFilter {
+                hostname: Some("internal".to_string()),
+                path_type: None,
+                replace_full_path: None,
+                replace_prefix_match: None,
+                pattern: None,
+                substitution: None,
+            }),
+            ..Default::default()
+        };
+        let filters = with_security_headers(Some(&route_filters), Some(&security)).unwrap();
+        assert!(filters.url_rewrite.is_some());
+        let resp = filters.response_header_modifier.unwrap();
+        assert_eq!(
+            names(&resp.set),
+            ["X-Frame-Options", "X-Content-Type-Options"]
+        );
+        assert_eq!(resp.set[0].value, "SAMEORIGIN");
+        assert_eq!(resp.remove, ["Referrer-Policy"]);
+
+        // Preserving upstream values turns them into defaults
+        let preserving = SecurityHeaders {
+            preserve_upstream: true,
+            ..security
+        };
+        let resp = with_security_headers(None, Some(&preserving))
+            .unwrap()
+            .response_header_modifier
+            .unwrap();
+        assert!(resp.set.is_empty());
+        assert_eq!(resp.set_if_absent.len(), 3);
+    }
+
+    #[test]
+    fn test_with_vhost_response() {
+        let mut vhost: VHost =
+            serde_json::from_str(r#"{"routes": [], "reason_phrases": {"503": "Down"}}"#).unwrap();
+        let route_filters = RouteFilters {
+            response_header_modifier: Some(crate::config::ResponseHeaderFilter {
+                remove: vec!["Server".to_string()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let resp = with_vhost_response(Some(&route_filters), &vhost)
+            .unwrap()
+            .response_header_modifier
+            .unwrap();
+        assert_eq!(resp.remove, ["Server"]);
+        assert_eq!(resp.reason_phrases[&503], "Down");
+
+        // Routes without filters get a response modifier for the phrases
+        let resp = with_vhost_response(None, &vhost)
+            .unwrap()
+            .response_header_modifier
+            .unwrap();
+        assert_eq!(resp.reason_phrases.len(), 1);
+
+        vhost.reason_phrases.clear();
+        assert!(with_vhost_response(None, &vhost).is_none());
+    }
+
+    fn regex(pattern: &str) -> Option<PathMatchCompiled> {
+        Some(PathMatchCompiled::Regex(Arc::new(
+            Regex::new(pattern).unwrap(),
+        )))
+    }
+
+    #[test]
+    fn test_expand_captures_numbered() {
+        let pm = regex(r"^/users/(\d+)(/.*)?$");
+        let expand = |t, p| expand_captures(t, pm.as_ref(), p);
+
+        assert_eq!(expand("/v2/people/$1", "/users/42"), "/v2/people/42");
+        assert_eq!(expand("/v2/people/${1}x", "/users/42"), "/v2/people/42x");
+        assert_eq!(expand("/p/$1$2", "/users/42/posts"), "/p/42/posts");
+        // Optional group that did not participate expands to nothing
+        assert_eq!(expand("/p/$1$2", "/users/42"), "/p/42");
+        // $0 is the whole match, $$ a literal dollar
+        assert_eq!(expand("/all$0", "/users/7"), "/all/users/7");
+        assert_eq!(expand("/cost$$/$1", "/users/7"), "/cost$/7");
+        assert_eq!(expand("/a$b", "/users/7"), "/a$b");
+    }
+
+    #[test]
+    fn test_expand_captures_named() {
+        let pm = regex(r"^/(?P<team>[a-z]+)/(?P<id>\d+)$");
+        assert_eq!(
+            expand_captures("/teams/${team}/members/${id}", pm.as_ref(), "/ops/12"),
+            "/teams/ops/members/12"
+        );
+        // Named groups are numbered as well
+        assert_eq!(expand_captures("/$2/$1", pm.as_ref(), "/ops/12"), "/12/ops");
+    }
+
+    #[test]
+    fn test_expand_captures_without_regex_match() {
+        // Prefix routes and non-matching paths leave the template alone
+        let prefix = Some(PathMatchCompiled::PathPrefix("/users".to_string()));
+        assert_eq!(
+            expand_captures("/v2/$1", prefix.as_ref(), "/users/1"),
+            "/v2/$1"
+        );
+        let pm = regex(r"^/users/(\d+)$");
+        assert_eq!(expand_captures("/v2/$1", pm.as_ref(), "/other"), "/v2/$1");
+    }
+
+    #[test]
+    fn test_validate_capture_refs() {
+        let pm = regex(r"^/(?P<team>[a-z]+)/(\d+)$");
+
+        assert!(validate_capture_refs("/x/$0/$1/$2/${team}", pm.as_ref()).is_ok());
+        assert!(validate_capture_refs("/no/refs/$$", None).is_ok());
+
+        let err = validate_capture_refs("/x/$3", pm.as_ref()).unwrap_err();
+        assert!(err.contains("capture group '3'"), "{}", err);
+        let err = validate_capture_refs("/x/${user}", pm.as_ref()).unwrap_err();
+        assert!(err.contains("capture group 'user'"), "{}", err);
+        let err = validate_capture_refs("/x/${team", pm.as_ref()).unwrap_err();
+        assert!(err.contains("unterminated"), "{}", err);
+
+        // References need a regex path match to resolve against
+        let prefix = Some(PathMatchCompiled::PathPrefix("/users".to_string()));
+        let err = validate_capture_refs("/v2/$1", prefix.as_ref()).unwrap_err();
+        assert!(err.contains("no RegularExpression path match"), "{}", err);
+    }
+
+    #[test]
+    fn test_substitute_path() {
+        let re = Regex::new(r"/v1/(?P<kind>[a-z]+)/(\d+)").unwrap();
+        assert_eq!(
+            substitute_path(&re, "/v2/${kind}s/$2", "/api/v1/user/42/posts"),
+            "/api/v2/users/42/posts"
+        );
+        assert_eq!(substitute_path(&re, "$$/$2", "/v1/a/7"), "$/7");
+        // Only the first match is replaced, and a path without one passes
+        assert_eq!(substitute_path(&re, "/x", "/v1/a/1/v1/b/2"), "/x/v1/b/2");
+        assert_eq!(substitute_path(&re, "/x", "/v2/a/1"), "/v2/a/1");
+    }
+
+    fn rewrite(
+        pattern: Option<&str>,
+        substitution: Option<&str>,
+    ) -> crate::config::URLRewriteFilter {
+        crate::config::URLRewriteFilter {
+            hostname: None,
+            path_type: Some("RegexSubstitution".to_string()),
+            replace_full_path: None,
+            replace_prefix_match: None,
+            pattern: pattern.map(str::to_string),
+            substitution: substitution.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_compile_url_rewrite() {
+        let re = compile_url_rewrite(&rewrite(Some(r"^/a/(\d+)"), Some("/b/$1")), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(re.as_str(), r"^/a/(\d+)");
+
+        // Without a pattern the route's regex path match is reused
+        let pm = regex(r"^/users/(?P<id>\d+)$");
+        let re = compile_url_rewrite(&rewrite(None, Some("/people/${id}")), pm.as_ref())
+            .unwrap()
+            .unwrap();
+        let Some(PathMatchCompiled::Regex(route_re)) = &pm else {
+            unreachable!()
+        };
+        assert!(Arc::ptr_eq(&re, route_re));
+
+        // Other rewrites compile nothing
+        let mut other = rewrite(None, None);
+        other.path_type = Some("ReplaceFullPath".to_string());
+        assert!(compile_url_rewrite(&other, None).unwrap().is_none());
+
+        let prefix = Some(PathMatchCompiled::PathPrefix("/users".to_string()));
+        for (filter, path_match, expected) in [
+            (rewrite(Some("^/a"), None), None, "requires a substitution"),
+            (
+                rewrite(Some("(["), Some("/b")),
+                None,
+                "Invalid regex pattern",
+            ),
+            (
+                rewrite(None, Some("/b")),
+                prefix.as_ref(),
+                "requires a RegularExpression path match",
+            ),
+            (
+                rewrite(Some(r"^/a/(\d+)"), Some("/b/$2")),
+                None,
+                "capture group '2'",
+            ),
+            (
+                rewrite(None, Some("/b/${name}")),
+                pm.as_ref(),
+                "capture group 'name'",
+            ),
+        ] {
+            let err = compile_url_rewrite(&filter, path_match).unwrap_err();
+            assert!(err.contains(expected), "{}", err);
+        }
+    }
+
     #[test]
     fn test_path_match_compiled_from_routes() {
         // Test PathMatchCompiled with different route types
@@ -979,6 +3016,8 @@ mod tests {
                         Arc::new(crate::backend_pool::BackendPool::new()),
                         None,
                         None,
+                        None,
+                        RoutingLog::Off,
                     )),
                 ),
                 (
@@ -989,9 +3028,26 @@ mod tests {
                         Arc::new(crate::backend_pool::BackendPool::new()),
                         None,
                         None,
+                        None,
+                        RoutingLog::Off,
                     )),
                 ),
             ],
+            match_order: vec![
+                HostMatchKind::Exact,
+                HostMatchKind::Wildcard,
+                HostMatchKind::Default,
+            ],
+            tls_fingerprint: None,
+            route_key: None,
+            debug_headers: DebugHeaders::Off,
+            routing_log: RoutingLog::Off,
+            tracing: false,
+            trace_sample_percent: 100.0,
+            connection_id_header: None,
+            authorized_private: Vec::new(),
+            sni_header: None,
+            reject_misdirected: Default::default(),
         };
 
         // foo.bar.example.com should match *.bar.example.com (more specific)
@@ -1002,6 +3058,17 @@ mod tests {
         let sorted_directors = VhostDirectorMap {
             exact: directors.exact,
             wildcards,
+            match_order: directors.match_order,
+            tls_fingerprint: None,
+            route_key: None,
+            debug_headers: DebugHeaders::Off,
+            routing_log: RoutingLog::Off,
+            tracing: false,
+            trace_sample_percent: 100.0,
+            connection_id_header: None,
+            authorized_private: Vec::new(),
+            sni_header: None,
+            reject_misdirected: Default::default(),
         };
 
         let matched = match_hostname(&sorted_directors, "foo.bar.example.com");
@@ -1014,6 +3081,357 @@ mod tests {
         assert_eq!(matched.unwrap().hostname(), "*.example.com");
     }
 
+    #[test]
+    fn test_host_match_order_overrides_precedence() {
+        let vhost = |name: &str| {
+            Arc::new(VhostDirector::new(
+                name.to_string(),
+                vec![],
+                Arc::new(crate::backend_pool::BackendPool::new()),
+                None,
+                None,
+                None,
+                RoutingLog::Off,
+            ))
+        };
+        let mut exact = HashMap::new();
+        exact.insert("api.example.com".to_string(), vhost("api.example.com"));
+        exact.insert("*".to_string(), vhost("*"));
+        let mut directors = VhostDirectorMap {
+            exact,
+            wildcards: vec![("*.example.com".to_string(), vhost("*.example.com"))],
+            match_order: vec![
+                HostMatchKind::Exact,
+                HostMatchKind::Wildcard,
+                HostMatchKind::Default,
+            ],
+            tls_fingerprint: None,
+            route_key: None,
+            debug_headers: DebugHeaders::Off,
+            routing_log: RoutingLog::Off,
+            tracing: false,
+            trace_sample_percent: 100.0,
+            connection_id_header: None,
+            authorized_private: Vec::new(),
+            sni_header: None,
+            reject_misdirected: Default::default(),
+        };
+
+        // Default order: the exact vhost wins for an overlapping host.
+        let matched = match_hostname(&directors, "api.example.com").unwrap();
+        assert_eq!(matched.hostname(), "api.example.com");
+
+        // Wildcard first: the same host now lands on the wildcard vhost.
+        directors.match_order = vec![
+            HostMatchKind::Wildcard,
+            HostMatchKind::Exact,
+            HostMatchKind::Default,
+        ];
+        let matched = match_hostname(&directors, "api.example.com").unwrap();
+        assert_eq!(matched.hostname(), "*.example.com");
+
+        // Catch-all first shadows everything.
+        directors.match_order = vec![
+            HostMatchKind::Default,
+            HostMatchKind::Exact,
+            HostMatchKind::Wildcard,
+        ];
+        let matched = match_hostname(&directors, "api.example.com").unwrap();
+        assert_eq!(matched.hostname(), "*");
+
+        // Non-overlapping hosts are unaffected by the order.
+        directors.match_order = vec![
+            HostMatchKind::Wildcard,
+            HostMatchKind::Exact,
+            HostMatchKind::Default,
+        ];
+        let matched = match_hostname(&directors, "other.test").unwrap();
+        assert_eq!(matched.hostname(), "*");
+    }
+
+    #[test]
+    fn test_topology_aligned() {
+        let hinted: Vec<config::Backend> = serde_json::from_str(
+            r#"[{"address": "10.0.0.1", "port": 80, "hints": {"for_zones": ["zone-a"]}},
+                {"address": "10.0.0.2", "port": 80, "hints": {"for_zones": ["zone-b"]}},
+                {"address": "10.0.0.3", "port": 80, "hints": {"for_zones": ["zone-b", "zone-a"]}}]"#,
+        )
+        .unwrap();
+        let aligned = |backends: &[config::Backend], zone| {
+            topology_aligned(backends, zone)
+                .into_iter()
+                .map(|b| b.address.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(aligned(&hinted, Some("zone-a")), ["10.0.0.1", "10.0.0.3"]);
+        assert_eq!(aligned(&hinted, Some("zone-b")), ["10.0.0.2", "10.0.0.3"]);
+        // No backend hinted for the zone, or no zone: all of them
+        assert_eq!(aligned(&hinted, Some("zone-c")).len(), 3);
+        assert_eq!(aligned(&hinted, None).len(), 3);
+
+        // A backend without hints turns them off for the whole group
+        let mut partial = hinted.clone();
+        partial[1].hints = None;
+        assert_eq!(aligned(&partial, Some("zone-a")).len(), 3);
+    }
+
+    #[test]
+    fn test_is_misdirected() {
+        let vhost = |name: &str| {
+            Arc::new(VhostDirector::new(
+                name.to_string(),
+                vec![],
+                Arc::new(crate::backend_pool::BackendPool::new()),
+                None,
+                None,
+                None,
+                RoutingLog::Off,
+            ))
+        };
+        let mut exact = HashMap::new();
+        exact.insert("api.example.com".to_string(), vhost("api.example.com"));
+        exact.insert("www.example.com".to_string(), vhost("www.example.com"));
+        let mut directors = VhostDirectorMap {
+            exact,
+            wildcards: vec![(
+                "*.apps.example.com".to_string(),
+                vhost("*.apps.example.com"),
+            )],
+            match_order: vec![
+                HostMatchKind::Exact,
+                HostMatchKind::Wildcard,
+                HostMatchKind::Default,
+            ],
+            tls_fingerprint: None,
+            route_key: None,
+            debug_headers: DebugHeaders::Off,
+            routing_log: RoutingLog::Off,
+            tracing: false,
+            trace_sample_percent: 100.0,
+            connection_id_header: None,
+            authorized_private: Vec::new(),
+            sni_header: Some("x-tls-sni".to_string()),
+            reject_misdirected: Default::default(),
+        };
+        let api = Arc::clone(&directors.exact["api.example.com"]);
+        let apps = Arc::clone(&directors.wildcards[0].1);
+
+        // Off unless the vhost asks for it
+        assert!(!is_misdirected(&directors, &api, Some("www.example.com")));
+
+        directors.reject_misdirected = ["api.example.com", "*.apps.example.com"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        assert!(!is_misdirected(&directors, &api, Some("api.example.com")));
+        assert!(!is_misdirected(&directors, &api, None));
+        assert!(is_misdirected(&directors, &api, Some("www.example.com")));
+        // An SNI no vhost serves is misdirected too
+        assert!(is_misdirected(&directors, &api, Some("other.test")));
+        // Any name under the same wildcard shares its connections
+        assert!(!is_misdirected(
+            &directors,
+            &apps,
+            Some("a.apps.example.com")
+        ));
+        assert!(is_misdirected(&directors, &apps, Some("api.example.com")));
+    }
+
+    #[test]
+    fn test_header_match_case_insensitive() {
+        let compile = |match_type, value: &str, case_insensitive| {
+            HeaderMatchCompiled::from_config(&HeaderMatch {
+                name: "Content-Type".to_string(),
+                value: Some(value.to_string()),
+                match_type,
+                case_insensitive,
+            })
+            .unwrap()
+        };
+        let json = StrOrBytes::Utf8("Application/JSON");
+
+        let exact = compile(MatchType::Exact, "application/json", false);
+        assert!(!exact.matches_value(&json));
+        assert!(exact.matches_value(&StrOrBytes::Utf8("application/json")));
+
+        let exact = compile(MatchType::Exact, "application/json", true);
+        assert!(exact.matches_value(&json));
+        assert!(exact.matches_value(&StrOrBytes::Bytes(b"APPLICATION/JSON")));
+        assert!(!exact.matches_value(&StrOrBytes::Utf8("application/jsonx")));
+
+        let regex = compile(
+            MatchType::RegularExpression,
+            "^application/(json|xml)$",
+            false,
+        );
+        assert!(!regex.matches_value(&json));
+        let regex = compile(
+            MatchType::RegularExpression,
+            "^application/(json|xml)$",
+            true,
+        );
+        assert!(regex.matches_value(&json));
+    }
+
+    fn header_match(match_type: MatchType, name: &str, value: Option<&str>) -> HeaderMatchCompiled {
+        HeaderMatchCompiled::from_config(&HeaderMatch {
+            name: name.to_string(),
+            value: value.map(str::to_string),
+            match_type,
+            case_insensitive: false,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_header_match_types() {
+        let request = [
+            ("Host", "api.example.com"),
+            ("Authorization", "Bearer abc"),
+            ("X-Empty", ""),
+            ("Accept", "text/html"),
+            ("Accept", "application/json"),
+        ];
+        let matches = |m: &HeaderMatchCompiled| {
+            m.matches_headers(request.iter().map(|(n, v)| (*n, StrOrBytes::Utf8(v))))
+        };
+
+        let exact = header_match(MatchType::Exact, "accept", Some("application/json"));
+        assert!(matches(&exact));
+        let regex = header_match(
+            MatchType::RegularExpression,
+            "authorization",
+            Some("^Bearer "),
+        );
+        assert!(matches(&regex));
+        let regex = header_match(
+            MatchType::RegularExpression,
+            "authorization",
+            Some("^Basic "),
+        );
+        assert!(!matches(&regex));
+
+        // Present takes any value, an empty one included
+        assert!(matches(&header_match(MatchType::Present, "x-empty", None)));
+        assert!(matches(&header_match(
+            MatchType::Present,
+            "AUTHORIZATION",
+            None
+        )));
+        assert!(!matches(&header_match(MatchType::Present, "cookie", None)));
+
+        assert!(matches(&header_match(MatchType::Absent, "cookie", None)));
+        assert!(!matches(&header_match(
+            MatchType::Absent,
+            "authorization",
+            None
+        )));
+        assert!(!matches(&header_match(MatchType::Absent, "X-Empty", None)));
+
+        // All of a route's header matches must hold
+        let all = |ms: &[HeaderMatchCompiled]| ms.iter().all(matches);
+        assert!(all(&[
+            header_match(MatchType::Present, "authorization", None),
+            header_match(MatchType::Absent, "cookie", None),
+            header_match(MatchType::Exact, "accept", Some("text/html")),
+        ]));
+        assert!(!all(&[
+            header_match(MatchType::Absent, "authorization", None),
+            header_match(MatchType::Exact, "accept", Some("text/html")),
+        ]));
+    }
+
+    #[test]
+    fn test_query_param_match_types() {
+        let compile = |match_type, value: Option<&str>| {
+            QueryParamMatchCompiled::from_config(&QueryParamMatch {
+                name: "debug".to_string(),
+                value: value.map(str::to_string),
+                match_type,
+            })
+            .unwrap()
+        };
+        let exact = compile(MatchType::Exact, Some("1"));
+        let regex = compile(MatchType::RegularExpression, Some("^[0-9]+$"));
+        let present = compile(MatchType::Present, None);
+        let absent = compile(MatchType::Absent, None);
+
+        assert!(exact.matches("a=b&debug=1"));
+        assert!(regex.matches("debug=42"));
+        assert!(!regex.matches("debug=on"));
+
+        // A parameter without a value is still present
+        for qs in ["debug=1", "a=b&debug=", "debug", "a=b&debug"] {
+            assert!(present.matches(qs), "{}", qs);
+            assert!(!absent.matches(qs), "{}", qs);
+        }
+        for qs in ["", "a=b", "debugging=1", "x=debug"] {
+            assert!(!present.matches(qs), "{}", qs);
+            assert!(absent.matches(qs), "{}", qs);
+        }
+        assert!(!exact.matches("debug"));
+    }
+
+    #[test]
+    fn test_route_order_counts_presence_matches() {
+        let with = |headers: Vec<HeaderMatchCompiled>| RouteEntry {
+            headers,
+            ..route(prefix("/"))
+        };
+        // An Absent match makes a route as specific as any other header match
+        assert_before(
+            with(vec![header_match(MatchType::Absent, "authorization", None)]),
+            route(prefix("/")),
+        );
+        assert_eq!(
+            with(vec![header_match(MatchType::Present, "cookie", None)]).precedence(),
+            with(vec![header("x-a")]).precedence()
+        );
+    }
+
+    #[test]
+    fn test_config_history() {
+        let config = |version| Config {
+            version,
+            ..Config::empty()
+        };
+        let mut history = ConfigHistory::default();
+        assert!(history.previous().is_none());
+
+        history.push(config(1));
+        assert!(history.previous().is_none());
+
+        for version in 2..=6 {
+            history.push(config(version));
+        }
+        assert_eq!(history.0.len(), CONFIG_HISTORY_DEPTH);
+        assert_eq!(history.previous().unwrap().version, 5);
+
+        // Roll back as far as the history goes
+        history.pop();
+        assert_eq!(history.previous().unwrap().version, 4);
+        history.pop();
+        assert_eq!(history.previous().unwrap().version, 3);
+        history.pop();
+        assert!(history.previous().is_none());
+    }
+
+    #[test]
+    fn test_first_path_segment() {
+        assert_eq!(
+            first_path_segment("/billing/invoices"),
+            Some("billing".to_string())
+        );
+        assert_eq!(first_path_segment("/Billing"), Some("billing".to_string()));
+        assert_eq!(
+            first_path_segment("/billing?page=2"),
+            Some("billing".to_string())
+        );
+        assert_eq!(first_path_segment("/"), None);
+        assert_eq!(first_path_segment("//billing"), None);
+        assert_eq!(first_path_segment("*"), None);
+    }
+
     #[test]
     fn test_strip_port_regular_hostname() {
         assert_eq!(strip_port("example.com"), "example.com");
@@ -1043,4 +3461,25 @@ mod tests {
         // Non-ambiguous bare IPv6 (no trailing numeric segment) is preserved.
         assert_eq!(strip_port("2001:db8::ff"), "2001:db8::ff");
     }
+
+    #[test]
+    fn test_request_target_rejects_control_chars() {
+        assert!(is_valid_request_target(b"/api/users?q=1"));
+        assert!(is_valid_request_target(b"/caf%C3%A9"));
+        assert!(!is_valid_request_target(b"/api\r\nX-Injected: 1"));
+        assert!(!is_valid_request_target(b"/api\x00.json"));
+        assert!(!is_valid_request_target(b"/api\tusers"));
+        assert!(!is_valid_request_target(b"/api\x7f"));
+    }
+
+    #[test]
+    fn test_double_slash_target_is_a_path() {
+        // "//evil.com/x" is an origin-form path, not an authority.
+        assert!(is_valid_request_target(b"//evil.com/x"));
+        assert!(PathMatchCompiled::PathPrefix("/".to_string()).matches("//evil.com/x"));
+        assert!(PathMatchCompiled::Exact("//foo".to_string()).matches("//foo"));
+        // The empty first segment doesn't collapse into a prefix match.
+        assert!(!PathMatchCompiled::PathPrefix("/evil.com".to_string()).matches("//evil.com/x"));
+        assert!(!PathMatchCompiled::PathPrefix("/foo".to_string()).matches("//foo"));
+    }
 }
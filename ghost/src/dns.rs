@@ -0,0 +1,244 @@
+//! DNS names as native backend addresses.
+//!
+//! A backend `address` that isn't an IP literal (e.g. a Service name like
+//! `api.default.svc.cluster.local`) is resolved when routing is built, and
+//! a native backend is created for each address it resolves to. Answers are
+//! kept in a [`DnsCache`] shared by the pool and its clones.
+//!
+//! The system resolver doesn't report record TTLs, so names are looked up
+//! again every `dns_refresh_ms` (default 30s) by a task on the shared
+//! background runtime. When an answer changes, the live config is applied
+//! again in a VCL context of the task's own, the way the file watcher
+//! reloads, creating backends for new addresses and retiring the rest.
+//! Like the watcher, refreshes only run while the VCL is warm.
+//!
+//! A name that doesn't resolve doesn't fail the reload: its route is left
+//! without those backends and answers 503 until a refresh resolves it. A
+//! name that resolved before keeps its last answer when a lookup fails.
+
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, ToSocketAddrs};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use parking_lot::{Mutex, RwLock};
+use tokio::task::JoinHandle;
+use varnish::ffi;
+
+use crate::config::Config;
+use crate::director::GhostDirector;
+use crate::watch::{self, Gate, TaskCtx, VclPtr};
+
+/// How often names are looked up again, unless `dns_refresh_ms` says
+pub const DEFAULT_REFRESH: Duration = Duration::from_secs(30);
+
+/// Whether a backend address is a DNS name to resolve rather than an IP
+pub fn is_dns_name(address: &str) -> bool {
+    address.parse::<IpAddr>().is_err()
+}
+
+/// Every DNS name native backends in `config` are addressed by, with its port
+pub fn names(config: &Config) -> BTreeSet<(String, u16)> {
+    config
+        .vhosts
+        .values()
+        .flat_map(|vhost| {
+            vhost
+                .routes
+                .iter()
+                .flat_map(|r| &r.backend_groups)
+                .chain(&vhost.default_backends)
+        })
+        .filter(|group| group.external_proxy.is_none())
+        .flat_map(|group| &group.backends)
+        .filter(|b| is_dns_name(&b.address))
+        .map(|b| (b.address.clone(), b.port))
+        .collect()
+}
+
+/// Look `host` up with the system resolver. Addresses come back sorted and
+/// without duplicates, so answers compare equal when the set is the same.
+fn lookup(host: &str, port: u16) -> Result<Vec<IpAddr>, String> {
+    let addrs: BTreeSet<IpAddr> = (host, port)
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .map(|addr| addr.ip())
+        .collect();
+    if addrs.is_empty() {
+        return Err("no addresses".to_string());
+    }
+    Ok(addrs.into_iter().collect())
+}
+
+/// Last answer for each name, shared by a backend pool and its clones.
+#[derive(Debug, Default)]
+pub struct DnsCache {
+    answers: RwLock<HashMap<(String, u16), Vec<IpAddr>>>,
+}
+
+impl DnsCache {
+    /// Look `host` up now, keeping the answer. A failed lookup falls back to
+    /// the last answer, if there is one.
+    pub fn resolve(&self, host: &str, port: u16) -> Result<Vec<IpAddr>, String> {
+        let key = (host.to_string(), port);
+        match lookup(host, port) {
+            Ok(addrs) => {
+                self.answers.write().insert(key, addrs.clone());
+                Ok(addrs)
+            }
+            Err(e) => self.answers.read().get(&key).cloned().ok_or(e),
+        }
+    }
+
+    /// Whether a fresh lookup of any of `names` gives a different answer
+    /// than the last one. Failed lookups count as unchanged.
+    pub fn changed(&self, names: &BTreeSet<(String, u16)>) -> bool {
+        names.iter().any(|(host, port)| {
+            lookup(host, *port)
+                .is_ok_and(|addrs| self.answers.read().get(&(host.clone(), *port)) != Some(&addrs))
+        })
+    }
+
+    /// Forget names no longer in the config
+    pub fn retain(&self, names: &BTreeSet<(String, u16)>) {
+        self.answers.write().retain(|key, _| names.contains(key));
+    }
+}
+
+/// A running refresh loop. Dropping it stops the loop; a refresh already
+/// running finishes first.
+pub struct DnsRefresher {
+    handle: JoinHandle<()>,
+    /// Held for the duration of each refresh
+    gate: Arc<Mutex<Gate>>,
+}
+
+impl DnsRefresher {
+    /// Look up the DNS names of `director`'s live config periodically.
+    /// `vcl` is the VCL the director's backends are created in.
+    pub fn start(director: Weak<GhostDirector>, vcl: ffi::VCL_VCL) -> Self {
+        let gate = watch::gate(vcl);
+        let handle =
+            crate::external_backend::spawn(refresh_loop(director, VclPtr(vcl), Arc::clone(&gate)));
+        DnsRefresher { handle, gate }
+    }
+}
+
+impl Drop for DnsRefresher {
+    fn drop(&mut self) {
+        self.gate.lock().stop();
+        self.handle.abort();
+    }
+}
+
+async fn refresh_loop(director: Weak<GhostDirector>, vcl: VclPtr, gate: Arc<Mutex<Gate>>) {
+    let vcl = Arc::new(vcl);
+    loop {
+        let interval = match director.upgrade() {
+            Some(director) => director.dns_refresh_interval(),
+            None => return,
+        };
+        tokio::time::sleep(interval).await;
+        let director = director.clone();
+        let vcl = Arc::clone(&vcl);
+        let task_gate = Arc::clone(&gate);
+        // Lookups block, and so does rebuilding routing. Skipped while the
+        // VCL is cold; the next refresh catches up.
+        let refreshed = tokio::task::spawn_blocking(move || {
+            let gate = task_gate.lock();
+            if !gate.open() {
+                return;
+            }
+            let Some(director) = director.upgrade() else {
+                return;
+            };
+            let mut task = TaskCtx::new(&vcl);
+            director.refresh_dns(&mut task.ctx());
+        })
+        .await;
+        if refreshed.is_err() || gate.lock().stopped() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(host: &str, port: u16) -> BTreeSet<(String, u16)> {
+        BTreeSet::from([(host.to_string(), port)])
+    }
+
+    #[test]
+    fn test_is_dns_name() {
+        assert!(is_dns_name("api.default.svc.cluster.local"));
+        assert!(is_dns_name("localhost"));
+        assert!(!is_dns_name("10.0.0.1"));
+        assert!(!is_dns_name("fd00::1"));
+    }
+
+    #[test]
+    fn test_resolve_localhost() {
+        let cache = DnsCache::default();
+        let addrs = cache.resolve("localhost", 8080).unwrap();
+        assert!(!addrs.is_empty());
+        assert!(addrs.iter().all(IpAddr::is_loopback), "{:?}", addrs);
+        assert!(!cache.changed(&name("localhost", 8080)));
+    }
+
+    #[test]
+    fn test_unresolvable_name() {
+        let cache = DnsCache::default();
+        // .invalid never resolves (RFC 6761)
+        assert!(cache.resolve("backend.invalid", 8080).is_err());
+        assert!(!cache.changed(&name("backend.invalid", 8080)));
+
+        // A name that resolved before keeps its last answer
+        let last = vec!["10.0.0.1".parse().unwrap()];
+        cache
+            .answers
+            .write()
+            .insert(("backend.invalid".to_string(), 8080), last.clone());
+        assert_eq!(cache.resolve("backend.invalid", 8080).unwrap(), last);
+    }
+
+    #[test]
+    fn test_changed_answer() {
+        let cache = DnsCache::default();
+        // Never looked up: a successful lookup is news
+        assert!(cache.changed(&name("localhost", 80)));
+        cache.answers.write().insert(
+            ("localhost".to_string(), 80),
+            vec!["10.0.0.1".parse().unwrap()],
+        );
+        assert!(cache.changed(&name("localhost", 80)));
+        cache.resolve("localhost", 80).unwrap();
+        assert!(!cache.changed(&name("localhost", 80)));
+
+        cache.retain(&BTreeSet::new());
+        assert!(cache.answers.read().is_empty());
+    }
+
+    #[test]
+    fn test_names_from_config() {
+        let config: Config = serde_json::from_str(
+            r#"{"version": 2, "vhosts": {"api.example.com": {
+                "routes": [{"backend_groups": [
+                    {"backends": [{"address": "api.default.svc", "port": 8080},
+                                  {"address": "10.0.0.1", "port": 8080}]},
+                    {"external_proxy": {"hostname": "origin.example.net", "port": 443}}
+                ]}],
+                "default_backends": [{"backends": [{"address": "web.default.svc", "port": 80}]}]
+            }}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            names(&config),
+            BTreeSet::from([
+                ("api.default.svc".to_string(), 8080),
+                ("web.default.svc".to_string(), 80),
+            ])
+        );
+    }
+}
@@ -5,39 +5,261 @@
 //! resolver hide rotating cloud IPs from Varnish.
 
 use std::io::Write;
-use std::sync::OnceLock;
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::Thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use bytes::Bytes;
+use arc_swap::{ArcSwap, ArcSwapOption};
+use bytes::{Bytes, BytesMut};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
 use reqwest::header::HeaderName;
 use reqwest::Client;
 use tokio::runtime::Runtime;
 use tokio::sync::mpsc::{Receiver, Sender};
-use varnish::vcl::{Ctx, StrOrBytes, VclBackend, VclError, VclResponse};
+use varnish::vcl::{BodyState as ReqBodyState, Ctx, StrOrBytes, VclBackend, VclError, VclResponse};
 
-use crate::config::ExternalProxy;
+use crate::config::{
+    CompressRequest, ExternalClientConfig, ExternalProxy, QosClass, UpstreamProtocol,
+};
+use crate::connect_timeout::{AdaptiveConnectLayer, AdaptiveConnectTimeout};
+use crate::fair_queue::FairQueue;
+use crate::internal_headers;
+use crate::outlier::OutcomeRecorder;
+use crate::request_body::{
+    body_limit, compress, exceeds_limit, gunzip, is_gzip, BodyError, BodySender, CappedBuffer,
+};
+use crate::retry::{RetryState, BODY_MATCH_HEADER, RETRY_STATE_HEADER};
+use crate::signing::SignerSlot;
+use crate::stats::LatencyStats;
+use crate::upstream_error::{synth_response, ErrorClass};
+use crate::vhost_director::{
+    is_internal_header, BACKEND_TIMEOUT_HEADER, FORWARD_HOST_HEADER, MAX_BODY_HEADER, QOS_HEADER,
+    TENANT_HEADER,
+};
 
 const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
 const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// reqwest's own default
+const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Time past the request's timeout a worker waits on the upstream task
+/// when `recv_timeout_ms` isn't set. reqwest gives up first unless the task
+/// or the runtime is stuck.
+const RECV_TIMEOUT_GRACE: Duration = Duration::from_secs(5);
 
 /// Per-stream chunk channel size. Roughly bounds in-flight buffered bytes
 /// per response to `CHUNK_CHANNEL_SIZE * reqwest_chunk_size` (~512KB at
 /// reqwest's 16KB default), giving backpressure without starving the stream.
 const CHUNK_CHANNEL_SIZE: usize = 32;
 
-/// Methods the synthetic backend will forward. Anything else is rejected
-/// with 405 because we cannot stream a request body through the current
-/// VclBackend bridge.
-const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+/// Body returned with the synthetic 413 for oversized request bodies.
+const BODY_TOO_LARGE_BODY: &[u8] = b"request body too large\n";
+
+/// Body returned with the synthetic 400 for undecodable gzip request bodies.
+const BODY_MALFORMED_BODY: &[u8] = b"request body is not valid gzip\n";
+
+/// Seconds a client should wait before retrying when the pending queue is full.
+const QUEUE_FULL_RETRY_AFTER: &str = "1";
+
+/// Body returned with the synthetic 503 when too many requests are pending.
+const QUEUE_FULL_BODY: &[u8] = b"external proxy backend is saturated; retry later\n";
+
+/// Body returned with the synthetic 503 when too many responses are streaming.
+const STREAMS_FULL_BODY: &[u8] =
+    b"external proxy backend has too many active streams; retry later\n";
+
+/// Cap on concurrent work, with the limit adjustable on reload.
+/// Lower QoS classes only get part of it, so they are shed first.
+struct ConcurrencyLimiter {
+    active: AtomicUsize,
+    max: AtomicUsize,
+    /// Requests waiting for a slot, when the limit queues at all
+    queue: FairQueue,
+}
+
+impl ConcurrencyLimiter {
+    const fn new(max: usize) -> Self {
+        Self {
+            active: AtomicUsize::new(0),
+            max: AtomicUsize::new(max),
+            queue: FairQueue::new(),
+        }
+    }
+
+    /// Reserve a slot, or `None` if the limit for `qos` is reached.
+    fn try_acquire(&self, qos: QosClass) -> Option<LimiterSlot<'_>> {
+        self.reserve(qos).then(|| LimiterSlot(self))
+    }
+
+    /// Reserve a slot, queued behind vhost `tenant`'s earlier requests if
+    /// the limit is reached and `queue_timeout` is set. `None` if the
+    /// limit is still reached when that runs out.
+    fn acquire(
+        &self,
+        qos: QosClass,
+        tenant: &str,
+        queue_timeout: Option<Duration>,
+        max_queued: usize,
+    ) -> Option<LimiterSlot<'_>> {
+        // Nothing jumps the queue
+        if self.queue.len() == 0 {
+            if let Some(slot) = self.try_acquire(qos) {
+                return Some(slot);
+            }
+        }
+        let timeout = queue_timeout?;
+        self.queue
+            .wait(tenant, qos, timeout, max_queued, |q| self.reserve(q))
+            .then(|| LimiterSlot(self))
+    }
+
+    fn reserve(&self, qos: QosClass) -> bool {
+        let max = admission_limit(qos, self.max.load(Ordering::Relaxed));
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then_some(n + 1)
+            })
+            .is_ok()
+    }
+}
+
+/// Slots of a limit of `max` that requests of class `qos` may fill. Low
+/// priority gets half, normal all but a tenth kept for high priority.
+fn admission_limit(qos: QosClass, max: usize) -> usize {
+    match qos {
+        QosClass::High => max,
+        QosClass::Normal => max - max / 10,
+        QosClass::Low => max / 2,
+    }
+}
+
+/// A reserved limiter slot, released on drop.
+struct LimiterSlot<'a>(&'a ConcurrencyLimiter);
+
+impl Drop for LimiterSlot<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+        self.0.queue.grant(|qos| self.0.reserve(qos));
+    }
+}
+
+/// Caps the number of requests handed to the tokio runtime that are still
+/// waiting for upstream response headers. Without a cap, a slow upstream
+/// lets Varnish keep queueing work on the runtime until memory runs out.
+static PENDING: ConcurrencyLimiter = ConcurrencyLimiter::new(1024);
+
+/// Caps responses being streamed, from dispatch until the body is dropped.
+/// Each one pins a Varnish worker and a chunk channel, so slow clients on
+/// long downloads could otherwise take every worker.
+static STREAMS: ConcurrencyLimiter = ConcurrencyLimiter::new(1024);
+
+/// How long a request may wait for a `PENDING` slot, in milliseconds. 0
+/// sheds it right away.
+static QUEUE_TIMEOUT_MS: AtomicU64 = AtomicU64::new(0);
+
+/// Requests that may wait for a `PENDING` slot at once
+static MAX_QUEUED: AtomicUsize = AtomicUsize::new(1024);
+
+/// Apply the global external client settings. Called on every reload.
+pub fn configure(config: &ExternalClientConfig) {
+    PENDING
+        .max
+        .store(config.max_pending_requests, Ordering::Relaxed);
+    STREAMS
+        .max
+        .store(config.max_active_streams, Ordering::Relaxed);
+    QUEUE_TIMEOUT_MS.store(config.queue_timeout_ms.unwrap_or(0), Ordering::Relaxed);
+    MAX_QUEUED.store(config.max_queued_requests, Ordering::Relaxed);
+}
+
+/// HTTP client settings shared by every external proxy, from
+/// `external_client`. A change rebuilds each client on the next reload;
+/// requests in flight finish on the client they started with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientParams {
+    pub pool_max_idle_per_host: Option<usize>,
+    pub pool_idle_timeout: Duration,
+    /// Used by proxies without their own `connect_timeout_ms`
+    pub connect_timeout: Duration,
+    /// Used by proxies without their own `request_timeout_ms`
+    pub request_timeout: Duration,
+    pub tcp_keepalive: Option<Duration>,
+    pub tcp_nodelay: bool,
+}
+
+impl Default for ClientParams {
+    fn default() -> Self {
+        Self::from_config(&ExternalClientConfig::default())
+    }
+}
+
+impl ClientParams {
+    pub fn from_config(config: &ExternalClientConfig) -> Self {
+        Self {
+            pool_max_idle_per_host: config.pool_max_idle_per_host,
+            pool_idle_timeout: config
+                .pool_idle_timeout_ms
+                .map_or(DEFAULT_POOL_IDLE_TIMEOUT, Duration::from_millis),
+            connect_timeout: config
+                .connect_timeout_ms
+                .map_or(DEFAULT_CONNECT_TIMEOUT, Duration::from_millis),
+            request_timeout: config
+                .request_timeout_ms
+                .map_or(DEFAULT_REQUEST_TIMEOUT, Duration::from_millis),
+            tcp_keepalive: config.tcp_keepalive_ms.map(Duration::from_millis),
+            tcp_nodelay: config.tcp_nodelay,
+        }
+    }
+
+    /// One-line summary for `backend.list`
+    pub fn describe(&self) -> String {
+        format!(
+            "pool_max_idle_per_host={} pool_idle_timeout={}ms connect_timeout={}ms \
+             request_timeout={}ms tcp_keepalive={} tcp_nodelay={}",
+            self.pool_max_idle_per_host
+                .map_or_else(|| "unlimited".to_string(), |n| n.to_string()),
+            self.pool_idle_timeout.as_millis(),
+            self.connect_timeout.as_millis(),
+            self.request_timeout.as_millis(),
+            self.tcp_keepalive
+                .map_or_else(|| "off".to_string(), |d| format!("{}ms", d.as_millis())),
+            self.tcp_nodelay
+        )
+    }
+
+    pub fn to_json(self) -> serde_json::Value {
+        serde_json::json!({
+            "pool_max_idle_per_host": self.pool_max_idle_per_host,
+            "pool_idle_timeout_ms": self.pool_idle_timeout.as_millis() as u64,
+            "connect_timeout_ms": self.connect_timeout.as_millis() as u64,
+            "request_timeout_ms": self.request_timeout.as_millis() as u64,
+            "tcp_keepalive_ms": self.tcp_keepalive.map(|d| d.as_millis() as u64),
+            "tcp_nodelay": self.tcp_nodelay,
+        })
+    }
+}
 
-/// Body returned with the synthetic 405 so curl/log readers see why.
-const METHOD_NOT_ALLOWED_BODY: &[u8] =
-    b"external proxy backend does not forward request bodies; allowed methods: GET, HEAD, OPTIONS\n";
+/// Number of external proxy requests currently waiting on response headers.
+pub fn pending_requests() -> usize {
+    PENDING.active.load(Ordering::Relaxed)
+}
+
+/// Number of requests queued for a pending request slot.
+pub fn queued_requests() -> usize {
+    PENDING.queue.len()
+}
+
+/// Number of external proxy responses currently being streamed.
+pub fn active_streams() -> usize {
+    STREAMS.active.load(Ordering::Relaxed)
+}
 
-/// Background tokio runtime shared by all external-proxy backends.
+/// Background tokio runtime shared by all external-proxy backends and
+/// active health probes.
 struct BgThread {
-    /// Held only for its destructor — dropping it stops the runtime.
-    #[allow(dead_code)]
     rt: Runtime,
 }
 
@@ -45,6 +267,8 @@ enum RespMsg {
     Headers(HeadersFrame),
     Chunk(Bytes),
     Err(String),
+    /// The request failed before response headers arrived.
+    Failed(ErrorClass, String),
 }
 
 struct HeadersFrame {
@@ -72,11 +296,46 @@ pub fn warm_runtime() {
     let _ = bgt();
 }
 
-async fn process_request(client: Client, request: reqwest::Request, resp_tx: Sender<RespMsg>) {
-    let mut resp = match client.execute(request).await {
+/// Run a background task (e.g. a health probe loop) on the shared runtime.
+pub fn spawn<F>(task: F) -> tokio::task::JoinHandle<()>
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    bgt().rt.spawn(task)
+}
+
+/// Run `request` and stream its response to `resp_tx`. With a
+/// `header_timeout`, an upstream that hasn't sent complete response headers
+/// by then fails the request as a timeout.
+async fn process_request(
+    client: Client,
+    request: reqwest::Request,
+    header_timeout: Option<Duration>,
+    resp_tx: Sender<RespMsg>,
+) {
+    let response = match header_timeout {
+        Some(limit) => match tokio::time::timeout(limit, client.execute(request)).await {
+            Ok(response) => response,
+            Err(_) => {
+                let msg = format!(
+                    "external proxy: no response headers within {}ms",
+                    limit.as_millis()
+                );
+                let _ = resp_tx
+                    .send(RespMsg::Failed(ErrorClass::Timeout, msg))
+                    .await;
+                return;
+            }
+        },
+        None => client.execute(request).await,
+    };
+    let mut resp = match response {
         Ok(r) => r,
         Err(e) => {
-            let _ = resp_tx.send(RespMsg::Err(format!("external proxy: {}", e))).await;
+            let class = ErrorClass::from_reqwest(&e);
+            let _ = resp_tx
+                .send(RespMsg::Failed(class, format!("external proxy: {}", e)))
+                .await;
             return;
         }
     };
@@ -108,18 +367,62 @@ async fn process_request(client: Client, request: reqwest::Request, resp_tx: Sen
     }
 }
 
+/// Counts one in-flight request against a backend for as long as it lives.
+///
+/// Created before the request is handed to the tokio runtime and carried by
+/// the response body, so the count drops when Varnish finishes the fetch or
+/// as soon as an error unwinds `get_response`.
+struct InFlightGuard(Arc<AtomicU64>);
+
+impl InFlightGuard {
+    fn new(counter: &Arc<AtomicU64>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        crate::vsc::incr(|c| &c.in_flight);
+        Self(Arc::clone(counter))
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+        crate::vsc::decr(|c| &c.in_flight);
+    }
+}
+
 /// Synthetic backend that proxies requests via `reqwest` to a single upstream
 /// hostname. One `ExternalBackend` per unique (hostname, port, tls) tuple —
 /// reqwest keeps the connection pool and DNS cache underneath, so rotating
 /// IPs don't pollute Varnish's VBE stats.
 pub struct ExternalBackend {
-    base_url: String,
     upstream_host: String,
-    client: Client,
+    /// Swapped by `reconfigure` when a reload changes the timeouts or the
+    /// TLS settings.
+    client: ArcSwap<UpstreamClient>,
+    /// In-flight request counter shared with the `BackendPool`.
+    in_flight: Arc<AtomicU64>,
+    /// Reports request outcomes for passive outlier detection.
+    outcomes: OutcomeRecorder,
+    /// Signs outgoing requests when the upstream requires it. Shared with
+    /// the `BackendPool` so reloads can rotate keys in place.
+    signer: SignerSlot,
+    /// Inflate gzip request bodies before forwarding.
+    decompress_request_body: AtomicBool,
+    /// Gzip request bodies before forwarding.
+    compress_request: ArcSwapOption<CompressRequest>,
+    /// Headers sent or delivered as one comma-joined line.
+    coalesce: ArcSwap<HeaderCoalescing>,
+    /// Round-trip timings, kept for as long as the backend is configured.
+    latency: Arc<LatencyStats>,
 }
 
 impl ExternalBackend {
-    pub fn new(proxy: &ExternalProxy) -> Result<Self, VclError> {
+    pub fn new(
+        proxy: &ExternalProxy,
+        params: &ClientParams,
+        in_flight: Arc<AtomicU64>,
+        outcomes: OutcomeRecorder,
+        signer: SignerSlot,
+    ) -> Result<Self, VclError> {
         if proxy.hostname.is_empty() {
             return Err(VclError::new("external_proxy: hostname is empty".to_string()));
         }
@@ -127,36 +430,252 @@ impl ExternalBackend {
             return Err(VclError::new("external_proxy: port is zero".to_string()));
         }
 
-        let scheme = if proxy.tls { "https" } else { "http" };
-        let base_url = format!("{}://{}:{}", scheme, proxy.hostname, proxy.port);
-
-        // Auto-decompression is intentionally not enabled (feature not
-        // compiled in) so proxied bytes pass through unmodified and Varnish
-        // can cache the wire representation.
-        let client = reqwest::ClientBuilder::new()
-            .timeout(DEFAULT_REQUEST_TIMEOUT)
-            .connect_timeout(DEFAULT_CONNECT_TIMEOUT)
-            // Surface 30x to the cache layer instead of following.
-            .redirect(reqwest::redirect::Policy::none())
-            .build()
-            .map_err(|e| {
-                VclError::new(format!(
-                    "external_proxy: failed to build reqwest client: {}",
-                    e
-                ))
-            })?;
+        let client = UpstreamClient::new(proxy, params)?;
 
         Ok(Self {
-            base_url,
             upstream_host: proxy.hostname.clone(),
+            client: ArcSwap::from_pointee(client),
+            in_flight,
+            outcomes,
+            signer,
+            decompress_request_body: AtomicBool::new(proxy.decompress_request_body),
+            compress_request: ArcSwapOption::from_pointee(proxy.compress_request.clone()),
+            coalesce: ArcSwap::from_pointee(HeaderCoalescing::from_proxy(proxy)),
+            latency: Arc::default(),
+        })
+    }
+
+    pub fn latency(&self) -> &LatencyStats {
+        &self.latency
+    }
+
+    /// Prepare reloaded settings for the same upstream, without applying
+    /// them, so a reload that fails later leaves this backend untouched. The
+    /// client is only rebuilt (dropping its connection pool) when the
+    /// timeouts, the TLS settings or the client parameters changed.
+    pub fn prepare_reconfigure(
+        &self,
+        proxy: &ExternalProxy,
+        params: &ClientParams,
+    ) -> Result<Reconfigure, VclError> {
+        let current = self.client.load();
+        let client = if current.timeouts != UpstreamTimeouts::from_proxy(proxy, params)
+            || current.tls != UpstreamTls::from_proxy(proxy)
+            || current.decompress != proxy.decompress_response
+            || current.protocol != proxy.protocol
+            || current.params != *params
+        {
+            Some(Arc::new(UpstreamClient::new(proxy, params)?))
+        } else {
+            None
+        };
+        Ok(Reconfigure {
+            decompress_request_body: proxy.decompress_request_body,
+            compress_request: proxy.compress_request.clone().map(Arc::new),
+            coalesce: Arc::new(HeaderCoalescing::from_proxy(proxy)),
+            client,
+        })
+    }
+
+    /// Apply settings from `prepare_reconfigure()`.
+    pub fn apply_reconfigure(&self, update: Reconfigure) {
+        self.decompress_request_body
+            .store(update.decompress_request_body, Ordering::Relaxed);
+        self.compress_request.store(update.compress_request);
+        self.coalesce.store(update.coalesce);
+        if let Some(client) = update.client {
+            self.client.store(client);
+        }
+    }
+}
+
+/// Reloaded settings of an external backend, applied once the reload has
+/// fully succeeded.
+#[derive(Clone)]
+pub struct Reconfigure {
+    decompress_request_body: bool,
+    compress_request: Option<Arc<CompressRequest>>,
+    coalesce: Arc<HeaderCoalescing>,
+    /// Replacement client, if the settings it was built with changed
+    client: Option<Arc<UpstreamClient>>,
+}
+
+impl std::fmt::Debug for Reconfigure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Reconfigure")
+            .field("decompress_request_body", &self.decompress_request_body)
+            .field("compress_request", &self.compress_request)
+            .field("coalesce", &self.coalesce)
+            .field("new_client", &self.client.is_some())
+            .finish()
+    }
+}
+
+/// Multi-value headers an upstream wants on a single line, lowercased.
+#[derive(Debug, Default)]
+struct HeaderCoalescing {
+    request: Vec<String>,
+    response: Vec<String>,
+}
+
+impl HeaderCoalescing {
+    fn from_proxy(proxy: &ExternalProxy) -> Self {
+        let lower = |names: &[String]| names.iter().map(|n| n.to_ascii_lowercase()).collect();
+        Self {
+            request: lower(&proxy.coalesce_request_headers),
+            response: lower(&proxy.coalesce_response_headers),
+        }
+    }
+}
+
+/// Connect, response header and whole-request deadlines of an upstream's
+/// client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct UpstreamTimeouts {
+    connect: Duration,
+    request: Duration,
+    /// Applied around each request rather than built into the client.
+    headers: Option<Duration>,
+    /// Worker-side wait on the upstream task, see `recv_within`.
+    recv: Option<Duration>,
+    /// Shorten `connect` after repeated connect timeouts.
+    adaptive_connect: bool,
+}
+
+impl UpstreamTimeouts {
+    fn from_proxy(proxy: &ExternalProxy, params: &ClientParams) -> Self {
+        Self {
+            connect: proxy
+                .connect_timeout_ms
+                .map_or(params.connect_timeout, Duration::from_millis),
+            request: proxy
+                .request_timeout_ms
+                .map_or(params.request_timeout, Duration::from_millis),
+            headers: proxy.header_timeout_ms.map(Duration::from_millis),
+            recv: proxy.recv_timeout_ms.map(Duration::from_millis),
+            adaptive_connect: proxy.adaptive_connect_timeout,
+        }
+    }
+}
+
+/// TLS options of an upstream's client.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct UpstreamTls {
+    /// Server name presented instead of the upstream hostname.
+    sni: Option<String>,
+    insecure_skip_verify: bool,
+}
+
+impl UpstreamTls {
+    fn from_proxy(proxy: &ExternalProxy) -> Self {
+        Self {
+            sni: proxy.sni.clone(),
+            insecure_skip_verify: proxy.insecure_skip_verify,
+        }
+    }
+}
+
+/// Resolves every name to one upstream host. reqwest takes the TLS server
+/// name from the URL, so an SNI override puts that name in the URL and
+/// relies on this to still connect to the configured hostname.
+struct PinnedResolver {
+    host: String,
+}
+
+impl Resolve for PinnedResolver {
+    fn resolve(&self, _name: Name) -> Resolving {
+        let host = self.host.clone();
+        Box::pin(async move {
+            // Port 0 is replaced by the URL's port when connecting.
+            let addrs: Vec<SocketAddr> =
+                tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// A reqwest client together with the settings it was built with.
+struct UpstreamClient {
+    client: Client,
+    /// Scheme, authority and port prefixed to each request path.
+    base_url: String,
+    timeouts: UpstreamTimeouts,
+    tls: UpstreamTls,
+    /// Inflate gzip responses (`decompress_response`).
+    decompress: bool,
+    protocol: UpstreamProtocol,
+    params: ClientParams,
+    /// Effective connect deadline when `adaptive_connect` is set.
+    adaptive: Option<Arc<AdaptiveConnectTimeout>>,
+}
+
+impl UpstreamClient {
+    fn new(proxy: &ExternalProxy, params: &ClientParams) -> Result<Self, VclError> {
+        let timeouts = UpstreamTimeouts::from_proxy(proxy, params);
+        let tls = UpstreamTls::from_proxy(proxy);
+        // Auto-decompression is off unless the upstream asks for it, so
+        // proxied bytes pass through unmodified and Varnish can cache the
+        // wire representation.
+        let mut builder = reqwest::ClientBuilder::new()
+            .gzip(proxy.decompress_response)
+            .timeout(timeouts.request)
+            .connect_timeout(timeouts.connect)
+            .pool_idle_timeout(params.pool_idle_timeout)
+            .tcp_keepalive(params.tcp_keepalive)
+            .tcp_nodelay(params.tcp_nodelay)
+            // Surface 30x to the cache layer instead of following.
+            .redirect(reqwest::redirect::Policy::none());
+        if let Some(max) = params.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        // Without h2c, stay on HTTP/1.1 even where TLS could negotiate h2
+        builder = match proxy.protocol {
+            UpstreamProtocol::Http1 => builder.http1_only(),
+            UpstreamProtocol::H2c => builder.http2_prior_knowledge(),
+        };
+        let adaptive = timeouts
+            .adaptive_connect
+            .then(|| Arc::new(AdaptiveConnectTimeout::new(timeouts.connect)));
+        if let Some(ref adaptive) = adaptive {
+            builder = builder.connector_layer(AdaptiveConnectLayer::new(adaptive.clone()));
+        }
+        if tls.insecure_skip_verify {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        let authority = match tls.sni {
+            Some(ref sni) => {
+                builder = builder.dns_resolver(Arc::new(PinnedResolver {
+                    host: proxy.hostname.clone(),
+                }));
+                sni
+            }
+            None => &proxy.hostname,
+        };
+        let scheme = if proxy.tls { "https" } else { "http" };
+        let base_url = format!("{}://{}:{}", scheme, authority, proxy.port);
+        let client = builder.build().map_err(|e| {
+            VclError::new(format!(
+                "external_proxy: failed to build reqwest client: {}",
+                e
+            ))
+        })?;
+        Ok(Self {
             client,
+            base_url,
+            timeouts,
+            tls,
+            decompress: proxy.decompress_response,
+            protocol: proxy.protocol,
+            params: *params,
+            adaptive,
         })
     }
 }
 
 impl VclBackend<ExternalBody> for ExternalBackend {
     fn get_response(&self, ctx: &mut Ctx<'_>) -> Result<Option<ExternalBody>, VclError> {
-        // Pull the method first so the 405 fast-path skips the header copy.
+        // vcl_backend_fetch hid them; none are forwarded upstream
+        internal_headers::restore(ctx);
         // Each block drops its bereq borrow before we touch ctx mutably.
         let method_str = {
             let bereq = ctx
@@ -168,66 +687,294 @@ impl VclBackend<ExternalBody> for ExternalBackend {
 
         let method = reqwest::Method::from_bytes(method_str.as_bytes())
             .map_err(|e| VclError::new(format!("external_proxy: invalid method: {}", e)))?;
+        // A HEAD response's Content-Length describes the body a GET would get
+        let is_head = method == reqwest::Method::HEAD;
 
-        // Bodies on bereq aren't streamed into reqwest yet. Rather than
-        // forwarding a body-bearing method with the body silently dropped —
-        // which can succeed at a forgiving upstream and look like a normal
-        // 2xx to the client — reject it locally with 405 + Allow.
-        if method_implies_body(&method) {
-            ctx.log(
-                varnish::vcl::LogTag::Error,
-                format!(
-                    "external_proxy: rejecting {} with 405; request bodies are not forwarded",
-                    method
-                ),
-            );
-            let beresp = ctx
-                .http_beresp
-                .as_mut()
-                .ok_or_else(|| VclError::new("external_proxy: missing beresp".to_string()))?;
-            beresp.set_status(405);
-            beresp.set_proto("HTTP/1.1")?;
-            beresp.set_header("Allow", ALLOWED_METHODS)?;
-            beresp.set_header("Content-Type", "text/plain; charset=utf-8")?;
-            beresp.set_header("Cache-Control", "no-store")?;
-            return Ok(Some(ExternalBody::from_static(METHOD_NOT_ALLOWED_BODY)));
-        }
-
-        let (path, headers_owned) = {
+        let (
+            path,
+            headers_owned,
+            timeout,
+            body_check,
+            qos,
+            tenant,
+            content_encoding,
+            forward_host,
+            route_limit,
+            content_length,
+            wants_trailers,
+        ) = {
             let bereq = ctx
                 .http_bereq
                 .as_ref()
                 .ok_or_else(|| VclError::new("external_proxy: missing bereq".to_string()))?;
             let p = sob_to_str(bereq.url())?.to_string();
-            let headers: Vec<(String, Vec<u8>)> = bereq
-                .into_iter()
-                .filter(|(k, _)| forward_client_header(k))
-                .map(|(k, v)| (k.to_string(), v.as_ref().to_vec()))
-                .collect();
-            (p, headers)
+            let all: Vec<_> = bereq.into_iter().collect();
+            let all: Vec<(&str, &[u8])> = all.iter().map(|(k, v)| (*k, v.as_ref())).collect();
+            let headers = coalesce_headers(request_headers(&all), &self.coalesce.load().request);
+            let timeout = bereq
+                .header(BACKEND_TIMEOUT_HEADER)
+                .and_then(|v| sob_to_str(Some(v)).ok().and_then(parse_timeout));
+            let body_check = bereq
+                .header(RETRY_STATE_HEADER)
+                .and_then(|v| RetryState::from_header(sob_to_str(Some(v)).ok()?))
+                .and_then(|state| state.body);
+            let qos = bereq
+                .header(QOS_HEADER)
+                .and_then(|v| sob_to_str(Some(v)).ok().and_then(QosClass::from_name))
+                .unwrap_or_default();
+            let tenant = bereq
+                .header(TENANT_HEADER)
+                .and_then(|v| sob_to_str(Some(v)).ok().map(str::to_string));
+            let content_encoding = bereq
+                .header("Content-Encoding")
+                .and_then(|v| sob_to_str(Some(v)).ok().map(str::to_string));
+            let forward_host = bereq
+                .header(FORWARD_HOST_HEADER)
+                .and_then(|v| sob_to_str(Some(v)).ok().map(str::to_string));
+            let route_limit = bereq
+                .header(MAX_BODY_HEADER)
+                .and_then(|v| sob_to_str(Some(v)).ok()?.parse().ok());
+            let content_length = bereq
+                .header("Content-Length")
+                .and_then(|v| sob_to_str(Some(v)).ok().map(str::to_string));
+            let wants_trailers = bereq
+                .header("TE")
+                .and_then(|v| sob_to_str(Some(v)).ok())
+                .is_some_and(accepts_trailers);
+            (
+                p,
+                headers,
+                timeout,
+                body_check,
+                qos,
+                tenant,
+                content_encoding,
+                forward_host,
+                route_limit,
+                content_length,
+                wants_trailers,
+            )
+        };
+
+        let has_body = ctx.req_body_state().is_ok_and(|s| s != ReqBodyState::None);
+        let decompress = self.decompress_request_body.load(Ordering::Relaxed)
+            && content_encoding.as_deref().is_some_and(is_gzip);
+        let compress_settings = self.compress_request.load_full();
+        // Gzip in either direction and the retry body check need the whole
+        // body; anything else is streamed (see request_body.rs)
+        let whole_body = decompress
+            || (compress_settings.is_some() && content_encoding.is_none())
+            || body_check.is_some();
+        let max_body = if whole_body {
+            Some(body_limit(route_limit))
+        } else {
+            route_limit.map(|l| usize::try_from(l).unwrap_or(usize::MAX))
+        };
+
+        // An announced length over the limit is refused before reading
+        if max_body.is_some_and(|limit| exceeds_limit(content_length.as_deref(), limit)) {
+            return reject(ctx, 413, BODY_TOO_LARGE_BODY);
+        }
+
+        // Reading an uncached body consumes it, so a retry needs
+        // std.cache_req_body().
+        let mut body = None;
+        if has_body && whole_body {
+            let mut buf = CappedBuffer::new(body_limit(route_limit));
+            if let Err(e) = ctx.req_body(&mut buf) {
+                if buf.overflowed() {
+                    return reject(ctx, 413, BODY_TOO_LARGE_BODY);
+                }
+                return Err(VclError::new(format!(
+                    "external_proxy: request body: {}",
+                    e
+                )));
+            }
+            body = Some(buf.into_inner());
+        }
+        if let (true, Some(compressed)) = (decompress, body.as_ref()) {
+            match gunzip(compressed) {
+                Ok(inflated) => body = Some(inflated),
+                Err(e) => {
+                    ctx.log(
+                        varnish::vcl::LogTag::Error,
+                        format!("external_proxy: rejecting gzip request body: {:?}", e),
+                    );
+                    return match e {
+                        BodyError::TooLarge => reject(ctx, 413, BODY_TOO_LARGE_BODY),
+                        BodyError::Malformed => reject(ctx, 400, BODY_MALFORMED_BODY),
+                    };
+                }
+            }
+        }
+        // A body the client already encoded is sent as it is
+        let gzipped = match (compress_settings.as_deref(), body.as_ref()) {
+            (Some(settings), Some(plain)) if content_encoding.is_none() => {
+                compress(plain, settings)
+            }
+            _ => None,
         };
+        let compressed = gzipped.is_some();
+        if gzipped.is_some() {
+            body = gzipped;
+        }
+
+        let signer = self.signer.load_full();
+        let signature = signer
+            .as_ref()
+            .map(|s| (s.header(), s.sign(method.as_str(), &path, unix_time())));
 
-        let url = format!("{}{}", self.base_url, path);
-        let mut req_builder = self.client.request(method, &url);
+        let upstream = self.client.load_full();
+        let client = upstream.client.clone();
+        let url = format!("{}{}", upstream.base_url, path);
+        let mut req_builder = client.request(method, &url);
         // Host is set explicitly to the externalName so object stores route to
         // the right bucket.
         for (k, v) in headers_owned {
+            // A client-supplied signature header must not reach the upstream
+            // alongside (or instead of) ours.
+            if signature
+                .as_ref()
+                .is_some_and(|(h, _)| h.eq_ignore_ascii_case(&k))
+            {
+                continue;
+            }
+            // reqwest sets Content-Length from a buffered body; a streamed
+            // one keeps the client's
+            if (whole_body && k.eq_ignore_ascii_case("content-length"))
+                || (decompress && k.eq_ignore_ascii_case("content-encoding"))
+            {
+                continue;
+            }
             if let Ok(name) = HeaderName::try_from(k.as_str()) {
                 req_builder = req_builder.header(name, v);
             }
         }
-        req_builder = req_builder.header("host", &self.upstream_host);
+        if compressed {
+            req_builder = req_builder.header("content-encoding", "gzip");
+        }
+        // TE is hop-by-hop, but gRPC servers expect `te: trailers`, the one
+        // value HTTP/2 allows
+        if wants_trailers && upstream.protocol == UpstreamProtocol::H2c {
+            req_builder = req_builder.header("te", "trailers");
+        }
+        // The route's forward_host, if any, replaces the upstream hostname
+        req_builder = req_builder.header(
+            "host",
+            forward_host.as_deref().unwrap_or(&self.upstream_host),
+        );
+        if let Some((header, value)) = signature {
+            req_builder = req_builder.header(header, value);
+        }
+        // Route timeout replaces the upstream's timeout for this request.
+        if let Some(timeout) = timeout {
+            req_builder = req_builder.timeout(timeout);
+        }
+        let mut streamed = None;
+        if let Some(body) = body {
+            req_builder = req_builder.body(body);
+        } else if has_body && !whole_body {
+            let (sender, body) = BodySender::channel(max_body);
+            req_builder = req_builder.body(body);
+            streamed = Some(sender);
+        }
 
         let request = req_builder
             .build()
             .map_err(|e| VclError::new(format!("external_proxy: build request: {}", e)))?;
 
+        // Fail fast rather than queue behind saturated streams or upstream.
+        let Some(stream) = STREAMS.try_acquire(qos) else {
+            ctx.log(
+                varnish::vcl::LogTag::Error,
+                format!(
+                    "external_proxy: {} responses streaming, rejecting {} priority request with 503",
+                    active_streams(),
+                    qos.as_str()
+                ),
+            );
+            return shed(ctx, STREAMS_FULL_BODY);
+        };
+        let queue_timeout = match QUEUE_TIMEOUT_MS.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        };
+        let Some(slot) = PENDING.acquire(
+            qos,
+            tenant.as_deref().unwrap_or_default(),
+            queue_timeout,
+            MAX_QUEUED.load(Ordering::Relaxed),
+        ) else {
+            ctx.log(
+                varnish::vcl::LogTag::Error,
+                format!(
+                    "external_proxy: {} requests pending, {} queued, rejecting {} priority request with 503",
+                    pending_requests(),
+                    queued_requests(),
+                    qos.as_str()
+                ),
+            );
+            return shed(ctx, QUEUE_FULL_BODY);
+        };
+
+        let recv_timeout = upstream
+            .timeouts
+            .recv
+            .unwrap_or_else(|| timeout.unwrap_or(upstream.timeouts.request) + RECV_TIMEOUT_GRACE);
+        let guard = InFlightGuard::new(&self.in_flight);
         let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
-        bgt().rt.spawn(process_request(self.client.clone(), request, tx));
+        let sent = Instant::now();
+        bgt().rt.spawn(process_request(
+            client,
+            request,
+            upstream.timeouts.headers,
+            tx,
+        ));
+
+        // The request is under way; feed it the body as Varnish reads it. An
+        // upstream that stopped taking it has answered or failed, which the
+        // response tells.
+        if let Some(mut sender) = streamed {
+            if let Err(e) = ctx.req_body(&mut sender) {
+                if sender.overflowed() {
+                    sender.abort("request body too large");
+                    return reject(ctx, 413, BODY_TOO_LARGE_BODY);
+                }
+                if !sender.upstream_gone() {
+                    sender.abort("request body read failed");
+                    return Err(VclError::new(format!(
+                        "external_proxy: request body: {}",
+                        e
+                    )));
+                }
+            }
+        }
 
-        let headers_frame = match rx.blocking_recv() {
+        let received = recv_within(&mut rx, recv_timeout);
+        drop(slot);
+        let headers_frame = match received {
             Some(RespMsg::Headers(f)) => f,
-            Some(RespMsg::Err(e)) => return Err(VclError::new(e)),
+            Some(RespMsg::Err(e)) => {
+                self.outcomes.failure();
+                return Err(VclError::new(e));
+            }
+            Some(RespMsg::Failed(class, e)) => {
+                self.outcomes.failure();
+                let mut msg = format!("{} [{}, {}]", e, class.as_str(), class.status());
+                if let Some(ref adaptive) = upstream.adaptive {
+                    msg.push_str(&format!(
+                        " (next connect timeout {}ms)",
+                        adaptive.current().as_millis()
+                    ));
+                }
+                ctx.log(varnish::vcl::LogTag::Error, msg);
+                let beresp = ctx
+                    .http_beresp
+                    .as_mut()
+                    .ok_or_else(|| VclError::new("external_proxy: missing beresp".to_string()))?;
+                let body = synth_response(beresp, class)?;
+                return Ok(Some(ExternalBody::from_static(body)));
+            }
             // process_request always emits Headers exactly once before any
             // Chunk and never returns None before sending something.
             Some(RespMsg::Chunk(_)) | None => {
@@ -237,33 +984,180 @@ impl VclBackend<ExternalBody> for ExternalBackend {
             }
         };
 
+        self.latency.first_byte.record(sent.elapsed());
+        if headers_frame.status >= 500 {
+            self.outcomes.failure();
+        } else {
+            self.outcomes.success();
+        }
+
+        // Hold back the start of the body so the retry hook can look at it
+        // before Varnish commits to the response.
+        let has_body = status_has_body(headers_frame.status);
+        let prefix = match &body_check {
+            Some(cond) if has_body => {
+                Some(read_prefix(&mut rx, cond.max_inspect_bytes, recv_timeout)?)
+            }
+            _ => None,
+        };
+        let body_matched = body_check
+            .as_ref()
+            .zip(prefix.as_ref())
+            .is_some_and(|(cond, prefix)| cond.matches(prefix));
+
         let beresp = ctx
             .http_beresp
             .as_mut()
             .ok_or_else(|| VclError::new("external_proxy: missing beresp".to_string()))?;
         beresp.set_status(headers_frame.status);
         beresp.set_proto("HTTP/1.1")?;
-        for (k, v) in headers_frame.headers.iter() {
-            if is_hop_by_hop(k.as_str()) {
+        let stale_length = !is_head && stale_content_length(&headers_frame);
+        let copied = response_headers(&headers_frame.headers)
+            .map(|(k, v)| (k, v.as_bytes().to_vec()))
+            .collect();
+        for (k, v) in coalesce_headers(copied, &self.coalesce.load().response) {
+            // Joined from UTF-8 values, so this can't fail
+            let Ok(v) = std::str::from_utf8(&v) else {
+                continue;
+            };
+            // A 304 may carry the length of the full representation; passed
+            // through, it would promise a body that never comes. After a
+            // transform the body is streamed chunked instead.
+            if (!has_body || stale_length) && k.eq_ignore_ascii_case("content-length") {
                 continue;
             }
-            if let Ok(s) = v.to_str() {
-                beresp.set_header(k.as_str(), s)?;
+            beresp.set_header(k, v)?;
+        }
+        if body_matched {
+            beresp.set_header(BODY_MATCH_HEADER, "1")?;
+        }
+
+        // No body to stream: hand Varnish a bodyless response right away
+        // instead of waiting on the channel. Dropping the receiver and
+        // guards ends the upstream task and frees the in-flight slot.
+        if !has_body {
+            self.latency.last_byte.record(sent.elapsed());
+            return Ok(None);
+        }
+
+        Ok(Some(
+            ExternalBody::streamed(
+                rx,
+                prefix,
+                headers_frame.content_length.map(|c| c as usize),
+                recv_timeout,
+                guard,
+                stream,
+            )
+            .timed(Arc::clone(&self.latency), sent),
+        ))
+    }
+}
+
+/// Buffer at least `limit` bytes of the body, or all of it if shorter,
+/// before streaming starts. Whole chunks are kept, so the result may run
+/// past `limit`.
+fn read_prefix(
+    rx: &mut Receiver<RespMsg>,
+    limit: usize,
+    recv_timeout: Duration,
+) -> Result<Bytes, VclError> {
+    let mut buf = BytesMut::new();
+    while buf.len() < limit {
+        match recv_within(rx, recv_timeout) {
+            Some(RespMsg::Chunk(bytes)) => buf.extend_from_slice(&bytes),
+            Some(RespMsg::Err(e)) | Some(RespMsg::Failed(_, e)) => return Err(VclError::new(e)),
+            None => break,
+            Some(RespMsg::Headers(_)) => {
+                return Err(VclError::new(
+                    "external_proxy: response stream invariant violated".to_string(),
+                ))
             }
         }
+    }
+    Ok(buf.freeze())
+}
 
-        Ok(Some(ExternalBody::streamed(
-            rx,
-            headers_frame.content_length.map(|c| c as usize),
-        )))
+/// Wakes a worker parked in `recv_within`.
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// `blocking_recv` with a deadline: the next message from the upstream
+/// task, or a timeout `Failed` if none comes within `limit`, so a task that
+/// panicked or hung can't hold a Varnish worker forever. The wait parks the
+/// thread rather than relying on tokio timers, which a wedged runtime would
+/// never fire.
+fn recv_within(rx: &mut Receiver<RespMsg>, limit: Duration) -> Option<RespMsg> {
+    let deadline = Instant::now() + limit;
+    let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(msg) = rx.poll_recv(&mut cx) {
+            return msg;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Some(RespMsg::Failed(
+                ErrorClass::Timeout,
+                format!(
+                    "external proxy: upstream task silent for {}ms",
+                    limit.as_millis()
+                ),
+            ));
+        }
+        std::thread::park_timeout(deadline - now);
     }
 }
 
+/// Answer locally with `status` instead of contacting the upstream.
+fn reject(
+    ctx: &mut Ctx<'_>,
+    status: u16,
+    body: &'static [u8],
+) -> Result<Option<ExternalBody>, VclError> {
+    let beresp = ctx
+        .http_beresp
+        .as_mut()
+        .ok_or_else(|| VclError::new("external_proxy: missing beresp".to_string()))?;
+    beresp.set_status(status);
+    beresp.set_proto("HTTP/1.1")?;
+    beresp.set_header("Content-Type", "text/plain; charset=utf-8")?;
+    beresp.set_header("Cache-Control", "no-store")?;
+    Ok(Some(ExternalBody::from_static(body)))
+}
+
+/// Answer locally with a 503 + Retry-After when a limit is reached.
+fn shed(ctx: &mut Ctx<'_>, body: &'static [u8]) -> Result<Option<ExternalBody>, VclError> {
+    let beresp = ctx
+        .http_beresp
+        .as_mut()
+        .ok_or_else(|| VclError::new("external_proxy: missing beresp".to_string()))?;
+    beresp.set_status(503);
+    beresp.set_proto("HTTP/1.1")?;
+    beresp.set_header("Retry-After", QUEUE_FULL_RETRY_AFTER)?;
+    beresp.set_header("Content-Type", "text/plain; charset=utf-8")?;
+    beresp.set_header("Cache-Control", "no-store")?;
+    Ok(Some(ExternalBody::from_static(body)))
+}
+
 /// Response body for a synthetic backend. Either streams chunks from the
 /// tokio runtime (upstream success path) or serves a fixed static buffer
 /// (locally generated responses like the 405 rejection).
 pub struct ExternalBody {
     state: BodyState,
+    /// Keeps the backend's in-flight count raised until the body is dropped.
+    _in_flight: Option<InFlightGuard>,
+    /// Holds a `STREAMS` slot until the body is fully read or dropped.
+    stream_slot: Option<LimiterSlot<'static>>,
+    /// Where to record the last-byte latency of a request sent at the
+    /// given instant, once the body completes. Bodies cut short are not
+    /// recorded.
+    timing: Option<(Arc<LatencyStats>, Instant)>,
 }
 
 enum BodyState {
@@ -272,6 +1166,8 @@ enum BodyState {
         current: Option<Bytes>,
         cursor: usize,
         content_length: Option<usize>,
+        /// Longest wait for each chunk.
+        recv_timeout: Duration,
     },
     Static {
         data: &'static [u8],
@@ -280,20 +1176,39 @@ enum BodyState {
 }
 
 impl ExternalBody {
-    fn streamed(chan: Receiver<RespMsg>, content_length: Option<usize>) -> Self {
+    fn streamed(
+        chan: Receiver<RespMsg>,
+        prefix: Option<Bytes>,
+        content_length: Option<usize>,
+        recv_timeout: Duration,
+        in_flight: InFlightGuard,
+        stream: LimiterSlot<'static>,
+    ) -> Self {
         Self {
             state: BodyState::Streamed {
                 chan,
-                current: None,
+                current: prefix.filter(|p| !p.is_empty()),
                 cursor: 0,
                 content_length,
+                recv_timeout,
             },
+            _in_flight: Some(in_flight),
+            stream_slot: Some(stream),
+            timing: None,
         }
     }
 
+    fn timed(mut self, latency: Arc<LatencyStats>, sent: Instant) -> Self {
+        self.timing = Some((latency, sent));
+        self
+    }
+
     fn from_static(data: &'static [u8]) -> Self {
         Self {
             state: BodyState::Static { data, cursor: 0 },
+            _in_flight: None,
+            stream_slot: None,
+            timing: None,
         }
     }
 }
@@ -305,18 +1220,29 @@ impl VclResponse for ExternalBody {
                 chan,
                 current,
                 cursor,
+                recv_timeout,
                 ..
             } => {
                 let mut total = 0;
                 loop {
                     if current.is_none() {
-                        match chan.blocking_recv() {
+                        match recv_within(chan, *recv_timeout) {
                             Some(RespMsg::Chunk(bytes)) => {
                                 *current = Some(bytes);
                                 *cursor = 0;
                             }
-                            Some(RespMsg::Err(e)) => return Err(VclError::new(e)),
-                            None => return Ok(total),
+                            Some(RespMsg::Err(e)) | Some(RespMsg::Failed(_, e)) => {
+                                return Err(VclError::new(e))
+                            }
+                            None => {
+                                // Upstream finished: free the slot without
+                                // waiting for Varnish to drop the body.
+                                self.stream_slot = None;
+                                if let Some((latency, sent)) = self.timing.take() {
+                                    latency.last_byte.record(sent.elapsed());
+                                }
+                                return Ok(total);
+                            }
                             // process_request only emits Headers once, before chunks.
                             Some(RespMsg::Headers(_)) => {
                                 return Err(VclError::new(
@@ -360,14 +1286,33 @@ impl VclResponse for ExternalBody {
     }
 }
 
-fn method_implies_body(method: &reqwest::Method) -> bool {
-    matches!(
-        *method,
-        reqwest::Method::POST
-            | reqwest::Method::PUT
-            | reqwest::Method::PATCH
-            | reqwest::Method::DELETE
-    )
+/// Whether the upstream's `Content-Length` no longer describes the body
+/// streamed to Varnish, e.g. because the client inflated it. The length of
+/// the body actually read is what reqwest reports; a header that disagrees
+/// (or is left without one) must not reach beresp.
+fn stale_content_length(frame: &HeadersFrame) -> bool {
+    let header = frame
+        .headers
+        .get(reqwest::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    header.is_some() && header != frame.content_length
+}
+
+/// Whether a `TE` request header value lists `trailers`
+fn accepts_trailers(te: &str) -> bool {
+    te.split(',').any(|t| {
+        t.split(';')
+            .next()
+            .unwrap_or("")
+            .trim()
+            .eq_ignore_ascii_case("trailers")
+    })
+}
+
+/// Whether a response with this status can carry a body (RFC 9110 §6.4.1).
+fn status_has_body(status: u16) -> bool {
+    !matches!(status, 100..=199 | 204 | 304)
 }
 
 fn sob_to_str<'a>(value: Option<StrOrBytes<'a>>) -> Result<&'a str, VclError> {
@@ -393,10 +1338,59 @@ const HOP_BY_HOP: &[&str] = &[
     "upgrade",
 ];
 
-fn is_hop_by_hop(name: &str) -> bool {
+pub(crate) fn is_hop_by_hop(name: &str) -> bool {
     HOP_BY_HOP.iter().any(|h| name.eq_ignore_ascii_case(h))
 }
 
+/// Header names listed in `Connection` values, lowercased. RFC 7230 §6.1
+/// makes each of them hop-by-hop for the message too.
+pub(crate) fn connection_tokens<'a>(values: impl IntoIterator<Item = &'a [u8]>) -> Vec<String> {
+    values
+        .into_iter()
+        .filter_map(|v| std::str::from_utf8(v).ok())
+        .flat_map(|v| v.split(','))
+        .map(|t| t.trim_matches([' ', '\t']).to_ascii_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// Whether `name` is one of the `connection_tokens` of its message
+pub(crate) fn is_connection_option(name: &str, tokens: &[String]) -> bool {
+    tokens.iter().any(|t| name.eq_ignore_ascii_case(t))
+}
+
+/// Upstream response headers to copy onto beresp, one entry per instance.
+///
+/// `HeaderMap` keeps repeated headers (several `Set-Cookie`, `Vary`, `Link`)
+/// as separate values and `iter()` yields each of them; `set_header` appends,
+/// so every instance reaches the client. Values that aren't UTF-8 can't be
+/// handed to Varnish as `&str` and are skipped. `HeaderValue::to_str` is
+/// deliberately not used: it also rejects UTF-8 outside visible ASCII, which
+/// would silently drop cookies carrying such bytes.
+/// Headers the upstream named in `Connection` are dropped with the rest of
+/// the hop-by-hop ones.
+fn response_headers(headers: &reqwest::header::HeaderMap) -> impl Iterator<Item = (&str, &str)> {
+    let options = connection_tokens(
+        headers
+            .get_all(reqwest::header::CONNECTION)
+            .iter()
+            .map(|v| v.as_bytes()),
+    );
+    headers
+        .iter()
+        .filter(move |(k, _)| {
+            !is_hop_by_hop(k.as_str()) && !is_connection_option(k.as_str(), &options)
+        })
+        .filter_map(|(k, v)| Some((k.as_str(), std::str::from_utf8(v.as_bytes()).ok()?)))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
 /// Whether a client (bereq) header should be copied verbatim onto the
 /// upstream reqwest request.
 ///
@@ -406,17 +1400,84 @@ fn is_hop_by_hop(name: &str) -> bool {
 /// `.header()` appends rather than replaces, so two conflicting `Host:` lines
 /// would go on the wire (client value first). Strict upstreams reject that
 /// (nginx 400; S3/GCS SigV4 signature mismatch). The upstream `Host` is set
-/// exactly once, from `self.upstream_host`.
+/// exactly once, from the route's `forward_host` or `self.upstream_host`.
+/// Ghost's internal `X-Ghost-*` headers stay here as well.
+/// `Range` and `If-Range` are forwarded, so byte-range requests reach the
+/// upstream and its `206` (with `Content-Range` and the partial length)
+/// streams back unchanged.
 fn forward_client_header(name: &str) -> bool {
-    !is_hop_by_hop(name) && !name.eq_ignore_ascii_case("host")
+    !is_hop_by_hop(name) && !name.eq_ignore_ascii_case("host") && !is_internal_header(name)
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Client (bereq) headers to copy onto the upstream request: the ones
+/// `forward_client_header` lets through, less those the client named in
+/// `Connection`.
+fn request_headers(headers: &[(&str, &[u8])]) -> Vec<(String, Vec<u8>)> {
+    let options = connection_tokens(
+        headers
+            .iter()
+            .filter(|(k, _)| k.eq_ignore_ascii_case("connection"))
+            .map(|(_, v)| *v),
+    );
+    headers
+        .iter()
+        .filter(|(k, _)| forward_client_header(k) && !is_connection_option(k, &options))
+        .map(|(k, v)| (k.to_string(), v.to_vec()))
+        .collect()
+}
 
-    #[test]
-    fn is_hop_by_hop_basic() {
+/// Join the instances of each header in `names` (lowercase) into one
+/// comma-separated value, RFC 9110 §5.3, at the position of the first
+/// instance. Empty instances add nothing to the list and are dropped.
+fn coalesce_headers<K: AsRef<str>>(
+    headers: Vec<(K, Vec<u8>)>,
+    names: &[String],
+) -> Vec<(K, Vec<u8>)> {
+    if names.is_empty() {
+        return headers;
+    }
+    let mut out: Vec<(K, Vec<u8>)> = Vec::with_capacity(headers.len());
+    // Index in `out` of the line each coalesced header is joined onto
+    let mut lines: Vec<(usize, usize)> = Vec::new();
+    for (k, v) in headers {
+        let Some(n) = names
+            .iter()
+            .position(|n| k.as_ref().eq_ignore_ascii_case(n))
+        else {
+            out.push((k, v));
+            continue;
+        };
+        match lines.iter().find(|(name, _)| *name == n) {
+            Some(_) if v.is_empty() => {}
+            Some(&(_, i)) => {
+                let joined = &mut out[i].1;
+                if !joined.is_empty() {
+                    joined.extend_from_slice(b", ");
+                }
+                joined.extend_from_slice(&v);
+            }
+            None => {
+                lines.push((n, out.len()));
+                out.push((k, v));
+            }
+        }
+    }
+    out
+}
+
+/// Parse the route timeout header value (`<N>ms`, as set by the router).
+pub(crate) fn parse_timeout(value: &str) -> Option<Duration> {
+    let ms: u64 = value.trim().strip_suffix("ms")?.parse().ok()?;
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream;
+
+    #[test]
+    fn is_hop_by_hop_basic() {
         assert!(is_hop_by_hop("Connection"));
         assert!(is_hop_by_hop("keep-alive"));
         assert!(is_hop_by_hop("Transfer-Encoding"));
@@ -424,6 +1485,135 @@ mod tests {
         assert!(!is_hop_by_hop("Host"));
     }
 
+    #[test]
+    fn connection_tokens_parsing() {
+        assert_eq!(
+            connection_tokens([b"close, X-Secret".as_slice(), b"Keep-Alive"]),
+            vec!["close", "x-secret", "keep-alive"]
+        );
+        // Odd whitespace and empty list elements
+        assert_eq!(
+            connection_tokens([b"  x-a ,,\tX-B\t, ,".as_slice(), b""]),
+            vec!["x-a", "x-b"]
+        );
+        assert!(connection_tokens([b"\xff".as_slice()]).is_empty());
+
+        let tokens = connection_tokens([b"x-secret".as_slice()]);
+        assert!(is_connection_option("X-Secret", &tokens));
+        assert!(!is_connection_option("X-Secret-2", &tokens));
+    }
+
+    #[test]
+    fn request_headers_drop_connection_options() {
+        let headers: Vec<(&str, &[u8])> = vec![
+            ("Host", b"app.example.com"),
+            ("Connection", b"X-Secret-Internal , upgrade"),
+            ("Connection", b"x-other"),
+            ("X-Secret-Internal", b"1"),
+            ("x-other", b"2"),
+            ("Upgrade", b"websocket"),
+            ("Accept", b"*/*"),
+            ("X-Kept", b"3"),
+        ];
+        let names: Vec<_> = request_headers(&headers)
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(names, vec!["Accept", "X-Kept"]);
+
+        // Without a Connection header only the static list applies
+        let headers: Vec<(&str, &[u8])> = vec![("X-Secret-Internal", b"1"), ("TE", b"trailers")];
+        let names: Vec<_> = request_headers(&headers)
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(names, vec!["X-Secret-Internal"]);
+    }
+
+    #[test]
+    fn coalesce_headers_joins_configured_names() {
+        let names = vec!["accept-encoding".to_string(), "forwarded".to_string()];
+        let headers: Vec<(&str, Vec<u8>)> = vec![
+            ("Accept-Encoding", b"gzip".to_vec()),
+            ("Accept", b"*/*".to_vec()),
+            ("accept-encoding", b"br".to_vec()),
+            ("X-Other", b"a".to_vec()),
+            ("X-Other", b"b".to_vec()),
+        ];
+        assert_eq!(
+            coalesce_headers(headers, &names),
+            vec![
+                ("Accept-Encoding", b"gzip, br".to_vec()),
+                ("Accept", b"*/*".to_vec()),
+                ("X-Other", b"a".to_vec()),
+                ("X-Other", b"b".to_vec()),
+            ]
+        );
+
+        // Empty instances are dropped, wherever they are
+        let headers: Vec<(&str, Vec<u8>)> = vec![
+            ("Forwarded", Vec::new()),
+            ("Forwarded", b"for=192.0.2.1".to_vec()),
+            ("Forwarded", Vec::new()),
+            ("Forwarded", b"for=192.0.2.2".to_vec()),
+        ];
+        assert_eq!(
+            coalesce_headers(headers, &names),
+            vec![("Forwarded", b"for=192.0.2.1, for=192.0.2.2".to_vec())]
+        );
+
+        // Nothing configured: untouched
+        let headers: Vec<(&str, Vec<u8>)> = vec![("Vary", b"a".to_vec()), ("Vary", b"b".to_vec())];
+        assert_eq!(coalesce_headers(headers.clone(), &[]), headers);
+    }
+
+    #[test]
+    fn response_headers_drop_connection_options() {
+        use reqwest::header::{HeaderMap, HeaderValue};
+
+        let mut headers = HeaderMap::new();
+        headers.append(
+            "connection",
+            HeaderValue::from_static("X-Backend-Hint,\tkeep-alive"),
+        );
+        headers.append("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.append("x-backend-hint", HeaderValue::from_static("a"));
+        headers.append("x-backend-hint", HeaderValue::from_static("b"));
+        headers.append("cache-control", HeaderValue::from_static("max-age=60"));
+
+        let copied: Vec<_> = response_headers(&headers).collect();
+        assert_eq!(copied, vec![("cache-control", "max-age=60")]);
+    }
+
+    #[test]
+    fn response_headers_keeps_every_instance() {
+        use reqwest::header::{HeaderMap, HeaderValue};
+
+        let mut headers = HeaderMap::new();
+        headers.append("set-cookie", HeaderValue::from_static("a=1; Path=/"));
+        headers.append("set-cookie", HeaderValue::from_static("b=2; Path=/"));
+        headers.append("vary", HeaderValue::from_static("Accept-Encoding"));
+        headers.append("vary", HeaderValue::from_static("Origin"));
+        headers.append("connection", HeaderValue::from_static("close"));
+        headers.append(
+            "x-name",
+            HeaderValue::from_bytes("caf\u{e9}".as_bytes()).unwrap(),
+        );
+        headers.append("x-binary", HeaderValue::from_bytes(b"\xff").unwrap());
+
+        let copied: Vec<_> = response_headers(&headers).collect();
+        assert_eq!(
+            copied,
+            vec![
+                ("set-cookie", "a=1; Path=/"),
+                ("set-cookie", "b=2; Path=/"),
+                ("vary", "Accept-Encoding"),
+                ("vary", "Origin"),
+                ("x-name", "caf\u{e9}"),
+            ]
+        );
+    }
+
     #[test]
     fn forward_client_header_drops_host_and_hop_by_hop() {
         // Host must be dropped so it isn't duplicated with the explicit
@@ -441,17 +1631,465 @@ mod tests {
         assert!(forward_client_header("User-Agent"));
         assert!(forward_client_header("Authorization"));
         assert!(forward_client_header("X-Amz-Date"));
+
+        // Ghost's internal headers never go upstream.
+        assert!(!forward_client_header("X-Ghost-Retry"));
+        assert!(!forward_client_header("x-ghost-filter-context"));
+        assert!(!forward_client_header("X-Ghost-Redirect-Config"));
+        assert!(forward_client_header("X-Ghostly"));
+    }
+
+    #[test]
+    fn parse_timeout_reads_router_format() {
+        assert_eq!(parse_timeout("100ms"), Some(Duration::from_millis(100)));
+        assert_eq!(parse_timeout(" 2500ms "), Some(Duration::from_millis(2500)));
+        assert_eq!(parse_timeout("0ms"), None);
+        assert_eq!(parse_timeout("100"), None);
+        assert_eq!(parse_timeout("1s"), None);
+        assert!(!forward_client_header("X-Ghost-Backend-Timeout"));
+    }
+
+    #[test]
+    fn route_timeout_fires_against_slow_backend() {
+        let upstream = mock_upstream::silent();
+
+        let client = reqwest::ClientBuilder::new().build().unwrap();
+        let request = client
+            .get(upstream.url("/slow"))
+            .timeout(parse_timeout("100ms").unwrap())
+            .build()
+            .unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        let started = std::time::Instant::now();
+        bgt().rt.spawn(process_request(client, request, None, tx));
+
+        // get_response() answers a Failed frame with synth_response(); the
+        // 504 it then sends is checked end to end in test_route_timeout.vtc
+        match rx.blocking_recv() {
+            Some(RespMsg::Failed(ErrorClass::Timeout, _)) => {
+                assert_eq!(ErrorClass::Timeout.status(), 504)
+            }
+            Some(RespMsg::Failed(_, e)) | Some(RespMsg::Err(e)) => {
+                panic!("expected timeout, got error: {}", e)
+            }
+            Some(RespMsg::Headers(_)) | Some(RespMsg::Chunk(_)) => {
+                panic!("expected timeout, got a response")
+            }
+            None => panic!("expected timeout, channel closed"),
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        upstream.join();
+    }
+
+    #[test]
+    fn header_timeout_fires_against_stalled_headers() {
+        let upstream = mock_upstream::silent();
+
+        let client = reqwest::ClientBuilder::new().build().unwrap();
+        let request = client.get(upstream.url("/stalled")).build().unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        let started = std::time::Instant::now();
+        let header_timeout = Some(Duration::from_millis(100));
+        bgt()
+            .rt
+            .spawn(process_request(client, request, header_timeout, tx));
+
+        match rx.blocking_recv() {
+            Some(RespMsg::Failed(class, e)) => {
+                assert_eq!(class, ErrorClass::Timeout, "{}", e);
+                assert_eq!(class.status(), 504);
+                assert!(e.contains("no response headers within 100ms"), "{}", e);
+            }
+            Some(RespMsg::Err(e)) => panic!("expected timeout, got error: {}", e),
+            Some(RespMsg::Headers(_)) | Some(RespMsg::Chunk(_)) => {
+                panic!("expected timeout, got a response")
+            }
+            None => panic!("expected timeout, channel closed"),
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        upstream.join();
+    }
+
+    #[test]
+    fn header_timeout_leaves_slow_body_alone() {
+        use std::io::Write;
+
+        // Headers right away, the body after longer than the header timeout
+        let upstream = mock_upstream::once(|stream, _| {
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n");
+            let _ = stream.flush();
+            std::thread::sleep(Duration::from_millis(300));
+            let _ = stream.write_all(b"slow");
+        });
+
+        let client = reqwest::ClientBuilder::new().build().unwrap();
+        let request = client.get(upstream.url("/slow")).build().unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        let header_timeout = Some(Duration::from_millis(100));
+        bgt()
+            .rt
+            .spawn(process_request(client, request, header_timeout, tx));
+
+        match rx.blocking_recv() {
+            Some(RespMsg::Headers(frame)) => assert_eq!(frame.status, 200),
+            _ => panic!("expected headers"),
+        }
+        let mut body = Vec::new();
+        while let Some(msg) = rx.blocking_recv() {
+            match msg {
+                RespMsg::Chunk(bytes) => body.extend_from_slice(&bytes),
+                _ => panic!("expected body chunks"),
+            }
+        }
+        assert_eq!(body, b"slow");
+        upstream.join();
     }
 
     #[test]
-    fn method_implies_body_matches_body_carrying_verbs() {
-        assert!(method_implies_body(&reqwest::Method::POST));
-        assert!(method_implies_body(&reqwest::Method::PUT));
-        assert!(method_implies_body(&reqwest::Method::PATCH));
-        assert!(method_implies_body(&reqwest::Method::DELETE));
-        assert!(!method_implies_body(&reqwest::Method::GET));
-        assert!(!method_implies_body(&reqwest::Method::HEAD));
-        assert!(!method_implies_body(&reqwest::Method::OPTIONS));
+    fn status_has_body_excludes_bodyless_statuses() {
+        for status in [100, 101, 204, 304] {
+            assert!(!status_has_body(status), "{}", status);
+        }
+        for status in [200, 206, 301, 404, 500] {
+            assert!(status_has_body(status), "{}", status);
+        }
+    }
+
+    #[test]
+    fn not_modified_completes_without_body() {
+        use std::io::Write;
+
+        // Answers 304 with the representation's length, then keeps the
+        // connection open: a reader expecting a body would hang.
+        let upstream = mock_upstream::once(|stream, _| {
+            let _ = stream.write_all(
+                b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nContent-Length: 1234\r\n\r\n",
+            );
+            std::thread::sleep(Duration::from_secs(1));
+        });
+
+        let client = reqwest::ClientBuilder::new().build().unwrap();
+        let request = client
+            .get(upstream.url("/cached"))
+            .header("If-None-Match", "\"v1\"")
+            .build()
+            .unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        let started = std::time::Instant::now();
+        bgt().rt.spawn(process_request(client, request, None, tx));
+
+        match rx.blocking_recv() {
+            Some(RespMsg::Headers(frame)) => {
+                assert_eq!(frame.status, 304);
+                assert!(!status_has_body(frame.status));
+            }
+            _ => panic!("expected 304 headers"),
+        }
+        assert!(rx.blocking_recv().is_none(), "304 must not stream a body");
+        assert!(started.elapsed() < Duration::from_secs(1));
+        upstream.join();
+    }
+
+    #[test]
+    fn partial_content_streams_the_requested_range() {
+        use std::io::Write;
+
+        // Range and If-Range are end-to-end: the upstream decides whether
+        // to answer 206, and its Content-Range must reach the client.
+        assert!(forward_client_header("Range"));
+        assert!(forward_client_header("If-Range"));
+
+        let upstream = mock_upstream::once(|stream, head| {
+            let request = head.to_lowercase();
+            assert!(request.contains("range: bytes=6-10\r\n"), "{}", request);
+            assert!(request.contains("if-range: \"v1\"\r\n"), "{}", request);
+            let _ = stream.write_all(
+                b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 6-10/11\r\n\
+                  Content-Length: 5\r\nETag: \"v1\"\r\n\r\nworld",
+            );
+        });
+
+        let client = reqwest::ClientBuilder::new().build().unwrap();
+        let request = client
+            .get(upstream.url("/media/hello.txt"))
+            .header("Range", "bytes=6-10")
+            .header("If-Range", "\"v1\"")
+            .build()
+            .unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        bgt().rt.spawn(process_request(client, request, None, tx));
+
+        let frame = match rx.blocking_recv() {
+            Some(RespMsg::Headers(frame)) => frame,
+            _ => panic!("expected 206 headers"),
+        };
+        assert_eq!(frame.status, 206);
+        assert!(status_has_body(frame.status));
+        // The length handed to Varnish is the partial body's
+        assert_eq!(frame.content_length, Some(5));
+        let copied: Vec<_> = response_headers(&frame.headers).collect();
+        assert!(copied.contains(&("content-range", "bytes 6-10/11")));
+
+        static LIMIT: ConcurrencyLimiter = ConcurrencyLimiter::new(1);
+        let counter = Arc::new(AtomicU64::new(0));
+        let slot = LIMIT.try_acquire(QosClass::Normal).unwrap();
+        let mut body = ExternalBody::streamed(
+            rx,
+            None,
+            Some(5),
+            DEFAULT_REQUEST_TIMEOUT,
+            InFlightGuard::new(&counter),
+            slot,
+        );
+        assert_eq!(body.len(), Some(5));
+        let mut buf = [0u8; 32];
+        let mut out = Vec::new();
+        loop {
+            let n = <ExternalBody as VclResponse>::read(&mut body, &mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(out, b"world");
+        upstream.join();
+    }
+
+    #[test]
+    fn streamed_body_reaches_upstream_past_the_buffer_cap() {
+        use std::io::{Read, Write};
+
+        let len = crate::request_body::MAX_REQUEST_BODY_BYTES + 1024 * 1024;
+        let upstream = mock_upstream::once(move |stream, head| {
+            let request = head.to_lowercase();
+            assert!(
+                request.contains(&format!("content-length: {}\r\n", len)),
+                "{}",
+                request
+            );
+            let start = head.find("\r\n\r\n").unwrap() + 4;
+            let mut received = head.len() - start;
+            let mut buf = vec![0u8; 64 * 1024];
+            while received < len {
+                match stream.read(&mut buf) {
+                    Ok(n) if n > 0 => received += n,
+                    _ => break,
+                }
+            }
+            let _ = stream.write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n");
+            received
+        });
+
+        let (mut sender, body) = BodySender::channel(None);
+        let client = reqwest::ClientBuilder::new().build().unwrap();
+        let request = client
+            .post(upstream.url("/upload"))
+            .header("Content-Length", len)
+            .body(body)
+            .build()
+            .unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        bgt().rt.spawn(process_request(client, request, None, tx));
+
+        let chunk = vec![b'x'; 64 * 1024];
+        for _ in 0..len / chunk.len() {
+            sender.write_all(&chunk).unwrap();
+        }
+        drop(sender);
+
+        match rx.blocking_recv() {
+            Some(RespMsg::Headers(frame)) => assert_eq!(frame.status, 201),
+            _ => panic!("expected 201 headers"),
+        }
+        assert_eq!(upstream.join(), len);
+    }
+
+    #[test]
+    fn decompressed_response_drops_stale_content_length() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(b"hello hello hello hello").unwrap();
+        let compressed = gz.finish().unwrap();
+
+        let server = mock_upstream::once(move |stream, _| {
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+                compressed.len()
+            );
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(&compressed);
+        });
+
+        let proxy = ExternalProxy {
+            hostname: server.addr().ip().to_string(),
+            port: server.addr().port(),
+            tls: false,
+            signing: None,
+            connect_timeout_ms: None,
+            request_timeout_ms: None,
+            header_timeout_ms: None,
+            recv_timeout_ms: None,
+            adaptive_connect_timeout: false,
+            sni: None,
+            insecure_skip_verify: false,
+            decompress_request_body: false,
+            compress_request: None,
+            protocol: UpstreamProtocol::Http1,
+            decompress_response: true,
+            coalesce_request_headers: Vec::new(),
+            coalesce_response_headers: Vec::new(),
+        };
+        let upstream = UpstreamClient::new(&proxy, &ClientParams::default()).unwrap();
+        let request = upstream
+            .client
+            .get(format!("{}/page", upstream.base_url))
+            .header("Accept-Encoding", "gzip")
+            .build()
+            .unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        bgt()
+            .rt
+            .spawn(process_request(upstream.client.clone(), request, None, tx));
+
+        let frame = match rx.blocking_recv() {
+            Some(RespMsg::Headers(frame)) => frame,
+            _ => panic!("expected headers"),
+        };
+        // Neither the encoding nor the compressed length reach beresp
+        assert_eq!(frame.content_length, None);
+        assert!(!stale_content_length(&frame));
+        let copied: Vec<_> = response_headers(&frame.headers).collect();
+        assert!(
+            copied
+                .iter()
+                .all(|(k, _)| *k != "content-encoding" && *k != "content-length"),
+            "{:?}",
+            copied
+        );
+        let mut body = Vec::new();
+        while let Some(RespMsg::Chunk(bytes)) = rx.blocking_recv() {
+            body.extend_from_slice(&bytes);
+        }
+        assert_eq!(body, b"hello hello hello hello");
+        server.join();
+    }
+
+    #[test]
+    fn compressed_response_passes_through_by_default() {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let mut gz = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(b"hello hello hello hello").unwrap();
+        let compressed = gz.finish().unwrap();
+        let sent = compressed.clone();
+
+        let server = mock_upstream::once(move |stream, request| {
+            let head = format!(
+                "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+                sent.len()
+            );
+            let _ = stream.write_all(head.as_bytes());
+            let _ = stream.write_all(&sent);
+            request.to_ascii_lowercase()
+        });
+
+        let proxy = ExternalProxy {
+            hostname: server.addr().ip().to_string(),
+            port: server.addr().port(),
+            tls: false,
+            signing: None,
+            connect_timeout_ms: None,
+            request_timeout_ms: None,
+            header_timeout_ms: None,
+            recv_timeout_ms: None,
+            adaptive_connect_timeout: false,
+            sni: None,
+            insecure_skip_verify: false,
+            decompress_request_body: false,
+            compress_request: None,
+            protocol: UpstreamProtocol::Http1,
+            decompress_response: false,
+            coalesce_request_headers: Vec::new(),
+            coalesce_response_headers: Vec::new(),
+        };
+        let upstream = UpstreamClient::new(&proxy, &ClientParams::default()).unwrap();
+        let request = upstream
+            .client
+            .get(format!("{}/page", upstream.base_url))
+            .header("Accept-Encoding", "br, gzip;q=0.5")
+            .build()
+            .unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        bgt()
+            .rt
+            .spawn(process_request(upstream.client.clone(), request, None, tx));
+
+        let frame = match rx.blocking_recv() {
+            Some(RespMsg::Headers(frame)) => frame,
+            _ => panic!("expected headers"),
+        };
+        assert_eq!(frame.content_length, Some(compressed.len() as u64));
+        assert!(!stale_content_length(&frame));
+        let copied: Vec<_> = response_headers(&frame.headers).collect();
+        assert!(
+            copied.contains(&("content-encoding", "gzip")),
+            "{:?}",
+            copied
+        );
+        let length = compressed.len().to_string();
+        assert!(
+            copied.contains(&("content-length", length.as_str())),
+            "{:?}",
+            copied
+        );
+        let mut body = Vec::new();
+        while let Some(RespMsg::Chunk(bytes)) = rx.blocking_recv() {
+            body.extend_from_slice(&bytes);
+        }
+        assert_eq!(body, compressed);
+
+        // The client's Accept-Encoding, not one of reqwest's own
+        let request = server.join();
+        assert!(
+            request.contains("accept-encoding: br, gzip;q=0.5\r\n"),
+            "{}",
+            request
+        );
+        assert_eq!(request.matches("accept-encoding").count(), 1);
+    }
+
+    #[test]
+    fn stale_content_length_compares_header_with_body() {
+        use reqwest::header::{HeaderMap, HeaderValue};
+
+        let frame = |length: Option<&'static str>, content_length: Option<u64>| {
+            let mut headers = HeaderMap::new();
+            if let Some(length) = length {
+                headers.insert("content-length", HeaderValue::from_static(length));
+            }
+            HeadersFrame {
+                status: 200,
+                headers,
+                content_length,
+            }
+        };
+        assert!(!stale_content_length(&frame(Some("42"), Some(42))));
+        assert!(!stale_content_length(&frame(None, None)));
+        assert!(!stale_content_length(&frame(None, Some(42))));
+        // The body was transformed after the upstream measured it
+        assert!(stale_content_length(&frame(Some("42"), None)));
+        assert!(stale_content_length(&frame(Some("42"), Some(120))));
     }
 
     #[test]
@@ -473,29 +2111,825 @@ mod tests {
         assert_eq!(n, 0);
     }
 
+    #[test]
+    fn pending_limiter_rejects_when_saturated() {
+        let limiter = ConcurrencyLimiter::new(2);
+
+        // Simulate two requests stuck on a slow upstream.
+        let a = limiter.try_acquire(QosClass::Normal).expect("first slot");
+        let b = limiter.try_acquire(QosClass::Normal).expect("second slot");
+        assert!(limiter.try_acquire(QosClass::Normal).is_none());
+        assert_eq!(limiter.active.load(Ordering::Relaxed), 2);
+
+        // Headers arrive for one of them; a new request fits again.
+        drop(a);
+        let c = limiter.try_acquire(QosClass::Normal).expect("slot freed");
+        assert!(limiter.try_acquire(QosClass::Normal).is_none());
+
+        drop(b);
+        drop(c);
+        assert_eq!(limiter.active.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn limiter_sheds_low_priority_first() {
+        let limiter = ConcurrencyLimiter::new(10);
+
+        // Half full: low priority is refused, the others still get in
+        let half: Vec<_> = (0..5)
+            .map(|_| limiter.try_acquire(QosClass::Low).expect("low slot"))
+            .collect();
+        assert!(limiter.try_acquire(QosClass::Low).is_none());
+        let normal: Vec<_> = (0..4)
+            .map(|_| limiter.try_acquire(QosClass::Normal).expect("normal slot"))
+            .collect();
+
+        // The last tenth is kept for high priority
+        assert!(limiter.try_acquire(QosClass::Normal).is_none());
+        let high = limiter.try_acquire(QosClass::High).expect("high slot");
+        assert!(limiter.try_acquire(QosClass::High).is_none());
+
+        drop(high);
+        drop(normal);
+        drop(half);
+        assert_eq!(limiter.active.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn admission_limit_per_class() {
+        assert_eq!(admission_limit(QosClass::High, 1024), 1024);
+        assert_eq!(admission_limit(QosClass::Normal, 1024), 922);
+        assert_eq!(admission_limit(QosClass::Low, 1024), 512);
+        // Tiny limits still admit high and normal traffic
+        assert_eq!(admission_limit(QosClass::Normal, 1), 1);
+        assert_eq!(admission_limit(QosClass::Low, 1), 0);
+    }
+
+    #[test]
+    fn pending_limiter_concurrent_acquire_respects_max() {
+        use std::sync::Barrier;
+
+        let limiter = Arc::new(ConcurrencyLimiter::new(4));
+        let barrier = Arc::new(Barrier::new(16));
+        let handles: Vec<_> = (0..16)
+            .map(|_| {
+                let limiter = Arc::clone(&limiter);
+                let barrier = Arc::clone(&barrier);
+                std::thread::spawn(move || {
+                    let slot = limiter.try_acquire(QosClass::Normal);
+                    let acquired = slot.is_some();
+                    // Hold slots until every thread has tried.
+                    barrier.wait();
+                    acquired
+                })
+            })
+            .collect();
+        let acquired = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|ok| *ok)
+            .count();
+        assert_eq!(acquired, 4);
+        assert_eq!(limiter.active.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn pending_limiter_queues_fairly_across_vhosts() {
+        let limiter = Arc::new(ConcurrencyLimiter::new(1));
+        let held = limiter
+            .acquire(QosClass::Normal, "busy", None, 0)
+            .expect("free slot");
+        // Without a queue timeout, a full limiter sheds at once
+        assert!(limiter
+            .acquire(QosClass::Normal, "busy", None, 16)
+            .is_none());
+
+        let served = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let mut threads = Vec::new();
+        for (tenant, count) in [("busy", 6), ("quiet", 2)] {
+            for _ in 0..count {
+                let (limiter, served) = (Arc::clone(&limiter), Arc::clone(&served));
+                threads.push(std::thread::spawn(move || {
+                    let timeout = Some(Duration::from_secs(10));
+                    let slot = limiter.acquire(QosClass::Normal, tenant, timeout, 16);
+                    assert!(slot.is_some());
+                    served.lock().push(tenant);
+                }));
+            }
+            // Queue the busy vhost's requests first
+            let queued = if tenant == "busy" { 6 } else { 8 };
+            while limiter.queue.len() != queued {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        // Each served request frees its slot for the next
+        drop(held);
+        for t in threads {
+            t.join().unwrap();
+        }
+        let served = served.lock();
+        assert_eq!(served.len(), 8);
+        assert_eq!(&served[..4], ["busy", "quiet", "busy", "quiet"]);
+        assert_eq!(limiter.active.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn stream_limit_sheds_until_body_completes() {
+        static LIMIT: ConcurrencyLimiter = ConcurrencyLimiter::new(1);
+        let counter = Arc::new(AtomicU64::new(0));
+
+        // One slow stream holds the only slot...
+        let (tx, rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        let slot = LIMIT.try_acquire(QosClass::Normal).expect("first stream");
+        let mut body = ExternalBody::streamed(
+            rx,
+            None,
+            None,
+            DEFAULT_REQUEST_TIMEOUT,
+            InFlightGuard::new(&counter),
+            slot,
+        );
+
+        // ...so further streams are shed.
+        assert!(LIMIT.try_acquire(QosClass::Normal).is_none());
+
+        // Reading to the end releases it even before the body is dropped.
+        tx.blocking_send(RespMsg::Chunk(Bytes::from_static(b"data")))
+            .unwrap();
+        drop(tx);
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            <ExternalBody as VclResponse>::read(&mut body, &mut buf).unwrap(),
+            4
+        );
+        assert_eq!(
+            <ExternalBody as VclResponse>::read(&mut body, &mut buf).unwrap(),
+            0
+        );
+        let next = LIMIT
+            .try_acquire(QosClass::Normal)
+            .expect("slot freed on completion");
+
+        // A client that goes away mid-stream frees its slot on drop.
+        let (_tx, rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        let body = ExternalBody::streamed(
+            rx,
+            None,
+            None,
+            DEFAULT_REQUEST_TIMEOUT,
+            InFlightGuard::new(&counter),
+            next,
+        );
+        assert!(LIMIT.try_acquire(QosClass::Normal).is_none());
+        drop(body);
+        assert!(LIMIT.try_acquire(QosClass::Normal).is_some());
+    }
+
+    #[test]
+    fn prefix_is_buffered_then_streamed_first() {
+        static LIMIT: ConcurrencyLimiter = ConcurrencyLimiter::new(1);
+        let counter = Arc::new(AtomicU64::new(0));
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        for chunk in [&b"{\"error\":"[..], b"\"try again\"}", b" and more"] {
+            tx.blocking_send(RespMsg::Chunk(Bytes::from_static(chunk)))
+                .unwrap();
+        }
+        drop(tx);
+
+        // Whole chunks until the limit is reached; the rest stays queued
+        let prefix = read_prefix(&mut rx, 12, DEFAULT_REQUEST_TIMEOUT).unwrap();
+        assert_eq!(&prefix[..], br#"{"error":"try again"}"#);
+
+        let slot = LIMIT.try_acquire(QosClass::Normal).unwrap();
+        let mut body = ExternalBody::streamed(
+            rx,
+            Some(prefix),
+            None,
+            DEFAULT_REQUEST_TIMEOUT,
+            InFlightGuard::new(&counter),
+            slot,
+        );
+        let mut out = Vec::new();
+        let mut buf = [0u8; 8];
+        loop {
+            let n = <ExternalBody as VclResponse>::read(&mut body, &mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            out.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(out, br#"{"error":"try again"} and more"#);
+    }
+
+    #[test]
+    fn silent_upstream_times_out_with_504() {
+        let upstream = mock_upstream::silent();
+
+        let client = reqwest::ClientBuilder::new()
+            .timeout(DEFAULT_REQUEST_TIMEOUT)
+            .build()
+            .unwrap();
+        let request = client.get(upstream.url("/slow")).build().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        bgt().rt.spawn(process_request(client, request, None, tx));
+
+        let start = Instant::now();
+        match recv_within(&mut rx, Duration::from_millis(200)) {
+            Some(RespMsg::Failed(class, msg)) => {
+                assert_eq!(class.status(), 504);
+                assert!(msg.contains("200ms"), "{}", msg);
+            }
+            _ => panic!("expected a timeout"),
+        }
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(200), "{:?}", waited);
+        assert!(waited < Duration::from_secs(2), "{:?}", waited);
+
+        // Still waiting on the client's own timeout, which drops the
+        // connection and lets the upstream go
+        drop(rx);
+    }
+
+    #[test]
+    fn h2c_upstream_round_trip() {
+        // A gRPC-style upstream speaking HTTP/2 without TLS: it answers with
+        // the request's body and ends the stream with trailers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let (seen_tx, seen_rx) = std::sync::mpsc::channel();
+        bgt().rt.spawn(async move {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut conn = h2::server::handshake(socket).await.unwrap();
+            let (request, mut respond) = conn.accept().await.unwrap().unwrap();
+            let (parts, mut body) = request.into_parts();
+            tokio::spawn(async move { while conn.accept().await.is_some() {} });
+            let mut echo = BytesMut::new();
+            while let Some(chunk) = body.data().await {
+                let chunk = chunk.unwrap();
+                let _ = body.flow_control().release_capacity(chunk.len());
+                echo.extend_from_slice(&chunk);
+            }
+            let _ = seen_tx.send((parts.version, parts.headers.get("te").cloned()));
+            let response = http::Response::builder()
+                .status(200)
+                .header("content-type", "application/grpc")
+                .body(())
+                .unwrap();
+            let mut send = respond.send_response(response, false).unwrap();
+            send.send_data(echo.freeze(), false).unwrap();
+            let mut trailers = http::HeaderMap::new();
+            trailers.insert("grpc-status", http::HeaderValue::from_static("0"));
+            send.send_trailers(trailers).unwrap();
+        });
+
+        let proxy = ExternalProxy {
+            hostname: "127.0.0.1".to_string(),
+            port: addr.port(),
+            tls: false,
+            signing: None,
+            connect_timeout_ms: None,
+            request_timeout_ms: None,
+            header_timeout_ms: None,
+            recv_timeout_ms: None,
+            adaptive_connect_timeout: false,
+            sni: None,
+            insecure_skip_verify: false,
+            decompress_request_body: false,
+            compress_request: None,
+            protocol: UpstreamProtocol::H2c,
+            decompress_response: false,
+            coalesce_request_headers: Vec::new(),
+            coalesce_response_headers: Vec::new(),
+        };
+        let upstream = UpstreamClient::new(&proxy, &ClientParams::default()).unwrap();
+        let request = upstream
+            .client
+            .post(format!("{}/echo.Echo/Say", upstream.base_url))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(&b"\0\0\0\0\x05hello"[..])
+            .build()
+            .unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        bgt()
+            .rt
+            .spawn(process_request(upstream.client.clone(), request, None, tx));
+
+        match recv_within(&mut rx, Duration::from_secs(5)) {
+            Some(RespMsg::Headers(frame)) => {
+                assert_eq!(frame.status, 200);
+                assert_eq!(frame.headers["content-type"], "application/grpc");
+            }
+            _ => panic!("expected response headers"),
+        }
+        let mut body = Vec::new();
+        loop {
+            match recv_within(&mut rx, Duration::from_secs(5)) {
+                Some(RespMsg::Chunk(bytes)) => body.extend_from_slice(&bytes),
+                None => break,
+                _ => panic!("expected the body to end cleanly"),
+            }
+        }
+        assert_eq!(body, b"\0\0\0\0\x05hello");
+        let (version, te) = seen_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(version, http::Version::HTTP_2);
+        assert_eq!(te.unwrap(), "trailers");
+    }
+
+    #[test]
+    fn test_accepts_trailers() {
+        assert!(accepts_trailers("trailers"));
+        assert!(accepts_trailers("gzip, Trailers"));
+        assert!(accepts_trailers("trailers;q=1"));
+        assert!(!accepts_trailers("gzip"));
+        assert!(!accepts_trailers("trailers-extra"));
+    }
+
+    #[test]
+    fn stalled_body_read_fails_instead_of_hanging() {
+        static LIMIT: ConcurrencyLimiter = ConcurrencyLimiter::new(1);
+        let counter = Arc::new(AtomicU64::new(0));
+        let (tx, rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        tx.blocking_send(RespMsg::Chunk(Bytes::from_static(b"part")))
+            .unwrap();
+        let slot = LIMIT.try_acquire(QosClass::Normal).unwrap();
+        let mut body = ExternalBody::streamed(
+            rx,
+            None,
+            None,
+            Duration::from_millis(50),
+            InFlightGuard::new(&counter),
+            slot,
+        );
+
+        let mut buf = [0u8; 4];
+        assert_eq!(
+            <ExternalBody as VclResponse>::read(&mut body, &mut buf).unwrap(),
+            4
+        );
+        // The sender is still alive but sends nothing more
+        assert!(<ExternalBody as VclResponse>::read(&mut body, &mut buf).is_err());
+        drop(tx);
+    }
+
+    #[test]
+    fn recv_within_wakes_on_send() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            tx.blocking_send(RespMsg::Chunk(Bytes::from_static(b"late")))
+                .unwrap();
+        });
+        match recv_within(&mut rx, Duration::from_secs(5)) {
+            Some(RespMsg::Chunk(bytes)) => assert_eq!(&bytes[..], b"late"),
+            _ => panic!("expected the chunk"),
+        }
+        sender.join().unwrap();
+        assert!(recv_within(&mut rx, Duration::from_secs(5)).is_none());
+    }
+
+    #[test]
+    fn prefix_of_short_body_is_whole_body() {
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        tx.blocking_send(RespMsg::Chunk(Bytes::from_static(b"ok")))
+            .unwrap();
+        drop(tx);
+        assert_eq!(
+            &read_prefix(&mut rx, 512, DEFAULT_REQUEST_TIMEOUT).unwrap()[..],
+            b"ok"
+        );
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<RespMsg>(CHUNK_CHANNEL_SIZE);
+        tx.blocking_send(RespMsg::Err("boom".to_string())).unwrap();
+        assert!(read_prefix(&mut rx, 512, DEFAULT_REQUEST_TIMEOUT).is_err());
+    }
+
+    #[test]
+    fn in_flight_guard_tracks_lifetime() {
+        let counter = Arc::new(AtomicU64::new(0));
+        let a = InFlightGuard::new(&counter);
+        let b = InFlightGuard::new(&counter);
+        assert_eq!(counter.load(Ordering::Relaxed), 2);
+        drop(a);
+        assert_eq!(counter.load(Ordering::Relaxed), 1);
+        drop(b);
+        assert_eq!(counter.load(Ordering::Relaxed), 0);
+    }
+
     #[test]
     fn external_backend_new_validates_inputs() {
+        let outcomes = || OutcomeRecorder::new(Arc::default(), Arc::default());
         let bad = ExternalProxy {
             hostname: String::new(),
             port: 443,
             tls: true,
+            signing: None,
+            connect_timeout_ms: None,
+            request_timeout_ms: None,
+            header_timeout_ms: None,
+            recv_timeout_ms: None,
+            adaptive_connect_timeout: false,
+            sni: None,
+            insecure_skip_verify: false,
+            decompress_request_body: false,
+            compress_request: None,
+            protocol: UpstreamProtocol::Http1,
+            decompress_response: false,
+            coalesce_request_headers: Vec::new(),
+            coalesce_response_headers: Vec::new(),
         };
-        assert!(ExternalBackend::new(&bad).is_err());
+        assert!(ExternalBackend::new(
+            &bad,
+            &ClientParams::default(),
+            Arc::default(),
+            outcomes(),
+            SignerSlot::default()
+        )
+        .is_err());
 
         let bad_port = ExternalProxy {
             hostname: "example.com".to_string(),
             port: 0,
             tls: false,
+            signing: None,
+            connect_timeout_ms: None,
+            request_timeout_ms: None,
+            header_timeout_ms: None,
+            recv_timeout_ms: None,
+            adaptive_connect_timeout: false,
+            sni: None,
+            insecure_skip_verify: false,
+            decompress_request_body: false,
+            compress_request: None,
+            protocol: UpstreamProtocol::Http1,
+            decompress_response: false,
+            coalesce_request_headers: Vec::new(),
+            coalesce_response_headers: Vec::new(),
         };
-        assert!(ExternalBackend::new(&bad_port).is_err());
+        assert!(ExternalBackend::new(
+            &bad_port,
+            &ClientParams::default(),
+            Arc::default(),
+            outcomes(),
+            SignerSlot::default()
+        )
+        .is_err());
 
         let good = ExternalProxy {
             hostname: "example.com".to_string(),
             port: 443,
             tls: true,
+            signing: None,
+            connect_timeout_ms: None,
+            request_timeout_ms: None,
+            header_timeout_ms: None,
+            recv_timeout_ms: None,
+            adaptive_connect_timeout: false,
+            sni: None,
+            insecure_skip_verify: false,
+            decompress_request_body: false,
+            compress_request: None,
+            protocol: UpstreamProtocol::Http1,
+            decompress_response: false,
+            coalesce_request_headers: Vec::new(),
+            coalesce_response_headers: Vec::new(),
         };
-        let be = ExternalBackend::new(&good).unwrap();
-        assert_eq!(be.base_url, "https://example.com:443");
+        let be = ExternalBackend::new(
+            &good,
+            &ClientParams::default(),
+            Arc::default(),
+            outcomes(),
+            SignerSlot::default(),
+        )
+        .unwrap();
+        assert_eq!(be.client.load().base_url, "https://example.com:443");
         assert_eq!(be.upstream_host, "example.com");
     }
+
+    #[test]
+    fn external_backend_timeouts_follow_config() {
+        let outcomes = OutcomeRecorder::new(Arc::default(), Arc::default());
+        let mut proxy = ExternalProxy {
+            hostname: "example.com".to_string(),
+            port: 443,
+            tls: true,
+            signing: None,
+            connect_timeout_ms: None,
+            request_timeout_ms: None,
+            header_timeout_ms: None,
+            recv_timeout_ms: None,
+            adaptive_connect_timeout: false,
+            sni: None,
+            insecure_skip_verify: false,
+            decompress_request_body: false,
+            compress_request: None,
+            protocol: UpstreamProtocol::Http1,
+            decompress_response: false,
+            coalesce_request_headers: Vec::new(),
+            coalesce_response_headers: Vec::new(),
+        };
+        let be = ExternalBackend::new(
+            &proxy,
+            &ClientParams::default(),
+            Arc::default(),
+            outcomes,
+            SignerSlot::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            be.client.load().timeouts,
+            UpstreamTimeouts {
+                connect: DEFAULT_CONNECT_TIMEOUT,
+                request: DEFAULT_REQUEST_TIMEOUT,
+                headers: None,
+                recv: None,
+                adaptive_connect: false,
+            }
+        );
+        assert!(be.client.load().adaptive.is_none());
+
+        // Unchanged settings keep the client and its connection pool
+        let before = be.client.load_full();
+        be.apply_reconfigure(
+            be.prepare_reconfigure(&proxy, &ClientParams::default())
+                .unwrap(),
+        );
+        assert!(Arc::ptr_eq(&before, &be.client.load_full()));
+
+        proxy.connect_timeout_ms = Some(250);
+        proxy.request_timeout_ms = Some(100);
+        proxy.recv_timeout_ms = Some(150);
+        be.apply_reconfigure(
+            be.prepare_reconfigure(&proxy, &ClientParams::default())
+                .unwrap(),
+        );
+        assert!(!Arc::ptr_eq(&before, &be.client.load_full()));
+        assert_eq!(
+            be.client.load().timeouts,
+            UpstreamTimeouts {
+                connect: Duration::from_millis(250),
+                request: Duration::from_millis(100),
+                headers: None,
+                recv: Some(Duration::from_millis(150)),
+                adaptive_connect: false,
+            }
+        );
+
+        proxy.adaptive_connect_timeout = true;
+        be.apply_reconfigure(
+            be.prepare_reconfigure(&proxy, &ClientParams::default())
+                .unwrap(),
+        );
+        let client = be.client.load();
+        assert!(client.timeouts.adaptive_connect);
+        let adaptive = client.adaptive.as_ref().unwrap();
+        assert_eq!(adaptive.current(), Duration::from_millis(250));
+    }
+
+    #[test]
+    fn client_params_set_defaults_and_rebuild_clients() {
+        let outcomes = OutcomeRecorder::new(Arc::default(), Arc::default());
+        let mut proxy = ExternalProxy {
+            hostname: "example.com".to_string(),
+            port: 443,
+            tls: true,
+            signing: None,
+            connect_timeout_ms: None,
+            request_timeout_ms: None,
+            header_timeout_ms: None,
+            recv_timeout_ms: None,
+            adaptive_connect_timeout: false,
+            sni: None,
+            insecure_skip_verify: false,
+            decompress_request_body: false,
+            compress_request: None,
+            protocol: UpstreamProtocol::Http1,
+            decompress_response: false,
+            coalesce_request_headers: Vec::new(),
+            coalesce_response_headers: Vec::new(),
+        };
+        let defaults = ClientParams::default();
+        assert_eq!(
+            defaults.describe(),
+            "pool_max_idle_per_host=unlimited pool_idle_timeout=90000ms \
+             connect_timeout=10000ms request_timeout=60000ms tcp_keepalive=off tcp_nodelay=true"
+        );
+        let be = ExternalBackend::new(
+            &proxy,
+            &defaults,
+            Arc::default(),
+            outcomes,
+            SignerSlot::default(),
+        )
+        .unwrap();
+
+        // Same parameters: the client and its pool are kept
+        let before = be.client.load_full();
+        be.apply_reconfigure(be.prepare_reconfigure(&proxy, &defaults).unwrap());
+        assert!(Arc::ptr_eq(&before, &be.client.load_full()));
+
+        let params = ClientParams::from_config(&ExternalClientConfig {
+            pool_max_idle_per_host: Some(4),
+            pool_idle_timeout_ms: Some(30_000),
+            connect_timeout_ms: Some(2_000),
+            request_timeout_ms: Some(300_000),
+            tcp_keepalive_ms: Some(15_000),
+            tcp_nodelay: false,
+            ..ExternalClientConfig::default()
+        });
+        assert_eq!(
+            params.describe(),
+            "pool_max_idle_per_host=4 pool_idle_timeout=30000ms connect_timeout=2000ms \
+             request_timeout=300000ms tcp_keepalive=15000ms tcp_nodelay=false"
+        );
+        be.apply_reconfigure(be.prepare_reconfigure(&proxy, &params).unwrap());
+        let client = be.client.load_full();
+        assert!(!Arc::ptr_eq(&before, &client));
+        assert_eq!(client.params, params);
+        // The global timeouts stand in for the proxy's unset ones
+        assert_eq!(client.timeouts.connect, Duration::from_millis(2_000));
+        assert_eq!(client.timeouts.request, Duration::from_millis(300_000));
+
+        // A proxy's own timeout still wins
+        proxy.connect_timeout_ms = Some(250);
+        be.apply_reconfigure(be.prepare_reconfigure(&proxy, &params).unwrap());
+        assert_eq!(
+            be.client.load().timeouts.connect,
+            Duration::from_millis(250)
+        );
+        assert_eq!(
+            be.client.load().timeouts.request,
+            Duration::from_millis(300_000)
+        );
+    }
+
+    /// HTTPS upstream with a self-signed certificate for `upstream.test`.
+    /// Serves "ok" to each connection and reports the SNI it was sent.
+    fn tls_upstream() -> (u16, std::sync::mpsc::Receiver<Option<String>>) {
+        use rustls::pki_types::pem::PemObject;
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+        use std::io::Read;
+
+        const PEM: &[u8] = include_bytes!("../tests/data/upstream-test.pem");
+        let certs = CertificateDer::pem_slice_iter(PEM)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_slice(PEM).unwrap();
+        let config = Arc::new(
+            rustls::ServerConfig::builder_with_provider(Arc::new(
+                rustls::crypto::ring::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .unwrap(),
+        );
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for tcp in listener.incoming() {
+                let Ok(mut tcp) = tcp else { break };
+                let mut conn = rustls::ServerConnection::new(config.clone()).unwrap();
+                while conn.is_handshaking() && conn.complete_io(&mut tcp).is_ok() {}
+                let _ = tx.send(conn.server_name().map(str::to_string));
+                if conn.is_handshaking() {
+                    continue;
+                }
+                let mut stream = rustls::Stream::new(&mut conn, &mut tcp);
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(n) if n > 0 => request.extend_from_slice(&buf[..n]),
+                        _ => break,
+                    }
+                }
+                let _ = stream.write_all(
+                    b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok",
+                );
+                conn.send_close_notify();
+                let _ = conn.complete_io(&mut tcp);
+            }
+        });
+        (port, rx)
+    }
+
+    fn fetch(proxy: &ExternalProxy) -> Result<String, reqwest::Error> {
+        let upstream = UpstreamClient::new(proxy, &ClientParams::default()).unwrap();
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            upstream
+                .client
+                .get(format!("{}/", upstream.base_url))
+                .send()
+                .await?
+                .text()
+                .await
+        })
+    }
+
+    #[test]
+    fn tls_upstream_requires_trusted_cert_unless_skipped() {
+        let (port, sni) = tls_upstream();
+        let mut proxy = ExternalProxy {
+            hostname: "127.0.0.1".to_string(),
+            port,
+            tls: true,
+            signing: None,
+            connect_timeout_ms: None,
+            request_timeout_ms: None,
+            header_timeout_ms: None,
+            recv_timeout_ms: None,
+            adaptive_connect_timeout: false,
+            sni: Some("upstream.test".to_string()),
+            insecure_skip_verify: false,
+            decompress_request_body: false,
+            compress_request: None,
+            protocol: UpstreamProtocol::Http1,
+            decompress_response: false,
+            coalesce_request_headers: Vec::new(),
+            coalesce_response_headers: Vec::new(),
+        };
+
+        // Connects to the hostname while presenting the SNI override, and
+        // rejects the self-signed certificate
+        assert_eq!(
+            UpstreamClient::new(&proxy, &ClientParams::default())
+                .unwrap()
+                .base_url,
+            format!("https://upstream.test:{}", port)
+        );
+        let err = fetch(&proxy).expect_err("self-signed certificate accepted");
+        assert!(err.is_connect(), "unexpected error: {:?}", err);
+        assert_eq!(sni.recv().unwrap().as_deref(), Some("upstream.test"));
+
+        proxy.insecure_skip_verify = true;
+        assert_eq!(fetch(&proxy).unwrap(), "ok");
+        assert_eq!(sni.recv().unwrap().as_deref(), Some("upstream.test"));
+
+        // Without an override an IP hostname sends no SNI at all
+        proxy.sni = None;
+        assert_eq!(fetch(&proxy).unwrap(), "ok");
+        assert_eq!(sni.recv().unwrap(), None);
+    }
+
+    #[test]
+    fn reconfigure_rebuilds_client_on_tls_change() {
+        let outcomes = OutcomeRecorder::new(Arc::default(), Arc::default());
+        let mut proxy = ExternalProxy {
+            hostname: "10.0.0.7".to_string(),
+            port: 8443,
+            tls: true,
+            signing: None,
+            connect_timeout_ms: None,
+            request_timeout_ms: None,
+            header_timeout_ms: None,
+            recv_timeout_ms: None,
+            adaptive_connect_timeout: false,
+            sni: None,
+            insecure_skip_verify: false,
+            decompress_request_body: false,
+            compress_request: None,
+            protocol: UpstreamProtocol::Http1,
+            decompress_response: false,
+            coalesce_request_headers: Vec::new(),
+            coalesce_response_headers: Vec::new(),
+        };
+        let be = ExternalBackend::new(
+            &proxy,
+            &ClientParams::default(),
+            Arc::default(),
+            outcomes,
+            SignerSlot::default(),
+        )
+        .unwrap();
+        let before = be.client.load_full();
+        assert_eq!(before.base_url, "https://10.0.0.7:8443");
+
+        proxy.sni = Some("api.internal".to_string());
+        be.apply_reconfigure(
+            be.prepare_reconfigure(&proxy, &ClientParams::default())
+                .unwrap(),
+        );
+        let after = be.client.load_full();
+        assert!(!Arc::ptr_eq(&before, &after));
+        assert_eq!(after.base_url, "https://api.internal:8443");
+        assert_eq!(be.upstream_host, "10.0.0.7");
+
+        proxy.insecure_skip_verify = true;
+        be.apply_reconfigure(
+            be.prepare_reconfigure(&proxy, &ClientParams::default())
+                .unwrap(),
+        );
+        assert!(be.client.load().tls.insecure_skip_verify);
+    }
 }
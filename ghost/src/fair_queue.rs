@@ -0,0 +1,263 @@
+//! Fair queuing for the external proxy concurrency limits.
+//!
+//! With `external_client.queue_timeout_ms` set, a request finding
+//! `max_pending_requests` full waits for a slot instead of getting a 503
+//! right away. Waiting requests are queued per vhost, and a freed slot goes
+//! to the vhosts in turn, oldest request first, so a vhost with many queued
+//! requests gets no more of the freed slots than one with a few.
+//!
+//! While anything is queued, new requests queue behind it rather than
+//! taking a freed slot first. QoS classes still apply: a waiter is only
+//! given a slot its class could have taken, and otherwise the next vhost's
+//! gets it.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::{Condvar, Mutex};
+
+use crate::config::QosClass;
+
+/// Requests waiting for a slot, per vhost
+pub struct FairQueue {
+    /// Vhosts with waiting requests, the next to be served first
+    tenants: Mutex<VecDeque<Tenant>>,
+    /// Waiting requests over all vhosts
+    queued: AtomicUsize,
+}
+
+struct Tenant {
+    name: String,
+    waiters: VecDeque<Arc<Waiter>>,
+}
+
+/// A queued request, woken when given a slot
+struct Waiter {
+    qos: QosClass,
+    granted: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl FairQueue {
+    pub const fn new() -> Self {
+        Self {
+            tenants: Mutex::new(VecDeque::new()),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Requests currently waiting
+    pub fn len(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Wait up to `timeout` for `reserve` to give a slot to this request of
+    /// vhost `tenant`. False if the queue already holds `max_queued`
+    /// requests or the time runs out.
+    pub fn wait(
+        &self,
+        tenant: &str,
+        qos: QosClass,
+        timeout: Duration,
+        max_queued: usize,
+        reserve: impl Fn(QosClass) -> bool,
+    ) -> bool {
+        let deadline = Instant::now() + timeout;
+        let waiter = {
+            let mut tenants = self.tenants.lock();
+            if self.queued.load(Ordering::SeqCst) >= max_queued {
+                return false;
+            }
+            // Counted before the retry, so a slot freed after it is seen
+            // by grant(), which needs this lock to hand it out
+            self.queued.fetch_add(1, Ordering::SeqCst);
+            if reserve(qos) {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                return true;
+            }
+            let waiter = Arc::new(Waiter {
+                qos,
+                granted: Mutex::new(false),
+                wake: Condvar::new(),
+            });
+            match tenants.iter_mut().find(|t| t.name == tenant) {
+                Some(t) => t.waiters.push_back(Arc::clone(&waiter)),
+                None => tenants.push_back(Tenant {
+                    name: tenant.to_string(),
+                    waiters: VecDeque::from([Arc::clone(&waiter)]),
+                }),
+            }
+            waiter
+        };
+
+        let mut granted = waiter.granted.lock();
+        while !*granted {
+            if waiter.wake.wait_until(&mut granted, deadline).timed_out() {
+                break;
+            }
+        }
+        if *granted {
+            return true;
+        }
+        drop(granted);
+
+        // Timed out, unless a slot came in the meantime
+        let mut tenants = self.tenants.lock();
+        if *waiter.granted.lock() {
+            return true;
+        }
+        if let Some(i) = tenants.iter().position(|t| t.name == tenant) {
+            tenants[i].waiters.retain(|w| !Arc::ptr_eq(w, &waiter));
+            if tenants[i].waiters.is_empty() {
+                tenants.remove(i);
+            }
+        }
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        false
+    }
+
+    /// Hand a freed slot to the oldest waiter of the next vhost whose class
+    /// `reserve` lets take one. That vhost moves to the back of the line.
+    pub fn grant(&self, reserve: impl Fn(QosClass) -> bool) {
+        if self.queued.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let mut tenants = self.tenants.lock();
+        let Some(i) = tenants
+            .iter()
+            .position(|t| t.waiters.front().is_some_and(|w| reserve(w.qos)))
+        else {
+            return;
+        };
+        let Some(mut tenant) = tenants.remove(i) else {
+            return;
+        };
+        if let Some(waiter) = tenant.waiters.pop_front() {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            *waiter.granted.lock() = true;
+            waiter.wake.notify_one();
+        }
+        if !tenant.waiters.is_empty() {
+            tenants.push_back(tenant);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    /// One slot, released by hand
+    struct OneSlot(AtomicBool);
+
+    impl OneSlot {
+        fn reserve(&self, _: QosClass) -> bool {
+            !self.0.swap(true, Ordering::SeqCst)
+        }
+
+        fn release(&self, queue: &FairQueue) {
+            self.0.store(false, Ordering::SeqCst);
+            queue.grant(|qos| self.reserve(qos));
+        }
+    }
+
+    fn wait_for_len(queue: &FairQueue, len: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while queue.len() != len {
+            assert!(Instant::now() < deadline, "queue never reached {}", len);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_vhosts_share_a_saturated_limit() {
+        let queue = Arc::new(FairQueue::new());
+        let slot = Arc::new(OneSlot(AtomicBool::new(true)));
+        let served = Arc::new(Mutex::new(Vec::new()));
+
+        // A busy vhost queues 8 requests before a quiet one queues 2
+        let mut threads = Vec::new();
+        for (tenant, count, queued) in [("busy", 8, 8), ("quiet", 2, 10)] {
+            for _ in 0..count {
+                let (queue, slot, served) = (queue.clone(), slot.clone(), served.clone());
+                threads.push(std::thread::spawn(move || {
+                    let ok = queue.wait(
+                        tenant,
+                        QosClass::Normal,
+                        Duration::from_secs(10),
+                        100,
+                        |q| slot.reserve(q),
+                    );
+                    assert!(ok);
+                    served.lock().push(tenant);
+                    slot.release(&queue);
+                }));
+            }
+            wait_for_len(&queue, queued);
+        }
+
+        slot.release(&queue);
+        for t in threads {
+            t.join().unwrap();
+        }
+        let served = served.lock();
+        assert_eq!(served.len(), 10);
+        // The quiet vhost is served every other slot, not after the busy one
+        assert_eq!(
+            &served[..4],
+            ["busy", "quiet", "busy", "quiet"],
+            "{:?}",
+            served
+        );
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_wait_times_out_and_bounds() {
+        let queue = FairQueue::new();
+        let full = |_| false;
+        let start = Instant::now();
+        assert!(!queue.wait("a", QosClass::Normal, Duration::from_millis(20), 10, full));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(queue.len(), 0);
+        assert!(queue.tenants.lock().is_empty());
+
+        // A free slot is taken without waiting, a full queue refuses
+        assert!(queue.wait("a", QosClass::Normal, Duration::from_secs(10), 10, |_| true));
+        let start = Instant::now();
+        assert!(!queue.wait("a", QosClass::Normal, Duration::from_secs(10), 0, |_| true));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_grant_skips_classes_without_room() {
+        let queue = Arc::new(FairQueue::new());
+        let low = {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                queue.wait("low", QosClass::Low, Duration::from_millis(200), 10, |_| {
+                    false
+                })
+            })
+        };
+        wait_for_len(&queue, 1);
+        let high = {
+            let queue = queue.clone();
+            std::thread::spawn(move || {
+                queue.wait("high", QosClass::High, Duration::from_secs(10), 10, |_| {
+                    false
+                })
+            })
+        };
+        wait_for_len(&queue, 2);
+
+        // Only room for high priority: the low one, though first, waits on
+        queue.grant(|qos| qos == QosClass::High);
+        assert!(high.join().unwrap());
+        assert!(!low.join().unwrap());
+        assert_eq!(queue.len(), 0);
+    }
+}
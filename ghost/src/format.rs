@@ -6,6 +6,11 @@
 use std::time::SystemTime;
 use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
+use crate::stats::HistogramSnapshot;
+
+/// Percentiles reported for latency histograms
+const PERCENTILES: [(&str, f64); 4] = [("p50", 0.5), ("p90", 0.9), ("p95", 0.95), ("p99", 0.99)];
+
 /// Format SystemTime as RFC3339 timestamp or "never"
 ///
 /// # Arguments
@@ -108,6 +113,43 @@ pub fn format_backend_selections_json(
         .collect()
 }
 
+/// Format a vhost's selection audit for JSON output, or None if no route
+/// has a shadow selection
+pub fn format_shadow_json(stats: &crate::stats::VhostStats) -> Option<serde_json::Value> {
+    let selections = stats.shadow_selections();
+    let total: u64 = selections.values().sum();
+    (total > 0).then(|| {
+        serde_json::json!({
+            "audited": total,
+            "mismatches": stats.shadow_mismatches(),
+            "backends": format_backend_selections_json(&selections, total)
+        })
+    })
+}
+
+/// Format p50/p90/p95/p99 of a latency histogram as `1.0/4.2/9.8/87.5ms`, or `-`
+/// without samples
+pub fn format_percentiles(latency: &HistogramSnapshot) -> String {
+    let values: Option<Vec<String>> = PERCENTILES
+        .iter()
+        .map(|&(_, q)| latency.percentile(q).map(|ms| format!("{:.1}", ms)))
+        .collect();
+    match values {
+        Some(v) => format!("{}ms", v.join("/")),
+        None => "-".to_string(),
+    }
+}
+
+/// Format p50/p90/p95/p99 of a latency histogram as a JSON object in
+/// milliseconds, with nulls without samples
+pub fn format_percentiles_json(latency: &HistogramSnapshot) -> serde_json::Value {
+    PERCENTILES
+        .iter()
+        .map(|&(name, q)| (name.to_string(), serde_json::json!(latency.percentile(q))))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -125,6 +167,25 @@ mod tests {
         assert!(formatted.contains("2025-01-25"));
     }
 
+    #[test]
+    fn test_format_percentiles() {
+        let h = crate::stats::Histogram::default();
+        assert_eq!(format_percentiles(&h.snapshot()), "-");
+        assert_eq!(
+            format_percentiles_json(&h.snapshot()),
+            serde_json::json!({"p50": null, "p90": null, "p95": null, "p99": null})
+        );
+
+        for _ in 0..10 {
+            h.record(Duration::from_millis(3));
+        }
+        assert_eq!(format_percentiles(&h.snapshot()), "3.0/4.6/4.8/5.0ms");
+        assert_eq!(
+            format_percentiles_json(&h.snapshot())["p50"],
+            serde_json::json!(3.0)
+        );
+    }
+
     #[test]
     fn test_format_percentage() {
         assert_eq!(format_percentage(75, 100), "75.0%");
@@ -0,0 +1,214 @@
+//! Ketama-style consistent hash ring for backend affinity.
+//!
+//! Each backend is placed on a 64-bit ring at several virtual points, with
+//! more points for backends in heavier groups. A request key hashes onto the
+//! ring and is served by the first backend point at or after it. Removing a
+//! backend only remaps the keys that landed on its points, so roughly 1/N of
+//! keys move when one of N backends goes away.
+
+use crate::director::WeightedBackendGroup;
+
+/// Virtual points per backend at average weight.
+const POINTS_PER_BACKEND: u64 = 160;
+
+/// Consistent hash ring over backend keys, built once per config load.
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    /// Ring points sorted by hash, each pointing into `backends`.
+    points: Vec<(u64, usize)>,
+    backends: Vec<String>,
+}
+
+impl HashRing {
+    /// Build a ring from a route's backend groups.
+    ///
+    /// A backend's share of points is its group's weight split evenly across
+    /// the group's pods, so group-level traffic split matches weighted random
+    /// selection. Weight-0 groups get no points. Draining backends get none
+    /// either: every request has a key, new clients included, so only the
+    /// session persistence cookie may still lead to them.
+    pub fn new(groups: &[WeightedBackendGroup]) -> Self {
+        let group_len = |g: &WeightedBackendGroup| g.backends.len() as u64;
+        let total_weight: u64 = groups
+            .iter()
+            .filter(|g| group_len(g) > 0)
+            .map(|g| g.weight as u64)
+            .sum();
+        let total_backends: u64 = groups.iter().filter(|g| g.weight > 0).map(group_len).sum();
+
+        let mut points = Vec::new();
+        let mut backends = Vec::new();
+        if total_weight == 0 {
+            return Self { points, backends };
+        }
+
+        for group in groups.iter().filter(|g| g.weight > 0) {
+            let len = group_len(group);
+            for key in &group.backends {
+                let count = (POINTS_PER_BACKEND * total_backends * group.weight as u64)
+                    / (total_weight * len);
+                let idx = backends.len();
+                backends.push(key.clone());
+                for i in 0..count.max(1) {
+                    points.push((hash_key(format!("{}-{}", key, i).as_bytes()), idx));
+                }
+            }
+        }
+        points.sort_unstable();
+
+        Self { points, backends }
+    }
+
+    /// Backend key for a request key, or `None` if the ring is empty.
+    pub fn get(&self, key: &[u8]) -> Option<&str> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let h = hash_key(key);
+        let pos = self.points.partition_point(|(p, _)| *p < h);
+        let (_, idx) = self.points[pos % self.points.len()];
+        Some(&self.backends[idx])
+    }
+}
+
+/// FNV-1a followed by a splitmix64 finalizer so short, similar keys
+/// (IP addresses, "backend-N" point labels) spread evenly over the ring.
+/// Stable across processes, unlike `DefaultHasher`.
+pub(crate) fn hash_key(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in data {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 30;
+    h = h.wrapping_mul(0xbf58476d1ce4e5b9);
+    h ^= h >> 27;
+    h = h.wrapping_mul(0x94d049bb133111eb);
+    h ^ (h >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn group(weight: u32, backends: &[&str]) -> WeightedBackendGroup {
+        WeightedBackendGroup {
+            weight,
+            backends: backends.iter().map(|b| b.to_string()).collect(),
+            draining: Vec::new(),
+            unresolved: Vec::new(),
+        }
+    }
+
+    fn pods(n: usize) -> Vec<String> {
+        (1..=n).map(|i| format!("10.0.0.{}:8080", i)).collect()
+    }
+
+    #[test]
+    fn test_hash_ring_empty() {
+        assert!(HashRing::new(&[]).get(b"client").is_none());
+        assert!(HashRing::new(&[group(0, &["10.0.0.1:8080"])])
+            .get(b"client")
+            .is_none());
+    }
+
+    #[test]
+    fn test_hash_ring_is_stable() {
+        let ring = HashRing::new(&[group(100, &["10.0.0.1:8080", "10.0.0.2:8080"])]);
+        let first = ring.get(b"192.0.2.10").unwrap().to_string();
+        for _ in 0..10 {
+            assert_eq!(ring.get(b"192.0.2.10").unwrap(), first);
+        }
+    }
+
+    #[test]
+    fn test_hash_ring_remove_backend_remaps_bounded_fraction() {
+        let all = pods(10);
+        let refs: Vec<&str> = all.iter().map(|s| s.as_str()).collect();
+        let before = HashRing::new(&[group(100, &refs)]);
+        let after = HashRing::new(&[group(100, &refs[..9])]);
+        let removed = refs[9];
+
+        let keys: Vec<String> = (0..10_000).map(|i| format!("client-{}", i)).collect();
+        let mut moved = 0;
+        for key in &keys {
+            let a = before.get(key.as_bytes()).unwrap();
+            let b = after.get(key.as_bytes()).unwrap();
+            if a != b {
+                // Only keys owned by the removed backend may move.
+                assert_eq!(a, removed, "key {} moved off a surviving backend", key);
+                moved += 1;
+            }
+        }
+
+        // Ideal is 1/10 of keys; allow for ring variance.
+        let fraction = moved as f64 / keys.len() as f64;
+        assert!(
+            fraction > 0.05 && fraction < 0.15,
+            "remapped fraction {} outside expected bound",
+            fraction
+        );
+    }
+
+    #[test]
+    fn test_hash_ring_add_backend_remaps_bounded_fraction() {
+        let all = pods(10);
+        let refs: Vec<&str> = all.iter().map(|s| s.as_str()).collect();
+        let before = HashRing::new(&[group(100, &refs[..9])]);
+        let after = HashRing::new(&[group(100, &refs)]);
+        let added = refs[9];
+
+        let mut moved = 0;
+        for i in 0..10_000 {
+            let key = format!("client-{}", i);
+            let a = before.get(key.as_bytes()).unwrap();
+            let b = after.get(key.as_bytes()).unwrap();
+            if a != b {
+                assert_eq!(b, added, "key {} moved between surviving backends", key);
+                moved += 1;
+            }
+        }
+        assert!(moved < 1_500, "{} of 10000 keys remapped", moved);
+    }
+
+    #[test]
+    fn test_hash_ring_draining_moves_only_its_keys() {
+        let all = pods(4);
+        let refs: Vec<&str> = all.iter().map(|s| s.as_str()).collect();
+        let before = HashRing::new(&[group(100, &refs)]);
+        let after = HashRing::new(&[WeightedBackendGroup {
+            weight: 100,
+            backends: all[..3].to_vec(),
+            draining: all[3..].to_vec(),
+            unresolved: Vec::new(),
+        }]);
+
+        // Keys of the draining backend move, no other key does
+        let mut moved = 0;
+        for i in 0..1_000 {
+            let key = format!("client-{}", i);
+            let (a, b) = (before.get(key.as_bytes()), after.get(key.as_bytes()));
+            assert_ne!(b, Some(all[3].as_str()));
+            if a == Some(all[3].as_str()) {
+                moved += 1;
+            } else {
+                assert_eq!(a, b);
+            }
+        }
+        assert!(moved > 0);
+    }
+
+    #[test]
+    fn test_hash_ring_respects_group_weight() {
+        let ring = HashRing::new(&[group(90, &["10.0.0.1:8080"]), group(10, &["10.0.0.2:8080"])]);
+
+        let mut counts: HashMap<&str, u32> = HashMap::new();
+        for i in 0..10_000 {
+            let key = format!("client-{}", i);
+            *counts.entry(ring.get(key.as_bytes()).unwrap()).or_insert(0) += 1;
+        }
+        let heavy = counts.get("10.0.0.1:8080").copied().unwrap_or(0);
+        assert!(heavy > 8_000, "heavy backend got {} of 10000", heavy);
+    }
+}
@@ -0,0 +1,382 @@
+//! Active HTTP health probing for native backends.
+//!
+//! Backends with a `health` block get a periodic GET on the shared
+//! background runtime. Results land in a [`HealthMap`] shared with the
+//! backend pool; selection skips keys marked unhealthy. Keys that were never
+//! probed (or have no health check) count as healthy. The first probe sets a
+//! backend's state; after that it takes `unhealthy_threshold` failing or
+//! `healthy_threshold` passing probes in a row to flip it.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use arc_swap::ArcSwap;
+use parking_lot::Mutex;
+use reqwest::Client;
+use tokio::task::JoinHandle;
+
+use crate::config::HealthCheck;
+use crate::upstream_error::ErrorClass;
+
+/// Latest probe result per backend key.
+pub type HealthMap = Arc<HealthStates>;
+
+/// Probe results, published as a snapshot: selection reads them without a
+/// lock, and a probe only replaces the map when a backend flips.
+#[derive(Debug, Default)]
+pub struct HealthStates {
+    snapshot: ArcSwap<HashMap<String, bool>>,
+    /// Serializes writers, so a probe stopped by `sync()` can't write back
+    /// the key `sync()` just removed
+    writer: Mutex<()>,
+}
+
+impl HealthStates {
+    pub fn get(&self, key: &str) -> Option<bool> {
+        self.snapshot.load().get(key).copied()
+    }
+
+    pub fn snapshot(&self) -> Arc<HashMap<String, bool>> {
+        self.snapshot.load_full()
+    }
+
+    #[cfg(test)]
+    pub fn set(&self, key: &str, healthy: bool) {
+        let _writer = self.writer.lock();
+        self.set_locked(key, healthy);
+    }
+
+    fn set_locked(&self, key: &str, healthy: bool) {
+        if self.get(key) != Some(healthy) {
+            let mut map = HashMap::clone(&self.snapshot.load());
+            map.insert(key.to_string(), healthy);
+            self.snapshot.store(Arc::new(map));
+        }
+    }
+
+    fn remove(&self, key: &str) {
+        let _writer = self.writer.lock();
+        if self.get(key).is_some() {
+            let mut map = HashMap::clone(&self.snapshot.load());
+            map.remove(key);
+            self.snapshot.store(Arc::new(map));
+        }
+    }
+}
+
+/// What to probe for one backend key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthTarget {
+    pub url: String,
+    pub check: HealthCheck,
+}
+
+/// Client shared by all probe tasks. Probes must not reuse a pooled
+/// connection that outlived a backend restart, so idle connections aren't kept.
+fn probe_client() -> &'static Client {
+    static CLIENT: OnceLock<Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .no_proxy()
+            .pool_max_idle_per_host(0)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("ghost: failed to build health probe client")
+    })
+}
+
+/// Probe history of one backend, for `backend.list -p`.
+#[derive(Debug, Clone, Default)]
+pub struct ProbeStatus {
+    /// Verdict after thresholds; None until the first probe.
+    pub healthy: Option<bool>,
+    /// Outcome of the last probe: its status code or failure class.
+    pub last_result: Option<String>,
+    pub last_probe: Option<SystemTime>,
+    successes: u32,
+    failures: u32,
+}
+
+impl ProbeStatus {
+    /// Count one probe and return the resulting verdict.
+    fn record(&mut self, passed: bool, check: &HealthCheck) -> bool {
+        if passed {
+            self.successes = self.successes.saturating_add(1);
+            self.failures = 0;
+        } else {
+            self.failures = self.failures.saturating_add(1);
+            self.successes = 0;
+        }
+        let healthy = match self.healthy {
+            None => passed,
+            Some(true) => self.failures < check.unhealthy_threshold,
+            Some(false) => self.successes >= check.healthy_threshold,
+        };
+        self.healthy = Some(healthy);
+        healthy
+    }
+}
+
+/// A running probe loop. `stopped` keeps a probe that was mid-request when
+/// aborted from writing a stale result after its key was forgotten.
+struct ProbeTask {
+    target: HealthTarget,
+    handle: JoinHandle<()>,
+    stopped: Arc<AtomicBool>,
+    status: Arc<Mutex<ProbeStatus>>,
+}
+
+impl ProbeTask {
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+        self.handle.abort();
+    }
+}
+
+/// Running probe tasks, one per backend key. Tasks are aborted when their
+/// key disappears, their check changes, or the owner is dropped.
+#[derive(Default)]
+pub struct HealthProbes {
+    tasks: Mutex<HashMap<String, ProbeTask>>,
+}
+
+impl HealthProbes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reconcile running probes with the wanted targets. Unchanged targets
+    /// keep their task (and last result); removed ones forget their result so
+    /// a backend that loses its health check is trusted again.
+    pub fn sync(&self, targets: &HashMap<String, HealthTarget>, health: &HealthMap) {
+        let mut tasks = self.tasks.lock();
+
+        tasks.retain(|key, task| {
+            let keep = targets.get(key) == Some(&task.target);
+            if !keep {
+                task.stop();
+                health.remove(key);
+            }
+            keep
+        });
+
+        for (key, target) in targets {
+            if tasks.contains_key(key) {
+                continue;
+            }
+            let stopped = Arc::new(AtomicBool::new(false));
+            let status = Arc::default();
+            let handle = crate::external_backend::spawn(probe_loop(
+                key.clone(),
+                target.clone(),
+                Arc::clone(health),
+                Arc::clone(&stopped),
+                Arc::clone(&status),
+            ));
+            tasks.insert(
+                key.clone(),
+                ProbeTask {
+                    target: target.clone(),
+                    handle,
+                    stopped,
+                    status,
+                },
+            );
+        }
+    }
+
+    /// Number of backends being probed (for diagnostics)
+    pub fn len(&self) -> usize {
+        self.tasks.lock().len()
+    }
+
+    /// Probe status of every probed backend, sorted by key
+    pub fn statuses(&self) -> Vec<(String, ProbeStatus)> {
+        let mut statuses: Vec<_> = self
+            .tasks
+            .lock()
+            .iter()
+            .map(|(key, task)| (key.clone(), task.status.lock().clone()))
+            .collect();
+        statuses.sort_by(|a, b| a.0.cmp(&b.0));
+        statuses
+    }
+}
+
+impl Drop for HealthProbes {
+    fn drop(&mut self) {
+        for (_, task) in self.tasks.lock().drain() {
+            task.stop();
+        }
+    }
+}
+
+async fn probe_loop(
+    key: String,
+    target: HealthTarget,
+    health: HealthMap,
+    stopped: Arc<AtomicBool>,
+    status: Arc<Mutex<ProbeStatus>>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_millis(target.check.interval_ms));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let (passed, result) = probe_once(&target).await;
+        let healthy = {
+            let mut status = status.lock();
+            status.last_result = Some(result);
+            status.last_probe = Some(SystemTime::now());
+            status.record(passed, &target.check)
+        };
+        let _writer = health.writer.lock();
+        if stopped.load(Ordering::Acquire) {
+            return;
+        }
+        health.set_locked(&key, healthy);
+    }
+}
+
+/// Probe once. Returns whether it passed, and the status code or failure
+/// class for diagnostics.
+async fn probe_once(target: &HealthTarget) -> (bool, String) {
+    let result = probe_client()
+        .get(&target.url)
+        .timeout(Duration::from_millis(target.check.timeout_ms))
+        .send()
+        .await;
+    match result {
+        Ok(resp) => {
+            let status = resp.status().as_u16();
+            (status == target.check.expected_status, status.to_string())
+        }
+        Err(e) => (false, ErrorClass::from_reqwest(&e).as_str().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream;
+    use std::sync::atomic::AtomicU16;
+    use std::time::Instant;
+
+    fn mock_server(status: Arc<AtomicU16>) -> String {
+        format!("http://{}/healthz", mock_upstream::status(status))
+    }
+
+    fn target(url: String) -> HealthTarget {
+        HealthTarget {
+            url,
+            check: HealthCheck {
+                path: "/healthz".to_string(),
+                interval_ms: 100,
+                timeout_ms: 100,
+                expected_status: 200,
+                healthy_threshold: 1,
+                unhealthy_threshold: 1,
+            },
+        }
+    }
+
+    fn wait_for(health: &HealthMap, key: &str, want: bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while health.get(key) != Some(want) {
+            assert!(Instant::now() < deadline, "{} never became {}", key, want);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_probe_tracks_backend_status() {
+        let status = Arc::new(AtomicU16::new(200));
+        let url = mock_server(Arc::clone(&status));
+        let health: HealthMap = Arc::default();
+        let probes = HealthProbes::new();
+        let targets = HashMap::from([("b1".to_string(), target(url))]);
+
+        probes.sync(&targets, &health);
+        wait_for(&health, "b1", true);
+
+        status.store(503, Ordering::Relaxed);
+        wait_for(&health, "b1", false);
+
+        status.store(200, Ordering::Relaxed);
+        wait_for(&health, "b1", true);
+    }
+
+    #[test]
+    fn test_thresholds_delay_transitions() {
+        let mut check = target(String::new()).check;
+        check.healthy_threshold = 2;
+        check.unhealthy_threshold = 3;
+        let mut status = ProbeStatus::default();
+
+        // The first probe decides outright
+        assert!(status.record(true, &check));
+        assert!(status.record(false, &check));
+        assert!(status.record(false, &check));
+        assert!(!status.record(false, &check));
+
+        // A single pass isn't enough to come back, and a failure restarts
+        // the count
+        assert!(!status.record(true, &check));
+        assert!(!status.record(false, &check));
+        assert!(!status.record(true, &check));
+        assert!(status.record(true, &check));
+
+        let mut status = ProbeStatus::default();
+        assert!(!status.record(false, &check));
+    }
+
+    #[test]
+    fn test_statuses_report_last_result() {
+        let status = Arc::new(AtomicU16::new(503));
+        let url = mock_server(status);
+        let health: HealthMap = Arc::default();
+        let probes = HealthProbes::new();
+        probes.sync(&HashMap::from([("b1".to_string(), target(url))]), &health);
+        wait_for(&health, "b1", false);
+
+        let statuses = probes.statuses();
+        assert_eq!(statuses.len(), 1);
+        let (key, status) = &statuses[0];
+        assert_eq!(key, "b1");
+        assert_eq!(status.healthy, Some(false));
+        assert_eq!(status.last_result.as_deref(), Some("503"));
+        assert!(status.last_probe.is_some());
+    }
+
+    #[test]
+    fn test_probe_unreachable_is_unhealthy() {
+        let port = mock_upstream::closed_port();
+        let health: HealthMap = Arc::default();
+        let probes = HealthProbes::new();
+        let targets = HashMap::from([(
+            "b1".to_string(),
+            target(format!("http://127.0.0.1:{}/healthz", port)),
+        )]);
+
+        probes.sync(&targets, &health);
+        wait_for(&health, "b1", false);
+    }
+
+    #[test]
+    fn test_sync_removes_dropped_targets() {
+        let status = Arc::new(AtomicU16::new(503));
+        let url = mock_server(status);
+        let health: HealthMap = Arc::default();
+        let probes = HealthProbes::new();
+
+        probes.sync(&HashMap::from([("b1".to_string(), target(url))]), &health);
+        wait_for(&health, "b1", false);
+        assert_eq!(probes.len(), 1);
+
+        // Health check removed on reload: task stops and the verdict is forgotten.
+        probes.sync(&HashMap::new(), &health);
+        assert_eq!(probes.len(), 0);
+        assert!(health.get("b1").is_none());
+    }
+}
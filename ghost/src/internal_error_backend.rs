@@ -14,6 +14,7 @@ impl VclBackend<InternalErrorBody> for InternalErrorBackend {
             .http_beresp
             .as_mut()
             .ok_or_else(|| VclError::new("Missing beresp in internal_error backend".to_string()))?;
+        crate::vsc::incr(|c| &c.synth_500);
         beresp.set_status(500);
         beresp.set_header("Content-Type", "text/plain")?;
         beresp.set_header("Cache-Control", "no-store")?;
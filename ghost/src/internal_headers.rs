@@ -0,0 +1,192 @@
+//! Keeping ghost's internal `X-Ghost-*` headers off the wire.
+//!
+//! Routing hands its decisions to the backend side in `X-Ghost-*` headers,
+//! which Varnish copies from req onto bereq. A native backend would receive
+//! them with the request, so `hide()`, run last in `vcl_backend_fetch` and
+//! after routing in the directors' `resolve()`, moves them into the task's
+//! PRIV_TASK. `restore()` puts them back on bereq where they are read:
+//! first thing in `vcl_backend_response` and `vcl_backend_error`, and in
+//! ghost's own synthetic and external proxy backends, none of which send
+//! them anywhere.
+//!
+//! The task also remembers when `hide()` last ran, i.e. when the current
+//! fetch attempt started, so `vcl_backend_error` can tell a fetch that ran
+//! out of its route timeout from one that failed outright.
+
+use std::ffi::c_void;
+use std::time::{Duration, Instant};
+
+use varnish::ffi::{vmod_priv_methods, vrt_ctx, VRT_priv_task, VMOD_PRIV_METHODS_MAGIC};
+use varnish::vcl::{Ctx, StrOrBytes};
+
+use crate::external_backend::parse_timeout;
+use crate::vhost_director::{is_internal_header, BACKEND_TIMEOUT_HEADER};
+
+/// Internal headers `hide()` took off bereq, in their order
+#[derive(Default)]
+struct Hidden {
+    headers: Vec<(String, String)>,
+    /// Start of the current fetch attempt
+    fetch_started: Option<Instant>,
+}
+
+impl Hidden {
+    /// Keep `headers`, replacing earlier values of the same names
+    fn merge(&mut self, headers: Vec<(String, String)>) {
+        self.headers.retain(|(name, _)| {
+            !headers
+                .iter()
+                .any(|(new, _)| new.eq_ignore_ascii_case(name))
+        });
+        self.headers.extend(headers);
+    }
+}
+
+/// PRIV_TASK key, unique by its address
+static KEY: u8 = 0;
+
+struct Methods(vmod_priv_methods);
+
+// SAFETY: `type_` points at a static C string, never written
+unsafe impl Sync for Methods {}
+
+static METHODS: Methods = Methods(vmod_priv_methods {
+    magic: VMOD_PRIV_METHODS_MAGIC,
+    type_: c"ghost internal headers".as_ptr(),
+    fini: Some(fini),
+});
+
+unsafe extern "C" fn fini(_ctx: *const vrt_ctx, hidden: *mut c_void) {
+    if !hidden.is_null() {
+        drop(unsafe { Box::from_raw(hidden.cast::<Hidden>()) });
+    }
+}
+
+/// This task's hidden headers. None outside of a Varnish task, as in unit
+/// tests, where headers then just stay on bereq.
+fn task<'a>(ctx: &'a mut Ctx<'_>) -> Option<&'a mut Hidden> {
+    let slot = unsafe { VRT_priv_task(ctx.raw, std::ptr::addr_of!(KEY).cast()).as_mut()? };
+    if slot.priv_.is_null() {
+        slot.priv_ = Box::into_raw(Box::<Hidden>::default()).cast();
+        slot.methods = &METHODS.0;
+    }
+    Some(unsafe { &mut *slot.priv_.cast::<Hidden>() })
+}
+
+/// Move the internal headers on bereq into the task
+pub fn hide(ctx: &mut Ctx) {
+    let Some(bereq) = ctx.http_bereq.as_ref() else {
+        return;
+    };
+    let headers: Vec<(String, String)> = bereq
+        .into_iter()
+        .filter(|(name, _)| is_internal_header(name))
+        .map(|(name, value)| {
+            let value: &[u8] = value.as_ref();
+            (
+                name.to_string(),
+                String::from_utf8_lossy(value).into_owned(),
+            )
+        })
+        .collect();
+    if headers.is_empty() {
+        return;
+    }
+    let names: Vec<String> = headers.iter().map(|(name, _)| name.clone()).collect();
+    let Some(hidden) = task(ctx) else {
+        return;
+    };
+    hidden.merge(headers);
+    hidden.fetch_started = Some(Instant::now());
+    if let Some(bereq) = ctx.http_bereq.as_mut() {
+        for name in names {
+            bereq.unset_header(&name);
+        }
+    }
+}
+
+/// Put the headers `hide()` took back on bereq
+pub fn restore(ctx: &mut Ctx) {
+    let Some(hidden) = task(ctx) else {
+        return;
+    };
+    let headers = std::mem::take(&mut hidden.headers);
+    let Some(bereq) = ctx.http_bereq.as_mut() else {
+        return;
+    };
+    // Must unset first since set_header() appends a header slot.
+    for (name, _) in &headers {
+        bereq.unset_header(name);
+    }
+    for (name, value) in &headers {
+        let _ = bereq.set_header(name, value);
+    }
+}
+
+/// Whether the fetch attempt that just failed ran into the route timeout.
+/// Call after `restore()`, which brings the timeout header back.
+pub fn fetch_timed_out(ctx: &mut Ctx) -> bool {
+    let timeout =
+        ctx.http_bereq
+            .as_ref()
+            .and_then(|bereq| match bereq.header(BACKEND_TIMEOUT_HEADER) {
+                Some(StrOrBytes::Utf8(value)) => parse_timeout(value),
+                _ => None,
+            });
+    let started = task(ctx).and_then(|hidden| hidden.fetch_started);
+    timed_out(timeout, started, Instant::now())
+}
+
+fn timed_out(timeout: Option<Duration>, started: Option<Instant>, now: Instant) -> bool {
+    match (timeout, started) {
+        (Some(timeout), Some(started)) => now.saturating_duration_since(started) >= timeout,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_replaces_by_name() {
+        let pair = |n: &str, v: &str| (n.to_string(), v.to_string());
+        let mut hidden = Hidden::default();
+        hidden.merge(vec![
+            pair("X-Ghost-Retry", "a"),
+            pair("X-Ghost-Tenant", "t"),
+            pair("X-Ghost-Retry", "b"),
+        ]);
+        // A later hide() of the same name replaces every earlier slot
+        hidden.merge(vec![pair("x-ghost-retry", "c")]);
+        assert_eq!(
+            hidden.headers,
+            vec![pair("X-Ghost-Tenant", "t"), pair("x-ghost-retry", "c")]
+        );
+        hidden.merge(Vec::new());
+        assert_eq!(hidden.headers.len(), 2);
+    }
+
+    #[test]
+    fn test_timed_out_needs_timeout_and_start() {
+        let started = Instant::now();
+        let timeout = Some(Duration::from_millis(100));
+        assert!(!timed_out(
+            timeout,
+            Some(started),
+            started + Duration::from_millis(99)
+        ));
+        assert!(timed_out(
+            timeout,
+            Some(started),
+            started + Duration::from_millis(100)
+        ));
+        // No route timeout, or no fetch hide() saw start
+        assert!(!timed_out(
+            None,
+            Some(started),
+            started + Duration::from_secs(5)
+        ));
+        assert!(!timed_out(timeout, None, started + Duration::from_secs(5)));
+    }
+}
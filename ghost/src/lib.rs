@@ -8,14 +8,19 @@ use parking_lot::RwLock;
 use std::ffi::CStr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use varnish::ffi::{vrt_ctx, VCL_STRING};
+use varnish::ffi::{vrt_ctx, VCL_INT, VCL_IP, VCL_STRING};
 use varnish::vcl::{Ctx, Director, StrOrBytes, VclError};
 
 // VRT_r_local_socket is declared in vrt_obj.h but not included in varnish-rs bindings.
 // It returns the name of the Varnish listener socket (e.g., "http-80") for the current request.
+// VRT_r_client_ip comes from the same header, for the reload endpoint's loopback check,
+// and VRT_r_sess_xid for the connection ID header.
 unsafe extern "C" {
     fn VRT_r_local_socket(ctx: *const vrt_ctx) -> VCL_STRING;
+    fn VRT_r_client_ip(ctx: *const vrt_ctx) -> VCL_IP;
+    fn VRT_r_sess_xid(ctx: *const vrt_ctx) -> VCL_INT;
 }
 
 /// Get the local socket name from the Varnish context.
@@ -31,24 +36,73 @@ fn local_socket<'a>(ctx: &'a Ctx<'a>) -> Option<&'a str> {
     }
 }
 
+/// Get `client.ip` from the Varnish context.
+fn client_ip(ctx: &Ctx) -> Option<std::net::IpAddr> {
+    let raw = unsafe { VRT_r_client_ip(ctx.raw) };
+    <Option<std::net::SocketAddr>>::from(raw).map(|sa| sa.ip())
+}
+
 mod backend_pool;
+mod bad_request_backend;
 mod config;
+mod connect_timeout;
+mod cors;
+mod cors_backend;
+mod debug_headers;
 mod director;
+mod dns;
 mod external_backend;
+mod fair_queue;
 pub mod format;
+mod hash_ring;
+mod health;
 mod internal_error_backend;
+mod internal_headers;
+mod method_not_allowed_backend;
+mod metrics;
+mod mirror;
+mod misdirected_backend;
+#[cfg(test)]
+mod mock_upstream;
+mod nonce;
+mod nonce_rejected_backend;
 mod not_found_backend;
+mod outlier;
+mod rate_limit;
+mod rate_limited_backend;
 mod redirect_backend;
+mod reload_auth;
+mod reload_diff;
+mod request_body;
+mod retry;
+mod routing_log;
+mod shadow_diff;
+mod signing;
 mod stats;
 mod sync_wrapper;
+mod trace_context;
+mod unavailable_backend;
+mod upstream_error;
+mod validate;
 mod vhost_director;
+mod vrnd;
+mod vsc;
+mod watch;
 
 use backend_pool::BackendPool;
+use bad_request_backend::{BadRequestBackend, BadRequestBody};
 use config::ResponseHeaderFilter;
+use cors_backend::PreflightBackend;
 use director::{GhostDirector, GhostDirectorBundle, SharedGhostDirector};
 use internal_error_backend::{InternalErrorBackend, InternalErrorBody};
+use method_not_allowed_backend::{MethodNotAllowedBackend, MethodNotAllowedBody};
+use misdirected_backend::{MisdirectedBackend, MisdirectedBody};
+use nonce_rejected_backend::{NonceRejectedBackend, NonceRejectedBody};
 use not_found_backend::{NotFoundBackend, NotFoundBody};
+use rate_limited_backend::{RateLimitedBackend, RateLimitedBody};
 use redirect_backend::{RedirectBackend, RedirectBody};
+use unavailable_backend::{UnavailableBackend, UnavailableBody};
+use watch::ConfigWatcher;
 
 /// Header name for passing matched route filters to vcl_deliver
 const FILTER_CONTEXT_HEADER: &str = "X-Ghost-Filter-Context";
@@ -59,6 +113,10 @@ varnish::run_vtc_tests!("tests/*.vtc");
 /// Global state for the ghost VMOD (routing config path only)
 struct GhostState {
     config_path: PathBuf,
+    /// Shared secret that lets non-loopback clients trigger a reload
+    reload_token: Option<String>,
+    /// Minimum time between reloads on file change; None unless watching
+    watch_interval: Option<Duration>,
 }
 
 /// Global state storage (config path only, routing is in director instances)
@@ -67,6 +125,10 @@ static STATE: RwLock<Option<Arc<GhostState>>> = RwLock::new(None);
 /// The ghost backend - wraps our director
 #[allow(non_camel_case_types)]
 pub struct ghost_backend {
+    // Dropped first: stops reloads, which use the backends below
+    _watcher: Option<ConfigWatcher>,
+    // Dropped next, for the same reason
+    _dns_refresher: dns::DnsRefresher,
     director: Director<SharedGhostDirector>,
     ghost_director: Arc<GhostDirector>,
     // Keep not_found_backend alive for the lifetime of this ghost_backend
@@ -75,6 +137,21 @@ pub struct ghost_backend {
     _redirect_backend: varnish::vcl::Backend<RedirectBackend, RedirectBody>,
     // Keep internal_error_backend alive for the lifetime of this ghost_backend
     _internal_error_backend: varnish::vcl::Backend<InternalErrorBackend, InternalErrorBody>,
+    // Keep unavailable_backend alive for the lifetime of this ghost_backend
+    _unavailable_backend: varnish::vcl::Backend<UnavailableBackend, UnavailableBody>,
+    // Keep bad_request_backend alive for the lifetime of this ghost_backend
+    _bad_request_backend: varnish::vcl::Backend<BadRequestBackend, BadRequestBody>,
+    // Keep misdirected_backend alive for the lifetime of this ghost_backend
+    _misdirected_backend: varnish::vcl::Backend<MisdirectedBackend, MisdirectedBody>,
+    // Keep preflight_backend alive for the lifetime of this ghost_backend
+    _preflight_backend: varnish::vcl::Backend<PreflightBackend, ()>,
+    // Keep rate_limited_backend alive for the lifetime of this ghost_backend
+    _rate_limited_backend: varnish::vcl::Backend<RateLimitedBackend, RateLimitedBody>,
+    // Keep method_not_allowed_backend alive for the lifetime of this ghost_backend
+    _method_not_allowed_backend:
+        varnish::vcl::Backend<MethodNotAllowedBackend, MethodNotAllowedBody>,
+    // Keep nonce_rejected_backend alive for the lifetime of this ghost_backend
+    _nonce_rejected_backend: varnish::vcl::Backend<NonceRejectedBackend, NonceRejectedBody>,
 }
 
 /// Ghost VMOD - Gateway API routing for Varnish.
@@ -86,20 +163,67 @@ pub struct ghost_backend {
 mod ghost {
     use super::*;
     use varnish::ffi::VCL_BACKEND;
+    use varnish::vcl::Event;
+
+    /// Draw random backend picks from Varnish's seedable generator.
+    ///
+    /// For VTCs: after `debug.srandom`, weighted and `least_conn`
+    /// tie-break picks repeat from one run to the next. Call it in
+    /// `vcl_init`; it holds for the whole process. Returns false where the
+    /// generator isn't available. Not for production: that generator takes
+    /// a global lock on every draw.
+    pub fn seeded_random() -> bool {
+        vrnd::use_seeded()
+    }
+
+    /// Follow the VCL's temperature: config watchers and DNS refreshes,
+    /// which create backends, only run while the VCL is warm.
+    #[event]
+    pub fn event(ctx: &mut Ctx, event: Event) {
+        watch::vcl_event(ctx.raw.vcl, event);
+    }
 
     /// Initialize ghost with a configuration file path.
     ///
     /// Must be called in `vcl_init` before creating any ghost backends.
     /// The config file is not loaded here — it will be loaded when `ghost_backend` is created.
-    pub fn init(path: &str) -> Result<(), VclError> {
+    /// `reload_token`, if set, lets non-loopback clients reload by sending it
+    /// in `X-Ghost-Reload-Token` (see `reload_authorized()`).
+    ///
+    /// With `watch`, each ghost backend also reloads by itself when the
+    /// config file changes, including through a symlink swap as in
+    /// ConfigMap mounts. Changes are picked up once settled, at most once
+    /// per `watch_interval` (default 1s). Results are logged to VSL and
+    /// counted in `GHOST.watch_reloads` and `GHOST.reload_failures`.
+    pub fn init(
+        path: &str,
+        reload_token: Option<&str>,
+        #[default(false)] watch: bool,
+        watch_interval: Option<Duration>,
+    ) -> Result<(), VclError> {
         let config_path = PathBuf::from(path);
+        let reload_token = reload_token.filter(|t| !t.is_empty()).map(str::to_string);
+        let watch_interval = match watch_interval {
+            Some(d) if d.is_zero() => {
+                return Err(VclError::new(
+                    "ghost.init: watch_interval must be positive".to_string(),
+                ))
+            }
+            Some(d) => Some(d),
+            None => Some(watch::DEFAULT_INTERVAL),
+        }
+        .filter(|_| watch);
 
         // Don't load config here - it may not exist yet during startup.
         // Config will be loaded when ghost_backend is created in vcl_init,
         // after chaperone has generated the initial ghost.json file.
         // This avoids race conditions during pod startup.
 
-        let state = GhostState { config_path };
+        let state = GhostState {
+            config_path,
+            reload_token,
+            watch_interval,
+        };
 
         let mut guard = STATE.write();
         *guard = Some(Arc::new(state));
@@ -114,29 +238,129 @@ mod ghost {
         None
     }
 
+    /// Routing statistics of every ghost backend as a JSON string.
+    ///
+    /// Per vhost: `requests`, `retries`, `last_request` (Unix time, or
+    /// null) and `backends`, the selection count per backend key. Plus
+    /// `total_requests` across vhosts. Counts are summed when several
+    /// `ghost_backend` objects (e.g. in different loaded VCLs) route the
+    /// same vhost. Suitable for a header or a `vcl_synth` body.
+    pub fn stats() -> String {
+        director::stats_json()
+    }
+
+    /// Routing statistics of every ghost backend in Prometheus text format.
+    ///
+    /// The per-vhost families of `/.varnish-ghost/metrics`, under the same
+    /// names: `ghost_vhost_requests_total`, `ghost_vhost_retries_total`,
+    /// `ghost_vhost_upstream_errors_total` (failed or 5xx backend fetches),
+    /// `ghost_vhost_last_request_timestamp_seconds` and the
+    /// `ghost_backend_selection*` families, summed across `ghost_backend`
+    /// objects like `stats()`. Meant as the body of a metrics endpoint in
+    /// `vcl_synth`.
+    pub fn metrics_prometheus() -> String {
+        director::stats_prometheus()
+    }
+
+    /// Check whether this request may use the reload endpoint.
+    ///
+    /// Loopback clients are always allowed. Other clients must send the
+    /// token given to `init()` in `X-Ghost-Reload-Token`. On refusal the
+    /// reason is logged to VSL and a JSON error body is stored in
+    /// `req.http.X-Ghost-Error` for `vcl_synth`.
+    pub fn reload_authorized(ctx: &mut Ctx) -> bool {
+        let client = client_ip(ctx);
+        let token = STATE.read().as_ref().and_then(|s| s.reload_token.clone());
+        let req = match ctx.http_req.as_mut() {
+            Some(r) => r,
+            None => return false,
+        };
+        let presented = match req.header(reload_auth::RELOAD_TOKEN_HEADER) {
+            Some(StrOrBytes::Utf8(s)) => Some(s.to_string()),
+            _ => None,
+        };
+        // Never let the token travel further than this check.
+        req.unset_header(reload_auth::RELOAD_TOKEN_HEADER);
+
+        match reload_auth::authorize(client, token.as_deref(), presented.as_deref()) {
+            Ok(()) => true,
+            Err(reason) => {
+                req.unset_header("X-Ghost-Error");
+                let _ = req.set_header("X-Ghost-Error", &reload_auth::denied_json(&reason));
+                let client = client.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+                ctx.log(
+                    varnish::vcl::LogTag::Error,
+                    format!("ghost reload denied for {}: {}", client, reason),
+                );
+                false
+            }
+        }
+    }
+
     /// Deliver hook for response header modification.
     ///
-    /// Call this in `vcl_deliver` to apply ResponseHeaderModifier filters.
-    /// Reads filter context from response headers (copied from bereq in vcl_backend_response).
+    /// Call this in `vcl_deliver` to apply ResponseHeaderModifier filters
+    /// and the vhost's `reason_phrases`.
+    /// Reads the filter context `recv()` left on the request, falling back to
+    /// a copy on the response (set by VCL from bereq, for `backend()` routing).
+    /// Also emits the session affinity cookie chosen during routing, and the
+    /// `X-Ghost-Vhost`, `X-Ghost-Route`, `X-Ghost-Backend` and `X-Ghost-Budget`
+    /// debug headers when `debug_headers` enables them for the request.
     pub fn deliver(ctx: &mut Ctx) {
+        // Affinity cookie and filter context are per-request, so they live on
+        // req rather than the (possibly cached) response. Keeping the filter
+        // context off bereq also keeps it away from the backend.
+        let (affinity_cookie, req_filter_json, debug_info, request_id) = match ctx.http_req.as_ref()
+        {
+            Some(req) => {
+                let cookie = match req.header(vhost_director::AFFINITY_COOKIE_HEADER) {
+                    Some(StrOrBytes::Utf8(s)) => Some(s.to_string()),
+                    _ => None,
+                };
+                let filter = match req.header(FILTER_CONTEXT_HEADER) {
+                    Some(StrOrBytes::Utf8(s)) => Some(s.to_string()),
+                    _ => None,
+                };
+                let debug = match req.header(debug_headers::DEBUG_INFO_HEADER) {
+                    Some(StrOrBytes::Utf8(s)) => debug_headers::DebugInfo::from_header(s),
+                    _ => None,
+                };
+                let request_id = match req.header(trace_context::ECHO_REQUEST_ID_HEADER) {
+                    Some(StrOrBytes::Utf8(s)) => Some(s.to_string()),
+                    _ => None,
+                };
+                (cookie, filter, debug, request_id)
+            }
+            None => (None, None, None, None),
+        };
+
         // Get mutable response for both reading and modifying
         let resp = match ctx.http_resp.as_mut() {
             Some(r) => r,
             None => return,
         };
 
+        // set_header() appends, which is what multiple Set-Cookie needs
+        if let Some(cookie) = affinity_cookie {
+            let _ = resp.set_header("Set-Cookie", &cookie);
+        }
+
+        debug_headers::apply(resp, debug_info.as_ref());
+        trace_context::echo_request_id(resp, request_id.as_deref());
+
         // Read filter context from response header
-        let filter_json = match resp.header(FILTER_CONTEXT_HEADER) {
-            Some(StrOrBytes::Utf8(s)) => s.to_string(),
-            Some(StrOrBytes::Bytes(b)) => match std::str::from_utf8(b) {
-                Ok(s) => s.to_string(),
-                Err(_) => return,
-            },
-            None => return,
+        let resp_filter_json = match resp.header(FILTER_CONTEXT_HEADER) {
+            Some(StrOrBytes::Utf8(s)) => Some(s.to_string()),
+            Some(StrOrBytes::Bytes(b)) => std::str::from_utf8(b).ok().map(str::to_string),
+            None => None,
         };
 
-        // Remove filter context header (internal only, don't leak to client)
+        // Remove filter context header (internal only, don't leak to client),
+        // even if it turns out to be unreadable
         resp.unset_header(FILTER_CONTEXT_HEADER);
+        let Some(filter_json) = req_filter_json.or(resp_filter_json) else {
+            return;
+        };
 
         // Deserialize filter
         let filter: ResponseHeaderFilter = match serde_json::from_str(&filter_json) {
@@ -156,23 +380,53 @@ mod ghost {
         }
 
         // Add headers (appends to existing value per Gateway API spec)
-        // Must unset+set to avoid duplicate header slots
         for action in &filter.add {
-            match resp.header(&action.name) {
-                Some(existing) => {
-                    let existing_str = match existing {
-                        StrOrBytes::Utf8(s) => s.to_string(),
-                        StrOrBytes::Bytes(b) => String::from_utf8_lossy(b).to_string(),
-                    };
-                    let combined = format!("{},{}", existing_str, action.value);
-                    resp.unset_header(&action.name);
-                    let _ = resp.set_header(&action.name, &combined);
-                }
-                None => {
-                    let _ = resp.set_header(&action.name, &action.value);
-                }
+            let _ = vhost_director::add_header(resp, &action.name, &action.value);
+        }
+
+        // Defaults that an upstream header takes precedence over
+        for action in &filter.set_if_absent {
+            if resp.header(&action.name).is_none() {
+                let _ = resp.set_header(&action.name, &action.value);
             }
         }
+
+        // The vhost's reason phrase for the status, if it has one
+        let status = match resp.status() {
+            Some(StrOrBytes::Utf8(s)) => s.parse::<u16>().ok(),
+            _ => None,
+        };
+        if let Some(reason) = status.and_then(|s| filter.reason_phrases.get(&s)) {
+            let _ = resp.set_reason(reason);
+        }
+    }
+
+    /// Keep ghost's internal `X-Ghost-*` headers from the backend.
+    ///
+    /// Call this last in `vcl_backend_fetch`. The headers move from bereq
+    /// into the backend task, so a native backend never receives them;
+    /// ghost's own backends still see them. Routing with `backend()` does
+    /// the same by itself.
+    pub fn hide_internal_headers(ctx: &mut Ctx) {
+        internal_headers::hide(ctx);
+    }
+
+    /// Put the headers `hide_internal_headers()` took back on bereq.
+    ///
+    /// Call this first in `vcl_backend_response` and `vcl_backend_error`,
+    /// before anything reads an `X-Ghost-*` header from bereq.
+    pub fn restore_internal_headers(ctx: &mut Ctx) {
+        internal_headers::restore(ctx);
+    }
+
+    /// Whether the failed fetch ran out of its route timeout.
+    ///
+    /// For native backends the route timeout only sets bereq's timeouts, so
+    /// Varnish reports a timed-out fetch like any other failure. Call this
+    /// in `vcl_backend_error`, after `restore_internal_headers()`, to turn
+    /// it into a 504.
+    pub fn fetch_timed_out(ctx: &mut Ctx) -> bool {
+        internal_headers::fetch_timed_out(ctx)
     }
 
     /// Ghost backend object for request routing.
@@ -197,19 +451,33 @@ mod ghost {
         #[allow(clippy::self_named_constructors)]
         pub fn ghost_backend(ctx: &mut Ctx, #[vcl_name] name: &str) -> Result<Self, VclError> {
             // Get config path from global state
-            let config_path = {
+            let (config_path, watch_interval) = {
                 let state_guard = STATE.read();
                 let state = state_guard.as_ref().ok_or_else(|| {
                     VclError::new("ghost.backend: ghost.init() must be called first".to_string())
                 })?;
-                state.config_path.clone()
+                (state.config_path.clone(), state.watch_interval)
             };
 
+            // Before the pre-load below, so its vhosts get counters
+            vsc::init();
+
             // Start with empty routing state
             use std::collections::HashMap;
             let empty_directors = director::VhostDirectorMap {
                 exact: HashMap::new(),
                 wildcards: Vec::new(),
+                match_order: config::Config::empty().host_match_order,
+                tls_fingerprint: None,
+                route_key: None,
+                debug_headers: config::DebugHeaders::Off,
+                routing_log: config::RoutingLog::Off,
+                tracing: false,
+                trace_sample_percent: 100.0,
+                connection_id_header: None,
+                authorized_private: Vec::new(),
+                sni_header: None,
+                reject_misdirected: Default::default(),
             };
             let backend_pool = BackendPool::new();
 
@@ -218,11 +486,18 @@ mod ghost {
                 not_found: not_found_backend,
                 redirect: redirect_backend,
                 internal_error: internal_error_backend,
+                unavailable: unavailable_backend,
+                bad_request: bad_request_backend,
+                misdirected: misdirected_backend,
+                preflight: preflight_backend,
+                rate_limited: rate_limited_backend,
+                method_not_allowed: method_not_allowed_backend,
+                nonce_rejected: nonce_rejected_backend,
             } = GhostDirectorBundle::new(
                 ctx,
                 Arc::new(empty_directors),
                 backend_pool,
-                config_path,
+                config_path.clone(),
             )?;
 
             // Pre-load config if the file already exists on disk.
@@ -240,15 +515,36 @@ mod ghost {
             }
 
             let ghost_director = Arc::new(ghost_director_impl);
+            director::register(&ghost_director);
             let shared_director = SharedGhostDirector(Arc::clone(&ghost_director));
             let director = Director::new(ctx, "ghost", name, shared_director)?;
 
+            let watcher = watch_interval.map(|interval| {
+                ConfigWatcher::start(
+                    config_path,
+                    interval,
+                    Arc::downgrade(&ghost_director),
+                    ctx.raw.vcl,
+                )
+            });
+            let dns_refresher =
+                dns::DnsRefresher::start(Arc::downgrade(&ghost_director), ctx.raw.vcl);
+
             Ok(ghost_backend {
+                _watcher: watcher,
+                _dns_refresher: dns_refresher,
                 director,
                 ghost_director,
                 _not_found_backend: not_found_backend,
                 _redirect_backend: redirect_backend,
                 _internal_error_backend: internal_error_backend,
+                _unavailable_backend: unavailable_backend,
+                _bad_request_backend: bad_request_backend,
+                _misdirected_backend: misdirected_backend,
+                _preflight_backend: preflight_backend,
+                _rate_limited_backend: rate_limited_backend,
+                _method_not_allowed_backend: method_not_allowed_backend,
+                _nonce_rejected_backend: nonce_rejected_backend,
             })
         }
 
@@ -268,6 +564,7 @@ mod ghost {
             // Copy listener to owned String to avoid borrow conflict:
             // local_socket() borrows ctx immutably, http_req.as_mut() needs mutable.
             let listener_owned = local_socket(ctx).map(|s| s.to_string());
+            let sess_xid = u64::try_from(VRT_r_sess_xid(ctx.raw).0).ok();
             let fallback = self.director.as_ref().vcl_ptr();
 
             let req = match ctx.http_req.as_mut() {
@@ -275,12 +572,20 @@ mod ghost {
                 None => return fallback,
             };
 
-            let result = self
-                .ghost_director
-                .route_request(req, listener_owned.as_deref());
+            let result =
+                self.ghost_director
+                    .route_request(req, listener_owned.as_deref(), sess_xid);
             for (tag, msg) in result.log_msgs {
                 ctx.log(tag, &msg);
             }
+            if let Some(mirror) = result.mirror {
+                if let Err(reason) = mirror.send(ctx) {
+                    ctx.log(
+                        varnish::vcl::LogTag::Debug,
+                        format!("Request mirror skipped: {}", reason),
+                    );
+                }
+            }
 
             // Signal pass via header instead of ctx.set_pass() so that
             // user VCL concatenated after the preamble vcl_recv still runs.
@@ -326,6 +631,45 @@ mod ghost {
             self.director.as_ref().vcl_ptr()
         }
 
+        /// Decide in `vcl_backend_response` whether to retry the fetch.
+        ///
+        /// Returns `true` when the matched route's `retry` policy covers the
+        /// response status, neither its attempts nor the route's request
+        /// deadline are used up, and an untried healthy backend is left.
+        /// Only GET, HEAD and OPTIONS are retried unless the policy opts in
+        /// to other methods. Call `return (retry)` on `true`, and use
+        /// `retry_backend()` in `vcl_backend_fetch` on the retried fetch.
+        pub fn retry(&self, ctx: &mut Ctx) -> bool {
+            self.ghost_director.retry(ctx)
+        }
+
+        /// Take a token from the outbound rate limit of the fetch's backend.
+        ///
+        /// Call in `vcl_backend_fetch`, after `retry_backend()`. Waits up to
+        /// the limit's `max_wait_ms` for a token; returns `false` if none
+        /// came, and the fetch should `return (error(503))`. Always `true`
+        /// for backends without a rate limit.
+        pub fn throttle(&self, ctx: &mut Ctx) -> bool {
+            self.ghost_director.throttle(ctx)
+        }
+
+        /// Backend for a retried fetch, chosen by the last `retry()`.
+        ///
+        /// Use in `vcl_backend_fetch` when `bereq.retries > 0`. Returns the
+        /// ghost director (which routes the request afresh) if the request
+        /// has no retry state.
+        ///
+        /// # Safety
+        ///
+        /// Must be called from VCL context. The returned `VCL_BACKEND` pointer is
+        /// only valid for the lifetime of the current VCL transaction.
+        pub unsafe fn retry_backend(&self, ctx: &mut Ctx) -> VCL_BACKEND {
+            match self.ghost_director.retry_backend(ctx) {
+                Some(backend_ref) => backend_ref.vcl_ptr(),
+                None => self.director.as_ref().vcl_ptr(),
+            }
+        }
+
         /// Reload the configuration from disk.
         ///
         /// Reads `ghost.json`, builds new routing state, and atomically swaps it in.
@@ -335,10 +679,64 @@ mod ghost {
             self.ghost_director.reload(ctx).is_ok()
         }
 
+        /// Revert to the configuration loaded before the current one.
+        ///
+        /// Uses the copy kept in memory, not the file, so a valid but bad
+        /// config can be undone before `ghost.json` is fixed. The last few
+        /// loads can be undone in turn; the next `reload()` reads the file
+        /// again.
+        /// Returns `true` on success, `false` on failure (see `last_error()`).
+        pub fn rollback_config(&self, ctx: &mut Ctx) -> bool {
+            self.ghost_director.rollback(ctx).is_ok()
+        }
+
+        /// Routing statistics in Prometheus text format.
+        ///
+        /// Per-vhost request and retry counts, per-backend selections and
+        /// shares, upstream error counts and config load state. Meant as the
+        /// body of a metrics endpoint in `vcl_synth`.
+        pub fn metrics(&self) -> String {
+            self.ghost_director.metrics()
+        }
+
+        /// Check `ghost.json` without loading it.
+        ///
+        /// Runs the same parsing, validation and route compilation as
+        /// `reload()`, leaving the live routing state alone, and returns a
+        /// JSON report: `status` (`ok` or `error`), vhost, route and backend
+        /// counts, and `errors` and `warnings` naming the vhost and route
+        /// concerned. Meant as the body of a validation endpoint in
+        /// `vcl_synth`.
+        pub fn validate(&self) -> String {
+            self.ghost_director.validate()
+        }
+
         /// Get the last reload error message, or empty string if no error.
         pub fn last_error(&self) -> String {
             self.ghost_director.last_error().unwrap_or_default()
         }
+
+        /// Get the last reload error as JSON.
+        ///
+        /// `{"status":"ok"}` if the last reload succeeded. Otherwise `status`
+        /// is `error`, `error` is the `last_error()` message, and `vhost`,
+        /// `route`, `rule_index` and `path` name the part of the config that
+        /// failed, when it was one vhost or route. Meant as the body of a
+        /// failed reload in `vcl_synth`.
+        pub fn last_error_json(&self) -> String {
+            self.ghost_director.last_error_json()
+        }
+
+        /// Get what the last successful reload changed, as JSON.
+        ///
+        /// `{"status":"ok","changes":{...}}`, where `changes` lists vhosts and
+        /// backends added and removed, and per vhost the routes added, removed
+        /// and changed. Reorderings that route the same way are not changes.
+        /// `changes` is null until the first reload. Meant as the body of a
+        /// successful reload in `vcl_synth`.
+        pub fn last_reload_json(&self) -> String {
+            self.ghost_director.last_reload_json()
+        }
     }
 }
 
@@ -346,7 +744,8 @@ mod ghost {
 mod test_stubs {
     //! Stub libvarnishd symbols for `cargo test --lib`.
     //!
-    //! `VRT_DelDirector`, `VRT_delete_backend`, and `VRT_Assign_Backend`
+    //! `VRT_DelDirector`, `VRT_delete_backend`, `VRT_Assign_Backend` and
+    //! `VRT_priv_task`
     //! live in libvarnishd (linked into the `varnishd` executable), not in
     //! libvarnishapi (what we link against at compile time). The production
     //! cdylib leaves them undefined and the host varnishd resolves them at
@@ -372,4 +771,10 @@ mod test_stubs {
 
     #[no_mangle]
     pub unsafe extern "C" fn VRT_Assign_Backend(_dst: *mut *const c_void, _src: *const c_void) {}
+
+    /// No task here: `internal_headers` leaves headers on bereq
+    #[no_mangle]
+    pub unsafe extern "C" fn VRT_priv_task(_ctx: *const c_void, _id: *const c_void) -> *mut c_void {
+        std::ptr::null_mut()
+    }
 }
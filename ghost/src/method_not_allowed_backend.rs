@@ -0,0 +1,114 @@
+//! Synthetic 405 backend for requests whose method no route accepts
+//!
+//! This backend generates 405 responses when no route of a vhost matches a
+//! request, but some would have if it weren't for their `method`. The methods
+//! of those routes come from `route_request()` in [`ALLOW_HEADER`] and are
+//! sent back in `Allow`.
+
+use varnish::vcl::{Ctx, StrOrBytes, VclBackend, VclError, VclResponse};
+
+use crate::internal_headers;
+
+/// Request header carrying the `Allow` value to the 405 backend
+pub const ALLOW_HEADER: &str = "X-Ghost-Allow";
+
+/// Backend that generates synthetic 405 responses
+pub struct MethodNotAllowedBackend;
+
+impl VclBackend<MethodNotAllowedBody> for MethodNotAllowedBackend {
+    fn get_response(&self, ctx: &mut Ctx) -> Result<Option<MethodNotAllowedBody>, VclError> {
+        internal_headers::restore(ctx);
+        let bereq = ctx.http_bereq.as_mut().ok_or_else(|| {
+            VclError::new("Missing bereq in method_not_allowed backend".to_string())
+        })?;
+        let allow = match bereq.header(ALLOW_HEADER) {
+            Some(StrOrBytes::Utf8(s)) if is_method_list(s) => Some(s.to_string()),
+            _ => None,
+        };
+        bereq.unset_header(ALLOW_HEADER);
+
+        let beresp = ctx.http_beresp.as_mut().ok_or_else(|| {
+            VclError::new("Missing beresp in method_not_allowed backend".to_string())
+        })?;
+        crate::vsc::incr(|c| &c.synth_405);
+        beresp.set_status(405);
+        beresp.set_header("Content-Type", "text/plain")?;
+        beresp.set_header("Cache-Control", "no-store")?;
+        // RFC 9110 requires Allow on a 405, even if empty
+        beresp.set_header("Allow", allow.as_deref().unwrap_or(""))?;
+
+        Ok(Some(MethodNotAllowedBody::new()))
+    }
+}
+
+/// Whether `value` is a comma-separated list of method tokens, as
+/// `route_request()` writes it
+fn is_method_list(value: &str) -> bool {
+    value.split(", ").all(|m| {
+        !m.is_empty()
+            && m.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+    })
+}
+
+/// Response body for 405 error
+pub struct MethodNotAllowedBody {
+    data: &'static [u8],
+    cursor: usize,
+}
+
+impl MethodNotAllowedBody {
+    /// Create a new 405 response body
+    pub fn new() -> Self {
+        Self {
+            data: b"method not allowed",
+            cursor: 0,
+        }
+    }
+}
+
+impl VclResponse for MethodNotAllowedBody {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, VclError> {
+        let remaining = &self.data[self.cursor..];
+        let to_copy = remaining.len().min(buf.len());
+
+        buf[..to_copy].copy_from_slice(&remaining[..to_copy]);
+        self.cursor += to_copy;
+
+        Ok(to_copy)
+    }
+
+    fn len(&self) -> Option<usize> {
+        Some(self.data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_not_allowed_body_read() {
+        let mut body = MethodNotAllowedBody::new();
+        assert_eq!(body.len(), Some(18));
+        let mut buf = vec![0u8; 100];
+
+        let n = body.read(&mut buf).unwrap();
+        assert_eq!(n, 18);
+        assert_eq!(&buf[..n], b"method not allowed");
+
+        // Second read should return 0 (EOF)
+        let n = body.read(&mut buf).unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn test_is_method_list() {
+        assert!(is_method_list("GET"));
+        assert!(is_method_list("GET, POST"));
+        assert!(is_method_list("PURGE"));
+        assert!(!is_method_list(""));
+        assert!(!is_method_list("GET,POST, "));
+        assert!(!is_method_list("GET\r\nX-Injected: 1"));
+    }
+}
@@ -0,0 +1,706 @@
+//! Prometheus text exposition of ghost statistics.
+//!
+//! Rendered on demand for the `/.varnish-ghost/metrics` endpoint. Labels
+//! come from the loaded config only (vhost names, backend keys and the
+//! fixed error classes), so cardinality is bounded by what is routed.
+//! Per-vhost counters restart from zero when a reload replaces the vhost;
+//! scrapers treat that as a counter reset.
+//!
+//! The same per-vhost stats are also rendered as JSON for `ghost.stats()`,
+//! and aggregated across all `ghost_backend` objects for
+//! `ghost.metrics_prometheus()`.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::backend_pool::BackendPool;
+use crate::director::VhostDirectorMap;
+use crate::stats::{HistogramSnapshot, VhostStats, LATENCY_BUCKETS_MS};
+use crate::vhost_director::VhostDirector;
+
+/// Config load state reported alongside the routing stats
+pub struct ReloadInfo {
+    /// Successful loads and rollbacks so far
+    pub generation: u64,
+    /// When the live config was loaded
+    pub last_reload: Option<SystemTime>,
+}
+
+/// Escape a label value: backslash, double quote and newline.
+fn escape_label(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unix_seconds(t: SystemTime) -> f64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64())
+}
+
+/// Write a metric family header
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Write the samples of one histogram: cumulative buckets, sum and count
+fn histogram(out: &mut String, name: &str, labels: &str, h: &HistogramSnapshot) {
+    let mut cumulative = 0;
+    for (i, n) in h.counts.iter().enumerate() {
+        cumulative += n;
+        let le = LATENCY_BUCKETS_MS.get(i).map_or_else(
+            || "+Inf".to_string(),
+            |&ms| (ms as f64 / 1000.0).to_string(),
+        );
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{},le=\"{}\"}} {}",
+            name, labels, le, cumulative
+        );
+    }
+    let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, h.sum.as_secs_f64());
+    let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, cumulative);
+}
+
+/// Render all metrics in Prometheus text format (version 0.0.4).
+pub fn render(directors: &VhostDirectorMap, backends: &BackendPool, reload: &ReloadInfo) -> String {
+    let mut out = String::new();
+    vhost_families(
+        &mut out,
+        &by_host(directors.all_directors().map(|d| d.as_ref())),
+    );
+
+    let latencies = backends.latencies();
+    family(
+        &mut out,
+        "ghost_backend_first_byte_seconds",
+        "histogram",
+        "External proxy request sent to response headers received.",
+    );
+    for (key, first_byte, _) in &latencies {
+        let labels = format!("backend=\"{}\"", escape_label(key));
+        histogram(
+            &mut out,
+            "ghost_backend_first_byte_seconds",
+            &labels,
+            first_byte,
+        );
+    }
+    family(
+        &mut out,
+        "ghost_backend_last_byte_seconds",
+        "histogram",
+        "External proxy request sent to the end of the response body.",
+    );
+    for (key, _, last_byte) in &latencies {
+        let labels = format!("backend=\"{}\"", escape_label(key));
+        histogram(
+            &mut out,
+            "ghost_backend_last_byte_seconds",
+            &labels,
+            last_byte,
+        );
+    }
+
+    family(
+        &mut out,
+        "ghost_backend_throttled_total",
+        "counter",
+        "Fetches answered with a 503 by the backend's outbound rate limit.",
+    );
+    for (key, count) in backends.throttled() {
+        let _ = writeln!(
+            out,
+            "ghost_backend_throttled_total{{backend=\"{}\"}} {}",
+            escape_label(key),
+            count
+        );
+    }
+
+    family(
+        &mut out,
+        "ghost_upstream_errors_total",
+        "counter",
+        "Synthetic error responses from external proxies, by error class.",
+    );
+    for (class, count) in crate::stats::upstream_errors() {
+        let _ = writeln!(
+            out,
+            "ghost_upstream_errors_total{{class=\"{}\"}} {}",
+            class.as_str(),
+            count
+        );
+    }
+
+    family(
+        &mut out,
+        "ghost_external_pending_requests",
+        "gauge",
+        "External proxy requests waiting on upstream response headers.",
+    );
+    let _ = writeln!(
+        out,
+        "ghost_external_pending_requests {}",
+        crate::external_backend::pending_requests()
+    );
+
+    family(
+        &mut out,
+        "ghost_external_queued_requests",
+        "gauge",
+        "External proxy requests queued for a pending request slot.",
+    );
+    let _ = writeln!(
+        out,
+        "ghost_external_queued_requests {}",
+        crate::external_backend::queued_requests()
+    );
+
+    family(
+        &mut out,
+        "ghost_external_active_streams",
+        "gauge",
+        "External proxy responses streaming to clients.",
+    );
+    let _ = writeln!(
+        out,
+        "ghost_external_active_streams {}",
+        crate::external_backend::active_streams()
+    );
+
+    family(
+        &mut out,
+        "ghost_config_generation",
+        "gauge",
+        "Config loads and rollbacks since the VCL was loaded.",
+    );
+    let _ = writeln!(out, "ghost_config_generation {}", reload.generation);
+
+    if let Some(t) = reload.last_reload {
+        family(
+            &mut out,
+            "ghost_config_last_reload_timestamp_seconds",
+            "gauge",
+            "Unix time the live config was loaded.",
+        );
+        let _ = writeln!(
+            out,
+            "ghost_config_last_reload_timestamp_seconds {:.3}",
+            unix_seconds(t)
+        );
+    }
+
+    out
+}
+
+/// Directors grouped by vhost name, sorted. A vhost routed by several
+/// directors (one per loaded VCL, say) is reported once, with their counts
+/// summed.
+fn by_host<'a>(
+    vhosts: impl IntoIterator<Item = &'a VhostDirector>,
+) -> BTreeMap<&'a str, Vec<&'a VhostDirector>> {
+    let mut by_host: BTreeMap<&str, Vec<&VhostDirector>> = BTreeMap::new();
+    for d in vhosts {
+        by_host.entry(d.hostname()).or_default().push(d);
+    }
+    by_host
+}
+
+/// Backend selection counts of several directors for one vhost, summed
+fn summed_selections(directors: &[&VhostDirector]) -> BTreeMap<String, u64> {
+    let mut selections = BTreeMap::new();
+    for d in directors {
+        for (key, count) in d.stats().backend_selections.read().iter() {
+            *selections.entry(key.clone()).or_insert(0) += count;
+        }
+    }
+    selections
+}
+
+/// Write one `{vhost}` counter family, summing `count` over each vhost's
+/// directors
+fn vhost_counter(
+    out: &mut String,
+    by_host: &BTreeMap<&str, Vec<&VhostDirector>>,
+    name: &str,
+    help: &str,
+    count: impl Fn(&VhostStats) -> u64,
+) {
+    family(out, name, "counter", help);
+    for (host, directors) in by_host {
+        let _ = writeln!(
+            out,
+            "{}{{vhost=\"{}\"}} {}",
+            name,
+            escape_label(host),
+            directors.iter().map(|d| count(d.stats())).sum::<u64>()
+        );
+    }
+}
+
+/// Write the per-vhost families, shared by the metrics endpoint and
+/// `ghost.metrics_prometheus()`. Vhosts are sorted so scrapes diff cleanly.
+fn vhost_families(out: &mut String, by_host: &BTreeMap<&str, Vec<&VhostDirector>>) {
+    vhost_counter(
+        out,
+        by_host,
+        "ghost_vhost_requests_total",
+        "Requests routed to a backend of the vhost.",
+        VhostStats::total_requests,
+    );
+    vhost_counter(
+        out,
+        by_host,
+        "ghost_vhost_retries_total",
+        "Fetches retried on another backend.",
+        VhostStats::retries,
+    );
+    vhost_counter(
+        out,
+        by_host,
+        "ghost_vhost_upstream_errors_total",
+        "Backend fetches for the vhost that failed or got a 5xx response.",
+        VhostStats::upstream_errors,
+    );
+
+    family(
+        out,
+        "ghost_vhost_last_request_timestamp_seconds",
+        "gauge",
+        "Unix time of the last request routed for the vhost.",
+    );
+    for (host, directors) in by_host {
+        if let Some(t) = directors
+            .iter()
+            .filter_map(|d| d.stats().last_request())
+            .max()
+        {
+            let _ = writeln!(
+                out,
+                "ghost_vhost_last_request_timestamp_seconds{{vhost=\"{}\"}} {:.3}",
+                escape_label(host),
+                unix_seconds(t)
+            );
+        }
+    }
+
+    // Both backend families come from one snapshot per vhost, so the ratio
+    // always matches the counts next to it.
+    let selections: Vec<_> = by_host
+        .iter()
+        .map(|(host, directors)| (escape_label(host), summed_selections(directors)))
+        .collect();
+
+    family(
+        out,
+        "ghost_backend_selections_total",
+        "counter",
+        "Times routing picked the backend for the vhost.",
+    );
+    for (vhost, backends) in &selections {
+        for (backend, count) in backends {
+            let _ = writeln!(
+                out,
+                "ghost_backend_selections_total{{vhost=\"{}\",backend=\"{}\"}} {}",
+                vhost,
+                escape_label(backend),
+                count
+            );
+        }
+    }
+
+    family(
+        out,
+        "ghost_backend_selection_ratio",
+        "gauge",
+        "Share of the vhost's selections that went to the backend.",
+    );
+    for (vhost, backends) in &selections {
+        let total: u64 = backends.values().sum();
+        for (backend, count) in backends {
+            let ratio = if total > 0 {
+                *count as f64 / total as f64
+            } else {
+                0.0
+            };
+            let _ = writeln!(
+                out,
+                "ghost_backend_selection_ratio{{vhost=\"{}\",backend=\"{}\"}} {:.4}",
+                vhost,
+                escape_label(backend),
+                ratio
+            );
+        }
+    }
+}
+
+/// Render the per-vhost families of [`render`] for
+/// `ghost.metrics_prometheus()`, under the same names. Vhosts are
+/// aggregated like in [`render_json`].
+pub fn render_vhosts<'a>(vhosts: impl IntoIterator<Item = &'a VhostDirector>) -> String {
+    let mut out = String::new();
+    vhost_families(&mut out, &by_host(vhosts));
+    out
+}
+
+/// Write `s` as a JSON string literal
+fn json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Render per-vhost totals, backend selection counts and last-request times
+/// as JSON, for `ghost.stats()`. A vhost routed by several directors (one
+/// per loaded VCL, say) is reported once, with their counts summed.
+///
+/// Written straight into one string: selection counts are read under their
+/// lock rather than copied out.
+pub fn render_json<'a>(vhosts: impl IntoIterator<Item = &'a VhostDirector>) -> String {
+    let by_host = by_host(vhosts);
+
+    let mut out = String::with_capacity(64 + by_host.len() * 128);
+    let mut total = 0;
+    out.push_str("{\"vhosts\":{");
+    for (i, (host, directors)) in by_host.iter().enumerate() {
+        let requests: u64 = directors.iter().map(|d| d.stats().total_requests()).sum();
+        let retries: u64 = directors.iter().map(|d| d.stats().retries()).sum();
+        let last = directors
+            .iter()
+            .filter_map(|d| d.stats().last_request())
+            .max();
+        total += requests;
+
+        if i > 0 {
+            out.push(',');
+        }
+        json_str(&mut out, host);
+        let _ = write!(
+            out,
+            ":{{\"requests\":{},\"retries\":{},\"last_request\":",
+            requests, retries
+        );
+        match last {
+            Some(t) => {
+                let _ = write!(out, "{:.3}", unix_seconds(t));
+            }
+            None => out.push_str("null"),
+        }
+
+        out.push_str(",\"backends\":{");
+        let guards: Vec<_> = directors
+            .iter()
+            .map(|d| d.stats().backend_selections.read())
+            .collect();
+        let mut selections: BTreeMap<&str, u64> = BTreeMap::new();
+        for (key, count) in guards.iter().flat_map(|g| g.iter()) {
+            *selections.entry(key).or_insert(0) += count;
+        }
+        for (j, (key, count)) in selections.iter().enumerate() {
+            if j > 0 {
+                out.push(',');
+            }
+            json_str(&mut out, key);
+            let _ = write!(out, ":{}", count);
+        }
+        out.push_str("}}");
+    }
+    let _ = write!(out, "}},\"total_requests\":{}}}", total);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DebugHeaders, HostMatchKind, RoutingLog};
+    use crate::stats::Histogram;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn directors(hostnames: &[&str]) -> VhostDirectorMap {
+        let exact = hostnames
+            .iter()
+            .map(|h| {
+                let director = VhostDirector::new(
+                    h.to_string(),
+                    Vec::new(),
+                    Arc::new(BackendPool::new()),
+                    None,
+                    None,
+                    None,
+                    RoutingLog::Off,
+                );
+                (h.to_string(), Arc::new(director))
+            })
+            .collect::<HashMap<_, _>>();
+        VhostDirectorMap {
+            exact,
+            wildcards: Vec::new(),
+            match_order: vec![HostMatchKind::Exact],
+            tls_fingerprint: None,
+            route_key: None,
+            debug_headers: DebugHeaders::Off,
+            routing_log: RoutingLog::Off,
+            tracing: false,
+            trace_sample_percent: 100.0,
+            connection_id_header: None,
+            authorized_private: Vec::new(),
+            sni_header: None,
+            reject_misdirected: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_render_json() {
+        let map = directors(&["b.example.com", "a.example.com"]);
+        let a = &map.exact["a.example.com"];
+        a.stats().record_request("10.0.0.1:80");
+        a.stats().record_request("10.0.0.2:80");
+        a.stats().record_request("10.0.0.1:80");
+        a.stats().record_retry();
+        // Another director for the same vhost, as after a VCL reload
+        let other = directors(&["a.example.com", "we\"ird\\host"]);
+        other.exact["a.example.com"]
+            .stats()
+            .record_request("10.0.0.2:80");
+
+        let out = render_json(
+            map.all_directors()
+                .chain(other.all_directors())
+                .map(|d| d.as_ref()),
+        );
+        let json: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(json["total_requests"], 4);
+        let vhost = &json["vhosts"]["a.example.com"];
+        assert_eq!(vhost["requests"], 4);
+        assert_eq!(vhost["retries"], 1);
+        assert!(vhost["last_request"].as_f64().unwrap() > 1.7e9);
+        assert_eq!(
+            vhost["backends"],
+            serde_json::json!({"10.0.0.1:80": 2, "10.0.0.2:80": 2})
+        );
+        assert_eq!(
+            json["vhosts"]["b.example.com"],
+            serde_json::json!({"requests": 0, "retries": 0, "last_request": null, "backends": {}})
+        );
+        assert_eq!(json["vhosts"]["we\"ird\\host"]["requests"], 0);
+        // Vhosts sorted by name
+        assert!(out.starts_with(r#"{"vhosts":{"a.example.com":{"requests":4,"#));
+
+        assert_eq!(
+            render_json(std::iter::empty()),
+            r#"{"vhosts":{},"total_requests":0}"#
+        );
+    }
+
+    #[test]
+    fn test_render_vhosts() {
+        let map = directors(&["b.example.com", "we\"ird\\host"]);
+        let b = &map.exact["b.example.com"];
+        b.stats().record_request("10.0.0.1:80");
+        b.stats().record_request("10.0.0.2:80");
+        b.stats().record_retry();
+        b.stats().record_upstream_error();
+        map.exact["we\"ird\\host"]
+            .stats()
+            .record_request("10.0.0.1:80");
+        let other = directors(&["b.example.com"]);
+        other.exact["b.example.com"]
+            .stats()
+            .record_request("10.0.0.1:80");
+
+        let out = render_vhosts(
+            map.all_directors()
+                .chain(other.all_directors())
+                .map(|d| d.as_ref()),
+        );
+        let samples: HashMap<&str, &str> = out
+            .lines()
+            .filter(|l| !l.starts_with('#'))
+            .map(|l| l.rsplit_once(' ').unwrap())
+            .collect();
+        let expected = [
+            (r#"ghost_vhost_requests_total{vhost="b.example.com"}"#, "3"),
+            (r#"ghost_vhost_requests_total{vhost="we\"ird\\host"}"#, "1"),
+            (r#"ghost_vhost_retries_total{vhost="b.example.com"}"#, "1"),
+            (r#"ghost_vhost_retries_total{vhost="we\"ird\\host"}"#, "0"),
+            (
+                r#"ghost_vhost_upstream_errors_total{vhost="b.example.com"}"#,
+                "1",
+            ),
+            (
+                r#"ghost_vhost_upstream_errors_total{vhost="we\"ird\\host"}"#,
+                "0",
+            ),
+            (
+                r#"ghost_backend_selections_total{vhost="b.example.com",backend="10.0.0.1:80"}"#,
+                "2",
+            ),
+            (
+                r#"ghost_backend_selections_total{vhost="b.example.com",backend="10.0.0.2:80"}"#,
+                "1",
+            ),
+            (
+                r#"ghost_backend_selections_total{vhost="we\"ird\\host",backend="10.0.0.1:80"}"#,
+                "1",
+            ),
+            (
+                r#"ghost_backend_selection_ratio{vhost="b.example.com",backend="10.0.0.1:80"}"#,
+                "0.6667",
+            ),
+            (
+                r#"ghost_backend_selection_ratio{vhost="b.example.com",backend="10.0.0.2:80"}"#,
+                "0.3333",
+            ),
+            (
+                r#"ghost_backend_selection_ratio{vhost="we\"ird\\host",backend="10.0.0.1:80"}"#,
+                "1.0000",
+            ),
+        ];
+        let timestamps = samples
+            .keys()
+            .filter(|k| k.starts_with("ghost_vhost_last_request_timestamp_seconds{"))
+            .count();
+        assert_eq!(timestamps, 2, "{}", out);
+        assert_eq!(samples.len(), expected.len() + timestamps, "{}", out);
+        for (series, value) in expected {
+            assert_eq!(samples.get(series), Some(&value), "{}", series);
+        }
+
+        // The families and their types are those of the metrics endpoint
+        let endpoint = render(
+            &map,
+            &BackendPool::new(),
+            &ReloadInfo {
+                generation: 0,
+                last_reload: None,
+            },
+        );
+        for header in out.lines().filter(|l| l.starts_with('#')) {
+            assert!(endpoint.contains(header), "{}", header);
+        }
+
+        assert_eq!(
+            render_vhosts(std::iter::empty())
+                .lines()
+                .filter(|l| !l.starts_with('#'))
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_escape_label() {
+        assert_eq!(escape_label("app.example.com"), "app.example.com");
+        assert_eq!(escape_label(r#"a"b\c"#), r#"a\"b\\c"#);
+        assert_eq!(escape_label("a\nb"), "a\\nb");
+    }
+
+    #[test]
+    fn test_histogram() {
+        let h = Histogram::default();
+        h.record(Duration::from_micros(500));
+        h.record(Duration::from_millis(40));
+        h.record(Duration::from_secs(9));
+        let mut out = String::new();
+        histogram(&mut out, "lat", "backend=\"b\"", &h.snapshot());
+        let expected = [
+            "lat_bucket{backend=\"b\",le=\"0.001\"} 1",
+            "lat_bucket{backend=\"b\",le=\"0.005\"} 1",
+            "lat_bucket{backend=\"b\",le=\"0.01\"} 1",
+            "lat_bucket{backend=\"b\",le=\"0.05\"} 2",
+            "lat_bucket{backend=\"b\",le=\"0.1\"} 2",
+            "lat_bucket{backend=\"b\",le=\"0.5\"} 2",
+            "lat_bucket{backend=\"b\",le=\"1\"} 2",
+            "lat_bucket{backend=\"b\",le=\"5\"} 2",
+            "lat_bucket{backend=\"b\",le=\"+Inf\"} 3",
+            "lat_sum{backend=\"b\"} 9.0405",
+            "lat_count{backend=\"b\"} 3",
+        ];
+        assert_eq!(out.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_render() {
+        let map = directors(&["b.example.com", "a.example.com", "we\"ird\\host"]);
+        let a = &map.exact["a.example.com"];
+        a.stats().record_request("10.0.0.1:80");
+        a.stats().record_request("10.0.0.1:80");
+        a.stats().record_request("10.0.0.1:80");
+        a.stats().record_request("10.0.0.2:80");
+        a.stats().record_retry();
+        let mut pool = BackendPool::new();
+        pool.set_rate_limit(
+            "10.0.0.2:80",
+            &crate::config::OutboundRateLimit {
+                rps: 1,
+                burst: None,
+                max_wait_ms: 0,
+            },
+        );
+        let bucket = pool.rate_limit("10.0.0.2:80").unwrap();
+        assert!(bucket.acquire());
+        assert!(!bucket.acquire());
+
+        let out = render(
+            &map,
+            &pool,
+            &ReloadInfo {
+                generation: 3,
+                last_reload: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_500)),
+            },
+        );
+
+        assert!(out.contains("# TYPE ghost_vhost_requests_total counter\n"));
+        assert!(out.contains("ghost_vhost_requests_total{vhost=\"a.example.com\"} 4\n"));
+        assert!(out.contains("ghost_vhost_requests_total{vhost=\"b.example.com\"} 0\n"));
+        assert!(out.contains("ghost_vhost_requests_total{vhost=\"we\\\"ird\\\\host\"} 0\n"));
+        assert!(out.contains("ghost_vhost_retries_total{vhost=\"a.example.com\"} 1\n"));
+        assert!(out.contains(
+            "ghost_backend_selections_total{vhost=\"a.example.com\",backend=\"10.0.0.1:80\"} 3\n"
+        ));
+        assert!(out.contains(
+            "ghost_backend_selection_ratio{vhost=\"a.example.com\",backend=\"10.0.0.2:80\"} 0.2500\n"
+        ));
+        assert!(out.contains("ghost_backend_throttled_total{backend=\"10.0.0.2:80\"} 1\n"));
+        assert!(out.contains("ghost_upstream_errors_total{class=\"timeout\"} "));
+        assert!(out.contains("ghost_config_generation 3\n"));
+        assert!(out.contains("ghost_config_last_reload_timestamp_seconds 1700000000.500\n"));
+        // Only vhosts that served a request have a last request time
+        assert_eq!(
+            out.matches("ghost_vhost_last_request_timestamp_seconds{")
+                .count(),
+            1
+        );
+
+        // Vhosts in name order
+        let a_pos = out.find("vhost=\"a.example.com\"").unwrap();
+        let b_pos = out.find("vhost=\"b.example.com\"").unwrap();
+        assert!(a_pos < b_pos);
+
+        // Every sample line is `name{labels} value` or `name value`
+        for line in out.lines().filter(|l| !l.starts_with('#')) {
+            let (_, value) = line.rsplit_once(' ').unwrap();
+            assert!(value.parse::<f64>().is_ok(), "bad sample: {}", line);
+        }
+    }
+}
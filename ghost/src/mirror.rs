@@ -0,0 +1,336 @@
+//! Fire-and-forget request mirroring (HTTPRoute `RequestMirror`)
+//!
+//! A route with a `request_mirror` filter sends a copy of `percent` (all by
+//! default) of its matched requests to the mirror upstream on the shared
+//! background runtime, while the request itself is routed as usual. The
+//! mirror's response is read to the end and thrown away; its errors and
+//! latency never reach the client. Copies sent and failed are counted in
+//! `GHOST.mirror_requests` and `GHOST.mirror_failures`.
+//!
+//! Routing captures the copy, and the caller sends it once it can get at
+//! the request body. Reading a body that isn't cached would take it from
+//! the primary request, so a request with a body is only mirrored when VCL
+//! cached it with `std.cache_req_body()`, and only up to
+//! [`MAX_MIRROR_BODY_BYTES`].
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use reqwest::{Client, Method};
+use varnish::vcl::{BodyState, Ctx, HttpHeaders};
+
+use crate::config::RequestMirrorFilter;
+use crate::external_backend::{connection_tokens, is_connection_option, is_hop_by_hop, spawn};
+use crate::request_body::CappedBuffer;
+use crate::vhost_director::is_internal_header;
+
+/// Mirrored requests still running after this long are abandoned.
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest request body copied to a mirror. Larger requests aren't
+/// mirrored.
+pub const MAX_MIRROR_BODY_BYTES: usize = 1024 * 1024;
+
+static MIRROR_CLIENT: OnceLock<Client> = OnceLock::new();
+
+fn client() -> &'static Client {
+    MIRROR_CLIENT.get_or_init(|| {
+        reqwest::ClientBuilder::new()
+            .timeout(MIRROR_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("ghost: failed to build request mirror client")
+    })
+}
+
+/// Copy of a client request, addressed to a mirror upstream
+#[derive(Debug)]
+pub struct MirrorRequest {
+    method: Method,
+    url: String,
+    headers: Vec<(String, Vec<u8>)>,
+    body: Option<Vec<u8>>,
+}
+
+/// Whether a request to a route mirroring to `target` gets a copy
+pub fn sampled(target: &RequestMirrorFilter) -> bool {
+    target
+        .percent
+        .is_none_or(|percent| rand::random::<f64>() * 100.0 < percent)
+}
+
+impl MirrorRequest {
+    /// Capture the request in `http` for `target`.
+    /// Returns None if the request carries a body.
+    pub fn from_http(target: &RequestMirrorFilter, http: &HttpHeaders) -> Option<Self> {
+        PendingMirror::from_http(target, http)
+            .filter(|pending| !pending.has_body)
+            .map(|pending| pending.req)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn from_parts<'a>(
+        target: &RequestMirrorFilter,
+        method: &str,
+        path: &str,
+        headers: impl Iterator<Item = (&'a str, &'a [u8])> + Clone,
+    ) -> Option<Self> {
+        Self::capture(target, method, path, headers)
+            .filter(|(_, has_body)| !has_body)
+            .map(|(req, _)| req)
+    }
+
+    /// The copy, and whether the request has a body still to attach
+    fn capture<'a>(
+        target: &RequestMirrorFilter,
+        method: &str,
+        path: &str,
+        headers: impl Iterator<Item = (&'a str, &'a [u8])> + Clone,
+    ) -> Option<(Self, bool)> {
+        let method = Method::from_bytes(method.as_bytes()).ok()?;
+        let options = connection_tokens(
+            headers
+                .clone()
+                .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+                .map(|(_, value)| value),
+        );
+        let mut copied = Vec::new();
+        let mut has_body = false;
+        for (name, value) in headers {
+            has_body |= name.eq_ignore_ascii_case("transfer-encoding")
+                || (name.eq_ignore_ascii_case("content-length") && value.trim_ascii() != b"0");
+            // Host is kept, so the mirror sees the client's virtual host
+            if !is_hop_by_hop(name)
+                && !is_connection_option(name, &options)
+                && !is_internal_header(name)
+            {
+                copied.push((name.to_string(), value.to_vec()));
+            }
+        }
+
+        let scheme = if target.tls { "https" } else { "http" };
+        let host = if target.address.contains(':') {
+            format!("[{}]", target.address)
+        } else {
+            target.address.clone()
+        };
+        let req = Self {
+            method,
+            url: format!("{}://{}:{}{}", scheme, host, target.port, path),
+            headers: copied,
+            body: None,
+        };
+        Some((req, has_body))
+    }
+
+    /// Send the copy in the background, draining and discarding the answer.
+    pub fn send(self) {
+        crate::vsc::incr(|c| &c.mirror_requests);
+        spawn(async move {
+            if self.execute().await.is_err() {
+                crate::vsc::incr(|c| &c.mirror_failures);
+            }
+        });
+    }
+
+    async fn execute(self) -> Result<(), reqwest::Error> {
+        let mut resp = self.build(client()).send().await?;
+        while resp.chunk().await?.is_some() {}
+        Ok(())
+    }
+
+    /// The copy as a request on `client`
+    pub(crate) fn build(self, client: &Client) -> reqwest::RequestBuilder {
+        let mut builder = client.request(self.method, &self.url);
+        for (name, value) in self.headers {
+            builder = builder.header(name, value);
+        }
+        if let Some(body) = self.body {
+            builder = builder.body(body);
+        }
+        builder
+    }
+}
+
+/// A copy captured while routing, sent by the caller once the request
+/// body, if any, can be attached
+#[derive(Debug)]
+pub struct PendingMirror {
+    req: MirrorRequest,
+    has_body: bool,
+}
+
+impl PendingMirror {
+    /// Capture the request in `http` for `target`
+    pub fn from_http(target: &RequestMirrorFilter, http: &HttpHeaders) -> Option<Self> {
+        let method = http.method()?;
+        let url = http.url()?;
+        let headers: Vec<_> = http.into_iter().collect();
+        let (req, has_body) = MirrorRequest::capture(
+            target,
+            std::str::from_utf8(method.as_ref()).ok()?,
+            std::str::from_utf8(url.as_ref()).ok()?,
+            headers.iter().map(|(k, v)| (*k, v.as_ref())),
+        )?;
+        Some(Self { req, has_body })
+    }
+
+    /// Attach the cached request body, if there is one, and send the copy.
+    /// Err says why the request isn't mirrored.
+    pub fn send(mut self, ctx: &mut Ctx) -> Result<(), &'static str> {
+        if self.has_body {
+            if ctx.req_body_state().ok() != Some(BodyState::Cached) {
+                return Err("request body not cached");
+            }
+            let mut buf = CappedBuffer::new(MAX_MIRROR_BODY_BYTES);
+            if ctx.req_body(&mut buf).is_err() {
+                return Err(if buf.overflowed() {
+                    "request body too large"
+                } else {
+                    "request body unreadable"
+                });
+            }
+            // The client may have sent it chunked; reqwest sets the length
+            self.req
+                .headers
+                .retain(|(name, _)| !name.eq_ignore_ascii_case("content-length"));
+            self.req.body = Some(buf.into_inner());
+        }
+        self.req.send();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream;
+    use std::io::Write;
+    use std::sync::mpsc;
+
+    fn target(port: u16) -> RequestMirrorFilter {
+        RequestMirrorFilter {
+            address: "127.0.0.1".to_string(),
+            port,
+            tls: false,
+            percent: None,
+        }
+    }
+
+    fn parts<'a>(method: &str, headers: &'a [(&'a str, &'a str)]) -> Option<MirrorRequest> {
+        MirrorRequest::from_parts(
+            &target(8080),
+            method,
+            "/a?b=1",
+            headers.iter().map(|(k, v)| (*k, v.as_bytes())),
+        )
+    }
+
+    #[test]
+    fn test_from_parts_copies_end_to_end_headers() {
+        let req = parts(
+            "GET",
+            &[
+                ("Host", "app.example.com"),
+                ("Accept", "text/html"),
+                ("Connection", "keep-alive, X-Hop"),
+                ("X-Hop", "1"),
+                ("X-Ghost-Pass", "true"),
+                ("Content-Length", "0"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(req.method, Method::GET);
+        assert_eq!(req.url, "http://127.0.0.1:8080/a?b=1");
+        let names: Vec<&str> = req.headers.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(names, vec!["Host", "Accept", "Content-Length"]);
+    }
+
+    #[test]
+    fn test_from_parts_skips_requests_with_body() {
+        assert!(parts("POST", &[("Content-Length", "12")]).is_none());
+        assert!(parts("POST", &[("Transfer-Encoding", "chunked")]).is_none());
+        assert!(parts("POST", &[]).is_some());
+    }
+
+    #[test]
+    fn test_capture_keeps_requests_with_body() {
+        let headers = [("Content-Length", "12")];
+        let (req, has_body) = MirrorRequest::capture(
+            &target(8080),
+            "POST",
+            "/",
+            headers.iter().map(|(k, v)| (*k, v.as_bytes())),
+        )
+        .unwrap();
+        assert!(has_body);
+        let mut req = PendingMirror { req, has_body };
+        req.req.body = Some(b"hello mirror".to_vec());
+        let built = req.req.build(&Client::new()).build().unwrap();
+        assert_eq!(
+            built.body().and_then(|b| b.as_bytes()),
+            Some(&b"hello mirror"[..])
+        );
+    }
+
+    #[test]
+    fn test_sampled() {
+        let mut t = target(8080);
+        assert!((0..100).all(|_| sampled(&t)));
+        t.percent = Some(100.0);
+        assert!((0..100).all(|_| sampled(&t)));
+        t.percent = Some(0.0);
+        assert!((0..100).all(|_| !sampled(&t)));
+    }
+
+    #[test]
+    fn test_from_parts_brackets_ipv6() {
+        let mut t = target(8080);
+        t.address = "fd00::1".to_string();
+        let req = MirrorRequest::from_parts(&t, "GET", "/", std::iter::empty()).unwrap();
+        assert_eq!(req.url, "http://[fd00::1]:8080/");
+    }
+
+    #[test]
+    fn test_send_reaches_mirror() {
+        let (tx, rx) = mpsc::channel();
+        let upstream = mock_upstream::once(move |conn, head| {
+            let lines: Vec<String> = head
+                .lines()
+                .take_while(|line| !line.is_empty())
+                .map(str::to_string)
+                .collect();
+            let _ = conn.write_all(b"HTTP/1.1 500 Oops\r\ncontent-length: 4\r\n\r\noops");
+            tx.send(lines).unwrap();
+        });
+        let port = upstream.addr().port();
+
+        let headers = [("Host", "app.example.com"), ("X-Test", "1")];
+        MirrorRequest::from_parts(
+            &target(port),
+            "GET",
+            "/mirrored?q=1",
+            headers.iter().map(|(k, v)| (*k, v.as_bytes())),
+        )
+        .unwrap()
+        .send();
+
+        let lines = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(lines[0], "GET /mirrored?q=1 HTTP/1.1");
+        assert!(lines
+            .iter()
+            .any(|l| l.eq_ignore_ascii_case("host: app.example.com")));
+        assert!(lines.iter().any(|l| l.eq_ignore_ascii_case("x-test: 1")));
+    }
+
+    #[test]
+    fn test_send_to_unreachable_mirror_is_silent() {
+        let port = mock_upstream::closed_port();
+        let req = MirrorRequest::from_parts(&target(port), "GET", "/", std::iter::empty()).unwrap();
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        assert!(rt.block_on(req.execute()).is_err());
+    }
+}
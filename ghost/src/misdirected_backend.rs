@@ -0,0 +1,87 @@
+//! Synthetic 421 backend for misdirected requests
+//!
+//! This backend generates 421 responses when a vhost with `reject_misdirected`
+//! gets a request over a TLS connection whose SNI names another vhost, so an
+//! HTTP/2 client that coalesced the connection retries on a new one.
+
+use varnish::vcl::{Ctx, VclBackend, VclError, VclResponse};
+
+/// Backend that generates synthetic 421 responses
+pub struct MisdirectedBackend;
+
+impl VclBackend<MisdirectedBody> for MisdirectedBackend {
+    fn get_response(&self, ctx: &mut Ctx) -> Result<Option<MisdirectedBody>, VclError> {
+        let beresp = ctx
+            .http_beresp
+            .as_mut()
+            .ok_or_else(|| VclError::new("Missing beresp in misdirected backend".to_string()))?;
+        beresp.set_status(421);
+        beresp.set_header("Content-Type", "text/plain")?;
+        beresp.set_header("Cache-Control", "no-store")?;
+
+        Ok(Some(MisdirectedBody::new()))
+    }
+}
+
+/// Response body for 421 error
+pub struct MisdirectedBody {
+    data: &'static [u8],
+    cursor: usize,
+}
+
+impl MisdirectedBody {
+    /// Create a new 421 response body
+    pub fn new() -> Self {
+        Self {
+            data: b"misdirected request",
+            cursor: 0,
+        }
+    }
+}
+
+impl VclResponse for MisdirectedBody {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, VclError> {
+        let remaining = &self.data[self.cursor..];
+        let to_copy = remaining.len().min(buf.len());
+
+        buf[..to_copy].copy_from_slice(&remaining[..to_copy]);
+        self.cursor += to_copy;
+
+        Ok(to_copy)
+    }
+
+    fn len(&self) -> Option<usize> {
+        Some(self.data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_misdirected_body_new() {
+        let body = MisdirectedBody::new();
+        assert_eq!(body.cursor, 0);
+    }
+
+    #[test]
+    fn test_misdirected_body_len() {
+        let body = MisdirectedBody::new();
+        assert_eq!(body.len(), Some(19));
+    }
+
+    #[test]
+    fn test_misdirected_body_read() {
+        let mut body = MisdirectedBody::new();
+        let mut buf = vec![0u8; 100];
+
+        let n = body.read(&mut buf).unwrap();
+        assert_eq!(n, 19);
+        assert_eq!(&buf[..n], b"misdirected request");
+
+        // Second read should return 0 (EOF)
+        let n = body.read(&mut buf).unwrap();
+        assert_eq!(n, 0);
+    }
+}
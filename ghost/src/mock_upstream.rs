@@ -0,0 +1,115 @@
+//! Plain HTTP/1 upstreams for unit tests, on 127.0.0.1 ports of their own.
+//!
+//! Each runs on a thread of its own, so tests on the background runtime or
+//! a `#[tokio::test]` runtime can use them alike. Upstreams speaking h2 or
+//! TLS set up their own listeners.
+
+use std::io::Read;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// An upstream serving one connection, see [`once`].
+pub(crate) struct MockUpstream<T> {
+    addr: SocketAddr,
+    server: JoinHandle<T>,
+}
+
+impl<T> MockUpstream<T> {
+    pub(crate) fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// `http://<addr><path>`
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("http://{}{}", self.addr, path)
+    }
+
+    /// Wait for the connection to be served, and get what the handler
+    /// returned. Panics in the handler are raised here.
+    pub(crate) fn join(self) -> T {
+        self.server.join().unwrap()
+    }
+}
+
+/// Accept one connection, read the request head and hand it to `handler`
+/// with the stream to answer on. The head is whatever was read up to its
+/// blank line, so it may hold the start of the body too, or the first read
+/// alone if that isn't HTTP (a TLS ClientHello).
+pub(crate) fn once<T, F>(handler: F) -> MockUpstream<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut TcpStream, String) -> T + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let head = read_head(&mut stream);
+        handler(&mut stream, head)
+    });
+    MockUpstream { addr, server }
+}
+
+/// Accept one connection, read the request and never answer, holding the
+/// connection until the client gives up on it.
+pub(crate) fn silent() -> MockUpstream<()> {
+    once(|stream, _| {
+        let _ = stream.read_to_end(&mut Vec::new());
+    })
+}
+
+/// Answer every connection with what `respond` returns for its request
+/// head, then close it. The server lives as long as the test binary.
+pub(crate) fn every<F>(respond: F) -> SocketAddr
+where
+    F: Fn(&str) -> String + Send + 'static,
+{
+    use std::io::Write;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let head = read_head(&mut stream);
+            let _ = stream.write_all(respond(&head).as_bytes());
+        }
+    });
+    addr
+}
+
+/// Answer every request with the status `status` holds at the time, and
+/// no body: a health endpoint tests can fail and heal.
+pub(crate) fn status(status: Arc<AtomicU16>) -> SocketAddr {
+    every(move |_| {
+        format!(
+            "HTTP/1.1 {} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            status.load(Ordering::Relaxed)
+        )
+    })
+}
+
+/// A port nothing listens on: bound, then released.
+pub(crate) fn closed_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn read_head(stream: &mut TcpStream) -> String {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 4096];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf) {
+            Ok(n) if n > 0 => head.extend_from_slice(&buf[..n]),
+            _ => break,
+        }
+        if !head[0].is_ascii_alphabetic() {
+            break;
+        }
+    }
+    String::from_utf8_lossy(&head).into_owned()
+}
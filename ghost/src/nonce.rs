@@ -0,0 +1,268 @@
+//! Replay protection for routes with a `nonce` filter.
+//!
+//! Each request must carry a nonce in the filter's header, one the route
+//! hasn't seen within `window_seconds`. Routing checks it as the route
+//! matches: a request without a usable nonce is answered 400, a replayed
+//! one 409 and one the store has no room for 503, all by the synthetic
+//! backend of [`REJECTED_HEADER`]. An
+//! accepted request goes upstream with the filter's `validated_header` set,
+//! any client copy of it replaced.
+//!
+//! The nonces a route has seen are kept in a [`NonceStore`], bounded by
+//! `max_nonces`. A full shard forgets expired nonces, and while none has
+//! expired it refuses new ones: forgetting a nonce still in its window
+//! would let it be replayed, so the store fails closed instead.
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::config::NonceFilter;
+
+/// Why a request was rejected, from routing to the rejection backend
+pub(crate) const REJECTED_HEADER: &str = "X-Ghost-Nonce-Rejected";
+
+/// Longest nonce accepted. Longer ones count as missing.
+pub const MAX_NONCE_LEN: usize = 256;
+
+/// Seen nonces are spread over this many independently locked shards, as
+/// the buckets of a `ClientRateLimiter` are.
+const SHARDS: usize = 16;
+
+/// Why a nonce was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// No nonce, an empty one or one over [`MAX_NONCE_LEN`]
+    Missing,
+    /// Seen within the window
+    Replayed,
+    /// New, but the store is full of nonces still in their window
+    Full,
+}
+
+impl Rejection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Rejection::Missing => "missing",
+            Rejection::Replayed => "replayed",
+            Rejection::Full => "full",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "missing" => Some(Rejection::Missing),
+            "replayed" => Some(Rejection::Replayed),
+            "full" => Some(Rejection::Full),
+            _ => None,
+        }
+    }
+
+    pub fn status(self) -> u16 {
+        match self {
+            Rejection::Missing => 400,
+            Rejection::Replayed => 409,
+            Rejection::Full => 503,
+        }
+    }
+}
+
+/// Nonces a route has seen, with when it first saw them.
+#[derive(Debug)]
+pub struct NonceStore {
+    filter: NonceFilter,
+    hasher: RandomState,
+    shards: Vec<Mutex<Shard>>,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    seen: HashMap<String, Instant>,
+    /// No nonce in `seen` was first seen earlier. Lets a full shard with
+    /// nothing expired refuse a nonce without a scan.
+    oldest: Option<Instant>,
+}
+
+impl NonceStore {
+    pub fn new(filter: &NonceFilter) -> Self {
+        Self {
+            filter: filter.clone(),
+            hasher: RandomState::new(),
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    pub fn filter(&self) -> &NonceFilter {
+        &self.filter
+    }
+
+    /// Accept `nonce` and remember it, unless it is missing, was seen
+    /// within the window, or there's no room for it.
+    pub fn check(&self, nonce: Option<&str>) -> Result<(), Rejection> {
+        self.check_at(nonce, Instant::now())
+    }
+
+    fn check_at(&self, nonce: Option<&str>, now: Instant) -> Result<(), Rejection> {
+        let nonce = nonce
+            .map(str::trim)
+            .filter(|n| !n.is_empty() && n.len() <= MAX_NONCE_LEN)
+            .ok_or(Rejection::Missing)?;
+        let window = Duration::from_secs(self.filter.window_seconds);
+        let shard = &self.shards[self.hasher.hash_one(nonce) as usize % self.shards.len()];
+        let mut shard = shard.lock();
+        match shard.seen.get(nonce) {
+            Some(first) if now.saturating_duration_since(*first) < window => {
+                return Err(Rejection::Replayed)
+            }
+            Some(_) => {}
+            None => {
+                let capacity = self.filter.max_nonces.div_ceil(self.shards.len());
+                if shard.seen.len() >= capacity && !shard.evict(now, window) {
+                    return Err(Rejection::Full);
+                }
+            }
+        }
+        shard.seen.insert(nonce.to_string(), now);
+        shard.oldest.get_or_insert(now);
+        Ok(())
+    }
+
+    /// Nonces currently remembered
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().seen.len()).sum()
+    }
+}
+
+impl Shard {
+    /// Make room in a full shard by dropping the nonces whose window is
+    /// over by `now`. False when none is: every nonce still guards against
+    /// a replay. Scans only once the oldest nonce has expired, so each scan
+    /// frees at least one entry.
+    fn evict(&mut self, now: Instant, window: Duration) -> bool {
+        match self.oldest {
+            Some(oldest) if now.saturating_duration_since(oldest) >= window => {}
+            _ => return false,
+        }
+        let before = self.seen.len();
+        self.seen
+            .retain(|_, first| now.saturating_duration_since(*first) < window);
+        self.oldest = self.seen.values().min().copied();
+        self.seen.len() < before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(window_seconds: u64, max_nonces: usize) -> NonceStore {
+        NonceStore::new(&NonceFilter {
+            header: "X-Nonce".to_string(),
+            window_seconds,
+            max_nonces,
+            validated_header: "X-Nonce-Validated".to_string(),
+        })
+    }
+
+    #[test]
+    fn test_replay_within_window() {
+        let s = store(300, 100);
+        let now = Instant::now();
+        assert_eq!(s.check_at(Some("a1"), now), Ok(()));
+        assert_eq!(s.check_at(Some("b2"), now), Ok(()));
+        assert_eq!(
+            s.check_at(Some("a1"), now + Duration::from_secs(299)),
+            Err(Rejection::Replayed)
+        );
+        // The window counts from the first use, not the replays
+        assert_eq!(
+            s.check_at(Some("a1"), now + Duration::from_secs(300)),
+            Ok(())
+        );
+        assert_eq!(
+            s.check_at(Some("a1"), now + Duration::from_secs(301)),
+            Err(Rejection::Replayed)
+        );
+    }
+
+    #[test]
+    fn test_missing_nonce() {
+        let s = store(300, 100);
+        let now = Instant::now();
+        assert_eq!(s.check_at(None, now), Err(Rejection::Missing));
+        assert_eq!(s.check_at(Some(" "), now), Err(Rejection::Missing));
+        let long = "x".repeat(MAX_NONCE_LEN + 1);
+        assert_eq!(s.check_at(Some(&long), now), Err(Rejection::Missing));
+        assert_eq!(s.len(), 0);
+        assert_eq!(Rejection::Missing.status(), 400);
+        assert_eq!(Rejection::Replayed.status(), 409);
+        assert_eq!(Rejection::from_name("replayed"), Some(Rejection::Replayed));
+    }
+
+    #[test]
+    fn test_bounded() {
+        let s = store(300, SHARDS * 2);
+        let now = Instant::now();
+        for i in 0..1000 {
+            let _ = s.check_at(Some(&format!("nonce-{}", i)), now);
+        }
+        assert!(s.len() <= SHARDS * 2);
+
+        // Expired nonces make room
+        let mut shard = Shard {
+            seen: HashMap::from([
+                ("expired".to_string(), now - Duration::from_secs(400)),
+                ("old".to_string(), now - Duration::from_secs(200)),
+                ("new".to_string(), now),
+            ]),
+            oldest: Some(now - Duration::from_secs(400)),
+        };
+        assert!(shard.evict(now, Duration::from_secs(300)));
+        let mut left: Vec<_> = shard.seen.keys().map(String::as_str).collect();
+        left.sort();
+        assert_eq!(left, ["new", "old"]);
+        assert_eq!(shard.oldest, Some(now - Duration::from_secs(200)));
+
+        // Nothing else is forgotten before its window ends
+        assert!(!shard.evict(now, Duration::from_secs(300)));
+        assert_eq!(shard.seen.len(), 2);
+    }
+
+    #[test]
+    fn test_flood_never_readmits_a_nonce_in_its_window() {
+        let s = store(300, SHARDS * 4);
+        let now = Instant::now();
+        assert_eq!(s.check_at(Some("victim"), now), Ok(()));
+
+        // Far more fresh nonces than the store holds, all within the window
+        let mut full = 0;
+        for i in 0..10_000u64 {
+            let at = now + Duration::from_millis(i);
+            match s.check_at(Some(&format!("flood-{}", i)), at) {
+                Ok(()) => {}
+                Err(Rejection::Full) => full += 1,
+                Err(r) => panic!("unexpected {:?}", r),
+            }
+            if i % 1000 == 0 {
+                assert_eq!(s.check_at(Some("victim"), at), Err(Rejection::Replayed));
+            }
+        }
+        assert!(full > 0);
+        assert!(s.len() <= SHARDS * 4);
+        assert_eq!(
+            s.check_at(Some("victim"), now + Duration::from_secs(299)),
+            Err(Rejection::Replayed)
+        );
+
+        // Once the window is over, the store has room again
+        let later = now + Duration::from_secs(311);
+        assert_eq!(s.check_at(Some("fresh"), later), Ok(()));
+        assert_eq!(s.check_at(Some("victim"), later), Ok(()));
+        assert_eq!(Rejection::Full.status(), 503);
+        assert_eq!(Rejection::from_name("full"), Some(Rejection::Full));
+    }
+}
@@ -0,0 +1,106 @@
+//! Synthetic 400/409/503 backend for requests a `nonce` filter refuses
+//!
+//! Answers requests without a usable nonce with a 400, replays with a 409
+//! and nonces a full store can't take with a 503, going by the reason
+//! routing left in [`REJECTED_HEADER`].
+
+use varnish::vcl::{Ctx, StrOrBytes, VclBackend, VclError, VclResponse};
+
+use crate::internal_headers;
+use crate::nonce::{Rejection, REJECTED_HEADER};
+
+const MISSING_BODY: &[u8] = b"{\"error\":\"nonce_missing\",\"status\":400}\n";
+const REPLAYED_BODY: &[u8] = b"{\"error\":\"nonce_replayed\",\"status\":409}\n";
+const FULL_BODY: &[u8] = b"{\"error\":\"nonce_store_full\",\"status\":503}\n";
+
+/// Backend that generates synthetic nonce rejections
+pub struct NonceRejectedBackend;
+
+impl VclBackend<NonceRejectedBody> for NonceRejectedBackend {
+    fn get_response(&self, ctx: &mut Ctx) -> Result<Option<NonceRejectedBody>, VclError> {
+        internal_headers::restore(ctx);
+        let bereq = ctx
+            .http_bereq
+            .as_mut()
+            .ok_or_else(|| VclError::new("Missing bereq in nonce backend".to_string()))?;
+        let rejection = match bereq.header(REJECTED_HEADER) {
+            Some(StrOrBytes::Utf8(s)) => Rejection::from_name(s),
+            _ => None,
+        }
+        .unwrap_or(Rejection::Missing);
+        bereq.unset_header(REJECTED_HEADER);
+
+        let beresp = ctx
+            .http_beresp
+            .as_mut()
+            .ok_or_else(|| VclError::new("Missing beresp in nonce backend".to_string()))?;
+        crate::vsc::incr(|c| &c.nonce_rejected);
+        beresp.set_status(rejection.status());
+        beresp.set_header("Content-Type", "application/json")?;
+        beresp.set_header("Cache-Control", "no-store")?;
+
+        Ok(Some(NonceRejectedBody::new(rejection)))
+    }
+}
+
+/// Response body for a nonce rejection
+pub struct NonceRejectedBody {
+    data: &'static [u8],
+    cursor: usize,
+}
+
+impl NonceRejectedBody {
+    fn new(rejection: Rejection) -> Self {
+        Self {
+            data: match rejection {
+                Rejection::Missing => MISSING_BODY,
+                Rejection::Replayed => REPLAYED_BODY,
+                Rejection::Full => FULL_BODY,
+            },
+            cursor: 0,
+        }
+    }
+}
+
+impl VclResponse for NonceRejectedBody {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, VclError> {
+        let remaining = &self.data[self.cursor..];
+        let to_copy = remaining.len().min(buf.len());
+
+        buf[..to_copy].copy_from_slice(&remaining[..to_copy]);
+        self.cursor += to_copy;
+
+        Ok(to_copy)
+    }
+
+    fn len(&self) -> Option<usize> {
+        Some(self.data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nonce_rejected_body_read() {
+        let mut body = NonceRejectedBody::new(Rejection::Replayed);
+        assert_eq!(body.len(), Some(REPLAYED_BODY.len()));
+
+        let mut buf = vec![0u8; 100];
+        let n = body.read(&mut buf).unwrap();
+        assert_eq!(
+            &buf[..n],
+            b"{\"error\":\"nonce_replayed\",\"status\":409}\n"
+        );
+
+        // Second read should return 0 (EOF)
+        let n = body.read(&mut buf).unwrap();
+        assert_eq!(n, 0);
+
+        let body = NonceRejectedBody::new(Rejection::Missing);
+        assert_eq!(body.data, MISSING_BODY);
+        let body = NonceRejectedBody::new(Rejection::Full);
+        assert_eq!(body.data, FULL_BODY);
+    }
+}
@@ -15,6 +15,7 @@ impl VclBackend<NotFoundBody> for NotFoundBackend {
             .http_beresp
             .as_mut()
             .ok_or_else(|| VclError::new("Missing beresp in not_found backend".to_string()))?;
+        crate::vsc::incr(|c| &c.synth_404);
         beresp.set_status(404);
         beresp.set_header("Content-Type", "text/plain")?;
         beresp.set_header("Cache-Control", "no-store")?;
@@ -0,0 +1,427 @@
+//! Passive outlier detection: eject backends after consecutive failures.
+//!
+//! Each backend key counts consecutive failed requests (connection errors,
+//! timeouts, 5xx responses). Reaching the threshold ejects the backend for a
+//! cool-down; selection skips it until the cool-down ends, after which it
+//! gets a fresh failure count. A backend ejected again before any request to
+//! it succeeds sits out twice as long each time, up to a cap; one success
+//! resets the backoff. Time is passed in so tests can drive a fake clock.
+//!
+//! Each key's state lives in an [`OutlierSlot`] the backend pool resolves
+//! when it builds the backend, as it does the in-flight counter. Selection
+//! checks a candidate with one atomic load, and a backend reporting an
+//! outcome only locks its own slot.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwapOption;
+use parking_lot::Mutex;
+
+use crate::config::OutlierDetectionConfig;
+
+/// Cap used when the config doesn't set `max_ejection_ms`.
+const DEFAULT_MAX_EJECTION: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Default)]
+struct OutlierState {
+    consecutive_failures: u32,
+    ejected_until: Option<Instant>,
+    /// Ejections since the last successful request.
+    ejections: u32,
+}
+
+/// An ejected backend, for `backend.list -p`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ejection {
+    pub key: String,
+    pub remaining: Duration,
+    pub ejections: u32,
+}
+
+/// Cool-down for the `ejections`-th ejection in a row.
+fn ejection_duration(config: &OutlierDetectionConfig, ejections: u32) -> Duration {
+    let base = Duration::from_millis(config.ejection_ms);
+    let max = config
+        .max_ejection_ms
+        .map_or(DEFAULT_MAX_EJECTION, Duration::from_millis)
+        .max(base);
+    let factor = 1u32 << ejections.saturating_sub(1).min(16);
+    base.saturating_mul(factor).min(max)
+}
+
+/// Origin of the instants slots store as nanoseconds
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+fn nanos_since_epoch(t: Instant) -> u64 {
+    t.saturating_duration_since(*EPOCH).as_nanos() as u64
+}
+
+/// One backend's failure tracking.
+#[derive(Debug, Default)]
+pub struct OutlierSlot {
+    /// End of the current ejection in nanoseconds since `EPOCH`, 0 when
+    /// not ejected. Mirrors `state`, for selection to read without a lock.
+    ejected_until: AtomicU64,
+    state: Mutex<OutlierState>,
+}
+
+impl OutlierSlot {
+    /// Whether the backend is currently ejected.
+    pub fn is_ejected(&self) -> bool {
+        self.is_ejected_at(Instant::now())
+    }
+
+    fn is_ejected_at(&self, now: Instant) -> bool {
+        nanos_since_epoch(now) < self.ejected_until.load(Ordering::Relaxed)
+    }
+
+    fn record_at(&self, config: &OutlierDetectionConfig, success: bool, now: Instant) {
+        let mut s = self.state.lock();
+        if success {
+            s.consecutive_failures = 0;
+            // A success after readmission ends the backoff.
+            if s.ejected_until.is_none_or(|until| now >= until) {
+                s.ejected_until = None;
+                s.ejections = 0;
+                self.ejected_until.store(0, Ordering::Relaxed);
+            }
+            return;
+        }
+        // Requests already in flight when the backend was ejected don't
+        // extend the cool-down.
+        if s.ejected_until.is_some_and(|until| now < until) {
+            return;
+        }
+        s.consecutive_failures += 1;
+        if s.consecutive_failures >= config.consecutive_failures {
+            s.consecutive_failures = 0;
+            s.ejections = s.ejections.saturating_add(1);
+            let until = now + ejection_duration(config, s.ejections);
+            s.ejected_until = Some(until);
+            self.ejected_until
+                .store(nanos_since_epoch(until).max(1), Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&self) {
+        *self.state.lock() = OutlierState::default();
+        self.ejected_until.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Per-backend failure tracking, shared by the backend pool and the
+/// backends that report request outcomes.
+#[derive(Debug, Default)]
+pub struct OutlierDetector {
+    /// None disables detection.
+    config: ArcSwapOption<OutlierDetectionConfig>,
+    /// Only locked to hand out a slot and for diagnostics
+    slots: Mutex<HashMap<String, Arc<OutlierSlot>>>,
+}
+
+impl OutlierDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Apply thresholds from a new config. Disabling detection or changing
+    /// its settings readmits every ejected backend; reloading the same
+    /// settings keeps the current state.
+    pub fn configure(&self, config: Option<&OutlierDetectionConfig>) {
+        if self.config.load().as_deref() != config {
+            for slot in self.slots.lock().values() {
+                slot.reset();
+            }
+            self.config.store(config.cloned().map(Arc::new));
+        }
+    }
+
+    /// The slot tracking `key`, created on first use.
+    pub fn slot(&self, key: &str) -> Arc<OutlierSlot> {
+        Arc::clone(self.slots.lock().entry(key.to_string()).or_default())
+    }
+
+    /// Record the outcome of one request to `key`.
+    #[cfg(test)]
+    pub fn record(&self, key: &str, success: bool) {
+        self.record_at(key, success, Instant::now());
+    }
+
+    #[cfg(test)]
+    fn record_at(&self, key: &str, success: bool, now: Instant) {
+        self.record_in(&self.slot(key), success, now);
+    }
+
+    fn record_in(&self, slot: &OutlierSlot, success: bool, now: Instant) {
+        if let Some(config) = self.config.load().as_deref() {
+            slot.record_at(config, success, now);
+        }
+    }
+
+    /// Whether `key` is currently ejected.
+    #[cfg(test)]
+    pub fn is_ejected(&self, key: &str) -> bool {
+        self.is_ejected_at(key, Instant::now())
+    }
+
+    #[cfg(test)]
+    fn is_ejected_at(&self, key: &str, now: Instant) -> bool {
+        self.slots
+            .lock()
+            .get(key)
+            .is_some_and(|s| s.is_ejected_at(now))
+    }
+
+    /// Currently ejected backend keys (for diagnostics)
+    pub fn ejected_keys(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut keys: Vec<String> = self
+            .slots
+            .lock()
+            .iter()
+            .filter(|(_, s)| s.is_ejected_at(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    /// Currently ejected backends with their remaining cool-down, sorted by
+    /// key.
+    pub fn ejections(&self) -> Vec<Ejection> {
+        self.ejections_at(Instant::now())
+    }
+
+    fn ejections_at(&self, now: Instant) -> Vec<Ejection> {
+        let mut ejected: Vec<Ejection> = self
+            .slots
+            .lock()
+            .iter()
+            .filter_map(|(key, slot)| {
+                let s = slot.state.lock();
+                let until = s.ejected_until.filter(|until| now < *until)?;
+                Some(Ejection {
+                    key: key.clone(),
+                    remaining: until - now,
+                    ejections: s.ejections,
+                })
+            })
+            .collect();
+        ejected.sort_by(|a, b| a.key.cmp(&b.key));
+        ejected
+    }
+
+    /// Drop state for backends that left the pool.
+    pub fn retain(&self, keep: impl Fn(&str) -> bool) {
+        // A backend still draining, or still held by an older pool, keeps
+        // its slot, which it gets back if a reload revives it
+        self.slots
+            .lock()
+            .retain(|key, slot| keep(key) || Arc::strong_count(slot) > 1);
+    }
+}
+
+/// Handle a backend uses to report its own request outcomes.
+#[derive(Debug, Clone)]
+pub struct OutcomeRecorder {
+    detector: Arc<OutlierDetector>,
+    slot: Arc<OutlierSlot>,
+}
+
+impl OutcomeRecorder {
+    pub fn new(detector: Arc<OutlierDetector>, slot: Arc<OutlierSlot>) -> Self {
+        Self { detector, slot }
+    }
+
+    pub fn success(&self) {
+        self.detector.record_in(&self.slot, true, Instant::now());
+    }
+
+    pub fn failure(&self) {
+        self.detector.record_in(&self.slot, false, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(consecutive_failures: u32, ejection_ms: u64) -> OutlierDetector {
+        let d = OutlierDetector::new();
+        d.configure(Some(&OutlierDetectionConfig {
+            consecutive_failures,
+            ejection_ms,
+            max_ejection_ms: Some(4 * ejection_ms),
+        }));
+        d
+    }
+
+    #[test]
+    fn test_eject_after_consecutive_failures_and_recover() {
+        let d = detector(3, 1000);
+        let t0 = Instant::now();
+
+        d.record_at("b1", false, t0);
+        d.record_at("b1", false, t0);
+        assert!(!d.is_ejected_at("b1", t0));
+        d.record_at("b1", false, t0);
+        assert!(d.is_ejected_at("b1", t0));
+        assert!(d.is_ejected_at("b1", t0 + Duration::from_millis(999)));
+
+        // Cool-down over: readmitted with a clean slate.
+        let t1 = t0 + Duration::from_millis(1000);
+        assert!(!d.is_ejected_at("b1", t1));
+        d.record_at("b1", false, t1);
+        assert!(!d.is_ejected_at("b1", t1));
+    }
+
+    #[test]
+    fn test_success_resets_failure_count() {
+        let d = detector(2, 1000);
+        let t0 = Instant::now();
+
+        d.record_at("b1", false, t0);
+        d.record_at("b1", true, t0);
+        d.record_at("b1", false, t0);
+        assert!(!d.is_ejected_at("b1", t0));
+        d.record_at("b1", false, t0);
+        assert!(d.is_ejected_at("b1", t0));
+    }
+
+    #[test]
+    fn test_failures_while_ejected_dont_extend_cooldown() {
+        let d = detector(1, 1000);
+        let t0 = Instant::now();
+
+        d.record_at("b1", false, t0);
+        d.record_at("b1", false, t0 + Duration::from_millis(900));
+        assert!(!d.is_ejected_at("b1", t0 + Duration::from_millis(1000)));
+    }
+
+    #[test]
+    fn test_disabled_never_ejects() {
+        let d = OutlierDetector::new();
+        for _ in 0..100 {
+            d.record("b1", false);
+        }
+        assert!(!d.is_ejected("b1"));
+
+        // Turning detection off readmits ejected backends.
+        let d = detector(1, 60_000);
+        d.record("b1", false);
+        assert_eq!(d.ejected_keys(), vec!["b1".to_string()]);
+        d.configure(None);
+        assert!(!d.is_ejected("b1"));
+    }
+
+    #[test]
+    fn test_repeat_ejections_back_off_until_success() {
+        let d = detector(2, 1000);
+        let mut now = Instant::now();
+        let fail_twice = |now: Instant| {
+            d.record_at("b1", false, now);
+            d.record_at("b1", false, now);
+        };
+
+        // 1s, 2s, 4s, then capped at max_ejection_ms (4s)
+        for expected in [1000, 2000, 4000, 4000] {
+            fail_twice(now);
+            let ejected = d.ejections_at(now);
+            assert_eq!(ejected.len(), 1);
+            assert_eq!(ejected[0].remaining, Duration::from_millis(expected));
+            now += Duration::from_millis(expected);
+            assert!(!d.is_ejected_at("b1", now));
+        }
+        assert_eq!(
+            d.ejections_at(now - Duration::from_millis(1))[0].ejections,
+            4
+        );
+
+        // A success after readmission resets the backoff
+        d.record_at("b1", true, now);
+        fail_twice(now);
+        assert_eq!(
+            d.ejections_at(now)[0].remaining,
+            Duration::from_millis(1000)
+        );
+    }
+
+    #[test]
+    fn test_success_while_ejected_keeps_backoff() {
+        let d = detector(1, 1000);
+        let t0 = Instant::now();
+        d.record_at("b1", false, t0);
+        // An in-flight request finishing during the cool-down
+        d.record_at("b1", true, t0 + Duration::from_millis(10));
+        let t1 = t0 + Duration::from_millis(1000);
+        d.record_at("b1", false, t1);
+        assert_eq!(d.ejections_at(t1)[0].remaining, Duration::from_millis(2000));
+    }
+
+    #[test]
+    fn test_reconfigure_resets_only_on_change() {
+        let d = detector(1, 60_000);
+        d.record("b1", false);
+
+        // Same settings on reload: still ejected
+        d.configure(Some(&OutlierDetectionConfig {
+            consecutive_failures: 1,
+            ejection_ms: 60_000,
+            max_ejection_ms: Some(240_000),
+        }));
+        assert!(d.is_ejected("b1"));
+
+        // New thresholds readmit everything
+        d.configure(Some(&OutlierDetectionConfig {
+            consecutive_failures: 3,
+            ejection_ms: 60_000,
+            max_ejection_ms: None,
+        }));
+        assert!(!d.is_ejected("b1"));
+        assert!(d.ejections().is_empty());
+    }
+
+    #[test]
+    fn test_recorder_and_selection_share_a_slot() {
+        let d = Arc::new(detector(2, 60_000));
+        let slot = d.slot("b1");
+        let recorder = OutcomeRecorder::new(Arc::clone(&d), d.slot("b1"));
+        recorder.failure();
+        assert!(!slot.is_ejected());
+        recorder.failure();
+        assert!(slot.is_ejected());
+        assert_eq!(d.ejected_keys(), vec!["b1".to_string()]);
+
+        // A backend leaving the pool while still held keeps its slot, and
+        // gets it back when revived
+        drop(slot);
+        d.retain(|_| false);
+        assert!(d.slot("b1").is_ejected());
+        drop(recorder);
+        d.retain(|_| false);
+        assert!(!d.slot("b1").is_ejected());
+    }
+
+    #[test]
+    fn test_reconfigure_resets_held_slots() {
+        let d = detector(1, 60_000);
+        let slot = d.slot("b1");
+        d.record("b1", false);
+        assert!(slot.is_ejected());
+        d.configure(None);
+        assert!(!slot.is_ejected());
+        assert!(d.ejections().is_empty());
+    }
+
+    #[test]
+    fn test_keys_are_independent() {
+        let d = detector(1, 1000);
+        let t0 = Instant::now();
+        d.record_at("b1", false, t0);
+        assert!(d.is_ejected_at("b1", t0));
+        assert!(!d.is_ejected_at("b2", t0));
+    }
+}
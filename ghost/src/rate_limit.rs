@@ -0,0 +1,417 @@
+//! Outbound request rate limiting per backend.
+//!
+//! A backend group's `outbound_rate_limit` gives each of its backends a
+//! token bucket, refilled at `rps` tokens a second up to `burst`. Every
+//! fetch sent to the backend takes a token; cache hits never do. A fetch
+//! finding the bucket empty waits for the next token if it comes within
+//! `max_wait_ms`, and is answered with a 503 otherwise.
+//!
+//! Routing names the selected backend in `X-Ghost-Rate-Limit` when it has a
+//! limit, and `router.throttle()` in vcl_backend_fetch takes the token, so
+//! the wait happens on the backend side, after the cache lookup.
+//!
+//! Inbound, a route's `rate_limit` filter gives each client a bucket of its
+//! own in a [`ClientRateLimiter`], checked as the route matches. A client
+//! finding its bucket empty doesn't wait: routing sends the request to the
+//! synthetic 429 backend, with the seconds until the next token in
+//! `X-Ghost-Retry-After` for its `Retry-After`.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::BuildHasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::config::{OutboundRateLimit, RateLimitFilter};
+
+/// Backend key of a rate limited backend, from routing (or `retry()`) to
+/// `throttle()`.
+pub(crate) const RATE_LIMIT_HEADER: &str = "X-Ghost-Rate-Limit";
+
+/// Seconds a rate limited client should wait, from routing to the 429
+/// backend.
+pub(crate) const RETRY_AFTER_HEADER: &str = "X-Ghost-Retry-After";
+
+/// Client buckets are spread over this many independently locked shards,
+/// so workers checking different clients rarely contend.
+const CLIENT_SHARDS: usize = 16;
+
+/// Token bucket for one backend
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: OutboundRateLimit,
+    state: Mutex<BucketState>,
+    /// Fetches turned away because no token came within `max_wait_ms`
+    throttled: AtomicU64,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    /// Negative while fetches are waiting for tokens reserved ahead of time
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    pub fn new(limit: &OutboundRateLimit) -> Self {
+        Self {
+            limit: *limit,
+            state: Mutex::new(BucketState {
+                tokens: limit.burst() as f64,
+                last: Instant::now(),
+            }),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    pub fn limit(&self) -> &OutboundRateLimit {
+        &self.limit
+    }
+
+    /// Take a token at `now`. Returns how long to wait before the token is
+    /// due, or None (taking nothing) if that is longer than `max_wait_ms`.
+    /// Waiting fetches reserve their token, so they are released in order
+    /// and never more than `rps` a second.
+    fn reserve(&self, now: Instant) -> Option<Duration> {
+        let rps = self.limit.rps as f64;
+        let mut state = self.state.lock();
+        if now > state.last {
+            let elapsed = now.duration_since(state.last).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * rps).min(self.limit.burst() as f64);
+            state.last = now;
+        }
+        let wait = Duration::from_secs_f64((1.0 - state.tokens).max(0.0) / rps);
+        if wait > Duration::from_millis(self.limit.max_wait_ms) {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        state.tokens -= 1.0;
+        Some(wait)
+    }
+
+    /// Take a token, sleeping until it is due. False if the fetch is
+    /// throttled instead.
+    pub fn acquire(&self) -> bool {
+        match self.reserve(Instant::now()) {
+            Some(wait) => {
+                if !wait.is_zero() {
+                    std::thread::sleep(wait);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Fetches throttled so far
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+}
+
+/// Token buckets of a route's clients.
+///
+/// Every worker routing to the route shares the limiter, so a client gets
+/// the same `rps` whichever worker serves it, and workers checking the
+/// same client take turns on its shard's lock, which parking_lot hands
+/// over fairly under contention.
+///
+/// Memory is bounded by `max_clients`: a shard that is full evicts the
+/// bucket idle the longest, along with any refilled ones next in line,
+/// which are no different from new ones. Shards keep their keys in order
+/// of use, so a new client costs O(1) amortized however full they are.
+#[derive(Debug)]
+pub struct ClientRateLimiter {
+    limit: RateLimitFilter,
+    hasher: RandomState,
+    shards: Vec<Mutex<ClientShard>>,
+}
+
+#[derive(Debug, Default)]
+struct ClientShard {
+    buckets: HashMap<String, BucketState>,
+    /// Keys with the time their bucket was last updated, least recently
+    /// used first. An entry is stale once its bucket has been updated
+    /// again or evicted; stale entries are skipped, and dropped when they
+    /// outnumber the live ones.
+    used: VecDeque<(String, Instant)>,
+}
+
+impl ClientRateLimiter {
+    pub fn new(limit: &RateLimitFilter) -> Self {
+        Self {
+            limit: limit.clone(),
+            hasher: RandomState::new(),
+            shards: (0..CLIENT_SHARDS)
+                .map(|_| Mutex::new(ClientShard::default()))
+                .collect(),
+        }
+    }
+
+    pub fn limit(&self) -> &RateLimitFilter {
+        &self.limit
+    }
+
+    /// Take a token for `key`. Err holds how long until the client's next
+    /// token when its bucket is empty.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let rps = self.limit.rps as f64;
+        let burst = self.limit.burst() as f64;
+        let shard = &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()];
+        let mut shard = shard.lock();
+        let new = !shard.buckets.contains_key(key);
+        if new {
+            let capacity = self.limit.max_clients.div_ceil(self.shards.len());
+            if shard.buckets.len() >= capacity {
+                shard.evict(now, rps, burst);
+            }
+        }
+        let state = shard
+            .buckets
+            .entry(key.to_string())
+            .or_insert_with(|| BucketState {
+                tokens: burst,
+                last: now,
+            });
+        let used = new || now > state.last;
+        if now > state.last {
+            let elapsed = now.duration_since(state.last).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * rps).min(burst);
+            state.last = now;
+        }
+        let result = if state.tokens < 1.0 {
+            Err(Duration::from_secs_f64((1.0 - state.tokens) / rps))
+        } else {
+            state.tokens -= 1.0;
+            Ok(())
+        };
+        if used {
+            shard.used.push_back((key.to_string(), now));
+            shard.compact();
+        }
+        result
+    }
+
+    /// Clients currently tracked
+    #[cfg(test)]
+    fn clients(&self) -> usize {
+        self.shards.iter().map(|s| s.lock().buckets.len()).sum()
+    }
+}
+
+impl ClientShard {
+    /// Make room in a full shard: drop the least recently used bucket, and
+    /// the ones after it for as long as they have refilled by `now`.
+    fn evict(&mut self, now: Instant, rps: f64, burst: f64) {
+        let mut evicted = false;
+        while let Some((key, last)) = self.used.pop_front() {
+            let Some(state) = self.buckets.get(&key).filter(|s| s.last == last) else {
+                continue;
+            };
+            let elapsed = now.saturating_duration_since(state.last).as_secs_f64();
+            if evicted && state.tokens + elapsed * rps < burst {
+                self.used.push_front((key, last));
+                return;
+            }
+            self.buckets.remove(&key);
+            evicted = true;
+        }
+    }
+
+    /// Drop stale `used` entries once they outnumber the live ones, so
+    /// the queue stays within twice the buckets.
+    fn compact(&mut self) {
+        if self.used.len() <= 2 * self.buckets.len().max(CLIENT_SHARDS) {
+            return;
+        }
+        let buckets = &self.buckets;
+        self.used
+            .retain(|(key, last)| buckets.get(key).is_some_and(|s| s.last == *last));
+    }
+}
+
+/// `Retry-After` seconds for a wait: rounded up, and at least 1
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    (wait.as_secs_f64().ceil() as u64).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HashSource;
+
+    fn bucket(rps: u32, burst: Option<u32>, max_wait_ms: u64) -> TokenBucket {
+        TokenBucket::new(&OutboundRateLimit {
+            rps,
+            burst,
+            max_wait_ms,
+        })
+    }
+
+    #[test]
+    fn test_requests_capped_at_rps() {
+        // 50 rps with a burst of 100, hammered every millisecond for 2s
+        let b = bucket(50, Some(100), 0);
+        let start = Instant::now();
+        let admitted = (0..2000)
+            .filter(|ms| {
+                b.reserve(start + Duration::from_millis(*ms))
+                    .is_some_and(|wait| wait.is_zero())
+            })
+            .count();
+        // The burst, then one token every 20ms
+        assert_eq!(admitted, 100 + 2000 / 20 - 1);
+        assert_eq!(b.throttled(), 2000 - admitted as u64);
+
+        // Kept busy, the bucket lets exactly rps through each second
+        let b = bucket(50, None, 0);
+        let in_second = |from: Instant| {
+            (0..1000)
+                .filter(|ms| b.reserve(from + Duration::from_millis(*ms)).is_some())
+                .count()
+        };
+        assert_eq!(in_second(start), 50 + 49);
+        assert_eq!(in_second(start + Duration::from_secs(1)), 50);
+        assert_eq!(in_second(start + Duration::from_secs(2)), 50);
+    }
+
+    #[test]
+    fn test_waits_are_queued_in_order() {
+        // 10 rps, burst 1: a token every 100ms, waits of up to 250ms allowed
+        let b = bucket(10, Some(1), 250);
+        let now = Instant::now();
+        let ms = |wait: Option<Duration>| wait.map(|w| (w.as_secs_f64() * 1000.0).round() as u64);
+        let waits: Vec<_> = (0..4).map(|_| ms(b.reserve(now))).collect();
+        assert_eq!(waits, vec![Some(0), Some(100), Some(200), None]);
+        assert_eq!(b.throttled(), 1);
+
+        // Once the queue has drained, the bucket refills up to burst only
+        let idle = now + Duration::from_secs(5);
+        assert_eq!(ms(b.reserve(idle)), Some(0));
+        assert_eq!(ms(b.reserve(idle)), Some(100));
+    }
+
+    fn limiter(rps: u32, burst: Option<u32>, max_clients: usize) -> ClientRateLimiter {
+        ClientRateLimiter::new(&RateLimitFilter {
+            rps,
+            burst,
+            key: HashSource::ClientIp,
+            max_clients,
+        })
+    }
+
+    #[test]
+    fn test_client_burst() {
+        // 1 rps with a burst of 5: five requests at once, then a wait
+        let l = limiter(1, Some(5), 100);
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert_eq!(l.check_at("10.0.0.1", now), Ok(()));
+        }
+        let wait = l.check_at("10.0.0.1", now).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+        assert_eq!(retry_after_secs(wait), 1);
+
+        // Other clients have buckets of their own
+        assert_eq!(l.check_at("10.0.0.2", now), Ok(()));
+    }
+
+    #[test]
+    fn test_client_steady_state() {
+        // 10 rps, burst 10, a request every 10ms for 3s
+        let l = limiter(10, None, 100);
+        let start = Instant::now();
+        let admitted = (0..300)
+            .filter(|i| {
+                l.check_at("client", start + Duration::from_millis(i * 10))
+                    .is_ok()
+            })
+            .count();
+        // The burst, then one every 100ms
+        assert_eq!(admitted, 10 + 29);
+
+        // Half a token in: the next one is 50ms away
+        let l = limiter(10, Some(1), 100);
+        assert!(l.check_at("client", start).is_ok());
+        let wait = l
+            .check_at("client", start + Duration::from_millis(50))
+            .unwrap_err();
+        assert_eq!((wait.as_secs_f64() * 1000.0).round(), 50.0);
+        assert_eq!(retry_after_secs(wait), 1);
+        assert_eq!(retry_after_secs(Duration::from_millis(2500)), 3);
+    }
+
+    #[test]
+    fn test_client_eviction() {
+        // Bounded at max_clients, spread over the shards
+        let l = limiter(1, Some(2), CLIENT_SHARDS * 2);
+        let now = Instant::now();
+        for i in 0..1000 {
+            l.check_at(&format!("10.0.{}.{}", i / 256, i % 256), now)
+                .unwrap();
+        }
+        assert!(l.clients() <= CLIENT_SHARDS * 2);
+
+        let mut shard = ClientShard::default();
+        let mut add = |key: &str, tokens: f64, ago_ms: u64| {
+            let last = now - Duration::from_millis(ago_ms);
+            shard
+                .buckets
+                .insert(key.to_string(), BucketState { tokens, last });
+            shard.used.push_back((key.to_string(), last));
+        };
+        add("lru", 0.0, 9000);
+        add("refilled", 0.0, 5000);
+        add("older", 0.5, 500);
+        add("recent", 0.0, 10);
+        // The least recently used goes, and refilled ones after it with it:
+        // a new bucket would be the same
+        shard.evict(now, 1.0, 2.0);
+        let mut left: Vec<_> = shard.buckets.keys().map(String::as_str).collect();
+        left.sort();
+        assert_eq!(left, ["older", "recent"]);
+
+        // Otherwise only the least recently used
+        shard.evict(now, 1.0, 2.0);
+        assert_eq!(shard.buckets.keys().collect::<Vec<_>>(), ["recent"]);
+    }
+
+    #[test]
+    fn test_client_eviction_follows_use() {
+        // Two clients a shard
+        let l = limiter(1, Some(1), CLIENT_SHARDS * 2);
+        let start = Instant::now();
+        let ms = |ms: u64| start + Duration::from_millis(ms);
+        let keys: Vec<_> = (0..1000)
+            .map(|i| format!("10.0.{}.{}", i / 256, i % 256))
+            .collect();
+        let shard = |key: &str| l.hasher.hash_one(key) as usize % CLIENT_SHARDS;
+        let first = shard(&keys[0]);
+        let mut same: Vec<_> = keys.iter().filter(|k| shard(k) == first).take(3).collect();
+        let (a, b, c) = (same.remove(0), same.remove(0), same.remove(0));
+
+        assert_eq!(l.check_at(a, ms(0)), Ok(()));
+        // A hot client keeps its bucket however many times it's used,
+        // without growing the queue past twice the buckets
+        for i in 1..500 {
+            assert!(l.check_at(a, ms(i)).is_err());
+        }
+        assert!(l.shards[first].lock().used.len() <= 2 * CLIENT_SHARDS);
+
+        // A new client in the full shard evicts the one used least
+        // recently, not the one added first, whose drained bucket holds
+        assert_eq!(l.check_at(b, ms(600)), Ok(()));
+        assert!(l.check_at(a, ms(650)).is_err());
+        assert_eq!(l.check_at(c, ms(700)), Ok(()));
+        assert!(l.check_at(a, ms(701)).is_err());
+        assert_eq!(l.clients(), 2);
+        assert!(!l.shards[first].lock().buckets.contains_key(b.as_str()));
+    }
+}
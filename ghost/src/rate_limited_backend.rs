@@ -0,0 +1,82 @@
+//! Synthetic 429 backend for rate limited clients
+//!
+//! Answers requests a route's `rate_limit` filter turns away, with the
+//! `Retry-After` routing left in [`RETRY_AFTER_HEADER`].
+
+use varnish::vcl::{Ctx, StrOrBytes, VclBackend, VclError, VclResponse};
+
+use crate::internal_headers;
+use crate::rate_limit::RETRY_AFTER_HEADER;
+
+const BODY: &[u8] = b"{\"error\":\"rate_limited\",\"status\":429}\n";
+
+/// Backend that generates synthetic 429 responses
+pub struct RateLimitedBackend;
+
+impl VclBackend<RateLimitedBody> for RateLimitedBackend {
+    fn get_response(&self, ctx: &mut Ctx) -> Result<Option<RateLimitedBody>, VclError> {
+        internal_headers::restore(ctx);
+        let bereq = ctx
+            .http_bereq
+            .as_mut()
+            .ok_or_else(|| VclError::new("Missing bereq in rate limited backend".to_string()))?;
+        let retry_after = match bereq.header(RETRY_AFTER_HEADER) {
+            Some(StrOrBytes::Utf8(s)) => s.parse::<u64>().ok(),
+            _ => None,
+        }
+        .unwrap_or(1);
+        bereq.unset_header(RETRY_AFTER_HEADER);
+
+        let beresp = ctx
+            .http_beresp
+            .as_mut()
+            .ok_or_else(|| VclError::new("Missing beresp in rate limited backend".to_string()))?;
+        crate::vsc::incr(|c| &c.synth_429);
+        beresp.set_status(429);
+        beresp.set_header("Content-Type", "application/json")?;
+        beresp.set_header("Cache-Control", "no-store")?;
+        beresp.set_header("Retry-After", &retry_after.to_string())?;
+
+        Ok(Some(RateLimitedBody { cursor: 0 }))
+    }
+}
+
+/// Response body for 429 error
+pub struct RateLimitedBody {
+    cursor: usize,
+}
+
+impl VclResponse for RateLimitedBody {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, VclError> {
+        let remaining = &BODY[self.cursor..];
+        let to_copy = remaining.len().min(buf.len());
+
+        buf[..to_copy].copy_from_slice(&remaining[..to_copy]);
+        self.cursor += to_copy;
+
+        Ok(to_copy)
+    }
+
+    fn len(&self) -> Option<usize> {
+        Some(BODY.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limited_body_read() {
+        let mut body = RateLimitedBody { cursor: 0 };
+        assert_eq!(body.len(), Some(BODY.len()));
+
+        let mut buf = vec![0u8; 100];
+        let n = body.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"{\"error\":\"rate_limited\",\"status\":429}\n");
+
+        // Second read should return 0 (EOF)
+        let n = body.read(&mut buf).unwrap();
+        assert_eq!(n, 0);
+    }
+}
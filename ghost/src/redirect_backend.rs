@@ -1,8 +1,12 @@
+use std::sync::OnceLock;
+
 use serde::{Deserialize, Serialize};
 use varnish::vcl::{Ctx, LogTag, StrOrBytes, VclBackend, VclError, VclResponse};
 
 use crate::config::RequestRedirectFilter;
-use crate::vhost_director::replace_first_segment_heuristic;
+use crate::internal_headers;
+use crate::reload_auth::constant_time_eq;
+use crate::vhost_director::{join_replaced_prefix, replace_first_segment_heuristic};
 
 /// Stateless redirect backend - actual redirect config passed via request header
 pub struct RedirectBackend;
@@ -19,10 +23,32 @@ pub struct RedirectConfig {
     pub original_query: String,
     // Matched path for prefix replacement (string value of matched prefix)
     pub matched_path: Option<String>,
+    /// Must equal `redirect_nonce()`; proves the router wrote this config
+    #[serde(default)]
+    pub nonce: String,
+}
+
+/// Per-process secret stamped into every redirect config the router emits.
+/// A config without it was not written by the router (e.g. a client forged
+/// the header) and is refused, so it can never produce a redirect.
+pub fn redirect_nonce() -> &'static str {
+    static NONCE: OnceLock<String> = OnceLock::new();
+    NONCE.get_or_init(|| format!("{:032x}", rand::random::<u128>()))
+}
+
+/// Parse the redirect config header, refusing configs the router didn't issue
+fn parse_config(json: &str) -> Result<RedirectConfig, String> {
+    let config: RedirectConfig =
+        serde_json::from_str(json).map_err(|e| format!("Invalid redirect config: {}", e))?;
+    if !constant_time_eq(config.nonce.as_bytes(), redirect_nonce().as_bytes()) {
+        return Err("Refusing redirect config not issued by the router".to_string());
+    }
+    Ok(config)
 }
 
 impl VclBackend<RedirectBody> for RedirectBackend {
     fn get_response(&self, ctx: &mut Ctx) -> Result<Option<RedirectBody>, VclError> {
+        internal_headers::restore(ctx);
         // Read redirect config from internal header (immutable borrow)
         let config_json = {
             let bereq = ctx
@@ -39,9 +65,9 @@ impl VclBackend<RedirectBody> for RedirectBackend {
                 .ok_or_else(|| VclError::new("Missing redirect config header".to_string()))?
         };
 
-        let config: RedirectConfig = serde_json::from_str(&config_json).map_err(|e| {
-            ctx.log(LogTag::Error, format!("Invalid redirect config: {}", e));
-            VclError::new(format!("Invalid redirect config: {}", e))
+        let config = parse_config(&config_json).map_err(|e| {
+            ctx.log(LogTag::Error, &e);
+            VclError::new(e)
         })?;
 
         // Build Location header
@@ -171,14 +197,7 @@ fn rewrite_path(
         if let Some(matched_prefix) = matched_path_str {
             // We have a matched prefix, use it
             if let Some(remainder) = original_path.strip_prefix(matched_prefix) {
-                let trimmed_new = new_prefix.trim_end_matches('/');
-                return Ok(if remainder.is_empty() {
-                    trimmed_new.to_string()
-                } else if remainder.starts_with('/') {
-                    format!("{}{}", trimmed_new, remainder)
-                } else {
-                    format!("{}/{}", trimmed_new, remainder)
-                });
+                return Ok(join_replaced_prefix(new_prefix, remainder));
             }
         }
 
@@ -234,6 +253,7 @@ mod tests {
             original_path: original_path.to_string(),
             original_query: original_query.to_string(),
             matched_path: None,
+            nonce: redirect_nonce().to_string(),
         }
     }
 
@@ -430,6 +450,24 @@ mod tests {
         assert_eq!(result, "/v2");
     }
 
+    #[test]
+    fn test_rewrite_path_prefix_trailing_slashes() {
+        for (matched, new_prefix) in [
+            ("/api", "/v2"),
+            ("/api", "/v2/"),
+            ("/api/", "/v2"),
+            ("/api/", "/v2/"),
+        ] {
+            let filter = make_filter(None, None, None, None, Some(new_prefix), 301);
+            let result = rewrite_path(&filter, "/api/users", Some(matched)).unwrap();
+            assert_eq!(result, "/v2/users", "{} -> {}", matched, new_prefix);
+        }
+
+        // Replacing with the root never leaves an empty path
+        let filter = make_filter(None, None, None, None, Some("/"), 301);
+        assert_eq!(rewrite_path(&filter, "/api", Some("/api")).unwrap(), "/");
+    }
+
     #[test]
     fn test_sanitize_redirect_status() {
         // Allowed Gateway API redirect codes pass through unchanged.
@@ -443,6 +481,24 @@ mod tests {
         assert_eq!(sanitize_redirect_status(999), 302);
     }
 
+    #[test]
+    fn test_parse_config_requires_router_nonce() {
+        let filter = make_filter(None, Some("evil.example"), None, None, None, 302);
+        let mut config = make_config(filter, "http", "example.com", 80, "/", "");
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(parse_config(&json).is_ok());
+
+        // A forged header without (or with a guessed) nonce is refused
+        config.nonce = String::new();
+        let forged = serde_json::to_string(&config).unwrap();
+        assert!(parse_config(&forged).is_err());
+        config.nonce = "0".repeat(32);
+        let forged = serde_json::to_string(&config).unwrap();
+        assert!(parse_config(&forged).is_err());
+
+        assert!(parse_config("{not json").is_err());
+    }
+
     #[test]
     fn test_should_omit_port() {
         assert!(should_omit_port("http", 80));
@@ -0,0 +1,139 @@
+//! Authorization for the `/.varnish-ghost/reload` endpoint.
+//!
+//! Reloads are allowed from loopback (chaperone curls over localhost). A
+//! remote client is allowed only when a reload token was given to
+//! `ghost.init()` and the request presents it in `X-Ghost-Reload-Token`.
+
+use std::net::IpAddr;
+
+/// Request header carrying the shared reload token.
+pub const RELOAD_TOKEN_HEADER: &str = "X-Ghost-Reload-Token";
+
+/// Why a reload request was refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReloadDenied {
+    /// Remote client and no token configured.
+    NotLoopback,
+    /// Remote client without a token header.
+    MissingToken,
+    /// Remote client with a token that doesn't match.
+    BadToken,
+}
+
+impl std::fmt::Display for ReloadDenied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReloadDenied::NotLoopback => f.write_str("reload only allowed from loopback"),
+            ReloadDenied::MissingToken => write!(f, "missing {} header", RELOAD_TOKEN_HEADER),
+            ReloadDenied::BadToken => write!(f, "invalid {} header", RELOAD_TOKEN_HEADER),
+        }
+    }
+}
+
+/// Decide whether a reload request may proceed. An unknown client address
+/// (e.g. a Unix domain socket listener) is treated as remote.
+pub fn authorize(
+    client_ip: Option<IpAddr>,
+    token: Option<&str>,
+    presented: Option<&str>,
+) -> Result<(), ReloadDenied> {
+    if client_ip.is_some_and(|ip| is_loopback(&ip)) {
+        return Ok(());
+    }
+    let Some(token) = token else {
+        return Err(ReloadDenied::NotLoopback);
+    };
+    match presented {
+        None => Err(ReloadDenied::MissingToken),
+        Some(p) if constant_time_eq(p.as_bytes(), token.as_bytes()) => Ok(()),
+        Some(_) => Err(ReloadDenied::BadToken),
+    }
+}
+
+/// Loopback, including IPv4-mapped IPv6 (`::ffff:127.0.0.1`).
+fn is_loopback(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_loopback(),
+        IpAddr::V6(v6) => {
+            v6.is_loopback() || v6.to_ipv4_mapped().is_some_and(|v4| v4.is_loopback())
+        }
+    }
+}
+
+/// Compare without short-circuiting on the first differing byte, so response
+/// timing doesn't reveal how much of a guessed token is right.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// JSON body for a refused reload.
+pub fn denied_json(reason: &ReloadDenied) -> String {
+    serde_json::json!({
+        "error": "reload_unauthorized",
+        "message": reason.to_string(),
+    })
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn test_loopback_allowed() {
+        assert_eq!(authorize(ip("127.0.0.1"), None, None), Ok(()));
+        assert_eq!(authorize(ip("::1"), None, None), Ok(()));
+        assert_eq!(authorize(ip("::ffff:127.0.0.1"), None, None), Ok(()));
+        // A token doesn't take loopback access away from chaperone.
+        assert_eq!(authorize(ip("127.0.0.1"), Some("s3cret"), None), Ok(()));
+    }
+
+    #[test]
+    fn test_remote_denied() {
+        assert_eq!(
+            authorize(ip("192.0.2.10"), None, None),
+            Err(ReloadDenied::NotLoopback)
+        );
+        // Presenting a token is useless when none is configured.
+        assert_eq!(
+            authorize(ip("192.0.2.10"), None, Some("s3cret")),
+            Err(ReloadDenied::NotLoopback)
+        );
+        assert_eq!(authorize(None, None, None), Err(ReloadDenied::NotLoopback));
+        assert_eq!(
+            authorize(ip("192.0.2.10"), Some("s3cret"), None),
+            Err(ReloadDenied::MissingToken)
+        );
+        assert_eq!(
+            authorize(ip("192.0.2.10"), Some("s3cret"), Some("guess")),
+            Err(ReloadDenied::BadToken)
+        );
+    }
+
+    #[test]
+    fn test_remote_with_token_allowed() {
+        assert_eq!(
+            authorize(ip("192.0.2.10"), Some("s3cret"), Some("s3cret")),
+            Ok(())
+        );
+        assert_eq!(
+            authorize(ip("2001:db8::1"), Some("s3cret"), Some("s3cret")),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_denied_json() {
+        let body: serde_json::Value =
+            serde_json::from_str(&denied_json(&ReloadDenied::BadToken)).unwrap();
+        assert_eq!(body["error"], "reload_unauthorized");
+        assert_eq!(body["message"], "invalid X-Ghost-Reload-Token header");
+    }
+}
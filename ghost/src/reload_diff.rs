@@ -0,0 +1,386 @@
+//! What a reload changed in the routing state.
+//!
+//! Computed on the compiled vhost directors rather than on ghost.json, so
+//! a reload that only reorders vhosts, routes of equal precedence or the
+//! backends of a group reports no changes. Returned by the reload endpoint
+//! and logged to VSL as one line:
+//!
+//! ```json
+//! {"vhosts":{"added":["new.example.com"],"removed":[]},
+//!  "routes":{"api.example.com":{"added":[],"removed":[],"changed":["default/api#0"]}},
+//!  "backends":{"added":["10.0.0.9:8080"],"removed":[]}}
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::Serialize;
+
+use crate::director::{PathMatchCompiled, RouteEntry, VhostDirectorMap};
+
+/// Routing state reduced to what a diff compares: per vhost, a description
+/// of each route by label, and the backend keys routes refer to.
+#[derive(Debug, Default, PartialEq)]
+pub struct Snapshot {
+    vhosts: BTreeMap<String, BTreeMap<String, String>>,
+    backends: BTreeSet<String>,
+}
+
+impl Snapshot {
+    pub fn of(directors: &VhostDirectorMap) -> Self {
+        let mut snapshot = Snapshot::default();
+        for director in directors.all_directors() {
+            snapshot.add_vhost(director.hostname(), director.routes());
+        }
+        snapshot
+    }
+
+    fn add_vhost(&mut self, hostname: &str, routes: &[RouteEntry]) {
+        let mut described = BTreeMap::new();
+        for route in routes {
+            let label = route_label(route);
+            // Unnamed routes can share a label; number the repeats
+            let mut unique = label.clone();
+            let mut n = 1;
+            while described.contains_key(&unique) {
+                n += 1;
+                unique = format!("{} ({})", label, n);
+            }
+            described.insert(unique, describe(route));
+            for group in &route.backend_groups {
+                self.backends
+                    .extend(group.backends.iter().chain(&group.draining).cloned());
+            }
+        }
+        self.vhosts.insert(hostname.to_string(), described);
+    }
+}
+
+/// How a route is named in the diff: `<route_name>#<rule_index>` when the
+/// route has a name, the path match otherwise, and `default` for a vhost's
+/// default backends.
+fn route_label(route: &RouteEntry) -> String {
+    if route.rule_index == i32::MAX && route.route_name.is_none() {
+        return "default".to_string();
+    }
+    match (&route.route_name, &route.path_match) {
+        (Some(name), _) => format!("{}#{}", name, route.rule_index),
+        (None, Some(PathMatchCompiled::Exact(p) | PathMatchCompiled::PathPrefix(p))) => {
+            format!("{}#{}", p, route.rule_index)
+        }
+        (None, Some(PathMatchCompiled::Regex(re))) => {
+            format!("{}#{}", re.as_str(), route.rule_index)
+        }
+        (None, None) => format!("*#{}", route.rule_index),
+    }
+}
+
+/// Everything about a route that affects how requests are handled. Hash
+/// rings are left out, being derived from the backend groups, and the
+/// backends of a group are sorted since their order doesn't matter.
+fn describe(route: &RouteEntry) -> String {
+    let groups: Vec<_> = route
+        .backend_groups
+        .iter()
+        .map(|g| {
+            let mut backends = g.backends.clone();
+            backends.sort();
+            let mut draining = g.draining.clone();
+            draining.sort();
+            (g.weight, backends, draining)
+        })
+        .collect();
+    let shadow = route.shadow_selection.as_ref().map(|s| {
+        (
+            s.selection,
+            s.backend_groups
+                .iter()
+                .map(|g| g.weight)
+                .collect::<Vec<_>>(),
+        )
+    });
+    format!(
+        "{:?}",
+        (
+            (
+                &route.path_match,
+                &route.method,
+                &route.headers,
+                &route.query_params,
+                &route.listeners,
+                route.priority,
+            ),
+            (&route.filters, &route.cache_policy, groups),
+            (route.selection, &route.hash_on, shadow),
+            (
+                &route.timeouts,
+                &route.session_persistence,
+                &route.retry,
+                route.qos,
+                &route.forward_host,
+            ),
+        )
+    )
+}
+
+/// Names added and removed
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Change {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl Change {
+    fn between<'a>(
+        old: impl Iterator<Item = &'a String> + Clone,
+        new: impl Iterator<Item = &'a String> + Clone,
+    ) -> Self {
+        let old: BTreeSet<_> = old.collect();
+        let new: BTreeSet<_> = new.collect();
+        Change {
+            added: new.difference(&old).map(|s| s.to_string()).collect(),
+            removed: old.difference(&new).map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Routes added, removed and changed in a vhost present before and after
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct RouteChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+/// Differences between the routing state before and after a reload
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ReloadDiff {
+    pub vhosts: Change,
+    /// Only vhosts whose routes changed
+    pub routes: BTreeMap<String, RouteChanges>,
+    pub backends: Change,
+}
+
+impl ReloadDiff {
+    pub fn between(old: &Snapshot, new: &Snapshot) -> Self {
+        let mut routes = BTreeMap::new();
+        for (hostname, new_routes) in &new.vhosts {
+            let Some(old_routes) = old.vhosts.get(hostname) else {
+                continue;
+            };
+            let added_removed = Change::between(old_routes.keys(), new_routes.keys());
+            let changed: Vec<_> = new_routes
+                .iter()
+                .filter(|(label, desc)| old_routes.get(*label).is_some_and(|d| d != *desc))
+                .map(|(label, _)| label.clone())
+                .collect();
+            if !added_removed.is_empty() || !changed.is_empty() {
+                routes.insert(
+                    hostname.clone(),
+                    RouteChanges {
+                        added: added_removed.added,
+                        removed: added_removed.removed,
+                        changed,
+                    },
+                );
+            }
+        }
+
+        ReloadDiff {
+            vhosts: Change::between(old.vhosts.keys(), new.vhosts.keys()),
+            routes,
+            backends: Change::between(old.backends.iter(), new.backends.iter()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vhosts.is_empty() && self.routes.is_empty() && self.backends.is_empty()
+    }
+
+    /// One-line summary for VSL
+    pub fn summary(&self) -> String {
+        if self.is_empty() {
+            return "ghost: config loaded, no changes".to_string();
+        }
+        let count = |f: fn(&RouteChanges) -> usize| self.routes.values().map(f).sum::<usize>();
+        format!(
+            "ghost: config loaded, vhosts +{} -{}, routes +{} -{} ~{}, backends +{} -{}",
+            self.vhosts.added.len(),
+            self.vhosts.removed.len(),
+            count(|r| r.added.len()),
+            count(|r| r.removed.len()),
+            count(|r| r.changed.len()),
+            self.backends.added.len(),
+            self.backends.removed.len(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{QosClass, SelectionPolicy};
+    use crate::director::WeightedBackendGroup;
+
+    fn route(name: &str, rule_index: i32, backends: &[&str]) -> RouteEntry {
+        RouteEntry {
+            path_match: Some(PathMatchCompiled::PathPrefix("/".to_string())),
+            method: None,
+            headers: Vec::new(),
+            query_params: Vec::new(),
+            filters: None,
+            backend_groups: vec![WeightedBackendGroup {
+                weight: 100,
+                backends: backends.iter().map(|b| b.to_string()).collect(),
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            }],
+            listeners: Vec::new(),
+            route_name: Some(name.to_string()),
+            priority: None,
+            rule_index,
+            cache_policy: None,
+            bypass_headers: Vec::new(),
+            selection: SelectionPolicy::default(),
+            hash_on: None,
+            hash_ring: None,
+            shadow_selection: None,
+            shadow_diff: None,
+            timeouts: None,
+            session_persistence: None,
+            retry: None,
+            qos: QosClass::Normal,
+            forward_host: None,
+            max_request_body_bytes: None,
+            equal_weights: false,
+            rate_limiter: None,
+            nonce_store: None,
+            url_rewrite_regex: None,
+        }
+    }
+
+    fn snapshot(vhosts: &[(&str, Vec<RouteEntry>)]) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        for (hostname, routes) in vhosts {
+            snapshot.add_vhost(hostname, routes);
+        }
+        snapshot
+    }
+
+    #[test]
+    fn test_no_op_reload() {
+        let old = snapshot(&[
+            (
+                "a.example.com",
+                vec![route("default/a", 0, &["10.0.0.1:80", "10.0.0.2:80"])],
+            ),
+            (
+                "b.example.com",
+                vec![route("default/b", 0, &["10.0.0.3:80"])],
+            ),
+        ]);
+        // Same routing, vhosts and backends listed in another order
+        let new = snapshot(&[
+            (
+                "b.example.com",
+                vec![route("default/b", 0, &["10.0.0.3:80"])],
+            ),
+            (
+                "a.example.com",
+                vec![route("default/a", 0, &["10.0.0.2:80", "10.0.0.1:80"])],
+            ),
+        ]);
+        let diff = ReloadDiff::between(&old, &new);
+        assert!(diff.is_empty(), "{:?}", diff);
+        assert_eq!(diff.summary(), "ghost: config loaded, no changes");
+        assert_eq!(
+            serde_json::to_value(&diff).unwrap(),
+            serde_json::json!({
+                "vhosts": {"added": [], "removed": []},
+                "routes": {},
+                "backends": {"added": [], "removed": []}
+            })
+        );
+    }
+
+    #[test]
+    fn test_add_vhost() {
+        let old = snapshot(&[(
+            "a.example.com",
+            vec![route("default/a", 0, &["10.0.0.1:80"])],
+        )]);
+        let new = snapshot(&[
+            (
+                "a.example.com",
+                vec![route("default/a", 0, &["10.0.0.1:80"])],
+            ),
+            (
+                "b.example.com",
+                vec![route("default/b", 0, &["10.0.0.2:80"])],
+            ),
+        ]);
+        let diff = ReloadDiff::between(&old, &new);
+        assert_eq!(diff.vhosts.added, vec!["b.example.com"]);
+        assert!(diff.vhosts.removed.is_empty());
+        // The new vhost's routes are not listed separately
+        assert!(diff.routes.is_empty());
+        assert_eq!(diff.backends.added, vec!["10.0.0.2:80"]);
+        assert_eq!(
+            diff.summary(),
+            "ghost: config loaded, vhosts +1 -0, routes +0 -0 ~0, backends +1 -0"
+        );
+    }
+
+    #[test]
+    fn test_remove_backend() {
+        let old = snapshot(&[(
+            "a.example.com",
+            vec![
+                route("default/a", 0, &["10.0.0.1:80", "10.0.0.2:80"]),
+                route("default/a", 1, &["10.0.0.1:80"]),
+            ],
+        )]);
+        let new = snapshot(&[(
+            "a.example.com",
+            vec![
+                route("default/a", 0, &["10.0.0.1:80"]),
+                route("default/a", 1, &["10.0.0.1:80"]),
+            ],
+        )]);
+        let diff = ReloadDiff::between(&old, &new);
+        assert!(diff.vhosts.added.is_empty() && diff.vhosts.removed.is_empty());
+        assert_eq!(
+            diff.routes["a.example.com"],
+            RouteChanges {
+                added: Vec::new(),
+                removed: Vec::new(),
+                changed: vec!["default/a#0".to_string()],
+            }
+        );
+        assert!(diff.backends.added.is_empty());
+        assert_eq!(diff.backends.removed, vec!["10.0.0.2:80"]);
+    }
+
+    #[test]
+    fn test_route_labels() {
+        let mut unnamed = route("x", 3, &[]);
+        unnamed.route_name = None;
+        let mut default = route("x", i32::MAX, &[]);
+        default.route_name = None;
+        default.path_match = None;
+        let snap = snapshot(&[(
+            "a.example.com",
+            vec![
+                unnamed.clone(),
+                unnamed,
+                route("default/a", 0, &[]),
+                default,
+            ],
+        )]);
+        let labels: Vec<_> = snap.vhosts["a.example.com"].keys().cloned().collect();
+        assert_eq!(labels, vec!["/#3", "/#3 (2)", "default", "default/a#0"]);
+    }
+}
@@ -0,0 +1,316 @@
+//! Request bodies forwarded by external proxy backends.
+//!
+//! Bodies are streamed to the upstream as they are read from bereq, through
+//! a [`BodySender`], bounded only by the route's `max_request_body_bytes`.
+//! A `Content-Length` over the limit is refused before anything is read; a
+//! chunked body when the limit is reached while reading it, aborting the
+//! upstream request.
+//!
+//! Bodies that have to be seen whole are read in full first instead, into a
+//! [`CappedBuffer`] bounded by [`MAX_REQUEST_BODY_BYTES`] as well. Upstreams
+//! configured with `decompress_request_body` get gzip bodies inflated; the
+//! inflated size is bounded too, relative to the compressed size, so a
+//! small request can't expand into gigabytes. Upstreams configured with
+//! `compress_request` get bodies of at least `min_bytes` gzipped, unless
+//! the client already encoded them or gzip doesn't make them smaller. And
+//! routes with a `retry_on_body` condition keep the body for the retry.
+
+use std::io::{self, Read, Write};
+
+use bytes::Bytes;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tokio::sync::mpsc::Sender;
+use tokio_stream::wrappers::ReceiverStream;
+
+use crate::config::CompressRequest;
+
+/// Chunks of a streamed body read ahead of the upstream connection
+const BODY_CHANNEL_SIZE: usize = 16;
+
+/// Largest request body forwarded to an external proxy, before and after
+/// decompression.
+pub const MAX_REQUEST_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Largest decompressed/compressed size ratio accepted. Real payloads rarely
+/// exceed 20:1; bombs are in the thousands.
+pub const MAX_DECOMPRESSION_RATIO: usize = 100;
+
+/// Why a request body can't be forwarded.
+#[derive(Debug, PartialEq)]
+pub enum BodyError {
+    /// Over [`MAX_REQUEST_BODY_BYTES`], or inflates past the ratio limit.
+    TooLarge,
+    /// Not valid gzip.
+    Malformed,
+}
+
+/// `Write` sink that keeps at most `limit` bytes and fails beyond that.
+pub struct CappedBuffer {
+    buf: Vec<u8>,
+    limit: usize,
+    overflowed: bool,
+}
+
+impl CappedBuffer {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            limit,
+            overflowed: false,
+        }
+    }
+
+    /// Whether a write was refused for exceeding the limit.
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+impl Write for CappedBuffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.buf.len() + data.len() > self.limit {
+            self.overflowed = true;
+            return Err(io::Error::other("request body too large"));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `Write` sink streaming a request body into an upstream request as it is
+/// read. Fails past `limit`, and once the upstream request is gone.
+/// Dropping it ends the body.
+pub struct BodySender {
+    tx: Sender<io::Result<Bytes>>,
+    sent: usize,
+    limit: Option<usize>,
+    overflowed: bool,
+}
+
+impl BodySender {
+    /// A sender and the request body it feeds
+    pub fn channel(limit: Option<usize>) -> (Self, reqwest::Body) {
+        let (tx, rx) = tokio::sync::mpsc::channel(BODY_CHANNEL_SIZE);
+        let sender = Self {
+            tx,
+            sent: 0,
+            limit,
+            overflowed: false,
+        };
+        (sender, reqwest::Body::wrap_stream(ReceiverStream::new(rx)))
+    }
+
+    /// Whether a write was refused for exceeding the limit.
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// Whether the upstream request stopped taking the body, having been
+    /// answered or failed.
+    pub fn upstream_gone(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// End the body with an error, so the upstream request is aborted
+    /// rather than sent short.
+    pub fn abort(self, reason: &str) {
+        let _ = self
+            .tx
+            .blocking_send(Err(io::Error::other(reason.to_string())));
+    }
+}
+
+impl Write for BodySender {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self
+            .limit
+            .is_some_and(|limit| self.sent + data.len() > limit)
+        {
+            self.overflowed = true;
+            return Err(io::Error::other("request body too large"));
+        }
+        self.tx
+            .blocking_send(Ok(Bytes::copy_from_slice(data)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "upstream request ended"))?;
+        self.sent += data.len();
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Body limit of a route: its `max_request_body_bytes`, but never more than
+/// [`MAX_REQUEST_BODY_BYTES`].
+pub fn body_limit(route_limit: Option<u64>) -> usize {
+    route_limit.map_or(MAX_REQUEST_BODY_BYTES, |limit| {
+        usize::try_from(limit).map_or(MAX_REQUEST_BODY_BYTES, |l| l.min(MAX_REQUEST_BODY_BYTES))
+    })
+}
+
+/// Whether a request's `Content-Length` announces more than `limit` bytes.
+/// A missing or unparsable length says nothing; the limit is then enforced
+/// while reading.
+pub fn exceeds_limit(content_length: Option<&str>, limit: usize) -> bool {
+    content_length
+        .and_then(|len| len.trim().parse::<u64>().ok())
+        .is_some_and(|len| len > limit as u64)
+}
+
+/// Whether a `Content-Encoding` value names gzip as the only coding.
+pub fn is_gzip(content_encoding: &str) -> bool {
+    let coding = content_encoding.trim();
+    coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip")
+}
+
+/// Inflate a gzip body, refusing output beyond [`MAX_DECOMPRESSION_RATIO`]
+/// times the input or [`MAX_REQUEST_BODY_BYTES`].
+pub fn gunzip(body: &[u8]) -> Result<Vec<u8>, BodyError> {
+    let limit = body
+        .len()
+        .saturating_mul(MAX_DECOMPRESSION_RATIO)
+        .min(MAX_REQUEST_BODY_BYTES);
+    let mut out = Vec::new();
+    // One byte past the limit tells "exactly at" from "over"
+    MultiGzDecoder::new(body)
+        .take(limit as u64 + 1)
+        .read_to_end(&mut out)
+        .map_err(|_| BodyError::Malformed)?;
+    if out.len() > limit {
+        return Err(BodyError::TooLarge);
+    }
+    Ok(out)
+}
+
+/// Gzip a body for an upstream with `compress_request`. None when the body
+/// is too small to bother or doesn't shrink.
+pub fn compress(body: &[u8], settings: &CompressRequest) -> Option<Vec<u8>> {
+    if !settings.enabled || body.len() < settings.min_bytes {
+        return None;
+    }
+    let mut enc = GzEncoder::new(Vec::with_capacity(body.len() / 2), Compression::fast());
+    enc.write_all(body).ok()?;
+    let compressed = enc.finish().ok()?;
+    (compressed.len() < body.len()).then_some(compressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(data).unwrap();
+        enc.finish().unwrap()
+    }
+
+    #[test]
+    fn test_gunzip_roundtrip() {
+        let body = br#"{"items":[1,2,3],"note":"compressed upload"}"#;
+        assert_eq!(gunzip(&gzip(body)).unwrap(), body);
+        assert_eq!(gunzip(&gzip(b"")).unwrap(), b"");
+    }
+
+    #[test]
+    fn test_gunzip_rejects_bomb() {
+        // 10 MiB of zeros compresses about a thousandfold
+        let bomb = gzip(&vec![0u8; 10 * 1024 * 1024]);
+        assert!(bomb.len() * MAX_DECOMPRESSION_RATIO < 10 * 1024 * 1024);
+        assert_eq!(gunzip(&bomb), Err(BodyError::TooLarge));
+    }
+
+    #[test]
+    fn test_gunzip_rejects_garbage() {
+        assert_eq!(gunzip(b"not gzip at all"), Err(BodyError::Malformed));
+        let mut truncated = gzip(b"hello world, hello world");
+        truncated.truncate(truncated.len() / 2);
+        assert_eq!(gunzip(&truncated), Err(BodyError::Malformed));
+    }
+
+    #[test]
+    fn test_compress() {
+        let settings = CompressRequest {
+            enabled: true,
+            min_bytes: 64,
+        };
+        let body = br#"{"items":[1,2,3],"note":"compressed upload"}"#.repeat(10);
+        let compressed = compress(&body, &settings).unwrap();
+        assert!(compressed.len() < body.len());
+        assert_eq!(gunzip(&compressed).unwrap(), body);
+
+        // Under min_bytes, disabled, or not worth it
+        assert!(compress(&body[..63], &settings).is_none());
+        let off = CompressRequest {
+            enabled: false,
+            ..settings.clone()
+        };
+        assert!(compress(&body, &off).is_none());
+        assert!(compress(&gzip(&body), &settings).is_none());
+    }
+
+    #[test]
+    fn test_is_gzip() {
+        assert!(is_gzip("gzip"));
+        assert!(is_gzip(" GZIP "));
+        assert!(is_gzip("x-gzip"));
+        assert!(!is_gzip("br"));
+        assert!(!is_gzip("gzip, br"));
+        assert!(!is_gzip("identity"));
+    }
+
+    #[test]
+    fn test_body_limit() {
+        assert_eq!(body_limit(None), MAX_REQUEST_BODY_BYTES);
+        assert_eq!(body_limit(Some(1024)), 1024);
+        assert_eq!(body_limit(Some(u64::MAX)), MAX_REQUEST_BODY_BYTES);
+    }
+
+    #[test]
+    fn test_exceeds_limit() {
+        assert!(exceeds_limit(Some("1025"), 1024));
+        assert!(exceeds_limit(Some(" 99999999999 "), 1024));
+        assert!(!exceeds_limit(Some("1024"), 1024));
+        assert!(!exceeds_limit(Some("0"), 1024));
+        assert!(!exceeds_limit(Some("chunked"), 1024));
+        assert!(!exceeds_limit(None, 1024));
+    }
+
+    #[test]
+    fn test_capped_buffer() {
+        let mut buf = CappedBuffer::new(8);
+        buf.write_all(b"1234").unwrap();
+        buf.write_all(b"5678").unwrap();
+        assert!(!buf.overflowed());
+        assert!(buf.write_all(b"9").is_err());
+        assert!(buf.overflowed());
+        assert_eq!(buf.into_inner(), b"12345678");
+    }
+
+    #[test]
+    fn test_body_sender() {
+        let (mut sender, body) = BodySender::channel(Some(8));
+        sender.write_all(b"1234").unwrap();
+        sender.write_all(b"5678").unwrap();
+        assert!(!sender.overflowed());
+        assert!(sender.write_all(b"9").is_err());
+        assert!(sender.overflowed());
+
+        // Once the request holding the body is dropped, writes fail
+        let (mut sender, _) = BodySender::channel(None);
+        assert!(sender.upstream_gone());
+        assert!(sender.write_all(b"1234").is_err());
+        assert!(!sender.overflowed());
+        drop(body);
+    }
+}
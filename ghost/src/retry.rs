@@ -0,0 +1,316 @@
+//! Per-route retries of failed backend responses.
+//!
+//! Routing in vcl_recv records a [`RetryState`] on the request when the
+//! matched route has a retry policy and the method may be retried. It
+//! travels to bereq, where `router.retry()` in vcl_backend_response decides
+//! whether a response is worth another attempt and picks a backend of the
+//! same route that hasn't been tried yet. Called in vcl_backend_error, it
+//! retries fetches that failed before any response headers arrived. `router.retry_backend()` then hands
+//! that backend to vcl_backend_fetch for the retried fetch.
+//!
+//! Routes with `retry_on_body` also carry a [`BodyCondition`] in the state.
+//! External proxy backends then buffer the start of the body before
+//! streaming it and flag a match with [`BODY_MATCH_HEADER`] on beresp, which
+//! `router.retry()` consumes.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{RetryOnBody, RetryPolicy};
+
+/// Header carrying the JSON-encoded [`RetryState`] from routing to the
+/// backend-side retry hooks.
+pub const RETRY_STATE_HEADER: &str = "X-Ghost-Retry";
+
+/// Header set on beresp by external proxy backends when the body prefix
+/// matched the route's [`BodyCondition`].
+pub const BODY_MATCH_HEADER: &str = "X-Ghost-Retry-Body";
+
+/// Body prefix check travelling with the retry state to the backend.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct BodyCondition {
+    pub pattern: String,
+    pub max_inspect_bytes: usize,
+}
+
+impl From<&RetryOnBody> for BodyCondition {
+    fn from(body: &RetryOnBody) -> Self {
+        Self {
+            pattern: body.pattern.clone(),
+            max_inspect_bytes: body.max_inspect_bytes,
+        }
+    }
+}
+
+impl BodyCondition {
+    /// Whether the pattern occurs within the first `max_inspect_bytes` of
+    /// `body`.
+    pub fn matches(&self, body: &[u8]) -> bool {
+        let prefix = &body[..body.len().min(self.max_inspect_bytes)];
+        let pattern = self.pattern.as_bytes();
+        prefix.windows(pattern.len()).any(|w| w == pattern)
+    }
+}
+
+/// Where a retryable request was routed and what has been tried so far.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RetryState {
+    /// Id of the vhost director that routed the request. A reload replaces
+    /// every director, so a stale id means the route index no longer applies.
+    pub vhost: u64,
+    /// Index of the matched route within that director.
+    pub route: usize,
+    /// Backend keys tried so far, in order. The last one is the current
+    /// attempt.
+    pub tried: Vec<String>,
+    /// Unix time in milliseconds after which no new attempt is started.
+    pub deadline_ms: Option<u64>,
+    /// Body prefix check, for routes with `retry_on_body` and idempotent
+    /// requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body: Option<BodyCondition>,
+}
+
+impl RetryState {
+    pub fn to_header(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn from_header(value: &str) -> Option<Self> {
+        serde_json::from_str(value).ok()
+    }
+
+    /// Attempts made so far.
+    pub fn attempts(&self) -> u32 {
+        self.tried.len() as u32
+    }
+
+    /// Backend key of the current attempt.
+    pub fn current(&self) -> Option<&str> {
+        self.tried.last().map(String::as_str)
+    }
+}
+
+/// GET, HEAD and OPTIONS are safe to repeat.
+pub fn is_idempotent(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "OPTIONS")
+}
+
+/// Whether requests with this method may be retried under `policy`.
+/// Anything but idempotent methods needs an explicit opt-in.
+pub fn method_allowed(policy: &RetryPolicy, method: &str) -> bool {
+    policy.unsafe_methods || is_idempotent(method)
+}
+
+/// What went wrong with the current attempt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trigger {
+    /// A response arrived with this status.
+    Status(u16),
+    /// A response arrived whose body matched the route's body condition.
+    BodyMatched,
+    /// No response headers arrived: connection refused or reset, DNS
+    /// failure, first byte timeout.
+    FetchFailed,
+}
+
+/// Whether an attempt that failed with `trigger` warrants another one.
+pub fn should_retry(
+    policy: &RetryPolicy,
+    state: &RetryState,
+    trigger: Trigger,
+    now_ms: u64,
+) -> bool {
+    let matched = match trigger {
+        Trigger::Status(status) => policy.retry_on.contains(&status),
+        Trigger::BodyMatched | Trigger::FetchFailed => true,
+    };
+    matched
+        && state.attempts() < policy.max_attempts
+        && state.deadline_ms.is_none_or(|deadline| now_ms < deadline)
+}
+
+/// Current Unix time in milliseconds.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            retry_on: vec![502, 503],
+            per_try_timeout_ms: None,
+            unsafe_methods: false,
+            retry_on_body: None,
+        }
+    }
+
+    fn state(tried: &[&str], deadline_ms: Option<u64>) -> RetryState {
+        RetryState {
+            vhost: 1,
+            route: 0,
+            tried: tried.iter().map(|s| s.to_string()).collect(),
+            deadline_ms,
+            body: None,
+        }
+    }
+
+    #[test]
+    fn test_method_allowed() {
+        let mut p = policy(2);
+        assert!(method_allowed(&p, "GET"));
+        assert!(method_allowed(&p, "HEAD"));
+        assert!(method_allowed(&p, "OPTIONS"));
+        assert!(!method_allowed(&p, "POST"));
+        assert!(!method_allowed(&p, "PUT"));
+
+        p.unsafe_methods = true;
+        assert!(method_allowed(&p, "POST"));
+    }
+
+    #[test]
+    fn test_should_retry_status_and_attempts() {
+        let p = policy(3);
+        assert!(should_retry(
+            &p,
+            &state(&["a"], None),
+            Trigger::Status(503),
+            0
+        ));
+        assert!(!should_retry(
+            &p,
+            &state(&["a"], None),
+            Trigger::Status(500),
+            0
+        ));
+        assert!(!should_retry(
+            &p,
+            &state(&["a"], None),
+            Trigger::Status(200),
+            0
+        ));
+        assert!(should_retry(
+            &p,
+            &state(&["a", "b"], None),
+            Trigger::Status(502),
+            0
+        ));
+        assert!(!should_retry(
+            &p,
+            &state(&["a", "b", "c"], None),
+            Trigger::Status(502),
+            0
+        ));
+    }
+
+    #[test]
+    fn test_should_retry_on_body_match() {
+        let p = policy(2);
+        assert!(should_retry(
+            &p,
+            &state(&["a"], None),
+            Trigger::BodyMatched,
+            0
+        ));
+        // Attempts still bound body-triggered retries
+        assert!(!should_retry(
+            &p,
+            &state(&["a", "b"], None),
+            Trigger::BodyMatched,
+            0
+        ));
+    }
+
+    #[test]
+    fn test_should_retry_fetch_failure() {
+        // No response to check against retry_on: any failed fetch qualifies
+        let mut p = policy(2);
+        p.retry_on = vec![500];
+        assert!(should_retry(
+            &p,
+            &state(&["a"], None),
+            Trigger::FetchFailed,
+            0
+        ));
+        assert!(!should_retry(
+            &p,
+            &state(&["a", "b"], None),
+            Trigger::FetchFailed,
+            0
+        ));
+        assert!(!should_retry(
+            &p,
+            &state(&["a"], Some(1000)),
+            Trigger::FetchFailed,
+            1000
+        ));
+    }
+
+    #[test]
+    fn test_body_condition_matches_prefix_only() {
+        let cond = BodyCondition {
+            pattern: "try again".to_string(),
+            max_inspect_bytes: 32,
+        };
+        assert!(cond.matches(br#"{"error":"try again"}"#));
+        assert!(!cond.matches(br#"{"ok":true}"#));
+        assert!(!cond.matches(b""));
+        // Past the inspected prefix doesn't count
+        let late = format!("{}try again", " ".repeat(30));
+        assert!(!cond.matches(late.as_bytes()));
+        // Straddling the limit doesn't either
+        let straddle = format!("{}try again", " ".repeat(24));
+        assert!(!cond.matches(straddle.as_bytes()));
+        let fits = format!("{}try again", " ".repeat(23));
+        assert!(cond.matches(fits.as_bytes()));
+    }
+
+    #[test]
+    fn test_is_idempotent() {
+        assert!(is_idempotent("GET"));
+        assert!(is_idempotent("HEAD"));
+        assert!(is_idempotent("OPTIONS"));
+        assert!(!is_idempotent("POST"));
+    }
+
+    #[test]
+    fn test_should_retry_respects_deadline() {
+        let p = policy(3);
+        assert!(should_retry(
+            &p,
+            &state(&["a"], Some(1000)),
+            Trigger::Status(503),
+            999
+        ));
+        assert!(!should_retry(
+            &p,
+            &state(&["a"], Some(1000)),
+            Trigger::Status(503),
+            1000
+        ));
+    }
+
+    #[test]
+    fn test_state_header_roundtrip() {
+        let mut s = state(&["10.0.0.1:80", "10.0.0.2:80"], Some(42));
+        let parsed = RetryState::from_header(&s.to_header()).unwrap();
+        assert_eq!(parsed, s);
+        assert!(!s.to_header().contains("body"));
+        s.body = Some(BodyCondition {
+            pattern: "x".to_string(),
+            max_inspect_bytes: 8,
+        });
+        assert_eq!(RetryState::from_header(&s.to_header()).unwrap(), s);
+        assert_eq!(parsed.attempts(), 2);
+        assert_eq!(parsed.current(), Some("10.0.0.2:80"));
+        assert!(RetryState::from_header("{not json").is_none());
+    }
+}
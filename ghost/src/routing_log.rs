@@ -0,0 +1,188 @@
+//! One-line summaries of routing decisions for VSL.
+//!
+//! With `routing_log` set in ghost.json, each routed request gets a
+//! `VCL_Log` record like
+//!
+//! ```text
+//! ghost: vhost=api.example.com rule=2 path_match=PathPrefix(/api) backend=10.0.0.1:8080 policy=weighted
+//! ```
+//!
+//! Fields are always present and in this order, `-` when not applicable,
+//! so the line can be grepped and split on spaces. `rule=default` marks the
+//! vhost's `default_backends`. Synthetic answers name their kind in place of
+//! a backend key: `redirect`, `not_found`, `unavailable`, `internal_error`,
+//! `bad_request` or `misdirected`. Under `full`, a `filters=` field lists the route
+//! filters that were applied.
+
+use std::fmt::Write;
+
+use crate::config::{RouteFilters, RoutingLog, SelectionPolicy};
+use crate::director::PathMatchCompiled;
+
+/// What routing decided for one request
+pub struct Decision<'a> {
+    pub vhost: Option<&'a str>,
+    pub rule: Option<i32>,
+    pub path_match: Option<&'a PathMatchCompiled>,
+    pub backend: &'a str,
+    pub policy: Option<SelectionPolicy>,
+    pub filters: Option<&'a RouteFilters>,
+}
+
+impl Decision<'_> {
+    /// The log line for `level`, or None when routing logging is off
+    pub fn line(&self, level: RoutingLog) -> Option<String> {
+        if level == RoutingLog::Off {
+            return None;
+        }
+        let mut out = String::with_capacity(160);
+        out.push_str("ghost: vhost=");
+        out.push_str(self.vhost.unwrap_or("-"));
+        out.push_str(" rule=");
+        match self.rule {
+            Some(i32::MAX) => out.push_str("default"),
+            Some(rule) => {
+                let _ = write!(out, "{}", rule);
+            }
+            None => out.push('-'),
+        }
+        out.push_str(" path_match=");
+        match self.path_match {
+            Some(PathMatchCompiled::Exact(path)) => {
+                let _ = write!(out, "Exact({})", path);
+            }
+            Some(PathMatchCompiled::PathPrefix(prefix)) => {
+                let _ = write!(out, "PathPrefix({})", prefix);
+            }
+            Some(PathMatchCompiled::Regex(re)) => {
+                let _ = write!(out, "RegularExpression({})", re.as_str());
+            }
+            None => out.push('-'),
+        }
+        out.push_str(" backend=");
+        out.push_str(self.backend);
+        out.push_str(" policy=");
+        out.push_str(self.policy.map_or("-", SelectionPolicy::as_str));
+
+        if level == RoutingLog::Full {
+            out.push_str(" filters=");
+            let start = out.len();
+            if let Some(f) = self.filters {
+                // A redirect short-circuits every other filter
+                let redirect = f.request_redirect.is_some();
+                for (name, applied) in [
+                    ("request_redirect", redirect),
+                    (
+                        "request_header_modifier",
+                        f.request_header_modifier.is_some(),
+                    ),
+                    ("url_rewrite", f.url_rewrite.is_some()),
+                    (
+                        "response_header_modifier",
+                        f.response_header_modifier.is_some(),
+                    ),
+                    ("request_mirror", f.request_mirror.is_some()),
+                    ("cors", f.cors.is_some()),
+                    ("rate_limit", f.rate_limit.is_some()),
+                    ("nonce", f.nonce.is_some()),
+                ] {
+                    if !applied || (redirect && name != "request_redirect") {
+                        continue;
+                    }
+                    if out.len() > start {
+                        out.push(',');
+                    }
+                    out.push_str(name);
+                }
+            }
+            if out.len() == start {
+                out.push('-');
+            }
+        }
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RequestRedirectFilter, ResponseHeaderFilter};
+    use regex::Regex;
+    use std::sync::Arc;
+
+    fn decision<'a>(path_match: Option<&'a PathMatchCompiled>) -> Decision<'a> {
+        Decision {
+            vhost: Some("api.example.com"),
+            rule: Some(2),
+            path_match,
+            backend: "10.0.0.1:8080",
+            policy: Some(SelectionPolicy::Weighted),
+            filters: None,
+        }
+    }
+
+    #[test]
+    fn test_decision_line() {
+        let prefix = PathMatchCompiled::PathPrefix("/api".to_string());
+        assert_eq!(decision(Some(&prefix)).line(RoutingLog::Off), None);
+        assert_eq!(
+            decision(Some(&prefix)).line(RoutingLog::Decisions).unwrap(),
+            "ghost: vhost=api.example.com rule=2 path_match=PathPrefix(/api) backend=10.0.0.1:8080 policy=weighted"
+        );
+
+        let regex = PathMatchCompiled::Regex(Arc::new(Regex::new("^/v[0-9]+/").unwrap()));
+        let line = decision(Some(&regex)).line(RoutingLog::Decisions).unwrap();
+        assert!(line.contains(" path_match=RegularExpression(^/v[0-9]+/) "));
+
+        let fallback = Decision {
+            vhost: None,
+            rule: None,
+            path_match: None,
+            backend: "not_found",
+            policy: None,
+            filters: None,
+        };
+        assert_eq!(
+            fallback.line(RoutingLog::Full).unwrap(),
+            "ghost: vhost=- rule=- path_match=- backend=not_found policy=- filters=-"
+        );
+
+        let default = Decision {
+            rule: Some(i32::MAX),
+            ..decision(None)
+        };
+        assert!(default
+            .line(RoutingLog::Decisions)
+            .unwrap()
+            .contains(" rule=default path_match=- "));
+    }
+
+    #[test]
+    fn test_decision_line_filters() {
+        let mut filters = RouteFilters {
+            response_header_modifier: Some(ResponseHeaderFilter::default()),
+            ..Default::default()
+        };
+        let line = |filters: &RouteFilters, level| {
+            Decision {
+                filters: Some(filters),
+                ..decision(None)
+            }
+            .line(level)
+            .unwrap()
+        };
+        assert!(!line(&filters, RoutingLog::Decisions).contains("filters="));
+        assert!(line(&filters, RoutingLog::Full).ends_with(" filters=response_header_modifier"));
+
+        filters.request_redirect = Some(RequestRedirectFilter {
+            scheme: Some("https".to_string()),
+            hostname: None,
+            path_type: None,
+            replace_full_path: None,
+            replace_prefix_match: None,
+            port: None,
+            status_code: 301,
+        });
+        assert!(line(&filters, RoutingLog::Full).ends_with(" filters=request_redirect"));
+    }
+}
@@ -0,0 +1,634 @@
+//! Shadow response diffing (route `shadow_diff`)
+//!
+//! A sampled share of a route's GET and HEAD requests is replayed on the
+//! shared background runtime, both to the backend selected for it and to a
+//! shadow upstream. The two responses are compared on the configured fields
+//! and each divergence is appended to a JSON Lines sink, together with the
+//! request it came from, for offline review. The client is served by the
+//! regular fetch: the replays never delay it, and their failures only end up
+//! in the sink.
+//!
+//! Both sampling and the sink are bounded. At most `MAX_IN_FLIGHT` replays
+//! run at a time and further samples are dropped; bodies are hashed as they
+//! stream in; and a sink about to grow past `max_sink_bytes` is rotated to
+//! `<sink>.1`, so it never takes more than twice that on disk.
+//!
+//! A native backend is replayed to by address, with its BackendTLS hostname
+//! pinned to that address. An external proxy is replayed to directly, without
+//! request signing.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, OnceLock, Weak};
+use std::time::{Duration, SystemTime};
+
+use parking_lot::Mutex;
+use reqwest::header::{HeaderMap, CONNECTION};
+use reqwest::Client;
+use ring::digest;
+use serde_json::{json, Map, Value};
+use varnish::vcl::{HttpHeaders, StrOrBytes};
+
+use crate::config::{DiffField, RequestMirrorFilter, ShadowDiff};
+use crate::external_backend::{connection_tokens, is_connection_option, is_hop_by_hop, spawn};
+use crate::format::format_timestamp;
+use crate::mirror::MirrorRequest;
+use crate::signing::hex;
+use crate::trace_context::REQUEST_ID_HEADER;
+
+/// Replays still running after this long are abandoned, and recorded as
+/// failed.
+const DIFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Replay pairs running at once, across all routes. Samples beyond it are
+/// dropped.
+const MAX_IN_FLIGHT: usize = 64;
+
+/// Response headers left out of the comparison, as they differ between any
+/// two responses
+const VOLATILE_HEADERS: &[&str] = &["date", "age", "expires", "x-request-id"];
+
+/// Clients of pinned TLS backends kept at most; the cache starts over when
+/// full.
+const MAX_PINNED_CLIENTS: usize = 256;
+
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Clients resolving a native TLS backend's hostname to its address, by
+/// backend pool key
+static PINNED_CLIENTS: LazyLock<Mutex<HashMap<String, Client>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Sinks in use, by path, so routes and reloads naming the same file share
+/// its size accounting and rotation
+static SINKS: LazyLock<Mutex<HashMap<PathBuf, Weak<Sink>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn client_builder() -> reqwest::ClientBuilder {
+    // Bodies are compared as sent, so no transparent decompression
+    reqwest::ClientBuilder::new()
+        .timeout(DIFF_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none())
+        .no_gzip()
+}
+
+fn client() -> Client {
+    CLIENT
+        .get_or_init(|| {
+            client_builder()
+                .build()
+                .expect("ghost: failed to build shadow diff client")
+        })
+        .clone()
+}
+
+/// Client reaching `hostname` at `addr`, for the backend with pool key `key`
+fn pinned_client(key: &str, hostname: &str, addr: SocketAddr) -> Option<Client> {
+    let mut clients = PINNED_CLIENTS.lock();
+    if let Some(client) = clients.get(key) {
+        return Some(client.clone());
+    }
+    if clients.len() >= MAX_PINNED_CLIENTS {
+        clients.clear();
+    }
+    let client = client_builder().resolve(hostname, addr).build().ok()?;
+    clients.insert(key.to_string(), client.clone());
+    Some(client)
+}
+
+/// Replay target of the backend with pool key `key` (the inverse of
+/// `backend_pool::native_key` and `external_key`), along with the address a
+/// native TLS backend's hostname is pinned to
+fn origin(key: &str) -> Option<(RequestMirrorFilter, Option<SocketAddr>)> {
+    if let Some(url) = key.strip_prefix("external:") {
+        let (scheme, rest) = url.split_once("://")?;
+        let (host, port) = rest.rsplit_once(':')?;
+        let target = RequestMirrorFilter {
+            address: host.trim_matches(['[', ']']).to_string(),
+            port: port.parse().ok()?,
+            tls: scheme == "https",
+            percent: None,
+        };
+        return Some((target, None));
+    }
+    let (addr, hostname) = match key.split_once(":tls:") {
+        Some((addr, hostname)) => (addr, Some(hostname)),
+        None => (key, None),
+    };
+    let (ip, port) = addr.rsplit_once(':')?;
+    let ip: IpAddr = ip.parse().ok()?;
+    let port: u16 = port.parse().ok()?;
+    Some(match hostname {
+        Some(hostname) => (
+            RequestMirrorFilter {
+                address: hostname.to_string(),
+                port,
+                tls: true,
+                percent: None,
+            },
+            Some(SocketAddr::new(ip, port)),
+        ),
+        None => (
+            RequestMirrorFilter {
+                address: ip.to_string(),
+                port,
+                tls: false,
+                percent: None,
+            },
+            None,
+        ),
+    })
+}
+
+/// Slot in the replay limit, released on drop
+struct InFlight;
+
+impl InFlight {
+    fn acquire() -> Option<Self> {
+        if IN_FLIGHT.fetch_add(1, Ordering::Relaxed) >= MAX_IN_FLIGHT {
+            IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(InFlight)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// JSON Lines file the diff records are appended to
+#[derive(Debug)]
+struct Sink {
+    path: PathBuf,
+    /// Open file and its size, opened on first write
+    file: Mutex<Option<(File, u64)>>,
+}
+
+impl Sink {
+    fn get(path: &str) -> Arc<Sink> {
+        let path = PathBuf::from(path);
+        let mut sinks = SINKS.lock();
+        sinks.retain(|_, sink| sink.strong_count() > 0);
+        if let Some(sink) = sinks.get(&path).and_then(Weak::upgrade) {
+            return sink;
+        }
+        let sink = Arc::new(Sink {
+            path: path.clone(),
+            file: Mutex::new(None),
+        });
+        sinks.insert(path, Arc::downgrade(&sink));
+        sink
+    }
+
+    fn open(&self) -> io::Result<(File, u64)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let len = file.metadata()?.len();
+        Ok((file, len))
+    }
+
+    /// Append `line`, first rotating the file to `<path>.1` if the line would
+    /// take it past `max_bytes`.
+    fn append(&self, line: &[u8], max_bytes: u64) -> io::Result<()> {
+        let mut slot = self.file.lock();
+        // Left closed on error, so the next record reopens the file
+        let (mut file, mut len) = match slot.take() {
+            Some(open) => open,
+            None => self.open()?,
+        };
+        if len > 0 && len + line.len() as u64 > max_bytes {
+            drop(file);
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(".1");
+            std::fs::rename(&self.path, rotated)?;
+            (file, len) = self.open()?;
+        }
+        file.write_all(line)?;
+        *slot = Some((file, len + line.len() as u64));
+        Ok(())
+    }
+}
+
+/// What a replay got back, as far as the compared fields go
+#[derive(Debug, Default)]
+struct Observed {
+    status: Option<u16>,
+    headers: BTreeMap<String, String>,
+    body_hash: Option<String>,
+    error: Option<String>,
+}
+
+async fn observe(client: &Client, req: MirrorRequest, fields: &[DiffField]) -> Observed {
+    let mut observed = Observed::default();
+    if let Err(e) = fetch(client, req, fields, &mut observed).await {
+        observed.error = Some(e.to_string());
+    }
+    observed
+}
+
+async fn fetch(
+    client: &Client,
+    req: MirrorRequest,
+    fields: &[DiffField],
+    observed: &mut Observed,
+) -> Result<(), reqwest::Error> {
+    let mut resp = req.build(client).send().await?;
+    observed.status = Some(resp.status().as_u16());
+    if fields.contains(&DiffField::Headers) {
+        observed.headers = comparable_headers(resp.headers());
+    }
+    if fields.contains(&DiffField::BodyHash) {
+        let mut ctx = digest::Context::new(&digest::SHA256);
+        while let Some(chunk) = resp.chunk().await? {
+            ctx.update(&chunk);
+        }
+        observed.body_hash = Some(hex(ctx.finish().as_ref()));
+    }
+    Ok(())
+}
+
+/// End-to-end, non-volatile response headers by lowercase name, repeated
+/// headers joined with ", "
+fn comparable_headers(headers: &HeaderMap) -> BTreeMap<String, String> {
+    let options = connection_tokens(headers.get_all(CONNECTION).iter().map(|v| v.as_bytes()));
+    let mut comparable: BTreeMap<String, String> = BTreeMap::new();
+    for (name, value) in headers {
+        let name = name.as_str();
+        if is_hop_by_hop(name)
+            || is_connection_option(name, &options)
+            || VOLATILE_HEADERS.contains(&name)
+        {
+            continue;
+        }
+        let value = String::from_utf8_lossy(value.as_bytes());
+        comparable
+            .entry(name.to_string())
+            .and_modify(|v| {
+                v.push_str(", ");
+                v.push_str(&value);
+            })
+            .or_insert_with(|| value.into_owned());
+    }
+    comparable
+}
+
+/// The fields in which `primary` and `shadow` differ, each as a
+/// `[primary, shadow]` pair, or None if they agree. A failed replay on
+/// either side is always a difference.
+fn diff(primary: &Observed, shadow: &Observed, fields: &[DiffField]) -> Option<Map<String, Value>> {
+    let mut diff = Map::new();
+    if primary.error.is_some() || shadow.error.is_some() {
+        diff.insert("error".into(), json!([primary.error, shadow.error]));
+    }
+    for field in fields {
+        let pair = match field {
+            DiffField::Status if primary.status != shadow.status => {
+                json!([primary.status, shadow.status])
+            }
+            DiffField::BodyHash if primary.body_hash != shadow.body_hash => {
+                json!([primary.body_hash, shadow.body_hash])
+            }
+            DiffField::Headers => {
+                let mut headers = Map::new();
+                let names = primary.headers.keys().chain(shadow.headers.keys());
+                for name in names {
+                    let (p, s) = (primary.headers.get(name), shadow.headers.get(name));
+                    if p != s {
+                        headers.insert(name.clone(), json!([p, s]));
+                    }
+                }
+                if headers.is_empty() {
+                    continue;
+                }
+                Value::Object(headers)
+            }
+            _ => continue,
+        };
+        diff.insert(field.as_str().into(), pair);
+    }
+    (!diff.is_empty()).then_some(diff)
+}
+
+/// A route's compiled `shadow_diff`
+#[derive(Debug)]
+pub struct ShadowDiffer {
+    config: ShadowDiff,
+    sink: Arc<Sink>,
+}
+
+/// A request picked for replay
+struct Replay {
+    primary: MirrorRequest,
+    primary_client: Client,
+    shadow: MirrorRequest,
+    /// Request context for the diff record
+    record: Map<String, Value>,
+}
+
+impl ShadowDiffer {
+    pub fn new(config: &ShadowDiff) -> Self {
+        Self {
+            config: config.clone(),
+            sink: Sink::get(&config.sink),
+        }
+    }
+
+    /// Replay the request in `http`, routed to `backend` of `route` in
+    /// `vhost`, if it is sampled. Returns why a sampled request wasn't
+    /// replayed.
+    pub fn sample(
+        self: &Arc<Self>,
+        http: &HttpHeaders,
+        vhost: &str,
+        route: Option<&str>,
+        backend: &str,
+    ) -> Option<&'static str> {
+        let method = match http.method()? {
+            StrOrBytes::Utf8(m) if m == "GET" || m == "HEAD" => m.to_string(),
+            _ => return None,
+        };
+        if rand::random::<f64>() * 100.0 >= self.config.percent {
+            return None;
+        }
+        let Some((primary, pin)) = origin(backend) else {
+            return Some("unknown backend address");
+        };
+        let primary_client = match pin {
+            Some(addr) => match pinned_client(backend, &primary.address, addr) {
+                Some(client) => client,
+                None => return Some("no client for backend"),
+            },
+            None => client(),
+        };
+        let Some(slot) = InFlight::acquire() else {
+            return Some("too many replays in flight");
+        };
+        let (Some(primary), Some(shadow)) = (
+            MirrorRequest::from_http(&primary, http),
+            MirrorRequest::from_http(&self.config.backend, http),
+        ) else {
+            return Some("request has a body");
+        };
+
+        let url = match http.url() {
+            Some(StrOrBytes::Utf8(url)) => Some(url.to_string()),
+            _ => None,
+        };
+        let request_id = match http.header(REQUEST_ID_HEADER) {
+            Some(StrOrBytes::Utf8(id)) => Some(id.to_string()),
+            _ => None,
+        };
+        let record = json!({
+            "vhost": vhost,
+            "route": route,
+            "method": method,
+            "url": url,
+            "request_id": request_id,
+            "backend": backend,
+            "shadow": format!("{}:{}", self.config.backend.address, self.config.backend.port),
+        });
+        let Value::Object(record) = record else {
+            unreachable!()
+        };
+        let replay = Replay {
+            primary,
+            primary_client,
+            shadow,
+            record,
+        };
+        let differ = Arc::clone(self);
+        spawn(async move {
+            let _slot = slot;
+            let _ = differ.compare(replay).await;
+        });
+        None
+    }
+
+    /// Run both halves of `replay` and record how they differ
+    async fn compare(&self, replay: Replay) -> io::Result<()> {
+        let fields = &self.config.fields;
+        let shadow_client = client();
+        let (primary, shadow) = tokio::join!(
+            observe(&replay.primary_client, replay.primary, fields),
+            observe(&shadow_client, replay.shadow, fields),
+        );
+        let Some(diff) = diff(&primary, &shadow, fields) else {
+            return Ok(());
+        };
+
+        let mut record = replay.record;
+        record.insert(
+            "time".into(),
+            format_timestamp(Some(SystemTime::now())).into(),
+        );
+        record.insert("diff".into(), Value::Object(diff));
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        let sink = Arc::clone(&self.sink);
+        let max_bytes = self.config.max_sink_bytes;
+        tokio::task::spawn_blocking(move || sink.append(&line, max_bytes))
+            .await
+            .map_err(io::Error::other)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream;
+
+    fn target(port: u16) -> RequestMirrorFilter {
+        RequestMirrorFilter {
+            address: "127.0.0.1".to_string(),
+            port,
+            tls: false,
+            percent: None,
+        }
+    }
+
+    /// Upstream answering every connection with `response`
+    fn upstream(response: &'static str) -> u16 {
+        mock_upstream::every(move |_| response.to_string()).port()
+    }
+
+    fn differ(sink: &std::path::Path, shadow_port: u16) -> ShadowDiffer {
+        ShadowDiffer::new(&ShadowDiff {
+            backend: target(shadow_port),
+            percent: 100.0,
+            sink: sink.to_str().unwrap().to_string(),
+            fields: vec![DiffField::Status, DiffField::BodyHash, DiffField::Headers],
+            max_sink_bytes: 1024 * 1024,
+        })
+    }
+
+    fn replay(primary_port: u16, shadow_port: u16) -> Replay {
+        let request = |port| {
+            MirrorRequest::from_parts(
+                &target(port),
+                "GET",
+                "/items?page=2",
+                [("Host", "app.example.com".as_bytes())].into_iter(),
+            )
+            .unwrap()
+        };
+        let Value::Object(record) = json!({"vhost": "app.example.com", "url": "/items?page=2"})
+        else {
+            unreachable!()
+        };
+        Replay {
+            primary: request(primary_port),
+            primary_client: client(),
+            shadow: request(shadow_port),
+            record,
+        }
+    }
+
+    #[tokio::test]
+    async fn divergent_shadow_response_is_recorded() {
+        let primary = upstream(
+            "HTTP/1.1 200 OK\r\ndate: Mon, 01 Jan 2024 00:00:00 GMT\r\n\
+             content-type: text/plain\r\ncontent-length: 2\r\n\r\nok",
+        );
+        let shadow = upstream(
+            "HTTP/1.1 500 Oops\r\ndate: Tue, 02 Jan 2024 00:00:00 GMT\r\n\
+             content-type: text/html\r\ncontent-length: 4\r\n\r\noops",
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let sink = dir.path().join("diff.jsonl");
+
+        differ(&sink, shadow)
+            .compare(replay(primary, shadow))
+            .await
+            .unwrap();
+
+        let contents = std::fs::read_to_string(&sink).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let record: Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record["vhost"], "app.example.com");
+        assert_eq!(record["url"], "/items?page=2");
+        assert!(record["time"].is_string());
+        let diff = &record["diff"];
+        assert_eq!(diff["status"], json!([200, 500]));
+        assert_eq!(
+            diff["body_hash"][0],
+            "2689367b205c16ce32ed4200942b8b8b1e262dfc70d9bc9fbc77c49699a4f1df"
+        );
+        assert_ne!(diff["body_hash"][0], diff["body_hash"][1]);
+        assert_eq!(
+            diff["headers"],
+            json!({
+                "content-length": ["2", "4"],
+                "content-type": ["text/plain", "text/html"],
+            })
+        );
+        assert!(diff.get("error").is_none());
+    }
+
+    #[tokio::test]
+    async fn matching_responses_are_not_recorded() {
+        let response = "HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok";
+        let primary = upstream(response);
+        let shadow = upstream(response);
+        let dir = tempfile::tempdir().unwrap();
+        let sink = dir.path().join("diff.jsonl");
+
+        differ(&sink, shadow)
+            .compare(replay(primary, shadow))
+            .await
+            .unwrap();
+
+        assert!(!sink.exists());
+    }
+
+    #[tokio::test]
+    async fn failed_shadow_is_recorded() {
+        let primary = upstream("HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok");
+        let closed = mock_upstream::closed_port();
+        let dir = tempfile::tempdir().unwrap();
+        let sink = dir.path().join("diff.jsonl");
+
+        differ(&sink, closed)
+            .compare(replay(primary, closed))
+            .await
+            .unwrap();
+
+        let record: Value = serde_json::from_str(&std::fs::read_to_string(&sink).unwrap()).unwrap();
+        assert_eq!(record["diff"]["status"], json!([200, null]));
+        assert!(record["diff"]["error"][0].is_null());
+        assert!(record["diff"]["error"][1].is_string());
+    }
+
+    #[test]
+    fn test_sink_rotates_past_max_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("diff.jsonl");
+        let sink = Sink::get(path.to_str().unwrap());
+        sink.append(b"first\n", 10).unwrap();
+        sink.append(b"second\n", 10).unwrap();
+        sink.append(b"3\n", 10).unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second\n3\n");
+        let rotated = dir.path().join("diff.jsonl.1");
+        assert_eq!(std::fs::read_to_string(rotated).unwrap(), "first\n");
+
+        // Another route naming the same file shares it
+        assert!(Arc::ptr_eq(&sink, &Sink::get(path.to_str().unwrap())));
+    }
+
+    #[test]
+    fn test_origin_from_backend_key() {
+        let (target, pin) = origin("10.0.0.1:8080").unwrap();
+        assert_eq!(
+            (target.address.as_str(), target.port, target.tls),
+            ("10.0.0.1", 8080, false)
+        );
+        assert!(pin.is_none());
+
+        let (target, _) = origin("fd00::1:8080").unwrap();
+        assert_eq!((target.address.as_str(), target.port), ("fd00::1", 8080));
+
+        let (target, pin) = origin("10.0.0.2:8443:tls:api.internal").unwrap();
+        assert_eq!(
+            (target.address.as_str(), target.port, target.tls),
+            ("api.internal", 8443, true)
+        );
+        assert_eq!(pin, Some("10.0.0.2:8443".parse().unwrap()));
+
+        let (target, pin) = origin("external:https://api.example.org:443").unwrap();
+        assert_eq!(
+            (target.address.as_str(), target.port, target.tls),
+            ("api.example.org", 443, true)
+        );
+        assert!(pin.is_none());
+
+        assert!(origin("garbage").is_none());
+    }
+
+    #[test]
+    fn test_comparable_headers_skip_volatile_and_hop_by_hop() {
+        let mut headers = HeaderMap::new();
+        headers.insert("date", "Mon, 01 Jan 2024 00:00:00 GMT".parse().unwrap());
+        headers.insert("connection", "x-hint".parse().unwrap());
+        headers.insert("x-hint", "1".parse().unwrap());
+        headers.insert("keep-alive", "timeout=5".parse().unwrap());
+        headers.append("vary", "Accept".parse().unwrap());
+        headers.append("vary", "Cookie".parse().unwrap());
+
+        let comparable = comparable_headers(&headers);
+        assert_eq!(comparable.len(), 1);
+        assert_eq!(comparable["vary"], "Accept, Cookie");
+    }
+}
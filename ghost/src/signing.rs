@@ -0,0 +1,115 @@
+//! HMAC request signing for external proxy upstreams.
+//!
+//! The signed string is the method, the path (with query) and a Unix
+//! timestamp in seconds, joined by newlines:
+//!
+//! ```text
+//! GET
+//! /objects/a.png?v=2
+//! 1700000000
+//! ```
+//!
+//! The signature header carries the key id, the timestamp and the hex HMAC:
+//! `keyId=gw-1,timestamp=1700000000,signature=<hex>`.
+
+use std::sync::Arc;
+
+use arc_swap::ArcSwapOption;
+use ring::hmac;
+
+use crate::config::{RequestSigning, SigningAlgorithm};
+
+/// Current signer of an external proxy backend, swapped on reload. Empty
+/// when the upstream doesn't require signing.
+pub type SignerSlot = Arc<ArcSwapOption<RequestSigner>>;
+
+/// Signs requests for one upstream. Built from config on every reload.
+#[derive(Debug)]
+pub struct RequestSigner {
+    key_id: String,
+    header: String,
+    key: hmac::Key,
+}
+
+impl RequestSigner {
+    pub fn new(config: &RequestSigning) -> Self {
+        let algorithm = match config.algorithm {
+            SigningAlgorithm::HmacSha256 => hmac::HMAC_SHA256,
+        };
+        Self {
+            key_id: config.key_id.clone(),
+            header: config.header.clone(),
+            key: hmac::Key::new(algorithm, &config.secret),
+        }
+    }
+
+    /// Name of the header carrying the signature
+    pub fn header(&self) -> &str {
+        &self.header
+    }
+
+    /// Signature header value for a request sent at `timestamp`
+    pub fn sign(&self, method: &str, path: &str, timestamp: u64) -> String {
+        let string_to_sign = format!("{}\n{}\n{}", method, path, timestamp);
+        let tag = hmac::sign(&self.key, string_to_sign.as_bytes());
+        format!(
+            "keyId={},timestamp={},signature={}",
+            self.key_id,
+            timestamp,
+            hex(tag.as_ref())
+        )
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signer(secret: &[u8]) -> RequestSigner {
+        RequestSigner::new(&RequestSigning {
+            key_id: "gw-1".to_string(),
+            secret_ref: "/unused".to_string(),
+            header: "X-Signature".to_string(),
+            algorithm: SigningAlgorithm::HmacSha256,
+            secret: secret.to_vec(),
+        })
+    }
+
+    #[test]
+    fn test_hmac_sha256_known_answer() {
+        // RFC 4231 test case 2
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"Jefe");
+        let tag = hmac::sign(&key, b"what do ya want for nothing?");
+        assert_eq!(
+            hex(tag.as_ref()),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_sign_request() {
+        let s = signer(b"s3cret");
+        assert_eq!(s.header(), "X-Signature");
+        // HMAC-SHA256("s3cret", "GET\n/objects/a.png?v=2\n1700000000")
+        assert_eq!(
+            s.sign("GET", "/objects/a.png?v=2", 1_700_000_000),
+            "keyId=gw-1,timestamp=1700000000,\
+             signature=1ab7ff53dfd93157e591c31eff9ae5759360eef6e527fd881b3f4dd5c494d738"
+        );
+    }
+
+    #[test]
+    fn test_signature_covers_method_path_and_time() {
+        let s = signer(b"s3cret");
+        let base = s.sign("GET", "/a", 1);
+        assert_ne!(base, s.sign("HEAD", "/a", 1));
+        assert_ne!(base, s.sign("GET", "/b", 1));
+        assert_ne!(base, signer(b"other").sign("GET", "/a", 1));
+        let sig = |v: &str| v.rsplit("signature=").next().unwrap().to_string();
+        assert_ne!(sig(&base), sig(&s.sign("GET", "/a", 2)));
+    }
+}
@@ -2,11 +2,151 @@
 //!
 //! Provides per-vhost request counters for observability.
 //! Stats are reset on config reload since they're tied to the current routing state.
+//! Upstream error counts are process-wide and survive reloads, as do
+//! per-backend latency histograms.
 
 use parking_lot::RwLock;
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
+
+use crate::upstream_error::ErrorClass;
+use crate::vsc::VhostVsc;
+
+/// Synthetic error responses per class, indexed like `ErrorClass::ALL`.
+/// Global because external proxy backends are shared between vhosts.
+static UPSTREAM_ERRORS: [AtomicU64; ErrorClass::ALL.len()] =
+    [const { AtomicU64::new(0) }; ErrorClass::ALL.len()];
+
+/// Count a synthetic error response
+pub fn record_upstream_error(class: ErrorClass) {
+    UPSTREAM_ERRORS[class as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Synthetic error responses so far, per class
+pub fn upstream_errors() -> Vec<(ErrorClass, u64)> {
+    ErrorClass::ALL
+        .iter()
+        .map(|&class| {
+            (
+                class,
+                UPSTREAM_ERRORS[class as usize].load(Ordering::Relaxed),
+            )
+        })
+        .collect()
+}
+
+/// Requests answered 404 because no vhost matched their host.
+/// Global because such requests never reach a vhost director.
+static NO_VHOST_MATCH: AtomicU64 = AtomicU64::new(0);
+
+/// Count a request that matched no vhost
+pub fn record_no_vhost_match() {
+    NO_VHOST_MATCH.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Requests that matched no vhost so far
+pub fn no_vhost_match() -> u64 {
+    NO_VHOST_MATCH.load(Ordering::Relaxed)
+}
+
+/// Upper bounds of the latency histogram buckets, in milliseconds. Slower
+/// requests land in a final overflow bucket.
+pub const LATENCY_BUCKETS_MS: [u64; 8] = [1, 5, 10, 50, 100, 500, 1000, 5000];
+
+const BUCKETS: usize = LATENCY_BUCKETS_MS.len() + 1;
+
+/// Fixed-bucket latency histogram. Recording is lock-free: one increment
+/// for the bucket and one for the running sum.
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    sum_us: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            sum_us: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, latency: Duration) {
+        let ms = latency.as_secs_f64() * 1000.0;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&le| ms <= le as f64)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        HistogramSnapshot {
+            counts: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+            sum: Duration::from_micros(self.sum_us.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Point-in-time copy of a `Histogram`
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+    /// Requests per bucket, the overflow bucket last
+    pub counts: [u64; BUCKETS],
+    pub sum: Duration,
+}
+
+impl HistogramSnapshot {
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Estimated latency in milliseconds below which a fraction `q` of
+    /// requests fall, interpolated linearly within the bucket holding that
+    /// rank (as Prometheus' `histogram_quantile` does). Requests in the overflow bucket are reported at its lower
+    /// bound. None without samples.
+    pub fn percentile(&self, q: f64) -> Option<f64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = q.clamp(0.0, 1.0) * count as f64;
+        let mut below = 0u64;
+        for (i, &n) in self.counts.iter().enumerate() {
+            // Tolerate float error in `rank`, e.g. 0.9 * 100 > 90
+            if n > 0 && (below + n) as f64 >= rank - 1e-9 {
+                let lower = if i == 0 {
+                    0.0
+                } else {
+                    LATENCY_BUCKETS_MS[i - 1] as f64
+                };
+                let Some(&upper) = LATENCY_BUCKETS_MS.get(i) else {
+                    return Some(lower);
+                };
+                let fraction = ((rank - below as f64) / n as f64).min(1.0);
+                return Some(lower + (upper as f64 - lower) * fraction);
+            }
+            below += n;
+        }
+        None
+    }
+}
+
+/// Round-trip latency of requests to one backend, recorded by the external
+/// proxy backends. Native backends are fetched by Varnish itself, which
+/// reports their timing in VSL and VBE counters instead.
+#[derive(Debug, Default)]
+pub struct LatencyStats {
+    /// Request sent to response headers received
+    pub first_byte: Histogram,
+    /// Request sent to the end of the response body
+    pub last_byte: Histogram,
+}
 
 /// Statistics for a single vhost director
 #[derive(Debug)]
@@ -17,6 +157,25 @@ pub struct VhostStats {
     pub total_requests: AtomicU64,
     /// Timestamp of last request
     pub last_request: RwLock<Option<SystemTime>>,
+    /// Fetches retried on another backend under a route retry policy
+    pub retries: AtomicU64,
+    /// Selection audit: shadow picks per backend key, to compare with
+    /// `backend_selections`
+    pub shadow_selections: RwLock<HashMap<String, u64>>,
+    /// Requests whose shadow pick differed from the enforced one
+    pub shadow_mismatches: AtomicU64,
+    /// Backend fetches that failed or got a 5xx response
+    pub upstream_errors: AtomicU64,
+    /// Requests answered 503 because no backend of the matched route was
+    /// selectable
+    pub no_backends: AtomicU64,
+    /// Requests answered 500 because the matched route has no backends
+    pub route_no_backends: AtomicU64,
+    /// Requests answered 503 because the selected backend key is missing
+    /// from the backend pool
+    pub missing_backends: AtomicU64,
+    /// The same counts, published to varnishstat
+    vsc: Option<VhostVsc>,
 }
 
 impl VhostStats {
@@ -26,6 +185,23 @@ impl VhostStats {
             backend_selections: RwLock::new(HashMap::new()),
             total_requests: AtomicU64::new(0),
             last_request: RwLock::new(None),
+            retries: AtomicU64::new(0),
+            shadow_selections: RwLock::new(HashMap::new()),
+            shadow_mismatches: AtomicU64::new(0),
+            upstream_errors: AtomicU64::new(0),
+            no_backends: AtomicU64::new(0),
+            route_no_backends: AtomicU64::new(0),
+            missing_backends: AtomicU64::new(0),
+            vsc: None,
+        }
+    }
+
+    /// Stats that are also published to varnishstat as `GHOST.<vhost>.*`,
+    /// with a `GHOST.<vhost>.<backend>.selected` counter per backend key.
+    pub fn published<'a>(vhost: &str, backend_keys: impl IntoIterator<Item = &'a str>) -> Self {
+        Self {
+            vsc: VhostVsc::new(vhost, backend_keys),
+            ..Self::new()
         }
     }
 
@@ -40,6 +216,50 @@ impl VhostStats {
         // Increment backend selection counter
         let mut selections = self.backend_selections.write();
         *selections.entry(backend_key.to_string()).or_insert(0) += 1;
+
+        if let Some(ref vsc) = self.vsc {
+            vsc.record_request(backend_key);
+        }
+    }
+
+    /// Record a fetch retried on another backend
+    pub fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        if let Some(ref vsc) = self.vsc {
+            vsc.record_retry();
+        }
+    }
+
+    /// Record a failed or 5xx backend fetch
+    pub fn record_upstream_error(&self) {
+        self.upstream_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a request answered 503 for lack of a selectable backend
+    pub fn record_no_backends(&self) {
+        self.no_backends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a request answered 500 for a route without backends
+    pub fn record_route_no_backends(&self) {
+        self.route_no_backends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a request answered 503 for a backend missing from the pool
+    pub fn record_missing_backend(&self) {
+        self.missing_backends.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a selection audit: the enforced backend and what the shadow
+    /// selection would have picked (None if it found no backend)
+    pub fn record_shadow(&self, enforced: &str, shadow: Option<&str>) {
+        if shadow != Some(enforced) {
+            self.shadow_mismatches.fetch_add(1, Ordering::Relaxed);
+        }
+        let mut selections = self.shadow_selections.write();
+        *selections
+            .entry(shadow.unwrap_or("-").to_string())
+            .or_insert(0) += 1;
     }
 
     /// Get total requests handled
@@ -56,6 +276,41 @@ impl VhostStats {
     pub fn last_request(&self) -> Option<SystemTime> {
         *self.last_request.read()
     }
+
+    /// Get retried fetches
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Get failed or 5xx backend fetches
+    pub fn upstream_errors(&self) -> u64 {
+        self.upstream_errors.load(Ordering::Relaxed)
+    }
+
+    /// Get requests answered 503 for lack of a selectable backend
+    pub fn no_backends(&self) -> u64 {
+        self.no_backends.load(Ordering::Relaxed)
+    }
+
+    /// Get requests answered 500 for a route without backends
+    pub fn route_no_backends(&self) -> u64 {
+        self.route_no_backends.load(Ordering::Relaxed)
+    }
+
+    /// Get requests answered 503 for a backend missing from the pool
+    pub fn missing_backends(&self) -> u64 {
+        self.missing_backends.load(Ordering::Relaxed)
+    }
+
+    /// Get shadow selections per backend key (cloned snapshot)
+    pub fn shadow_selections(&self) -> HashMap<String, u64> {
+        self.shadow_selections.read().clone()
+    }
+
+    /// Get requests whose shadow selection differed
+    pub fn shadow_mismatches(&self) -> u64 {
+        self.shadow_mismatches.load(Ordering::Relaxed)
+    }
 }
 
 impl Default for VhostStats {
@@ -74,6 +329,115 @@ mod tests {
         assert_eq!(stats.total_requests(), 0);
         assert!(stats.last_request().is_none());
         assert_eq!(stats.backend_selections().len(), 0);
+        assert_eq!(stats.retries(), 0);
+    }
+
+    #[test]
+    fn test_vhost_stats_record_retry() {
+        let stats = VhostStats::new();
+
+        stats.record_retry();
+        stats.record_retry();
+        assert_eq!(stats.retries(), 2);
+        // Retries don't count as requests of their own
+        assert_eq!(stats.total_requests(), 0);
+    }
+
+    #[test]
+    fn test_vhost_stats_record_errors() {
+        let stats = VhostStats::new();
+
+        stats.record_no_backends();
+        stats.record_no_backends();
+        stats.record_route_no_backends();
+        stats.record_missing_backend();
+        assert_eq!(stats.no_backends(), 2);
+        assert_eq!(stats.route_no_backends(), 1);
+        assert_eq!(stats.missing_backends(), 1);
+        // Error responses are not routed requests
+        assert_eq!(stats.total_requests(), 0);
+        assert!(stats.backend_selections().is_empty());
+
+        let before = no_vhost_match();
+        record_no_vhost_match();
+        // Other tests may count concurrently
+        assert!(no_vhost_match() > before);
+    }
+
+    #[test]
+    fn test_vhost_stats_record_shadow() {
+        let stats = VhostStats::new();
+
+        stats.record_shadow("10.0.0.1:8080", Some("10.0.0.1:8080"));
+        stats.record_shadow("10.0.0.1:8080", Some("10.0.0.2:8080"));
+        stats.record_shadow("10.0.0.2:8080", None);
+
+        assert_eq!(stats.shadow_mismatches(), 2);
+        let shadow = stats.shadow_selections();
+        assert_eq!(shadow.get("10.0.0.1:8080"), Some(&1));
+        assert_eq!(shadow.get("10.0.0.2:8080"), Some(&1));
+        assert_eq!(shadow.get("-"), Some(&1));
+        // Audits don't count as requests of their own
+        assert_eq!(stats.total_requests(), 0);
+    }
+
+    fn snapshot(latencies_ms: &[u64]) -> HistogramSnapshot {
+        let h = Histogram::default();
+        for &ms in latencies_ms {
+            h.record(Duration::from_millis(ms));
+        }
+        h.snapshot()
+    }
+
+    #[test]
+    fn test_histogram_buckets() {
+        let s = snapshot(&[0, 1, 2, 5, 7, 60, 999, 1000, 1001, 30_000]);
+        assert_eq!(s.counts, [2, 2, 1, 0, 1, 0, 2, 1, 1]);
+        assert_eq!(s.count(), 10);
+        assert_eq!(s.sum, Duration::from_millis(33_075));
+
+        // Bucket bounds are inclusive, sub-millisecond included
+        let h = Histogram::default();
+        h.record(Duration::from_micros(1_001));
+        assert_eq!(h.snapshot().counts[1], 1);
+    }
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let approx = |p: Option<f64>| p.map(|ms| (ms * 1000.0).round() / 1000.0);
+        assert_eq!(snapshot(&[]).percentile(0.5), None);
+
+        // 100 requests: 50 in (0, 1ms], 40 in (10, 50ms], 10 in (500ms, 1s]
+        let mut latencies = vec![1; 50];
+        latencies.extend([20; 40]);
+        latencies.extend([700; 10]);
+        let s = snapshot(&latencies);
+        assert_eq!(approx(s.percentile(0.5)), Some(1.0));
+        assert_eq!(approx(s.percentile(0.9)), Some(50.0));
+        // Rank 95 is halfway through the (500, 1000] bucket
+        assert_eq!(approx(s.percentile(0.95)), Some(750.0));
+        // Rank 99 is the 9th of 10 requests in (500, 1000]
+        assert_eq!(approx(s.percentile(0.99)), Some(950.0));
+        // Rank 70 is halfway through the (10, 50] bucket
+        assert_eq!(approx(s.percentile(0.7)), Some(30.0));
+
+        // The overflow bucket has no upper bound
+        assert_eq!(snapshot(&[10_000]).percentile(0.99), Some(5000.0));
+    }
+
+    #[test]
+    fn test_upstream_errors() {
+        let count = |class| {
+            upstream_errors()
+                .into_iter()
+                .find(|&(c, _)| c == class)
+                .map(|(_, n)| n)
+                .unwrap()
+        };
+        let before = count(ErrorClass::Tls);
+        record_upstream_error(ErrorClass::Tls);
+        assert_eq!(count(ErrorClass::Tls), before + 1);
+        assert_eq!(upstream_errors().len(), ErrorClass::ALL.len());
     }
 
     #[test]
@@ -0,0 +1,290 @@
+//! W3C trace context and request IDs.
+//!
+//! With `tracing` enabled in ghost.json, routing makes sure every request
+//! carries a valid `traceparent` and an `X-Request-Id`, generating whichever
+//! is missing (or malformed) and keeping valid incoming values untouched.
+//! Both are forwarded to the backend with the rest of the request headers;
+//! `ghost.deliver()` echoes the request ID on the response, and both IDs
+//! are logged to VSL ahead of the routing decision.
+//!
+//! The IDs are only logged for sampled requests, so ghost's trace volume
+//! follows the rest of the pipeline: an incoming `traceparent` is sampled
+//! when its sampled flag is set, and a generated one is sampled for
+//! `tracing.sample_percent` of requests (head-based sampling), its flag
+//! telling backends the outcome.
+//!
+//! Independently, `tracing.connection_id_header` names a header set to an
+//! ID of the client connection the request came in on, so upstream access
+//! logs can group requests by gateway connection. reqwest doesn't expose
+//! which pooled upstream connection a request goes out on, and native
+//! backends don't either, so the ID names the client side of the gateway.
+
+use std::sync::LazyLock;
+
+use varnish::vcl::{HttpHeaders, StrOrBytes};
+
+pub(crate) const TRACEPARENT_HEADER: &str = "traceparent";
+pub(crate) const TRACESTATE_HEADER: &str = "tracestate";
+pub(crate) const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// Header carrying the request ID from routing to `ghost.deliver()`.
+pub(crate) const ECHO_REQUEST_ID_HEADER: &str = "X-Ghost-Request-Id";
+
+/// Longest incoming request ID passed through; longer ones are replaced.
+const MAX_REQUEST_ID_LEN: usize = 200;
+
+/// Tells this varnishd apart from other gateway replicas and its own
+/// earlier runs, as session IDs start over on restart.
+static INSTANCE_ID: LazyLock<u32> = LazyLock::new(rand::random);
+
+/// IDs a request was routed under
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TraceIds {
+    pub trace_id: String,
+    pub request_id: String,
+    /// Whether the trace is sampled, and the IDs logged
+    pub sampled: bool,
+}
+
+impl TraceIds {
+    /// VSL line logged with the routing decision
+    pub fn log_line(&self) -> String {
+        format!(
+            "ghost: trace_id={} request_id={}",
+            self.trace_id, self.request_id
+        )
+    }
+}
+
+fn is_lower_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Trace ID of a valid `traceparent` value.
+///
+/// Follows the W3C Trace Context parsing rules: version `ff` and all-zero
+/// trace or parent IDs are invalid, version `00` has exactly four fields,
+/// and later versions may append fields.
+pub(crate) fn trace_id(traceparent: &str) -> Option<&str> {
+    let mut fields = traceparent.split('-');
+    let version = fields.next()?;
+    let trace_id = fields.next()?;
+    let parent_id = fields.next()?;
+    let flags = fields.next()?;
+    if !is_lower_hex(version, 2) || version == "ff" || (version == "00" && fields.next().is_some())
+    {
+        return None;
+    }
+    if !is_lower_hex(trace_id, 32) || trace_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+    if !is_lower_hex(parent_id, 16) || parent_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+    if !is_lower_hex(flags, 2) {
+        return None;
+    }
+    Some(trace_id)
+}
+
+/// Whether a valid `traceparent` has the sampled flag set
+pub(crate) fn is_sampled(traceparent: &str) -> bool {
+    traceparent
+        .split('-')
+        .nth(3)
+        .and_then(|flags| u8::from_str_radix(flags, 16).ok())
+        .is_some_and(|flags| flags & 0x01 != 0)
+}
+
+/// Whether an incoming request ID can be passed through: 1 to 200 visible
+/// ASCII characters.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// A new root `traceparent`, with the sampled flag telling backends
+/// whether to record the trace.
+fn new_traceparent(trace_id: u128, parent_id: u64, sampled: bool) -> String {
+    // Zero IDs are invalid; the odds of drawing one are negligible, but
+    // a generated header must never be rejected downstream.
+    format!(
+        "00-{:032x}-{:016x}-{:02x}",
+        trace_id.max(1),
+        parent_id.max(1),
+        sampled as u8
+    )
+}
+
+/// A UUIDv7 (RFC 9562): 48-bit Unix milliseconds, then random bits, so
+/// request IDs sort by time.
+fn uuid_v7(unix_ms: u64, random: u128) -> String {
+    let value = ((unix_ms as u128 & 0xffff_ffff_ffff) << 80)
+        | (0x7 << 76)
+        | (((random >> 64) & 0xfff) << 64)
+        | (0b10 << 62)
+        | (random & 0x3fff_ffff_ffff_ffff);
+    let hex = format!("{:032x}", value);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+fn header_str(http: &HttpHeaders, name: &str) -> Option<String> {
+    match http.header(name)? {
+        StrOrBytes::Utf8(s) => Some(s.trim().to_string()),
+        StrOrBytes::Bytes(_) => None,
+    }
+}
+
+/// Give the request a valid `traceparent` and `X-Request-Id`, keeping valid
+/// incoming ones, and stash the request ID for `ghost.deliver()`. A
+/// generated `traceparent` is sampled for `sample_percent` of requests.
+pub(crate) fn apply(http: &mut HttpHeaders, sample_percent: f64) -> TraceIds {
+    let incoming = header_str(http, TRACEPARENT_HEADER);
+    let valid = incoming
+        .as_deref()
+        .and_then(|tp| trace_id(tp).map(|id| (id, is_sampled(tp))));
+    let (trace, sampled) = match valid {
+        Some((id, sampled)) => (id.to_string(), sampled),
+        None => {
+            // tracestate is meaningless without the traceparent it belongs to
+            http.unset_header(TRACEPARENT_HEADER);
+            http.unset_header(TRACESTATE_HEADER);
+            let sampled = rand::random::<f64>() * 100.0 < sample_percent;
+            let traceparent = new_traceparent(rand::random(), rand::random(), sampled);
+            let _ = http.set_header(TRACEPARENT_HEADER, &traceparent);
+            (traceparent[3..35].to_string(), sampled)
+        }
+    };
+
+    let request_id = match header_str(http, REQUEST_ID_HEADER) {
+        Some(id) if is_valid_request_id(&id) => id,
+        _ => {
+            let unix_ms = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0);
+            let id = uuid_v7(unix_ms, rand::random());
+            http.unset_header(REQUEST_ID_HEADER);
+            let _ = http.set_header(REQUEST_ID_HEADER, &id);
+            id
+        }
+    };
+    let _ = http.set_header(ECHO_REQUEST_ID_HEADER, &request_id);
+
+    TraceIds {
+        trace_id: trace,
+        request_id,
+        sampled,
+    }
+}
+
+/// Echo the request ID routing recorded, replacing any upstream (or
+/// cached) copy.
+pub(crate) fn echo_request_id(resp: &mut HttpHeaders, request_id: Option<&str>) {
+    if let Some(id) = request_id {
+        resp.unset_header(REQUEST_ID_HEADER);
+        let _ = resp.set_header(REQUEST_ID_HEADER, id);
+    }
+}
+
+/// ID of the client connection with Varnish session ID `sess_xid`:
+/// `<instance>-<sess_xid>`, the same for every request on the connection.
+pub(crate) fn connection_id(sess_xid: u64) -> String {
+    format!("{:08x}-{}", *INSTANCE_ID, sess_xid)
+}
+
+/// Set `name` to the client connection's ID, replacing any value the
+/// client sent.
+pub(crate) fn set_connection_id(http: &mut HttpHeaders, name: &str, sess_xid: u64) {
+    http.unset_header(name);
+    let _ = http.set_header(name, &connection_id(sess_xid));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_trace_id() {
+        assert_eq!(trace_id(VALID), Some("4bf92f3577b34da6a3ce929d0e0e4736"));
+        // Later versions may append fields
+        assert!(trace_id(&format!("01{}-extra", &VALID[2..])).is_some());
+
+        for bad in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            &format!("{}-extra", VALID),
+            &format!("ff{}", &VALID[2..]),
+            &VALID.to_uppercase(),
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-1",
+        ] {
+            assert_eq!(trace_id(bad), None, "accepted {:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_is_sampled() {
+        assert!(is_sampled(VALID));
+        assert!(!is_sampled(&format!("{}00", &VALID[..VALID.len() - 2])));
+        // Only the sampled bit counts; the rest are reserved or unrelated
+        assert!(is_sampled(&format!("{}03", &VALID[..VALID.len() - 2])));
+        assert!(!is_sampled(&format!("{}02", &VALID[..VALID.len() - 2])));
+    }
+
+    #[test]
+    fn test_connection_id() {
+        let id = connection_id(1001);
+        let (instance, sess) = id.split_once('-').unwrap();
+        assert!(is_lower_hex(instance, 8));
+        assert_eq!(sess, "1001");
+
+        // Stable per connection, distinct across connections
+        assert_eq!(connection_id(1001), id);
+        assert_ne!(connection_id(1004), id);
+        assert!(connection_id(1004).starts_with(instance));
+    }
+
+    #[test]
+    fn test_generated_ids() {
+        let traceparent = new_traceparent(0x4bf9_2f35, 0xf067, true);
+        assert_eq!(
+            traceparent,
+            "00-0000000000000000000000004bf92f35-000000000000f067-01"
+        );
+        assert!(trace_id(&traceparent).is_some());
+        assert!(is_sampled(&traceparent));
+        assert!(trace_id(&new_traceparent(0, 0, true)).is_some());
+
+        let unsampled = new_traceparent(0x4bf9_2f35, 0xf067, false);
+        assert!(unsampled.ends_with("-00"));
+        assert!(trace_id(&unsampled).is_some());
+        assert!(!is_sampled(&unsampled));
+
+        let id = uuid_v7(0x0190_a1b2_c3d4, u128::MAX);
+        assert_eq!(id, "0190a1b2-c3d4-7fff-bfff-ffffffffffff");
+        let id = uuid_v7(0x0190_a1b2_c3d4, 0);
+        assert_eq!(id, "0190a1b2-c3d4-7000-8000-000000000000");
+        assert!(is_valid_request_id(&id));
+    }
+
+    #[test]
+    fn test_request_id_validation() {
+        assert!(is_valid_request_id("req-123"));
+        assert!(is_valid_request_id(&"a".repeat(200)));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id(&"a".repeat(201)));
+        assert!(!is_valid_request_id("has space"));
+        assert!(!is_valid_request_id("caf\u{e9}"));
+    }
+}
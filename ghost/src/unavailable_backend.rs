@@ -0,0 +1,69 @@
+//! Synthetic 503 backend for matched routes with no selectable backend
+//!
+//! This backend generates 503 responses when a request matches a route whose
+//! backends are all unhealthy or ejected. Routes with no backends at all get
+//! the internal error backend's 500 instead.
+
+use varnish::vcl::{Ctx, VclBackend, VclError, VclResponse};
+
+use crate::upstream_error::{synth_response, ErrorClass};
+
+/// Backend that generates synthetic 503 responses
+pub struct UnavailableBackend;
+
+impl VclBackend<UnavailableBody> for UnavailableBackend {
+    fn get_response(&self, ctx: &mut Ctx) -> Result<Option<UnavailableBody>, VclError> {
+        let beresp = ctx
+            .http_beresp
+            .as_mut()
+            .ok_or_else(|| VclError::new("Missing beresp in unavailable backend".to_string()))?;
+        crate::vsc::incr(|c| &c.synth_503);
+        let data = synth_response(beresp, ErrorClass::Unavailable)?;
+
+        Ok(Some(UnavailableBody { data, cursor: 0 }))
+    }
+}
+
+/// Response body for 503 error
+pub struct UnavailableBody {
+    data: &'static [u8],
+    cursor: usize,
+}
+
+impl VclResponse for UnavailableBody {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, VclError> {
+        let remaining = &self.data[self.cursor..];
+        let to_copy = remaining.len().min(buf.len());
+
+        buf[..to_copy].copy_from_slice(&remaining[..to_copy]);
+        self.cursor += to_copy;
+
+        Ok(to_copy)
+    }
+
+    fn len(&self) -> Option<usize> {
+        Some(self.data.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unavailable_body_read() {
+        let mut body = UnavailableBody {
+            data: ErrorClass::Unavailable.body(),
+            cursor: 0,
+        };
+        assert_eq!(body.len(), Some(ErrorClass::Unavailable.body().len()));
+
+        let mut buf = vec![0u8; 100];
+        let n = body.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"{\"error\":\"unavailable\",\"status\":503}\n");
+
+        // Second read should return 0 (EOF)
+        let n = body.read(&mut buf).unwrap();
+        assert_eq!(n, 0);
+    }
+}
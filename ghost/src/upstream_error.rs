@@ -0,0 +1,228 @@
+//! Synthetic responses for requests that never got an upstream answer
+//!
+//! Each failure class maps to its own status so clients and dashboards can
+//! tell a slow upstream (504) from an unreachable one (502) or a route with
+//! no usable backend (503). The body is a small JSON document naming the
+//! class, which is also sent in `x-ghost-error` and counted in stats.
+
+use varnish::vcl::{HttpHeaders, VclError};
+
+/// Response header naming the error class.
+pub const ERROR_CLASS_HEADER: &str = "x-ghost-error";
+
+/// Why no upstream response is available.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// The upstream didn't answer within the request timeout.
+    Timeout,
+    /// Connecting failed: refused or reset connection.
+    Connect,
+    /// The upstream hostname didn't resolve.
+    Dns,
+    /// The TLS handshake failed, e.g. on an untrusted certificate.
+    Tls,
+    /// Any other failure talking to the upstream, typically a response
+    /// that isn't valid HTTP.
+    Upstream,
+    /// The route has backends, but none could be selected (all unhealthy
+    /// or ejected).
+    Unavailable,
+}
+
+impl ErrorClass {
+    /// Every class, in declaration order.
+    pub const ALL: [ErrorClass; 6] = [
+        ErrorClass::Timeout,
+        ErrorClass::Connect,
+        ErrorClass::Dns,
+        ErrorClass::Tls,
+        ErrorClass::Upstream,
+        ErrorClass::Unavailable,
+    ];
+
+    /// Classify a failed reqwest request.
+    pub fn from_reqwest(e: &reqwest::Error) -> Self {
+        if e.is_timeout() {
+            ErrorClass::Timeout
+        } else if e.is_connect() {
+            connect_error_class(e)
+        } else {
+            ErrorClass::Upstream
+        }
+    }
+
+    pub fn status(self) -> u16 {
+        match self {
+            ErrorClass::Timeout => 504,
+            ErrorClass::Connect | ErrorClass::Dns | ErrorClass::Tls | ErrorClass::Upstream => 502,
+            ErrorClass::Unavailable => 503,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ErrorClass::Timeout => "timeout",
+            ErrorClass::Connect => "connect",
+            ErrorClass::Dns => "dns",
+            ErrorClass::Tls => "tls",
+            ErrorClass::Upstream => "upstream",
+            ErrorClass::Unavailable => "unavailable",
+        }
+    }
+
+    /// JSON response body, e.g. `{"error":"timeout","status":504}`.
+    pub fn body(self) -> &'static [u8] {
+        match self {
+            ErrorClass::Timeout => b"{\"error\":\"timeout\",\"status\":504}\n",
+            ErrorClass::Connect => b"{\"error\":\"connect\",\"status\":502}\n",
+            ErrorClass::Dns => b"{\"error\":\"dns\",\"status\":502}\n",
+            ErrorClass::Tls => b"{\"error\":\"tls\",\"status\":502}\n",
+            ErrorClass::Upstream => b"{\"error\":\"upstream\",\"status\":502}\n",
+            ErrorClass::Unavailable => b"{\"error\":\"unavailable\",\"status\":503}\n",
+        }
+    }
+}
+
+/// Tell DNS and TLS failures apart from other connect errors by walking the
+/// error's causes. hyper's connector reports resolver failures as
+/// "dns error"; during connect, only the TLS handshake reads data, so
+/// malformed data means TLS.
+fn connect_error_class(e: &reqwest::Error) -> ErrorClass {
+    let mut source = std::error::Error::source(e);
+    while let Some(err) = source {
+        if err.to_string() == "dns error" {
+            return ErrorClass::Dns;
+        }
+        if err
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(is_invalid_data)
+        {
+            return ErrorClass::Tls;
+        }
+        source = err.source();
+    }
+    ErrorClass::Connect
+}
+
+/// Whether an I/O error is, or wraps, an `InvalidData` one. The TLS stream
+/// nests its error in another io::Error, which `source()` skips.
+fn is_invalid_data(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::InvalidData
+        || e.get_ref()
+            .and_then(|inner| inner.downcast_ref::<std::io::Error>())
+            .is_some_and(is_invalid_data)
+}
+
+/// Turn `beresp` into the synthetic error response for `class` and return
+/// the body to serve with it.
+pub fn synth_response(
+    beresp: &mut HttpHeaders,
+    class: ErrorClass,
+) -> Result<&'static [u8], VclError> {
+    crate::stats::record_upstream_error(class);
+    beresp.set_status(class.status());
+    beresp.set_proto("HTTP/1.1")?;
+    beresp.unset_header("Content-Type");
+    beresp.set_header("Content-Type", "application/json")?;
+    beresp.unset_header("Cache-Control");
+    beresp.set_header("Cache-Control", "no-store")?;
+    beresp.unset_header(ERROR_CLASS_HEADER);
+    beresp.set_header(ERROR_CLASS_HEADER, class.as_str())?;
+    Ok(class.body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_upstream;
+    use std::io::Write;
+
+    #[test]
+    fn test_status_mapping() {
+        assert_eq!(ErrorClass::Timeout.status(), 504);
+        assert_eq!(ErrorClass::Connect.status(), 502);
+        assert_eq!(ErrorClass::Dns.status(), 502);
+        assert_eq!(ErrorClass::Tls.status(), 502);
+        assert_eq!(ErrorClass::Upstream.status(), 502);
+        assert_eq!(ErrorClass::Unavailable.status(), 503);
+    }
+
+    #[test]
+    fn test_body_names_class_and_status() {
+        for class in ErrorClass::ALL {
+            let body: serde_json::Value = serde_json::from_slice(class.body()).unwrap();
+            assert_eq!(body["error"], class.as_str());
+            assert_eq!(body["status"], class.status());
+        }
+    }
+
+    fn classify(url: &str, timeout: std::time::Duration) -> ErrorClass {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let err = rt.block_on(async {
+            let client = reqwest::Client::builder().timeout(timeout).build().unwrap();
+            client.get(url).send().await.unwrap_err()
+        });
+        ErrorClass::from_reqwest(&err)
+    }
+
+    #[test]
+    fn test_from_reqwest_connect_refused() {
+        let url = format!("http://127.0.0.1:{}/", mock_upstream::closed_port());
+        assert_eq!(
+            classify(&url, std::time::Duration::from_secs(5)),
+            ErrorClass::Connect
+        );
+    }
+
+    #[test]
+    fn test_from_reqwest_timeout() {
+        let upstream = mock_upstream::silent();
+        assert_eq!(
+            classify(&upstream.url("/"), std::time::Duration::from_millis(100)),
+            ErrorClass::Timeout
+        );
+        upstream.join();
+    }
+
+    #[test]
+    fn test_from_reqwest_protocol_error() {
+        // Answers with something that isn't HTTP
+        let upstream = mock_upstream::once(|conn, _| {
+            let _ = conn.write_all(b"not http at all\r\n\r\n");
+        });
+        assert_eq!(
+            classify(&upstream.url("/"), std::time::Duration::from_secs(5)),
+            ErrorClass::Upstream
+        );
+        upstream.join();
+    }
+
+    #[test]
+    fn test_from_reqwest_dns_failure() {
+        // .invalid never resolves (RFC 6761)
+        assert_eq!(
+            classify(
+                "http://upstream.invalid/",
+                std::time::Duration::from_secs(5)
+            ),
+            ErrorClass::Dns
+        );
+    }
+
+    #[test]
+    fn test_from_reqwest_tls_failure() {
+        // Answers a TLS ClientHello in plain HTTP
+        let upstream = mock_upstream::once(|conn, _| {
+            let _ = conn.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+        });
+        let url = format!("https://{}/", upstream.addr());
+        assert_eq!(
+            classify(&url, std::time::Duration::from_secs(5)),
+            ErrorClass::Tls
+        );
+        upstream.join();
+    }
+}
@@ -0,0 +1,361 @@
+//! Dry-run validation of ghost.json for `/.varnish-ghost/validate`.
+//!
+//! Runs the checks a reload makes, `config::load()` and then
+//! `director::compile_route()` for every route, without creating backends
+//! or touching the live routing state. The result is a JSON report the
+//! control plane can check before it asks for a reload:
+//!
+//! ```json
+//! {"status":"error","vhosts":1,"routes":2,"backends":1,
+//!  "errors":[{"vhost":"api.example.com","route":"default/api","rule_index":0,
+//!             "path":"^/v1/(","message":"Invalid path match: ..."}],
+//!  "warnings":[]}
+//! ```
+//!
+//! Errors fail a reload; warnings describe configs that load but are
+//! probably not what was meant.
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::backend_pool::{external_key, native_key};
+use crate::config::{self, BackendGroup, Config, Route};
+use crate::director::compile_route;
+use crate::dns::DnsCache;
+
+/// One problem found in the config, with where it was found. Also what a
+/// failed reload reports.
+#[derive(Debug, Clone, Serialize)]
+pub struct Issue {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vhost: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule_index: Option<i32>,
+    /// Path match value of the route
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    pub message: String,
+}
+
+impl Issue {
+    pub fn new(message: impl Into<String>) -> Self {
+        Issue {
+            vhost: None,
+            route: None,
+            rule_index: None,
+            path: None,
+            message: message.into(),
+        }
+    }
+
+    /// A problem with a vhost as a whole, e.g. its default backends
+    pub fn in_vhost(hostname: &str, message: impl Into<String>) -> Self {
+        Issue {
+            vhost: Some(hostname.to_string()),
+            ..Issue::new(message)
+        }
+    }
+
+    /// A problem with one route of a vhost
+    pub fn in_route(hostname: &str, route: &Route, message: impl Into<String>) -> Self {
+        Issue {
+            vhost: Some(hostname.to_string()),
+            route: route.route_name.clone(),
+            rule_index: Some(route.rule_index),
+            path: route.path_match.as_ref().map(|pm| pm.value.clone()),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ref vhost) = self.vhost {
+            write!(f, "vhost '{}'", vhost)?;
+            if let Some(ref route) = self.route {
+                write!(f, ", route '{}'", route)?;
+            }
+            if let Some(rule) = self.rule_index {
+                write!(f, ", rule {}", rule)?;
+            }
+            f.write_str(": ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Outcome of a dry run
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// `ok` when a reload would succeed, `error` otherwise
+    pub status: &'static str,
+    pub vhosts: usize,
+    pub routes: usize,
+    /// Distinct backends, counted like the backend pool keys them
+    pub backends: usize,
+    pub errors: Vec<Issue>,
+    pub warnings: Vec<Issue>,
+}
+
+impl Report {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Validate the config file at `path` as a reload would read it.
+pub fn validate_file(path: &Path) -> Report {
+    let mut warnings = Vec::new();
+    if !path.exists() {
+        warnings.push(Issue::new(format!(
+            "{} does not exist; a reload would load an empty config",
+            path.display()
+        )));
+    }
+    match config::load(path) {
+        Ok(config) => {
+            let mut report = validate_config(&config);
+            warnings.append(&mut report.warnings);
+            report.warnings = warnings;
+            report
+        }
+        Err(e) => Report {
+            status: "error",
+            vhosts: 0,
+            routes: 0,
+            backends: 0,
+            errors: vec![Issue::new(e)],
+            warnings,
+        },
+    }
+}
+
+/// Validate a config that `config::load()` accepted.
+pub fn validate_config(config: &Config) -> Report {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    let mut routes = 0;
+    let mut backends = BTreeSet::new();
+
+    let mut hostnames: Vec<_> = config.vhosts.keys().collect();
+    hostnames.sort();
+    for hostname in hostnames {
+        let vhost = &config.vhosts[hostname];
+        routes += vhost.routes.len();
+        if vhost.routes.is_empty() && vhost.default_backends.is_empty() {
+            warnings.push(Issue::in_vhost(
+                hostname,
+                "vhost has no routes; every request gets a 404",
+            ));
+        }
+
+        for route in &vhost.routes {
+            if let Err(e) = compile_route(route, vhost) {
+                errors.push(Issue::in_route(hostname, route, e));
+            }
+            if !has_backends(&route.backend_groups) {
+                warnings.push(Issue::in_route(
+                    hostname,
+                    route,
+                    "route has no backends; requests get a 500",
+                ));
+            }
+        }
+
+        for group in vhost.routes.iter().flat_map(|r| &r.backend_groups) {
+            backends.extend(backend_keys(group));
+        }
+        for group in &vhost.default_backends {
+            backends.extend(backend_keys(group));
+        }
+    }
+
+    let dns = DnsCache::default();
+    for (host, port) in crate::dns::names(config) {
+        if let Err(e) = dns.resolve(&host, port) {
+            warnings.push(Issue::new(format!(
+                "backend {}:{} does not resolve ({}); requests for it get a 503",
+                host, port, e
+            )));
+        }
+    }
+
+    Report {
+        status: if errors.is_empty() { "ok" } else { "error" },
+        vhosts: config.vhosts.len(),
+        routes,
+        backends: backends.len(),
+        errors,
+        warnings,
+    }
+}
+
+fn has_backends(groups: &[BackendGroup]) -> bool {
+    groups
+        .iter()
+        .any(|g| g.external_proxy.is_some() || !g.backends.is_empty())
+}
+
+fn backend_keys(group: &BackendGroup) -> Vec<String> {
+    match &group.external_proxy {
+        Some(ep) => vec![external_key(ep)],
+        None => group
+            .backends
+            .iter()
+            .map(|b| native_key(&b.address, b.port, group.backend_tls.as_ref()))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn report_for(json: &str) -> serde_json::Value {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(json.as_bytes()).unwrap();
+        serde_json::from_str(&validate_file(file.path()).to_json()).unwrap()
+    }
+
+    #[test]
+    fn test_validate_ok() {
+        let report = report_for(
+            r#"{"version": 2, "vhosts": {
+                "api.example.com": {
+                    "routes": [
+                        {"path_match": {"type": "RegularExpression", "value": "^/v[0-9]+/"},
+                         "backend_groups": [{"backends": [{"address": "10.0.0.1", "port": 8080}]}]},
+                        {"path_match": {"type": "PathPrefix", "value": "/static"},
+                         "backend_groups": [{"backends": [{"address": "10.0.0.2", "port": 8080}]}]}
+                    ],
+                    "default_backends": [{"backends": [{"address": "10.0.0.1", "port": 8080}]}]
+                },
+                "www.example.com": {
+                    "routes": [{"backend_groups": [{"external_proxy": {"hostname": "origin.example.net", "port": 443, "tls": true}}]}]
+                }
+            }}"#,
+        );
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "status": "ok",
+                "vhosts": 2,
+                "routes": 3,
+                "backends": 3,
+                "errors": [],
+                "warnings": []
+            })
+        );
+    }
+
+    #[test]
+    fn test_validate_broken_regex() {
+        let report = report_for(
+            r#"{"version": 2, "vhosts": {"api.example.com": {"routes": [
+                {"path_match": {"type": "PathPrefix", "value": "/ok"},
+                 "backend_groups": [{"backends": [{"address": "10.0.0.1", "port": 8080}]}]},
+                {"path_match": {"type": "RegularExpression", "value": "^/v1/("},
+                 "backend_groups": [{"backends": [{"address": "10.0.0.1", "port": 8080}]}],
+                 "route_name": "default/api", "rule_index": 2}
+            ]}}}"#,
+        );
+        assert_eq!(report["status"], "error");
+        assert_eq!(report["routes"], 2);
+        let errors = report["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["vhost"], "api.example.com");
+        assert_eq!(errors[0]["route"], "default/api");
+        assert_eq!(errors[0]["rule_index"], 2);
+        assert_eq!(errors[0]["path"], "^/v1/(");
+        let message = errors[0]["message"].as_str().unwrap();
+        assert!(message.starts_with("Invalid path match: "), "{}", message);
+    }
+
+    #[test]
+    fn test_validate_load_error_and_warnings() {
+        let report = report_for(r#"{"version": 2, "vhosts": "#);
+        assert_eq!(report["status"], "error");
+        assert!(report["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("failed to parse"));
+        assert!(report["errors"][0].get("vhost").is_none());
+
+        let report = report_for(
+            r#"{"version": 2, "vhosts": {
+                "empty.example.com": {"routes": []},
+                "api.example.com": {"routes": [{"backend_groups": []}]}
+            }}"#,
+        );
+        assert_eq!(report["status"], "ok");
+        let warnings = report["warnings"].as_array().unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0]["vhost"], "api.example.com");
+        assert!(warnings[0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("no backends"));
+        assert_eq!(warnings[1]["vhost"], "empty.example.com");
+
+        let missing = validate_file(Path::new("/nonexistent/ghost.json"));
+        assert_eq!(missing.status, "ok");
+        assert_eq!(missing.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_validate_dns_backends() {
+        let report = report_for(
+            r#"{"version": 2, "vhosts": {"api.example.com": {"routes": [
+                {"path_match": {"type": "PathPrefix", "value": "/local"},
+                 "backend_groups": [{"backends": [{"address": "localhost", "port": 8080}]}]},
+                {"backend_groups": [{"backends": [{"address": "backend.invalid", "port": 8080}]}]}
+            ]}}}"#,
+        );
+        assert_eq!(report["status"], "ok");
+        let warnings = report["warnings"].as_array().unwrap();
+        assert_eq!(warnings.len(), 1);
+        let message = warnings[0]["message"].as_str().unwrap();
+        assert!(
+            message.starts_with("backend backend.invalid:8080 does not resolve"),
+            "{}",
+            message
+        );
+
+        let report = report_for(
+            r#"{"version": 2, "vhosts": {"api.example.com": {"routes": [
+                {"backend_groups": [{"backends": [{"address": "not a host", "port": 8080}]}]}
+            ]}}}"#,
+        );
+        assert_eq!(report["status"], "error");
+        let message = report["errors"][0]["message"].as_str().unwrap();
+        assert!(
+            message.contains("invalid address 'not a host'"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_issue_display_names_location() {
+        let route: Route = serde_json::from_str(
+            r#"{"path_match": {"type": "PathPrefix", "value": "/api"},
+                "backend_groups": [], "route_name": "default/api", "rule_index": 1}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Issue::in_route("api.example.com", &route, "Invalid IP address").to_string(),
+            "vhost 'api.example.com', route 'default/api', rule 1: Invalid IP address"
+        );
+        assert_eq!(
+            Issue::in_vhost("api.example.com", "no backends").to_string(),
+            "vhost 'api.example.com': no backends"
+        );
+        assert_eq!(Issue::new("bad JSON").to_string(), "bad JSON");
+    }
+}
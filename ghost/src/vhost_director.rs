@@ -4,23 +4,90 @@
 //! It's part of a two-tier director architecture where GhostDirector (meta-director)
 //! matches the hostname and delegates to the appropriate VhostDirector.
 
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::SystemTime;
 
+use rand::Rng;
+use regex::Regex;
 use varnish::vcl::{
     BackendRef, Buffer, Ctx, HttpHeaders, LogTag, ProbeResult, StrOrBytes, VclDirector, VclError,
 };
 
-use crate::backend_pool::BackendPool;
-use crate::config::RouteFilters;
-use crate::director::{BypassHeaderCompiled, PathMatchCompiled, RouteEntry, WeightedBackendGroup};
+use crate::backend_pool::{BackendEntry, BackendPool};
+use crate::config::{
+    ForwardHost, HashSource, QosClass, ResponseHeaderFilter, RetryPolicy, RouteFilters,
+    RouteTimeouts, RoutingLog, SelectionPolicy, SessionPersistence,
+};
+use crate::director::{
+    expand_captures, substitute_path, BypassHeaderCompiled, MethodSet, PathMatchCompiled,
+    RouteEntry, ShadowSelectionCompiled, WeightedBackendGroup,
+};
+use crate::hash_ring::{hash_key, HashRing};
+use crate::internal_headers;
+use crate::method_not_allowed_backend::ALLOW_HEADER;
+use crate::mirror::PendingMirror;
+use crate::nonce::REJECTED_HEADER as NONCE_REJECTED_HEADER;
+use crate::rate_limit::RATE_LIMIT_HEADER;
 use crate::redirect_backend::RedirectConfig;
+use crate::retry::{BodyCondition, RetryState, RETRY_STATE_HEADER};
+use crate::routing_log::Decision;
+use crate::shadow_diff::ShadowDiffer;
 use crate::stats::VhostStats;
 use crate::sync_wrapper::SendSyncBackendRef;
+use crate::vrnd;
 
 /// Header name for passing matched route filters to vcl_deliver
 const FILTER_CONTEXT_HEADER: &str = "X-Ghost-Filter-Context";
 
+/// Header carrying the route's backend timeout (e.g. `100ms`) to the fetch.
+/// Read by the external proxy backend and by vcl_backend_fetch for native
+/// backends.
+pub(crate) const BACKEND_TIMEOUT_HEADER: &str = "X-Ghost-Backend-Timeout";
+
+/// Bridges the matched route's load-shedding class to external proxy
+/// backends. Only set for classes other than normal.
+pub(crate) const QOS_HEADER: &str = "X-Ghost-QoS";
+
+/// The matched vhost, which external proxy backends queue requests by when
+/// `max_pending_requests` is reached
+pub(crate) const TENANT_HEADER: &str = "X-Ghost-Tenant";
+
+/// The matched route's `max_request_body_bytes`, enforced by external proxy
+/// backends as they read the body.
+pub(crate) const MAX_BODY_HEADER: &str = "X-Ghost-Max-Body-Bytes";
+
+/// Host the matched route's `forward_host` sends upstream. Applied to bereq
+/// by vcl_backend_fetch for native backends; external proxy backends send
+/// it in place of their hostname.
+pub(crate) const FORWARD_HOST_HEADER: &str = "X-Ghost-Forward-Host";
+
+/// The request's Host before `forward_host` replaced it on bereq, kept for
+/// the ban lurker's `x-cache-host`.
+pub(crate) const CLIENT_HOST_HEADER: &str = "X-Ghost-Client-Host";
+
+/// Marks a request authorized under `authorized_private`. Read by
+/// vcl_backend_response, which makes a response without caching directives
+/// `Cache-Control: private`.
+pub(crate) const PRIVATE_HEADER: &str = "X-Ghost-Private";
+
+/// Header carrying a `Set-Cookie` value for session affinity from routing
+/// to `ghost.deliver()`, which emits it on the client response.
+pub(crate) const AFFINITY_COOKIE_HEADER: &str = "X-Ghost-Affinity-Cookie";
+
+/// Whether a header belongs to the `X-Ghost-*` namespace ghost uses for
+/// internal signalling. These are never accepted from clients nor sent to
+/// upstreams.
+pub(crate) fn is_internal_header(name: &str) -> bool {
+    name.as_bytes()
+        .get(..8)
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(b"x-ghost-"))
+}
+
+/// Source of `VhostDirector::id`, unique for the life of the process.
+static NEXT_VHOST_ID: AtomicU64 = AtomicU64::new(1);
+
 /// Result of route matching containing backend groups, filters, and match context
 #[derive(Debug)]
 pub struct RouteMatchResult<'a> {
@@ -30,6 +97,18 @@ pub struct RouteMatchResult<'a> {
     pub route_name: Option<&'a str>,
     pub cache_policy: Option<&'a crate::config::CachePolicy>,
     pub bypass_headers: &'a [crate::director::BypassHeaderCompiled],
+    pub selection: SelectionPolicy,
+    pub hash_on: Option<&'a HashSource>,
+    pub hash_ring: Option<&'a HashRing>,
+    pub shadow_selection: Option<&'a ShadowSelectionCompiled>,
+    pub shadow_diff: Option<&'a Arc<ShadowDiffer>>,
+    pub timeouts: Option<RouteTimeouts>,
+    pub session_persistence: Option<&'a SessionPersistence>,
+    pub retry: Option<&'a RetryPolicy>,
+    pub qos: QosClass,
+    pub forward_host: Option<&'a ForwardHost>,
+    /// Index of the matched route in the director's route list
+    pub route_index: usize,
 }
 
 /// Result returned by route_request to the caller (recv/resolve).
@@ -38,9 +117,18 @@ pub struct RouteMatchResult<'a> {
 pub struct RouteRequestResult {
     pub backend: Option<BackendRef>,
     pub route_name: Option<String>,
+    /// Rule index of the matched route, for debug headers
+    pub rule_index: Option<i32>,
+    /// Key of the selected backend, for debug headers
+    pub backend_key: Option<String>,
     pub log_msgs: Vec<(LogTag, String)>,
     /// Whether to bypass the cache entirely (return(pass) in VCL terms).
     pub pass: bool,
+    /// The route's `request_ms` budget, when it asks for `X-Ghost-Budget`
+    pub budget_ms: Option<u64>,
+    /// Copy for the route's `request_mirror`, for the caller to send once
+    /// it can attach the request body
+    pub mirror: Option<PendingMirror>,
 }
 
 impl Default for RouteRequestResult {
@@ -50,8 +138,12 @@ impl Default for RouteRequestResult {
         Self {
             backend: None,
             route_name: None,
+            rule_index: None,
+            backend_key: None,
             log_msgs: Vec::new(),
             pass: true,
+            budget_ms: None,
+            mirror: None,
         }
     }
 }
@@ -62,9 +154,12 @@ impl Default for RouteRequestResult {
 /// for all routes belonging to a single hostname.
 #[derive(Debug)]
 pub struct VhostDirector {
+    /// Unique per instance, so retry state can tell whether the director
+    /// that routed a request was replaced by a reload.
+    id: u64,
     /// Hostname this director handles (for debugging/observability)
     hostname: String,
-    /// Routes for this vhost (already sorted by priority)
+    /// Routes for this vhost (already sorted by precedence)
     routes: Vec<RouteEntry>,
     /// Shared backend pool (shared with GhostDirector)
     backend_pool: Arc<BackendPool>,
@@ -72,8 +167,23 @@ pub struct VhostDirector {
     redirect_backend: Option<SendSyncBackendRef>,
     /// Synthetic 500 backend for matched routes with no backends
     internal_error_backend: Option<SendSyncBackendRef>,
+    /// Synthetic 503 backend for matched routes with no selectable backend
+    unavailable_backend: Option<SendSyncBackendRef>,
+    /// Synthetic 204 backend for CORS preflights
+    preflight_backend: Option<SendSyncBackendRef>,
+    /// Synthetic 429 backend for clients over a route's `rate_limit`
+    rate_limited_backend: Option<SendSyncBackendRef>,
+    /// Synthetic 405 backend for requests only a route's `method` refused
+    method_not_allowed_backend: Option<SendSyncBackendRef>,
+    /// Synthetic 400/409 backend for requests a route's `nonce` refused
+    nonce_rejected_backend: Option<SendSyncBackendRef>,
+    /// Response filters of the 404 for requests no route matches: the
+    /// vhost's security headers
+    unmatched_filters: Option<Arc<RouteFilters>>,
     /// Statistics for this vhost
     stats: Arc<VhostStats>,
+    /// VSL logging of routing decisions
+    routing_log: RoutingLog,
 }
 
 impl VhostDirector {
@@ -84,22 +194,74 @@ impl VhostDirector {
         backend_pool: Arc<BackendPool>,
         redirect_backend: Option<BackendRef>,
         internal_error_backend: Option<BackendRef>,
+        unavailable_backend: Option<BackendRef>,
+        routing_log: RoutingLog,
     ) -> Self {
+        let backend_keys = routes
+            .iter()
+            .flat_map(|r| &r.backend_groups)
+            .flat_map(|g| g.backends.iter().chain(&g.draining))
+            .map(String::as_str);
+        let stats = VhostStats::published(&hostname, backend_keys);
         Self {
+            id: NEXT_VHOST_ID.fetch_add(1, Ordering::Relaxed),
             hostname,
             routes,
             backend_pool,
             redirect_backend: redirect_backend.map(SendSyncBackendRef),
             internal_error_backend: internal_error_backend.map(SendSyncBackendRef),
-            stats: Arc::new(VhostStats::new()),
+            unavailable_backend: unavailable_backend.map(SendSyncBackendRef),
+            preflight_backend: None,
+            rate_limited_backend: None,
+            method_not_allowed_backend: None,
+            nonce_rejected_backend: None,
+            unmatched_filters: None,
+            stats: Arc::new(stats),
+            routing_log,
         }
     }
 
+    /// Set the response filters of the 404 answered when no route matches
+    pub fn with_unmatched_filters(mut self, filters: Option<Arc<RouteFilters>>) -> Self {
+        self.unmatched_filters = filters;
+        self
+    }
+
+    /// Set the backend answering CORS preflights of routes with a `cors` filter
+    pub fn with_preflight_backend(mut self, backend: Option<BackendRef>) -> Self {
+        self.preflight_backend = backend.map(SendSyncBackendRef);
+        self
+    }
+
+    /// Set the backend answering clients over a route's `rate_limit`
+    pub fn with_rate_limited_backend(mut self, backend: Option<BackendRef>) -> Self {
+        self.rate_limited_backend = backend.map(SendSyncBackendRef);
+        self
+    }
+
+    /// Set the backend answering requests that only a route's `method` kept
+    /// from matching
+    pub fn with_method_not_allowed_backend(mut self, backend: Option<BackendRef>) -> Self {
+        self.method_not_allowed_backend = backend.map(SendSyncBackendRef);
+        self
+    }
+
+    /// Set the backend answering requests a route's `nonce` refused
+    pub fn with_nonce_rejected_backend(mut self, backend: Option<BackendRef>) -> Self {
+        self.nonce_rejected_backend = backend.map(SendSyncBackendRef);
+        self
+    }
+
     /// Get hostname for this director
     pub fn hostname(&self) -> &str {
         &self.hostname
     }
 
+    /// Get the unique id of this director instance
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
     /// Get stats for this director
     pub fn stats(&self) -> &Arc<VhostStats> {
         &self.stats
@@ -108,9 +270,13 @@ impl VhostDirector {
     /// Check if this director has any routes with backends or filters
     /// Routes with redirect filters but no backends are considered "healthy"
     fn has_backends(&self) -> bool {
-        self.routes
-            .iter()
-            .any(|r| r.backend_groups.iter().any(|g| !g.backends.is_empty()) || r.filters.is_some())
+        self.routes.iter().any(|r| {
+            r.backend_groups.iter().any(|g| {
+                g.selectable()
+                    .iter()
+                    .any(|key| self.backend_pool.is_healthy(key))
+            }) || r.filters.is_some()
+        })
     }
 
     /// Number of routes this director serves (matches the "routes" count in
@@ -119,12 +285,17 @@ impl VhostDirector {
         self.routes.len()
     }
 
+    /// Routes in match order
+    pub fn routes(&self) -> &[RouteEntry] {
+        &self.routes
+    }
+
     /// Collect all backend keys used by this director
     pub fn backend_keys(&self) -> Vec<String> {
         let mut keys = Vec::new();
         for route in &self.routes {
             for group in &route.backend_groups {
-                for key in &group.backends {
+                for key in group.backends.iter().chain(&group.draining) {
                     keys.push(key.clone());
                 }
             }
@@ -172,6 +343,29 @@ impl VhostDirector {
         let msg = format!("  Total requests: {}\n", total);
         let _ = vsb.write(&msg);
 
+        let msg = format!("  Retries: {}\n", self.stats.retries());
+        let _ = vsb.write(&msg);
+
+        let msg = format!(
+            "  Errors: {} no backends (503), {} route without backends (500), \
+             {} missing from pool (503)\n",
+            self.stats.no_backends(),
+            self.stats.route_no_backends(),
+            self.stats.missing_backends()
+        );
+        let _ = vsb.write(&msg);
+
+        let shadow = self.stats.shadow_selections();
+        let audited: u64 = shadow.values().sum();
+        if audited > 0 {
+            let msg = format!(
+                "  Selection audit: {} audited, {} differed\n",
+                audited,
+                self.stats.shadow_mismatches()
+            );
+            let _ = vsb.write(&msg);
+        }
+
         if let Some(last) = self.stats.last_request() {
             let msg = format!("  Last request: {}\n", format_timestamp(Some(last)));
             let _ = vsb.write(&msg);
@@ -213,14 +407,98 @@ impl VhostDirector {
             "health": if self.has_backends() { "healthy" } else { "sick" },
             "routes": self.routes.len(),
             "total_requests": total,
+            "retries": self.stats.retries(),
+            "no_backends": self.stats.no_backends(),
+            "route_no_backends": self.stats.route_no_backends(),
+            "missing_backends": self.stats.missing_backends(),
             "last_request": self.stats.last_request().map(|t| format_timestamp(Some(t))),
-            "backends": backends
+            "backends": backends,
+            "selection_audit": crate::format::format_shadow_json(&self.stats)
         });
 
         let json_str = serde_json::to_string(&obj).unwrap_or_else(|_| "{}".to_string());
         let _ = vsb.write(&json_str);
     }
 
+    /// Push the routing log line for a request that matched a route
+    fn log_decision(
+        &self,
+        log_msgs: &mut Vec<(LogTag, String)>,
+        match_result: &RouteMatchResult,
+        rule_index: Option<i32>,
+        backend: &str,
+        policy: Option<SelectionPolicy>,
+    ) {
+        let decision = Decision {
+            vhost: Some(&self.hostname),
+            rule: rule_index,
+            path_match: match_result.matched_path,
+            backend,
+            policy,
+            filters: match_result.filters.as_deref(),
+        };
+        if let Some(line) = decision.line(self.routing_log) {
+            log_msgs.push((LogTag::VclLog, line));
+        }
+    }
+
+    /// The pool entry of a selected backend. A miss is counted and logged
+    /// with the key, apart from routes whose backends are all down.
+    fn pool_entry(
+        &self,
+        backend_key: &str,
+        log_msgs: &mut Vec<(LogTag, String)>,
+    ) -> Option<BackendEntry> {
+        let entry = self.backend_pool.get(backend_key);
+        if entry.is_none() {
+            self.stats.record_missing_backend();
+            log_msgs.push((
+                LogTag::Error,
+                format!(
+                    "Backend {} selected for vhost {} is missing from the backend pool",
+                    backend_key, self.hostname
+                ),
+            ));
+        }
+        entry
+    }
+
+    /// Run a route's shadow selection next to the enforced pick, count
+    /// the outcome and return the VSL line recording both.
+    fn audit_selection(
+        &self,
+        shadow: &ShadowSelectionCompiled,
+        enforced: &str,
+        hash_key: Option<&str>,
+        route_name: Option<&str>,
+    ) -> String {
+        let healthy = healthy_groups(&shadow.backend_groups, |key| {
+            self.backend_pool.is_healthy(key)
+        });
+        let groups = new_session_groups(&healthy);
+        let picked = select_backend(
+            shadow.selection,
+            &groups,
+            false,
+            shadow.hash_ring.as_deref(),
+            hash_key,
+            |key| self.backend_pool.in_flight(key),
+        )
+        .filter(|key| self.backend_pool.is_healthy(key));
+        self.stats.record_shadow(enforced, picked);
+        format!(
+            "Selection audit: route={} enforced={} shadow={}{}",
+            route_name.unwrap_or("-"),
+            enforced,
+            picked.unwrap_or("-"),
+            if picked == Some(enforced) {
+                ""
+            } else {
+                " (differs)"
+            }
+        )
+    }
+
     /// Route a request using the given HTTP headers.
     ///
     /// This is the core routing logic extracted from resolve() so it can work
@@ -263,7 +541,7 @@ impl VhostDirector {
             )
         };
 
-        // Match routes (already sorted by priority)
+        // Match routes (already sorted by precedence)
         let match_result = match match_routes(
             &self.routes,
             &path_owned,
@@ -274,19 +552,195 @@ impl VhostDirector {
         ) {
             Some(r) => r,
             None => {
+                if let Some(resp_filter) = self
+                    .unmatched_filters
+                    .as_ref()
+                    .and_then(|f| f.response_header_modifier.as_ref())
+                {
+                    let _ = store_filter_context(http, resp_filter);
+                }
+                // Routes refusing only the method make this a 405, not a 404
+                let allow = allowed_methods(
+                    &self.routes,
+                    &path_owned,
+                    http,
+                    query_string_owned.as_deref(),
+                    listener,
+                );
+                if let (Some(allow), Some(backend)) = (allow, &self.method_not_allowed_backend) {
+                    log_msgs.push((
+                        LogTag::Debug,
+                        format!("{} not allowed, routes accept {}", method_owned, allow),
+                    ));
+                    let decision = Decision {
+                        vhost: Some(&self.hostname),
+                        rule: None,
+                        path_match: None,
+                        backend: "method_not_allowed",
+                        policy: None,
+                        filters: None,
+                    };
+                    if let Some(line) = decision.line(self.routing_log) {
+                        log_msgs.push((LogTag::VclLog, line));
+                    }
+                    http.unset_header(ALLOW_HEADER);
+                    let _ = http.set_header(ALLOW_HEADER, &allow);
+                    return RouteRequestResult {
+                        backend: Some(backend.0.clone()),
+                        log_msgs,
+                        ..Default::default()
+                    };
+                }
                 return RouteRequestResult {
                     log_msgs,
                     ..Default::default()
-                }
+                };
             }
         };
         let backend_groups = match_result.backend_groups;
         let matched_filters = match_result.filters.as_ref();
         let route_name = match_result.route_name.map(|s| s.to_string());
+        let rule_index = self
+            .routes
+            .get(match_result.route_index)
+            .map(|r| r.rule_index);
 
         // Apply request filters BEFORE backend selection
+        let mut mirror = None;
         if let Some(filters) = matched_filters {
-            // RequestRedirect - takes precedence over all other filters
+            let origin = header_string(http, "Origin");
+            let request_method = header_string(http, "Access-Control-Request-Method");
+            let preflight = filters.cors.as_ref().filter(|_| {
+                crate::cors::is_preflight(
+                    &method_owned,
+                    origin.as_deref(),
+                    request_method.as_deref(),
+                )
+            });
+
+            // Response filters apply to whatever answers for the route:
+            // the upstream, a redirect, a preflight or a synthetic 500/503.
+            // A request from an allowed origin also gets its CORS headers
+            // this way; a preflight gets them from the preflight backend.
+            match (&filters.cors, origin.as_deref()) {
+                (Some(cors), Some(origin)) if preflight.is_none() => {
+                    let mut resp_filter =
+                        filters.response_header_modifier.clone().unwrap_or_default();
+                    crate::cors::add_response_headers(&mut resp_filter, cors, origin);
+                    let _ = store_filter_context(http, &resp_filter);
+                }
+                _ => {
+                    if let Some(resp_filter) = &filters.response_header_modifier {
+                        let _ = store_filter_context(http, resp_filter);
+                    }
+                }
+            }
+
+            // Rate limit - ahead of anything else the route does, so a
+            // throttled client costs no redirect, preflight or mirror.
+            // Requests without the key share one bucket.
+            if let Some(limiter) = self
+                .routes
+                .get(match_result.route_index)
+                .and_then(|r| r.rate_limiter.as_ref())
+            {
+                let key = extract_hash_key(http, &limiter.limit().key).unwrap_or_default();
+                if let Err(wait) = limiter.check(&key) {
+                    let retry_after = crate::rate_limit::retry_after_secs(wait);
+                    log_msgs.push((
+                        LogTag::Debug,
+                        format!("Rate limited {}, retry after {}s", key, retry_after),
+                    ));
+                    http.unset_header(crate::rate_limit::RETRY_AFTER_HEADER);
+                    let _ = http.set_header(
+                        crate::rate_limit::RETRY_AFTER_HEADER,
+                        &retry_after.to_string(),
+                    );
+                    self.log_decision(
+                        &mut log_msgs,
+                        &match_result,
+                        rule_index,
+                        "rate_limited",
+                        None,
+                    );
+                    return RouteRequestResult {
+                        backend: self.rate_limited_backend.as_ref().map(|r| r.0.clone()),
+                        route_name,
+                        rule_index,
+                        log_msgs,
+                        ..Default::default()
+                    };
+                }
+            }
+
+            // CORS preflight - answered here, ahead of redirects, since
+            // browsers don't follow a redirected preflight
+            if let (Some(cors), Some(origin)) = (preflight, origin.as_deref()) {
+                let request_headers = header_string(http, "Access-Control-Request-Headers");
+                let headers = crate::cors::preflight_headers(
+                    cors,
+                    origin,
+                    request_method.as_deref(),
+                    request_headers.as_deref(),
+                );
+                log_msgs.push((
+                    LogTag::Debug,
+                    format!(
+                        "CORS preflight from {} {}",
+                        origin,
+                        if headers.is_empty() {
+                            "refused"
+                        } else {
+                            "allowed"
+                        }
+                    ),
+                ));
+                http.unset_header(crate::cors::PREFLIGHT_HEADER);
+                if let Ok(json) = serde_json::to_string(&headers) {
+                    let _ = http.set_header(crate::cors::PREFLIGHT_HEADER, &json);
+                }
+                self.log_decision(&mut log_msgs, &match_result, rule_index, "preflight", None);
+                return RouteRequestResult {
+                    backend: self.preflight_backend.as_ref().map(|r| r.0.clone()),
+                    route_name,
+                    rule_index,
+                    log_msgs,
+                    ..Default::default()
+                };
+            }
+
+            // Nonce - after preflights, which browsers send without one
+            if let Some(store) = self
+                .routes
+                .get(match_result.route_index)
+                .and_then(|r| r.nonce_store.as_ref())
+            {
+                let validated = &store.filter().validated_header;
+                http.unset_header(validated);
+                let nonce = header_string(http, &store.filter().header);
+                if let Err(rejection) = store.check(nonce.as_deref()) {
+                    log_msgs.push((LogTag::Debug, format!("Nonce {}", rejection.as_str())));
+                    http.unset_header(NONCE_REJECTED_HEADER);
+                    let _ = http.set_header(NONCE_REJECTED_HEADER, rejection.as_str());
+                    self.log_decision(
+                        &mut log_msgs,
+                        &match_result,
+                        rule_index,
+                        "nonce_rejected",
+                        None,
+                    );
+                    return RouteRequestResult {
+                        backend: self.nonce_rejected_backend.as_ref().map(|r| r.0.clone()),
+                        route_name,
+                        rule_index,
+                        log_msgs,
+                        ..Default::default()
+                    };
+                }
+                let _ = http.set_header(validated, "true");
+            }
+
+            // RequestRedirect - takes precedence over the request filters
             if let Some(redirect_filter) = &filters.request_redirect {
                 log_msgs.push((
                     LogTag::Debug,
@@ -324,14 +778,26 @@ impl VhostDirector {
                     PathMatchCompiled::Regex(_) => None,
                 });
 
+                // Resolve capture references in ReplaceFullPath against the
+                // request path, so the redirect backend sees a plain path
+                let mut filter = redirect_filter.clone();
+                if let Some(template) = &filter.replace_full_path {
+                    filter.replace_full_path = Some(expand_captures(
+                        template,
+                        match_result.matched_path,
+                        &path_owned,
+                    ));
+                }
+
                 let redirect_config = RedirectConfig {
-                    filter: redirect_filter.clone(),
+                    filter,
                     original_scheme,
                     original_hostname,
                     original_port,
                     original_path: path_owned.clone(),
                     original_query: query_string_owned.clone().unwrap_or_default(),
                     matched_path: matched_path_str,
+                    nonce: crate::redirect_backend::redirect_nonce().to_string(),
                 };
 
                 let config_json = match serde_json::to_string(&redirect_config) {
@@ -343,6 +809,7 @@ impl VhostDirector {
                         ));
                         return RouteRequestResult {
                             route_name: route_name.clone(),
+                            rule_index,
                             log_msgs,
                             ..Default::default()
                         };
@@ -358,14 +825,17 @@ impl VhostDirector {
                     ));
                     return RouteRequestResult {
                         route_name: route_name.clone(),
+                        rule_index,
                         log_msgs,
                         ..Default::default()
                     };
                 }
 
+                self.log_decision(&mut log_msgs, &match_result, rule_index, "redirect", None);
                 return RouteRequestResult {
                     backend: self.redirect_backend.as_ref().map(|r| r.0.clone()),
                     route_name: route_name.clone(),
+                    rule_index,
                     log_msgs,
                     ..Default::default()
                 };
@@ -378,7 +848,16 @@ impl VhostDirector {
 
             if let Some(url_rewrite) = &filters.url_rewrite {
                 log_msgs.push((LogTag::Debug, "Applying URL rewrite filter".to_string()));
-                match apply_url_rewrite_filter(http, url_rewrite, match_result.matched_path) {
+                let rewrite_regex = self
+                    .routes
+                    .get(match_result.route_index)
+                    .and_then(|r| r.url_rewrite_regex.as_deref());
+                match apply_url_rewrite_filter(
+                    http,
+                    url_rewrite,
+                    match_result.matched_path,
+                    rewrite_regex,
+                ) {
                     Ok(msgs) => log_msgs.extend(msgs),
                     Err(e) => {
                         log_msgs.push((LogTag::Error, format!("URL rewrite failed: {}", e)));
@@ -386,61 +865,287 @@ impl VhostDirector {
                 }
             }
 
-            if filters.response_header_modifier.is_some() {
-                let _ = store_filter_context(http, filters);
-            }
+            // The mirror sees the request as rewritten by the filters above
+            mirror = filters
+                .request_mirror
+                .as_ref()
+                .filter(|target| crate::mirror::sampled(target))
+                .and_then(|target| PendingMirror::from_http(target, http));
         }
 
         // Determine cache behavior from policy
         let pass = apply_cache_policy_headers(http, &match_result, &query_string_owned);
 
-        // Select backend using two-level weighted random:
-        // Level 1: pick a group by weight
-        // Level 2: pick a random pod within the selected group
-        let backend_key = match select_backend_from_groups(backend_groups) {
+        // Per-route backend timeout. Must unset first since set_header() appends
+        // a header slot, and a restart may land on a route without timeouts.
+        // With retries, each attempt gets the per-try timeout.
+        http.unset_header(BACKEND_TIMEOUT_HEADER);
+        let route_timeout = match_result.timeouts.and_then(|t| t.effective_ms());
+        let budget_ms = match_result
+            .timeouts
+            .filter(|t| t.expose_budget_header)
+            .and_then(|t| t.request_ms);
+        let per_try_timeout = match_result.retry.and_then(|r| r.per_try_timeout_ms);
+        let backend_timeout = match (route_timeout, per_try_timeout) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        if let Some(ms) = backend_timeout {
+            let _ = http.set_header(BACKEND_TIMEOUT_HEADER, &format!("{}ms", ms));
+        }
+
+        http.unset_header(QOS_HEADER);
+        if match_result.qos != QosClass::Normal {
+            let _ = http.set_header(QOS_HEADER, match_result.qos.as_str());
+        }
+        http.unset_header(TENANT_HEADER);
+        let _ = http.set_header(TENANT_HEADER, &self.hostname);
+
+        http.unset_header(MAX_BODY_HEADER);
+        if let Some(limit) = self
+            .routes
+            .get(match_result.route_index)
+            .and_then(|r| r.max_request_body_bytes)
+        {
+            let _ = http.set_header(MAX_BODY_HEADER, &limit.to_string());
+        }
+
+        // Set once a backend is selected, since `backend` mode depends on it
+        http.unset_header(FORWARD_HOST_HEADER);
+        http.unset_header(RATE_LIMIT_HEADER);
+
+        // Session affinity: a valid cookie pins the request to its backend.
+        // A cookie for a backend that left the route falls through to normal
+        // selection and gets replaced.
+        http.unset_header(AFFINITY_COOKIE_HEADER);
+        let healthy_groups =
+            healthy_groups(backend_groups, |key| self.backend_pool.is_healthy(key));
+        let pinned = match_result.session_persistence.and_then(|sp| {
+            let cookies = match http.header("Cookie")? {
+                StrOrBytes::Utf8(s) => s.to_string(),
+                StrOrBytes::Bytes(b) => String::from_utf8_lossy(b).into_owned(),
+            };
+            let token = cookie_value(&cookies, &sp.cookie_name)?;
+            find_affinity_backend(&healthy_groups, token)
+        });
+        let new_session_groups = new_session_groups(&healthy_groups);
+
+        // Otherwise select backend according to the route's selection policy
+        let equal_weights = self
+            .routes
+            .get(match_result.route_index)
+            .is_some_and(|r| r.equal_weights);
+        let hash_key = match_result
+            .hash_on
+            .and_then(|src| extract_hash_key(http, src));
+        let selected = pinned.or_else(|| {
+            select_backend(
+                match_result.selection,
+                &new_session_groups,
+                equal_weights,
+                match_result.hash_ring,
+                hash_key.as_deref(),
+                |key| self.backend_pool.in_flight(key),
+            )
+            // The hash ring is built per config load and may still point at
+            // a backend that has since failed its health check.
+            .filter(|key| self.backend_pool.is_healthy(key))
+            .or_else(|| select_backend_from_groups(&new_session_groups))
+        });
+
+        // Selection audit: what the shadow strategy would have picked. Pinned
+        // requests are left out, since affinity overrides either strategy.
+        if let (None, Some(shadow), Some(enforced)) =
+            (pinned, match_result.shadow_selection, selected)
+        {
+            let msg =
+                self.audit_selection(shadow, enforced, hash_key.as_deref(), route_name.as_deref());
+            log_msgs.push((LogTag::Debug, msg));
+        }
+
+        if let (None, Some(sp), Some(key)) = (pinned, match_result.session_persistence, selected) {
+            let _ = store_affinity_cookie(http, sp, key);
+        }
+
+        let backend_key = match selected {
             Some(key) => key,
             None => {
+                // A route without backends is a config error (500); one whose
+                // backends are all down is temporarily unavailable (503).
+                let (fallback, kind) = if has_configured_backends(backend_groups) {
+                    self.stats.record_no_backends();
+                    (&self.unavailable_backend, "unavailable")
+                } else {
+                    self.stats.record_route_no_backends();
+                    (&self.internal_error_backend, "internal_error")
+                };
+                self.log_decision(
+                    &mut log_msgs,
+                    &match_result,
+                    rule_index,
+                    kind,
+                    Some(match_result.selection),
+                );
                 return RouteRequestResult {
-                    backend: self.internal_error_backend.as_ref().map(|r| r.0.clone()),
+                    backend: fallback.as_ref().map(|r| r.0.clone()),
                     route_name,
+                    rule_index,
+                    backend_key: None,
                     log_msgs,
                     pass,
+                    budget_ms,
+                    mirror,
                 };
             }
         };
 
+        // Routing state and pool are swapped separately on reload, so a key
+        // can briefly name a backend the pool no longer has. That's not an
+        // outage of the route: answer 503 and say which key.
+        let Some(entry) = self.pool_entry(backend_key, &mut log_msgs) else {
+            self.log_decision(
+                &mut log_msgs,
+                &match_result,
+                rule_index,
+                "missing_backend",
+                Some(match_result.selection),
+            );
+            return RouteRequestResult {
+                backend: self.unavailable_backend.as_ref().map(|r| r.0.clone()),
+                route_name,
+                rule_index,
+                backend_key: None,
+                log_msgs,
+                pass,
+                budget_ms,
+                mirror,
+            };
+        };
+
         // Record stats
         self.stats.record_request(backend_key);
 
-        // Look up in backend pool
-        let entry = match self.backend_pool.get(backend_key) {
-            Some(e) => e,
-            None => {
-                return RouteRequestResult {
-                    backend: None,
-                    route_name,
-                    log_msgs,
-                    pass,
-                }
+        if let Some(differ) = match_result.shadow_diff {
+            if let Some(reason) =
+                differ.sample(http, &self.hostname, route_name.as_deref(), backend_key)
+            {
+                log_msgs.push((LogTag::Debug, format!("Shadow diff skipped: {}", reason)));
             }
-        };
+        }
+
+        // The fetch takes a token in vcl_backend_fetch, after the cache
+        // lookup, so cache hits don't count against the limit.
+        if self.backend_pool.rate_limit(backend_key).is_some() {
+            let _ = http.set_header(RATE_LIMIT_HEADER, backend_key);
+        }
+
+        let request_host = http.header("host").map(|h| match h {
+            StrOrBytes::Utf8(s) => s.to_string(),
+            StrOrBytes::Bytes(b) => String::from_utf8_lossy(b).into_owned(),
+        });
+        if let Some(host) = match_result.forward_host.and_then(|mode| {
+            forward_host_value(
+                mode,
+                request_host.as_deref(),
+                self.backend_pool.host_name(backend_key),
+            )
+        }) {
+            let _ = http.set_header(FORWARD_HOST_HEADER, &host);
+        }
+
+        // Retry state for the backend-side retry hooks. Unset first: a
+        // restart may land on a route without retries.
+        http.unset_header(RETRY_STATE_HEADER);
+        if let Some(policy) = match_result.retry {
+            if crate::retry::method_allowed(policy, &method_owned) {
+                let state = RetryState {
+                    vhost: self.id,
+                    route: match_result.route_index,
+                    tried: vec![backend_key.to_string()],
+                    deadline_ms: match_result
+                        .timeouts
+                        .and_then(|t| t.request_ms)
+                        .map(|ms| crate::retry::now_ms() + ms),
+                    // Buffering the body to look for errors only makes
+                    // sense for requests that are safe to repeat.
+                    body: policy
+                        .retry_on_body
+                        .as_ref()
+                        .filter(|_| crate::retry::is_idempotent(&method_owned))
+                        .map(BodyCondition::from),
+                };
+                let _ = http.set_header(RETRY_STATE_HEADER, &state.to_header());
+            }
+        }
 
+        self.log_decision(
+            &mut log_msgs,
+            &match_result,
+            rule_index,
+            backend_key,
+            Some(match_result.selection),
+        );
         RouteRequestResult {
             backend: Some(entry.backend_ref()),
             route_name,
+            rule_index,
+            backend_key: Some(backend_key.to_string()),
             log_msgs,
             pass,
+            budget_ms,
+            mirror,
+        }
+    }
+
+    /// Pick a backend for another attempt on route `route`, skipping the
+    /// backends already tried and unhealthy ones. None when the route has
+    /// nothing left to try.
+    pub fn select_retry_backend(&self, route: usize, tried: &[String]) -> Option<String> {
+        let entry = self.routes.get(route)?;
+        let untried = healthy_groups(&entry.backend_groups, |key| {
+            self.backend_pool.is_healthy(key) && !tried.iter().any(|t| t == key)
+        });
+        let candidates = new_session_groups(&untried);
+        // The hash ring would map the request straight back to the backend
+        // that just failed, so consistent-hash routes retry by weight.
+        let key = select_backend(
+            entry.selection,
+            &candidates,
+            entry.equal_weights,
+            None,
+            None,
+            |key| self.backend_pool.in_flight(key),
+        )?;
+        self.stats.record_request(key);
+        self.stats.record_retry();
+        Some(key.to_string())
+    }
+
+    /// Host to send when a retry of route `route` moves to backend `key`.
+    /// Only `forward_host: backend` depends on the backend; the other modes
+    /// keep the value routing chose.
+    pub fn retry_forward_host(&self, route: usize, key: &str) -> Option<&str> {
+        match self.routes.get(route)?.forward_host {
+            Some(ForwardHost::Backend) => self.backend_pool.host_name(key),
+            _ => None,
         }
     }
+
+    /// Retry policy of route `route`, if it has one
+    pub fn retry_policy(&self, route: usize) -> Option<&RetryPolicy> {
+        self.routes.get(route)?.retry.as_ref()
+    }
 }
 
 impl VclDirector for VhostDirector {
     fn resolve(&self, ctx: &mut Ctx) -> Option<BackendRef> {
+        internal_headers::restore(ctx);
         let bereq = ctx.http_bereq.as_mut()?;
         let result = self.route_request(bereq, None);
         for (tag, msg) in result.log_msgs {
             ctx.log(tag, &msg);
         }
+        internal_headers::hide(ctx);
         result.backend
     }
 
@@ -470,7 +1175,7 @@ impl VclDirector for VhostDirector {
     }
 }
 
-/// Match routes against all conditions (already sorted by priority)
+/// Match routes against all conditions (already sorted by precedence)
 /// All conditions within a match are AND-ed together.
 /// The listener parameter filters routes by which Varnish listener received the request.
 fn match_routes<'a>(
@@ -481,41 +1186,13 @@ fn match_routes<'a>(
     query_string: Option<&str>,
     listener: Option<&str>,
 ) -> Option<RouteMatchResult<'a>> {
-    for route in routes {
-        // Listener filter (empty = match all)
-        if !route.listeners.is_empty() {
-            match listener {
-                Some(l) if route.listeners.iter().any(|rl| rl == l) => {}
-                _ => continue,
-            }
-        }
-
-        // Check path match
-        if let Some(ref pm) = route.path_match {
-            if !pm.matches(path) {
-                continue;
-            }
-        }
-
+    for (route_index, route) in routes.iter().enumerate() {
         // Check method match
-        if let Some(ref m) = route.method {
-            if m != method {
-                continue;
-            }
-        }
-
-        // Check header matches (all must match - AND)
-        if !route.headers.iter().all(|hm| hm.matches(http)) {
+        if route.method.is_some_and(|m| !m.contains(method)) {
             continue;
         }
 
-        // Check query param matches (all must match - AND)
-        if let Some(qs) = query_string {
-            if !route.query_params.iter().all(|qpm| qpm.matches(qs)) {
-                continue;
-            }
-        } else if !route.query_params.is_empty() {
-            // No query string but route requires query params
+        if !matches_except_method(route, path, http, query_string, listener) {
             continue;
         }
 
@@ -528,12 +1205,83 @@ fn match_routes<'a>(
             route_name: route.route_name.as_deref(),
             cache_policy: route.cache_policy.as_ref(),
             bypass_headers: &route.bypass_headers,
+            selection: route.selection,
+            hash_on: route.hash_on.as_ref(),
+            hash_ring: route.hash_ring.as_deref(),
+            shadow_selection: route.shadow_selection.as_ref(),
+            shadow_diff: route.shadow_diff.as_ref(),
+            timeouts: route.timeouts,
+            session_persistence: route.session_persistence.as_ref(),
+            retry: route.retry.as_ref(),
+            qos: route.qos,
+            forward_host: route.forward_host.as_ref(),
+            route_index,
         });
     }
 
     None
 }
 
+/// Whether a route matches on everything but its `method`: listener, path,
+/// headers and query params.
+fn matches_except_method(
+    route: &RouteEntry,
+    path: &str,
+    http: &HttpHeaders,
+    query_string: Option<&str>,
+    listener: Option<&str>,
+) -> bool {
+    // Listener filter (empty = match all)
+    if !route.listeners.is_empty() {
+        match listener {
+            Some(l) if route.listeners.iter().any(|rl| rl == l) => {}
+            _ => return false,
+        }
+    }
+
+    // Check path match
+    if let Some(ref pm) = route.path_match {
+        if !pm.matches(path) {
+            return false;
+        }
+    }
+
+    // Check header matches (all must match - AND)
+    if !route.headers.iter().all(|hm| hm.matches(http)) {
+        return false;
+    }
+
+    // Check query param matches (all must match - AND). Without a query
+    // string, only `Absent` ones can.
+    let qs = query_string.unwrap_or_default();
+    route.query_params.iter().all(|qpm| qpm.matches(qs))
+}
+
+/// `Allow` value for a request no route matched: the methods of the routes
+/// that would have matched with another method. None when there are none,
+/// so the request is a plain 404.
+fn allowed_methods(
+    routes: &[RouteEntry],
+    path: &str,
+    http: &HttpHeaders,
+    query_string: Option<&str>,
+    listener: Option<&str>,
+) -> Option<String> {
+    allow_header(
+        routes
+            .iter()
+            .filter(|r| matches_except_method(r, path, http, query_string, listener))
+            .filter_map(|r| r.method)
+            .flat_map(MethodSet::iter),
+    )
+}
+
+/// Methods sorted and without duplicates, comma-joined
+fn allow_header<'a>(methods: impl Iterator<Item = &'a str>) -> Option<String> {
+    let methods: std::collections::BTreeSet<&str> = methods.collect();
+    (!methods.is_empty()).then(|| methods.into_iter().collect::<Vec<_>>().join(", "))
+}
+
 /// Apply cache policy to the request. Returns whether to pass (bypass cache).
 ///
 /// Sets bereq-bridging headers for values that need to reach vcl_backend_response:
@@ -691,47 +1439,289 @@ fn filter_query_params(query_string: &str, include: &[String], exclude: &[String
     filtered.join("&")
 }
 
+/// Pick a backend key for a matched route according to its selection policy.
+///
+/// `groups` come from `new_session_groups`: draining backends are only
+/// reachable through the session persistence cookie. The hash ring leaves
+/// them out too, since a hash key (client IP, a header) doesn't make the
+/// request part of an existing session.
+///
+/// `equal_weights` says every group weighs the same, as flagged on the
+/// route when routing is built, letting weighted picks skip the weights.
+fn select_backend<'a>(
+    selection: SelectionPolicy,
+    groups: &'a [WeightedBackendGroup],
+    equal_weights: bool,
+    hash_ring: Option<&'a HashRing>,
+    hash_key: Option<&str>,
+    in_flight: impl Fn(&str) -> u64,
+) -> Option<&'a str> {
+    match selection {
+        // Two-level weighted random:
+        // Level 1: pick a group by weight
+        // Level 2: pick a random pod within the selected group
+        SelectionPolicy::Weighted => select_weighted(groups, equal_weights),
+        SelectionPolicy::LeastConn => select_least_conn_from_groups(groups, in_flight),
+        // Requests without the hash attribute fall back to weighted random.
+        SelectionPolicy::ConsistentHash => match (hash_key, hash_ring) {
+            (Some(key), Some(ring)) => ring.get(key.as_bytes()),
+            _ => select_weighted(groups, equal_weights),
+        },
+    }
+}
+
 /// Select a backend using two-level weighted random selection:
 /// Level 1: pick a group by weight (skip weight-0 groups)
 /// Level 2: uniform random within selected group
 fn select_backend_from_groups(groups: &[WeightedBackendGroup]) -> Option<&str> {
+    select_weighted(groups, false)
+}
+
+/// Two-level weighted random selection. With `equal_weights` (all groups
+/// share one non-zero weight) level 1 is a uniform pick, which is what the
+/// cumulative walk would come to anyway.
+fn select_weighted(groups: &[WeightedBackendGroup], equal_weights: bool) -> Option<&str> {
+    select_weighted_with(groups, equal_weights, &mut vrnd::rng())
+}
+
+/// `select_weighted` drawing from `rng`, so a seeded one repeats its picks
+fn select_weighted_with<'a>(
+    groups: &'a [WeightedBackendGroup],
+    equal_weights: bool,
+    rng: &mut impl Rng,
+) -> Option<&'a str> {
     if groups.is_empty() {
         return None;
     }
 
-    use rand::Rng;
-    let mut rng = rand::thread_rng();
+    let selected_group = if equal_weights {
+        &groups[rng.gen_range(0..groups.len())]
+    } else {
+        // Level 1: pick a group by weight. Sum as u64 so many high-weight
+        // groups can't overflow the accumulator (weights are u32 and
+        // operator-supplied).
+        let total_weight: u64 = groups.iter().map(|g| g.weight as u64).sum();
+
+        if total_weight == 0 {
+            return None;
+        }
 
-    // Level 1: pick a group by weight. Sum as u64 so many high-weight groups
-    // can't overflow the accumulator (weights are u32 and operator-supplied).
-    let total_weight: u64 = groups.iter().map(|g| g.weight as u64).sum();
+        let r = rng.gen_range(0..total_weight);
+        let mut cumulative = 0u64;
+        let mut selected_group = &groups[0];
+        for group in groups {
+            cumulative += group.weight as u64;
+            if r < cumulative {
+                selected_group = group;
+                break;
+            }
+        }
+        selected_group
+    };
 
-    if total_weight == 0 {
+    let backends = selected_group.selectable();
+    if backends.is_empty() {
         return None;
     }
 
+    // Level 2: uniform random within selected group
+    if backends.len() == 1 {
+        return Some(&backends[0]);
+    }
+
+    let idx = rng.gen_range(0..backends.len());
+    Some(&backends[idx])
+}
+
+/// Select the backend with the fewest in-flight requests.
+///
+/// `in_flight` reports the current load per backend key. Backends in weight-0
+/// groups are never considered. When several backends share the minimum
+/// load, the tie is broken with the usual two-level weighted random pick
+/// restricted to the tied backends.
+fn select_least_conn_from_groups(
+    groups: &[WeightedBackendGroup],
+    in_flight: impl Fn(&str) -> u64,
+) -> Option<&str> {
+    // Snapshot loads once so concurrent updates can't empty the tie set.
+    let loads: Vec<(u32, Vec<(&str, u64)>)> = groups
+        .iter()
+        .filter(|g| g.weight > 0)
+        .map(|g| {
+            let backends = g
+                .selectable()
+                .iter()
+                .map(|b| (b.as_str(), in_flight(b)))
+                .collect();
+            (g.weight, backends)
+        })
+        .collect();
+
+    let min = loads
+        .iter()
+        .flat_map(|(_, backends)| backends.iter().map(|(_, load)| *load))
+        .min()?;
+
+    let tied: Vec<(u32, Vec<&str>)> = loads
+        .into_iter()
+        .filter_map(|(weight, backends)| {
+            let keys: Vec<&str> = backends
+                .into_iter()
+                .filter(|(_, load)| *load == min)
+                .map(|(key, _)| key)
+                .collect();
+            (!keys.is_empty()).then_some((weight, keys))
+        })
+        .collect();
+
+    let mut rng = vrnd::rng();
+
+    let total_weight: u64 = tied.iter().map(|(w, _)| *w as u64).sum();
     let r = rng.gen_range(0..total_weight);
     let mut cumulative = 0u64;
-    let mut selected_group = &groups[0];
-    for group in groups {
-        cumulative += group.weight as u64;
+    let mut selected = &tied[0].1;
+    for (weight, keys) in &tied {
+        cumulative += *weight as u64;
         if r < cumulative {
-            selected_group = group;
+            selected = keys;
             break;
         }
     }
 
-    if selected_group.backends.is_empty() {
-        return None;
+    Some(selected[rng.gen_range(0..selected.len())])
+}
+
+/// Drop backends that failed their active health check. Groups left empty
+/// are dropped too, so their weight shifts to groups that can still serve.
+/// Borrows when nothing is unhealthy, which is the common case.
+/// Whether any group of a route lists a backend, draining ones and ones
+/// whose DNS name didn't resolve included.
+fn has_configured_backends(groups: &[WeightedBackendGroup]) -> bool {
+    groups
+        .iter()
+        .any(|g| !g.backends.is_empty() || !g.draining.is_empty() || !g.unresolved.is_empty())
+}
+
+/// Groups a new session may go to. Groups whose backends are all draining
+/// are dropped, shifting their weight to the others, unless nothing but
+/// draining backends is left, which then still serve rather than fail.
+fn new_session_groups(groups: &[WeightedBackendGroup]) -> Cow<'_, [WeightedBackendGroup]> {
+    let draining_only = |g: &WeightedBackendGroup| g.backends.is_empty() && !g.draining.is_empty();
+    let any_live = groups
+        .iter()
+        .any(|g| g.weight > 0 && !g.backends.is_empty());
+    if !any_live || !groups.iter().any(draining_only) {
+        return Cow::Borrowed(groups);
     }
+    Cow::Owned(
+        groups
+            .iter()
+            .filter(|g| !draining_only(g))
+            .cloned()
+            .collect(),
+    )
+}
 
-    // Level 2: uniform random within selected group
-    if selected_group.backends.len() == 1 {
-        return Some(&selected_group.backends[0]);
+fn healthy_groups(
+    groups: &[WeightedBackendGroup],
+    is_healthy: impl Fn(&str) -> bool,
+) -> Cow<'_, [WeightedBackendGroup]> {
+    let all_healthy = groups
+        .iter()
+        .flat_map(|g| g.backends.iter().chain(&g.draining))
+        .all(|key| is_healthy(key));
+    if all_healthy {
+        return Cow::Borrowed(groups);
     }
 
-    let idx = rng.gen_range(0..selected_group.backends.len());
-    Some(&selected_group.backends[idx])
+    let filtered = groups
+        .iter()
+        .filter_map(|g| {
+            let backends: Vec<String> = g
+                .backends
+                .iter()
+                .filter(|k| is_healthy(k))
+                .cloned()
+                .collect();
+            let draining: Vec<String> = g
+                .draining
+                .iter()
+                .filter(|k| is_healthy(k))
+                .cloned()
+                .collect();
+            let was_empty = g.backends.is_empty() && g.draining.is_empty();
+            if backends.is_empty() && draining.is_empty() && !was_empty {
+                return None;
+            }
+            Some(WeightedBackendGroup {
+                weight: g.weight,
+                backends,
+                draining,
+                unresolved: g.unresolved.clone(),
+            })
+        })
+        .collect();
+    Cow::Owned(filtered)
+}
+
+/// Opaque affinity cookie value for a backend key. Hashed so pod addresses
+/// aren't exposed to clients.
+fn affinity_token(backend_key: &str) -> String {
+    format!("{:016x}", hash_key(backend_key.as_bytes()))
+}
+
+/// Backend in the route whose affinity token matches, draining ones included
+/// so pinned sessions survive a drain.
+fn find_affinity_backend<'a>(groups: &'a [WeightedBackendGroup], token: &str) -> Option<&'a str> {
+    groups
+        .iter()
+        .filter(|g| g.weight > 0)
+        .flat_map(|g| g.backends.iter().chain(&g.draining))
+        .find(|key| affinity_token(key) == token)
+        .map(String::as_str)
+}
+
+/// `Set-Cookie` value pinning the client to a backend.
+fn affinity_set_cookie(sp: &SessionPersistence, backend_key: &str) -> String {
+    let mut cookie = format!(
+        "{}={}; Path=/; HttpOnly",
+        sp.cookie_name,
+        affinity_token(backend_key)
+    );
+    if let Some(max_age) = sp.max_age_seconds {
+        cookie.push_str(&format!("; Max-Age={}", max_age));
+    }
+    cookie
+}
+
+/// Read the request attribute a consistent-hash route hashes on.
+fn extract_hash_key(http: &HttpHeaders, source: &HashSource) -> Option<String> {
+    let header = |name: &str| -> Option<String> {
+        match http.header(name)? {
+            StrOrBytes::Utf8(s) => Some(s.to_string()),
+            StrOrBytes::Bytes(b) => Some(String::from_utf8_lossy(b).into_owned()),
+        }
+    };
+    match source {
+        HashSource::ClientIp => {
+            first_forwarded_for(&header("X-Forwarded-For")?).map(str::to_string)
+        }
+        HashSource::Header { name } => header(name).filter(|v| !v.is_empty()),
+        HashSource::Cookie { name } => cookie_value(&header("Cookie")?, name).map(str::to_string),
+    }
+}
+
+/// First (client-most) address in an `X-Forwarded-For` value.
+fn first_forwarded_for(xff: &str) -> Option<&str> {
+    xff.split(',').map(str::trim).find(|s| !s.is_empty())
+}
+
+/// Value of a named cookie in a `Cookie` header, if present and non-empty.
+fn cookie_value<'a>(cookie_header: &'a str, name: &str) -> Option<&'a str> {
+    cookie_header.split(';').find_map(|pair| {
+        let (k, v) = pair.trim().split_once('=')?;
+        (k.trim() == name && !v.is_empty()).then_some(v.trim())
+    })
 }
 
 /// Extract path and query string from URL
@@ -773,31 +1763,72 @@ fn apply_request_header_filter(
     }
 
     // Add headers (appends to existing value per Gateway API spec)
-    // Must unset+set to avoid duplicate header slots
     for action in &filter.add {
-        if let Some(existing) = http.header(&action.name) {
-            let existing_str = match existing {
-                StrOrBytes::Utf8(s) => s.to_string(),
-                StrOrBytes::Bytes(b) => String::from_utf8_lossy(b).to_string(),
-            };
-            let combined = format!("{},{}", existing_str, action.value);
-            http.unset_header(&action.name);
-            http.set_header(&action.name, &combined)?;
-        } else {
-            http.set_header(&action.name, &action.value)?;
-        }
+        add_header(http, &action.name, &action.value)?;
     }
 
     Ok(())
 }
 
-/// Apply URL rewrite filter to HTTP headers.
-/// Returns a list of log messages to be emitted by the caller.
-fn apply_url_rewrite_filter(
-    http: &mut HttpHeaders,
-    filter: &crate::config::URLRewriteFilter,
-    matched_path: Option<&PathMatchCompiled>,
-) -> Result<Vec<(LogTag, String)>, VclError> {
+/// Headers whose instances must stay on separate lines: a `Set-Cookie`
+/// can't be comma-joined at all, and joined challenges are misparsed by
+/// many clients.
+const SEPARATE_LINE_HEADERS: &[&str] = &["set-cookie", "www-authenticate", "proxy-authenticate"];
+
+/// Whether `add` comma-joins onto a header present `instances` times rather
+/// than appending a line of its own. Only a single instance is joined onto:
+/// replacing several would collapse them.
+fn joins_onto(name: &str, instances: usize) -> bool {
+    instances == 1
+        && !SEPARATE_LINE_HEADERS
+            .iter()
+            .any(|h| name.eq_ignore_ascii_case(h))
+}
+
+/// Add `value` to header `name` (HTTPHeaderFilter `add`): comma-joined onto
+/// a single existing instance, otherwise appended as its own line, so
+/// repeated headers such as `Set-Cookie` all survive.
+pub(crate) fn add_header(http: &mut HttpHeaders, name: &str, value: &str) -> Result<(), VclError> {
+    let instances = http
+        .into_iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case(name))
+        .count();
+    if !joins_onto(name, instances) {
+        // set_header() appends a header slot
+        return http.set_header(name, value);
+    }
+    let existing = match http.header(name) {
+        Some(StrOrBytes::Utf8(s)) => s.to_string(),
+        Some(StrOrBytes::Bytes(b)) => String::from_utf8_lossy(b).to_string(),
+        None => String::new(),
+    };
+    let combined = format!("{},{}", existing, value);
+    http.unset_header(name);
+    http.set_header(name, &combined)
+}
+
+/// Host header value for a route's `forward_host` mode, given the request's
+/// (possibly rewritten) Host and the selected backend's name.
+fn forward_host_value(
+    mode: &ForwardHost,
+    request_host: Option<&str>,
+    backend_host: Option<&str>,
+) -> Option<String> {
+    match mode {
+        ForwardHost::Preserve => request_host.map(str::to_string),
+        ForwardHost::Backend => backend_host.map(str::to_string),
+        ForwardHost::Literal(host) => Some(host.clone()),
+    }
+}
+
+/// Apply URL rewrite filter to HTTP headers.
+/// Returns a list of log messages to be emitted by the caller.
+fn apply_url_rewrite_filter(
+    http: &mut HttpHeaders,
+    filter: &crate::config::URLRewriteFilter,
+    matched_path: Option<&PathMatchCompiled>,
+    rewrite_regex: Option<&Regex>,
+) -> Result<Vec<(LogTag, String)>, VclError> {
     let mut log_msgs = Vec::new();
 
     // Rewrite hostname — must unset first since set_header() appends
@@ -818,11 +1849,12 @@ fn apply_url_rewrite_filter(
                             StrOrBytes::Bytes(b) => std::str::from_utf8(b).ok(),
                         })
                         .unwrap_or("/");
-                    let (_, query) = extract_path_and_query(current_url);
+                    let (current_path, query) = extract_path_and_query(current_url);
+                    let path = expand_captures(path, matched_path, current_path);
                     let final_url = if let Some(q) = query {
                         format!("{}?{}", path, q)
                     } else {
-                        path.to_string()
+                        path
                     };
                     http.set_url(&final_url)?;
                 }
@@ -855,6 +1887,29 @@ fn apply_url_rewrite_filter(
                     }
                 }
             }
+            "RegexSubstitution" => {
+                if let (Some(re), Some(substitution)) = (rewrite_regex, &filter.substitution) {
+                    let current_url = http
+                        .url()
+                        .and_then(|u| match u {
+                            StrOrBytes::Utf8(s) => Some(s),
+                            StrOrBytes::Bytes(b) => std::str::from_utf8(b).ok(),
+                        })
+                        .unwrap_or("/");
+                    let (path, query) = extract_path_and_query(current_url);
+                    let new_path = substitute_path(re, substitution, path);
+                    log_msgs.push((
+                        LogTag::Debug,
+                        format!("RegexSubstitution: {} -> {}", path, new_path),
+                    ));
+                    let final_url = if let Some(q) = query {
+                        format!("{}?{}", new_path, q)
+                    } else {
+                        new_path
+                    };
+                    http.set_url(&final_url)?;
+                }
+            }
             _ => {
                 log_msgs.push((
                     LogTag::Error,
@@ -888,43 +1943,24 @@ fn apply_replace_prefix_match(
     matched_path: Option<&PathMatchCompiled>,
 ) -> (String, Option<(LogTag, String)>) {
     match matched_path {
-        Some(PathMatchCompiled::PathPrefix(matched_prefix)) => {
-            // Replace the matched prefix with new_prefix
-            if path.starts_with(matched_prefix) {
-                let remainder = &path[matched_prefix.len()..];
-                let trimmed_new = new_prefix.trim_end_matches('/');
-
-                let result = if remainder.is_empty() {
-                    let r = trimmed_new.to_string();
-                    if r.is_empty() {
-                        "/".to_string()
-                    } else {
-                        r
-                    }
-                } else if remainder.starts_with('/') {
-                    format!("{}{}", trimmed_new, remainder)
-                } else {
-                    format!("{}/{}", trimmed_new, remainder)
-                };
+        Some(PathMatchCompiled::PathPrefix(matched) | PathMatchCompiled::Exact(matched)) => {
+            // Replace the matched prefix with new_prefix. An Exact match is
+            // the whole path, so nothing remains after it.
+            if let Some(remainder) = path.strip_prefix(matched.as_str()) {
+                let result = join_replaced_prefix(new_prefix, remainder);
                 let log = format!(
                     "ReplacePrefixMatch: {} + {} -> {}",
-                    matched_prefix, remainder, result
+                    matched, remainder, result
                 );
                 (result, Some((LogTag::Debug, log)))
             } else {
                 let msg = format!(
                     "ReplacePrefixMatch: path {} doesn't start with matched prefix {}",
-                    path, matched_prefix
+                    path, matched
                 );
                 (path.to_string(), Some((LogTag::Error, msg)))
             }
         }
-        Some(PathMatchCompiled::Exact(_)) => {
-            // Exact match: treat as ReplaceFullPath
-            let msg =
-                "ReplacePrefixMatch with Exact match - treating as full replacement".to_string();
-            (new_prefix.to_string(), Some((LogTag::Debug, msg)))
-        }
         Some(PathMatchCompiled::Regex(_)) => {
             // Regex: cannot extract matched portion, use heuristic
             let msg = "ReplacePrefixMatch with RegularExpression not supported - using heuristic"
@@ -945,6 +1981,23 @@ fn apply_replace_prefix_match(
     }
 }
 
+/// `new_prefix` followed by what remained of the path after the matched
+/// prefix, with exactly one `/` between them whether or not either side
+/// brings its own. Shared by rewrites and redirects.
+pub(crate) fn join_replaced_prefix(new_prefix: &str, remainder: &str) -> String {
+    let trimmed_new = new_prefix.trim_end_matches('/');
+    if remainder.is_empty() {
+        if trimmed_new.is_empty() {
+            "/".to_string()
+        } else {
+            trimmed_new.to_string()
+        }
+    } else {
+        // A remainder of only slashes keeps one, as a trailing slash
+        format!("{}/{}", trimmed_new, remainder.trim_start_matches('/'))
+    }
+}
+
 /// Heuristic for replacing the first path segment when matched prefix is unknown
 ///
 /// This is a **fallback behavior** used when `ReplacePrefixMatch` is configured but:
@@ -976,19 +2029,14 @@ fn apply_replace_prefix_match(
 /// # Implementation
 ///
 /// 1. Split path on `/` into at most 3 parts: `["", "v1", "users/123"]`
-/// 2. Replace the second part (first segment) with `new_prefix` (trimmed of trailing `/`)
-/// 3. Append the remainder (third part, if any)
+/// 2. Replace the second part (first segment) with `new_prefix`
+/// 3. Append the remainder (third part, if any) with `join_replaced_prefix`
 pub(crate) fn replace_first_segment_heuristic(path: &str, new_prefix: &str) -> String {
     if path.starts_with('/') {
         let segments: Vec<&str> = path.splitn(3, '/').collect();
         if segments.len() >= 2 {
             let remainder = if segments.len() > 2 { segments[2] } else { "" };
-            let trimmed_new = new_prefix.trim_end_matches('/');
-            if remainder.is_empty() {
-                trimmed_new.to_string()
-            } else {
-                format!("{}/{}", trimmed_new, remainder)
-            }
+            join_replaced_prefix(new_prefix, remainder)
         } else {
             new_prefix.to_string()
         }
@@ -1029,17 +2077,35 @@ fn parse_host_and_port(host_header: &str) -> (&str, Option<u16>) {
 
 fn store_filter_context(
     http: &mut HttpHeaders,
-    filters: &Arc<RouteFilters>,
+    resp_filter: &ResponseHeaderFilter,
 ) -> Result<(), VclError> {
-    // Serialize response filter to JSON
-    if let Some(resp_filter) = &filters.response_header_modifier {
-        let json = serde_json::to_string(resp_filter)
-            .map_err(|e| VclError::new(format!("serialize filter: {}", e)))?;
-        // Must unset first since set_header() appends a header slot.
-        http.unset_header(FILTER_CONTEXT_HEADER);
-        http.set_header(FILTER_CONTEXT_HEADER, &json)?;
+    let json = serde_json::to_string(resp_filter)
+        .map_err(|e| VclError::new(format!("serialize filter: {}", e)))?;
+    // Must unset first since set_header() appends a header slot.
+    http.unset_header(FILTER_CONTEXT_HEADER);
+    http.set_header(FILTER_CONTEXT_HEADER, &json)?;
+    Ok(())
+}
+
+/// A request header's value, if it has one that is valid UTF-8
+fn header_string(http: &HttpHeaders, name: &str) -> Option<String> {
+    match http.header(name)? {
+        StrOrBytes::Utf8(s) => Some(s.to_string()),
+        StrOrBytes::Bytes(b) => std::str::from_utf8(b).ok().map(str::to_string),
     }
+}
 
+/// Stash the affinity `Set-Cookie` on req for `ghost.deliver()`.
+fn store_affinity_cookie(
+    http: &mut HttpHeaders,
+    sp: &SessionPersistence,
+    backend_key: &str,
+) -> Result<(), VclError> {
+    http.unset_header(AFFINITY_COOKIE_HEADER);
+    http.set_header(
+        AFFINITY_COOKIE_HEADER,
+        &affinity_set_cookie(sp, backend_key),
+    )?;
     Ok(())
 }
 #[cfg(test)]
@@ -1065,6 +2131,12 @@ mod tests {
         );
         assert_eq!(extract_path_and_query(""), ("/", None));
         assert_eq!(extract_path_and_query("?query"), ("/", Some("query")));
+        // Protocol-relative-looking targets stay paths
+        assert_eq!(extract_path_and_query("//foo"), ("//foo", None));
+        assert_eq!(
+            extract_path_and_query("//evil.com/x?a=1"),
+            ("//evil.com/x", Some("a=1"))
+        );
     }
 
     #[test]
@@ -1072,6 +2144,8 @@ mod tests {
         let groups = vec![WeightedBackendGroup {
             weight: 100,
             backends: vec!["10.0.0.1:8080".to_string()],
+            draining: Vec::new(),
+            unresolved: Vec::new(),
         }];
         let selected = select_backend_from_groups(&groups).unwrap();
         assert_eq!(selected, "10.0.0.1:8080");
@@ -1084,10 +2158,14 @@ mod tests {
             WeightedBackendGroup {
                 weight: 90,
                 backends: vec!["10.0.0.1:8080".to_string(), "10.0.0.2:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
             },
             WeightedBackendGroup {
                 weight: 10,
                 backends: vec!["10.0.0.3:8080".to_string(), "10.0.0.4:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
             },
         ];
 
@@ -1116,6 +2194,114 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_select_weighted_equal_weights() {
+        let group = |weight, backend: &str| WeightedBackendGroup {
+            weight,
+            backends: vec![backend.to_string()],
+            draining: Vec::new(),
+            unresolved: Vec::new(),
+        };
+        let groups = vec![
+            group(5, "10.0.0.1:8080"),
+            group(5, "10.0.0.2:8080"),
+            group(5, "10.0.0.3:8080"),
+        ];
+        assert!(crate::director::equal_weights(&groups));
+        assert!(!crate::director::equal_weights(&[
+            group(5, "10.0.0.1:8080"),
+            group(6, "10.0.0.2:8080"),
+        ]));
+        assert!(!crate::director::equal_weights(&[
+            group(0, "10.0.0.1:8080"),
+            group(0, "10.0.0.2:8080"),
+        ]));
+        assert!(!crate::director::equal_weights(&[]));
+
+        // The uniform pick still splits traffic evenly
+        let mut counts = HashMap::new();
+        for _ in 0..3000 {
+            let selected = select_weighted(&groups, true).unwrap();
+            *counts.entry(selected.to_string()).or_insert(0) += 1;
+        }
+        for backend in ["10.0.0.1:8080", "10.0.0.2:8080", "10.0.0.3:8080"] {
+            let count = *counts.get(backend).unwrap_or(&0);
+            assert!(
+                (800..1200).contains(&count),
+                "{} selected {} times, expected ~1000",
+                backend,
+                count
+            );
+        }
+        assert!(select_weighted(&[], true).is_none());
+    }
+
+    #[test]
+    fn test_seeded_random_pick_sequence() {
+        extern "C" {
+            fn srandom(seed: std::ffi::c_uint);
+            fn random() -> std::ffi::c_long;
+        }
+
+        // What test_seeded_random.vtc sees after `debug.srandom 1`, as
+        // VRND_RandomTestable() is random(3) under a lock
+        let groups = vec![
+            WeightedBackendGroup {
+                weight: 1,
+                backends: vec!["s1".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+            WeightedBackendGroup {
+                weight: 1,
+                backends: vec!["s2".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+        ];
+        unsafe { srandom(1) };
+        let mut rng = vrnd::GhostRng::Varnish(random);
+        let picks: Vec<_> = (0..8)
+            .map(|_| select_weighted_with(&groups, true, &mut rng).unwrap())
+            .collect();
+        assert_eq!(picks, ["s2", "s1", "s1", "s2", "s2", "s1", "s1", "s1"]);
+    }
+
+    #[test]
+    fn test_select_weighted_seeded_is_deterministic() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let groups = vec![
+            WeightedBackendGroup {
+                weight: 3,
+                backends: vec!["10.0.0.1:8080".to_string(), "10.0.0.2:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+            WeightedBackendGroup {
+                weight: 1,
+                backends: vec!["10.0.0.3:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+        ];
+        let picks = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..200)
+                .map(|_| select_weighted_with(&groups, false, &mut rng).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        // The same seed gives the same picks, another seed other ones
+        let first = picks(42);
+        assert_eq!(first, picks(42));
+        assert_ne!(first, picks(43));
+        // ... still split by weight
+        let group2 = first.iter().filter(|b| **b == "10.0.0.3:8080").count();
+        assert!((20..80).contains(&group2), "group2 picked {} times", group2);
+    }
+
     #[test]
     fn test_select_backend_from_groups_empty() {
         let groups: Vec<WeightedBackendGroup> = vec![];
@@ -1128,6 +2314,8 @@ mod tests {
         let groups = vec![WeightedBackendGroup {
             weight: 100,
             backends: vec!["10.0.0.1:8080".to_string(), "10.0.0.2:8080".to_string()],
+            draining: Vec::new(),
+            unresolved: Vec::new(),
         }];
 
         let mut counts = HashMap::new();
@@ -1152,6 +2340,602 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_select_least_conn_picks_least_loaded() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::sync::Barrier;
+
+        let groups = vec![
+            WeightedBackendGroup {
+                weight: 100,
+                backends: vec!["10.0.0.1:8080".to_string(), "10.0.0.2:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+            WeightedBackendGroup {
+                weight: 100,
+                backends: vec!["10.0.0.3:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+        ];
+        let counters: HashMap<String, Arc<AtomicU64>> = groups
+            .iter()
+            .flat_map(|g| g.backends.iter())
+            .map(|k| (k.clone(), Arc::new(AtomicU64::new(0))))
+            .collect();
+
+        // Hold 5, 3 and 1 requests open concurrently on the three backends.
+        let in_flight = [
+            ("10.0.0.1:8080", 5),
+            ("10.0.0.2:8080", 3),
+            ("10.0.0.3:8080", 1),
+        ];
+        let total: usize = in_flight.iter().map(|(_, n)| n).sum();
+        let started = Arc::new(Barrier::new(total + 1));
+        let release = Arc::new(Barrier::new(total + 1));
+        let mut handles = Vec::new();
+        for (key, n) in in_flight {
+            for _ in 0..n {
+                let counter = Arc::clone(&counters[key]);
+                let started = Arc::clone(&started);
+                let release = Arc::clone(&release);
+                handles.push(std::thread::spawn(move || {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                    started.wait();
+                    release.wait();
+                    counter.fetch_sub(1, Ordering::Relaxed);
+                }));
+            }
+        }
+        started.wait();
+
+        let load = |key: &str| counters[key].load(Ordering::Relaxed);
+        for _ in 0..100 {
+            assert_eq!(
+                select_least_conn_from_groups(&groups, load),
+                Some("10.0.0.3:8080")
+            );
+        }
+
+        release.wait();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert!(counters.values().all(|c| c.load(Ordering::Relaxed) == 0));
+    }
+
+    #[test]
+    fn test_select_least_conn_ties_broken_by_weight() {
+        let groups = vec![
+            WeightedBackendGroup {
+                weight: 90,
+                backends: vec!["10.0.0.1:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+            WeightedBackendGroup {
+                weight: 10,
+                backends: vec!["10.0.0.2:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+            WeightedBackendGroup {
+                weight: 0,
+                backends: vec!["10.0.0.3:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+        ];
+
+        // All idle: weight decides, and the weight-0 group is never picked
+        // even though it is equally idle.
+        let mut counts = HashMap::new();
+        for _ in 0..1000 {
+            let selected = select_least_conn_from_groups(&groups, |_| 0).unwrap();
+            *counts.entry(selected).or_insert(0) += 1;
+        }
+        assert!(counts.get("10.0.0.1:8080").copied().unwrap_or(0) > 800);
+        assert!(!counts.contains_key("10.0.0.3:8080"));
+
+        // Load outranks weight.
+        let selected =
+            select_least_conn_from_groups(&groups, |k| if k == "10.0.0.1:8080" { 1 } else { 0 });
+        assert_eq!(selected, Some("10.0.0.2:8080"));
+    }
+
+    #[test]
+    fn test_select_least_conn_empty() {
+        let groups: Vec<WeightedBackendGroup> = vec![];
+        assert!(select_least_conn_from_groups(&groups, |_| 0).is_none());
+
+        let zero_weight = vec![WeightedBackendGroup {
+            weight: 0,
+            backends: vec!["10.0.0.1:8080".to_string()],
+            draining: Vec::new(),
+            unresolved: Vec::new(),
+        }];
+        assert!(select_least_conn_from_groups(&zero_weight, |_| 0).is_none());
+    }
+
+    #[test]
+    fn test_select_backend_draining_keeps_existing_sessions() {
+        let groups = vec![WeightedBackendGroup {
+            weight: 100,
+            backends: vec!["10.0.0.1:8080".to_string(), "10.0.0.2:8080".to_string()],
+            draining: vec!["10.0.0.3:8080".to_string()],
+            unresolved: Vec::new(),
+        }];
+        let ring = HashRing::new(&groups);
+        let draining = "10.0.0.3:8080";
+
+        // The session cookie keeps reaching the draining backend.
+        assert_eq!(
+            find_affinity_backend(&groups, &affinity_token(draining)),
+            Some(draining)
+        );
+
+        // A new client whose key hashed to it before the drain lands elsewhere
+        let before = HashRing::new(&[WeightedBackendGroup {
+            weight: 100,
+            backends: (1..=3).map(|i| format!("10.0.0.{}:8080", i)).collect(),
+            draining: Vec::new(),
+            unresolved: Vec::new(),
+        }]);
+        let key = (0..1_000)
+            .map(|i| format!("192.0.2.{}", i))
+            .find(|k| before.get(k.as_bytes()) == Some(draining))
+            .expect("some key maps to the draining backend");
+        let live = new_session_groups(&groups);
+        let selected = select_backend(
+            SelectionPolicy::ConsistentHash,
+            &live,
+            false,
+            Some(&ring),
+            Some(&key),
+            |_| 0,
+        );
+        assert!(selected.is_some_and(|s| s != draining), "{:?}", selected);
+
+        // New clients never land on it, under any policy.
+        for policy in [
+            SelectionPolicy::ConsistentHash,
+            SelectionPolicy::Weighted,
+            SelectionPolicy::LeastConn,
+        ] {
+            for _ in 0..200 {
+                let selected =
+                    select_backend(policy, &groups, false, Some(&ring), None, |_| 0).unwrap();
+                assert_ne!(selected, draining, "{:?} picked a draining backend", policy);
+            }
+        }
+    }
+
+    #[test]
+    fn test_consistent_hash_stable_across_reload_adding_backend() {
+        let pods: Vec<String> = (1..=4).map(|i| format!("10.0.0.{}:8080", i)).collect();
+        let group = |backends: &[String]| WeightedBackendGroup {
+            weight: 100,
+            backends: backends.to_vec(),
+            draining: Vec::new(),
+            unresolved: Vec::new(),
+        };
+        let before = vec![group(&pods[..3])];
+        let after = vec![group(&pods)];
+        let (ring_before, ring_after) = (HashRing::new(&before), HashRing::new(&after));
+        let pick = |groups, ring, key: &str| {
+            select_backend(
+                SelectionPolicy::ConsistentHash,
+                groups,
+                false,
+                Some(ring),
+                Some(key),
+                |_| 0,
+            )
+            .unwrap()
+            .to_string()
+        };
+
+        // Keys either keep their backend or move to the new one
+        let mut moved = 0;
+        for i in 0..2_000 {
+            let key = format!("session-{}", i);
+            let a = pick(&before, &ring_before, &key);
+            let b = pick(&after, &ring_after, &key);
+            assert_eq!(a, pick(&before, &ring_before, &key));
+            if a != b {
+                assert_eq!(b, pods[3], "key {} moved between existing backends", key);
+                moved += 1;
+            }
+        }
+        assert!(moved > 0 && moved < 800, "{} of 2000 keys moved", moved);
+
+        // No key: weighted random over the route's backends
+        let selected = select_backend(
+            SelectionPolicy::ConsistentHash,
+            &after,
+            false,
+            Some(&ring_after),
+            None,
+            |_| 0,
+        );
+        assert!(pods.iter().any(|p| Some(p.as_str()) == selected));
+    }
+
+    #[test]
+    fn test_consistent_hash_distribution_follows_weight() {
+        // 75% to a single pod, 25% split across three
+        let groups = vec![
+            WeightedBackendGroup {
+                weight: 75,
+                backends: vec!["10.0.0.1:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+            WeightedBackendGroup {
+                weight: 25,
+                backends: (2..=4).map(|i| format!("10.0.0.{}:8080", i)).collect(),
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+        ];
+        let ring = HashRing::new(&groups);
+
+        let mut heavy = 0;
+        for i in 0..10_000 {
+            let key = format!("user-{}", i);
+            let selected = select_backend(
+                SelectionPolicy::ConsistentHash,
+                &groups,
+                false,
+                Some(&ring),
+                Some(&key),
+                |_| 0,
+            );
+            if selected == Some("10.0.0.1:8080") {
+                heavy += 1;
+            }
+        }
+        assert!(
+            (6_500..8_500).contains(&heavy),
+            "heavy group got {} of 10000 keys, expected ~7500",
+            heavy
+        );
+    }
+
+    #[test]
+    fn test_new_session_groups_shift_weight_off_draining_groups() {
+        let group = |weight, backends: &[&str], draining: &[&str]| WeightedBackendGroup {
+            weight,
+            backends: backends.iter().map(|s| s.to_string()).collect(),
+            draining: draining.iter().map(|s| s.to_string()).collect(),
+            unresolved: Vec::new(),
+        };
+        let groups = vec![
+            group(90, &[], &["10.0.0.1:8080"]),
+            group(10, &["10.0.0.2:8080"], &[]),
+        ];
+        let live = new_session_groups(&groups);
+        assert_eq!(live.len(), 1);
+        let ring = HashRing::new(&groups);
+        for i in 0..100 {
+            let key = format!("client-{}", i);
+            for (policy, key) in [
+                (SelectionPolicy::Weighted, None),
+                (SelectionPolicy::LeastConn, None),
+                (SelectionPolicy::ConsistentHash, Some(key.as_str())),
+            ] {
+                let selected = select_backend(policy, &live, false, Some(&ring), key, |_| 0);
+                assert_eq!(selected, Some("10.0.0.2:8080"), "{:?}", policy);
+            }
+        }
+
+        // Nothing but draining backends left: they still serve
+        let all_draining = vec![group(100, &[], &["10.0.0.1:8080"])];
+        assert!(matches!(
+            new_session_groups(&all_draining),
+            Cow::Borrowed(_)
+        ));
+        let live = new_session_groups(&all_draining);
+        assert_eq!(select_backend_from_groups(&live), Some("10.0.0.1:8080"));
+    }
+
+    #[test]
+    fn test_select_backend_all_draining_still_served() {
+        let groups = vec![WeightedBackendGroup {
+            weight: 100,
+            backends: Vec::new(),
+            draining: vec!["10.0.0.1:8080".to_string()],
+            unresolved: Vec::new(),
+        }];
+        assert_eq!(select_backend_from_groups(&groups), Some("10.0.0.1:8080"));
+        assert_eq!(
+            select_least_conn_from_groups(&groups, |_| 0),
+            Some("10.0.0.1:8080")
+        );
+    }
+
+    #[test]
+    fn test_healthy_groups_skips_unhealthy() {
+        let groups = vec![
+            WeightedBackendGroup {
+                weight: 90,
+                backends: vec!["10.0.0.1:8080".to_string(), "10.0.0.2:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+            WeightedBackendGroup {
+                weight: 10,
+                backends: vec!["10.0.0.3:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+        ];
+
+        assert!(matches!(
+            healthy_groups(&groups, |_| true),
+            Cow::Borrowed(_)
+        ));
+
+        let filtered = healthy_groups(&groups, |key| key != "10.0.0.1:8080");
+        assert_eq!(filtered.len(), 2);
+        assert_eq!(filtered[0].backends, vec!["10.0.0.2:8080".to_string()]);
+
+        // A group with nothing healthy left gives its share to the others.
+        let filtered = healthy_groups(&groups, |key| key != "10.0.0.3:8080");
+        assert_eq!(filtered.len(), 1);
+        for _ in 0..100 {
+            assert_ne!(select_backend_from_groups(&filtered), Some("10.0.0.3:8080"));
+        }
+
+        assert!(healthy_groups(&groups, |_| false).is_empty());
+    }
+
+    #[test]
+    fn test_has_configured_backends() {
+        let group = |backends: &[&str], draining: &[&str]| WeightedBackendGroup {
+            weight: 100,
+            backends: backends.iter().map(|s| s.to_string()).collect(),
+            draining: draining.iter().map(|s| s.to_string()).collect(),
+            unresolved: Vec::new(),
+        };
+        // No backends at all: a config error, answered with 500
+        assert!(!has_configured_backends(&[]));
+        assert!(!has_configured_backends(&[group(&[], &[])]));
+        // Backends that are merely down or draining: 503
+        assert!(has_configured_backends(&[
+            group(&[], &[]),
+            group(&["10.0.0.1:80"], &[])
+        ]));
+        assert!(has_configured_backends(&[group(&[], &["10.0.0.1:80"])]));
+    }
+
+    #[test]
+    fn test_failing_backend_ejected_traffic_shifts() {
+        use crate::config::OutlierDetectionConfig;
+        use crate::outlier::OutlierDetector;
+
+        let failing = "10.0.0.1:8080";
+        let groups = vec![WeightedBackendGroup {
+            weight: 100,
+            backends: vec![failing.to_string(), "10.0.0.2:8080".to_string()],
+            draining: Vec::new(),
+            unresolved: Vec::new(),
+        }];
+        let outliers = OutlierDetector::new();
+        outliers.configure(Some(&OutlierDetectionConfig {
+            consecutive_failures: 3,
+            ejection_ms: 60_000,
+            max_ejection_ms: None,
+        }));
+
+        // The failing backend gets at most `consecutive_failures` requests
+        let mut failed = 0;
+        for _ in 0..200 {
+            let healthy = healthy_groups(&groups, |key| !outliers.is_ejected(key));
+            let selected = select_backend_from_groups(&healthy).unwrap();
+            let ok = selected != failing;
+            if !ok {
+                failed += 1;
+            }
+            outliers.record(selected, ok);
+        }
+        assert_eq!(failed, 3);
+        assert_eq!(outliers.ejected_keys(), vec![failing.to_string()]);
+    }
+
+    #[test]
+    fn test_unhealthy_backend_stops_being_selected() {
+        use crate::health::{HealthMap, HealthProbes, HealthTarget};
+        use crate::mock_upstream;
+        use std::sync::atomic::{AtomicU16, Ordering};
+        use std::time::{Duration, Instant};
+
+        // Mock backend whose health endpoint answers with `status`.
+        let status = Arc::new(AtomicU16::new(200));
+        let addr = mock_upstream::status(Arc::clone(&status));
+
+        let flaky = addr.to_string();
+        let groups = vec![WeightedBackendGroup {
+            weight: 100,
+            backends: vec![flaky.clone(), "10.0.0.2:8080".to_string()],
+            draining: Vec::new(),
+            unresolved: Vec::new(),
+        }];
+        let health: HealthMap = Arc::default();
+        let probes = HealthProbes::new();
+        let target = HealthTarget {
+            url: format!("http://{}/healthz", addr),
+            check: crate::config::HealthCheck {
+                path: "/healthz".to_string(),
+                interval_ms: 100,
+                timeout_ms: 100,
+                expected_status: 200,
+                healthy_threshold: 1,
+                unhealthy_threshold: 1,
+            },
+        };
+        probes.sync(
+            &std::collections::HashMap::from([(flaky.clone(), target)]),
+            &health,
+        );
+
+        let is_healthy = |key: &str| health.get(key).unwrap_or(true);
+        let wait_for = |want: bool| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while health.get(&flaky) != Some(want) {
+                assert!(Instant::now() < deadline, "probe never reported {}", want);
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        wait_for(true);
+        let selected: Vec<String> = (0..200)
+            .filter_map(|_| {
+                select_backend_from_groups(&healthy_groups(&groups, is_healthy)).map(String::from)
+            })
+            .collect();
+        assert!(selected.contains(&flaky));
+
+        status.store(503, Ordering::Relaxed);
+        wait_for(false);
+        for _ in 0..200 {
+            let healthy = healthy_groups(&groups, is_healthy);
+            assert_eq!(select_backend_from_groups(&healthy), Some("10.0.0.2:8080"));
+        }
+    }
+
+    #[test]
+    fn test_session_affinity_cookie_roundtrip() {
+        let sp = SessionPersistence {
+            cookie_name: "GHOST_AFFINITY".to_string(),
+            max_age_seconds: None,
+        };
+        let groups = vec![WeightedBackendGroup {
+            weight: 100,
+            backends: vec!["10.0.0.1:8080".to_string(), "10.0.0.2:8080".to_string()],
+            draining: vec!["10.0.0.3:8080".to_string()],
+            unresolved: Vec::new(),
+        }];
+
+        // First response: cookie names the chosen backend opaquely.
+        let set_cookie = affinity_set_cookie(&sp, "10.0.0.2:8080");
+        assert!(set_cookie.starts_with("GHOST_AFFINITY="));
+        assert!(set_cookie.ends_with("; Path=/; HttpOnly"));
+        assert!(!set_cookie.contains("10.0.0.2"));
+
+        // Next request presents it and is pinned to the same backend.
+        let value = set_cookie.split(';').next().unwrap();
+        let token = cookie_value(value, "GHOST_AFFINITY").unwrap();
+        assert_eq!(find_affinity_backend(&groups, token), Some("10.0.0.2:8080"));
+
+        // Pins to a draining backend still hold.
+        let token = affinity_token("10.0.0.3:8080");
+        assert_eq!(
+            find_affinity_backend(&groups, &token),
+            Some("10.0.0.3:8080")
+        );
+    }
+
+    #[test]
+    fn test_session_affinity_removed_backend_falls_back() {
+        let token = affinity_token("10.0.0.9:8080");
+        let groups = vec![WeightedBackendGroup {
+            weight: 100,
+            backends: vec!["10.0.0.1:8080".to_string()],
+            draining: Vec::new(),
+            unresolved: Vec::new(),
+        }];
+        // Backend no longer in the route after reload: no pin.
+        assert_eq!(find_affinity_backend(&groups, &token), None);
+        assert_eq!(find_affinity_backend(&groups, "garbage"), None);
+    }
+
+    #[test]
+    fn test_session_affinity_max_age() {
+        let sp = SessionPersistence {
+            cookie_name: "sticky".to_string(),
+            max_age_seconds: Some(3600),
+        };
+        let cookie = affinity_set_cookie(&sp, "10.0.0.1:8080");
+        assert!(cookie.starts_with("sticky="));
+        assert!(cookie.ends_with("; Path=/; HttpOnly; Max-Age=3600"));
+    }
+
+    #[test]
+    fn test_is_internal_header() {
+        assert!(is_internal_header("X-Ghost-Filter-Context"));
+        assert!(is_internal_header("x-ghost-redirect-config"));
+        assert!(is_internal_header("X-GHOST-PASS"));
+        assert!(!is_internal_header("X-Ghost"));
+        assert!(!is_internal_header("X-Ghostly"));
+        assert!(!is_internal_header("X-Gateway-Route"));
+        assert!(!is_internal_header("Ümlaut-x"));
+    }
+
+    #[test]
+    fn test_forward_host_value() {
+        let request = Some("rewritten.example.com");
+        let backend = Some("origin.internal:8080");
+        assert_eq!(
+            forward_host_value(&ForwardHost::Preserve, request, backend).as_deref(),
+            Some("rewritten.example.com")
+        );
+        assert_eq!(
+            forward_host_value(&ForwardHost::Backend, request, backend).as_deref(),
+            Some("origin.internal:8080")
+        );
+        let literal = ForwardHost::Literal("api.example.net".to_string());
+        assert_eq!(
+            forward_host_value(&literal, request, backend).as_deref(),
+            Some("api.example.net")
+        );
+        // Nothing known to send leaves the backend's default in place
+        assert_eq!(
+            forward_host_value(&ForwardHost::Preserve, None, backend),
+            None
+        );
+        assert_eq!(
+            forward_host_value(&ForwardHost::Backend, request, None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_first_forwarded_for() {
+        assert_eq!(first_forwarded_for("192.0.2.1"), Some("192.0.2.1"));
+        assert_eq!(
+            first_forwarded_for("192.0.2.1, 10.0.0.1, 10.0.0.2"),
+            Some("192.0.2.1")
+        );
+        assert_eq!(first_forwarded_for(" , 192.0.2.1"), Some("192.0.2.1"));
+        assert_eq!(first_forwarded_for(""), None);
+    }
+
+    #[test]
+    fn test_cookie_value() {
+        let header = "theme=dark; session=abc123; lang=en";
+        assert_eq!(cookie_value(header, "session"), Some("abc123"));
+        assert_eq!(cookie_value(header, "theme"), Some("dark"));
+        assert_eq!(cookie_value(header, "missing"), None);
+        assert_eq!(cookie_value("session=", "session"), None);
+        // Name must match exactly, not as a prefix.
+        assert_eq!(
+            cookie_value("session_old=x; session=y", "session"),
+            Some("y")
+        );
+    }
+
+    #[test]
+    fn test_allow_header() {
+        assert_eq!(allow_header(std::iter::empty()), None);
+        assert_eq!(allow_header(["GET"].into_iter()).as_deref(), Some("GET"));
+        assert_eq!(
+            allow_header(["POST", "GET", "POST"].into_iter()).as_deref(),
+            Some("GET, POST")
+        );
+    }
+
     #[test]
     fn test_match_routes_no_path_match() {
         // Route with no path match should match all paths
@@ -1164,13 +2948,30 @@ mod tests {
             backend_groups: vec![WeightedBackendGroup {
                 weight: 100,
                 backends: vec!["10.0.0.1:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
             }],
             listeners: Vec::new(),
             route_name: None,
-            priority: 100,
+            priority: Some(100),
             rule_index: 0,
             cache_policy: None,
             bypass_headers: Vec::new(),
+            selection: SelectionPolicy::default(),
+            hash_on: None,
+            hash_ring: None,
+            shadow_selection: None,
+            shadow_diff: None,
+            timeouts: None,
+            session_persistence: None,
+            retry: None,
+            qos: QosClass::Normal,
+            forward_host: None,
+            max_request_body_bytes: None,
+            equal_weights: false,
+            rate_limiter: None,
+            nonce_store: None,
+            url_rewrite_regex: None,
         }];
 
         // This test doesn't use HttpHeaders, so we can't fully test it here
@@ -1191,13 +2992,30 @@ mod tests {
             backend_groups: vec![WeightedBackendGroup {
                 weight: 100,
                 backends: vec!["10.0.0.1:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
             }],
             listeners: Vec::new(),
             route_name: None,
-            priority: 100,
+            priority: Some(100),
             rule_index: 0,
             cache_policy: None,
             bypass_headers: Vec::new(),
+            selection: SelectionPolicy::default(),
+            hash_on: None,
+            hash_ring: None,
+            shadow_selection: None,
+            shadow_diff: None,
+            timeouts: None,
+            session_persistence: None,
+            retry: None,
+            qos: QosClass::Normal,
+            forward_host: None,
+            max_request_body_bytes: None,
+            equal_weights: false,
+            rate_limiter: None,
+            nonce_store: None,
+            url_rewrite_regex: None,
         }];
 
         // Verify route structure
@@ -1205,6 +3023,187 @@ mod tests {
         assert!(routes[0].path_match.is_some());
     }
 
+    #[test]
+    fn test_select_retry_backend_skips_tried() {
+        let director = VhostDirector::new(
+            "api.example.com".to_string(),
+            vec![RouteEntry {
+                path_match: None,
+                method: None,
+                headers: Vec::new(),
+                query_params: Vec::new(),
+                filters: None,
+                backend_groups: vec![
+                    WeightedBackendGroup {
+                        weight: 100,
+                        backends: vec!["10.0.0.1:8080".to_string(), "10.0.0.2:8080".to_string()],
+                        draining: Vec::new(),
+                        unresolved: Vec::new(),
+                    },
+                    WeightedBackendGroup {
+                        weight: 1,
+                        backends: vec!["10.0.0.3:8080".to_string()],
+                        draining: Vec::new(),
+                        unresolved: Vec::new(),
+                    },
+                ],
+                listeners: Vec::new(),
+                route_name: None,
+                priority: Some(100),
+                rule_index: 0,
+                cache_policy: None,
+                bypass_headers: Vec::new(),
+                selection: SelectionPolicy::default(),
+                hash_on: None,
+                hash_ring: None,
+                shadow_selection: None,
+                shadow_diff: None,
+                timeouts: None,
+                session_persistence: None,
+                retry: Some(RetryPolicy {
+                    max_attempts: 3,
+                    retry_on: vec![503],
+                    per_try_timeout_ms: None,
+                    unsafe_methods: false,
+                    retry_on_body: None,
+                }),
+                qos: QosClass::Normal,
+                forward_host: None,
+                max_request_body_bytes: None,
+                equal_weights: false,
+                rate_limiter: None,
+                nonce_store: None,
+                url_rewrite_regex: None,
+            }],
+            Arc::new(BackendPool::new()),
+            None,
+            None,
+            None,
+            RoutingLog::Off,
+        );
+
+        assert_eq!(director.retry_policy(0).map(|p| p.max_attempts), Some(3));
+        assert!(director.retry_policy(1).is_none());
+
+        let tried = vec!["10.0.0.1:8080".to_string()];
+        for _ in 0..100 {
+            let next = director.select_retry_backend(0, &tried).unwrap();
+            assert_ne!(next, "10.0.0.1:8080");
+        }
+
+        // An exhausted group cedes its weight to the rest of the route.
+        let tried = vec!["10.0.0.1:8080".to_string(), "10.0.0.2:8080".to_string()];
+        assert_eq!(
+            director.select_retry_backend(0, &tried).as_deref(),
+            Some("10.0.0.3:8080")
+        );
+
+        let tried = vec![
+            "10.0.0.1:8080".to_string(),
+            "10.0.0.2:8080".to_string(),
+            "10.0.0.3:8080".to_string(),
+        ];
+        assert_eq!(director.select_retry_backend(0, &tried), None);
+        assert_eq!(director.select_retry_backend(7, &[]), None);
+    }
+
+    #[test]
+    fn test_audit_selection_logs_both_picks() {
+        let groups = [
+            WeightedBackendGroup {
+                weight: 1,
+                backends: vec!["10.0.0.1:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+            WeightedBackendGroup {
+                weight: 0,
+                backends: vec!["10.0.0.2:8080".to_string()],
+                draining: Vec::new(),
+                unresolved: Vec::new(),
+            },
+        ];
+        let director = VhostDirector::new(
+            "api.example.com".to_string(),
+            Vec::new(),
+            Arc::new(BackendPool::new()),
+            None,
+            None,
+            None,
+            RoutingLog::Off,
+        );
+        let shadow = |weights: [u32; 2]| ShadowSelectionCompiled {
+            selection: SelectionPolicy::Weighted,
+            backend_groups: groups
+                .iter()
+                .zip(weights)
+                .map(|(g, weight)| WeightedBackendGroup {
+                    weight,
+                    ..g.clone()
+                })
+                .collect(),
+            hash_ring: None,
+        };
+
+        // A weight set moving all traffic to the second group
+        let msg =
+            director.audit_selection(&shadow([0, 1]), "10.0.0.1:8080", None, Some("default/api"));
+        assert_eq!(
+            msg,
+            "Selection audit: route=default/api enforced=10.0.0.1:8080 \
+             shadow=10.0.0.2:8080 (differs)"
+        );
+
+        // Same outcome under both: recorded, not flagged
+        let msg = director.audit_selection(&shadow([1, 0]), "10.0.0.1:8080", None, None);
+        assert_eq!(
+            msg,
+            "Selection audit: route=- enforced=10.0.0.1:8080 shadow=10.0.0.1:8080"
+        );
+
+        // Nothing selectable under the shadow weights
+        let msg = director.audit_selection(&shadow([0, 0]), "10.0.0.1:8080", None, None);
+        assert!(msg.ends_with("shadow=- (differs)"), "{}", msg);
+
+        let stats = director.stats();
+        assert_eq!(stats.shadow_mismatches(), 2);
+        assert_eq!(stats.shadow_selections().values().sum::<u64>(), 3);
+        // The audit doesn't count as traffic
+        assert_eq!(stats.total_requests(), 0);
+    }
+
+    #[test]
+    fn test_missing_backend_is_logged_and_counted() {
+        // Routing state naming a backend the pool doesn't have
+        let director = VhostDirector::new(
+            "api.example.com".to_string(),
+            Vec::new(),
+            Arc::new(BackendPool::new()),
+            None,
+            None,
+            None,
+            RoutingLog::Off,
+        );
+
+        let mut log_msgs = Vec::new();
+        assert!(director
+            .pool_entry("10.0.0.9:8080", &mut log_msgs)
+            .is_none());
+        assert_eq!(log_msgs.len(), 1);
+        let (tag, msg) = &log_msgs[0];
+        assert!(matches!(tag, LogTag::Error));
+        assert_eq!(
+            msg,
+            "Backend 10.0.0.9:8080 selected for vhost api.example.com is missing from the backend pool"
+        );
+
+        let stats = director.stats();
+        assert_eq!(stats.missing_backends(), 1);
+        // Not the same failure as a route whose backends are all down
+        assert_eq!(stats.no_backends(), 0);
+        assert_eq!(stats.total_requests(), 0);
+    }
+
     #[test]
     fn test_vhost_director_has_backends() {
         let backend_pool = Arc::new(BackendPool::new());
@@ -1221,17 +3220,36 @@ mod tests {
                 backend_groups: vec![WeightedBackendGroup {
                     weight: 100,
                     backends: vec!["10.0.0.1:8080".to_string()],
+                    draining: Vec::new(),
+                    unresolved: Vec::new(),
                 }],
                 listeners: Vec::new(),
                 route_name: None,
-                priority: 100,
+                priority: Some(100),
                 rule_index: 0,
                 cache_policy: None,
                 bypass_headers: Vec::new(),
+                selection: SelectionPolicy::default(),
+                hash_on: None,
+                hash_ring: None,
+                shadow_selection: None,
+                shadow_diff: None,
+                timeouts: None,
+                session_persistence: None,
+                retry: None,
+                qos: QosClass::Normal,
+                forward_host: None,
+                max_request_body_bytes: None,
+                equal_weights: false,
+                rate_limiter: None,
+                nonce_store: None,
+                url_rewrite_regex: None,
             }],
             backend_pool.clone(),
             None,
             None,
+            None,
+            RoutingLog::Off,
         );
 
         assert!(director.has_backends());
@@ -1243,6 +3261,8 @@ mod tests {
             backend_pool,
             None,
             None,
+            None,
+            RoutingLog::Off,
         );
 
         assert!(!empty_director.has_backends());
@@ -1257,6 +3277,8 @@ mod tests {
             backend_pool,
             None,
             None,
+            None,
+            RoutingLog::Off,
         );
 
         // Initial stats should be zero
@@ -1301,6 +3323,8 @@ mod tests {
         let groups = vec![WeightedBackendGroup {
             weight: 100,
             backends: vec!["10.0.0.1:8080".to_string()],
+            draining: Vec::new(),
+            unresolved: Vec::new(),
         }];
 
         let path_match = PathMatchCompiled::PathPrefix("/api/v1".to_string());
@@ -1309,6 +3333,10 @@ mod tests {
             response_header_modifier: None,
             request_redirect: None,
             url_rewrite: None,
+            request_mirror: None,
+            cors: None,
+            rate_limit: None,
+            nonce: None,
         });
 
         let result = RouteMatchResult {
@@ -1318,10 +3346,105 @@ mod tests {
             route_name: Some("default/my-route"),
             cache_policy: None,
             bypass_headers: &[],
+            selection: SelectionPolicy::default(),
+            hash_on: None,
+            hash_ring: None,
+            shadow_selection: None,
+            shadow_diff: None,
+            timeouts: None,
+            session_persistence: None,
+            retry: None,
+            qos: QosClass::Normal,
+            forward_host: None,
+            route_index: 0,
         };
 
         assert_eq!(result.backend_groups.len(), 1);
         assert!(result.filters.is_some());
         assert!(result.matched_path.is_some());
     }
+
+    #[test]
+    fn test_replace_prefix_match_exact() {
+        let rewrite = |exact: &str, path: &str, new_prefix: &str| {
+            let matched = PathMatchCompiled::Exact(exact.to_string());
+            apply_replace_prefix_match(path, new_prefix, Some(&matched)).0
+        };
+        // The whole path is the match: only the new prefix is left
+        assert_eq!(rewrite("/foo", "/foo", "/bar"), "/bar");
+        assert_eq!(rewrite("/foo", "/foo", "/bar/"), "/bar");
+        assert_eq!(rewrite("/foo/", "/foo/", "/bar"), "/bar");
+        assert_eq!(rewrite("/foo/", "/foo/", "/bar/"), "/bar");
+        assert_eq!(rewrite("/foo", "/foo", "/"), "/");
+        assert_eq!(rewrite("/", "/", "/bar"), "/bar");
+    }
+
+    #[test]
+    fn test_replace_prefix_match_exact_agrees_with_prefix() {
+        for (matched, new_prefix) in [
+            ("/foo", "/bar"),
+            ("/foo/", "/bar/"),
+            ("/foo", "/"),
+            ("/api/v1", "/v2"),
+        ] {
+            let exact = PathMatchCompiled::Exact(matched.to_string());
+            let prefix = PathMatchCompiled::PathPrefix(matched.to_string());
+            assert_eq!(
+                apply_replace_prefix_match(matched, new_prefix, Some(&exact)).0,
+                apply_replace_prefix_match(matched, new_prefix, Some(&prefix)).0,
+                "{} -> {}",
+                matched,
+                new_prefix
+            );
+        }
+    }
+
+    #[test]
+    fn test_replace_prefix_match_trailing_slashes() {
+        // Every trailing-slash combination of matched and new prefix
+        for (matched, new_prefix) in [
+            ("/api", "/v2"),
+            ("/api", "/v2/"),
+            ("/api/", "/v2"),
+            ("/api/", "/v2/"),
+        ] {
+            let prefix = PathMatchCompiled::PathPrefix(matched.to_string());
+            for (path, expected) in [
+                ("/api/users", "/v2/users"),
+                ("/api/users/", "/v2/users/"),
+                ("/api//users", "/v2/users"),
+            ] {
+                assert_eq!(
+                    apply_replace_prefix_match(path, new_prefix, Some(&prefix)).0,
+                    expected,
+                    "{} with {} -> {}",
+                    path,
+                    matched,
+                    new_prefix
+                );
+            }
+        }
+
+        assert_eq!(join_replaced_prefix("/v2", "users"), "/v2/users");
+        assert_eq!(join_replaced_prefix("/v2/", "/users"), "/v2/users");
+        assert_eq!(join_replaced_prefix("/v2//", "//users"), "/v2/users");
+        assert_eq!(join_replaced_prefix("/v2", "/"), "/v2/");
+        assert_eq!(join_replaced_prefix("/v2/", ""), "/v2");
+        assert_eq!(join_replaced_prefix("/", "users"), "/users");
+        assert_eq!(join_replaced_prefix("/", ""), "/");
+        assert_eq!(join_replaced_prefix("", ""), "/");
+    }
+
+    #[test]
+    fn test_add_keeps_repeated_headers_apart() {
+        // A single plain header is joined onto
+        assert!(joins_onto("X-Append", 1));
+        // Absent, or already repeated: a line of its own
+        assert!(!joins_onto("X-Append", 0));
+        assert!(!joins_onto("Vary", 2));
+        // Never joined, whatever the count
+        assert!(!joins_onto("Set-Cookie", 1));
+        assert!(!joins_onto("www-authenticate", 1));
+        assert!(!joins_onto("Proxy-Authenticate", 1));
+    }
 }
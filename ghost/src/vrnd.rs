@@ -0,0 +1,121 @@
+//! Random numbers for backend selection.
+//!
+//! Picks draw from `thread_rng`. A VTC that seeds Varnish's generator with
+//! `debug.srandom` can call `ghost.seeded_random()` in `vcl_init` to have
+//! them drawn from `VRND_RandomTestable()` instead, and assert an exact
+//! sequence of picks. That generator is `random(3)` under a global lock,
+//! so it stays off unless asked for.
+//!
+//! The symbol lives in varnishd, not in libvarnishapi, so it is looked up
+//! at run time. Where it is missing (the unit-test binary), picks stay on
+//! `thread_rng`.
+
+use std::ffi::{c_long, c_void};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::LazyLock;
+
+use rand::rngs::ThreadRng;
+use rand::RngCore;
+
+type RandomFn = unsafe extern "C" fn() -> c_long;
+
+static VRND: LazyLock<Option<RandomFn>> = LazyLock::new(|| {
+    let sym = unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"VRND_RandomTestable".as_ptr()) };
+    // SAFETY: VRND_RandomTestable is `long VRND_RandomTestable(void)`
+    (!sym.is_null()).then(|| unsafe { std::mem::transmute::<*mut c_void, RandomFn>(sym) })
+});
+
+/// Set by `ghost.seeded_random()`
+static SEEDED: AtomicBool = AtomicBool::new(false);
+
+/// Draw picks from Varnish's seedable generator from now on. False when
+/// it isn't available.
+pub fn use_seeded() -> bool {
+    let available = VRND.is_some();
+    SEEDED.store(available, Ordering::Relaxed);
+    available
+}
+
+/// Varnish's generator once a VTC asked for it, `thread_rng` otherwise
+pub enum GhostRng {
+    Varnish(RandomFn),
+    Thread(ThreadRng),
+}
+
+pub fn rng() -> GhostRng {
+    match *VRND {
+        Some(random) if SEEDED.load(Ordering::Relaxed) => GhostRng::Varnish(random),
+        _ => GhostRng::Thread(rand::thread_rng()),
+    }
+}
+
+impl RngCore for GhostRng {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            Self::Varnish(random) => {
+                // random(3) gives 31 bits: take the top 16 of two draws
+                let (hi, lo) = unsafe { (random(), random()) };
+                ((hi as u32 >> 15) << 16) | (lo as u32 >> 15)
+            }
+            Self::Thread(rng) => rng.next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            Self::Varnish(_) => (u64::from(self.next_u32()) << 32) | u64::from(self.next_u32()),
+            Self::Thread(rng) => rng.next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.next_u32().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicI64, Ordering};
+
+    static NEXT: AtomicI64 = AtomicI64::new(0);
+
+    unsafe extern "C" fn counting_random() -> c_long {
+        NEXT.fetch_add(0x8000, Ordering::SeqCst) as c_long
+    }
+
+    #[test]
+    fn test_falls_back_without_varnishd() {
+        assert!(VRND.is_none());
+        assert!(matches!(rng(), GhostRng::Thread(_)));
+        // Asking for the seeded generator doesn't change that
+        assert!(!use_seeded());
+        assert!(matches!(rng(), GhostRng::Thread(_)));
+    }
+
+    unsafe extern "C" fn max_random() -> c_long {
+        0x7fff_ffff
+    }
+
+    #[test]
+    fn test_varnish_draws_fill_32_bits() {
+        assert_eq!(GhostRng::Varnish(max_random).next_u32(), u32::MAX);
+        assert_eq!(GhostRng::Varnish(max_random).next_u64(), u64::MAX);
+
+        // Draws 0x10000, 0x18000, ... give 2, 3, ... in their top 16 bits
+        NEXT.store(0x0001_0000, Ordering::SeqCst);
+        let mut rng = GhostRng::Varnish(counting_random);
+        let mut bytes = [0u8; 6];
+        rng.fill_bytes(&mut bytes);
+        // 0x0002_0003, then 0x0004_0005 cut to two bytes
+        assert_eq!(bytes, [0x03, 0x00, 0x02, 0x00, 0x05, 0x00]);
+    }
+}
@@ -0,0 +1,242 @@
+//! varnishstat counters (VSC) for ghost.
+//!
+//! Published under stable names: `GHOST.<counter>` process-wide,
+//! `GHOST.<vhost>.<counter>` per vhost and `GHOST.<vhost>.<backend>.<counter>`
+//! per backend of a vhost, where `<backend>` is the backend pool key.
+//!
+//! A segment is shared by every director that uses its name, so counters keep
+//! counting across reloads. Once no loaded config routes a vhost (or a
+//! vhost's backend) any more, its segment is dropped, which removes it from
+//! varnishstat.
+//!
+//! Segments are only published once `init()` ran in varnishd. Until then,
+//! recording is a no-op. Unit tests run outside varnishd, so there segments
+//! are plain heap memory.
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, OnceLock, Weak};
+
+use parking_lot::Mutex;
+#[cfg(not(test))]
+use varnish::Vsc;
+use varnish::VscMetric;
+
+/// VSC module name, the first component of every counter name
+#[cfg(not(test))]
+const MODULE: &str = "GHOST";
+
+/// Process-wide counters
+#[derive(VscMetric)]
+#[repr(C)]
+pub struct GhostCounters {
+    /// Successful configuration loads, including the one at VCL load
+    #[counter]
+    pub reloads: AtomicU64,
+    /// Configuration loads that failed, leaving the previous config active
+    #[counter]
+    pub reload_failures: AtomicU64,
+    /// Reloads started by the file watcher (`init(watch = true)`)
+    #[counter]
+    pub watch_reloads: AtomicU64,
+    /// Requests answered 404 because no vhost or route matched
+    #[counter]
+    pub synth_404: AtomicU64,
+    /// Requests answered 500 because the matched route has no backends
+    #[counter]
+    pub synth_500: AtomicU64,
+    /// Requests answered 503 because no backend of the route was selectable
+    #[counter]
+    pub synth_503: AtomicU64,
+    /// Requests answered 429 by a route's `rate_limit`
+    #[counter]
+    pub synth_429: AtomicU64,
+    /// Requests answered 405 because only a route's `method` didn't match
+    #[counter]
+    pub synth_405: AtomicU64,
+    /// Requests answered 400 or 409 by a route's `nonce`
+    #[counter]
+    pub nonce_rejected: AtomicU64,
+    /// Request copies sent to a route's `request_mirror`
+    #[counter]
+    pub mirror_requests: AtomicU64,
+    /// Mirrored requests that failed or timed out
+    #[counter]
+    pub mirror_failures: AtomicU64,
+    /// External proxy requests in flight
+    #[gauge]
+    pub in_flight: AtomicU64,
+}
+
+/// Per-vhost counters
+#[derive(VscMetric)]
+#[repr(C)]
+pub struct VhostCounters {
+    /// Requests routed to a backend of this vhost
+    #[counter]
+    pub req: AtomicU64,
+    /// Fetches retried on another backend under a route retry policy
+    #[counter]
+    pub retries: AtomicU64,
+}
+
+/// Per-backend counters within a vhost
+#[derive(VscMetric)]
+#[repr(C)]
+pub struct BackendCounters {
+    /// Times routing selected this backend
+    #[counter]
+    pub selected: AtomicU64,
+}
+
+/// A published segment.
+///
+/// SAFETY: the segment is shared memory owned by varnishd until
+/// `VRT_VSC_Destroy` runs on drop, and every field is atomic, so it may be
+/// read, updated and dropped from any thread.
+pub struct Segment<T: VscMetric>(#[cfg(not(test))] Vsc<T>, #[cfg(test)] Box<T>);
+
+unsafe impl<T: VscMetric> Send for Segment<T> {}
+unsafe impl<T: VscMetric> Sync for Segment<T> {}
+
+impl<T: VscMetric> Segment<T> {
+    /// `name` is a printf format to varnishd
+    #[cfg(not(test))]
+    fn publish(name: &str) -> Self {
+        Self(Vsc::new(MODULE, name))
+    }
+
+    #[cfg(test)]
+    fn publish(_name: &str) -> Self {
+        // SAFETY: VscMetric structs only hold atomics, for which zero is valid
+        Self(Box::new(unsafe { std::mem::zeroed() }))
+    }
+}
+
+impl<T: VscMetric> Deref for Segment<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+type Registry<T> = LazyLock<Mutex<HashMap<String, Weak<Segment<T>>>>>;
+
+static GLOBAL: OnceLock<Segment<GhostCounters>> = OnceLock::new();
+static VHOSTS: Registry<VhostCounters> = LazyLock::new(Default::default);
+static BACKENDS: Registry<BackendCounters> = LazyLock::new(Default::default);
+
+/// Publish the process-wide counters. Must run in varnishd, in VCL context.
+pub fn init() {
+    GLOBAL.get_or_init(|| Segment::publish(""));
+}
+
+/// Add one to a process-wide counter
+pub fn incr(counter: fn(&GhostCounters) -> &AtomicU64) {
+    if let Some(global) = GLOBAL.get() {
+        counter(global).fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Subtract one from a process-wide gauge
+pub fn decr(gauge: fn(&GhostCounters) -> &AtomicU64) {
+    if let Some(global) = GLOBAL.get() {
+        gauge(global).fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Existing segment named `name`, or a new one
+fn segment<T: VscMetric>(registry: &Registry<T>, name: &str) -> Arc<Segment<T>> {
+    let mut segments = registry.lock();
+    if let Some(segment) = segments.get(name).and_then(Weak::upgrade) {
+        return segment;
+    }
+    segments.retain(|_, s| s.strong_count() > 0);
+    let segment = Arc::new(Segment::publish(&name.replace('%', "%%")));
+    segments.insert(name.to_string(), Arc::downgrade(&segment));
+    segment
+}
+
+/// Counters of one vhost and its backends, held by its director
+pub struct VhostVsc {
+    counters: Arc<Segment<VhostCounters>>,
+    backends: HashMap<String, Arc<Segment<BackendCounters>>>,
+}
+
+impl VhostVsc {
+    /// Counters for `vhost` and the given backend keys. None until `init()`.
+    pub fn new<'a>(vhost: &str, backend_keys: impl IntoIterator<Item = &'a str>) -> Option<Self> {
+        GLOBAL.get()?;
+        let backends = backend_keys
+            .into_iter()
+            .map(|key| {
+                let name = format!("{}.{}", vhost, key);
+                (key.to_string(), segment(&BACKENDS, &name))
+            })
+            .collect();
+        Some(Self {
+            counters: segment(&VHOSTS, vhost),
+            backends,
+        })
+    }
+
+    pub fn record_request(&self, backend_key: &str) {
+        self.counters.req.fetch_add(1, Ordering::Relaxed);
+        if let Some(backend) = self.backends.get(backend_key) {
+            backend.selected.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_retry(&self) {
+        self.counters.retries.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl std::fmt::Debug for VhostVsc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("VhostVsc")
+            .field("backends", &self.backends.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vhost_segments_shared_and_retired() {
+        init();
+
+        let first = VhostVsc::new("vsc-test.example.com", ["10.0.0.1:80"]).unwrap();
+        let second = VhostVsc::new("vsc-test.example.com", ["10.0.0.1:80", "10.0.0.2:80"]).unwrap();
+        first.record_request("10.0.0.1:80");
+        second.record_request("10.0.0.1:80");
+        second.record_request("10.0.0.2:80");
+        second.record_retry();
+
+        // Both directors count into the same segments
+        assert!(Arc::ptr_eq(&first.counters, &second.counters));
+        assert_eq!(second.counters.req.load(Ordering::Relaxed), 3);
+        assert_eq!(second.counters.retries.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            first.backends["10.0.0.1:80"]
+                .selected
+                .load(Ordering::Relaxed),
+            2
+        );
+
+        // Unknown keys only count towards the vhost
+        second.record_request("10.0.0.9:80");
+        assert_eq!(second.counters.req.load(Ordering::Relaxed), 4);
+
+        // Once no director holds it, the segment is gone
+        drop(first);
+        drop(second);
+        assert!(VHOSTS.lock()["vsc-test.example.com"].upgrade().is_none());
+        let fresh = VhostVsc::new("vsc-test.example.com", []).unwrap();
+        assert_eq!(fresh.counters.req.load(Ordering::Relaxed), 0);
+    }
+}
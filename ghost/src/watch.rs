@@ -0,0 +1,542 @@
+//! Reload `ghost.json` when it changes on disk.
+//!
+//! Opt-in with `ghost.init(path, watch = true)`. A task on the shared
+//! background runtime stats the config file and runs the same reload as the
+//! HTTP endpoint once a change has settled, at most once per
+//! `watch_interval`. The file is checked when inotify reports activity in
+//! its directory, and on a timer for changes inotify doesn't see (network
+//! filesystems, or no watch could be set up).
+//!
+//! The file is compared by what its path resolves to, not just its mtime:
+//! Kubernetes ConfigMap mounts replace `ghost.json` by swapping a `..data`
+//! symlink in the parent directory, and editors and chaperone write a
+//! temporary file and rename it into place. Either way the resolved target
+//! or the inode changes. A path that briefly doesn't resolve mid-swap is
+//! ignored until it does again.
+//!
+//! Reloads create and retire backends, which Varnish only allows in a warm
+//! VCL, so the watcher only runs while its VCL is warm: the VMOD event
+//! handler pauses it when the VCL goes cold and stops it on discard.
+
+use std::ffi::c_char;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use varnish::ffi;
+use varnish::vcl::{log, Ctx, Event, LogTag};
+
+use crate::director::GhostDirector;
+
+/// Default minimum time between two reloads triggered by the watcher
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the config file is checked, unless `watch_interval` is shorter
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Quiet time after file events before the file is checked. A ConfigMap
+/// update is a burst of events.
+const SETTLE_DELAY: Duration = Duration::from_millis(50);
+
+/// Workspace for the VCL context reloads run in. Building routing state
+/// doesn't allocate from it.
+const WORKSPACE_SIZE: usize = 16 * 1024;
+
+/// What identifies one version of the config file
+#[derive(Debug, Clone, PartialEq)]
+struct FileStamp {
+    /// The path with symlinks resolved
+    target: PathBuf,
+    ino: u64,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    /// None while the path doesn't resolve to a file
+    fn read(path: &Path) -> Option<Self> {
+        let target = std::fs::canonicalize(path).ok()?;
+        let meta = std::fs::metadata(&target).ok()?;
+        Some(FileStamp {
+            target,
+            ino: meta.ino(),
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        })
+    }
+}
+
+/// Decides when a file change triggers a reload: once the file was seen
+/// unchanged on two checks in a row, and no sooner than `interval` after
+/// the previous reload.
+#[derive(Debug)]
+struct Debounce {
+    interval: Duration,
+    seen: Option<FileStamp>,
+    pending: bool,
+    last_reload: Option<Instant>,
+}
+
+impl Debounce {
+    /// `current` is the file as loaded when watching starts
+    fn new(interval: Duration, current: Option<FileStamp>) -> Self {
+        Debounce {
+            interval,
+            seen: current,
+            pending: false,
+            last_reload: None,
+        }
+    }
+
+    /// Record a check of the file, and return whether to reload now
+    fn observe(&mut self, stamp: Option<FileStamp>, now: Instant) -> bool {
+        let Some(stamp) = stamp else {
+            return false;
+        };
+        if self.seen.as_ref() != Some(&stamp) {
+            self.seen = Some(stamp);
+            self.pending = true;
+            return false;
+        }
+        if !self.pending
+            || self
+                .last_reload
+                .is_some_and(|t| now.duration_since(t) < self.interval)
+        {
+            return false;
+        }
+        self.pending = false;
+        self.last_reload = Some(now);
+        true
+    }
+
+    /// The reload `observe()` asked for didn't happen: ask again
+    fn defer(&mut self) {
+        self.pending = true;
+        self.last_reload = None;
+    }
+}
+
+/// The VCL a watcher reloads in, or a DNS refresh rebuilds backends in.
+///
+/// SAFETY: the VCL outlives its `ghost_backend` objects, and a watcher or
+/// refresher is stopped, waiting for any reload or refresh in progress,
+/// before its `ghost_backend` is dropped.
+pub struct VclPtr(pub ffi::VCL_VCL);
+
+unsafe impl Send for VclPtr {}
+unsafe impl Sync for VclPtr {}
+
+/// Whether a background task of a VCL may run: a reload, or a DNS
+/// refresh. Held for the duration of each, so pausing or stopping the task
+/// waits for one in progress.
+#[derive(Debug, Default)]
+pub struct Gate {
+    /// The VCL is warm. Tasks start paused: a VCL loaded warm gets its
+    /// first `Warm` event right after `vcl_init`.
+    warm: bool,
+    /// The task was dropped or its VCL discarded, for good
+    stopped: bool,
+}
+
+impl Gate {
+    pub fn open(&self) -> bool {
+        self.warm && !self.stopped
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    pub fn stop(&mut self) {
+        self.stopped = true;
+    }
+}
+
+/// Gates of every background task, by the address of their VCL
+static GATES: Mutex<Vec<(usize, Weak<Mutex<Gate>>)>> = Mutex::new(Vec::new());
+
+/// A gate for a background task of `vcl`, opened and closed along with the
+/// VCL's temperature
+pub fn gate(vcl: ffi::VCL_VCL) -> Arc<Mutex<Gate>> {
+    let gate = Arc::new(Mutex::new(Gate::default()));
+    let mut gates = GATES.lock();
+    gates.retain(|(_, g)| g.strong_count() > 0);
+    gates.push((vcl.0 as usize, Arc::downgrade(&gate)));
+    gate
+}
+
+/// Follow a VCL temperature change: run background tasks of `vcl` while it
+/// is warm, pause them when it goes cold, and stop them for good when it is
+/// discarded. Waits for a reload or refresh in progress.
+pub fn vcl_event(vcl: ffi::VCL_VCL, event: Event) {
+    let gates: Vec<Arc<Mutex<Gate>>> = GATES
+        .lock()
+        .iter()
+        .filter(|(v, _)| *v == vcl.0 as usize)
+        .filter_map(|(_, g)| g.upgrade())
+        .collect();
+    for gate in gates {
+        let mut gate = gate.lock();
+        match event {
+            Event::Warm => gate.warm = true,
+            Event::Cold => gate.warm = false,
+            Event::Discard => gate.stopped = true,
+            _ => {}
+        }
+    }
+    if matches!(event, Event::Discard) {
+        GATES
+            .lock()
+            .retain(|(v, g)| *v != vcl.0 as usize && g.strong_count() > 0);
+    }
+}
+
+/// A VCL context of a background task's own, outside of any client or
+/// backend task, with a workspace of `WORKSPACE_SIZE`. VSL lines logged in
+/// it go to the global log.
+pub struct TaskCtx {
+    raw: ffi::vrt_ctx,
+    ws: ffi::ws,
+    _space: Vec<u64>,
+}
+
+impl TaskCtx {
+    pub fn new(vcl: &VclPtr) -> Box<Self> {
+        let mut space = vec![0u64; WORKSPACE_SIZE / size_of::<u64>()];
+        let s = space.as_mut_ptr().cast::<c_char>();
+        let ws = ffi::ws {
+            magic: ffi::WS_MAGIC,
+            id: [b't', b's', b'k', 0].map(|b| b as c_char),
+            s,
+            f: s,
+            r: std::ptr::null_mut(),
+            e: unsafe { s.add(WORKSPACE_SIZE) },
+        };
+        // Boxed: raw.ws points into it
+        let mut task = Box::new(TaskCtx {
+            raw: ffi::vrt_ctx {
+                magic: ffi::VRT_CTX_MAGIC,
+                vcl: vcl.0,
+                ..ffi::vrt_ctx::default()
+            },
+            ws,
+            _space: space,
+        });
+        task.raw.ws = &raw mut task.ws;
+        task
+    }
+
+    pub fn ctx(&mut self) -> Ctx<'_> {
+        Ctx::from_ref(&mut self.raw)
+    }
+}
+
+/// A running file watcher. Dropping it stops the watch; a reload already
+/// running finishes first.
+pub struct ConfigWatcher {
+    handle: JoinHandle<()>,
+    /// Held for the duration of each reload
+    gate: Arc<Mutex<Gate>>,
+}
+
+impl ConfigWatcher {
+    /// Watch `path` and reload `director` when it changes. `vcl` is the VCL
+    /// the director's backends are created in.
+    pub fn start(
+        path: PathBuf,
+        interval: Duration,
+        director: Weak<GhostDirector>,
+        vcl: ffi::VCL_VCL,
+    ) -> Self {
+        let gate = gate(vcl);
+        let handle = crate::external_backend::spawn(watch_loop(
+            path,
+            interval,
+            director,
+            VclPtr(vcl),
+            Arc::clone(&gate),
+        ));
+        ConfigWatcher { handle, gate }
+    }
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        self.gate.lock().stop();
+        self.handle.abort();
+    }
+}
+
+async fn watch_loop(
+    path: PathBuf,
+    interval: Duration,
+    director: Weak<GhostDirector>,
+    vcl: VclPtr,
+    gate: Arc<Mutex<Gate>>,
+) {
+    let vcl = Arc::new(vcl);
+    // Kept for as long as the loop runs
+    let (_events_watcher, mut events) = match watch_events(&path) {
+        Ok((watcher, events)) => (Some(watcher), events),
+        Err(e) => {
+            log(
+                LogTag::Error,
+                format!(
+                    "ghost: no file events for {} ({}), polling only",
+                    path.display(),
+                    e
+                ),
+            );
+            (None, mpsc::channel(1).1)
+        }
+    };
+    let mut debounce = Debounce::new(interval, FileStamp::read(&path));
+    let mut ticker = tokio::time::interval(POLL_INTERVAL.min(interval));
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            Some(()) = events.recv() => {
+                // Check once the burst is over, and again a little later so
+                // the debounce sees the new file unchanged
+                tokio::time::sleep(SETTLE_DELAY).await;
+                while events.try_recv().is_ok() {}
+                debounce.observe(FileStamp::read(&path), Instant::now());
+                tokio::time::sleep(SETTLE_DELAY).await;
+            }
+        }
+        // While the VCL is cold, changes wait: the first check once it is
+        // warm again sees them
+        let open = {
+            let gate = gate.lock();
+            if gate.stopped() {
+                return;
+            }
+            gate.open()
+        };
+        if !open || !debounce.observe(FileStamp::read(&path), Instant::now()) {
+            continue;
+        }
+        let director = director.clone();
+        let vcl = Arc::clone(&vcl);
+        let task_gate = Arc::clone(&gate);
+        let path = path.clone();
+        let reloaded = tokio::task::spawn_blocking(move || {
+            let gate = task_gate.lock();
+            if !gate.open() {
+                return false;
+            }
+            let Some(director) = director.upgrade() else {
+                return false;
+            };
+            reload(&director, &vcl, &path);
+            true
+        })
+        .await;
+        match reloaded {
+            Ok(true) => {}
+            // Went cold in the meantime
+            Ok(false) if !gate.lock().stopped() => debounce.defer(),
+            _ => return,
+        }
+    }
+}
+
+/// Report activity in the directory of `path`. The directory is watched
+/// rather than the file so that renames into place and symlink swaps are
+/// seen, and the watch outlives them. Events only prompt a check of the
+/// file, so they are not filtered.
+fn watch_events(path: &Path) -> notify::Result<(RecommendedWatcher, mpsc::Receiver<()>)> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let (tx, rx) = mpsc::channel(1);
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if event.is_ok() {
+            // A full channel already has a check coming
+            let _ = tx.try_send(());
+        }
+    })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    Ok((watcher, rx))
+}
+
+/// Run `GhostDirector::reload` in a VCL context of our own. Its VSL lines
+/// go to the global log, not to a request.
+fn reload(director: &GhostDirector, vcl: &VclPtr, path: &Path) {
+    log(
+        LogTag::Debug,
+        format!("ghost: {} changed, reloading", path.display()),
+    );
+    let mut task = TaskCtx::new(vcl);
+    let mut ctx = task.ctx();
+    crate::vsc::incr(|c| &c.watch_reloads);
+    // Failures are logged and kept for last_error() by reload() itself
+    let _ = director.reload(&mut ctx);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn stamp(ino: u64) -> Option<FileStamp> {
+        Some(FileStamp {
+            target: PathBuf::from("/etc/ghost/ghost.json"),
+            ino,
+            len: 10,
+            modified: None,
+        })
+    }
+
+    #[test]
+    fn test_reload_once_change_settles() {
+        let start = Instant::now();
+        let mut d = Debounce::new(Duration::from_secs(1), stamp(1));
+
+        assert!(!d.observe(stamp(1), start));
+        // Changed: wait for the next check to see it unchanged
+        assert!(!d.observe(stamp(2), start));
+        assert!(d.observe(stamp(2), start + Duration::from_millis(250)));
+        // Reloaded once only
+        assert!(!d.observe(stamp(2), start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_at_most_one_reload_per_interval() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut d = Debounce::new(Duration::from_secs(1), stamp(1));
+
+        d.observe(stamp(2), at(0));
+        assert!(d.observe(stamp(2), at(250)));
+
+        d.observe(stamp(3), at(500));
+        assert!(!d.observe(stamp(3), at(750)));
+        assert!(!d.observe(stamp(3), at(1000)));
+        assert!(d.observe(stamp(3), at(1250)));
+    }
+
+    #[test]
+    fn test_deferred_reload_is_asked_for_again() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut d = Debounce::new(Duration::from_secs(1), stamp(1));
+
+        d.observe(stamp(2), at(0));
+        assert!(d.observe(stamp(2), at(250)));
+        // The VCL went cold before the reload ran
+        d.defer();
+        assert!(d.observe(stamp(2), at(500)));
+        assert!(!d.observe(stamp(2), at(750)));
+    }
+
+    #[test]
+    fn test_gate_follows_vcl_temperature() {
+        let mut vcls = [0u8; 2];
+        let vcl = ffi::VCL_VCL(std::ptr::addr_of_mut!(vcls[0]).cast());
+        let other = ffi::VCL_VCL(std::ptr::addr_of_mut!(vcls[1]).cast());
+        let g = gate(vcl);
+        let g_other = gate(other);
+        // Paused until the VCL is warm
+        assert!(!g.lock().open());
+
+        vcl_event(vcl, Event::Warm);
+        assert!(g.lock().open());
+        assert!(!g_other.lock().open());
+
+        vcl_event(vcl, Event::Cold);
+        assert!(!g.lock().open());
+        assert!(!g.lock().stopped());
+
+        vcl_event(vcl, Event::Warm);
+        vcl_event(vcl, Event::Discard);
+        assert!(!g.lock().open());
+        assert!(g.lock().stopped());
+        assert!(!g_other.lock().stopped());
+    }
+
+    #[test]
+    fn test_missing_file_is_not_a_change() {
+        let start = Instant::now();
+        let mut d = Debounce::new(Duration::from_secs(1), stamp(1));
+
+        // Mid-swap, the path doesn't resolve
+        assert!(!d.observe(None, start));
+        assert!(!d.observe(stamp(1), start));
+        assert!(!d.observe(None, start));
+        assert!(!d.observe(stamp(2), start));
+        assert!(!d.observe(None, start));
+        assert!(d.observe(stamp(2), start));
+    }
+
+    #[tokio::test]
+    async fn test_events_report_rename_into_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ghost.json");
+        std::fs::write(&path, r#"{"version": 2}"#).unwrap();
+        let (_watcher, mut events) = watch_events(&path).unwrap();
+
+        let tmp = dir.path().join(".ghost.json.tmp");
+        std::fs::write(&tmp, r#"{"version": 2, "vhosts": {}}"#).unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .expect("no event for the rename")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_stamp_follows_symlink_swap() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, body: &str| {
+            let mut f = std::fs::File::create(dir.path().join(name)).unwrap();
+            f.write_all(body.as_bytes()).unwrap();
+        };
+        // ConfigMap layout: ghost.json -> ..data/ghost.json, ..data -> ..v1
+        std::fs::create_dir(dir.path().join("..v1")).unwrap();
+        std::fs::create_dir(dir.path().join("..v2")).unwrap();
+        write("..v1/ghost.json", r#"{"version": 2}"#);
+        write("..v2/ghost.json", r#"{"version": 2}"#);
+        std::os::unix::fs::symlink("..v1", dir.path().join("..data")).unwrap();
+        std::os::unix::fs::symlink("..data/ghost.json", dir.path().join("ghost.json")).unwrap();
+
+        let path = dir.path().join("ghost.json");
+        let before = FileStamp::read(&path).unwrap();
+        assert_eq!(FileStamp::read(&path), Some(before.clone()));
+
+        // Atomic swap of ..data, as the kubelet does it
+        std::os::unix::fs::symlink("..v2", dir.path().join("..data_tmp")).unwrap();
+        std::fs::rename(dir.path().join("..data_tmp"), dir.path().join("..data")).unwrap();
+
+        let after = FileStamp::read(&path).unwrap();
+        assert_ne!(before, after);
+        assert!(after.target.ends_with("..v2/ghost.json"));
+
+        std::fs::remove_file(dir.path().join("..data")).unwrap();
+        assert_eq!(FileStamp::read(&path), None);
+    }
+
+    #[test]
+    fn test_stamp_follows_rename_into_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ghost.json");
+        std::fs::write(&path, r#"{"version": 2}"#).unwrap();
+        let before = FileStamp::read(&path).unwrap();
+
+        // Same size, possibly the same mtime: the inode tells them apart
+        let tmp = dir.path().join(".ghost.json.tmp");
+        std::fs::write(&tmp, r#"{"version": 2}"#).unwrap();
+        std::fs::rename(&tmp, &path).unwrap();
+
+        assert_ne!(FileStamp::read(&path), Some(before));
+    }
+}
@@ -62,6 +62,69 @@ func TestGenerate_GhostPreamble(t *testing.T) {
 		t.Error("expected router.recv() call in vcl_recv")
 	}
 
+	// Route retry policies are driven from the backend side
+	if !strings.Contains(result, "if (router.retry()) {") {
+		t.Error("expected router.retry() check in vcl_backend_response")
+	}
+	if !strings.Contains(result, "sub vcl_backend_error {") {
+		t.Error("expected router.retry() check in vcl_backend_error")
+	}
+	if !strings.Contains(result, "set bereq.backend = router.retry_backend()") {
+		t.Error("expected router.retry_backend() in vcl_backend_fetch")
+	}
+
+	// Route timeouts bound native fetches and turn into a 504
+	if !strings.Contains(result, "set bereq.connect_timeout = std.duration(bereq.http.X-Ghost-Backend-Timeout") {
+		t.Error("expected the route timeout to bound connecting in vcl_backend_fetch")
+	}
+	if !strings.Contains(result, "if (ghost.fetch_timed_out()) {") {
+		t.Error("expected timed-out fetches to become a 504 in vcl_backend_error")
+	}
+
+	// Route forward_host is applied to native backend fetches, while bans
+	// keep matching the client's Host
+	if !strings.Contains(result, "set bereq.http.Host = bereq.http.X-Ghost-Forward-Host") {
+		t.Error("expected forward_host to be applied in vcl_backend_fetch")
+	}
+	if !strings.Contains(result, "set beresp.http.x-cache-host = bereq.http.X-Ghost-Client-Host") {
+		t.Error("expected x-cache-host to record the client's Host under forward_host")
+	}
+
+	// Routing debug info is read from req in vcl_deliver and never fetched
+	if !strings.Contains(result, "unset bereq.http.X-Ghost-Debug-Info;") {
+		t.Error("expected debug info to be kept from backends in vcl_backend_fetch")
+	}
+	if !strings.Contains(result, "unset bereq.http.X-Ghost-Request-Id;") {
+		t.Error("expected the echoed request ID to be kept from backends in vcl_backend_fetch")
+	}
+
+	// Outbound rate limits are enforced per fetch, after any retry backend
+	if !strings.Contains(result, "if (bereq.http.X-Ghost-Rate-Limit && !router.throttle()) {") {
+		t.Error("expected the outbound rate limit to be checked in vcl_backend_fetch")
+	}
+	if strings.Index(result, "router.throttle()") < strings.Index(result, "router.retry_backend()") {
+		t.Error("expected router.throttle() after router.retry_backend()")
+	}
+
+	// Internal headers are hidden from native backends once vcl_backend_fetch
+	// is done with them, and restored for the subs that read them afterwards
+	hide := strings.Index(result, "ghost.hide_internal_headers();")
+	if hide < strings.Index(result, "bereq.http.X-Ghost-Backend-Timeout, bereq.between_bytes_timeout") {
+		t.Error("expected ghost.hide_internal_headers() at the end of vcl_backend_fetch")
+	}
+	if strings.Count(result, "ghost.restore_internal_headers();") != 2 {
+		t.Error("expected vcl_backend_response and vcl_backend_error to restore internal headers")
+	}
+
+	// Responses to authorized requests are marked private before the cache
+	// policy can give them a TTL
+	if !strings.Contains(result, `set beresp.http.Cache-Control = "private";`) {
+		t.Error("expected authorized_private to be applied in vcl_backend_response")
+	}
+	if strings.Index(result, "bereq.http.X-Ghost-Private &&") > strings.Index(result, "if (bereq.http.X-Ghost-Forced-TTL) {") {
+		t.Error("expected authorized_private before the forced TTL")
+	}
+
 	// vcl_backend_fetch should clean up internal cache policy headers
 	if !strings.Contains(result, "sub vcl_backend_fetch {") {
 		t.Error("expected vcl_backend_fetch for cache policy header cleanup")
@@ -81,6 +144,14 @@ func TestGenerate_GhostReloadHandler(t *testing.T) {
 		t.Error("expected vcl_recv to use localhost ACL for reload endpoint")
 	}
 
+	// Remote clients may reload only with the ghost reload token
+	if !strings.Contains(result, "ghost.reload_authorized()") {
+		t.Error("expected vcl_recv to check ghost.reload_authorized() for non-localhost reloads")
+	}
+	if !strings.Contains(result, `return (synth(403, "Forbidden"))`) {
+		t.Error("expected vcl_recv to return synth(403) for unauthorized reloads")
+	}
+
 	// Check that reload is called on the router
 	if !strings.Contains(result, "router.reload()") {
 		t.Error("expected vcl_recv to call router.reload()")
@@ -101,9 +172,42 @@ func TestGenerate_GhostReloadHandler(t *testing.T) {
 		t.Error("expected vcl_recv to return synth(500) on failed reload")
 	}
 
-	// Should NOT have vcl_backend_error (reload handled in vcl_recv)
-	if strings.Contains(result, "sub vcl_backend_error {") {
-		t.Error("should not have vcl_backend_error (reload handled in vcl_recv)")
+	// Rollback endpoint reverts to the previous in-memory config
+	if !strings.Contains(result, `if (req.url == "/.varnish-ghost/rollback") {`) {
+		t.Error("expected vcl_recv to handle the /.varnish-ghost/rollback endpoint")
+	}
+	if !strings.Contains(result, "router.rollback_config()") {
+		t.Error("expected vcl_recv to call router.rollback_config()")
+	}
+	if !strings.Contains(result, `return (synth(500, "Rollback failed"))`) {
+		t.Error("expected vcl_recv to return synth(500) on failed rollback")
+	}
+
+	// Validate endpoint reports on ghost.json without loading it
+	if !strings.Contains(result, `if (req.url == "/.varnish-ghost/validate") {`) {
+		t.Error("expected the /.varnish-ghost/validate endpoint")
+	}
+	if !strings.Contains(result, "synthetic(router.validate());") {
+		t.Error("expected vcl_synth to render router.validate()")
+	}
+
+	// Metrics endpoint renders ghost stats from vcl_synth
+	if !strings.Contains(result, `if (req.url == "/.varnish-ghost/metrics") {`) {
+		t.Error("expected the /.varnish-ghost/metrics endpoint")
+	}
+	if !strings.Contains(result, "synthetic(router.metrics());") {
+		t.Error("expected vcl_synth to render router.metrics()")
+	}
+
+	// vcl_backend_error only drives retries; reload is handled in vcl_recv
+	if i := strings.Index(result, "sub vcl_backend_error {"); i >= 0 {
+		body := result[i:]
+		if end := strings.Index(body, "\n}"); end >= 0 {
+			body = body[:end]
+		}
+		if strings.Contains(body, "router.reload()") {
+			t.Error("vcl_backend_error should not handle reloads (reload handled in vcl_recv)")
+		}
 	}
 }
 
@@ -124,6 +228,12 @@ func TestGenerate_GhostErrorSurfacing(t *testing.T) {
 	if !strings.Contains(result, "set resp.http.x-ghost-error = req.http.X-Ghost-Error") {
 		t.Error("expected vcl_synth to copy X-Ghost-Error from request to response header")
 	}
+	if !strings.Contains(result, "synthetic(router.last_error_json());") {
+		t.Error("expected vcl_synth to render router.last_error_json() for a failed reload")
+	}
+	if !strings.Contains(result, "synthetic(router.last_reload_json());") {
+		t.Error("expected vcl_synth to render router.last_reload_json() for a successful reload")
+	}
 
 	// Check comment explaining purpose
 	if !strings.Contains(result, "Surface ghost reload errors") {